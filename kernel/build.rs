@@ -0,0 +1,110 @@
+//! Generates the registry of user test binaries.
+//!
+//! Every executable ELF file in `src/processes/test_binaries` is embedded in
+//! the kernel under its file name, so adding a test program only takes
+//! dropping the binary, and optionally its source, into that directory.
+//!
+//! Also passes the kernel version and build time on to `version`.
+
+use std::{
+    env,
+    fmt::Write,
+    fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const BINARY_DIR: &str = "src/processes/test_binaries";
+
+/// Extensions of test binary sources: NASM, then GNU assembler
+const SOURCE_EXTENSIONS: [&str; 2] = ["asm", "s"];
+
+/// ELF file types the kernel can load
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+/// Returns the entry point of an executable ELF file, None for anything
+/// else, such as object files and assembly sources
+fn elf_entry(contents: &[u8]) -> Option<u64> {
+    if contents.len() < 32 || &contents[..4] != b"\x7fELF" {
+        return None;
+    }
+    let file_type = u16::from_le_bytes([contents[16], contents[17]]);
+    if file_type != ET_EXEC && file_type != ET_DYN {
+        return None;
+    }
+    Some(u64::from_le_bytes(contents[24..32].try_into().unwrap()))
+}
+
+/// Sets TAOS_VERSION to the revision `git describe` names, or the crate
+/// version outside a checkout, and TAOS_BUILD_TIME to the current time,
+/// unless SOURCE_DATE_EPOCH pins it for a reproducible build
+fn emit_version() {
+    let git_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../.git");
+    for file in ["HEAD", "index"] {
+        if git_dir.join(file).exists() {
+            println!("cargo:rerun-if-changed={}", git_dir.join(file).display());
+        }
+    }
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let version = Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_owned())
+        .unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_owned());
+    println!("cargo:rustc-env=TAOS_VERSION={version}");
+
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Clock is before the Unix epoch")
+                .as_secs()
+        });
+    println!("cargo:rustc-env=TAOS_BUILD_TIME={build_time}");
+}
+
+fn main() {
+    emit_version();
+    println!("cargo:rerun-if-changed={BINARY_DIR}");
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(BINARY_DIR);
+
+    let mut binaries = Vec::new();
+    for entry in fs::read_dir(&dir).expect("Failed to read test binary directory") {
+        let path = entry.expect("Failed to read test binary directory").path();
+        let contents = fs::read(&path).expect("Failed to read test binary");
+        let Some(entry_point) = elf_entry(&contents) else {
+            continue;
+        };
+        let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        let source = SOURCE_EXTENSIONS
+            .iter()
+            .map(|extension| format!("{name}.{extension}"))
+            .find(|source| dir.join(source).exists());
+        binaries.push((name, path, entry_point, source));
+    }
+    binaries.sort();
+
+    let mut registry = String::from("[\n");
+    for (name, path, entry_point, source) in binaries {
+        println!("cargo:rerun-if-changed={}", path.display());
+        writeln!(
+            registry,
+            "    TestBinary {{ name: {name:?}, elf: include_bytes!({path:?}), \
+             entry_point: {entry_point:#x}, source: {source:?} }},"
+        )
+        .unwrap();
+    }
+    registry.push(']');
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("test_binaries.rs");
+    fs::write(out, registry).expect("Failed to write test binary registry");
+}
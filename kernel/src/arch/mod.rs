@@ -0,0 +1,32 @@
+//! Architecture specific primitives.
+//!
+//! Subsystems that only need to know which core they run on, mask
+//! interrupts or wait for one go through these wrappers instead of the
+//! x86_64 crate, so they build unchanged for another architecture. Every
+//! architecture module provides the same functions, and the one for the
+//! target is re-exported here.
+//!
+//! The interrupt controllers, descriptor tables, paging and drivers are
+//! still x86_64 specific and live outside this boundary for now.
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_without_interrupts_restores_state() {
+        let enabled = interrupts_enabled();
+        let core = without_interrupts(|| {
+            assert!(!interrupts_enabled());
+            core_id()
+        });
+        assert_eq!(interrupts_enabled(), enabled);
+        assert_eq!(core, core_id());
+    }
+}
@@ -0,0 +1,79 @@
+//! x86_64 implementation of the architecture primitives.
+
+use core::arch::x86_64::{__cpuid_count, _rdtsc};
+
+use ::x86_64::instructions::{hlt, interrupts, port::Port};
+
+use crate::interrupts::x2apic;
+
+/// Returns the ID of the core running this code
+pub fn core_id() -> u32 {
+    x2apic::current_core_id() as u32
+}
+
+/// Returns the ID of the core running this code. Slower than `core_id`,
+/// but works before the core's x2APIC is enabled.
+pub fn early_core_id() -> u32 {
+    // The extended topology leaf reports the x2APIC ID in edx
+    unsafe { __cpuid_count(0xB, 0) }.edx
+}
+
+/// Reads the timestamp counter. Ticks are converted to nanoseconds with
+/// `time::tsc_to_ns`.
+pub fn timestamp() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Runs `f` with interrupts masked on this core, then restores whether
+/// they were enabled before
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    interrupts::without_interrupts(f)
+}
+
+/// Returns true if interrupts are enabled on this core
+pub fn interrupts_enabled() -> bool {
+    interrupts::are_enabled()
+}
+
+/// Enables interrupts and sleeps until one arrives. An interrupt that
+/// arrives while enabling cannot be missed.
+pub fn enable_interrupts_and_wait() {
+    interrupts::enable_and_hlt();
+}
+
+/// Masks interrupts on this core
+pub fn disable_interrupts() {
+    interrupts::disable();
+}
+
+/// Sleeps until the next interrupt, if interrupts are enabled
+pub fn wait_for_interrupt() {
+    hlt();
+}
+
+/// Writes a byte to an I/O port
+///
+/// # Safety
+/// Writing to a port can have arbitrary side effects on the device behind it
+pub unsafe fn port_write_u8(port: u16, value: u8) {
+    unsafe { Port::new(port).write(value) };
+}
+
+/// Writes a doubleword to an I/O port
+///
+/// # Safety
+/// Writing to a port can have arbitrary side effects on the device behind it
+pub unsafe fn port_write_u32(port: u16, value: u32) {
+    unsafe { Port::new(port).write(value) };
+}
+
+/// Reads a byte from an I/O port
+///
+/// # Safety
+/// Reading a port can have side effects on the device behind it
+pub unsafe fn port_read_u8(port: u16) -> u8 {
+    unsafe { Port::new(port).read() }
+}
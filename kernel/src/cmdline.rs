@@ -0,0 +1,49 @@
+//! Kernel command line.
+//!
+//! Limine hands over the `cmdline` of the boot entry in limine.conf along
+//! with the kernel file. Options are separated by spaces and take the form
+//! `key=value`; an option without `=` has an empty value. When an option
+//! is given more than once, the last one wins.
+
+use limine::request::KernelFileRequest;
+
+#[used]
+#[link_section = ".requests"]
+static KERNEL_FILE_REQUEST: KernelFileRequest = KernelFileRequest::new();
+
+/// The whole command line, empty if the bootloader passed none
+pub fn cmdline() -> &'static str {
+    KERNEL_FILE_REQUEST
+        .get_response()
+        .and_then(|response| core::str::from_utf8(response.file().cmdline()).ok())
+        .unwrap_or("")
+}
+
+/// Returns the value of the option `key`, or None if it was not given
+pub fn option(key: &str) -> Option<&'static str> {
+    find_option(cmdline(), key)
+}
+
+fn find_option<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_ascii_whitespace()
+        .rev()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_find_option() {
+        let cmdline = "serial=ttyS1,115200 quiet debug_serial=0x2f8 serial=ttyS0";
+        assert_eq!(find_option(cmdline, "serial"), Some("ttyS0"));
+        assert_eq!(find_option(cmdline, "debug_serial"), Some("0x2f8"));
+        assert_eq!(find_option(cmdline, "quiet"), Some(""));
+        assert_eq!(find_option(cmdline, "seria"), None);
+        assert_eq!(find_option("", "serial"), None);
+    }
+}
@@ -7,4 +7,25 @@ pub const MAX_EVENTS: usize = 256;
 /// Higher priority events are processed before lower priority ones.
 pub const NUM_EVENT_PRIORITIES: usize = 4;
 
+/// Priority of maintenance events, below every normal priority level.
+/// Idle events only run when all normal queues are empty and are never
+/// promoted.
+pub const IDLE_PRIORITY: usize = NUM_EVENT_PRIORITIES;
+
+/// How long kernel work may hold a core in one poll before the timer asks
+/// it to yield at its next checkpoint
+pub const KERNEL_POLL_BUDGET_NS: u64 = 10_000_000;
+
+/// Longest a single poll may hold its event's future before the runner
+/// reports it as a long poll
+pub const LONG_POLL_NS: u64 = 10_000_000;
+
 pub const PRIORITY_INC_DELAY: u64 = 5; // TODO try different values
+
+/// Credit a CPU group spends to have one of its events polled
+pub const DRR_POLL_COST: u64 = 1024;
+
+/// Fewest normal events a core must have queued for an idle core to steal
+/// one. A core's last queued event is left alone, as it will run soon
+/// anyway.
+pub const STEAL_MIN_QUEUED: usize = 2;
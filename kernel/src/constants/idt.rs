@@ -4,3 +4,8 @@
 pub const TIMER_VECTOR: u8 = 32;
 pub const SYSCALL_HANDLER: u8 = 0x80;
 pub const TLB_SHOOTDOWN_VECTOR: u8 = 33;
+/// Wakes a halted core to run events another core placed on it
+pub const WAKE_VECTOR: u8 = 34;
+/// Vectors handed out to devices for message signaled interrupts
+pub const DEVICE_VECTOR_BASE: u8 = 48;
+pub const DEVICE_VECTOR_COUNT: usize = 16;
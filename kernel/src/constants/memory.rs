@@ -6,6 +6,9 @@ pub const PAGE_SIZE: usize = 4096;
 /// Size of a physical memory frame in bytes.
 pub const FRAME_SIZE: usize = 4096;
 
+/// First address past the lower, user half of the address space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
 /// Starting virtual address of the kernel heap.
 pub const HEAP_START: *mut u8 = 0x_FFFF_8100_0000_0000 as *mut u8;
 
@@ -22,4 +25,23 @@ pub const BITMAP_ENTRY_SIZE: usize = 64;
 /// Value representing a fully allocated bitmap entry.
 pub const FULL_BITMAP_ENTRY: u64 = 0xFFFFFFFFFFFFFFFF;
 
+/// Shootdowns covering more pages than this flush the whole TLB instead of
+/// invalidating page by page.
+pub const TLB_FULL_FLUSH_PAGES: u64 = 32;
+
+/// Number of frames the zeroing daemon keeps zeroed ahead of time.
+pub const ZERO_POOL_TARGET: usize = 64;
+
+/// Time between the zeroing daemon's checks of the pool (100 ms).
+pub const ZERO_POOL_REFILL_INTERVAL_NS: u64 = 100_000_000;
+
+/// Time between sweeps of every heap allocation's red zones (1 s).
+pub const RED_ZONE_CHECK_INTERVAL_NS: u64 = 1_000_000_000;
+
 pub const EPHEMERAL_KERNEL_MAPPINGS_START: u64 = 0xFFFF_FF80_0000_0000;
+
+/// Start of the kernel virtual window the virtio-gpu framebuffer is mapped into.
+pub const GPU_FRAMEBUFFER_START: u64 = 0xFFFF_FE00_0000_0000;
+
+/// Largest framebuffer the virtio-gpu window can hold (64 MiB).
+pub const GPU_FRAMEBUFFER_MAX_SIZE: u64 = 64 * 1024 * 1024;
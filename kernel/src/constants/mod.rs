@@ -7,6 +7,7 @@ pub mod events;
 pub mod gdt;
 pub mod idt;
 pub mod memory;
+pub mod net;
 pub mod ports;
 pub mod processes;
 pub mod syscalls;
@@ -0,0 +1,19 @@
+use core::net::Ipv4Addr;
+
+/// Address of the interface, as handed out by QEMU's user networking
+pub const DEFAULT_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+pub const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+/// Where packets for other networks are sent
+pub const DEFAULT_GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+/// UDP ports given to sockets bound to port 0 are picked from here up
+pub const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Datagrams queued on a socket before further ones are dropped
+pub const SOCKET_QUEUE_LEN: usize = 32;
+
+/// Neighbors whose hardware addresses are remembered
+pub const MAX_NEIGHBORS: usize = 64;
+
+/// Packets held while the hardware address of where they go is asked for
+pub const MAX_UNRESOLVED: usize = 16;
@@ -2,3 +2,6 @@
 
 /// Base I/O port address for the first serial port (COM1).
 pub const SERIAL_PORT: u16 = 0x3F8;
+
+/// Command port of the PS/2 keyboard controller, used to reset the CPU.
+pub const KEYBOARD_CONTROLLER_PORT: u16 = 0x64;
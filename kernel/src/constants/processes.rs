@@ -1,6 +1,51 @@
-pub const INFINITE_LOOP: &[u8] = include_bytes!("../processes/test_binaries/rand_regs");
-pub const SYSCALL_BINARY: &[u8] = include_bytes!("../processes/test_binaries/syscall_test");
-pub const LONG_LOOP: &[u8] = include_bytes!("../processes/test_binaries/long_loop_print");
+use super::events::NUM_EVENT_PRIORITIES;
+
+/// Event priority processes start at. Processes may lower their priority
+/// from here with nice, but not raise it above.
+pub const PROCESS_DEFAULT_PRIORITY: usize = NUM_EVENT_PRIORITIES - 2;
+
+/// Descriptors a process may have open at once
+pub const MAX_OPEN_FILES: usize = 64;
+
+/// Bytes a pipe buffers before writers wait for the reader
+pub const PIPE_CAPACITY: usize = 4096;
 
 pub const STACK_START: u64 = 0x7000_0000_0000;
 pub const STACK_SIZE: usize = 2 * 4096; // 2 pages for the stack
+
+/// File mappings are placed from here up to the stack area
+pub const MMAP_START: u64 = 0x6000_0000_0000;
+
+/// Program images kept cached, besides those processes are still running
+pub const MAX_CACHED_IMAGES: usize = 16;
+
+/// Largest user stack a binary may ask for in its notes
+pub const MAX_STACK_SIZE: usize = 1024 * 1024;
+
+/// Owner name of the ELF notes the loader reads hints from, and their
+/// types. Each note holds one little-endian u64.
+pub const TAOS_NOTE_NAME: &str = "TAOS";
+/// Bytes of user stack to map instead of STACK_SIZE
+pub const NT_TAOS_STACK_SIZE: u32 = 1;
+/// Bytes of heap the binary expects to use
+pub const NT_TAOS_HEAP_SIZE: u32 = 2;
+/// Syscall feature bits the binary needs, see `syscalls::FEATURE_*`
+pub const NT_TAOS_FEATURES: u32 = 3;
+
+/// Stack of each kernel thread. Interrupts taken while a thread runs use
+/// it too, as they stay in ring 0.
+pub const KTHREAD_STACK_SIZE: usize = 64 * 1024;
+
+/// A process ended by a signal exits with this plus the signal's number,
+/// as shells report it
+pub const SIGNAL_EXIT_BASE: i32 = 128;
+
+/// PID of events run on the kernel's own behalf. Never given to a process.
+pub const KERNEL_PID: u32 = 0;
+
+/// Highest PID handed out, Linux's default pid_max
+pub const PID_MAX: u32 = 32767;
+
+/// How long a freed PID stays unused before it can be handed out again, so
+/// anything still holding the old PID cannot reach a new process by mistake
+pub const PID_QUARANTINE_NS: u64 = 1_000_000_000;
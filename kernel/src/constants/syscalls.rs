@@ -1,2 +1,133 @@
 pub const SYSCALL_EXIT: u32 = 1;
 pub const SYSCALL_PRINT: u32 = 3;
+pub const SYSCALL_CLOCK_GETTIME: u32 = 4;
+pub const SYSCALL_SETTIMEOFDAY: u32 = 5;
+pub const SYSCALL_STATFS: u32 = 6;
+pub const SYSCALL_UTIMENSAT: u32 = 7;
+pub const SYSCALL_REBOOT: u32 = 8;
+pub const SYSCALL_NICE: u32 = 9;
+pub const SYSCALL_OPEN: u32 = 10;
+pub const SYSCALL_READ: u32 = 11;
+pub const SYSCALL_WRITE: u32 = 12;
+pub const SYSCALL_CLOSE: u32 = 13;
+pub const SYSCALL_SEEK: u32 = 14;
+pub const SYSCALL_DUP: u32 = 15;
+pub const SYSCALL_DUP2: u32 = 16;
+pub const SYSCALL_EXEC: u32 = 17;
+pub const SYSCALL_WAITPID: u32 = 18;
+pub const SYSCALL_UNAME: u32 = 19;
+pub const SYSCALL_MMAP: u32 = 20;
+pub const SYSCALL_MSYNC: u32 = 21;
+pub const SYSCALL_PIPE: u32 = 22;
+pub const SYSCALL_KILL: u32 = 23;
+pub const SYSCALL_SIGACTION: u32 = 24;
+pub const SYSCALL_SIGRETURN: u32 = 25;
+pub const SYSCALL_BRK: u32 = 26;
+pub const SYSCALL_SBRK: u32 = 27;
+pub const SYSCALL_MUNMAP: u32 = 28;
+pub const SYSCALL_MPROTECT: u32 = 29;
+
+/// Version of the syscall table reported by uname. Bumped whenever a
+/// syscall is renumbered or its arguments change meaning, so programs can
+/// tell an incompatible kernel apart from one that lacks a feature.
+pub const SYSCALL_TABLE_VERSION: u64 = 1;
+
+/// Groups of syscalls a binary can require in its TAOS features note, by
+/// bit. Binaries needing a bit missing from SUPPORTED_FEATURES are refused.
+pub const FEATURE_FILES: u64 = 1 << 0; // open, read, write, close, seek, dup, dup2, pipe
+pub const FEATURE_PROCESSES: u64 = 1 << 1; // exec, waitpid
+pub const FEATURE_MEMORY: u64 = 1 << 2; // mmap, msync, munmap, mprotect, brk, sbrk
+pub const FEATURE_SIGNALS: u64 = 1 << 3; // kill, sigaction, sigreturn
+pub const SUPPORTED_FEATURES: u64 =
+    FEATURE_FILES | FEATURE_PROCESSES | FEATURE_MEMORY | FEATURE_SIGNALS;
+
+/// Options accepted by waitpid, with Linux's values
+pub const WNOHANG: u64 = 1;
+
+/// Signals, with Linux's numbers. Signals run from 1 to NSIG - 1.
+pub const NSIG: usize = 32;
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+
+/// Handlers sigaction accepts besides the address of a function, with
+/// Linux's values
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// Clock IDs accepted by clock_gettime
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// Flags accepted by open, with Linux's values
+pub const O_CREAT: u64 = 0o100;
+pub const O_DIRECT: u64 = 0o40000;
+
+/// Protections and flags accepted by mmap, with Linux's values. Exactly
+/// one of MAP_SHARED and MAP_PRIVATE must be given.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const MAP_SHARED: u64 = 1;
+pub const MAP_PRIVATE: u64 = 2;
+
+/// Flags accepted by msync, with Linux's values
+pub const MS_ASYNC: u64 = 1;
+pub const MS_INVALIDATE: u64 = 2;
+pub const MS_SYNC: u64 = 4;
+
+/// Origins accepted by seek
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// Most bytes a single read or write transfers. Longer requests are cut
+/// short, and the caller sees a short count.
+pub const IO_MAX: usize = 64 * 1024;
+
+/// Special tv_nsec values accepted by utimensat
+pub const UTIME_NOW: i64 = (1 << 30) - 1;
+pub const UTIME_OMIT: i64 = (1 << 30) - 2;
+
+/// Commands accepted by reboot, with Linux's values
+pub const REBOOT_CMD_POWER_OFF: u64 = 0x4321_FEDC;
+pub const REBOOT_CMD_RESTART: u64 = 0x0123_4567;
+
+/// Longest path a syscall accepts, including the terminating NUL
+pub const PATH_MAX: usize = 4096;
+
+/// Longest buffer print accepts in one call
+pub const PRINT_MAX: usize = 4096;
+
+/// Error numbers. Syscalls return the negated value on failure.
+pub const EPERM: i64 = 1;
+pub const ENOENT: i64 = 2;
+pub const ESRCH: i64 = 3;
+pub const EIO: i64 = 5;
+pub const ENOEXEC: i64 = 8;
+pub const EBADF: i64 = 9;
+pub const ECHILD: i64 = 10;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EBUSY: i64 = 16;
+pub const EEXIST: i64 = 17;
+pub const ENODEV: i64 = 19;
+pub const EINVAL: i64 = 22;
+pub const EMFILE: i64 = 24;
+pub const ENOSPC: i64 = 28;
+pub const ESPIPE: i64 = 29;
+pub const EROFS: i64 = 30;
+pub const EPIPE: i64 = 32;
+pub const ENAMETOOLONG: i64 = 36;
+pub const ENOSYS: i64 = 38;
+pub const ENOTEMPTY: i64 = 39;
+pub const ELOOP: i64 = 40;
+pub const ETIMEDOUT: i64 = 110;
@@ -3,3 +3,6 @@
 /// CPU timer frequency in Hertz.
 /// Determines how often timer interrupts occur.
 pub const CPU_FREQUENCY: u32 = 100;
+
+/// Physical address of the first I/O APIC, where PC firmware puts it
+pub const IOAPIC_BASE: u64 = 0xFEC0_0000;
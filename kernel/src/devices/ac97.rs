@@ -0,0 +1,510 @@
+//! AC'97 audio driver.
+//!
+//! Plays signed 16 bit stereo PCM through the PCM out channel of an AC'97
+//! controller. Samples are copied into a ring of DMA buffers described by
+//! the controller's Buffer Descriptor List. Every buffer raises an
+//! interrupt once played, and the playback API sleeps on a wait queue the
+//! interrupt wakes whenever the ring is full. The controller has no MSI,
+//! so the interrupt comes through its PCI interrupt line; if that cannot be
+//! routed, playback wakes once a buffer's worth of time passes instead.
+//! The `audio` node of devfs writes to the same ring.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::port::Port,
+    structures::paging::{OffsetPageTable, PhysFrame},
+};
+
+use crate::{
+    arch::core_id,
+    constants::memory::PAGE_SIZE,
+    debug_println,
+    devices::{
+        dma::frame_ptr,
+        drivers::{find_device, DriverError, PciDriver, PciMatch},
+        manager::{find_device_data, DeviceClass, DeviceHandle, DeviceState, DEVICE_MANAGER},
+        pci::{read_config, write_pci_command, DeviceInfo, PCICommand},
+    },
+    events::futures::WaitQueue,
+    interrupts::ioapic,
+    memory::frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+    time,
+};
+
+/// Multimedia device class code
+const AUDIO_CLASS_CODE: u8 = 0x04;
+/// Multimedia audio controller subclass
+const AUDIO_SUB_CLASS: u8 = 0x01;
+
+/// Native Audio Mixer registers (BAR 0)
+const NAM_RESET: u16 = 0x00;
+const NAM_MASTER_VOLUME: u16 = 0x02;
+const NAM_PCM_OUT_VOLUME: u16 = 0x18;
+const NAM_EXTENDED_AUDIO_ID: u16 = 0x28;
+const NAM_EXTENDED_AUDIO_CONTROL: u16 = 0x2A;
+const NAM_FRONT_DAC_RATE: u16 = 0x2C;
+
+/// Native Audio Bus Master registers (BAR 1)
+const NABM_PCM_OUT: u16 = 0x10;
+const NABM_GLOBAL_CONTROL: u16 = 0x2C;
+
+/// Offsets within a bus master box
+const BOX_BDL_BASE: u16 = 0x00;
+const BOX_CURRENT_INDEX: u16 = 0x04;
+const BOX_LAST_VALID_INDEX: u16 = 0x05;
+const BOX_STATUS: u16 = 0x06;
+const BOX_CONTROL: u16 = 0x0B;
+
+/// Box control register bits
+const CONTROL_RUN: u8 = 1 << 0;
+const CONTROL_RESET: u8 = 1 << 1;
+/// Last valid buffer, FIFO error, and buffer completion interrupt enables
+const CONTROL_INTERRUPTS: u8 = 0b11100;
+
+/// Buffer descriptor control bit asking for an interrupt once played
+const DESCRIPTOR_INTERRUPT: u16 = 1 << 15;
+
+/// Box status register bits
+const STATUS_DMA_HALTED: u16 = 1 << 0;
+/// Last valid buffer, buffer completion, and FIFO error interrupts
+const STATUS_CLEAR: u16 = 0b11100;
+
+/// Global control: cold reset is active low
+const GLOBAL_CONTROL_COLD_RESET: u32 = 1 << 1;
+
+/// Variable rate audio bit of the extended audio registers
+const EXTENDED_AUDIO_VRA: u16 = 1;
+
+/// Number of entries in the Buffer Descriptor List, fixed by the hardware
+const BDL_ENTRIES: usize = 32;
+/// Number of 16 bit samples that fit in one DMA buffer
+const SAMPLES_PER_BUFFER: usize = PAGE_SIZE / size_of::<i16>();
+/// Sample rate used when the codec does not support variable rates
+pub const DEFAULT_SAMPLE_RATE: u16 = 48000;
+
+/// Maximum number of polling iterations while waiting on the controller
+const MAX_ITERATIONS: usize = 1_000_000;
+
+/// I/O port base of the bus master whose interrupts are handled, 0 if
+/// none. Kept apart from the `Ac97` so the handler takes no lock.
+static INTERRUPT_BUS_MASTER: AtomicU16 = AtomicU16::new(0);
+/// Woken whenever the controller finishes a buffer
+static PLAYBACK_WAITERS: WaitQueue = WaitQueue::new();
+/// Interrupts handled since boot
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+/// Represents errors that can occur while setting up or using the AC'97
+pub enum Ac97Error {
+    /// A frame could not be allocated for DMA
+    OutOfMemory,
+    /// A DMA frame was above 4 GiB, which the controller cannot address
+    DmaAddressTooHigh,
+    /// The controller did not finish resetting
+    Timeout,
+    /// The codec does not support the requested sample rate
+    UnsupportedSampleRate,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+/// An entry of the Buffer Descriptor List
+struct BufferDescriptor {
+    /// Physical address of the buffer
+    address: u32,
+    /// Number of 16 bit samples in the buffer
+    samples: u16,
+    /// Bit 15 is interrupt on completion, bit 14 is buffer underrun policy
+    control: u16,
+}
+
+/// An AC'97 controller with its PCM out ring
+pub struct Ac97 {
+    /// I/O port base of the Native Audio Mixer
+    mixer_base: u16,
+    /// I/O port base of the Native Audio Bus Master
+    bus_master_base: u16,
+    /// Frame holding the Buffer Descriptor List
+    bdl_frame: PhysFrame,
+    /// Frames backing each DMA buffer
+    buffers: Vec<PhysFrame>,
+    /// The next ring entry to fill
+    fill_index: usize,
+    /// Whether any buffer has been queued since the ring was reset
+    started: bool,
+    /// The sample rate the codec is running at
+    sample_rate: u16,
+}
+
+/// The AC'97 controllers this driver supports
+const AC97_ID_TABLE: &[PciMatch] = &[PciMatch::Class {
+    class_code: AUDIO_CLASS_CODE,
+    subclass: AUDIO_SUB_CLASS,
+    programming_interface: None,
+}];
+
+/// Registry entry for the AC'97 driver
+pub static AC97_DRIVER: PciDriver = PciDriver {
+    name: "AC'97",
+    id_table: AC97_ID_TABLE,
+    probe: probe_ac97,
+};
+
+/// Finds the FIRST AC'97 controller on the PCI bus
+pub fn find_ac97(devices: &[Arc<Mutex<DeviceInfo>>]) -> Option<Arc<Mutex<DeviceInfo>>> {
+    find_device(devices, AC97_ID_TABLE)
+}
+
+/// Probe function for the driver registry
+fn probe_ac97(
+    handle: DeviceHandle,
+    ac97_arc: &Arc<Mutex<DeviceInfo>>,
+    _mapper: &mut OffsetPageTable,
+) -> Result<(), DriverError> {
+    let ac97 = initialize_ac97(ac97_arc).map_err(DriverError::Ac97)?;
+    let mut manager = DEVICE_MANAGER.lock();
+    manager.set_state(handle, DeviceState::Active);
+    let output = manager.register("ac97 pcm out".into(), DeviceClass::Audio, Some(handle));
+    manager.bind(output, AC97_DRIVER.name);
+    manager.activate(output, ac97);
+    Result::Ok(())
+}
+
+/// Allocates a zeroed frame that the controller can address
+fn alloc_dma_frame() -> Result<PhysFrame, Ac97Error> {
+    let frame = alloc_frame_zeroed().ok_or(Ac97Error::OutOfMemory)?;
+    if frame.start_address().as_u64() + PAGE_SIZE as u64 > u64::from(u32::MAX) {
+        dealloc_frame(frame);
+        return Result::Err(Ac97Error::DmaAddressTooHigh);
+    }
+    Result::Ok(frame)
+}
+
+/// Sets up an AC'97 controller, returning the controller ready for playback
+pub fn initialize_ac97(ac97_arc: &Arc<Mutex<DeviceInfo>>) -> Result<Ac97, Ac97Error> {
+    let device = ac97_arc.lock();
+    let interrupt_line = device.interrupt_line();
+    let mixer_base =
+        (read_config(device.bus, device.device, device.function, 0x10) & 0xFFFC) as u16;
+    let bus_master_base =
+        (read_config(device.bus, device.device, device.function, 0x14) & 0xFFFC) as u16;
+    write_pci_command(
+        device.bus,
+        device.device,
+        device.function,
+        device.command | PCICommand::IO_SPACE | PCICommand::BUS_MASTER,
+    );
+
+    let bdl_frame = alloc_dma_frame()?;
+    let mut buffers = Vec::with_capacity(BDL_ENTRIES);
+    for _ in 0..BDL_ENTRIES {
+        match alloc_dma_frame() {
+            Ok(frame) => buffers.push(frame),
+            Err(e) => {
+                buffers.into_iter().for_each(dealloc_frame);
+                dealloc_frame(bdl_frame);
+                return Result::Err(e);
+            }
+        }
+    }
+
+    let mut ac97 = Ac97 {
+        mixer_base,
+        bus_master_base,
+        bdl_frame,
+        buffers,
+        fill_index: 0,
+        started: false,
+        sample_rate: DEFAULT_SAMPLE_RATE,
+    };
+
+    // Bring the codec out of cold reset and reset the mixer to defaults
+    ac97.write_bus_master::<u32>(NABM_GLOBAL_CONTROL, GLOBAL_CONTROL_COLD_RESET);
+    ac97.write_mixer(NAM_RESET, 1);
+    // 0 is full volume, unmuted
+    ac97.write_mixer(NAM_MASTER_VOLUME, 0);
+    ac97.write_mixer(NAM_PCM_OUT_VOLUME, 0);
+
+    ac97.reset_ring()?;
+    route_interrupt(interrupt_line, bus_master_base);
+    debug_println!(
+        "AC'97 initalized with mixer at {:#X} and bus master at {:#X}",
+        mixer_base,
+        bus_master_base
+    );
+
+    Result::Ok(ac97)
+}
+
+/// Quiets the controller and wakes playback. Called from the interrupt
+/// handler, including for interrupts from devices sharing the line.
+fn handle_ac97_interrupt() {
+    let bus_master_base = INTERRUPT_BUS_MASTER.load(Ordering::Relaxed);
+    if bus_master_base == 0 {
+        return;
+    }
+    let mut status = Port::<u16>::new(bus_master_base + NABM_PCM_OUT + BOX_STATUS);
+    let pending = unsafe { status.read() } & STATUS_CLEAR;
+    if pending == 0 {
+        return;
+    }
+    // The line is level triggered, so it must drop before the EOI
+    unsafe { status.write(pending) };
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    PLAYBACK_WAITERS.wake_all();
+}
+
+/// Routes the controller's PCI interrupt line to this core. Only the first
+/// controller gets its interrupt, as the handler knows of one.
+fn route_interrupt(line: Option<u8>, bus_master_base: u16) {
+    let Some(line) = line else {
+        return;
+    };
+    if INTERRUPT_BUS_MASTER
+        .compare_exchange(0, bus_master_base, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+    if !ioapic::handle_pci_irq(line, handle_ac97_interrupt, core_id()) {
+        debug_println!("Cannot route IRQ {}, AC'97 playback polls instead", line);
+        INTERRUPT_BUS_MASTER.store(0, Ordering::Release);
+    }
+}
+
+impl Ac97 {
+    fn read_mixer(&self, register: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.mixer_base + register).read() }
+    }
+
+    fn write_mixer(&self, register: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.mixer_base + register).write(value) }
+    }
+
+    fn read_bus_master<T: x86_64::instructions::port::PortRead>(&self, register: u16) -> T {
+        unsafe { Port::<T>::new(self.bus_master_base + register).read() }
+    }
+
+    fn write_bus_master<T: x86_64::instructions::port::PortWrite>(&self, register: u16, value: T) {
+        unsafe { Port::<T>::new(self.bus_master_base + register).write(value) }
+    }
+
+    fn bdl(&self) -> *mut BufferDescriptor {
+        frame_ptr(self.bdl_frame) as *mut BufferDescriptor
+    }
+
+    /// Stops playback, resets the PCM out channel, and points it at the
+    /// start of the Buffer Descriptor List
+    fn reset_ring(&mut self) -> Result<(), Ac97Error> {
+        self.write_bus_master::<u8>(NABM_PCM_OUT + BOX_CONTROL, 0);
+        self.write_bus_master::<u8>(NABM_PCM_OUT + BOX_CONTROL, CONTROL_RESET);
+        let mut reset = false;
+        for _ in 0..MAX_ITERATIONS {
+            if self.read_bus_master::<u8>(NABM_PCM_OUT + BOX_CONTROL) & CONTROL_RESET == 0 {
+                reset = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        if !reset {
+            return Result::Err(Ac97Error::Timeout);
+        }
+
+        self.write_bus_master::<u32>(
+            NABM_PCM_OUT + BOX_BDL_BASE,
+            self.bdl_frame.start_address().as_u64() as u32,
+        );
+        self.fill_index = 0;
+        self.started = false;
+        Result::Ok(())
+    }
+
+    /// Returns the sample rate the codec is playing at
+    pub fn sample_rate(&self) -> u16 {
+        self.sample_rate
+    }
+
+    /// Sets the playback sample rate. Codecs without variable rate audio
+    /// only support DEFAULT_SAMPLE_RATE.
+    pub fn set_sample_rate(&mut self, rate: u16) -> Result<(), Ac97Error> {
+        if self.read_mixer(NAM_EXTENDED_AUDIO_ID) & EXTENDED_AUDIO_VRA == 0 {
+            if rate != DEFAULT_SAMPLE_RATE {
+                return Result::Err(Ac97Error::UnsupportedSampleRate);
+            }
+            return Result::Ok(());
+        }
+        let control = self.read_mixer(NAM_EXTENDED_AUDIO_CONTROL);
+        self.write_mixer(NAM_EXTENDED_AUDIO_CONTROL, control | EXTENDED_AUDIO_VRA);
+        self.write_mixer(NAM_FRONT_DAC_RATE, rate);
+        if self.read_mixer(NAM_FRONT_DAC_RATE) != rate {
+            return Result::Err(Ac97Error::UnsupportedSampleRate);
+        }
+        self.sample_rate = rate;
+        Result::Ok(())
+    }
+
+    /// Sets the master volume, where 0 is loudest and 63 is quietest
+    pub fn set_volume(&mut self, attenuation: u8) {
+        let attenuation = u16::from(attenuation.min(63));
+        self.write_mixer(NAM_MASTER_VOLUME, (attenuation << 8) | attenuation);
+    }
+
+    /// Returns true if the controller has played every queued buffer
+    pub fn is_idle(&self) -> bool {
+        !self.started
+            || self.read_bus_master::<u16>(NABM_PCM_OUT + BOX_STATUS) & STATUS_DMA_HALTED != 0
+    }
+
+    /// Number of ring entries that are queued but not yet fully played
+    fn in_flight(&self) -> usize {
+        if self.is_idle() {
+            return 0;
+        }
+        let current = usize::from(self.read_bus_master::<u8>(NABM_PCM_OUT + BOX_CURRENT_INDEX));
+        (self.fill_index + BDL_ENTRIES - current) % BDL_ENTRIES
+    }
+
+    /// Returns true if no more samples can be queued until a buffer is
+    /// played. One entry is always kept free so a full ring can be told
+    /// apart from an empty one.
+    pub fn ring_full(&self) -> bool {
+        self.in_flight() >= BDL_ENTRIES - 1
+    }
+
+    /// How long the controller takes to play one full buffer
+    fn buffer_ns(&self) -> u64 {
+        // Samples are interleaved stereo, so two make up a frame
+        let frames = (SAMPLES_PER_BUFFER / 2) as u64;
+        frames * 1_000_000_000 / u64::from(self.sample_rate)
+    }
+
+    /// Copies as many samples as fit into free DMA buffers and starts the
+    /// channel if it was stopped.
+    ///
+    /// # Arguments
+    /// * `samples` - Interleaved signed 16 bit stereo samples
+    ///
+    /// # Returns
+    /// The number of samples that were queued
+    pub fn queue_samples(&mut self, samples: &[i16]) -> usize {
+        // Acknowledge any status so the controller can continue
+        self.write_bus_master::<u16>(NABM_PCM_OUT + BOX_STATUS, STATUS_CLEAR);
+
+        let halted = self.is_idle();
+        if halted && self.started {
+            // The channel ran dry, which leaves it stuck on the last
+            // valid entry, so start over from the head of the ring
+            if self.reset_ring().is_err() {
+                return 0;
+            }
+        }
+
+        let mut queued = 0;
+        while queued < samples.len() && !self.ring_full() {
+            let chunk = &samples[queued..samples.len().min(queued + SAMPLES_PER_BUFFER)];
+            let frame = self.buffers[self.fill_index];
+            let buffer = frame_ptr(frame) as *mut i16;
+            unsafe {
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), buffer, chunk.len());
+                core::ptr::write_volatile(
+                    self.bdl().add(self.fill_index),
+                    BufferDescriptor {
+                        address: frame.start_address().as_u64() as u32,
+                        samples: chunk.len() as u16,
+                        control: DESCRIPTOR_INTERRUPT,
+                    },
+                );
+            }
+            self.write_bus_master::<u8>(NABM_PCM_OUT + BOX_LAST_VALID_INDEX, self.fill_index as u8);
+            self.fill_index = (self.fill_index + 1) % BDL_ENTRIES;
+            self.started = true;
+            queued += chunk.len();
+        }
+
+        if queued > 0 {
+            let control = self.read_bus_master::<u8>(NABM_PCM_OUT + BOX_CONTROL);
+            self.write_bus_master::<u8>(
+                NABM_PCM_OUT + BOX_CONTROL,
+                control | CONTROL_RUN | CONTROL_INTERRUPTS,
+            );
+        }
+        queued
+    }
+
+    /// Stops playback and drops any queued samples
+    pub fn stop(&mut self) -> Result<(), Ac97Error> {
+        self.reset_ring()
+    }
+}
+
+/// Plays PCM samples on the FIRST AC'97 controller, sleeping until the
+/// controller finishes a buffer whenever the DMA ring is full. Completes
+/// once every sample has been handed to the controller.
+///
+/// # Arguments
+/// * `samples` - Interleaved signed 16 bit stereo samples
+pub async fn play(samples: Vec<i16>) {
+    let Some(ac97) = find_device_data::<Ac97>() else {
+        return;
+    };
+    let mut position = 0;
+    loop {
+        let buffer_ns = {
+            let mut ac97 = ac97.lock();
+            position += ac97.queue_samples(&samples[position..]);
+            ac97.buffer_ns()
+        };
+        if position >= samples.len() {
+            return;
+        }
+        // The deadline stands in for an interrupt that was not routed
+        let deadline = time::monotonic_ns().saturating_add(buffer_ns);
+        PLAYBACK_WAITERS
+            .wait_until_deadline(deadline, || !ac97.lock().ring_full())
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::futures::run_to_completion;
+    use alloc::vec;
+
+    #[test_case]
+    fn test_playback() {
+        let ac97 = find_device_data::<Ac97>().expect("No AC'97 found");
+        ac97.lock().stop().unwrap();
+        assert!(ac97.lock().is_idle());
+        let rate = ac97.lock().sample_rate();
+        match ac97.lock().set_sample_rate(44100) {
+            Ok(()) => assert_eq!(ac97.lock().sample_rate(), 44100),
+            Err(Ac97Error::UnsupportedSampleRate) => assert_eq!(ac97.lock().sample_rate(), rate),
+            Err(e) => panic!("Unexpected error {:?}", e),
+        }
+
+        // More than the ring holds, so playback sleeps until the controller
+        // finishes a buffer
+        let interrupts = INTERRUPTS.load(Ordering::Relaxed);
+        run_to_completion(play(vec![0; BDL_ENTRIES * SAMPLES_PER_BUFFER]));
+        assert!(!ac97.lock().is_idle());
+        if INTERRUPT_BUS_MASTER.load(Ordering::Relaxed) != 0 {
+            // The interrupt may land just after the buffer was seen done
+            let deadline = time::monotonic_ns() + 2 * ac97.lock().buffer_ns();
+            while INTERRUPTS.load(Ordering::Relaxed) == interrupts
+                && time::monotonic_ns() < deadline
+            {
+                core::hint::spin_loop();
+            }
+            assert!(INTERRUPTS.load(Ordering::Relaxed) > interrupts);
+        }
+
+        let mut ac97 = ac97.lock();
+        ac97.stop().unwrap();
+        assert!(ac97.is_idle());
+        assert!(!ac97.ring_full());
+        ac97.set_sample_rate(DEFAULT_SAMPLE_RATE).unwrap();
+    }
+}
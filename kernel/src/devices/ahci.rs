@@ -0,0 +1,634 @@
+//! AHCI SATA driver.
+//!
+//! Finds an AHCI controller on the PCI bus, maps its registers (ABAR, BAR
+//! 5), and brings up every port with a SATA disk attached. Each disk is
+//! registered with the device manager as a block device, so filesystems
+//! can be put on it like on the sd card.
+//!
+//! Only command slot 0 of a port is used, and commands are polled to
+//! completion, since the controller's interrupts are not routed yet. Data
+//! moves through a fixed set of DMA frames per port, one PRDT entry each.
+
+use alloc::{format, sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::structures::paging::{OffsetPageTable, PhysFrame};
+
+use crate::{
+    constants::memory::PAGE_SIZE,
+    debug_println,
+    devices::{
+        dma::{for_each_buffer, frame_ptr},
+        drivers::{find_device, DriverError, PciDriver, PciMatch},
+        manager::{DeviceClass, DeviceHandle, DeviceState, DEVICE_MANAGER},
+        pci::{write_pci_command, BarError, DeviceInfo, PCICommand},
+    },
+    filesys::{
+        block::stats::{DiskStats, IoDirection},
+        BlockDevice, FsError,
+    },
+    memory::frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+    processes::rusage::account_block_io,
+};
+
+/// Mass storage class code
+const STORAGE_CLASS_CODE: u8 = 0x01;
+/// SATA controller subclass
+const SATA_SUB_CLASS: u8 = 0x06;
+/// Programming interface of an AHCI 1.0 controller
+const AHCI_INTERFACE: u8 = 0x01;
+
+/// The BAR holding the controller's registers
+const ABAR: u8 = 5;
+/// Size of the generic host control registers and all 32 ports
+const ABAR_LENGTH: u64 = 0x1100;
+
+/// Generic host control registers
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0C;
+
+/// Supports 64 bit addressing
+const CAP_S64A: u32 = 1 << 31;
+/// AHCI enable
+const GHC_AE: u32 = 1 << 31;
+/// HBA reset, cleared by the controller once done
+const GHC_HR: u32 = 1;
+
+/// Start of the first port's registers, and the space each port takes
+const PORT_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+
+/// Port registers
+const PORT_CLB: u64 = 0x00;
+const PORT_CLBU: u64 = 0x04;
+const PORT_FB: u64 = 0x08;
+const PORT_FBU: u64 = 0x0C;
+const PORT_IS: u64 = 0x10;
+const PORT_IE: u64 = 0x14;
+const PORT_CMD: u64 = 0x18;
+const PORT_TFD: u64 = 0x20;
+const PORT_SIG: u64 = 0x24;
+const PORT_SSTS: u64 = 0x28;
+const PORT_SERR: u64 = 0x30;
+const PORT_CI: u64 = 0x38;
+
+/// Port command bits
+const CMD_ST: u32 = 1;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+/// Task file error status of the port interrupt status register
+const IS_TFES: u32 = 1 << 30;
+/// Task file status bits
+const TFD_ERR: u32 = 1;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// SStatus device detection value of a device with an established link
+const SSTS_DET_PRESENT: u32 = 3;
+/// SStatus power management value of an active link
+const SSTS_IPM_ACTIVE: u32 = 1;
+/// Signature of a SATA disk, as opposed to ATAPI or a port multiplier
+const SATA_SIGNATURE: u32 = 0x0000_0101;
+
+/// Where the received FIS area sits in the command list frame
+const FIS_AREA_OFFSET: u64 = 0x400;
+/// Where the PRDT starts in a command table
+const PRDT_OFFSET: usize = 0x80;
+/// Length of a register FIS in dwords
+const H2D_FIS_DWORDS: u16 = 5;
+/// Command header bit for commands that write to the device
+const HEADER_WRITE: u16 = 1 << 6;
+
+/// Frame information structure type of a host to device register FIS
+const FIS_TYPE_H2D: u8 = 0x27;
+/// Register FIS flag marking it as a command
+const FIS_COMMAND: u8 = 1 << 7;
+/// Device register bit selecting LBA addressing
+const DEVICE_LBA: u8 = 1 << 6;
+
+/// ATA commands
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+/// Logical sector size. Disks with larger sectors are not supported.
+const SECTOR_SIZE: usize = 512;
+/// DMA frames of each port, which bounds the sectors one command moves
+const DMA_FRAMES: usize = 16;
+const MAX_SECTORS_PER_COMMAND: usize = DMA_FRAMES * PAGE_SIZE / SECTOR_SIZE;
+
+/// Maximum number of polling iterations while waiting on the controller
+const MAX_ITERATIONS: usize = 1_000_000;
+/// Polls given to a port's link to come up after a reset
+const LINK_ITERATIONS: usize = 10_000;
+
+#[derive(Debug)]
+/// Represents errors that can occur while setting up or using an AHCI
+/// controller
+pub enum AhciError {
+    /// ABAR could not be mapped
+    Bar(BarError),
+    /// A frame could not be allocated for DMA
+    OutOfMemory,
+    /// The controller or a port did not respond in time
+    Timeout,
+    /// The disk reported an error in its task file
+    TaskFileError,
+    /// A request was outside the disk
+    OutOfRange,
+}
+
+/// A command header of a port's command list
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CommandHeader {
+    /// FIS length in dwords and the command's direction
+    flags: u16,
+    /// Number of PRDT entries in the command table
+    prdt_length: u16,
+    /// Bytes moved, updated by the controller
+    transferred: u32,
+    /// Physical address of the command table, 128 byte aligned
+    table: u64,
+    reserved: [u32; 4],
+}
+
+/// An entry of a command table's physical region descriptor table
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct PrdEntry {
+    address: u64,
+    reserved: u32,
+    /// Bytes to move minus one
+    byte_count: u32,
+}
+
+/// A port of the controller with a SATA disk attached
+struct AhciPort {
+    /// Kernel virtual address of the port's registers
+    registers: u64,
+    /// Holds the command list, then the received FIS area
+    command_list: PhysFrame,
+    /// Command table of slot 0, the only slot used
+    command_table: PhysFrame,
+    /// Frames data moves through
+    buffers: Vec<PhysFrame>,
+}
+
+/// A SATA disk behind an AHCI port. Clones share the port, and each command
+/// holds it until done.
+#[derive(Clone)]
+pub struct AhciDisk {
+    port: Arc<Mutex<AhciPort>>,
+    sectors: u64,
+    stats: Arc<DiskStats>,
+}
+
+/// The AHCI controllers this driver supports
+const AHCI_ID_TABLE: &[PciMatch] = &[PciMatch::Class {
+    class_code: STORAGE_CLASS_CODE,
+    subclass: SATA_SUB_CLASS,
+    programming_interface: Some(AHCI_INTERFACE),
+}];
+
+/// Registry entry for the AHCI driver
+pub static AHCI_DRIVER: PciDriver = PciDriver {
+    name: "AHCI",
+    id_table: AHCI_ID_TABLE,
+    probe: probe_ahci,
+};
+
+/// Finds the FIRST AHCI controller on the PCI bus
+pub fn find_ahci(devices: &[Arc<Mutex<DeviceInfo>>]) -> Option<Arc<Mutex<DeviceInfo>>> {
+    find_device(devices, AHCI_ID_TABLE)
+}
+
+/// Probe function for the driver registry. Every disk found is registered
+/// as a block device behind the controller.
+fn probe_ahci(
+    handle: DeviceHandle,
+    ahci_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), DriverError> {
+    let disks = initialize_ahci(ahci_arc, mapper).map_err(DriverError::Ahci)?;
+    let mut manager = DEVICE_MANAGER.lock();
+    manager.set_state(handle, DeviceState::Active);
+    for (port, disk) in disks {
+        let name = format!("sata disk {}", port);
+        let node = manager.register(name, DeviceClass::Block, Some(handle));
+        manager.bind(node, AHCI_DRIVER.name);
+        manager.activate(node, disk);
+    }
+    Result::Ok(())
+}
+
+fn read_register(address: u64) -> u32 {
+    unsafe { core::ptr::read_volatile(address as *const u32) }
+}
+
+fn write_register(address: u64, value: u32) {
+    unsafe { core::ptr::write_volatile(address as *mut u32, value) }
+}
+
+/// Polls a register until `done` holds for its value
+fn wait_register(address: u64, iterations: usize, done: impl Fn(u32) -> bool) -> bool {
+    for _ in 0..iterations {
+        if done(read_register(address)) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Allocates a zeroed frame the controller can address
+fn alloc_dma_frame(addresses_64: bool) -> Result<PhysFrame, AhciError> {
+    let frame = alloc_frame_zeroed().ok_or(AhciError::OutOfMemory)?;
+    if !addresses_64 && frame.start_address().as_u64() + PAGE_SIZE as u64 > u64::from(u32::MAX) {
+        dealloc_frame(frame);
+        return Result::Err(AhciError::OutOfMemory);
+    }
+    Result::Ok(frame)
+}
+
+/// Sets up an AHCI controller, returning the disks found on its ports
+/// along with the port numbers they are on
+pub fn initialize_ahci(
+    ahci_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<Vec<(u32, AhciDisk)>, AhciError> {
+    let device = ahci_arc.lock();
+    let abar = device
+        .bar(ABAR)
+        .ok_or(BarError::Missing)
+        .and_then(|bar| bar.map(mapper, 0, ABAR_LENGTH))
+        .map_err(AhciError::Bar)?;
+    write_pci_command(
+        device.bus,
+        device.device,
+        device.function,
+        device.command | PCICommand::MEMORY_SPACE | PCICommand::BUS_MASTER,
+    );
+
+    // Reset the controller so no port is left running from firmware
+    write_register(abar + HBA_GHC, GHC_AE);
+    write_register(abar + HBA_GHC, GHC_AE | GHC_HR);
+    if !wait_register(abar + HBA_GHC, MAX_ITERATIONS, |ghc| ghc & GHC_HR == 0) {
+        return Result::Err(AhciError::Timeout);
+    }
+    // Commands are polled, so interrupts stay off
+    write_register(abar + HBA_GHC, GHC_AE);
+    write_register(abar + HBA_IS, u32::MAX);
+
+    let addresses_64 = read_register(abar + HBA_CAP) & CAP_S64A != 0;
+    let implemented = read_register(abar + HBA_PI);
+    let mut disks = Vec::new();
+    for port in (0..32).filter(|port| implemented & (1 << port) != 0) {
+        let registers = abar + PORT_BASE + u64::from(port) * PORT_SIZE;
+        if !disk_attached(registers) {
+            continue;
+        }
+        match AhciPort::new(registers, addresses_64).and_then(AhciDisk::new) {
+            Ok(disk) => {
+                debug_println!("AHCI port {} has a disk of {} sectors", port, disk.sectors);
+                disks.push((port, disk));
+            }
+            Err(e) => {
+                debug_println!("AHCI port {} failed to start: {:?}", port, e);
+            }
+        }
+    }
+    Result::Ok(disks)
+}
+
+/// Returns true if a SATA disk has an active link on the port
+fn disk_attached(registers: u64) -> bool {
+    let linked = wait_register(registers + PORT_SSTS, LINK_ITERATIONS, |ssts| {
+        ssts & 0xF == SSTS_DET_PRESENT
+    });
+    let ssts = read_register(registers + PORT_SSTS);
+    linked
+        && (ssts >> 8) & 0xF == SSTS_IPM_ACTIVE
+        && read_register(registers + PORT_SIG) == SATA_SIGNATURE
+}
+
+/// Builds a host to device register FIS for an LBA48 command
+fn h2d_fis(command: u8, lba: u64, count: u16) -> [u8; 20] {
+    let lba = lba.to_le_bytes();
+    let count = count.to_le_bytes();
+    let mut fis = [0; 20];
+    fis[0] = FIS_TYPE_H2D;
+    fis[1] = FIS_COMMAND;
+    fis[2] = command;
+    fis[4..7].copy_from_slice(&lba[0..3]);
+    fis[7] = DEVICE_LBA;
+    fis[8..11].copy_from_slice(&lba[3..6]);
+    fis[12..14].copy_from_slice(&count);
+    fis
+}
+
+impl AhciPort {
+    /// Points a stopped port at freshly allocated command structures and
+    /// starts it
+    fn new(registers: u64, addresses_64: bool) -> Result<Self, AhciError> {
+        // Frames are only owned once the port holds them, so its Drop
+        // frees them on every error below
+        let mut port = AhciPort {
+            registers,
+            command_list: alloc_dma_frame(addresses_64)?,
+            command_table: alloc_dma_frame(addresses_64)?,
+            buffers: Vec::with_capacity(DMA_FRAMES),
+        };
+        for _ in 0..DMA_FRAMES {
+            port.buffers.push(alloc_dma_frame(addresses_64)?);
+        }
+        port.stop()?;
+
+        let command_list = port.command_list.start_address().as_u64();
+        let fis_area = command_list + FIS_AREA_OFFSET;
+        port.write(PORT_CLB, command_list as u32);
+        port.write(PORT_CLBU, (command_list >> 32) as u32);
+        port.write(PORT_FB, fis_area as u32);
+        port.write(PORT_FBU, (fis_area >> 32) as u32);
+        port.write(PORT_IE, 0);
+        port.write(PORT_SERR, u32::MAX);
+        port.write(PORT_IS, u32::MAX);
+
+        port.write(PORT_CMD, port.read(PORT_CMD) | CMD_FRE | CMD_SUD | CMD_POD);
+        if !wait_register(registers + PORT_TFD, MAX_ITERATIONS, |tfd| {
+            tfd & (TFD_BSY | TFD_DRQ) == 0
+        }) {
+            return Result::Err(AhciError::Timeout);
+        }
+        port.write(PORT_CMD, port.read(PORT_CMD) | CMD_ST);
+        Result::Ok(port)
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        read_register(self.registers + register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        write_register(self.registers + register, value)
+    }
+
+    /// Stops command processing and FIS receiving, waiting for the port to
+    /// go idle
+    fn stop(&self) -> Result<(), AhciError> {
+        self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_ST);
+        if !wait_register(self.registers + PORT_CMD, MAX_ITERATIONS, |cmd| {
+            cmd & CMD_CR == 0
+        }) {
+            return Result::Err(AhciError::Timeout);
+        }
+        self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_FRE);
+        if !wait_register(self.registers + PORT_CMD, MAX_ITERATIONS, |cmd| {
+            cmd & CMD_FR == 0
+        }) {
+            return Result::Err(AhciError::Timeout);
+        }
+        Result::Ok(())
+    }
+
+    /// Issues a command in slot 0 and polls it to completion. Data of
+    /// `sectors` sectors moves through the start of the DMA frames.
+    fn issue(&self, command: u8, lba: u64, sectors: u16, write: bool) -> Result<(), AhciError> {
+        let bytes = usize::from(sectors) * SECTOR_SIZE;
+        let entries = bytes.div_ceil(PAGE_SIZE);
+
+        let table = frame_ptr(self.command_table);
+        let fis = h2d_fis(command, lba, sectors);
+        unsafe {
+            core::ptr::copy_nonoverlapping(fis.as_ptr(), table, fis.len());
+            let prdt = table.add(PRDT_OFFSET) as *mut PrdEntry;
+            for (i, frame) in self.buffers[..entries].iter().enumerate() {
+                let length = (bytes - i * PAGE_SIZE).min(PAGE_SIZE);
+                core::ptr::write_volatile(
+                    prdt.add(i),
+                    PrdEntry {
+                        address: frame.start_address().as_u64(),
+                        reserved: 0,
+                        byte_count: length as u32 - 1,
+                    },
+                );
+            }
+            core::ptr::write_volatile(
+                frame_ptr(self.command_list) as *mut CommandHeader,
+                CommandHeader {
+                    flags: H2D_FIS_DWORDS | if write { HEADER_WRITE } else { 0 },
+                    prdt_length: entries as u16,
+                    transferred: 0,
+                    table: self.command_table.start_address().as_u64(),
+                    reserved: [0; 4],
+                },
+            );
+        }
+
+        self.write(PORT_IS, u32::MAX);
+        self.write(PORT_CI, 1);
+        let mut done = false;
+        for _ in 0..MAX_ITERATIONS {
+            if self.read(PORT_IS) & IS_TFES != 0 {
+                break;
+            }
+            if self.read(PORT_CI) & 1 == 0 {
+                done = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        if self.read(PORT_TFD) & TFD_ERR != 0 || self.read(PORT_IS) & IS_TFES != 0 {
+            // The port stops itself on a task file error, so restart it
+            // for the next command
+            self.stop()?;
+            self.write(PORT_SERR, u32::MAX);
+            self.write(PORT_IS, u32::MAX);
+            self.write(PORT_CMD, self.read(PORT_CMD) | CMD_FRE | CMD_ST);
+            return Result::Err(AhciError::TaskFileError);
+        }
+        if !done {
+            return Result::Err(AhciError::Timeout);
+        }
+        Result::Ok(())
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        let sectors = (buf.len() / SECTOR_SIZE) as u16;
+        self.issue(ATA_READ_DMA_EXT, lba, sectors, false)?;
+        for_each_buffer(&self.buffers, buf.len(), |frame, start, len| unsafe {
+            core::ptr::copy_nonoverlapping(frame, buf[start..].as_mut_ptr(), len);
+        });
+        Result::Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), AhciError> {
+        for_each_buffer(&self.buffers, buf.len(), |frame, start, len| unsafe {
+            core::ptr::copy_nonoverlapping(buf[start..].as_ptr(), frame, len);
+        });
+        let sectors = (buf.len() / SECTOR_SIZE) as u16;
+        self.issue(ATA_WRITE_DMA_EXT, lba, sectors, true)
+    }
+}
+
+impl Drop for AhciPort {
+    fn drop(&mut self) {
+        let _ = self.stop();
+        dealloc_frame(self.command_list);
+        dealloc_frame(self.command_table);
+        self.buffers.drain(..).for_each(dealloc_frame);
+    }
+}
+
+impl AhciDisk {
+    /// Identifies the disk on a started port
+    fn new(port: AhciPort) -> Result<Self, AhciError> {
+        port.issue(ATA_IDENTIFY, 0, 1, false)?;
+        let mut identify = [0u16; SECTOR_SIZE / 2];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                frame_ptr(port.buffers[0]) as *const u16,
+                identify.as_mut_ptr(),
+                identify.len(),
+            )
+        };
+        let word = |i: usize| u64::from(identify[i]);
+        // Word 83 bit 10 is set if the disk supports LBA48
+        let sectors = if identify[83] & (1 << 10) != 0 {
+            word(100) | word(101) << 16 | word(102) << 32 | word(103) << 48
+        } else {
+            word(60) | word(61) << 16
+        };
+        Result::Ok(AhciDisk {
+            port: Arc::new(Mutex::new(port)),
+            sectors,
+            stats: DiskStats::register("ahci"),
+        })
+    }
+
+    /// Checks a request of `len` bytes from `lba` lies inside the disk
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), AhciError> {
+        let sectors = (len / SECTOR_SIZE) as u64;
+        if len % SECTOR_SIZE != 0 || lba.saturating_add(sectors) > self.sectors {
+            return Result::Err(AhciError::OutOfRange);
+        }
+        Result::Ok(())
+    }
+
+    /// Reads whole sectors starting at `lba`, as many per command as the
+    /// DMA frames hold
+    pub fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        self.check_range(lba, buf.len())?;
+        let port = self.port.lock();
+        for (i, chunk) in buf
+            .chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE)
+            .enumerate()
+        {
+            port.read_sectors(lba + (i * MAX_SECTORS_PER_COMMAND) as u64, chunk)?;
+        }
+        Result::Ok(())
+    }
+
+    /// Writes whole sectors starting at `lba`, like `read_sectors`
+    pub fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), AhciError> {
+        self.check_range(lba, buf.len())?;
+        let port = self.port.lock();
+        for (i, chunk) in buf
+            .chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE)
+            .enumerate()
+        {
+            port.write_sectors(lba + (i * MAX_SECTORS_PER_COMMAND) as u64, chunk)?;
+        }
+        Result::Ok(())
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.read_blocks(block_num, buf)
+    }
+
+    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.write_blocks(block_num, buf)
+    }
+
+    fn read_blocks(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let sectors = (buf.len() / SECTOR_SIZE) as u64;
+        self.stats
+            .record(IoDirection::Read, sectors, || {
+                self.read_sectors(block_num, buf)
+            })
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(sectors, 0);
+        Result::Ok(())
+    }
+
+    fn write_blocks(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let sectors = (buf.len() / SECTOR_SIZE) as u64;
+        let stats = self.stats.clone();
+        stats
+            .record(IoDirection::Write, sectors, || {
+                self.write_sectors(block_num, buf)
+            })
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(0, sectors);
+        Result::Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.port
+            .lock()
+            .issue(ATA_FLUSH_CACHE_EXT, 0, 0, false)
+            .map_err(|_| FsError::IOError)
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.sectors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::manager::find_device_data;
+
+    #[test_case]
+    fn test_h2d_fis() {
+        let fis = h2d_fis(ATA_READ_DMA_EXT, 0x0605_0403_0201, 0x0180);
+        assert_eq!(fis[..4], [FIS_TYPE_H2D, FIS_COMMAND, ATA_READ_DMA_EXT, 0]);
+        assert_eq!(fis[4..7], [0x01, 0x02, 0x03]);
+        assert_eq!(fis[7], DEVICE_LBA);
+        assert_eq!(fis[8..11], [0x04, 0x05, 0x06]);
+        assert_eq!(fis[12..14], [0x80, 0x01]);
+    }
+
+    #[test_case]
+    fn test_disk_round_trip() {
+        let mut disk = find_device_data::<AhciDisk>()
+            .expect("No SATA disk found")
+            .lock()
+            .clone();
+        // Spans more than one command's worth of DMA frames
+        let sectors = MAX_SECTORS_PER_COMMAND + 3;
+        let lba = disk.total_blocks() - sectors as u64;
+        let data: Vec<u8> = (0..sectors * SECTOR_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        disk.write_blocks(lba, &data).unwrap();
+        disk.flush().unwrap();
+
+        let mut read = alloc::vec![0; data.len()];
+        disk.read_blocks(lba, &mut read).unwrap();
+        assert!(read == data);
+        assert!(disk.read_blocks(disk.total_blocks(), &mut read).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+//! Helpers for the frames drivers hand to devices for DMA.
+//!
+//! Devices read and write DMA frames by physical address, while the kernel
+//! reaches them through the higher half direct map. Transfers larger than
+//! a page are spread over several frames that need not be contiguous.
+
+use x86_64::structures::paging::PhysFrame;
+
+use crate::{constants::memory::PAGE_SIZE, memory::HHDM_OFFSET};
+
+/// Kernel address of a frame's contents
+pub fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (HHDM_OFFSET.as_u64() + frame.start_address().as_u64()) as *mut u8
+}
+
+/// Calls `f` with each of `frames` holding the first `len` bytes of a
+/// transfer, along with the offset and length of its part
+pub fn for_each_buffer(frames: &[PhysFrame], len: usize, mut f: impl FnMut(*mut u8, usize, usize)) {
+    for (i, frame) in frames.iter().enumerate() {
+        let start = i * PAGE_SIZE;
+        if start >= len {
+            break;
+        }
+        f(frame_ptr(*frame), start, (len - start).min(PAGE_SIZE));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use x86_64::PhysAddr;
+
+    use super::*;
+
+    #[test_case]
+    fn test_buffer_parts() {
+        let frames: Vec<_> = (1..=3)
+            .map(|n| PhysFrame::containing_address(PhysAddr::new(n * PAGE_SIZE as u64)))
+            .collect();
+        let parts = |len| {
+            let mut parts = Vec::new();
+            for_each_buffer(&frames, len, |ptr, start, len| {
+                parts.push((ptr, start, len))
+            });
+            parts
+        };
+
+        assert!(parts(0).is_empty());
+        assert_eq!(parts(512), [(frame_ptr(frames[0]), 0, 512)]);
+        // A transfer ending partway through a frame uses only what it needs
+        assert_eq!(
+            parts(PAGE_SIZE + 512),
+            [
+                (frame_ptr(frames[0]), 0, PAGE_SIZE),
+                (frame_ptr(frames[1]), PAGE_SIZE, 512)
+            ]
+        );
+        // Bytes beyond the last frame are left out
+        assert_eq!(parts(4 * PAGE_SIZE).len(), 3);
+    }
+}
@@ -0,0 +1,150 @@
+//! PCI driver registry.
+//!
+//! Every PCI driver declares a table of the devices it can drive and a
+//! probe function. After the PCI bus is walked, each device is bound to the
+//! first registered driver whose table matches it. Devices without a driver
+//! are left alone.
+
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+
+use crate::serial_println;
+
+use super::{
+    ac97::Ac97Error,
+    ahci::AhciError,
+    manager::{DeviceHandle, DeviceState, DEVICE_MANAGER},
+    nvme::NvmeError,
+    pci::DeviceInfo,
+    sd_card::SDCardError,
+    virtio::VirtioError,
+};
+
+/// Describes a set of PCI devices a driver can bind to
+#[derive(Debug, Clone, Copy)]
+pub enum PciMatch {
+    /// Matches on the class code, subclass, and optionally the programming
+    /// interface
+    Class {
+        class_code: u8,
+        subclass: u8,
+        programming_interface: Option<u8>,
+    },
+    /// Matches a specific vendor and device ID
+    Device { vendor_id: u16, device_id: u16 },
+}
+
+impl PciMatch {
+    /// Returns true if the device is described by this entry
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        match *self {
+            PciMatch::Class {
+                class_code,
+                subclass,
+                programming_interface,
+            } => {
+                device.class_code == class_code
+                    && device.subclass == subclass
+                    && programming_interface
+                        .is_none_or(|interface| interface == device.programming_interface)
+            }
+            PciMatch::Device {
+                vendor_id,
+                device_id,
+            } => device.vendor_id == vendor_id && device.device_id == device_id,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Errors a driver can report when probing a device
+pub enum DriverError {
+    /// The driver only supports one device and is already bound
+    AlreadyBound,
+    /// The device or its driver state could not be found
+    NotFound,
+    /// The device did not respond in time
+    Timeout,
+    SdCard(SDCardError),
+    Virtio(VirtioError),
+    Ac97(Ac97Error),
+    Ahci(AhciError),
+    Nvme(NvmeError),
+}
+
+/// Sets up a matched device. Called with the kernel mapper held so drivers
+/// can map their registers. On success the driver marks the device Active
+/// in the device manager and registers anything it found behind it.
+pub type ProbeFn =
+    fn(DeviceHandle, &Arc<Mutex<DeviceInfo>>, &mut OffsetPageTable) -> Result<(), DriverError>;
+
+/// A driver for PCI devices
+pub struct PciDriver {
+    /// Name used when reporting bindings
+    pub name: &'static str,
+    /// The devices this driver supports
+    pub id_table: &'static [PciMatch],
+    /// Called for each device that matches id_table
+    pub probe: ProbeFn,
+}
+
+impl PciDriver {
+    /// Returns true if any entry of the id table matches the device
+    pub fn supports(&self, device: &DeviceInfo) -> bool {
+        self.id_table.iter().any(|entry| entry.matches(device))
+    }
+}
+
+/// Finds the FIRST device in `devices` that matches any entry of `id_table`
+pub fn find_device(
+    devices: &[Arc<Mutex<DeviceInfo>>],
+    id_table: &[PciMatch],
+) -> Option<Arc<Mutex<DeviceInfo>>> {
+    devices
+        .iter()
+        .find(|device| {
+            let device = device.lock();
+            id_table.iter().any(|entry| entry.matches(&device))
+        })
+        .cloned()
+}
+
+/// Binds every device to the first driver that supports it. Probe failures
+/// are reported but do not stop other devices from being bound.
+///
+/// # Arguments
+/// * `devices` - Handles of PCI functions registered with the device manager
+/// * `drivers` - The registered drivers, in order of preference
+/// * `mapper` - The kernel mapper
+pub fn bind_pci_drivers(
+    devices: &[DeviceHandle],
+    drivers: &[&PciDriver],
+    mapper: &mut OffsetPageTable,
+) {
+    for &handle in devices {
+        let Some(info) = DEVICE_MANAGER
+            .lock()
+            .get(handle)
+            .and_then(|node| node.pci.clone())
+        else {
+            continue;
+        };
+        let driver = {
+            let info = info.lock();
+            drivers.iter().find(|driver| driver.supports(&info))
+        };
+        let Some(driver) = driver else {
+            continue;
+        };
+
+        DEVICE_MANAGER.lock().bind(handle, driver.name);
+        match (driver.probe)(handle, &info, mapper) {
+            Ok(()) => serial_println!("{} initalized", driver.name),
+            Err(e) => {
+                DEVICE_MANAGER.lock().set_state(handle, DeviceState::Failed);
+                serial_println!("{} failed to initalize: {:?}", driver.name, e);
+            }
+        }
+    }
+}
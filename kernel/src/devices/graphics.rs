@@ -0,0 +1,296 @@
+//! Double buffered drawing on the boot framebuffer.
+//!
+//! `Screen` draws into a back buffer on the kernel heap and copies it to the
+//! framebuffer only when `present` is called, so a frame is never shown
+//! half drawn. Pixels are 32 bit 0x00RRGGBB values, the layout Limine
+//! reports for the usual 32 bpp modes. Every drawing primitive clips to
+//! the screen, so callers can draw partly off screen.
+//!
+//! The framebuffer Limine sets up is registered with the device manager
+//! as a display once devices are initialized, and `screen` finds it there.
+
+use alloc::{format, sync::Arc, vec, vec::Vec};
+use core::ptr;
+
+use limine::{framebuffer::Framebuffer, request::FramebufferRequest};
+use spin::Mutex;
+
+use crate::{
+    devices::manager::{find_device_data, DeviceClass, DEVICE_MANAGER},
+    serial_println,
+};
+
+/// Framebuffer request to the bootloader.
+/// Used to get access to video output capabilities.
+#[used]
+#[link_section = ".requests"]
+static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
+
+pub const BLACK: u32 = 0x000000;
+pub const WHITE: u32 = 0xFFFFFF;
+
+/// A rectangle in pixels. Coordinates are signed so shapes may start off
+/// screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Returns the part of the rectangle inside a `width` by `height` area
+    /// at the origin, as (x, y, width, height), or None if nothing is
+    fn clip(&self, width: usize, height: usize) -> Option<(usize, usize, usize, usize)> {
+        let left = (self.x as i64).clamp(0, width as i64);
+        let top = (self.y as i64).clamp(0, height as i64);
+        let right = (self.x as i64 + self.width as i64).clamp(0, width as i64);
+        let bottom = (self.y as i64 + self.height as i64).clamp(0, height as i64);
+        if left >= right || top >= bottom {
+            return None;
+        }
+        Some((
+            left as usize,
+            top as usize,
+            (right - left) as usize,
+            (bottom - top) as usize,
+        ))
+    }
+}
+
+/// The mapped memory `present` copies to
+struct FrontBuffer {
+    addr: *mut u8,
+    /// Bytes from the start of one row to the next
+    pitch: usize,
+}
+
+/// A drawing surface with a back buffer, optionally shown on a framebuffer
+pub struct Screen {
+    width: usize,
+    height: usize,
+    /// Row major pixels, `width` to a row
+    back: Vec<u32>,
+    front: Option<FrontBuffer>,
+}
+
+// The framebuffer is only written through the `Screen` that owns it
+unsafe impl Send for Screen {}
+
+impl Screen {
+    /// Creates a screen that is never shown, for drawing off screen
+    pub fn new(width: usize, height: usize) -> Self {
+        Screen {
+            width,
+            height,
+            back: vec![BLACK; width * height],
+            front: None,
+        }
+    }
+
+    /// Creates a screen shown on the framebuffer at `addr`
+    ///
+    /// # Safety
+    /// `addr` must point to mapped, writable memory of `height` rows of
+    /// `pitch` bytes, each starting with `width` 32 bit pixels, that
+    /// nothing else writes to while the screen exists.
+    pub unsafe fn from_raw(addr: *mut u8, width: usize, height: usize, pitch: usize) -> Self {
+        assert!(pitch >= width * 4, "Rows overlap");
+        Screen {
+            front: Some(FrontBuffer { addr, pitch }),
+            ..Screen::new(width, height)
+        }
+    }
+
+    /// Creates a screen shown on a framebuffer from the bootloader
+    ///
+    /// # Returns
+    /// None if the framebuffer does not have 32 bit pixels
+    pub fn from_limine(framebuffer: &Framebuffer) -> Option<Self> {
+        if framebuffer.bpp() != 32 {
+            return None;
+        }
+        // Limine maps the framebuffer in the higher half for the kernel
+        Some(unsafe {
+            Screen::from_raw(
+                framebuffer.addr(),
+                framebuffer.width() as usize,
+                framebuffer.height() as usize,
+                framebuffer.pitch() as usize,
+            )
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the pixel at (x, y) in the back buffer, if it is on screen
+    pub fn pixel(&self, x: usize, y: usize) -> Option<u32> {
+        (x < self.width && y < self.height).then(|| self.back[y * self.width + x])
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: u32) {
+        self.fill_rect(Rect::new(x, y, 1, 1), color);
+    }
+
+    /// Fills the whole screen
+    pub fn fill(&mut self, color: u32) {
+        self.back.fill(color);
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: u32) {
+        let Some((x, y, width, height)) = rect.clip(self.width, self.height) else {
+            return;
+        };
+        for row in self.back.chunks_exact_mut(self.width).skip(y).take(height) {
+            row[x..x + width].fill(color);
+        }
+    }
+
+    /// Draws the one pixel wide outline of a rectangle
+    pub fn draw_rect(&mut self, rect: Rect, color: u32) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        let right = rect.x + rect.width as i32 - 1;
+        let bottom = rect.y + rect.height as i32 - 1;
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, bottom, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, rect.y, 1, rect.height), color);
+        self.fill_rect(Rect::new(right, rect.y, 1, rect.height), color);
+    }
+
+    /// Copies an image to the screen with its top left corner at (x, y)
+    ///
+    /// # Arguments
+    /// * `pixels` - The image, row major
+    /// * `width` - Pixels in a row of the image
+    pub fn blit(&mut self, x: i32, y: i32, pixels: &[u32], width: usize) {
+        if width == 0 {
+            return;
+        }
+        let height = pixels.len() / width;
+        let rect = Rect::new(x, y, width as u32, height as u32);
+        let Some((left, top, visible_width, visible_height)) = rect.clip(self.width, self.height)
+        else {
+            return;
+        };
+        // Where the visible part starts within the image
+        let skip_x = (left as i64 - x as i64) as usize;
+        let skip_y = (top as i64 - y as i64) as usize;
+        let rows = self
+            .back
+            .chunks_exact_mut(self.width)
+            .skip(top)
+            .zip(pixels.chunks_exact(width).skip(skip_y))
+            .take(visible_height);
+        for (row, source) in rows {
+            row[left..left + visible_width]
+                .copy_from_slice(&source[skip_x..skip_x + visible_width]);
+        }
+    }
+
+    /// Copies the back buffer to the framebuffer, if the screen has one
+    pub fn present(&self) {
+        let Some(front) = &self.front else {
+            return;
+        };
+        if self.width == 0 {
+            return;
+        }
+        for (y, row) in self.back.chunks_exact(self.width).enumerate() {
+            // Safety: from_raw's caller vouched for every row of the
+            // framebuffer, and the back buffer has the same rows
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    row.as_ptr(),
+                    front.addr.add(y * front.pitch) as *mut u32,
+                    self.width,
+                );
+            }
+        }
+    }
+}
+
+/// Sets up a screen on the first framebuffer from the bootloader, cleared,
+/// and registers it with the device manager
+pub fn init() {
+    let Some(response) = FRAMEBUFFER_REQUEST.get_response() else {
+        return;
+    };
+    let Some(framebuffer) = response.framebuffers().next() else {
+        return;
+    };
+    let Some(mut screen) = Screen::from_limine(&framebuffer) else {
+        serial_println!("Framebuffer has {} bpp, not drawing", framebuffer.bpp());
+        return;
+    };
+    serial_println!("Found frame buffer {}x{}", screen.width(), screen.height());
+    screen.fill(BLACK);
+    screen.present();
+    let mut manager = DEVICE_MANAGER.lock();
+    let handle = manager.register(
+        format!("framebuffer {}x{}", screen.width(), screen.height()),
+        DeviceClass::Display,
+        None,
+    );
+    manager.bind(handle, "limine framebuffer");
+    manager.activate(handle, screen);
+}
+
+/// The screen on the boot framebuffer, if the bootloader set one up
+pub fn screen() -> Option<Arc<Mutex<Screen>>> {
+    find_device_data::<Screen>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_screen_draw_and_present() {
+        let mut screen = Screen::new(6, 4);
+        screen.fill(0x112233);
+        screen.fill_rect(Rect::new(-2, -2, 4, 4), WHITE);
+        assert_eq!(screen.pixel(1, 1), Some(WHITE));
+        assert_eq!(screen.pixel(2, 1), Some(0x112233));
+        screen.draw_rect(Rect::new(3, 1, 3, 3), 0xFF0000);
+        assert_eq!(screen.pixel(5, 3), Some(0xFF0000));
+        assert_eq!(screen.pixel(4, 2), Some(0x112233));
+
+        // Only the part of the image on screen is copied
+        let image = [1, 2, 3, 4, 5, 6];
+        screen.blit(4, -1, &image, 3);
+        assert_eq!(screen.pixel(4, 0), Some(4));
+        assert_eq!(screen.pixel(5, 0), Some(5));
+        assert_eq!(screen.pixel(3, 0), Some(0x112233));
+        screen.blit(10, 10, &image, 3);
+
+        // Rows of the framebuffer are longer than the screen is wide
+        let pitch = 8 * 4;
+        let mut memory = vec![0u32; 8 * 4];
+        let mut shown = unsafe { Screen::from_raw(memory.as_mut_ptr() as *mut u8, 6, 4, pitch) };
+        shown.blit(0, 0, &screen.back, 6);
+        assert!(memory.iter().all(|&pixel| pixel == 0));
+        shown.present();
+        for y in 0..4 {
+            assert_eq!(&memory[y * 8..y * 8 + 6], &screen.back[y * 6..y * 6 + 6]);
+            assert_eq!(&memory[y * 8 + 6..y * 8 + 8], &[0, 0]);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Kernel input events.
+//!
+//! Input drivers turn what their hardware reports into `InputEvent`s and
+//! hand them to `report`, which queues them on one channel shared by every
+//! input device. Consumers, such as a console, take a receiver with
+//! `subscribe`. Each event is received once, by whichever receiver gets to
+//! it first. When nobody keeps up the queue fills, and further events are
+//! dropped and counted rather than blocking the driver.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bitflags::bitflags;
+use lazy_static::lazy_static;
+
+use crate::ipc::channel::{channel, Receiver, Sender, TrySendError};
+
+/// Events queued before further ones are dropped
+const INPUT_CAPACITY: usize = 128;
+
+bitflags! {
+    /// Modifier keys held during a key event. The bits are laid out like
+    /// the first byte of a USB HID boot keyboard report.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Modifiers: u8 {
+        const LEFT_CTRL = 1 << 0;
+        const LEFT_SHIFT = 1 << 1;
+        const LEFT_ALT = 1 << 2;
+        const LEFT_GUI = 1 << 3;
+        const RIGHT_CTRL = 1 << 4;
+        const RIGHT_SHIFT = 1 << 5;
+        const RIGHT_ALT = 1 << 6;
+        const RIGHT_GUI = 1 << 7;
+    }
+}
+
+impl Modifiers {
+    pub fn shift(self) -> bool {
+        self.intersects(Modifiers::LEFT_SHIFT | Modifiers::RIGHT_SHIFT)
+    }
+
+    pub fn ctrl(self) -> bool {
+        self.intersects(Modifiers::LEFT_CTRL | Modifiers::RIGHT_CTRL)
+    }
+}
+
+/// A key going down or up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// USB HID usage ID of the key, from the keyboard usage page
+    pub usage: u8,
+    pub pressed: bool,
+    /// Modifiers held once the event took effect
+    pub modifiers: Modifiers,
+    /// The character the key types with these modifiers, if any
+    pub ascii: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Key(KeyEvent),
+}
+
+lazy_static! {
+    static ref INPUT: (Sender<InputEvent>, Receiver<InputEvent>) = channel(INPUT_CAPACITY);
+}
+
+/// Events dropped because the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Queues an event for consumers, dropping it if the queue is full
+pub fn report(event: InputEvent) {
+    match INPUT.0.try_send(event) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        // The channel holds its own receiver, so it never closes
+        Err(TrySendError::Closed(_)) => unreachable!("Input channel closed"),
+    }
+}
+
+/// Returns a receiver of input events
+pub fn subscribe() -> Receiver<InputEvent> {
+    INPUT.1.clone()
+}
+
+/// Events dropped since boot because nobody kept up
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
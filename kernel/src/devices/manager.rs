@@ -0,0 +1,546 @@
+//! Device manager.
+//!
+//! Tracks every device the kernel knows about, from PCI functions found on
+//! the bus to the devices drivers discover behind them (such as the card in
+//! an SD slot). Each device gets a stable handle that is never reused, a
+//! lifecycle state, and links to its parent and children. Drivers attach
+//! their per-device state to the device's node, which is how the rest of
+//! the kernel reaches a driver.
+//!
+//! Drivers can also register power hooks, which the manager runs in
+//! dependency order to suspend and resume the whole system.
+//!
+//! `lsdev` lists the tree, which is printed at boot and read from
+//! `/proc/devices`. Each device also has a file in devfs named after its
+//! handle.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{any::Any, fmt, fmt::Write, str::FromStr};
+use spin::Mutex;
+
+use crate::{debug_println, serial_print, serial_println, sync};
+
+use super::{drivers::DriverError, pci::DeviceInfo};
+
+/// The kernel's device manager
+pub static DEVICE_MANAGER: sync::Mutex<DeviceManager> =
+    sync::Mutex::new("device manager", DeviceManager::new());
+
+/// A stable identifier for a device. Handles are never reused, even after
+/// the device is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceHandle(u32);
+
+impl fmt::Display for DeviceHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dev{}", self.0)
+    }
+}
+
+/// Parses a handle as it is displayed, such as `dev3`
+impl FromStr for DeviceHandle {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        let number = name.strip_prefix("dev").ok_or(())?;
+        // Only the form Display writes, so each handle has one name
+        if number.starts_with('+') || (number.len() > 1 && number.starts_with('0')) {
+            return Err(());
+        }
+        number.parse().map(DeviceHandle).map_err(|_| ())
+    }
+}
+
+/// Where a device is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    /// The device was discovered but no driver has claimed it
+    Probed,
+    /// A driver has claimed the device and is setting it up
+    Bound,
+    /// The device is set up and usable
+    Active,
+    /// The driver failed to set up the device
+    Failed,
+    /// The device was quiesced by its suspend hook and must be resumed
+    /// before use
+    Suspended,
+    /// The device was removed and can no longer be used
+    Removed,
+}
+
+/// What kind of device a node represents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    /// A function on the PCI bus
+    PciFunction,
+    /// A storage device holding blocks, such as an SD card
+    Block,
+    Serial,
+    Timer,
+    Display,
+    Audio,
+    Entropy,
+    Network,
+}
+
+/// Callbacks a driver provides to take part in system sleep
+pub struct PowerOps {
+    /// Quiesces the device. Children are suspended before their parents.
+    pub suspend: fn(DeviceHandle) -> Result<(), DriverError>,
+    /// Brings the device back. Parents are resumed before their children.
+    pub resume: fn(DeviceHandle) -> Result<(), DriverError>,
+}
+
+/// A device tracked by the device manager
+pub struct DeviceNode {
+    /// The handle of this device
+    pub handle: DeviceHandle,
+    /// A human readable name
+    pub name: String,
+    pub class: DeviceClass,
+    pub state: DeviceState,
+    /// The device this one was found behind, if any
+    pub parent: Option<DeviceHandle>,
+    pub children: Vec<DeviceHandle>,
+    /// The name of the driver bound to the device
+    pub driver: Option<&'static str>,
+    /// Configuration space information if this is a PCI function
+    pub pci: Option<Arc<Mutex<DeviceInfo>>>,
+    /// State attached by the driver, an Arc<Mutex<T>> behind the Any
+    data: Option<Arc<dyn Any + Send + Sync>>,
+    /// Suspend and resume hooks, if the driver has any
+    power: Option<&'static PowerOps>,
+}
+
+/// Tracks every device in the system
+pub struct DeviceManager {
+    /// All devices ever registered, indexed by handle
+    devices: Vec<DeviceNode>,
+}
+
+impl Default for DeviceManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceManager {
+    pub const fn new() -> Self {
+        DeviceManager {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Registers a newly discovered device in the Probed state
+    ///
+    /// # Arguments
+    /// * `name` - A human readable name for the device
+    /// * `class` - What kind of device this is
+    /// * `parent` - The device this one was found behind, if any
+    ///
+    /// # Returns
+    /// The handle of the new device
+    pub fn register(
+        &mut self,
+        name: String,
+        class: DeviceClass,
+        parent: Option<DeviceHandle>,
+    ) -> DeviceHandle {
+        let handle = DeviceHandle(self.devices.len() as u32);
+        self.devices.push(DeviceNode {
+            handle,
+            name,
+            class,
+            state: DeviceState::Probed,
+            parent,
+            children: Vec::new(),
+            driver: Option::None,
+            pci: Option::None,
+            data: Option::None,
+            power: Option::None,
+        });
+        if let Some(parent) = parent.and_then(|parent| self.get_mut(parent)) {
+            parent.children.push(handle);
+        }
+        handle
+    }
+
+    /// Registers a PCI function found while walking the bus, under the
+    /// bridge it is behind. Bridges must be registered before the functions
+    /// behind them, which is the order walk_pci_bus returns them in.
+    pub fn register_pci(&mut self, info: Arc<Mutex<DeviceInfo>>) -> DeviceHandle {
+        let (name, bus) = {
+            let device = info.lock();
+            let name = alloc::format!(
+                "pci {:02x}:{:02x}.{} {:04x}:{:04x}",
+                device.bus,
+                device.device,
+                device.function,
+                device.vendor_id,
+                device.device_id
+            );
+            (name, device.bus)
+        };
+        let bridge = self
+            .devices()
+            .find(|node| {
+                node.pci
+                    .as_ref()
+                    .is_some_and(|pci| pci.lock().secondary_bus == Some(bus))
+            })
+            .map(|node| node.handle);
+        let handle = self.register(name, DeviceClass::PciFunction, bridge);
+        if let Some(node) = self.get_mut(handle) {
+            node.pci = Option::Some(info);
+        }
+        handle
+    }
+
+    /// Returns the device with the given handle
+    pub fn get(&self, handle: DeviceHandle) -> Option<&DeviceNode> {
+        self.devices.get(handle.0 as usize)
+    }
+
+    fn get_mut(&mut self, handle: DeviceHandle) -> Option<&mut DeviceNode> {
+        self.devices.get_mut(handle.0 as usize)
+    }
+
+    /// Returns every device that has not been removed
+    pub fn devices(&self) -> impl Iterator<Item = &DeviceNode> {
+        self.devices
+            .iter()
+            .filter(|node| node.state != DeviceState::Removed)
+    }
+
+    /// Moves a device to a new state. Removed devices stay removed.
+    pub fn set_state(&mut self, handle: DeviceHandle, state: DeviceState) {
+        if let Some(node) = self.get_mut(handle) {
+            if node.state != DeviceState::Removed {
+                node.state = state;
+            }
+        }
+    }
+
+    /// Marks a device as claimed by a driver
+    pub fn bind(&mut self, handle: DeviceHandle, driver: &'static str) {
+        if let Some(node) = self.get_mut(handle) {
+            node.driver = Option::Some(driver);
+        }
+        self.set_state(handle, DeviceState::Bound);
+    }
+
+    /// Attaches driver state to a device and marks it Active
+    ///
+    /// # Returns
+    /// The shared state, for the driver to keep using
+    pub fn activate<T: Any + Send>(&mut self, handle: DeviceHandle, data: T) -> Arc<Mutex<T>> {
+        let data = Arc::new(Mutex::new(data));
+        if let Some(node) = self.get_mut(handle) {
+            node.data = Option::Some(data.clone());
+        }
+        self.set_state(handle, DeviceState::Active);
+        data
+    }
+
+    /// Registers suspend and resume hooks for a device
+    pub fn set_power_ops(&mut self, handle: DeviceHandle, ops: &'static PowerOps) {
+        if let Some(node) = self.get_mut(handle) {
+            node.power = Option::Some(ops);
+        }
+    }
+
+    /// Returns the devices with power hooks in the order they must be
+    /// suspended: every child comes before its parent, and devices registered
+    /// later come before those registered earlier, so early platform devices
+    /// like the serial port stay up the longest.
+    fn suspend_order(&self) -> Vec<(DeviceHandle, &'static PowerOps)> {
+        let mut order = Vec::new();
+        for node in self
+            .devices
+            .iter()
+            .rev()
+            .filter(|node| node.parent.is_none())
+        {
+            self.push_suspend_order(node.handle, &mut order);
+        }
+        order
+    }
+
+    fn push_suspend_order(
+        &self,
+        handle: DeviceHandle,
+        order: &mut Vec<(DeviceHandle, &'static PowerOps)>,
+    ) {
+        let Some(node) = self.get(handle) else {
+            return;
+        };
+        for child in node.children.iter().rev() {
+            self.push_suspend_order(*child, order);
+        }
+        if let (DeviceState::Active, Some(power)) = (node.state, node.power) {
+            order.push((handle, power));
+        }
+    }
+
+    /// Returns the driver state of a device if it is Active and of type T
+    pub fn data<T: Any + Send>(&self, handle: DeviceHandle) -> Option<Arc<Mutex<T>>> {
+        if self.get(handle)?.state != DeviceState::Active {
+            return Option::None;
+        }
+        self.driver_data(handle)
+    }
+
+    /// Returns the driver state of a device of type T regardless of its
+    /// state. Meant for the device's own driver, for example in power hooks.
+    pub fn driver_data<T: Any + Send>(&self, handle: DeviceHandle) -> Option<Arc<Mutex<T>>> {
+        self.get(handle)?.data.clone()?.downcast::<Mutex<T>>().ok()
+    }
+
+    /// Returns the FIRST Active device whose driver state is of type T
+    pub fn find<T: Any + Send>(&self) -> Option<(DeviceHandle, Arc<Mutex<T>>)> {
+        self.devices
+            .iter()
+            .find_map(|node| Some((node.handle, self.data::<T>(node.handle)?)))
+    }
+
+    /// Removes a device and everything found behind it. Driver state is
+    /// dropped once no one else holds it.
+    pub fn remove(&mut self, handle: DeviceHandle) {
+        let children = match self.get_mut(handle) {
+            Some(node) => {
+                node.state = DeviceState::Removed;
+                node.data = Option::None;
+                node.children.clone()
+            }
+            None => return,
+        };
+        for child in children {
+            self.remove(child);
+        }
+    }
+
+    /// Lists the devices that have not been removed, a line each, with
+    /// every device indented under the one it was found behind
+    pub fn lsdev(&self) -> String {
+        let mut listing = String::new();
+        for node in self.devices().filter(|node| node.parent.is_none()) {
+            self.list_node(node, 0, &mut listing);
+        }
+        listing
+    }
+
+    fn list_node(&self, node: &DeviceNode, depth: usize, listing: &mut String) {
+        let _ = writeln!(
+            listing,
+            "{:indent$}{} {} [{:?}, {:?}] driver: {}",
+            "",
+            node.handle,
+            node.name,
+            node.class,
+            node.state,
+            node.driver.unwrap_or("none"),
+            indent = depth * 2
+        );
+        for child in &node.children {
+            if let Some(child) = self.get(*child) {
+                if child.state != DeviceState::Removed {
+                    self.list_node(child, depth + 1, listing);
+                }
+            }
+        }
+    }
+
+    /// Prints the device tree to the serial port
+    pub fn print_tree(&self) {
+        serial_print!("{}", self.lsdev());
+    }
+}
+
+/// Returns the FIRST Active device whose driver state is of type T
+pub fn find_device_data<T: Any + Send>() -> Option<Arc<Mutex<T>>> {
+    DEVICE_MANAGER.lock().find::<T>().map(|(_, data)| data)
+}
+
+/// Runs the suspend hook of every Active device, children before parents.
+/// If a device fails to suspend, everything already suspended is resumed.
+///
+/// # Returns
+/// The handles that were suspended, in the order they were suspended
+pub fn suspend_devices() -> Result<Vec<DeviceHandle>, DriverError> {
+    // The hooks look devices up themselves, so the manager can not be held
+    let order = DEVICE_MANAGER.lock().suspend_order();
+    let mut suspended = Vec::with_capacity(order.len());
+    for (handle, power) in order {
+        debug_println!("Suspending {}", handle);
+        if let Err(e) = (power.suspend)(handle) {
+            serial_println!("{} failed to suspend: {:?}", handle, e);
+            resume_devices(&suspended)?;
+            return Result::Err(e);
+        }
+        DEVICE_MANAGER
+            .lock()
+            .set_state(handle, DeviceState::Suspended);
+        suspended.push(handle);
+    }
+    Result::Ok(suspended)
+}
+
+/// Resumes devices suspended by suspend_devices, parents before children
+///
+/// # Arguments
+/// * `suspended` - The handles returned by suspend_devices
+pub fn resume_devices(suspended: &[DeviceHandle]) -> Result<(), DriverError> {
+    for &handle in suspended.iter().rev() {
+        let power = match DEVICE_MANAGER.lock().get(handle) {
+            Some(node) if node.state == DeviceState::Suspended => node.power,
+            _ => None,
+        };
+        let Some(power) = power else {
+            continue;
+        };
+        debug_println!("Resuming {}", handle);
+        match (power.resume)(handle) {
+            Ok(()) => DEVICE_MANAGER.lock().set_state(handle, DeviceState::Active),
+            Err(e) => {
+                DEVICE_MANAGER.lock().set_state(handle, DeviceState::Failed);
+                serial_println!("{} failed to resume: {:?}", handle, e);
+                return Result::Err(e);
+            }
+        }
+    }
+    Result::Ok(())
+}
+
+/// Suspends and immediately resumes every device, exercising the same
+/// quiesce and resume ordering a real sleep state would, without
+/// powering anything down.
+pub fn fake_suspend() -> Result<(), DriverError> {
+    let suspended = suspend_devices()?;
+    debug_println!("All devices suspended");
+    resume_devices(&suspended)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    static NO_POWER: PowerOps = PowerOps {
+        suspend: |_| Ok(()),
+        resume: |_| Ok(()),
+    };
+
+    #[test_case]
+    fn test_lifecycle_states() {
+        let mut manager = DeviceManager::new();
+        let controller = manager.register("controller".into(), DeviceClass::PciFunction, None);
+        let card = manager.register("card".into(), DeviceClass::Block, Some(controller));
+        assert_eq!(manager.get(card).unwrap().state, DeviceState::Probed);
+        assert_eq!(manager.get(controller).unwrap().children, [card]);
+        assert_eq!(manager.get(card).unwrap().parent, Some(controller));
+
+        // Binding claims the device, and activating hands out its state
+        manager.bind(card, "driver");
+        assert_eq!(manager.get(card).unwrap().state, DeviceState::Bound);
+        assert_eq!(manager.get(card).unwrap().driver, Some("driver"));
+        assert!(manager.data::<u32>(card).is_none());
+        let data = manager.activate(card, 7u32);
+        assert_eq!(manager.get(card).unwrap().state, DeviceState::Active);
+        assert!(Arc::ptr_eq(&manager.data::<u32>(card).unwrap(), &data));
+        assert!(manager.data::<u64>(card).is_none());
+        assert_eq!(manager.find::<u32>().unwrap().0, card);
+
+        // Only Active devices are handed out, but their driver still
+        // reaches the state
+        manager.set_state(card, DeviceState::Suspended);
+        assert!(manager.data::<u32>(card).is_none());
+        assert!(manager.driver_data::<u32>(card).is_some());
+        manager.set_state(card, DeviceState::Failed);
+        assert!(manager.find::<u32>().is_none());
+        manager.set_state(card, DeviceState::Active);
+
+        // Removing a device removes what was found behind it, for good
+        manager.remove(controller);
+        for handle in [controller, card] {
+            assert_eq!(manager.get(handle).unwrap().state, DeviceState::Removed);
+        }
+        assert!(manager.driver_data::<u32>(card).is_none());
+        assert_eq!(Arc::strong_count(&data), 1);
+        manager.set_state(card, DeviceState::Active);
+        assert_eq!(manager.get(card).unwrap().state, DeviceState::Removed);
+        assert_eq!(manager.devices().count(), 0);
+
+        // Handles are not reused
+        let next = manager.register("next".into(), DeviceClass::Serial, None);
+        assert!(next > card);
+        assert_eq!(manager.devices().count(), 1);
+    }
+
+    #[test_case]
+    fn test_suspend_order_and_listing() {
+        let mut manager = DeviceManager::new();
+        let serial = manager.register("serial".into(), DeviceClass::Serial, None);
+        let bridge = manager.register("bridge".into(), DeviceClass::PciFunction, None);
+        let controller =
+            manager.register("controller".into(), DeviceClass::PciFunction, Some(bridge));
+        let card = manager.register("card".into(), DeviceClass::Block, Some(controller));
+        for handle in [serial, controller, card] {
+            manager.bind(handle, "driver");
+            manager.activate(handle, ());
+            manager.set_power_ops(handle, &NO_POWER);
+        }
+
+        // Children before parents, and the devices found first last
+        let order: Vec<DeviceHandle> = manager
+            .suspend_order()
+            .into_iter()
+            .map(|(handle, _)| handle)
+            .collect();
+        assert_eq!(order, [card, controller, serial]);
+
+        // Devices that are not Active are skipped
+        manager.set_state(controller, DeviceState::Failed);
+        assert_eq!(manager.suspend_order().len(), 2);
+
+        let listing = manager.lsdev();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("dev0 serial [Serial, Active]"));
+        assert!(lines[1].starts_with("dev1 bridge [PciFunction, Probed] driver: none"));
+        assert!(lines[2].starts_with("  dev2 controller [PciFunction, Failed]"));
+        assert!(lines[3].starts_with("    dev3 card [Block, Active] driver: driver"));
+        manager.remove(controller);
+        assert_eq!(manager.lsdev().lines().count(), 2);
+    }
+
+    #[test_case]
+    fn test_handle_names() {
+        for handle in [DeviceHandle(0), DeviceHandle(42)] {
+            assert_eq!(handle.to_string().parse(), Ok(handle));
+        }
+        for name in ["dev", "dev01", "dev+1", "dev-1", "sd0", "dev1x"] {
+            assert_eq!(name.parse::<DeviceHandle>(), Err(()));
+        }
+    }
+
+    #[test_case]
+    fn test_fake_suspend_restores_devices() {
+        let active_before: Vec<DeviceHandle> = DEVICE_MANAGER
+            .lock()
+            .devices()
+            .filter(|node| node.state == DeviceState::Active)
+            .map(|node| node.handle)
+            .collect();
+
+        fake_suspend().expect("Fake suspend failed");
+
+        let manager = DEVICE_MANAGER.lock();
+        for handle in active_before {
+            assert_eq!(
+                manager.get(handle).map(|node| node.state),
+                Some(DeviceState::Active)
+            );
+        }
+    }
+}
@@ -1,56 +1,106 @@
 //! Device management and initialization.
 //!
 //! This module handles initialization and access to hardware devices including:
-//! - Serial ports for debugging output
-//! - Frame buffer for screen output
+//! - Serial ports for debugging output, and line editing input on the
+//!   serial console
+//! - Frame buffer for screen output, drawn through `graphics::Screen`
+//! - Virtio devices, including the network card the `net` stack runs on
+//! - AC'97 audio
+//! - AHCI SATA disks
+//! - USB keyboards, reporting to the kernel input channel
 //! - Future device support will be added here
 
-use crate::{memory::MAPPER, serial_println};
-use limine::request::FramebufferRequest;
+use crate::{
+    interrupts::x2apic::TIMER_POWER_OPS,
+    memory::{
+        accounting::{charge_to, Subsystem},
+        MAPPER,
+    },
+};
+use alloc::{format, vec::Vec};
+use drivers::{bind_pci_drivers, PciDriver};
+use manager::{DeviceClass, DeviceState, DEVICE_MANAGER};
 use pci::walk_pci_bus;
-use sd_card::{find_sd_card, initalize_sd_card};
+pub mod ac97;
+pub mod ahci;
+pub mod dma;
+pub mod drivers;
+pub mod graphics;
+pub mod input;
+pub mod manager;
+pub mod nvme;
 pub mod pci;
 pub mod sd_card;
 pub mod serial;
+pub mod tty;
+pub mod usb;
+pub mod virtio;
 
-/// Framebuffer request to the bootloader.
-/// Used to get access to video output capabilities.
-///
-/// TODO: Move to proper frame buffer implementation
-#[used]
-#[link_section = ".requests"]
-static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
+/// Every PCI driver in the kernel. A device is bound to the first driver
+/// in this list that supports it.
+static PCI_DRIVERS: &[&PciDriver] = &[
+    &sd_card::SD_CARD_DRIVER,
+    &ahci::AHCI_DRIVER,
+    &nvme::NVME_DRIVER,
+    &virtio::blk::VIRTIO_BLK_DRIVER,
+    &virtio::gpu::VIRTIO_GPU_DRIVER,
+    &virtio::net::VIRTIO_NET_DRIVER,
+    &virtio::rng::VIRTIO_RNG_DRIVER,
+    &ac97::AC97_DRIVER,
+];
 
 /// Initialize hardware devices.
 ///
 /// This function handles early device initialization during boot.
 /// Currently initializes:
-/// - Frame buffer with basic test pattern
+/// - The serial console's receive interrupt
+/// - The platform devices, and the screen on the boot frame buffer
+/// - Every PCI device that a registered driver supports
 ///
 /// # Arguments
 /// * `cpu_id` - ID of the CPU performing initialization. Only CPU 0
 ///   performs device initialization.
 pub fn init(cpu_id: u32) {
+    let _charge = charge_to(Subsystem::Devices);
     if cpu_id == 0 {
-        // Initialize frame buffer if available
-        if let Some(framebuffer_response) = FRAMEBUFFER_REQUEST.get_response() {
-            serial_println!("Found frame buffer");
-            if let Some(framebuffer) = framebuffer_response.framebuffers().next() {
-                // Draw a simple diagonal line test pattern
-                // TODO: Replace with proper graphics initialization
-                for i in 0..100_u64 {
-                    let pixel_offset = i * framebuffer.pitch() + i * 4;
-                    unsafe {
-                        *(framebuffer.addr().add(pixel_offset as usize) as *mut u32) = 0xFFFFFFFF;
-                    }
-                }
-            }
-        }
-        let devices = walk_pci_bus();
-        let sd_card_device =
-            find_sd_card(&devices).expect("Build system currently sets up an sd-card");
+        tty::init();
+        register_platform_devices();
+        graphics::init();
+        let devices: Vec<_> = {
+            let mut manager = DEVICE_MANAGER.lock();
+            walk_pci_bus()
+                .into_iter()
+                .map(|device| manager.register_pci(device))
+                .collect()
+        };
         let mut mapper = MAPPER.lock();
-        initalize_sd_card(&sd_card_device, &mut mapper).unwrap();
-        serial_println!("Sd card initalized");
+        bind_pci_drivers(&devices, PCI_DRIVERS, &mut mapper);
+        DEVICE_MANAGER.lock().print_tree();
     }
 }
+
+/// Registers the devices that are not found on any bus
+fn register_platform_devices() {
+    let mut manager = DEVICE_MANAGER.lock();
+
+    let serial = manager.register("serial console".into(), DeviceClass::Serial, None);
+    manager.bind(serial, "uart 16550");
+    manager.set_state(serial, DeviceState::Active);
+    manager.set_power_ops(serial, &serial::SERIAL_POWER_OPS);
+
+    if let Some(config) = serial::config(serial::SerialChannel::Debug) {
+        let debug = manager.register(
+            format!("serial debug {:#x}", config.port),
+            DeviceClass::Serial,
+            None,
+        );
+        manager.bind(debug, "uart 16550");
+        manager.set_state(debug, DeviceState::Active);
+        manager.set_power_ops(debug, &serial::DEBUG_SERIAL_POWER_OPS);
+    }
+
+    let timer = manager.register("apic timer".into(), DeviceClass::Timer, None);
+    manager.bind(timer, "x2apic");
+    manager.set_state(timer, DeviceState::Active);
+    manager.set_power_ops(timer, &TIMER_POWER_OPS);
+}
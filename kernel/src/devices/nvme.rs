@@ -0,0 +1,699 @@
+//! NVMe driver.
+//!
+//! Finds an NVMe controller on the PCI bus, maps its registers (BAR 0), and
+//! sets up the admin queue pair and a single I/O queue pair. Every active
+//! namespace is registered with the device manager as a block device, and
+//! namespaces share the controller's I/O queue.
+//!
+//! One command is outstanding at a time, and completions are polled, since
+//! the controller's interrupts are not routed yet. Data moves through a
+//! fixed set of DMA frames, described to the controller with PRP entries.
+
+use alloc::{format, sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::structures::paging::{OffsetPageTable, PhysFrame};
+
+use crate::{
+    constants::memory::PAGE_SIZE,
+    debug_println,
+    devices::{
+        dma::{for_each_buffer, frame_ptr},
+        drivers::{find_device, DriverError, PciDriver, PciMatch},
+        manager::{DeviceClass, DeviceHandle, DeviceState, DEVICE_MANAGER},
+        pci::{write_pci_command, BarError, DeviceInfo, PCICommand},
+    },
+    filesys::{
+        block::stats::{DiskStats, IoDirection},
+        BlockDevice, FsError,
+    },
+    memory::frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+    processes::rusage::account_block_io,
+};
+
+/// Mass storage class code
+const STORAGE_CLASS_CODE: u8 = 0x01;
+/// Non-volatile memory controller subclass
+const NVM_SUB_CLASS: u8 = 0x08;
+/// Programming interface of an NVM Express controller
+const NVME_INTERFACE: u8 = 0x02;
+
+/// The BAR holding the controller's registers
+const REGISTERS_BAR: u8 = 0;
+/// Size mapped for the registers and the doorbells of both queue pairs
+const REGISTERS_LENGTH: u64 = 0x2000;
+
+/// Controller registers
+const REG_CAP: u64 = 0x00;
+const REG_INTMS: u64 = 0x0C;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1C;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+/// Start of the doorbells
+const DOORBELL_BASE: u64 = 0x1000;
+
+/// Controller configuration bits. Commands use the NVM command set and
+/// memory pages of 4 KiB.
+const CC_EN: u32 = 1;
+/// Submission and completion queue entry sizes, as powers of two
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+/// Controller status bits
+const CSTS_RDY: u32 = 1;
+const CSTS_CFS: u32 = 1 << 1;
+
+/// Queue identifiers
+const ADMIN_QUEUE: u16 = 0;
+const IO_QUEUE: u16 = 1;
+/// Entries of each queue, fewer if the controller allows fewer. Both queues
+/// of a pair fit in a frame each.
+const QUEUE_SIZE: u16 = 64;
+
+/// Admin commands
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+/// Identify data structures
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+/// I/O queues live in contiguous memory
+const QUEUE_PHYSICALLY_CONTIGUOUS: u32 = 1;
+
+/// NVM commands
+const NVM_FLUSH: u8 = 0x00;
+const NVM_WRITE: u8 = 0x01;
+const NVM_READ: u8 = 0x02;
+
+/// DMA frames of the controller, which bounds the data one command moves
+const DMA_FRAMES: usize = 16;
+
+/// Maximum number of polling iterations while waiting on the controller
+const MAX_ITERATIONS: usize = 1_000_000;
+
+#[derive(Debug)]
+/// Represents errors that can occur while setting up or using an NVMe
+/// controller
+pub enum NvmeError {
+    /// BAR 0 could not be mapped
+    Bar(BarError),
+    /// A frame could not be allocated for DMA
+    OutOfMemory,
+    /// The controller did not respond in time
+    Timeout,
+    /// The controller cannot work with 4 KiB pages or its doorbells lie
+    /// outside the mapped registers
+    Unsupported,
+    /// The controller hit a fatal error and has to be reset
+    ControllerFatal,
+    /// A command completed with the given status field
+    CommandFailed(u16),
+    /// A request was outside the namespace
+    OutOfRange,
+}
+
+/// An entry of a submission queue
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Command {
+    /// Opcode in the low byte, command identifier in the high half
+    cdw0: u32,
+    namespace: u32,
+    reserved: u64,
+    metadata: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+/// An entry of a completion queue
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Completion {
+    result: u32,
+    reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    command_id: u16,
+    /// Phase tag in bit 0, status field above it
+    status: u16,
+}
+
+/// A submission queue and the completion queue it posts to
+struct QueuePair {
+    submissions: PhysFrame,
+    completions: PhysFrame,
+    size: u16,
+    sq_tail: u16,
+    cq_head: u16,
+    /// Phase tag of completions not yet consumed, flipped on every wrap
+    phase: bool,
+    next_command_id: u16,
+    sq_doorbell: u64,
+    cq_doorbell: u64,
+}
+
+/// A controller with its queues set up
+struct NvmeController {
+    /// Kernel virtual address of the registers
+    registers: u64,
+    admin: QueuePair,
+    io: QueuePair,
+    /// Holds the PRP list of commands spanning more than two frames
+    prp_list: PhysFrame,
+    /// Frames data moves through
+    buffers: Vec<PhysFrame>,
+    /// Most bytes one command may move
+    max_transfer: usize,
+    /// Whether the controller has a volatile write cache to flush
+    write_cache: bool,
+}
+
+/// A namespace of an NVMe controller. Clones share the controller, and each
+/// command holds it until done.
+#[derive(Clone)]
+pub struct NvmeDisk {
+    controller: Arc<Mutex<NvmeController>>,
+    namespace: u32,
+    block_size: usize,
+    blocks: u64,
+    stats: Arc<DiskStats>,
+}
+
+/// The NVMe controllers this driver supports
+const NVME_ID_TABLE: &[PciMatch] = &[PciMatch::Class {
+    class_code: STORAGE_CLASS_CODE,
+    subclass: NVM_SUB_CLASS,
+    programming_interface: Some(NVME_INTERFACE),
+}];
+
+/// Registry entry for the NVMe driver
+pub static NVME_DRIVER: PciDriver = PciDriver {
+    name: "NVMe",
+    id_table: NVME_ID_TABLE,
+    probe: probe_nvme,
+};
+
+/// Finds the FIRST NVMe controller on the PCI bus
+pub fn find_nvme(devices: &[Arc<Mutex<DeviceInfo>>]) -> Option<Arc<Mutex<DeviceInfo>>> {
+    find_device(devices, NVME_ID_TABLE)
+}
+
+/// Probe function for the driver registry. Every namespace found is
+/// registered as a block device behind the controller.
+fn probe_nvme(
+    handle: DeviceHandle,
+    nvme_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), DriverError> {
+    let disks = initialize_nvme(nvme_arc, mapper).map_err(DriverError::Nvme)?;
+    let mut manager = DEVICE_MANAGER.lock();
+    manager.set_state(handle, DeviceState::Active);
+    for disk in disks {
+        let name = format!("nvme disk {}", disk.namespace);
+        let node = manager.register(name, DeviceClass::Block, Some(handle));
+        manager.bind(node, NVME_DRIVER.name);
+        manager.activate(node, disk);
+    }
+    Result::Ok(())
+}
+
+fn read_register(address: u64) -> u32 {
+    unsafe { core::ptr::read_volatile(address as *const u32) }
+}
+
+fn write_register(address: u64, value: u32) {
+    unsafe { core::ptr::write_volatile(address as *mut u32, value) }
+}
+
+fn write_register_64(address: u64, value: u64) {
+    write_register(address, value as u32);
+    write_register(address + 4, (value >> 32) as u32);
+}
+
+/// Polls a register until `done` holds for its value
+fn wait_register(address: u64, done: impl Fn(u32) -> bool) -> bool {
+    for _ in 0..MAX_ITERATIONS {
+        if done(read_register(address)) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+fn alloc_dma_frame() -> Result<PhysFrame, NvmeError> {
+    alloc_frame_zeroed().ok_or(NvmeError::OutOfMemory)
+}
+
+/// Sets up an NVMe controller, returning a disk for each active namespace
+pub fn initialize_nvme(
+    nvme_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<Vec<NvmeDisk>, NvmeError> {
+    let device = nvme_arc.lock();
+    let registers = device
+        .bar(REGISTERS_BAR)
+        .ok_or(BarError::Missing)
+        .and_then(|bar| bar.map(mapper, 0, REGISTERS_LENGTH))
+        .map_err(NvmeError::Bar)?;
+    write_pci_command(
+        device.bus,
+        device.device,
+        device.function,
+        device.command | PCICommand::MEMORY_SPACE | PCICommand::BUS_MASTER,
+    );
+
+    let mut controller = NvmeController::new(registers)?;
+    let namespaces = controller.active_namespaces()?;
+    let controller = Arc::new(Mutex::new(controller));
+    let mut disks = Vec::new();
+    for namespace in namespaces {
+        match NvmeDisk::new(controller.clone(), namespace) {
+            Ok(Some(disk)) => {
+                debug_println!(
+                    "NVMe namespace {} has {} blocks of {} bytes",
+                    namespace,
+                    disk.blocks,
+                    disk.block_size
+                );
+                disks.push(disk);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                debug_println!(
+                    "NVMe namespace {} could not be identified: {:?}",
+                    namespace,
+                    e
+                );
+            }
+        }
+    }
+    Result::Ok(disks)
+}
+
+/// Builds a command, leaving the identifier to the queue it is submitted to
+fn command(opcode: u8, namespace: u32) -> Command {
+    Command {
+        cdw0: opcode.into(),
+        namespace,
+        ..Default::default()
+    }
+}
+
+impl QueuePair {
+    /// Allocates both queues of the pair with identifier `id`
+    fn new(id: u16, size: u16, registers: u64, doorbell_stride: u64) -> Result<Self, NvmeError> {
+        let submissions = alloc_dma_frame()?;
+        let completions = alloc_dma_frame().inspect_err(|_| dealloc_frame(submissions))?;
+        let doorbells = registers + DOORBELL_BASE + 2 * u64::from(id) * doorbell_stride;
+        Result::Ok(QueuePair {
+            submissions,
+            completions,
+            size,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            next_command_id: 0,
+            sq_doorbell: doorbells,
+            cq_doorbell: doorbells + doorbell_stride,
+        })
+    }
+
+    /// Submits a command and polls its completion
+    ///
+    /// # Returns
+    /// The command specific result of the completion
+    fn execute(&mut self, mut command: Command) -> Result<u32, NvmeError> {
+        let id = self.next_command_id;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+        command.cdw0 = (command.cdw0 & 0xFFFF) | u32::from(id) << 16;
+        unsafe {
+            let slot = (frame_ptr(self.submissions) as *mut Command).add(self.sq_tail.into());
+            core::ptr::write_volatile(slot, command);
+        }
+        self.sq_tail = (self.sq_tail + 1) % self.size;
+        write_register(self.sq_doorbell, self.sq_tail.into());
+
+        let slot =
+            unsafe { (frame_ptr(self.completions) as *const Completion).add(self.cq_head.into()) };
+        for _ in 0..MAX_ITERATIONS {
+            let completion = unsafe { core::ptr::read_volatile(slot) };
+            if (completion.status & 1 == 1) != self.phase {
+                core::hint::spin_loop();
+                continue;
+            }
+            self.cq_head += 1;
+            if self.cq_head == self.size {
+                self.cq_head = 0;
+                self.phase = !self.phase;
+            }
+            write_register(self.cq_doorbell, self.cq_head.into());
+
+            let status = completion.status >> 1;
+            if completion.command_id != id || status != 0 {
+                return Result::Err(NvmeError::CommandFailed(status));
+            }
+            return Result::Ok(completion.result);
+        }
+        Result::Err(NvmeError::Timeout)
+    }
+}
+
+impl Drop for QueuePair {
+    fn drop(&mut self) {
+        dealloc_frame(self.submissions);
+        dealloc_frame(self.completions);
+    }
+}
+
+impl NvmeController {
+    /// Resets the controller, brings it up with the admin queue pair, and
+    /// creates the I/O queue pair
+    fn new(registers: u64) -> Result<Self, NvmeError> {
+        let cap = u64::from(read_register(registers + REG_CAP))
+            | u64::from(read_register(registers + REG_CAP + 4)) << 32;
+        let max_entries = (cap & 0xFFFF) as u16;
+        let doorbell_stride = 4 << ((cap >> 32) & 0xF);
+        let min_page_shift = 12 + ((cap >> 48) & 0xF);
+        let doorbells_end = DOORBELL_BASE + 4 * doorbell_stride;
+        if min_page_shift != 12 || doorbells_end > REGISTERS_LENGTH {
+            return Result::Err(NvmeError::Unsupported);
+        }
+        // The maximum queue entries field is zero based
+        let size = QUEUE_SIZE.min(max_entries.saturating_add(1));
+
+        // Disable the controller so it lets go of whatever firmware set up
+        write_register(
+            registers + REG_CC,
+            read_register(registers + REG_CC) & !CC_EN,
+        );
+        if !wait_register(registers + REG_CSTS, |csts| csts & CSTS_RDY == 0) {
+            return Result::Err(NvmeError::Timeout);
+        }
+
+        let admin = QueuePair::new(ADMIN_QUEUE, size, registers, doorbell_stride)?;
+        let io = QueuePair::new(IO_QUEUE, size, registers, doorbell_stride)?;
+        // Frames are only owned once the controller holds them, so its
+        // Drop frees them on every error below
+        let mut controller = NvmeController {
+            registers,
+            admin,
+            io,
+            prp_list: alloc_dma_frame()?,
+            buffers: Vec::with_capacity(DMA_FRAMES),
+            max_transfer: DMA_FRAMES * PAGE_SIZE,
+            write_cache: false,
+        };
+        for _ in 0..DMA_FRAMES {
+            controller.buffers.push(alloc_dma_frame()?);
+        }
+
+        // Completions are polled, so interrupts stay masked
+        write_register(registers + REG_INTMS, u32::MAX);
+        let queue_size = u32::from(size - 1);
+        write_register(registers + REG_AQA, queue_size << 16 | queue_size);
+        write_register_64(
+            registers + REG_ASQ,
+            controller.admin.submissions.start_address().as_u64(),
+        );
+        write_register_64(
+            registers + REG_ACQ,
+            controller.admin.completions.start_address().as_u64(),
+        );
+        write_register(registers + REG_CC, CC_EN | CC_IOSQES | CC_IOCQES);
+        if !wait_register(registers + REG_CSTS, |csts| {
+            csts & (CSTS_RDY | CSTS_CFS) != 0
+        }) {
+            return Result::Err(NvmeError::Timeout);
+        }
+        if read_register(registers + REG_CSTS) & CSTS_CFS != 0 {
+            return Result::Err(NvmeError::ControllerFatal);
+        }
+
+        controller.identify(IDENTIFY_CONTROLLER, 0)?;
+        let identify = frame_ptr(controller.buffers[0]);
+        // The maximum data transfer size is a power of two of pages, 0 for
+        // no limit
+        let mdts = unsafe { identify.add(77).read() };
+        if mdts != 0 {
+            controller.max_transfer = controller.max_transfer.min(PAGE_SIZE << mdts);
+        }
+        controller.write_cache = unsafe { identify.add(525).read() } & 1 != 0;
+
+        let mut create_cq = command(ADMIN_CREATE_IO_CQ, 0);
+        create_cq.prp1 = controller.io.completions.start_address().as_u64();
+        create_cq.cdw10 = queue_size << 16 | u32::from(IO_QUEUE);
+        create_cq.cdw11 = QUEUE_PHYSICALLY_CONTIGUOUS;
+        controller.admin.execute(create_cq)?;
+
+        let mut create_sq = command(ADMIN_CREATE_IO_SQ, 0);
+        create_sq.prp1 = controller.io.submissions.start_address().as_u64();
+        create_sq.cdw10 = queue_size << 16 | u32::from(IO_QUEUE);
+        create_sq.cdw11 = u32::from(IO_QUEUE) << 16 | QUEUE_PHYSICALLY_CONTIGUOUS;
+        controller.admin.execute(create_sq)?;
+
+        Result::Ok(controller)
+    }
+
+    /// Reads an identify data structure into the first DMA frame
+    fn identify(&mut self, structure: u32, namespace: u32) -> Result<(), NvmeError> {
+        let mut identify = command(ADMIN_IDENTIFY, namespace);
+        identify.prp1 = self.buffers[0].start_address().as_u64();
+        identify.cdw10 = structure;
+        self.admin.execute(identify).map(|_| ())
+    }
+
+    /// Lists the identifiers of the active namespaces, in increasing order
+    fn active_namespaces(&mut self) -> Result<Vec<u32>, NvmeError> {
+        self.identify(IDENTIFY_ACTIVE_NAMESPACES, 0)?;
+        let list = frame_ptr(self.buffers[0]) as *const u32;
+        let namespaces = (0..PAGE_SIZE / 4)
+            .map(|i| unsafe { list.add(i).read_volatile() })
+            .take_while(|&namespace| namespace != 0)
+            .collect();
+        Result::Ok(namespaces)
+    }
+
+    /// Moves `len` bytes through the start of the DMA frames with an NVM
+    /// read or write of `count` blocks from `lba`
+    fn transfer(
+        &mut self,
+        opcode: u8,
+        namespace: u32,
+        lba: u64,
+        count: u16,
+        len: usize,
+    ) -> Result<(), NvmeError> {
+        let frames = len.div_ceil(PAGE_SIZE);
+        let mut transfer = command(opcode, namespace);
+        transfer.prp1 = self.buffers[0].start_address().as_u64();
+        // A second frame goes in PRP2, more than that in a list PRP2
+        // points to
+        if frames == 2 {
+            transfer.prp2 = self.buffers[1].start_address().as_u64();
+        } else if frames > 2 {
+            let list = frame_ptr(self.prp_list) as *mut u64;
+            for (i, frame) in self.buffers[1..frames].iter().enumerate() {
+                unsafe { list.add(i).write_volatile(frame.start_address().as_u64()) };
+            }
+            transfer.prp2 = self.prp_list.start_address().as_u64();
+        }
+        transfer.cdw10 = lba as u32;
+        transfer.cdw11 = (lba >> 32) as u32;
+        // The number of blocks is zero based
+        transfer.cdw12 = u32::from(count - 1);
+        self.io.execute(transfer).map(|_| ())
+    }
+}
+
+impl Drop for NvmeController {
+    fn drop(&mut self) {
+        write_register(self.registers + REG_CC, 0);
+        wait_register(self.registers + REG_CSTS, |csts| csts & CSTS_RDY == 0);
+        dealloc_frame(self.prp_list);
+        self.buffers.drain(..).for_each(dealloc_frame);
+    }
+}
+
+impl NvmeDisk {
+    /// Identifies a namespace. Returns None for namespaces whose blocks do
+    /// not fit the DMA frames evenly.
+    fn new(
+        controller: Arc<Mutex<NvmeController>>,
+        namespace: u32,
+    ) -> Result<Option<Self>, NvmeError> {
+        let (blocks, block_size) = {
+            let mut locked = controller.lock();
+            locked.identify(IDENTIFY_NAMESPACE, namespace)?;
+            let identify = frame_ptr(locked.buffers[0]);
+            let blocks = unsafe { (identify as *const u64).read_volatile() };
+            // Formatted LBA size picks one of the LBA formats at byte 128
+            let format = usize::from(unsafe { identify.add(26).read() } & 0xF);
+            let lba_shift = unsafe { identify.add(128 + format * 4 + 2).read() };
+            (blocks, 1usize.checked_shl(lba_shift.into()).unwrap_or(0))
+        };
+        if blocks == 0 || !(512..=PAGE_SIZE).contains(&block_size) {
+            return Result::Ok(None);
+        }
+        Result::Ok(Some(NvmeDisk {
+            controller,
+            namespace,
+            block_size,
+            blocks,
+            stats: DiskStats::register("nvme"),
+        }))
+    }
+
+    /// Checks a request of `len` bytes from `lba` lies inside the namespace
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), NvmeError> {
+        let blocks = (len / self.block_size) as u64;
+        if len % self.block_size != 0 || lba.saturating_add(blocks) > self.blocks {
+            return Result::Err(NvmeError::OutOfRange);
+        }
+        Result::Ok(())
+    }
+
+    /// Reads whole blocks starting at `lba`, as many per command as the
+    /// DMA frames and the controller allow
+    pub fn read_lbas(&self, lba: u64, buf: &mut [u8]) -> Result<(), NvmeError> {
+        self.check_range(lba, buf.len())?;
+        let mut controller = self.controller.lock();
+        let chunk_size = controller.max_transfer;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let start = lba + (i * chunk_size / self.block_size) as u64;
+            let count = (chunk.len() / self.block_size) as u16;
+            controller.transfer(NVM_READ, self.namespace, start, count, chunk.len())?;
+            for_each_buffer(
+                &controller.buffers,
+                chunk.len(),
+                |frame, offset, len| unsafe {
+                    core::ptr::copy_nonoverlapping(frame, chunk[offset..].as_mut_ptr(), len);
+                },
+            );
+        }
+        Result::Ok(())
+    }
+
+    /// Writes whole blocks starting at `lba`, like `read_lbas`
+    pub fn write_lbas(&self, lba: u64, buf: &[u8]) -> Result<(), NvmeError> {
+        self.check_range(lba, buf.len())?;
+        let mut controller = self.controller.lock();
+        let chunk_size = controller.max_transfer;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            for_each_buffer(
+                &controller.buffers,
+                chunk.len(),
+                |frame, offset, len| unsafe {
+                    core::ptr::copy_nonoverlapping(chunk[offset..].as_ptr(), frame, len);
+                },
+            );
+            let start = lba + (i * chunk_size / self.block_size) as u64;
+            let count = (chunk.len() / self.block_size) as u16;
+            controller.transfer(NVM_WRITE, self.namespace, start, count, chunk.len())?;
+        }
+        Result::Ok(())
+    }
+
+    /// Makes every completed write durable. A controller without a
+    /// volatile write cache has nothing to flush.
+    pub fn flush_cache(&self) -> Result<(), NvmeError> {
+        let mut controller = self.controller.lock();
+        if !controller.write_cache {
+            return Result::Ok(());
+        }
+        controller
+            .io
+            .execute(command(NVM_FLUSH, self.namespace))
+            .map(|_| ())
+    }
+
+    /// Identifier of the namespace on its controller
+    pub fn namespace(&self) -> u32 {
+        self.namespace
+    }
+}
+
+impl BlockDevice for NvmeDisk {
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.read_blocks(block_num, buf)
+    }
+
+    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.write_blocks(block_num, buf)
+    }
+
+    fn read_blocks(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let blocks = (buf.len() / self.block_size) as u64;
+        self.stats
+            .record(IoDirection::Read, blocks, || self.read_lbas(block_num, buf))
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(blocks, 0);
+        Result::Ok(())
+    }
+
+    fn write_blocks(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let blocks = (buf.len() / self.block_size) as u64;
+        let stats = self.stats.clone();
+        stats
+            .record(IoDirection::Write, blocks, || {
+                self.write_lbas(block_num, buf)
+            })
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(0, blocks);
+        Result::Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.flush_cache().map_err(|_| FsError::IOError)
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::manager::find_device_data;
+
+    #[test_case]
+    fn test_queue_entry_layout() {
+        assert_eq!(size_of::<Command>(), 64);
+        assert_eq!(size_of::<Completion>(), 16);
+        assert!(usize::from(QUEUE_SIZE) * size_of::<Command>() <= PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn test_disk_round_trip() {
+        let mut disk = find_device_data::<NvmeDisk>()
+            .expect("No NVMe namespace found")
+            .lock()
+            .clone();
+        // Spans more than one command's worth of DMA frames
+        let max_transfer = disk.controller.lock().max_transfer;
+        let blocks = max_transfer / disk.block_size() + 3;
+        let lba = disk.total_blocks() - blocks as u64;
+        let data: Vec<u8> = (0..blocks * disk.block_size())
+            .map(|i| (i % 249) as u8)
+            .collect();
+        disk.write_blocks(lba, &data).unwrap();
+        disk.flush().unwrap();
+
+        let mut read = alloc::vec![0; data.len()];
+        disk.read_blocks(lba, &mut read).unwrap();
+        assert!(read == data);
+        assert!(disk.read_blocks(disk.total_blocks(), &mut read).is_err());
+    }
+}
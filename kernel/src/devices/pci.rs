@@ -1,11 +1,20 @@
 use alloc::{sync::Arc, vec::Vec};
 use bitflags::bitflags;
 use spin::Mutex;
-use x86_64::instructions::port::{
-    self, PortGeneric, ReadOnlyAccess, ReadWriteAccess, WriteOnlyAccess,
+use x86_64::{
+    instructions::port::{self, PortGeneric, ReadOnlyAccess, ReadWriteAccess, WriteOnlyAccess},
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
 };
 
-use crate::debug_println;
+use crate::{
+    debug_println,
+    interrupts::idt::{allocate_vector, free_vector},
+    memory::{frame_allocator::FRAME_ALLOCATOR, regions::overlaps_ram},
+};
 
 /// The port used for setting the address of  PCI configuration
 const CONFIG_ADDRESS_BUS: u16 = 0xCF8;
@@ -15,6 +24,63 @@ const CONFIG_DATA_BUS: u16 = 0xCFC;
 /// A lock to protect access to the PCI bus
 static PCI_LOCK: Mutex<()> = Mutex::new(());
 
+/// Status register bit set when the device has a capability list
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+/// Config space offset of the first capability's offset
+const CAPABILITIES_POINTER: u8 = 0x34;
+/// Most capabilities walked, so a list that loops cannot hang the walk
+const MAX_CAPABILITIES: usize = 48;
+
+/// Config space offset of BAR 0
+const BAR_OFFSET: u8 = 0x10;
+/// BARs of a general device and of a PCI to PCI bridge
+const BAR_COUNT: u8 = 6;
+const BRIDGE_BAR_COUNT: u8 = 2;
+/// Low bits of a BAR
+const BAR_IO_SPACE: u32 = 1;
+const BAR_TYPE: u32 = 0b11 << 1;
+const BAR_TYPE_64_BIT: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_MEMORY_FLAGS: u32 = 0xF;
+const BAR_IO_FLAGS: u32 = 0b11;
+
+/// Devices on a bus and functions of a device
+const MAX_DEVICES: u8 = 32;
+const MAX_FUNCTIONS: u8 = 8;
+/// Header type bit set when the device has more than one function
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_MASK: u8 = 0x7F;
+/// Header type of a PCI to PCI bridge
+const HEADER_TYPE_BRIDGE: u8 = 0x1;
+/// Config space offset of a bridge's secondary bus number
+const BRIDGE_SECONDARY_BUS: u8 = 0x19;
+/// Config space offset of the legacy interrupt line
+const INTERRUPT_LINE: u8 = 0x3C;
+/// Interrupt line of a device that is not connected to one
+const NO_INTERRUPT_LINE: u8 = 0xFF;
+
+/// Capability IDs
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+/// MSI message control bits
+const MSI_ENABLE: u32 = 1;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u32 = 0b111 << 4;
+const MSI_64_BIT: u32 = 1 << 7;
+/// MSI-X message control bits
+const MSIX_TABLE_SIZE: u32 = 0x7FF;
+const MSIX_FUNCTION_MASK: u32 = 1 << 14;
+const MSIX_ENABLE: u32 = 1 << 15;
+/// Size of an MSI-X table entry, and the mask bit of its vector control
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_MASKED: u32 = 1;
+
+/// Messages are writes to the local APIC of the core in bits 19:12.
+/// Without interrupt remapping only the first 256 APIC IDs can be reached.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MSI_MAX_DESTINATION: u32 = 0xFF;
+
 bitflags! {
     #[derive(Debug, Clone, Copy)]
     /// Holds possible PCI Command values
@@ -55,6 +121,9 @@ pub struct DeviceInfo {
     pub bus: u8,
     /// The device that this device is on
     pub device: u8,
+    /// Which of the device's functions this is. Only multi-function
+    /// devices have functions other than 0
+    pub function: u8,
     /// A Marker for the specific device that the vendor made
     pub device_id: u16,
     /// The identifier for the Manufacturer of this device
@@ -87,6 +156,217 @@ pub struct DeviceInfo {
     pub latency_timer: u8,
     /// Determines the system cache line size in 32 bit units
     pub cache_line_size: u8,
+    /// The capabilities in the device's capability list, in list order
+    pub capabilities: Vec<Capability>,
+    /// For a PCI to PCI bridge, the bus behind it
+    pub secondary_bus: Option<u8>,
+}
+
+/// An entry of a device's capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    /// What kind of capability this is, such as CAP_ID_MSI
+    pub id: u8,
+    /// Where the capability starts in the configuration space
+    pub offset: u8,
+}
+
+impl DeviceInfo {
+    /// Returns the offset of the first capability with the given ID
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities
+            .iter()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    /// Returns the legacy interrupt line firmware connected the device to,
+    /// or None if it has none
+    pub fn interrupt_line(&self) -> Option<u8> {
+        let line = read_config(self.bus, self.device, self.function, INTERRUPT_LINE) as u8;
+        (line != NO_INTERRUPT_LINE).then_some(line)
+    }
+
+    /// Reads and sizes a BAR, returning None if the device does not
+    /// implement it. `index` must not be the upper half of a 64 bit BAR.
+    ///
+    /// Sizing briefly stops the device decoding addresses, so this should
+    /// only be called while no one else is accessing the device.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index >= self.bar_count() {
+            return None;
+        }
+        let offset = BAR_OFFSET + index * 4;
+        let low = read_config(self.bus, self.device, self.function, offset);
+        let is_64_bit = low & BAR_IO_SPACE == 0 && low & BAR_TYPE == BAR_TYPE_64_BIT;
+        if is_64_bit && index + 1 >= self.bar_count() {
+            return None;
+        }
+
+        let command = read_pci_command(self.bus, self.device, 0);
+        write_pci_command(
+            self.bus,
+            self.device,
+            self.function,
+            command & !(PCICommand::MEMORY_SPACE | PCICommand::IO_SPACE),
+        );
+        // Writable address bits read back as 1, the rest as 0
+        let low_mask = self.size_bar_register(offset, low);
+        let (high, high_mask) = if is_64_bit {
+            let high = read_config(self.bus, self.device, self.function, offset + 4);
+            (high, self.size_bar_register(offset + 4, high))
+        } else {
+            (0, 0)
+        };
+        write_pci_command(self.bus, self.device, self.function, command);
+
+        if low & BAR_IO_SPACE != 0 {
+            // x86 port addresses are 16 bits, and the upper half of an I/O
+            // BAR may be hardwired to 0
+            let mask = (low_mask & !BAR_IO_FLAGS) as u16;
+            return (mask != 0).then(|| Bar::Io {
+                port: (low & !BAR_IO_FLAGS) as u16,
+                size: (!mask).wrapping_add(1),
+            });
+        }
+        let prefetchable = low & BAR_PREFETCHABLE != 0;
+        if is_64_bit {
+            let mask = u64::from(high_mask) << 32 | u64::from(low_mask & !BAR_MEMORY_FLAGS);
+            (mask != 0).then(|| Bar::Memory64 {
+                address: u64::from(high) << 32 | u64::from(low & !BAR_MEMORY_FLAGS),
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+            })
+        } else {
+            let mask = low_mask & !BAR_MEMORY_FLAGS;
+            (mask != 0).then(|| Bar::Memory32 {
+                address: low & !BAR_MEMORY_FLAGS,
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+            })
+        }
+    }
+
+    /// Reads and sizes every BAR the device implements, along with its
+    /// index. Has the same restrictions as `bar`.
+    pub fn bars(&self) -> Vec<(u8, Bar)> {
+        let mut bars = Vec::new();
+        let mut index = 0;
+        while index < self.bar_count() {
+            let bar = self.bar(index);
+            if let Some(bar) = bar {
+                bars.push((index, bar));
+            }
+            index += match bar {
+                Some(Bar::Memory64 { .. }) => 2,
+                _ => 1,
+            };
+        }
+        bars
+    }
+
+    fn bar_count(&self) -> u8 {
+        match self.header_type & HEADER_TYPE_MASK {
+            0x0 => BAR_COUNT,
+            HEADER_TYPE_BRIDGE => BRIDGE_BAR_COUNT,
+            _ => 0,
+        }
+    }
+
+    /// Writes all ones to a BAR register, returning what reads back, and
+    /// restores it
+    fn size_bar_register(&self, offset: u8, original: u32) -> u32 {
+        write_pci_data(self.bus, self.device, self.function, offset, u32::MAX);
+        let mask = read_config(self.bus, self.device, self.function, offset);
+        write_pci_data(self.bus, self.device, self.function, offset, original);
+        mask
+    }
+}
+
+/// A base address register, which places one of the device's register
+/// blocks in the physical address space or in the I/O ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory32 {
+        address: u32,
+        size: u32,
+        /// Reads have no side effects, so the region may be cached
+        prefetchable: bool,
+    },
+    Memory64 {
+        address: u64,
+        size: u64,
+        /// Reads have no side effects, so the region may be cached
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Errors that can occur while mapping a BAR
+pub enum BarError {
+    /// The device does not implement the BAR
+    Missing,
+    /// The BAR is in the I/O ports, which cannot be mapped
+    NotMemory,
+    /// The region does not fit within the BAR
+    OutOfRange,
+    /// The BAR points into RAM, so mapping it would corrupt memory
+    InRam,
+}
+
+impl Bar {
+    /// The physical address or first port of the BAR
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { address, .. } => address.into(),
+            Bar::Memory64 { address, .. } => address,
+            Bar::Io { port, .. } => port.into(),
+        }
+    }
+
+    /// How many bytes or ports the BAR spans
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { size, .. } => size.into(),
+            Bar::Memory64 { size, .. } => size,
+            Bar::Io { size, .. } => size.into(),
+        }
+    }
+
+    pub fn is_prefetchable(&self) -> bool {
+        match *self {
+            Bar::Memory32 { prefetchable, .. } | Bar::Memory64 { prefetchable, .. } => prefetchable,
+            Bar::Io { .. } => false,
+        }
+    }
+
+    /// Maps `length` bytes starting `offset` bytes into a memory BAR as
+    /// uncached memory, returning their kernel virtual address
+    pub fn map(
+        &self,
+        mapper: &mut OffsetPageTable,
+        offset: u64,
+        length: u64,
+    ) -> Result<u64, BarError> {
+        if let Bar::Io { .. } = self {
+            return Result::Err(BarError::NotMemory);
+        }
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > self.size())
+        {
+            return Result::Err(BarError::OutOfRange);
+        }
+        let address = self.address() + offset;
+        if overlaps_ram(address, length) {
+            return Result::Err(BarError::InRam);
+        }
+        Result::Ok(map_mmio_region(mapper, address, length))
+    }
 }
 
 fn get_pci_addres(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
@@ -174,11 +454,96 @@ pub fn write_pci_command(bus: u8, device: u8, function: u8, command: PCICommand)
     }
 }
 
-/// Determines if a device is connected to the given bus and device pair. If
-/// no device is connected then returns None. Othwewise returns data to find the
-/// device in the DeviceInfo struct
-fn device_connected(bus: u8, device: u8) -> Option<DeviceInfo> {
-    let mut config_word = read_config(bus, device, 0, 0);
+/// Reads the command register as it is now, rather than as it was cached
+/// when the bus was walked
+pub fn read_pci_command(bus: u8, device: u8, function: u8) -> PCICommand {
+    PCICommand::from_bits_retain(read_config(bus, device, function, 0x4) as u16)
+}
+
+/// Maps a physical MMIO region into the kernel's address space as uncached
+/// memory, returning the kernel virtual address of the region.
+pub fn map_mmio_region(mapper: &mut OffsetPageTable, phys_addr: u64, length: u64) -> u64 {
+    let offset = mapper.phys_offset().as_u64();
+    let start_page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(phys_addr + offset));
+    let end_page: Page<Size4KiB> =
+        Page::containing_address(VirtAddr::new(phys_addr + offset + length.max(1) - 1));
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                offset: _,
+                flags,
+            } => unsafe {
+                if let Ok(flush) = mapper.update_flags(
+                    page,
+                    flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE,
+                ) {
+                    flush.flush();
+                }
+            },
+            // Huge pages of the HHDM are left as is
+            TranslateResult::Mapped { .. } => (),
+            TranslateResult::InvalidFrameAddress(_) => {
+                panic!("Invalid physical address in PCI BAR")
+            }
+            TranslateResult::NotMapped => {
+                let frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(
+                    page.start_address().as_u64() - offset,
+                ));
+                unsafe {
+                    mapper
+                        .map_to(
+                            page,
+                            frame,
+                            PageTableFlags::PRESENT
+                                | PageTableFlags::NO_CACHE
+                                | PageTableFlags::WRITABLE,
+                            FRAME_ALLOCATOR
+                                .lock()
+                                .as_mut()
+                                .expect("Global allocator not initialized"),
+                        )
+                        .expect("Mapping PCI BAR failed")
+                        .flush();
+                }
+            }
+        }
+    }
+
+    phys_addr + offset
+}
+
+/// Reads a single byte out of the PCI configuration space
+pub fn read_config_u8(bus: u8, device: u8, function: u8, offset: u8) -> u8 {
+    let word = read_config(bus, device, function, offset & !0b11);
+    (word >> ((offset & 0b11) * 8)) as u8
+}
+
+/// Walks the capability list of a device
+fn read_capabilities(bus: u8, device: u8, function: u8, status: u16) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if status & STATUS_CAPABILITIES_LIST == 0 {
+        return capabilities;
+    }
+    // The low two bits of every pointer are reserved
+    let mut offset = read_config_u8(bus, device, function, CAPABILITIES_POINTER) & 0xFC;
+    while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let header = read_config(bus, device, function, offset);
+        capabilities.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) as u8 & 0xFC;
+    }
+    capabilities
+}
+
+/// Determines if a function is present at the given bus, device and
+/// function. If nothing is there then returns None. Othwewise returns data
+/// to find the function in the DeviceInfo struct
+fn device_connected(bus: u8, device: u8, function: u8) -> Option<DeviceInfo> {
+    let mut config_word = read_config(bus, device, function, 0);
     let device_id: u16 = (config_word >> 16).try_into().expect("Masked out bits");
     let vendor_id: u16 = (config_word & 0x0000FFFF)
         .try_into()
@@ -188,7 +553,7 @@ fn device_connected(bus: u8, device: u8) -> Option<DeviceInfo> {
         return Option::None;
     }
 
-    config_word = read_config(bus, device, 0, 4);
+    config_word = read_config(bus, device, function, 4);
     let status: u16 = (config_word >> 16).try_into().expect("Masked out bits");
     let command = PCICommand::from_bits_retain(
         (config_word & 0x0000FFFF)
@@ -196,7 +561,7 @@ fn device_connected(bus: u8, device: u8) -> Option<DeviceInfo> {
             .expect("Masked out bits"),
     );
 
-    config_word = read_config(bus, device, 0, 8);
+    config_word = read_config(bus, device, function, 8);
     let class_code: u8 = (config_word >> 24).try_into().expect("Masked out bits");
     let subclass: u8 = ((config_word & 0x00FF0000) >> 16)
         .try_into()
@@ -208,7 +573,7 @@ fn device_connected(bus: u8, device: u8) -> Option<DeviceInfo> {
         .try_into()
         .expect("Masked out bits");
 
-    config_word = read_config(bus, device, 0, 12);
+    config_word = read_config(bus, device, function, 12);
     let built_in_self_test: u8 = (config_word >> 24).try_into().expect("Masked out bits");
     let header_type: u8 = ((config_word & 0x00FF0000) >> 16)
         .try_into()
@@ -223,6 +588,7 @@ fn device_connected(bus: u8, device: u8) -> Option<DeviceInfo> {
     let device_info = DeviceInfo {
         bus,
         device,
+        function,
         device_id,
         vendor_id,
         status,
@@ -235,6 +601,9 @@ fn device_connected(bus: u8, device: u8) -> Option<DeviceInfo> {
         header_type,
         latency_timer,
         cache_line_size,
+        capabilities: read_capabilities(bus, device, function, status),
+        secondary_bus: (header_type & HEADER_TYPE_MASK == HEADER_TYPE_BRIDGE)
+            .then(|| read_config_u8(bus, device, function, BRIDGE_SECONDARY_BUS)),
     };
     Option::Some(device_info)
 }
@@ -244,6 +613,7 @@ pub fn print_pci_info(device: &DeviceInfo) {
     debug_println!("----------");
     debug_println!("bus = {}", { device.bus });
     debug_println!("device = {}", { device.device });
+    debug_println!("function = {}", { device.function });
     debug_println!("device_id = 0x{:X}", { device.device_id });
     debug_println!("vendor_id = 0x{:X}", { device.vendor_id });
     debug_println!("status = 0x{:X}", { device.status });
@@ -254,23 +624,375 @@ pub fn print_pci_info(device: &DeviceInfo) {
     });
 }
 
-/// Determines all devices connected to the PCI bus. Does not currently have
-/// support for multi function devices.
+/// Determines every function on the PCI buses reachable from the host
+/// bridges, including the functions of multi-function devices and those
+/// behind PCI to PCI bridges. Bridges come before the functions behind them.
 pub fn walk_pci_bus() -> Vec<Arc<Mutex<DeviceInfo>>> {
-    let mut bus: u16 = 0;
-    let mut devices = Vec::new();
-    while bus < 256 {
-        let mut device: u8 = 0;
-        while device < 32 {
-            match device_connected(bus.try_into().expect("Masked out bits"), device) {
-                None => (),
-                Some(device_info) => {
-                    devices.push(Arc::new(Mutex::new(device_info)));
+    let mut walk = BusWalk {
+        devices: Vec::new(),
+        scanned: [false; 256],
+    };
+    match device_connected(0, 0, 0) {
+        // Each function of a multi-function host bridge is the host
+        // bridge of the bus with its number
+        Some(host) if host.header_type & HEADER_TYPE_MULTI_FUNCTION != 0 => {
+            for function in 0..MAX_FUNCTIONS {
+                if device_connected(0, 0, function).is_some() {
+                    walk.scan_bus(function);
                 }
             }
-            device += 1
         }
-        bus += 1
+        _ => walk.scan_bus(0),
+    }
+    walk.devices
+}
+
+/// State of a walk over the PCI buses
+struct BusWalk {
+    devices: Vec<Arc<Mutex<DeviceInfo>>>,
+    /// Buses already scanned, so a misconfigured bridge cannot loop
+    scanned: [bool; 256],
+}
+
+impl BusWalk {
+    fn scan_bus(&mut self, bus: u8) {
+        if core::mem::replace(&mut self.scanned[usize::from(bus)], true) {
+            return;
+        }
+        for device in 0..MAX_DEVICES {
+            let Some(first) = device_connected(bus, device, 0) else {
+                continue;
+            };
+            let functions = if first.header_type & HEADER_TYPE_MULTI_FUNCTION != 0 {
+                MAX_FUNCTIONS
+            } else {
+                1
+            };
+            self.add_function(first);
+            for function in 1..functions {
+                if let Some(info) = device_connected(bus, device, function) {
+                    self.add_function(info);
+                }
+            }
+        }
+    }
+
+    fn add_function(&mut self, info: DeviceInfo) {
+        let secondary_bus = info.secondary_bus;
+        self.devices.push(Arc::new(Mutex::new(info)));
+        // Bus 0 is never behind a bridge, so it means the firmware left the
+        // bridge unconfigured
+        if let Some(secondary_bus) = secondary_bus.filter(|&bus| bus != 0) {
+            self.scan_bus(secondary_bus);
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Errors that can occur while setting up message signaled interrupts
+pub enum MsiError {
+    /// The device has neither an MSI nor an MSI-X capability
+    Unsupported,
+    /// Every device vector is taken
+    NoFreeVector,
+    /// The core's APIC ID cannot be the target of a message
+    UnreachableCore,
+    /// The BAR holding the MSI-X table could not be mapped
+    Bar(BarError),
+}
+
+/// How a device signals its interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiKind {
+    Msi,
+    /// Through entry 0 of the MSI-X table, the others left masked
+    MsiX,
+}
+
+/// A message signaled interrupt set up for a device
+#[derive(Debug, Clone, Copy)]
+pub struct MsiInterrupt {
+    /// The IDT vector the device raises
+    pub vector: u8,
+    pub kind: MsiKind,
+}
+
+/// Address of the message that interrupts `core`
+fn msi_address(core: u32) -> Result<u64, MsiError> {
+    if core > MSI_MAX_DESTINATION {
+        return Result::Err(MsiError::UnreachableCore);
+    }
+    Result::Ok(MSI_ADDRESS_BASE | u64::from(core) << 12)
+}
+
+/// Has a device raise `handler` on `core` through a message signaled
+/// interrupt, preferring MSI-X over MSI, and stops its INTx interrupt. The
+/// device sends a single message.
+///
+/// # Arguments
+/// * `device` - The device, whose capabilities were read when the bus was
+///   walked
+/// * `mapper` - Used to map the MSI-X table
+/// * `core` - x2APIC ID of the core the interrupt is delivered to
+/// * `handler` - Called in interrupt context for each interrupt
+pub fn enable_msi(
+    device: &DeviceInfo,
+    mapper: &mut OffsetPageTable,
+    core: u32,
+    handler: fn(),
+) -> Result<MsiInterrupt, MsiError> {
+    let address = msi_address(core)?;
+    let msix = device.find_capability(CAP_ID_MSIX);
+    let msi = device.find_capability(CAP_ID_MSI);
+    if msix.is_none() && msi.is_none() {
+        return Result::Err(MsiError::Unsupported);
+    }
+    let vector = allocate_vector(handler).ok_or(MsiError::NoFreeVector)?;
+
+    let result = match (msix, msi) {
+        (Some(capability), _) => enable_msix_entry(device, mapper, capability, address, vector),
+        (None, Some(capability)) => {
+            enable_msi_message(device, capability, address, vector);
+            Result::Ok(MsiKind::Msi)
+        }
+        (None, None) => unreachable!(),
+    };
+    match result {
+        Ok(kind) => {
+            write_pci_command(
+                device.bus,
+                device.device,
+                device.function,
+                read_pci_command(device.bus, device.device, 0) | PCICommand::INTERRUPT_DISABLE,
+            );
+            debug_println!(
+                "PCI device {:X} on bus {} device {} raises vector {} on core {}",
+                device.device_id,
+                device.bus,
+                device.device,
+                vector,
+                core
+            );
+            Result::Ok(MsiInterrupt { vector, kind })
+        }
+        Err(e) => {
+            free_vector(vector);
+            Result::Err(e)
+        }
+    }
+}
+
+/// Turns off a device's message signaled interrupt and frees its vector
+pub fn disable_msi(device: &DeviceInfo, interrupt: MsiInterrupt) {
+    let (id, enable) = match interrupt.kind {
+        MsiKind::Msi => (CAP_ID_MSI, MSI_ENABLE),
+        MsiKind::MsiX => (CAP_ID_MSIX, MSIX_ENABLE),
+    };
+    if let Some(capability) = device.find_capability(id) {
+        let header = read_config(device.bus, device.device, device.function, capability);
+        write_pci_data(
+            device.bus,
+            device.device,
+            device.function,
+            capability,
+            header & !(enable << 16),
+        );
+    }
+    free_vector(interrupt.vector);
+}
+
+/// Programs and enables the single message of an MSI capability
+fn enable_msi_message(device: &DeviceInfo, capability: u8, address: u64, vector: u8) {
+    let header = read_config(device.bus, device.device, device.function, capability);
+    let control = header >> 16;
+    write_pci_data(
+        device.bus,
+        device.device,
+        device.function,
+        capability + 4,
+        address as u32,
+    );
+    // Fixed delivery and edge triggered, so the data is just the vector
+    let data_offset = if control & MSI_64_BIT != 0 {
+        write_pci_data(
+            device.bus,
+            device.device,
+            device.function,
+            capability + 8,
+            (address >> 32) as u32,
+        );
+        capability + 12
+    } else {
+        capability + 8
+    };
+    write_pci_data(
+        device.bus,
+        device.device,
+        device.function,
+        data_offset,
+        vector.into(),
+    );
+    let control = (control & !MSI_MULTIPLE_MESSAGE_ENABLE) | MSI_ENABLE;
+    write_pci_data(
+        device.bus,
+        device.device,
+        device.function,
+        capability,
+        (header & 0xFFFF) | control << 16,
+    );
+}
+
+/// Maps the MSI-X table, masks every entry but the first, which it points
+/// at the vector, and enables MSI-X
+fn enable_msix_entry(
+    device: &DeviceInfo,
+    mapper: &mut OffsetPageTable,
+    capability: u8,
+    address: u64,
+    vector: u8,
+) -> Result<MsiKind, MsiError> {
+    let header = read_config(device.bus, device.device, device.function, capability);
+    let control = header >> 16;
+    let entries = u64::from(control & MSIX_TABLE_SIZE) + 1;
+    // The table's BAR is in the low three bits of its offset
+    let table = read_config(device.bus, device.device, device.function, capability + 4);
+    let table = device
+        .bar((table & 0b111) as u8)
+        .ok_or(BarError::Missing)
+        .and_then(|bar| bar.map(mapper, (table & !0b111).into(), entries * MSIX_ENTRY_SIZE))
+        .map_err(MsiError::Bar)?;
+    write_pci_command(
+        device.bus,
+        device.device,
+        device.function,
+        read_pci_command(device.bus, device.device, 0) | PCICommand::MEMORY_SPACE,
+    );
+    // Entries can only be written safely while the whole function is masked
+    write_pci_data(
+        device.bus,
+        device.device,
+        device.function,
+        capability,
+        (header & 0xFFFF) | (control | MSIX_ENABLE | MSIX_FUNCTION_MASK) << 16,
+    );
+    for entry in 0..entries {
+        let entry = table + entry * MSIX_ENTRY_SIZE;
+        unsafe { core::ptr::write_volatile((entry + 12) as *mut u32, MSIX_ENTRY_MASKED) };
+    }
+    unsafe {
+        core::ptr::write_volatile(table as *mut u32, address as u32);
+        core::ptr::write_volatile((table + 4) as *mut u32, (address >> 32) as u32);
+        core::ptr::write_volatile((table + 8) as *mut u32, vector.into());
+        core::ptr::write_volatile((table + 12) as *mut u32, 0);
+    }
+    write_pci_data(
+        device.bus,
+        device.device,
+        device.function,
+        capability,
+        (header & 0xFFFF) | ((control & !MSIX_FUNCTION_MASK) | MSIX_ENABLE) << 16,
+    );
+    Result::Ok(MsiKind::MsiX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::virtio::{blk::find_virtio_blk, VIRTIO_VENDOR_ID};
+
+    fn noop() {}
+
+    #[test_case]
+    fn test_capability_walk() {
+        let devices = walk_pci_bus();
+        for device in devices.iter().map(|device| device.lock()) {
+            // Capabilities live after the standard header
+            assert!(device
+                .capabilities
+                .iter()
+                .all(|capability| capability.offset >= 0x40));
+            if device.vendor_id == VIRTIO_VENDOR_ID {
+                assert!(device.find_capability(CAP_ID_VENDOR).is_some());
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_bus_topology() {
+        let devices = walk_pci_bus();
+        let devices: Vec<_> = devices.iter().map(|device| device.lock()).collect();
+        for (i, device) in devices.iter().enumerate() {
+            // Every function is found once
+            assert!(devices[..i]
+                .iter()
+                .all(|other| (other.bus, other.device, other.function)
+                    != (device.bus, device.device, device.function)));
+            // A function other than 0 belongs to a multi-function device
+            if device.function != 0 {
+                assert!(devices.iter().any(|other| other.bus == device.bus
+                    && other.device == device.device
+                    && other.function == 0
+                    && other.header_type & HEADER_TYPE_MULTI_FUNCTION != 0));
+            }
+            // Functions off bus 0 sit behind a bridge found earlier
+            if device.bus != 0 {
+                assert!(devices[..i]
+                    .iter()
+                    .any(|bridge| bridge.secondary_bus == Some(device.bus)));
+            }
+        }
+        // The PIIX3 southbridge has several functions, and the entropy
+        // device is behind a bridge
+        assert!(devices.iter().any(|device| device.function != 0));
+        assert!(devices
+            .iter()
+            .any(|device| device.vendor_id == VIRTIO_VENDOR_ID && device.bus != 0));
+    }
+
+    #[test_case]
+    fn test_bar_sizing() {
+        let devices = walk_pci_bus();
+        let blk = find_virtio_blk(&devices).expect("No virtio disk found");
+        let blk = blk.lock();
+        let before: Vec<u32> = (0..BAR_COUNT)
+            .map(|index| read_config(blk.bus, blk.device, blk.function, BAR_OFFSET + index * 4))
+            .collect();
+        let bars = blk.bars();
+        // Virtio places its configuration structures in a memory BAR
+        assert!(bars
+            .iter()
+            .any(|(_, bar)| matches!(bar, Bar::Memory32 { .. } | Bar::Memory64 { .. })));
+        for (_, bar) in bars.iter() {
+            assert!(bar.size().is_power_of_two());
+            assert_eq!(bar.address() % bar.size(), 0);
+        }
+        // Sizing puts every register back
+        for index in 0..BAR_COUNT {
+            let after = read_config(blk.bus, blk.device, blk.function, BAR_OFFSET + index * 4);
+            assert_eq!(after, before[usize::from(index)]);
+        }
+    }
+
+    #[test_case]
+    fn test_vector_allocation() {
+        let first = allocate_vector(noop).expect("No free device vector");
+        let second = allocate_vector(noop).expect("No free device vector");
+        assert_ne!(first, second);
+        free_vector(first);
+        assert_eq!(allocate_vector(noop), Some(first));
+        free_vector(first);
+        free_vector(second);
+    }
+
+    #[test_case]
+    fn test_virtio_blk_uses_msix() {
+        let devices = walk_pci_bus();
+        let blk = find_virtio_blk(&devices).expect("No virtio disk found");
+        let blk = blk.lock();
+        let capability = blk.find_capability(CAP_ID_MSIX).expect("No MSI-X");
+        let control = read_config(blk.bus, blk.device, blk.function, capability) >> 16;
+        assert!(control & MSIX_ENABLE != 0);
+        assert!(control & MSIX_FUNCTION_MASK == 0);
+        assert!(read_pci_command(blk.bus, blk.device, 0).contains(PCICommand::INTERRUPT_DISABLE));
     }
-    devices
 }
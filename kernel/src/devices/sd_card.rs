@@ -1,26 +1,32 @@
 use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 use spin::Mutex;
-use x86_64::{
-    structures::paging::{
-        mapper::{MappedFrame, TranslateResult},
-        Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
-        Translate,
-    },
-    PhysAddr, VirtAddr,
-};
+use x86_64::structures::paging::{OffsetPageTable, PhysFrame};
 
 use crate::{
+    arch::without_interrupts,
+    constants::memory::PAGE_SIZE,
     debug_println,
-    devices::pci::write_pci_command,
-    filesys::{BlockDevice, FsError},
-    memory::paging,
+    devices::{
+        dma::{for_each_buffer, frame_ptr},
+        drivers::{find_device, DriverError, PciDriver, PciMatch},
+        manager::{DeviceClass, DeviceHandle, DeviceState, PowerOps, DEVICE_MANAGER},
+        pci::{enable_msi, write_pci_command, BarError},
+    },
+    events::futures::{run_to_completion, WaitQueue},
+    filesys::{
+        block::stats::{DiskStats, IoDirection},
+        BlockDevice, FsError,
+    },
+    interrupts::{ioapic, x2apic},
+    kassert, kexpect,
+    memory::frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+    processes::rusage::account_block_io,
+    time,
 };
 use bitflags::bitflags;
 
-use super::pci::{read_config, DeviceInfo, PCICommand};
-/// Used to get access to the sd card in the system. Multiple SD cards
-/// are NOT supported
-pub static SD_CARD: Mutex<Option<SDCardInfo>> = Mutex::new(Option::None);
+use super::pci::{DeviceInfo, PCICommand};
 
 #[derive(Debug, Clone)]
 /// A struct storing data of an sd card that can be recieved without
@@ -50,6 +56,10 @@ pub struct SDCardInfo {
     /// Stores the relative card address. This is used as an argument in some
     /// sd commands
     reletave_card_address: u32,
+    /// Whether the card takes CMD23 to announce how many blocks a
+    /// multiple block transfer moves
+    set_block_count: bool,
+    stats: Arc<DiskStats>,
 }
 
 #[derive(Debug)]
@@ -115,6 +125,7 @@ bitflags! {
         const ResponseTypeSDIO = 1 << 6;
         const MultipleBlockSelect = 1 << 5;
         const ReadToCard = 1 << 4;
+        const AutoCMD12Enable = 1 << 2;
         const BlockCountEnable = 1 << 1;
         const DMAEnable = 1;
     }
@@ -176,6 +187,49 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Bits of the normal interrupt status register
+    #[derive(Debug, Clone, Copy)]
+    struct NormalInterrupts: u16 {
+        /// Set while the error interrupt status register is non zero, and
+        /// cleared with it
+        const ErrorInterrupt = 1 << 15;
+        const BufferReadReady = 1 << 5;
+        const BufferWriteReady = 1 << 4;
+        const TransferComplete = 1 << 1;
+        const CommandComplete = 1;
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    /// Attributes of an ADMA2 descriptor
+    struct AdmaAttributes: u16 {
+        const Valid = 1;
+        /// The last descriptor of the table
+        const End = 1 << 1;
+        /// Moves the data the descriptor points at
+        const Transfer = 0b10 << 4;
+    }
+}
+
+/// A 32 bit ADMA2 descriptor, pointing the controller at up to a page of a
+/// transfer
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AdmaDescriptor {
+    attributes: u16,
+    length: u16,
+    address: u32,
+}
+
+/// The frames one transfer moves through with ADMA2, and the descriptor
+/// table that points the controller at them. Freed when dropped.
+struct AdmaBuffer {
+    table: PhysFrame,
+    frames: Vec<PhysFrame>,
+}
+
 bitflags! {
     struct CommandFlags: u16 {
         const DataPresentSelect = 1 << 5;
@@ -191,21 +245,65 @@ const SD_SUB_CLASS: u8 = 0x5;
 const SD_NO_DMA_INTERFACE: u8 = 0x0;
 const SD_DMA_INTERFACE: u8 = 0x1;
 const MAX_ITERATIONS: usize = 1_000;
+/// The BAR holding the slot's registers, and their size
+const REGISTERS_BAR: u8 = 0;
+const REGISTERS_LENGTH: u64 = 0x100;
 const SD_BLOCK_SIZE: u32 = 512;
+/// Most blocks one multiple block command moves
+const MAX_BLOCKS_PER_COMMAND: usize = 128;
+/// Bit of the SCR register set if the card supports CMD23
+const SCR_CMD23_SUPPORT: u64 = 1 << 33;
+/// How long a transfer waits for the controller to raise an interrupt
+const SD_TIMEOUT_NS: u64 = 100_000_000;
+
+/// Interrupts of the sd host controller. Like find_sd_card, only the first
+/// controller set up is handled.
+struct SdInterrupts {
+    /// Kernel virtual address of the controller's registers, 0 until one
+    /// is set up
+    base: AtomicU64,
+    /// Normal interrupt status bits acknowledged but not yet taken by a
+    /// waiter
+    status: AtomicU16,
+    /// Error interrupt status bits acknowledged but not yet reported
+    errors: AtomicU16,
+    /// Woken whenever the controller interrupts
+    waiters: WaitQueue,
+    /// Whether the controller's interrupts reach handle_sd_interrupt, by
+    /// MSI or a legacy line. Waiters poll if not.
+    routed: AtomicBool,
+    /// Interrupts from the controller handled since boot
+    handled: AtomicU64,
+}
+
+static SD_INTERRUPTS: SdInterrupts = SdInterrupts {
+    base: AtomicU64::new(0),
+    status: AtomicU16::new(0),
+    errors: AtomicU16::new(0),
+    waiters: WaitQueue::new(),
+    routed: AtomicBool::new(false),
+    handled: AtomicU64::new(0),
+};
 
 impl BlockDevice for SDCardInfo {
     fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
         if block_num > self.total_blocks {
             return Result::Err(FsError::IOError);
         }
-        let data = read_sd_card(
-            self,
+        let block = kexpect!(
+            u32::try_from(block_num).ok(),
+            Err(FsError::IOError),
+            "Block {} is past what a command can address",
             block_num
-                .try_into()
-                .expect("Maxumum block number should not be greater than 32 bits"),
-        )
-        .map_err(|_| FsError::IOError)?;
+        );
+        let data = self
+            .stats
+            .record(IoDirection::Read, 1, || {
+                run_to_completion(read_sd_card(self, block))
+            })
+            .map_err(|_| FsError::IOError)?;
         buf.copy_from_slice(&data);
+        account_block_io(1, 0);
 
         Result::Ok(())
     }
@@ -216,16 +314,60 @@ impl BlockDevice for SDCardInfo {
         }
         let mut data: [u8; 512] = [0; 512];
         data.copy_from_slice(buf);
-        write_sd_card(
-            self,
+        let block = kexpect!(
+            u32::try_from(block_num).ok(),
+            Err(FsError::IOError),
+            "Block {} is past what a command can address",
             block_num
-                .try_into()
-                .expect("Maximum block number should not be greater than 32 bits"),
-            data,
-        )
-        .map_err(|_| FsError::IOError)?;
+        );
+        self.stats
+            .record(IoDirection::Write, 1, || {
+                run_to_completion(write_sd_card(self, block, data))
+            })
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(0, 1);
+        Result::Ok(())
+    }
+    fn read_blocks(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let count = (buf.len() / SD_BLOCK_SIZE as usize) as u64;
+        if block_num + count > self.total_blocks {
+            return Result::Err(FsError::IOError);
+        }
+        let block = kexpect!(
+            u32::try_from(block_num).ok(),
+            Err(FsError::IOError),
+            "Block {} is past what a command can address",
+            block_num
+        );
+        self.stats
+            .record(IoDirection::Read, count, || {
+                run_to_completion(read_sd_card_blocks(self, block, buf))
+            })
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(count, 0);
         Result::Ok(())
     }
+
+    fn write_blocks(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let count = (buf.len() / SD_BLOCK_SIZE as usize) as u64;
+        if block_num + count > self.total_blocks {
+            return Result::Err(FsError::IOError);
+        }
+        let block = kexpect!(
+            u32::try_from(block_num).ok(),
+            Err(FsError::IOError),
+            "Block {} is past what a command can address",
+            block_num
+        );
+        self.stats
+            .record(IoDirection::Write, count, || {
+                run_to_completion(write_sd_card_blocks(self, block, buf))
+            })
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(0, count);
+        Result::Ok(())
+    }
+
     fn block_size(&self) -> usize {
         SD_BLOCK_SIZE.try_into().expect("To be on 64 bit system")
     }
@@ -235,23 +377,107 @@ impl BlockDevice for SDCardInfo {
     }
 }
 
+/// The SD host controllers this driver supports
+const SD_ID_TABLE: &[PciMatch] = &[
+    PciMatch::Class {
+        class_code: SD_CLASS_CODE,
+        subclass: SD_SUB_CLASS,
+        programming_interface: Some(SD_NO_DMA_INTERFACE),
+    },
+    PciMatch::Class {
+        class_code: SD_CLASS_CODE,
+        subclass: SD_SUB_CLASS,
+        programming_interface: Some(SD_DMA_INTERFACE),
+    },
+];
+
+/// Registry entry for the SD host controller driver
+pub static SD_CARD_DRIVER: PciDriver = PciDriver {
+    name: "Sd card",
+    id_table: SD_ID_TABLE,
+    probe: probe_sd_card,
+};
+
 /// Finds the FIRST device that represents an SD card, or returns None if
 /// this was not found. Most functions take in SDCard Info struct, which
 /// can be recieved by using initalize_sd_card with the SD card that
 /// was found using this function.
-pub fn find_sd_card(devices: &Vec<Arc<Mutex<DeviceInfo>>>) -> Option<Arc<Mutex<DeviceInfo>>> {
-    for possible_device in devices {
-        let arc_device = possible_device.clone();
-        let device = arc_device.lock();
-        if device.class_code == SD_CLASS_CODE
-            && device.subclass == SD_SUB_CLASS
-            && (device.programming_interface == SD_DMA_INTERFACE
-                || device.programming_interface == SD_NO_DMA_INTERFACE)
-        {
-            return Option::Some(possible_device.clone());
+pub fn find_sd_card(devices: &[Arc<Mutex<DeviceInfo>>]) -> Option<Arc<Mutex<DeviceInfo>>> {
+    find_device(devices, SD_ID_TABLE)
+}
+
+/// Probe function for the driver registry. The card is registered as a
+/// block device behind its host controller.
+fn probe_sd_card(
+    handle: DeviceHandle,
+    sd_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), DriverError> {
+    let sd_card = initalize_sd_card(sd_arc, mapper).map_err(DriverError::SdCard)?;
+    let mut manager = DEVICE_MANAGER.lock();
+    manager.set_state(handle, DeviceState::Active);
+    let card = manager.register("sd card".into(), DeviceClass::Block, Some(handle));
+    manager.bind(card, SD_CARD_DRIVER.name);
+    manager.activate(card, sd_card);
+    manager.set_power_ops(card, &SD_CARD_POWER_OPS);
+    Result::Ok(())
+}
+
+/// Power hooks for an sd card
+static SD_CARD_POWER_OPS: PowerOps = PowerOps {
+    suspend: suspend_sd_card,
+    resume: resume_sd_card,
+};
+
+/// Waits for any outstanding command to finish, then turns off the sd clock
+/// and bus power
+fn suspend_sd_card(handle: DeviceHandle) -> Result<(), DriverError> {
+    let sd_card = DEVICE_MANAGER
+        .lock()
+        .driver_data::<SDCardInfo>(handle)
+        .ok_or(DriverError::NotFound)?;
+    let sd_card = sd_card.lock();
+    let internal_info = &sd_card.internal_info;
+
+    let mut idle = false;
+    for _ in 0..MAX_ITERATIONS {
+        if sending_command_valid(internal_info).is_ok() {
+            idle = true;
+            break;
         }
+        core::hint::spin_loop();
+    }
+    if !idle {
+        return Result::Err(DriverError::SdCard(SDCardError::SDTimeout));
+    }
+
+    let clock_ctrl_reg_addr = (internal_info.base_address_register + 0x2c) as *mut u16;
+    let power_control_addr = (internal_info.base_address_register + 0x29) as *mut u8;
+    unsafe {
+        let clock_ctrl_reg = core::ptr::read_volatile(clock_ctrl_reg_addr);
+        // Stop the sd clock first, then the internal clock
+        core::ptr::write_volatile(clock_ctrl_reg_addr, clock_ctrl_reg & !0b100);
+        core::ptr::write_volatile(clock_ctrl_reg_addr, clock_ctrl_reg & !0b101);
+        core::ptr::write_volatile(power_control_addr, 0);
     }
-    Option::None
+    Result::Ok(())
+}
+
+/// Powers the sd card back up and runs the full initialization sequence,
+/// as the card loses its state when bus power is removed
+fn resume_sd_card(handle: DeviceHandle) -> Result<(), DriverError> {
+    let sd_card = DEVICE_MANAGER
+        .lock()
+        .driver_data::<SDCardInfo>(handle)
+        .ok_or(DriverError::NotFound)?;
+    let mut sd_card = sd_card.lock();
+    // Keep counting under the same name
+    let stats = sd_card.stats.clone();
+    *sd_card = SDCardInfo {
+        stats,
+        ..reset_sd_card(&sd_card.internal_info).map_err(DriverError::SdCard)?
+    };
+    Result::Ok(())
 }
 
 /// Sets up an sd card, returning an SDCardInfo that can be used for further
@@ -260,7 +486,7 @@ pub fn find_sd_card(devices: &Vec<Arc<Mutex<DeviceInfo>>>) -> Option<Arc<Mutex<D
 pub fn initalize_sd_card(
     sd_arc: &Arc<Mutex<DeviceInfo>>,
     mapper: &mut OffsetPageTable,
-) -> Result<(), SDCardError> {
+) -> Result<SDCardInfo, SDCardError> {
     // Assume sd_card is a device info for an SD Crd
     // Lets assume 1 slot, and it uses BAR 1
 
@@ -268,78 +494,40 @@ pub fn initalize_sd_card(
     let sd_lock = sd_arc.clone();
     let sd_card = sd_lock.lock();
     let command = sd_card.command & !PCICommand::MEMORY_SPACE;
-    write_pci_command(sd_card.bus, sd_card.device, 0, command);
+    write_pci_command(sd_card.bus, sd_card.device, sd_card.function, command);
 
     // Determine the Base Address, and setup a mapping
-    let base_address_register = read_config(sd_card.bus, sd_card.device, 0, 0x10);
-    let bar_address: u64 = (base_address_register & 0xFFFFFF00).into();
-    let offset = mapper.phys_offset().as_u64();
-    let mut offset_bar = bar_address + offset;
-    let translate_result = mapper.translate(VirtAddr::new(offset_bar));
-    match translate_result {
-        TranslateResult::Mapped {
-            frame,
-            offset: _,
-            flags,
-        } => match frame {
-            MappedFrame::Size4KiB(_) => {
-                let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(offset_bar));
-                unsafe {
-                    mapper
-                        .update_flags(
-                            page,
-                            flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE,
-                        )
-                        .map_err(|_| SDCardError::GenericSDError)?
-                        .flush();
-                }
-            }
-            MappedFrame::Size2MiB(_) => {
-                let page: Page<Size2MiB> = Page::containing_address(VirtAddr::new(offset_bar));
-                unsafe {
-                    mapper
-                        .update_flags(
-                            page,
-                            flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE,
-                        )
-                        .map_err(|_| SDCardError::GenericSDError)?
-                        .flush();
-                }
-            }
-            MappedFrame::Size1GiB(_) => {
-                let page: Page<Size1GiB> = Page::containing_address(VirtAddr::new(offset_bar));
-                unsafe {
-                    mapper
-                        .update_flags(
-                            page,
-                            flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE,
-                        )
-                        .map_err(|_| SDCardError::GenericSDError)?
-                        .flush();
-                }
-            }
-        },
-        TranslateResult::InvalidFrameAddress(_) => {
-            panic!("Invalid physical address in SD BAR")
-        }
-        TranslateResult::NotMapped => {
-            let bar_frame: PhysFrame<Size4KiB> =
-                PhysFrame::containing_address(PhysAddr::new(bar_address));
-            let sd_va = paging::map_kernel_frame(
-                mapper,
-                bar_frame,
-                PageTableFlags::PRESENT | PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE,
-            );
-            offset_bar = sd_va.as_u64();
-        }
-    }
+    let offset_bar = sd_card
+        .bar(REGISTERS_BAR)
+        .ok_or(BarError::Missing)
+        .and_then(|bar| bar.map(mapper, 0, REGISTERS_LENGTH))
+        .map_err(|_| SDCardError::GenericSDError)?;
     // Re-enable memory space commands
     write_pci_command(
         sd_card.bus,
         sd_card.device,
-        0,
+        sd_card.function,
         sd_card.command | PCICommand::MEMORY_SPACE | PCICommand::BUS_MASTER,
     );
+    // QEMU's controller has no MSI, so fall back to its legacy line. The
+    // line is only taken for the first controller, as the handler knows of
+    // one.
+    let core = x2apic::current_core_id() as u32;
+    let routed = match enable_msi(&sd_card, mapper, core, handle_sd_interrupt) {
+        Result::Ok(_) => true,
+        Result::Err(e) => {
+            debug_println!("SD card has no MSI: {:?}", e);
+            !SD_INTERRUPTS.routed.load(Ordering::SeqCst)
+                && sd_card
+                    .interrupt_line()
+                    .is_some_and(|line| ioapic::handle_pci_irq(line, handle_sd_interrupt, core))
+        }
+    };
+    if routed {
+        SD_INTERRUPTS.routed.store(true, Ordering::SeqCst);
+    } else {
+        debug_println!("SD card interrupts are not routed, transfers poll");
+    }
     // Store capabilities in capabilties register
     let capablities = unsafe { core::ptr::read_volatile((offset_bar + 0x40) as *const u64) };
 
@@ -349,9 +537,7 @@ pub fn initalize_sd_card(
         base_address_register: offset_bar,
     };
 
-    let new_info = reset_sd_card(&info)?;
-    *SD_CARD.lock() = Option::Some(new_info);
-    Result::Ok(())
+    reset_sd_card(&info)
 }
 
 /// Sends a software reset to the sd card using the reset register
@@ -376,21 +562,28 @@ fn software_reset_sd_card(sd_card: &SDCardInfoInternal) -> Result<(), SDCardErro
     Result::Ok(())
 }
 
-/// Enables most interrupts of the sd card
-///
-/// Curently we do not have an interrupt handler set up
+/// Enables most interrupts of the sd card and has handle_sd_interrupt
+/// acknowledge them
 fn enable_sd_card_interrupts(sd_card: &SDCardInfoInternal) -> Result<(), SDCardError> {
+    // A controller that is reset keeps its registers where they were
+    let _ = SD_INTERRUPTS.base.compare_exchange(
+        0,
+        sd_card.base_address_register,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+    );
     let normal_intr_status_addr = (sd_card.base_address_register + 0x34) as *mut u16;
     unsafe { core::ptr::write_volatile(normal_intr_status_addr, 0x1FF) };
     sending_command_valid(sd_card)?;
     let error_intr_status_addr = (sd_card.base_address_register + 0x36) as *mut u16;
-    unsafe { core::ptr::write_volatile(error_intr_status_addr, 0x0FB) };
+    // Includes ADMA errors, which would otherwise only show as a timeout
+    unsafe { core::ptr::write_volatile(error_intr_status_addr, 0x2FB) };
     sending_command_valid(sd_card)?;
     let normal_intr_enable_addr = (sd_card.base_address_register + 0x38) as *mut u16;
     unsafe { core::ptr::write_volatile(normal_intr_enable_addr, 0x1FF) };
     sending_command_valid(sd_card)?;
     let error_intr_enable_addr = (sd_card.base_address_register + 0x3A) as *mut u16;
-    unsafe { core::ptr::write_volatile(error_intr_enable_addr, 0x0FB) };
+    unsafe { core::ptr::write_volatile(error_intr_enable_addr, 0x2FB) };
     sending_command_valid(sd_card)?;
     Result::Ok(())
 }
@@ -528,6 +721,13 @@ fn send_sd_reset_commands(sd_card: &SDCardInfoInternal) -> Result<SDCardInfo, SD
         // Sebd cnd 7 to set transfer state
         unsafe { core::ptr::write_volatile(argument_register_addr, rca) };
         send_sd_command(sd_card, 7, SDResponseTypes::R1b, CommandFlags::empty())?;
+
+        // Cards that cannot say, or whose SCR cannot be read, are stopped
+        // with CMD12 instead
+        let set_block_count = read_scr(sd_card, rca).is_ok_and(|scr| scr & SCR_CMD23_SUPPORT != 0);
+        if let Result::Ok(info) = result.as_mut() {
+            info.set_block_count = set_block_count;
+        }
     } else {
         panic!("CMD 3 should return a 32 bit response");
     }
@@ -535,6 +735,52 @@ fn send_sd_reset_commands(sd_card: &SDCardInfoInternal) -> Result<SDCardInfo, SD
     result
 }
 
+/// Reads the card's SCR register, which lists the optional commands it
+/// supports. The card has to be in the transfer state.
+fn read_scr(sd_card: &SDCardInfoInternal, rca: u32) -> Result<u64, SDCardError> {
+    let argument_register_addr = (sd_card.base_address_register + 0x8) as *mut u32;
+    unsafe { core::ptr::write_volatile(argument_register_addr, rca) };
+    send_sd_command(sd_card, 55, SDResponseTypes::R1, CommandFlags::empty())?;
+
+    // The SCR is a single 8 byte block
+    clear_transfer_interrupts(sd_card);
+    let block_size_register_addr = (sd_card.base_address_register + 0x4) as *mut u16;
+    let block_count_register_addr = (sd_card.base_address_register + 0x6) as *mut u16;
+    let transfer_mode_register_addr = (sd_card.base_address_register + 0xC) as *mut u16;
+    unsafe {
+        core::ptr::write_volatile(block_size_register_addr, 8);
+        core::ptr::write_volatile(block_count_register_addr, 1);
+        core::ptr::write_volatile(argument_register_addr, 0);
+        core::ptr::write_volatile(
+            transfer_mode_register_addr,
+            TransferModeFlags::ReadToCard.bits(),
+        );
+    }
+    send_sd_command(
+        sd_card,
+        51,
+        SDResponseTypes::R1,
+        CommandFlags::DataPresentSelect,
+    )?;
+    run_to_completion(wait_for_interrupt(
+        sd_card,
+        NormalInterrupts::BufferReadReady,
+    ))?;
+
+    let buffer_data_port_reg_addr = (sd_card.base_address_register + 0x20) as *const u32;
+    let mut scr = [0u8; 8];
+    for word in scr.chunks_exact_mut(4) {
+        let data = unsafe { core::ptr::read_volatile(buffer_data_port_reg_addr) };
+        word.copy_from_slice(&data.to_le_bytes());
+    }
+    run_to_completion(wait_for_interrupt(
+        sd_card,
+        NormalInterrupts::TransferComplete,
+    ))?;
+    // Sent most significant byte first
+    Result::Ok(u64::from_be_bytes(scr))
+}
+
 /// Creates an SDCardInfo from the provided data
 fn get_full_sd_card_info(
     sd_card: &SDCardInfoInternal,
@@ -545,8 +791,9 @@ fn get_full_sd_card_info(
     let csd_structre: u32 = (csd >> 126)
         .try_into()
         .expect("Higher bits to be masked out");
-    assert!(
+    kassert!(
         csd_structre == 1,
+        Err(SDCardError::GenericSDError),
         "Only SDHC and SDXC cards are supported as of this moment"
     );
     let c_size: u32 = ((csd >> 48) & 0xFFFFFF)
@@ -558,6 +805,8 @@ fn get_full_sd_card_info(
         reletave_card_address: rca,
         block_size: SD_BLOCK_SIZE.try_into().expect("To be on 64 bit system"),
         total_blocks: (c_size + 1).into(),
+        set_block_count: false,
+        stats: DiskStats::register("mmcblk"),
     };
 
     Result::Ok(info)
@@ -607,6 +856,129 @@ fn check_no_errors(sd_card: &SDCardInfoInternal) -> Result<(), SDCardError> {
     Result::Ok(())
 }
 
+/// Handles an interrupt from the sd host controller, called from the
+/// handler of its MSI vector or of a legacy line it may share
+pub fn handle_sd_interrupt() {
+    let base = SD_INTERRUPTS.base.load(Ordering::SeqCst);
+    if base == 0 || !collect_interrupts(base) {
+        return;
+    }
+    SD_INTERRUPTS.handled.fetch_add(1, Ordering::Relaxed);
+    SD_INTERRUPTS.waiters.wake_all();
+}
+
+/// Acknowledges the interrupts the controller has raised, keeping them for
+/// the waiters. Both the handler and waiters call this, so an interrupt
+/// that is not routed or arrives late is still seen.
+///
+/// # Returns
+/// Whether the controller had raised any
+fn collect_interrupts(base: u64) -> bool {
+    let status_addr = (base + 0x30) as *mut u16;
+    let error_status_addr = (base + 0x32) as *mut u16;
+    without_interrupts(|| unsafe {
+        let status = core::ptr::read_volatile(status_addr);
+        if status == 0 {
+            return false;
+        }
+        if NormalInterrupts::ErrorInterrupt.bits() & status != 0 {
+            let errors = core::ptr::read_volatile(error_status_addr);
+            core::ptr::write_volatile(error_status_addr, errors);
+            SD_INTERRUPTS.errors.fetch_or(errors, Ordering::SeqCst);
+        }
+        // The error bit is read only, it clears with the error register
+        core::ptr::write_volatile(
+            status_addr,
+            status & !NormalInterrupts::ErrorInterrupt.bits(),
+        );
+        SD_INTERRUPTS.status.fetch_or(status, Ordering::SeqCst);
+        true
+    })
+}
+
+/// Takes any of the `wanted` interrupts the controller has raised
+///
+/// # Returns
+/// None if none of them has been raised yet, or an error if the
+/// controller reported one
+fn take_interrupts(base: u64, wanted: NormalInterrupts) -> Option<Result<(), SDCardError>> {
+    collect_interrupts(base);
+    let errors = SD_INTERRUPTS.errors.swap(0, Ordering::SeqCst);
+    if errors != 0 {
+        debug_println!("Error detected 0x{errors:x}");
+        return Some(Result::Err(SDCardError::GenericSDError));
+    }
+    let status = SD_INTERRUPTS
+        .status
+        .fetch_and(!wanted.bits(), Ordering::SeqCst);
+    wanted
+        .intersects(NormalInterrupts::from_bits_retain(status))
+        .then_some(Result::Ok(()))
+}
+
+/// Forgets data interrupts left over from earlier commands, so a transfer
+/// only sees its own
+fn clear_transfer_interrupts(sd_card: &SDCardInfoInternal) {
+    collect_interrupts(sd_card.base_address_register);
+    let data = NormalInterrupts::TransferComplete
+        | NormalInterrupts::BufferReadReady
+        | NormalInterrupts::BufferWriteReady;
+    SD_INTERRUPTS
+        .status
+        .fetch_and(!data.bits(), Ordering::SeqCst);
+}
+
+/// Waits for the controller to raise any of `wanted`, letting the core run
+/// its other events in the meantime. The event sleeps until the interrupt
+/// or the timer wakes it if the controller's interrupts are routed, and
+/// otherwise stays runnable and polls.
+async fn wait_for_interrupt(
+    sd_card: &SDCardInfoInternal,
+    wanted: NormalInterrupts,
+) -> Result<(), SDCardError> {
+    let deadline = time::monotonic_ns().saturating_add(SD_TIMEOUT_NS);
+    let mut result = Result::Err(SDCardError::SDTimeout);
+    let mut taken = || match take_interrupts(sd_card.base_address_register, wanted) {
+        Some(taken) => {
+            result = taken;
+            true
+        }
+        None => false,
+    };
+    // The timer never meets a deadline while the clock is not calibrated
+    if SD_INTERRUPTS.routed.load(Ordering::SeqCst) && time::tsc_frequency() != 0 {
+        if !SD_INTERRUPTS
+            .waiters
+            .wait_until_deadline(deadline, &mut taken)
+            .await
+        {
+            // One last look, for an interrupt raised as the deadline passed
+            taken();
+        }
+        return result;
+    }
+    let mut polls = 0;
+    SD_INTERRUPTS
+        .waiters
+        .poll_until(|| {
+            if taken() {
+                return true;
+            }
+            polls += 1;
+            // Count polls instead while the clock is not calibrated
+            if time::tsc_frequency() == 0 {
+                polls >= MAX_ITERATIONS
+            } else {
+                time::monotonic_ns() >= deadline
+            }
+        })
+        .await;
+    result
+}
+
+/// Sends a command and spins until it completes. Used while setting up the
+/// card, transfers use send_sd_command_async.
+///
 /// Bugs: Does not currently work with cmd 12 or 23 (gets response in wrong place)
 /// Also Response type r7 does not seem to be documented
 fn send_sd_command(
@@ -615,7 +987,40 @@ fn send_sd_command(
     respone_type: SDResponseTypes,
     flags: CommandFlags,
 ) -> Result<SDCommandResponse, SDCardError> {
-    assert!(command_idx < 64);
+    issue_sd_command(sd_card, command_idx, &respone_type, flags)?;
+    for _ in 0..MAX_ITERATIONS {
+        if let Some(result) = take_interrupts(
+            sd_card.base_address_register,
+            NormalInterrupts::CommandComplete,
+        ) {
+            return result.map(|_| determine_sd_card_response(sd_card, respone_type));
+        }
+        core::hint::spin_loop();
+    }
+    check_no_errors(sd_card)?;
+    Result::Err(SDCardError::SDTimeout)
+}
+
+/// Sends a command and waits for it to complete without spinning
+async fn send_sd_command_async(
+    sd_card: &SDCardInfoInternal,
+    command_idx: u8,
+    respone_type: SDResponseTypes,
+    flags: CommandFlags,
+) -> Result<SDCommandResponse, SDCardError> {
+    issue_sd_command(sd_card, command_idx, &respone_type, flags)?;
+    wait_for_interrupt(sd_card, NormalInterrupts::CommandComplete).await?;
+    Result::Ok(determine_sd_card_response(sd_card, respone_type))
+}
+
+/// Writes a command to the command register without waiting for it
+fn issue_sd_command(
+    sd_card: &SDCardInfoInternal,
+    command_idx: u8,
+    respone_type: &SDResponseTypes,
+    flags: CommandFlags,
+) -> Result<(), SDCardError> {
+    kassert!(command_idx < 64, Err(SDCardError::GenericSDError));
     sending_command_valid(sd_card)?;
 
     let command_register_addr = (sd_card.base_address_register + 0xE) as *mut u16;
@@ -643,21 +1048,7 @@ fn send_sd_command(
     command |= myflags.bits();
     sending_command_valid(sd_card)?;
     unsafe { core::ptr::write_volatile(command_register_addr, command) };
-    check_no_errors(sd_card)?;
-    let interrupt_status_register = (sd_card.base_address_register + 0x30) as *mut u16;
-    for _ in 0..MAX_ITERATIONS {
-        unsafe {
-            let command_done = core::ptr::read_volatile(interrupt_status_register);
-            if (command_done & 1) == 1 {
-                core::ptr::write_volatile(interrupt_status_register, 1);
-                return Result::Ok(determine_sd_card_response(sd_card, respone_type));
-            } else if command_done != 0 {
-                debug_println!("Something happened 0x{command_done:X}");
-            }
-        }
-    }
-    check_no_errors(sd_card)?;
-    Result::Err(SDCardError::SDTimeout)
+    check_no_errors(sd_card)
 }
 
 /// Returns the data in the SD Cards response register
@@ -704,8 +1095,9 @@ fn determine_sd_card_response(
 
 /// Reads data from a sd card, returning it as  a return value unless an Error
 /// Occurred
-pub fn read_sd_card(sd_card: &SDCardInfo, block: u32) -> Result<[u8; 512], SDCardError> {
+pub async fn read_sd_card(sd_card: &SDCardInfo, block: u32) -> Result<[u8; 512], SDCardError> {
     let internal_info = &sd_card.internal_info;
+    clear_transfer_interrupts(internal_info);
     let block_size_register_addr = (internal_info.base_address_register + 0x4) as *mut u16;
     unsafe { core::ptr::write_volatile(block_size_register_addr, 0x200) };
     let block_count_register_addr = (internal_info.base_address_register + 0x6) as *mut u16;
@@ -722,29 +1114,19 @@ pub fn read_sd_card(sd_card: &SDCardInfo, block: u32) -> Result<[u8; 512], SDCar
     };
 
     // Send command
-    send_sd_command(
+    send_sd_command_async(
         internal_info,
         17,
         SDResponseTypes::R1,
         CommandFlags::DataPresentSelect,
-    )?;
+    )
+    .await?;
 
-    let present_state_register_addr = (internal_info.base_address_register + 0x24) as *const u32;
-    let mut finished = false;
-    for _ in 0..MAX_ITERATIONS {
-        let present_state = unsafe {
-            PresentState::from_bits_retain(core::ptr::read_volatile(present_state_register_addr))
-        };
-        if PresentState::BufferReadEnable.intersects(present_state) {
-            finished = true;
-            break;
-        }
-        core::hint::spin_loop();
-    }
-    if !finished {
-        debug_println!("Timedout");
-        return Result::Err(SDCardError::SDTimeout);
-    }
+    wait_for_interrupt(internal_info, NormalInterrupts::BufferReadReady)
+        .await
+        .inspect_err(|_| {
+            debug_println!("Timedout");
+        })?;
 
     let mut data = [0; 128];
     let buffer_data_port_reg_addr = (internal_info.base_address_register + 0x20) as *const u32;
@@ -753,12 +1135,18 @@ pub fn read_sd_card(sd_card: &SDCardInfo, block: u32) -> Result<[u8; 512], SDCar
     }
 
     let new_data = unsafe { core::mem::transmute::<[u32; 128], [u8; 512]>(data) };
+    wait_for_interrupt(internal_info, NormalInterrupts::TransferComplete).await?;
     Result::Ok(new_data)
 }
 
 /// Writes data to block of sd card
-pub fn write_sd_card(sd_card: &SDCardInfo, block: u32, data: [u8; 512]) -> Result<(), SDCardError> {
+pub async fn write_sd_card(
+    sd_card: &SDCardInfo,
+    block: u32,
+    data: [u8; 512],
+) -> Result<(), SDCardError> {
     let internal_info = &sd_card.internal_info;
+    clear_transfer_interrupts(internal_info);
     let block_size_register_addr = (internal_info.base_address_register + 0x4) as *mut u16;
     unsafe { core::ptr::write_volatile(block_size_register_addr, 0x200) };
     let block_count_register_addr = (internal_info.base_address_register + 0x6) as *mut u16;
@@ -770,29 +1158,19 @@ pub fn write_sd_card(sd_card: &SDCardInfo, block: u32, data: [u8; 512]) -> Resul
     unsafe { core::ptr::write_volatile(transfer_mode_register_adder, 0) };
 
     // Send command
-    send_sd_command(
+    send_sd_command_async(
         internal_info,
         24,
         SDResponseTypes::R1,
         CommandFlags::DataPresentSelect,
-    )?;
+    )
+    .await?;
 
     let present_state_register_addr = (internal_info.base_address_register + 0x24) as *const u32;
-    let mut finished = false;
-    for _ in 0..MAX_ITERATIONS {
-        let present_state = unsafe {
-            PresentState::from_bits_retain(core::ptr::read_volatile(present_state_register_addr))
-        };
-        if PresentState::BufferWriteEnable.intersects(present_state) {
-            finished = true;
-            break;
-        }
-        core::hint::spin_loop();
-    }
-    if !finished {
+    if let Err(e) = wait_for_interrupt(internal_info, NormalInterrupts::BufferWriteReady).await {
         let present_state = unsafe { core::ptr::read_volatile(present_state_register_addr) };
         debug_println!("State = 0x{present_state:X}");
-        return Result::Err(SDCardError::SDTimeout);
+        return Result::Err(e);
     }
 
     let data_32_bits: [u32; 128] = unsafe { core::mem::transmute(data) };
@@ -803,5 +1181,372 @@ pub fn write_sd_card(sd_card: &SDCardInfo, block: u32, data: [u8; 512]) -> Resul
         }
     }
 
+    // The card is busy programming the block until the transfer completes
+    wait_for_interrupt(internal_info, NormalInterrupts::TransferComplete).await
+}
+
+/// Allocates a zeroed frame the controller can address with 32 bit ADMA2
+fn alloc_dma_frame() -> Option<PhysFrame> {
+    let frame = alloc_frame_zeroed()?;
+    if frame.start_address().as_u64() + PAGE_SIZE as u64 > u64::from(u32::MAX) {
+        dealloc_frame(frame);
+        return None;
+    }
+    Some(frame)
+}
+
+impl AdmaBuffer {
+    /// Sets up a buffer for a transfer of `len` bytes
+    ///
+    /// # Returns
+    /// None if the controller cannot do ADMA2, or the frames could not be
+    /// had below 4 GiB, in which case the transfer goes through the buffer
+    /// data port instead
+    fn new(sd_card: &SDCardInfoInternal, len: usize) -> Option<Self> {
+        if !sd_card.capabilities.contains(Capabilities::ADMA2Support) {
+            return None;
+        }
+        let mut buffer = AdmaBuffer {
+            table: alloc_dma_frame()?,
+            frames: Vec::with_capacity(len.div_ceil(PAGE_SIZE)),
+        };
+        for _ in 0..len.div_ceil(PAGE_SIZE) {
+            buffer.frames.push(alloc_dma_frame()?);
+        }
+
+        let table = frame_ptr(buffer.table) as *mut AdmaDescriptor;
+        for (i, descriptor) in adma_descriptors(&buffer.frames, len).enumerate() {
+            unsafe { core::ptr::write_volatile(table.add(i), descriptor) };
+        }
+        Some(buffer)
+    }
+
+    /// Copies data to be written into the buffer
+    fn fill(&mut self, data: &[u8]) {
+        for_each_buffer(&self.frames, data.len(), |frame, start, len| unsafe {
+            core::ptr::copy_nonoverlapping(data[start..].as_ptr(), frame, len);
+        });
+    }
+
+    /// Copies data that was read out of the buffer
+    fn drain(&self, data: &mut [u8]) {
+        for_each_buffer(&self.frames, data.len(), |frame, start, len| unsafe {
+            core::ptr::copy_nonoverlapping(frame, data[start..].as_mut_ptr(), len);
+        });
+    }
+}
+
+/// Builds the descriptor table for a transfer of `len` bytes through
+/// `frames`, one descriptor per frame. Only the last descriptor ends the
+/// table.
+fn adma_descriptors(frames: &[PhysFrame], len: usize) -> impl Iterator<Item = AdmaDescriptor> + '_ {
+    let last = frames.len().saturating_sub(1);
+    frames.iter().enumerate().map(move |(i, frame)| {
+        let mut attributes = AdmaAttributes::Valid | AdmaAttributes::Transfer;
+        if i == last {
+            attributes |= AdmaAttributes::End;
+        }
+        AdmaDescriptor {
+            attributes: attributes.bits(),
+            length: (len - i * PAGE_SIZE).min(PAGE_SIZE) as u16,
+            address: frame.start_address().as_u64() as u32,
+        }
+    })
+}
+
+/// Splits a transfer of `blocks` blocks from `block` on into the commands
+/// that move it, each covering at most MAX_BLOCKS_PER_COMMAND blocks
+///
+/// # Returns
+/// The first block and block count of each command, in order
+fn command_runs(block: u32, blocks: usize) -> impl Iterator<Item = (u32, u16)> {
+    (0..blocks)
+        .step_by(MAX_BLOCKS_PER_COMMAND)
+        .map(move |done| {
+            let count = (blocks - done).min(MAX_BLOCKS_PER_COMMAND);
+            (block + done as u32, count as u16)
+        })
+}
+
+impl Drop for AdmaBuffer {
+    fn drop(&mut self) {
+        self.frames.drain(..).for_each(dealloc_frame);
+        dealloc_frame(self.table);
+    }
+}
+
+/// Points the controller at a buffer's descriptor table for the next
+/// transfer
+fn select_adma2(sd_card: &SDCardInfoInternal, buffer: &AdmaBuffer) {
+    let host_control_addr = (sd_card.base_address_register + 0x28) as *mut u8;
+    let adma_address_low_addr = (sd_card.base_address_register + 0x58) as *mut u32;
+    let adma_address_high_addr = (sd_card.base_address_register + 0x5C) as *mut u32;
+    unsafe {
+        // DMA select is bits 3 and 4, 0b10 being 32 bit ADMA2
+        let host_control = core::ptr::read_volatile(host_control_addr);
+        core::ptr::write_volatile(host_control_addr, (host_control & !0b11000) | 0b10000);
+        let table = buffer.table.start_address().as_u64();
+        core::ptr::write_volatile(adma_address_low_addr, table as u32);
+        core::ptr::write_volatile(adma_address_high_addr, (table >> 32) as u32);
+    }
+}
+
+/// Sets up a multiple block transfer of `count` blocks from `block` on and
+/// sends the command that starts it. The data goes through `dma` if given,
+/// and through the buffer data port otherwise.
+async fn start_multi_block(
+    sd_card: &SDCardInfo,
+    block: u32,
+    count: u16,
+    read: bool,
+    dma: Option<&AdmaBuffer>,
+) -> Result<(), SDCardError> {
+    let internal_info = &sd_card.internal_info;
+    clear_transfer_interrupts(internal_info);
+    let block_size_register_addr = (internal_info.base_address_register + 0x4) as *mut u16;
+    unsafe { core::ptr::write_volatile(block_size_register_addr, 0x200) };
+    let block_count_register_addr = (internal_info.base_address_register + 0x6) as *mut u16;
+    unsafe { core::ptr::write_volatile(block_count_register_addr, count) };
+    let argument_register_addr = (internal_info.base_address_register + 0x8) as *mut u32;
+
+    let mut transfer_mode =
+        TransferModeFlags::MultipleBlockSelect | TransferModeFlags::BlockCountEnable;
+    if sd_card.set_block_count {
+        // The card stops by itself after the blocks CMD23 announced
+        unsafe { core::ptr::write_volatile(argument_register_addr, count.into()) };
+        send_sd_command_async(
+            internal_info,
+            23,
+            SDResponseTypes::R1,
+            CommandFlags::empty(),
+        )
+        .await?;
+    } else {
+        // The controller sends CMD12 once the block count runs out
+        transfer_mode |= TransferModeFlags::AutoCMD12Enable;
+    }
+    if read {
+        transfer_mode |= TransferModeFlags::ReadToCard;
+    }
+    if let Some(buffer) = dma {
+        select_adma2(internal_info, buffer);
+        transfer_mode |= TransferModeFlags::DMAEnable;
+    }
+
+    sending_command_valid(internal_info)?;
+    unsafe { core::ptr::write_volatile(argument_register_addr, block * SD_BLOCK_SIZE) };
+    let transfer_mode_register_adder = (internal_info.base_address_register + 0xC) as *mut u16;
+    unsafe { core::ptr::write_volatile(transfer_mode_register_adder, transfer_mode.bits()) };
+
+    send_sd_command_async(
+        internal_info,
+        if read { 18 } else { 25 },
+        SDResponseTypes::R1,
+        CommandFlags::DataPresentSelect,
+    )
+    .await?;
+    Result::Ok(())
+}
+
+/// Ends a multiple block transfer that failed partway, so the card and the
+/// controller are ready for the next command
+fn abort_multi_block(sd_card: &SDCardInfoInternal) {
+    // Reset the command and data lines, which may still be inhibited
+    let reset_addr = (sd_card.base_address_register + 0x2f) as *mut u8;
+    unsafe { core::ptr::write_volatile(reset_addr, 0b110) };
+    for _ in 0..MAX_ITERATIONS {
+        if unsafe { core::ptr::read_volatile(reset_addr) } & 0b110 == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+
+    let argument_register_addr = (sd_card.base_address_register + 0x8) as *mut u32;
+    unsafe { core::ptr::write_volatile(argument_register_addr, 0) };
+    if send_sd_command(sd_card, 12, SDResponseTypes::R1b, CommandFlags::empty()).is_err() {
+        debug_println!("Stopping the transfer failed");
+    }
+    clear_transfer_interrupts(sd_card);
+}
+
+/// Reads consecutive blocks from `block` on into `buf`, which holds a whole
+/// number of blocks, with one command per MAX_BLOCKS_PER_COMMAND blocks
+pub async fn read_sd_card_blocks(
+    sd_card: &SDCardInfo,
+    block: u32,
+    buf: &mut [u8],
+) -> Result<(), SDCardError> {
+    let block_bytes = SD_BLOCK_SIZE as usize;
+    if buf.len() % block_bytes != 0 {
+        return Result::Err(SDCardError::GenericSDError);
+    }
+    let internal_info = &sd_card.internal_info;
+    let buffer_data_port_reg_addr = (internal_info.base_address_register + 0x20) as *const u32;
+
+    let runs = command_runs(block, buf.len() / block_bytes);
+    for ((first, count), run) in runs.zip(buf.chunks_mut(MAX_BLOCKS_PER_COMMAND * block_bytes)) {
+        let dma = AdmaBuffer::new(internal_info, run.len());
+        let result = async {
+            start_multi_block(sd_card, first, count, true, dma.as_ref()).await?;
+            if let Some(buffer) = dma.as_ref() {
+                wait_for_interrupt(internal_info, NormalInterrupts::TransferComplete).await?;
+                buffer.drain(run);
+                return Result::Ok(());
+            }
+            for data in run.chunks_exact_mut(block_bytes) {
+                wait_for_interrupt(internal_info, NormalInterrupts::BufferReadReady).await?;
+                for word in data.chunks_exact_mut(4) {
+                    let value = unsafe { core::ptr::read_volatile(buffer_data_port_reg_addr) };
+                    word.copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            wait_for_interrupt(internal_info, NormalInterrupts::TransferComplete).await
+        }
+        .await;
+        if let Result::Err(e) = result {
+            abort_multi_block(internal_info);
+            return Result::Err(e);
+        }
+    }
+    Result::Ok(())
+}
+
+/// Writes `buf`, which holds a whole number of blocks, to consecutive
+/// blocks from `block` on, like read_sd_card_blocks
+pub async fn write_sd_card_blocks(
+    sd_card: &SDCardInfo,
+    block: u32,
+    buf: &[u8],
+) -> Result<(), SDCardError> {
+    let block_bytes = SD_BLOCK_SIZE as usize;
+    if buf.len() % block_bytes != 0 {
+        return Result::Err(SDCardError::GenericSDError);
+    }
+    let internal_info = &sd_card.internal_info;
+    let buffer_data_port_reg_addr = (internal_info.base_address_register + 0x20) as *mut u32;
+
+    let runs = command_runs(block, buf.len() / block_bytes);
+    for ((first, count), run) in runs.zip(buf.chunks(MAX_BLOCKS_PER_COMMAND * block_bytes)) {
+        let mut dma = AdmaBuffer::new(internal_info, run.len());
+        if let Some(buffer) = dma.as_mut() {
+            buffer.fill(run);
+        }
+        let result = async {
+            start_multi_block(sd_card, first, count, false, dma.as_ref()).await?;
+            if dma.is_some() {
+                return wait_for_interrupt(internal_info, NormalInterrupts::TransferComplete).await;
+            }
+            for data in run.chunks_exact(block_bytes) {
+                wait_for_interrupt(internal_info, NormalInterrupts::BufferWriteReady).await?;
+                for word in data.chunks_exact(4) {
+                    let value = u32::from_le_bytes(word.try_into().expect("Words are 4 bytes"));
+                    unsafe { core::ptr::write_volatile(buffer_data_port_reg_addr, value) };
+                }
+            }
+            // The card is busy programming until the transfer completes
+            wait_for_interrupt(internal_info, NormalInterrupts::TransferComplete).await
+        }
+        .await;
+        if let Result::Err(e) = result {
+            abort_multi_block(internal_info);
+            return Result::Err(e);
+        }
+    }
     Result::Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{devices::manager::find_device_data, events::timer};
+    use x86_64::PhysAddr;
+
+    #[test_case]
+    fn test_interrupt_read() {
+        let sd_card = find_device_data::<SDCardInfo>().expect("QEMU has an sd card");
+        let sd_card = sd_card.lock();
+        let handled = SD_INTERRUPTS.handled.load(Ordering::Relaxed);
+        let mut buf = [0; SD_BLOCK_SIZE as usize];
+        sd_card.read_block(0, &mut buf).unwrap();
+        // The command complete interrupt is raised as the command is issued
+        if SD_INTERRUPTS.routed.load(Ordering::SeqCst) {
+            assert!(SD_INTERRUPTS.handled.load(Ordering::Relaxed) > handled);
+        }
+    }
+
+    #[test_case]
+    fn test_interrupt_timeout() {
+        let sd_card = find_device_data::<SDCardInfo>().expect("QEMU has an sd card");
+        let sd_card = sd_card.lock();
+        let pending = timer::pending();
+        // No transfer is underway, so nothing completes before the deadline
+        clear_transfer_interrupts(&sd_card.internal_info);
+        let result = run_to_completion(wait_for_interrupt(
+            &sd_card.internal_info,
+            NormalInterrupts::TransferComplete,
+        ));
+        assert!(matches!(result, Err(SDCardError::SDTimeout)));
+        // and the deadline is forgotten once the wait gives up
+        assert_eq!(timer::pending(), pending);
+    }
+
+    #[test_case]
+    fn test_command_runs() {
+        // A run of MAX_BLOCKS_PER_COMMAND blocks is 64 KiB
+        assert_eq!(MAX_BLOCKS_PER_COMMAND * SD_BLOCK_SIZE as usize, 64 * 1024);
+        let runs: Vec<_> = command_runs(100, 300).collect();
+        assert_eq!(runs, [(100, 128), (228, 128), (356, 44)]);
+        let runs: Vec<_> = command_runs(0, MAX_BLOCKS_PER_COMMAND).collect();
+        assert_eq!(runs, [(0, 128)]);
+        let runs: Vec<_> = command_runs(7, MAX_BLOCKS_PER_COMMAND + 1).collect();
+        assert_eq!(runs, [(7, 128), (135, 1)]);
+        assert_eq!(command_runs(0, 0).count(), 0);
+    }
+
+    #[test_case]
+    fn test_adma_descriptors() {
+        let frame = |n: u64| PhysFrame::containing_address(PhysAddr::new(n * PAGE_SIZE as u64));
+        let transfer = (AdmaAttributes::Valid | AdmaAttributes::Transfer).bits();
+        let end = transfer | AdmaAttributes::End.bits();
+
+        // A partial last page only moves what is left
+        let frames = [frame(3), frame(9), frame(4)];
+        let descriptors: Vec<_> = adma_descriptors(&frames, 20 * SD_BLOCK_SIZE as usize).collect();
+        assert_eq!(
+            descriptors,
+            [
+                AdmaDescriptor {
+                    attributes: transfer,
+                    length: 4096,
+                    address: 0x3000
+                },
+                AdmaDescriptor {
+                    attributes: transfer,
+                    length: 4096,
+                    address: 0x9000
+                },
+                AdmaDescriptor {
+                    attributes: end,
+                    length: 2048,
+                    address: 0x4000
+                },
+            ]
+        );
+
+        // A whole run never needs a descriptor longer than a page, whose
+        // length would wrap to 0, which ADMA2 reads as 64 KiB
+        let len = MAX_BLOCKS_PER_COMMAND * SD_BLOCK_SIZE as usize;
+        let frames: Vec<_> = (1..=len.div_ceil(PAGE_SIZE) as u64).map(frame).collect();
+        let descriptors: Vec<_> = adma_descriptors(&frames, len).collect();
+        assert_eq!(descriptors.len(), 16);
+        assert!(descriptors
+            .iter()
+            .all(|descriptor| descriptor.length as usize == PAGE_SIZE));
+        assert!(descriptors[..15]
+            .iter()
+            .all(|descriptor| descriptor.attributes == transfer));
+        assert_eq!(descriptors[15].attributes, end);
+        // and the table fits the one frame it gets
+        assert!(descriptors.len() * core::mem::size_of::<AdmaDescriptor>() <= PAGE_SIZE);
+    }
+}
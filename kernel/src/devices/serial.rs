@@ -1,25 +1,357 @@
 //! Serial port interface for UART 16550 communication.
 //! Provides thread-safe access to write formatted text to a serial port.
+//!
+//! Up to two UARTs are driven. The console carries human readable logs and
+//! is COM1 unless the `serial` boot option picks another port. The debug
+//! UART is only set up if `debug_serial` names one, and is left to machine
+//! protocols such as a GDB stub or test control, so their bytes never
+//! interleave with logs. Both options take `<port>[,<baud>]`, where the
+//! port is `ttyS0` to `ttyS3` or an I/O port address like `0x2f8`.
 
-use crate::constants::ports::SERIAL_PORT;
+use crate::{
+    cmdline,
+    constants::ports::SERIAL_PORT,
+    devices::{
+        drivers::DriverError,
+        manager::{DeviceHandle, PowerOps},
+    },
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+/// Offset of the line control register from the base port
+const LINE_CONTROL_OFFSET: u16 = 3;
+/// Offset of the line status register from the base port
+const LINE_STATUS_OFFSET: u16 = 5;
+/// Line control bit that puts the divisor latch behind the data ports
+const DIVISOR_LATCH_ACCESS: u8 = 1 << 7;
+/// 8 data bits, no parity and one stop bit, as uart_16550 sets up
+const LINE_8N1: u8 = 0x03;
+/// Line status bit set while a received byte waits to be read
+const DATA_READY: u8 = 1 << 0;
+/// Line status bit set once the transmitter is completely idle
+const TRANSMITTER_EMPTY: u8 = 1 << 6;
+/// Maximum number of polling iterations while draining the transmitter
+const MAX_ITERATIONS: usize = 1_000_000;
+
+/// The UART clock, divided down to get the baud rate
+const UART_CLOCK_BAUD: u32 = 115_200;
+/// Baud rate used when a boot option names none
+pub const DEFAULT_BAUD: u32 = 38_400;
+/// Base ports of `ttyS0` to `ttyS3`
+const COM_PORTS: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+/// ISA IRQs of `ttyS0` to `ttyS3`
+const COM_IRQS: [u8; 4] = [4, 3, 4, 3];
+
+/// Where a UART is and how fast it runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    /// Base I/O port
+    pub port: u16,
+    pub baud: u32,
+}
+
+impl SerialConfig {
+    /// The console when the boot options leave it alone
+    pub const CONSOLE_DEFAULT: SerialConfig = SerialConfig {
+        port: SERIAL_PORT,
+        baud: DEFAULT_BAUD,
+    };
+
+    /// Parses a `<port>[,<baud>]` boot option
+    ///
+    /// # Returns
+    /// None if the port is not understood or the UART cannot run at the
+    /// baud rate
+    pub fn parse(option: &str) -> Option<Self> {
+        let (port, baud) = option.split_once(',').unwrap_or((option, ""));
+        let port = match port.strip_prefix("ttyS") {
+            Some(index) => *COM_PORTS.get(index.parse::<usize>().ok()?)?,
+            None => u16::from_str_radix(port.strip_prefix("0x")?, 16).ok()?,
+        };
+        let baud = match baud {
+            "" => DEFAULT_BAUD,
+            baud => baud.parse().ok()?,
+        };
+        let config = SerialConfig { port, baud };
+        config.divisor().map(|_| config)
+    }
+
+    /// Divisor latch value of the baud rate, None if the UART cannot run
+    /// at exactly that rate
+    fn divisor(&self) -> Option<u16> {
+        if self.baud == 0 || UART_CLOCK_BAUD % self.baud != 0 {
+            return None;
+        }
+        u16::try_from(UART_CLOCK_BAUD / self.baud).ok()
+    }
+}
+
+/// What a UART is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialChannel {
+    /// Kernel logs, always present
+    Console,
+    /// Machine protocols, present once configured
+    Debug,
+}
+
+#[derive(Debug)]
+/// Errors that can occur when reconfiguring a UART
+pub enum SerialError {
+    /// The UART cannot run at the requested baud rate
+    UnsupportedBaud,
+    /// The other channel is already on the port
+    PortInUse,
+}
+
+/// A UART programmed with a configuration
+pub struct Uart {
+    config: SerialConfig,
+    port: SerialPort,
+}
+
+impl Uart {
+    fn new(config: SerialConfig) -> Self {
+        let mut uart = Uart {
+            config,
+            port: unsafe { SerialPort::new(config.port) },
+        };
+        uart.init();
+        uart
+    }
+
+    /// Programs the UART from scratch, then sets its baud rate
+    fn init(&mut self) {
+        self.port.init();
+        let Some(divisor) = self.config.divisor() else {
+            return;
+        };
+        let base = self.config.port;
+        let mut line_control = Port::<u8>::new(base + LINE_CONTROL_OFFSET);
+        unsafe {
+            line_control.write(DIVISOR_LATCH_ACCESS | LINE_8N1);
+            Port::<u8>::new(base).write(divisor as u8);
+            Port::<u8>::new(base + 1).write((divisor >> 8) as u8);
+            line_control.write(LINE_8N1);
+        }
+    }
+
+    pub fn config(&self) -> SerialConfig {
+        self.config
+    }
+
+    /// Waits for the transmitter to send everything written so far
+    ///
+    /// # Returns
+    /// False if it did not go idle in time
+    fn drain(&self) -> bool {
+        let mut line_status = Port::<u8>::new(self.config.port + LINE_STATUS_OFFSET);
+        for _ in 0..MAX_ITERATIONS {
+            if unsafe { line_status.read() } & TRANSMITTER_EMPTY != 0 {
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+        false
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.port.write_str(s)
+    }
+}
+
+/// Base port of the console, kept outside its lock for panics to write to
+static CONSOLE_PORT: AtomicU16 = AtomicU16::new(SERIAL_PORT);
+
+/// Set while the console is suspended. Output written in the meantime is
+/// dropped.
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Power hooks for the console
+pub static SERIAL_POWER_OPS: PowerOps = PowerOps {
+    suspend: suspend_serial,
+    resume: resume_serial,
+};
+
+/// Power hooks for the debug UART
+pub static DEBUG_SERIAL_POWER_OPS: PowerOps = PowerOps {
+    suspend: suspend_debug_serial,
+    resume: resume_debug_serial,
+};
+
+/// The configuration a boot option asks for, if it is given and valid
+fn boot_config(option: &str) -> Option<SerialConfig> {
+    cmdline::option(option).and_then(SerialConfig::parse)
+}
 
 lazy_static! {
-    /// Thread-safe wrapper around the first serial port (COM1).
+    /// Thread-safe wrapper around the console UART.
     /// Initializes the port on first access.
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(SERIAL_PORT) };
-        serial_port.init();
-        Mutex::new(serial_port)
+    pub static ref CONSOLE: Mutex<Uart> = {
+        let config = boot_config("serial").unwrap_or(SerialConfig::CONSOLE_DEFAULT);
+        CONSOLE_PORT.store(config.port, Ordering::Relaxed);
+        Mutex::new(Uart::new(config))
+    };
+
+    /// The debug UART, if configured. A boot option putting it on the
+    /// console's port is ignored.
+    static ref DEBUG: Mutex<Option<Uart>> = {
+        let console = boot_config("serial").unwrap_or(SerialConfig::CONSOLE_DEFAULT).port;
+        let config = boot_config("debug_serial").filter(|config| config.port != console);
+        Mutex::new(config.map(Uart::new))
     };
 }
 
+/// Base port of the console, for writers that cannot take its lock
+pub fn console_port() -> u16 {
+    CONSOLE_PORT.load(Ordering::Relaxed)
+}
+
+/// ISA IRQ the console raises, None if it is not on a standard COM port.
+/// `uart_16550` enables the receive interrupt when it programs a UART.
+pub fn console_irq() -> Option<u8> {
+    let port = console_port();
+    let index = COM_PORTS.iter().position(|&com| com == port)?;
+    Some(COM_IRQS[index])
+}
+
+/// Takes a byte received on the console without waiting. The registers are
+/// read without the console's lock, since the receive interrupt may have
+/// interrupted a writer holding it, so callers must keep each other from
+/// reading at the same time.
+pub fn console_receive() -> Option<u8> {
+    let base = console_port();
+    unsafe {
+        if Port::<u8>::new(base + LINE_STATUS_OFFSET).read() & DATA_READY == 0 {
+            return None;
+        }
+        Some(Port::<u8>::new(base).read())
+    }
+}
+
+/// Sends bytes unchanged to the console, leaving them out of the kernel log
+pub fn console_write(bytes: &[u8]) {
+    if SUSPENDED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut console = CONSOLE.lock();
+    bytes.iter().for_each(|&byte| console.port.send_raw(byte));
+}
+
+/// Returns the configuration of a channel, None if it is not set up
+pub fn config(channel: SerialChannel) -> Option<SerialConfig> {
+    match channel {
+        SerialChannel::Console => Some(CONSOLE.lock().config),
+        SerialChannel::Debug => DEBUG.lock().as_ref().map(Uart::config),
+    }
+}
+
+/// Moves a channel to another port or baud rate, or sets up the debug
+/// UART. Whatever the channel's old UART was still sending is let out
+/// first.
+pub fn configure(channel: SerialChannel, config: SerialConfig) -> Result<(), SerialError> {
+    if config.divisor().is_none() {
+        return Result::Err(SerialError::UnsupportedBaud);
+    }
+    let mut console = CONSOLE.lock();
+    let mut debug = DEBUG.lock();
+    let (uart, other) = match channel {
+        SerialChannel::Console => (Some(&mut *console), debug.as_ref()),
+        SerialChannel::Debug => (debug.as_mut(), Some(&*console)),
+    };
+    if other.is_some_and(|other| other.config.port == config.port) {
+        return Result::Err(SerialError::PortInUse);
+    }
+    if let Some(uart) = &uart {
+        uart.drain();
+    }
+    match uart {
+        Some(uart) => {
+            uart.config = config;
+            uart.port = unsafe { SerialPort::new(config.port) };
+            uart.init();
+        }
+        None => *debug = Some(Uart::new(config)),
+    }
+    if channel == SerialChannel::Console {
+        CONSOLE_PORT.store(config.port, Ordering::Relaxed);
+    }
+    Result::Ok(())
+}
+
+/// Stops using the debug UART
+pub fn close_debug() {
+    if let Some(uart) = DEBUG.lock().take() {
+        uart.drain();
+    }
+}
+
+/// Sends bytes unchanged over the debug UART
+///
+/// # Returns
+/// False if no debug UART is set up
+pub fn debug_write(bytes: &[u8]) -> bool {
+    let mut debug = DEBUG.lock();
+    let Some(uart) = debug.as_mut() else {
+        return false;
+    };
+    bytes.iter().for_each(|&byte| uart.port.send_raw(byte));
+    true
+}
+
+/// Takes a byte received on the debug UART without waiting
+pub fn debug_read() -> Option<u8> {
+    DEBUG.lock().as_mut()?.port.try_receive().ok()
+}
+
+/// Lets the transmitter drain, then stops writing to the console
+fn suspend_serial(_handle: DeviceHandle) -> Result<(), DriverError> {
+    let console = CONSOLE.lock();
+    if !console.drain() {
+        return Result::Err(DriverError::Timeout);
+    }
+    SUSPENDED.store(true, Ordering::SeqCst);
+    Result::Ok(())
+}
+
+/// Reprograms the console from scratch and starts writing to it again
+fn resume_serial(_handle: DeviceHandle) -> Result<(), DriverError> {
+    CONSOLE.lock().init();
+    SUSPENDED.store(false, Ordering::SeqCst);
+    Result::Ok(())
+}
+
+fn suspend_debug_serial(_handle: DeviceHandle) -> Result<(), DriverError> {
+    match DEBUG.lock().as_ref() {
+        Some(uart) if !uart.drain() => Result::Err(DriverError::Timeout),
+        _ => Result::Ok(()),
+    }
+}
+
+fn resume_debug_serial(_handle: DeviceHandle) -> Result<(), DriverError> {
+    if let Some(uart) = DEBUG.lock().as_mut() {
+        uart.init();
+    }
+    Result::Ok(())
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL1
+    crate::klog::write_fmt(args);
+    if SUSPENDED.load(Ordering::Relaxed) {
+        return;
+    }
+    CONSOLE
         .lock()
         .write_fmt(args)
         .expect("Printing to serial failed");
@@ -49,3 +381,59 @@ macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
     ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_config() {
+        assert_eq!(
+            SerialConfig::parse("ttyS1,115200"),
+            Some(SerialConfig {
+                port: 0x2F8,
+                baud: 115_200
+            })
+        );
+        assert_eq!(
+            SerialConfig::parse("0x3e8"),
+            Some(SerialConfig {
+                port: 0x3E8,
+                baud: DEFAULT_BAUD
+            })
+        );
+        // 115200 does not divide down to 100000
+        assert_eq!(SerialConfig::parse("ttyS0,100000"), None);
+        assert_eq!(SerialConfig::parse("ttyS4"), None);
+        assert_eq!(SerialConfig::parse("com1"), None);
+    }
+
+    #[test_case]
+    fn test_debug_channel_kept_off_console() {
+        let console = config(SerialChannel::Console).unwrap();
+        assert!(matches!(
+            configure(SerialChannel::Debug, console),
+            Err(SerialError::PortInUse)
+        ));
+
+        let previous = config(SerialChannel::Debug);
+        let debug = SerialConfig {
+            port: COM_PORTS
+                .into_iter()
+                .find(|&port| port != console.port)
+                .unwrap(),
+            baud: 115_200,
+        };
+        configure(SerialChannel::Debug, debug).unwrap();
+        assert_eq!(config(SerialChannel::Debug), Some(debug));
+        assert!(matches!(
+            configure(SerialChannel::Console, debug),
+            Err(SerialError::PortInUse)
+        ));
+
+        match previous {
+            Some(previous) => configure(SerialChannel::Debug, previous).unwrap(),
+            None => close_debug(),
+        }
+    }
+}
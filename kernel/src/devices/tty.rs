@@ -0,0 +1,208 @@
+//! Line editing on the serial console.
+//!
+//! Bytes the console receives are queued by its interrupt handler, and
+//! `read_line` turns them into lines the way a terminal in canonical mode
+//! does: what is typed is echoed, backspace and delete erase the last
+//! character, and a carriage return or line feed ends the line. A CR LF
+//! pair ends only one line, so terminals sending either work. Other
+//! control characters are dropped.
+//!
+//! The receive interrupt is routed through the I/O APIC for the COM port
+//! the console is on at boot. `read_line` also polls the UART while it
+//! waits, so input still arrives, if less promptly, when the interrupt is
+//! not delivered.
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    arch::{core_id, without_interrupts},
+    devices::serial,
+    events::futures::{PriorityMutex, WaitQueue},
+    interrupts::{
+        idt::{allocate_vector, free_vector},
+        ioapic,
+    },
+    serial_println,
+};
+
+/// Received bytes queued before further ones are dropped
+const RECEIVE_CAPACITY: usize = 256;
+/// Longest line kept. Bytes typed past it are dropped, with a bell.
+pub const MAX_LINE: usize = 256;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const BELL: u8 = 0x07;
+
+/// Bytes received and not yet read. Only locked with interrupts off, since
+/// the receive interrupt fills it.
+static RECEIVED: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+/// Bytes dropped because nobody read them in time
+static OVERRUNS: AtomicU64 = AtomicU64::new(0);
+
+/// Readers waiting for bytes
+static RECEIVE_WAITERS: WaitQueue = WaitQueue::new();
+
+/// The line being typed. Held by one reader at a time, so concurrent
+/// readers take whole lines in turn.
+static READER: PriorityMutex<LineDiscipline> = PriorityMutex::new(LineDiscipline::new());
+
+/// Turns received bytes into edited lines
+#[derive(Debug, Default)]
+pub struct LineDiscipline {
+    line: Vec<u8>,
+    /// Whether the last byte ended a line with a carriage return
+    after_cr: bool,
+}
+
+impl LineDiscipline {
+    pub const fn new() -> Self {
+        LineDiscipline {
+            line: Vec::new(),
+            after_cr: false,
+        }
+    }
+
+    /// Takes one received byte, adding what the terminal should show to
+    /// `echo`
+    ///
+    /// # Returns
+    /// The line, without its terminator, if the byte ended one
+    pub fn input(&mut self, byte: u8, echo: &mut Vec<u8>) -> Option<String> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                echo.extend_from_slice(b"\r\n");
+                let line = core::mem::take(&mut self.line);
+                Some(String::from_utf8_lossy(&line).into_owned())
+            }
+            BACKSPACE | DELETE => {
+                if self.line.is_empty() {
+                    return None;
+                }
+                // A character may take several bytes, and is erased whole
+                while self.line.pop().is_some_and(|last| last & 0xC0 == 0x80) {}
+                echo.extend_from_slice(b"\x08 \x08");
+                None
+            }
+            byte if byte < 0x20 => None,
+            _ if self.line.len() >= MAX_LINE => {
+                echo.push(BELL);
+                None
+            }
+            byte => {
+                self.line.push(byte);
+                echo.push(byte);
+                None
+            }
+        }
+    }
+}
+
+/// Moves whatever the UART has received into the queue
+///
+/// # Returns
+/// Whether anything was received
+fn receive() -> bool {
+    without_interrupts(|| {
+        // Held while reading, so bytes are queued in the order they came
+        let mut received = RECEIVED.lock();
+        let mut any = false;
+        while let Some(byte) = serial::console_receive() {
+            any = true;
+            if received.len() < RECEIVE_CAPACITY {
+                received.push_back(byte);
+            } else {
+                OVERRUNS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        any
+    })
+}
+
+fn receive_interrupt() {
+    if receive() {
+        RECEIVE_WAITERS.wake_all();
+    }
+}
+
+/// Routes the console's receive interrupt to this core
+pub fn init() {
+    let Some(irq) = serial::console_irq() else {
+        return;
+    };
+    let Some(vector) = allocate_vector(receive_interrupt) else {
+        serial_println!("No vector for serial input, polling instead");
+        return;
+    };
+    if !ioapic::route_isa_irq(irq, vector, core_id()) {
+        serial_println!("Cannot route IRQ {}, polling serial input instead", irq);
+        free_vector(vector);
+    }
+}
+
+/// Bytes received on the console that were dropped because the queue was
+/// full
+pub fn overruns() -> u64 {
+    OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Waits for a line typed on the console, echoing it as it is typed
+///
+/// # Returns
+/// The line, without its terminator. Bytes that are not UTF-8 are
+/// replaced.
+pub async fn read_line() -> String {
+    let mut discipline = READER.lock().await;
+    loop {
+        let mut echo = Vec::new();
+        let mut line = None;
+        while line.is_none() {
+            let Some(byte) = without_interrupts(|| RECEIVED.lock().pop_front()) else {
+                break;
+            };
+            line = discipline.input(byte, &mut echo);
+        }
+        serial::console_write(&echo);
+        if let Some(line) = line {
+            return line;
+        }
+        RECEIVE_WAITERS
+            .poll_until(|| receive() || without_interrupts(|| !RECEIVED.lock().is_empty()))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_line_discipline() {
+        let mut discipline = LineDiscipline::new();
+        let mut echo = Vec::new();
+        let mut lines = Vec::new();
+        let typed = "ab\x7fc\r\nxy\x08\x08\x08z\x1b\n\u{e9}!\x7f\x7f\r";
+        for &byte in typed.as_bytes() {
+            lines.extend(discipline.input(byte, &mut echo));
+        }
+        assert_eq!(lines, ["ac", "z", ""]);
+        assert_eq!(
+            echo,
+            b"ab\x08 \x08c\r\nxy\x08 \x08\x08 \x08z\r\n\xc3\xa9!\x08 \x08\x08 \x08\r\n"
+        );
+
+        // Bytes past the longest line ring the bell instead
+        echo.clear();
+        for _ in 0..MAX_LINE + 2 {
+            assert_eq!(discipline.input(b'x', &mut echo), None);
+        }
+        assert_eq!(echo.iter().filter(|&&byte| byte == BELL).count(), 2);
+        assert_eq!(discipline.input(b'\n', &mut echo).unwrap().len(), MAX_LINE);
+    }
+}
@@ -0,0 +1,261 @@
+//! USB HID boot protocol keyboards.
+//!
+//! Keyboards that support the boot protocol send a fixed 8 byte report:
+//! a modifier bitmap, a reserved byte, and the usage IDs of up to six keys
+//! held down. The driver switches the interface to the boot protocol, so
+//! the report descriptor never has to be parsed, polls the interrupt IN
+//! endpoint, and compares each report with the last one to find which keys
+//! went down or up. Every change becomes a `KeyEvent` on the kernel input
+//! channel.
+
+use alloc::boxed::Box;
+
+use crate::{
+    devices::input::{self, InputEvent, KeyEvent, Modifiers},
+    serial_println,
+};
+
+use super::{
+    EndpointDescriptor, InterfaceDescriptor, SetupPacket, UsbDevice, UsbError,
+    REQUEST_TYPE_CLASS_INTERFACE,
+};
+
+/// Interface class of human interface devices
+const CLASS_HID: u8 = 0x03;
+/// Subclass of HID interfaces that support the boot protocol
+const SUBCLASS_BOOT: u8 = 0x01;
+/// Boot interface protocol of keyboards
+const PROTOCOL_KEYBOARD: u8 = 0x01;
+
+/// HID class requests
+const REQUEST_SET_IDLE: u8 = 0x0A;
+const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+/// SET_PROTOCOL value selecting the boot protocol
+const BOOT_PROTOCOL: u16 = 0;
+
+/// Length of a boot keyboard report
+pub const BOOT_REPORT_LENGTH: usize = 8;
+/// Keys a boot report can hold at once
+const REPORT_KEYS: usize = 6;
+/// Sent in every key slot when more keys are held than fit
+const USAGE_ERROR_ROLLOVER: u8 = 0x01;
+/// Usage ID of the left control key, the first of the eight modifiers
+const USAGE_LEFT_CTRL: u8 = 0xE0;
+
+/// Returns true for interfaces of keyboards that speak the boot protocol
+pub fn is_boot_keyboard(interface: &InterfaceDescriptor) -> bool {
+    interface.class == CLASS_HID
+        && interface.subclass == SUBCLASS_BOOT
+        && interface.protocol == PROTOCOL_KEYBOARD
+}
+
+/// Returns the character a key types, if it types one
+fn usage_to_ascii(usage: u8, modifiers: Modifiers) -> Option<u8> {
+    const DIGITS: &[u8; 10] = b"1234567890";
+    const SHIFTED_DIGITS: &[u8; 10] = b"!@#$%^&*()";
+    /// Usages 0x2D through 0x38
+    const PUNCTUATION: &[u8; 12] = b"-=[]\\#;'`,./";
+    const SHIFTED_PUNCTUATION: &[u8; 12] = b"_+{}|~:\"~<>?";
+
+    let shift = modifiers.shift();
+    let ascii = match usage {
+        0x04..=0x1D => {
+            let letter = b'a' + (usage - 0x04);
+            if modifiers.ctrl() {
+                // Control characters, like ^C
+                letter - b'a' + 1
+            } else if shift {
+                letter.to_ascii_uppercase()
+            } else {
+                letter
+            }
+        }
+        0x1E..=0x27 if shift => SHIFTED_DIGITS[(usage - 0x1E) as usize],
+        0x1E..=0x27 => DIGITS[(usage - 0x1E) as usize],
+        0x28 => b'\n',
+        0x29 => 0x1B,
+        0x2A => 0x08,
+        0x2B => b'\t',
+        0x2C => b' ',
+        0x2D..=0x38 if shift => SHIFTED_PUNCTUATION[(usage - 0x2D) as usize],
+        0x2D..=0x38 => PUNCTUATION[(usage - 0x2D) as usize],
+        _ => return None,
+    };
+    Some(ascii)
+}
+
+/// Tracks what a keyboard last reported
+#[derive(Debug, Default)]
+pub struct BootKeyboard {
+    modifiers: Modifiers,
+    keys: [u8; REPORT_KEYS],
+}
+
+impl BootKeyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compares a report with the last one and emits an event for every key
+    /// that went up or down. Releases come first, so a key moving between
+    /// slots is not seen as released.
+    pub fn handle_report(
+        &mut self,
+        report: &[u8; BOOT_REPORT_LENGTH],
+        mut emit: impl FnMut(KeyEvent),
+    ) {
+        let keys: [u8; REPORT_KEYS] = report[2..].try_into().expect("Report holds six keys");
+        // Too many keys are held to tell which, so nothing changed
+        if keys.contains(&USAGE_ERROR_ROLLOVER) {
+            return;
+        }
+        let modifiers = Modifiers::from_bits_retain(report[0]);
+
+        let event = |usage: u8, pressed: bool| KeyEvent {
+            usage,
+            pressed,
+            modifiers,
+            ascii: if pressed {
+                usage_to_ascii(usage, modifiers)
+            } else {
+                None
+            },
+        };
+
+        for usage in self.keys {
+            if usage != 0 && !keys.contains(&usage) {
+                emit(event(usage, false));
+            }
+        }
+        let changed = self.modifiers ^ modifiers;
+        for bit in 0..8 {
+            let modifier = Modifiers::from_bits_retain(1 << bit);
+            if changed.contains(modifier) {
+                emit(event(USAGE_LEFT_CTRL + bit, modifiers.contains(modifier)));
+            }
+        }
+        for usage in keys {
+            if usage != 0 && !self.keys.contains(&usage) {
+                emit(event(usage, true));
+            }
+        }
+
+        self.modifiers = modifiers;
+        self.keys = keys;
+    }
+}
+
+/// Switches the interface to the boot protocol and sets up its interrupt
+/// endpoint
+fn configure(
+    device: &mut dyn UsbDevice,
+    interface: &InterfaceDescriptor,
+    endpoint: &EndpointDescriptor,
+) -> Result<(), UsbError> {
+    device.control_out(SetupPacket {
+        request_type: REQUEST_TYPE_CLASS_INTERFACE,
+        request: REQUEST_SET_PROTOCOL,
+        value: BOOT_PROTOCOL,
+        index: interface.number as u16,
+        length: 0,
+    })?;
+    // Only report when something changes. Optional, so a stall is fine.
+    match device.control_out(SetupPacket {
+        request_type: REQUEST_TYPE_CLASS_INTERFACE,
+        request: REQUEST_SET_IDLE,
+        value: 0,
+        index: interface.number as u16,
+        length: 0,
+    }) {
+        Ok(()) | Err(UsbError::Stall) => {}
+        Err(error) => return Err(error),
+    }
+    device.configure_endpoint(endpoint)
+}
+
+/// Drives a boot keyboard until it is unplugged
+///
+/// # Arguments
+/// * `device` - Handle on the keyboard
+/// * `interface` - The keyboard's boot interface
+/// * `endpoint` - The interface's interrupt IN endpoint
+pub async fn run_keyboard(
+    mut device: Box<dyn UsbDevice>,
+    interface: InterfaceDescriptor,
+    endpoint: EndpointDescriptor,
+) {
+    if let Err(error) = configure(&mut *device, &interface, &endpoint) {
+        serial_println!("USB keyboard: setup failed: {:?}", error);
+        return;
+    }
+
+    let mut keyboard = BootKeyboard::new();
+    let mut report = [0u8; BOOT_REPORT_LENGTH];
+    loop {
+        match device.interrupt_in(&endpoint, &mut report).await {
+            Ok(BOOT_REPORT_LENGTH) => {
+                keyboard.handle_report(&report, |event| input::report(InputEvent::Key(event)))
+            }
+            // Boot reports are never shorter, this one is garbage
+            Ok(_) => {}
+            Err(UsbError::Disconnected) => return,
+            Err(error) => serial_println!("USB keyboard: transfer failed: {:?}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn report(modifiers: Modifiers, keys: &[u8]) -> [u8; BOOT_REPORT_LENGTH] {
+        let mut report = [0u8; BOOT_REPORT_LENGTH];
+        report[0] = modifiers.bits();
+        report[2..2 + keys.len()].copy_from_slice(keys);
+        report
+    }
+
+    #[test_case]
+    fn test_boot_keyboard_reports() {
+        let mut keyboard = BootKeyboard::new();
+        let mut events = Vec::new();
+
+        // 'a' down, then shift and 'b' down while 'a' moves slots
+        keyboard.handle_report(&report(Modifiers::empty(), &[0x04]), |e| events.push(e));
+        keyboard.handle_report(&report(Modifiers::LEFT_SHIFT, &[0x05, 0x04]), |e| {
+            events.push(e)
+        });
+        // Rollover reports change nothing
+        keyboard.handle_report(&report(Modifiers::empty(), &[1; 6]), |e| events.push(e));
+        keyboard.handle_report(&report(Modifiers::empty(), &[]), |e| events.push(e));
+
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| (e.usage, e.pressed, e.ascii))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0x04, true, Some(b'a')),
+                (0xE1, true, None),
+                (0x05, true, Some(b'B')),
+                (0x05, false, None),
+                (0x04, false, None),
+                (0xE1, false, None),
+            ]
+        );
+
+        // Events reach the input channel
+        let receiver = input::subscribe();
+        while receiver.try_recv().is_ok() {}
+        keyboard.handle_report(&report(Modifiers::LEFT_CTRL, &[0x06]), |e| {
+            input::report(InputEvent::Key(e))
+        });
+        let InputEvent::Key(ctrl) = receiver.try_recv().unwrap();
+        assert_eq!(ctrl.usage, USAGE_LEFT_CTRL);
+        let InputEvent::Key(key) = receiver.try_recv().unwrap();
+        assert_eq!(key.ascii, Some(0x03));
+    }
+}
@@ -0,0 +1,130 @@
+//! USB class drivers.
+//!
+//! A host controller driver enumerates the devices on its ports, reads
+//! their configuration descriptors, and offers each interface to
+//! `bind_interface` along with a `UsbDevice` handle it implements. Class
+//! drivers only talk to the device through that handle, so they do not
+//! depend on the controller. The handle owns the transfer rings: a class
+//! driver asks for an endpoint to be configured and then queues transfers
+//! on it.
+//!
+//! There is no host controller driver yet. An xHCI driver is expected to
+//! implement `UsbDevice` for its slots and schedule the tasks
+//! `bind_interface` returns.
+
+use alloc::boxed::Box;
+use core::{future::Future, pin::Pin};
+
+pub mod hid;
+
+/// USB standard request type: host to device, class request, to an
+/// interface
+pub const REQUEST_TYPE_CLASS_INTERFACE: u8 = 0x21;
+
+/// Endpoint address bit set for device to host endpoints
+const ENDPOINT_DIRECTION_IN: u8 = 0x80;
+/// Transfer type bits of an endpoint's attributes
+const ENDPOINT_TRANSFER_TYPE: u8 = 0x03;
+const TRANSFER_TYPE_INTERRUPT: u8 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// The device was unplugged
+    Disconnected,
+    /// The device answered a request with a STALL handshake
+    Stall,
+    /// The transfer failed on the bus
+    Transaction,
+    /// The controller has no room for another transfer ring
+    NoResources,
+    /// The endpoint is not configured, or not of the right type
+    InvalidEndpoint,
+}
+
+/// The setup stage of a control transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+/// The fields of an interface descriptor class drivers match on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+/// The fields of an endpoint descriptor class drivers use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    /// Endpoint number, with `ENDPOINT_DIRECTION_IN` set for IN endpoints
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    /// Polling interval, in frames or in the controller's encoding for the
+    /// device's speed
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn is_in(&self) -> bool {
+        self.address & ENDPOINT_DIRECTION_IN != 0
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        self.attributes & ENDPOINT_TRANSFER_TYPE == TRANSFER_TYPE_INTERRUPT
+    }
+}
+
+/// A transfer queued on an endpoint, completing with the bytes moved
+pub type TransferFuture<'a> = Pin<Box<dyn Future<Output = Result<usize, UsbError>> + Send + 'a>>;
+
+/// A task driving a bound interface, to be scheduled by the host
+/// controller driver
+pub type ClassTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// An enumerated device, as seen by class drivers
+pub trait UsbDevice: Send {
+    /// Runs a control transfer with no data stage on the default endpoint
+    fn control_out(&mut self, setup: SetupPacket) -> Result<(), UsbError>;
+
+    /// Sets up a transfer ring for an endpoint of the current configuration
+    fn configure_endpoint(&mut self, endpoint: &EndpointDescriptor) -> Result<(), UsbError>;
+
+    /// Queues a transfer from an interrupt IN endpoint into `buffer`, which
+    /// completes once the device sends a report
+    fn interrupt_in<'a>(
+        &'a mut self,
+        endpoint: &EndpointDescriptor,
+        buffer: &'a mut [u8],
+    ) -> TransferFuture<'a>;
+}
+
+/// Binds an interface to the first class driver that can drive it
+///
+/// # Arguments
+/// * `device` - Handle on the device the interface belongs to
+/// * `interface` - The interface
+/// * `endpoints` - The interface's endpoints, from its descriptors
+///
+/// # Returns
+/// The task running the driver, or None if no driver matched
+pub fn bind_interface(
+    device: Box<dyn UsbDevice>,
+    interface: InterfaceDescriptor,
+    endpoints: &[EndpointDescriptor],
+) -> Option<ClassTask> {
+    if hid::is_boot_keyboard(&interface) {
+        let endpoint = *endpoints
+            .iter()
+            .find(|endpoint| endpoint.is_in() && endpoint.is_interrupt())?;
+        return Some(Box::pin(hid::run_keyboard(device, interface, endpoint)));
+    }
+    None
+}
@@ -0,0 +1,429 @@
+//! Virtio block device driver.
+//!
+//! Moves sectors to and from a virtio-blk disk through the device's single
+//! request queue. A request is a chain of a header the device reads, the
+//! data frames, and a status byte the device writes back. One request is
+//! in flight at a time. The device's MSI-X interrupt wakes the transfer
+//! waiting on it, and the used ring is also checked on every poll, so a
+//! device without a working interrupt still completes requests.
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::structures::paging::{OffsetPageTable, PhysFrame};
+
+use crate::{
+    constants::memory::PAGE_SIZE,
+    debug_println,
+    devices::{
+        dma::{for_each_buffer, frame_ptr},
+        drivers::{find_device, DriverError, PciDriver, PciMatch},
+        manager::{DeviceClass, DeviceHandle, DeviceState, DEVICE_MANAGER},
+        pci::{disable_msi, enable_msi, DeviceInfo, MsiKind},
+    },
+    events::futures::{run_to_completion, PriorityMutex, WaitQueue},
+    filesys::{
+        block::stats::{DiskStats, IoDirection},
+        BlockDevice, FsError,
+    },
+    interrupts::x2apic,
+    memory::frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+    processes::rusage::account_block_io,
+    time,
+};
+
+use super::{
+    queue::{Buffer, VirtQueue},
+    virtio_pci_id, VirtioDeviceType, VirtioError, VirtioPciDevice, VIRTIO_F_VERSION_1,
+    VIRTIO_VENDOR_ID,
+};
+
+/// PCI device ID of a transitional virtio-blk device, which QEMU creates
+/// by default. It has the modern capabilities as well.
+const TRANSITIONAL_BLK_DEVICE_ID: u16 = 0x1001;
+
+/// Index of the request queue
+const REQUEST_QUEUE: u16 = 0;
+/// Number of descriptors used for the request queue, enough for the
+/// header, every data frame and the status byte
+const REQUEST_QUEUE_SIZE: u16 = 32;
+
+/// The device refuses writes
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The device has a write cache that can be flushed
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// Offset of the capacity, in sectors, in the device configuration
+const CONFIG_CAPACITY: u64 = 0;
+
+/// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+/// Status the device writes for a request that succeeded
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// Where the status byte sits in the header frame, after the header
+const STATUS_OFFSET: u64 = size_of::<RequestHeader>() as u64;
+
+/// Virtio sectors are always 512 bytes, whatever the disk's block size
+const SECTOR_SIZE: usize = 512;
+/// DMA frames of each device, which bounds the sectors one request moves
+const DMA_FRAMES: usize = 16;
+const MAX_SECTORS_PER_REQUEST: usize = DMA_FRAMES * PAGE_SIZE / SECTOR_SIZE;
+
+/// How long a request may take before it is given up on
+const REQUEST_TIMEOUT_NS: u64 = 1_000_000_000;
+/// Polls a request may take while the clock is not calibrated
+const MAX_ITERATIONS: usize = 1_000_000;
+
+/// Header at the start of every request
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Woken whenever a virtio-blk device interrupts. Devices share it, and
+/// each transfer checks its own used ring once woken.
+static BLK_WAITERS: WaitQueue = WaitQueue::new();
+
+/// A virtio-blk device with its request queue
+struct VirtioBlk {
+    device: VirtioPciDevice,
+    requests: VirtQueue,
+    /// Holds the request header, then the status byte
+    header_frame: PhysFrame,
+    /// Frames data moves through
+    buffers: Vec<PhysFrame>,
+    /// Whether the device has a write cache to flush
+    can_flush: bool,
+}
+
+/// A virtio-blk disk. Clones share the device, and each request holds it
+/// until done.
+#[derive(Clone)]
+pub struct VirtioBlkDisk {
+    blk: Arc<PriorityMutex<VirtioBlk>>,
+    sectors: u64,
+    read_only: bool,
+    stats: Arc<DiskStats>,
+}
+
+/// The virtio block devices this driver supports
+const BLK_ID_TABLE: &[PciMatch] = &[
+    virtio_pci_id(VirtioDeviceType::Block),
+    PciMatch::Device {
+        vendor_id: VIRTIO_VENDOR_ID,
+        device_id: TRANSITIONAL_BLK_DEVICE_ID,
+    },
+];
+
+/// Registry entry for the virtio block driver
+pub static VIRTIO_BLK_DRIVER: PciDriver = PciDriver {
+    name: "Virtio blk",
+    id_table: BLK_ID_TABLE,
+    probe: probe_virtio_blk,
+};
+
+/// Finds the FIRST virtio block device on the PCI bus
+pub fn find_virtio_blk(devices: &[Arc<Mutex<DeviceInfo>>]) -> Option<Arc<Mutex<DeviceInfo>>> {
+    find_device(devices, BLK_ID_TABLE)
+}
+
+/// Probe function for the driver registry. The disk is registered as a
+/// block device behind its PCI function.
+fn probe_virtio_blk(
+    handle: DeviceHandle,
+    blk_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), DriverError> {
+    let disk = initialize_virtio_blk(blk_arc, mapper).map_err(DriverError::Virtio)?;
+    let mut manager = DEVICE_MANAGER.lock();
+    manager.set_state(handle, DeviceState::Active);
+    let node = manager.register("virtio disk".into(), DeviceClass::Block, Some(handle));
+    manager.bind(node, VIRTIO_BLK_DRIVER.name);
+    manager.activate(node, disk);
+    Result::Ok(())
+}
+
+/// Sets up a virtio block device
+pub fn initialize_virtio_blk(
+    blk_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<VirtioBlkDisk, VirtioError> {
+    let info = blk_arc.lock();
+    let device = VirtioPciDevice::new(&info, mapper)?;
+    let features = device.begin_init(VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH)?;
+    let requests = device.setup_queue(REQUEST_QUEUE, REQUEST_QUEUE_SIZE)?;
+    let sectors: u64 = device.read_device_config(CONFIG_CAPACITY);
+
+    let header_frame = alloc_frame_zeroed().ok_or(VirtioError::OutOfMemory)?;
+    // Frames are only owned once the device holds them, so its Drop frees
+    // them if the rest cannot be had
+    let mut blk = VirtioBlk {
+        device,
+        requests,
+        header_frame,
+        buffers: Vec::with_capacity(DMA_FRAMES),
+        can_flush: features & VIRTIO_BLK_F_FLUSH != 0,
+    };
+    for _ in 0..DMA_FRAMES {
+        blk.buffers
+            .push(alloc_frame_zeroed().ok_or(VirtioError::OutOfMemory)?);
+    }
+    // Virtio only signals used buffers through MSI-X entries, so a device
+    // limited to MSI is polled like one without an interrupt
+    let core = x2apic::current_core_id() as u32;
+    match enable_msi(&info, mapper, core, handle_virtio_blk_interrupt) {
+        Ok(interrupt) if interrupt.kind == MsiKind::MsiX => {
+            if blk.device.set_queue_msix_entry(REQUEST_QUEUE, 0).is_err() {
+                disable_msi(&info, interrupt);
+            }
+        }
+        Ok(interrupt) => disable_msi(&info, interrupt),
+        Err(e) => {
+            debug_println!("Virtio blk has no interrupt: {:?}", e);
+        }
+    }
+    blk.device.finish_init();
+
+    Result::Ok(VirtioBlkDisk {
+        blk: Arc::new(PriorityMutex::new(blk)),
+        sectors,
+        read_only: features & VIRTIO_BLK_F_RO != 0,
+        stats: DiskStats::register("vd"),
+    })
+}
+
+/// Wakes the transfers waiting on virtio-blk devices. Called from the
+/// interrupt handler; MSI-X interrupts need no acknowledging.
+pub fn handle_virtio_blk_interrupt() {
+    BLK_WAITERS.wake_all();
+}
+
+impl VirtioBlk {
+    /// Sends one request moving `len` bytes through the start of the DMA
+    /// frames, and waits for the device to finish it
+    async fn request(&mut self, kind: u32, sector: u64, len: usize) -> Result<(), VirtioError> {
+        let header = frame_ptr(self.header_frame);
+        let status = unsafe { header.add(STATUS_OFFSET as usize) };
+        unsafe {
+            core::ptr::write_volatile(
+                header as *mut RequestHeader,
+                RequestHeader {
+                    kind,
+                    reserved: 0,
+                    sector,
+                },
+            );
+            // Not a status the device writes, so a request left unfinished
+            // is not taken for a success
+            core::ptr::write_volatile(status, u8::MAX);
+        }
+
+        let header_address = self.header_frame.start_address();
+        let mut chain = Vec::with_capacity(DMA_FRAMES + 2);
+        chain.push(Buffer {
+            addr: header_address,
+            len: size_of::<RequestHeader>() as u32,
+            device_writable: false,
+        });
+        for (i, frame) in self.buffers.iter().enumerate() {
+            let start = i * PAGE_SIZE;
+            if start >= len {
+                break;
+            }
+            chain.push(Buffer {
+                addr: frame.start_address(),
+                len: (len - start).min(PAGE_SIZE) as u32,
+                device_writable: kind == VIRTIO_BLK_T_IN,
+            });
+        }
+        chain.push(Buffer {
+            addr: header_address + STATUS_OFFSET,
+            len: 1,
+            device_writable: true,
+        });
+
+        let head = self.requests.submit(&chain)?;
+        self.device.notify(&self.requests);
+
+        let deadline = time::monotonic_ns().saturating_add(REQUEST_TIMEOUT_NS);
+        let mut polls = 0;
+        let mut used = None;
+        let requests = &mut self.requests;
+        BLK_WAITERS
+            .poll_until(|| {
+                used = requests.pop_used();
+                if used.is_some() {
+                    return true;
+                }
+                polls += 1;
+                // Count polls instead while the clock is not calibrated
+                if time::tsc_frequency() == 0 {
+                    polls >= MAX_ITERATIONS
+                } else {
+                    time::monotonic_ns() >= deadline
+                }
+            })
+            .await;
+
+        match used {
+            None => Result::Err(VirtioError::Timeout),
+            Some((id, _)) if id != head => Result::Err(VirtioError::BadResponse),
+            Some(_) if unsafe { core::ptr::read_volatile(status) } != VIRTIO_BLK_S_OK => {
+                Result::Err(VirtioError::RequestFailed)
+            }
+            Some(_) => Result::Ok(()),
+        }
+    }
+}
+
+impl Drop for VirtioBlk {
+    fn drop(&mut self) {
+        let _ = self.device.reset();
+        dealloc_frame(self.header_frame);
+        self.buffers.drain(..).for_each(dealloc_frame);
+    }
+}
+
+impl VirtioBlkDisk {
+    /// Checks a request of `len` bytes from `sector` lies inside the disk
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), VirtioError> {
+        let sectors = (len / SECTOR_SIZE) as u64;
+        if len % SECTOR_SIZE != 0 || sector.saturating_add(sectors) > self.sectors {
+            return Result::Err(VirtioError::BadRequest);
+        }
+        Result::Ok(())
+    }
+
+    /// Reads whole sectors starting at `sector`, as many per request as
+    /// the DMA frames hold
+    pub async fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), VirtioError> {
+        self.check_range(sector, buf.len())?;
+        let mut blk = self.blk.lock().await;
+        for (i, chunk) in buf
+            .chunks_mut(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE)
+            .enumerate()
+        {
+            let start = sector + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            blk.request(VIRTIO_BLK_T_IN, start, chunk.len()).await?;
+            for_each_buffer(&blk.buffers, chunk.len(), |frame, offset, len| unsafe {
+                core::ptr::copy_nonoverlapping(frame, chunk[offset..].as_mut_ptr(), len);
+            });
+        }
+        Result::Ok(())
+    }
+
+    /// Writes whole sectors starting at `sector`, like `read_sectors`
+    pub async fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), VirtioError> {
+        self.check_range(sector, buf.len())?;
+        if self.read_only {
+            return Result::Err(VirtioError::BadRequest);
+        }
+        let mut blk = self.blk.lock().await;
+        for (i, chunk) in buf
+            .chunks(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE)
+            .enumerate()
+        {
+            for_each_buffer(&blk.buffers, chunk.len(), |frame, offset, len| unsafe {
+                core::ptr::copy_nonoverlapping(chunk[offset..].as_ptr(), frame, len);
+            });
+            let start = sector + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            blk.request(VIRTIO_BLK_T_OUT, start, chunk.len()).await?;
+        }
+        Result::Ok(())
+    }
+
+    /// Makes every completed write durable. A device without a write cache
+    /// has nothing to flush.
+    pub async fn flush(&self) -> Result<(), VirtioError> {
+        let mut blk = self.blk.lock().await;
+        if !blk.can_flush {
+            return Result::Ok(());
+        }
+        blk.request(VIRTIO_BLK_T_FLUSH, 0, 0).await
+    }
+
+    /// Whether the device refuses writes
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+impl BlockDevice for VirtioBlkDisk {
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.read_blocks(block_num, buf)
+    }
+
+    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.write_blocks(block_num, buf)
+    }
+
+    fn read_blocks(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let sectors = (buf.len() / SECTOR_SIZE) as u64;
+        self.stats
+            .record(IoDirection::Read, sectors, || {
+                run_to_completion(self.read_sectors(block_num, buf))
+            })
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(sectors, 0);
+        Result::Ok(())
+    }
+
+    fn write_blocks(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        if self.read_only {
+            return Result::Err(FsError::ReadOnly);
+        }
+        let sectors = (buf.len() / SECTOR_SIZE) as u64;
+        let stats = self.stats.clone();
+        stats
+            .record(IoDirection::Write, sectors, || {
+                run_to_completion(self.write_sectors(block_num, buf))
+            })
+            .map_err(|_| FsError::IOError)?;
+        account_block_io(0, sectors);
+        Result::Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        run_to_completion(VirtioBlkDisk::flush(self)).map_err(|_| FsError::IOError)
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.sectors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::manager::find_device_data;
+
+    #[test_case]
+    fn test_disk_round_trip() {
+        let mut disk = find_device_data::<VirtioBlkDisk>()
+            .expect("No virtio disk found")
+            .lock()
+            .clone();
+        // Spans more than one request's worth of DMA frames
+        let sectors = MAX_SECTORS_PER_REQUEST + 5;
+        let start = disk.total_blocks() - sectors as u64;
+        let data: Vec<u8> = (0..sectors * SECTOR_SIZE)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        disk.write_blocks(start, &data).unwrap();
+        BlockDevice::flush(&mut disk).unwrap();
+
+        let mut read = alloc::vec![0; data.len()];
+        disk.read_blocks(start, &mut read).unwrap();
+        assert!(read == data);
+        assert!(disk.read_blocks(disk.total_blocks(), &mut read).is_err());
+    }
+}
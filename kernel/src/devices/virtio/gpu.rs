@@ -0,0 +1,644 @@
+//! Virtio GPU driver.
+//!
+//! Drives a virtio-gpu device in 2D mode:
+//! - Queries the host for its display configuration
+//! - Allocates a guest framebuffer and attaches it as a host resource
+//! - Supports switching modes at runtime
+//! - Pushes framebuffer contents to the host on explicit flushes
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    constants::memory::{GPU_FRAMEBUFFER_MAX_SIZE, GPU_FRAMEBUFFER_START, PAGE_SIZE},
+    debug_println,
+    devices::{
+        dma::frame_ptr,
+        drivers::{find_device, DriverError, PciDriver, PciMatch},
+        manager::{DeviceClass, DeviceHandle, DeviceState, DEVICE_MANAGER},
+        pci::DeviceInfo,
+    },
+    memory::{
+        frame_allocator::{alloc_frame, dealloc_frame},
+        paging::{create_mapping, remove_mapped_frame},
+    },
+};
+
+use super::{
+    queue::{Buffer, VirtQueue},
+    virtio_pci_id, VirtioDeviceType, VirtioError, VirtioPciDevice,
+};
+
+/// Maximum number of scanouts a virtio gpu can report
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+/// Index of the control queue
+const CONTROL_QUEUE: u16 = 0;
+/// Number of descriptors used for the control queue
+const CONTROL_QUEUE_SIZE: u16 = 64;
+/// Offset of the response inside the request frame
+const RESPONSE_OFFSET: u64 = 2048;
+/// Bytes per pixel of the only format we use
+const BYTES_PER_PIXEL: u32 = 4;
+/// Mode used when the host does not report an enabled display
+const DEFAULT_MODE: (u32, u32) = (1024, 768);
+
+/// Control command types
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+/// Response types
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Pixel format with blue in the lowest byte, matching the limine framebuffer
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct CtrlHeader {
+    ctrl_type: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    padding: [u8; 3],
+}
+
+impl CtrlHeader {
+    fn new(ctrl_type: u32) -> Self {
+        CtrlHeader {
+            ctrl_type,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// A rectangle in pixels
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+/// A single display (scanout) as reported by the host
+pub struct DisplayInfo {
+    /// The preferred position and size of the display
+    pub rect: Rect,
+    /// Whether the display is connected
+    pub enabled: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RespDisplayInfo {
+    header: CtrlHeader,
+    displays: [DisplayInfo; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceCreate2D {
+    header: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceId {
+    header: CtrlHeader,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SetScanout {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceFlush {
+    header: CtrlHeader,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TransferToHost2D {
+    header: CtrlHeader,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AttachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+/// A host resource backed by guest memory that is shown on scanout 0
+struct Framebuffer {
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    /// Frames backing the resource, in order
+    frames: Vec<PhysFrame>,
+}
+
+/// A virtio gpu with a control queue and, once a mode is set, a framebuffer
+pub struct VirtioGpu {
+    device: VirtioPciDevice,
+    control: VirtQueue,
+    /// Frame holding the in flight request and its response
+    request_frame: PhysFrame,
+    /// Displays reported by the host during initialization
+    displays: Vec<DisplayInfo>,
+    framebuffer: Option<Framebuffer>,
+    next_resource_id: u32,
+}
+
+/// The virtio gpus this driver supports
+const GPU_ID_TABLE: &[PciMatch] = &[virtio_pci_id(VirtioDeviceType::Gpu)];
+
+/// Registry entry for the virtio gpu driver
+pub static VIRTIO_GPU_DRIVER: PciDriver = PciDriver {
+    name: "Virtio gpu",
+    id_table: GPU_ID_TABLE,
+    probe: probe_virtio_gpu,
+};
+
+/// Finds the FIRST virtio gpu on the PCI bus
+pub fn find_virtio_gpu(devices: &[Arc<Mutex<DeviceInfo>>]) -> Option<Arc<Mutex<DeviceInfo>>> {
+    find_device(devices, GPU_ID_TABLE)
+}
+
+/// Probe function for the driver registry. Only the first gpu found is
+/// bound, as every gpu would share the framebuffer window.
+fn probe_virtio_gpu(
+    handle: DeviceHandle,
+    gpu_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), DriverError> {
+    if DEVICE_MANAGER.lock().find::<VirtioGpu>().is_some() {
+        return Result::Err(DriverError::AlreadyBound);
+    }
+    let gpu = initialize_virtio_gpu(gpu_arc, mapper).map_err(DriverError::Virtio)?;
+    let mut manager = DEVICE_MANAGER.lock();
+    manager.set_state(handle, DeviceState::Active);
+    let display = manager.register("virtio display".into(), DeviceClass::Display, Some(handle));
+    manager.bind(display, VIRTIO_GPU_DRIVER.name);
+    manager.activate(display, gpu);
+    Result::Ok(())
+}
+
+/// Sets up a virtio gpu, queries the attached displays, and sets the
+/// preferred mode of the first enabled display.
+pub fn initialize_virtio_gpu(
+    gpu_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<VirtioGpu, VirtioError> {
+    let device = VirtioPciDevice::new(&gpu_arc.lock(), mapper)?;
+    device.begin_init(0)?;
+    let control = device.setup_queue(CONTROL_QUEUE, CONTROL_QUEUE_SIZE)?;
+    device.finish_init();
+
+    let request_frame = alloc_frame().ok_or(VirtioError::OutOfMemory)?;
+    let mut gpu = VirtioGpu {
+        device,
+        control,
+        request_frame,
+        displays: Vec::new(),
+        framebuffer: Option::None,
+        next_resource_id: 1,
+    };
+
+    gpu.displays = gpu.query_display_info()?;
+    let (width, height) = gpu
+        .displays
+        .iter()
+        .find(|display| display.enabled != 0)
+        .map(|display| (display.rect.width, display.rect.height))
+        .unwrap_or(DEFAULT_MODE);
+    gpu.set_mode(width, height, mapper)?;
+    debug_println!("Virtio gpu running at {}x{}", width, height);
+
+    Result::Ok(gpu)
+}
+
+impl VirtioGpu {
+    /// Kernel virtual address of the request frame
+    fn request_address(&self) -> u64 {
+        frame_ptr(self.request_frame) as u64
+    }
+
+    /// Sends a control command and waits for the response
+    ///
+    /// # Arguments
+    /// * `request` - The command, starting with a control header
+    /// * `extra` - Buffers placed between the request and the response
+    ///
+    /// # Returns
+    /// The response read back from the device
+    fn command<Req: Copy, Resp: Copy>(
+        &mut self,
+        request: Req,
+        extra: &[Buffer],
+    ) -> Result<Resp, VirtioError> {
+        let request_phys = self.request_frame.start_address();
+        let base = self.request_address();
+        unsafe {
+            core::ptr::write_volatile(base as *mut Req, request);
+            core::ptr::write_bytes((base + RESPONSE_OFFSET) as *mut u8, 0, size_of::<Resp>());
+        }
+
+        let mut buffers = Vec::with_capacity(extra.len() + 2);
+        buffers.push(Buffer {
+            addr: request_phys,
+            len: size_of::<Req>() as u32,
+            device_writable: false,
+        });
+        buffers.extend_from_slice(extra);
+        buffers.push(Buffer {
+            addr: request_phys + RESPONSE_OFFSET,
+            len: size_of::<Resp>() as u32,
+            device_writable: true,
+        });
+        self.device.submit_and_wait(&mut self.control, &buffers)?;
+
+        Result::Ok(unsafe { core::ptr::read_volatile((base + RESPONSE_OFFSET) as *const Resp) })
+    }
+
+    /// Sends a command that is answered with an OK_NODATA header
+    fn command_nodata<Req: Copy>(
+        &mut self,
+        request: Req,
+        extra: &[Buffer],
+    ) -> Result<(), VirtioError> {
+        let response: CtrlHeader = self.command(request, extra)?;
+        if response.ctrl_type != VIRTIO_GPU_RESP_OK_NODATA {
+            return Result::Err(VirtioError::BadResponse);
+        }
+        Result::Ok(())
+    }
+
+    fn query_display_info(&mut self) -> Result<Vec<DisplayInfo>, VirtioError> {
+        let response: RespDisplayInfo =
+            self.command(CtrlHeader::new(VIRTIO_GPU_CMD_GET_DISPLAY_INFO), &[])?;
+        if response.header.ctrl_type != VIRTIO_GPU_RESP_OK_DISPLAY_INFO {
+            return Result::Err(VirtioError::BadResponse);
+        }
+        Result::Ok(response.displays.to_vec())
+    }
+
+    /// Returns the displays reported by the host
+    pub fn displays(&self) -> &[DisplayInfo] {
+        &self.displays
+    }
+
+    /// Returns the current mode as (width, height), if one is set
+    pub fn mode(&self) -> Option<(u32, u32)> {
+        self.framebuffer
+            .as_ref()
+            .map(|framebuffer| (framebuffer.width, framebuffer.height))
+    }
+
+    /// Returns the framebuffer as a slice of B8G8R8X8 pixels, row major.
+    /// Changes become visible on the next flush.
+    pub fn framebuffer(&mut self) -> Option<&mut [u32]> {
+        self.framebuffer.as_ref().map(|framebuffer| unsafe {
+            core::slice::from_raw_parts_mut(
+                GPU_FRAMEBUFFER_START as *mut u32,
+                (framebuffer.width * framebuffer.height) as usize,
+            )
+        })
+    }
+
+    /// Switches the display to a new mode. A new framebuffer is allocated,
+    /// cleared, and attached; the old one is released.
+    ///
+    /// # Arguments
+    /// * `width` - Width of the new mode in pixels
+    /// * `height` - Height of the new mode in pixels
+    /// * `mapper` - The kernel mapper, used to map the framebuffer
+    pub fn set_mode(
+        &mut self,
+        width: u32,
+        height: u32,
+        mapper: &mut impl Mapper<Size4KiB>,
+    ) -> Result<(), VirtioError> {
+        let size = u64::from(width) * u64::from(height) * u64::from(BYTES_PER_PIXEL);
+        if size == 0 || size > GPU_FRAMEBUFFER_MAX_SIZE {
+            return Result::Err(VirtioError::BadResponse);
+        }
+
+        // The new framebuffer reuses the virtual window of the old one
+        self.release_framebuffer(mapper)?;
+
+        let resource_id = self.next_resource_id;
+        self.next_resource_id += 1;
+        self.command_nodata(
+            ResourceCreate2D {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+                resource_id,
+                format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+                width,
+                height,
+            },
+            &[],
+        )?;
+
+        let num_pages = size.div_ceil(PAGE_SIZE as u64);
+        let mut frames = Vec::with_capacity(num_pages as usize);
+        for i in 0..num_pages {
+            let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(
+                GPU_FRAMEBUFFER_START + i * PAGE_SIZE as u64,
+            ));
+            let frame = create_mapping(
+                page,
+                mapper,
+                Some(
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                ),
+            );
+            frames.push(frame);
+        }
+        self.framebuffer = Option::Some(Framebuffer {
+            resource_id,
+            width,
+            height,
+            frames,
+        });
+
+        self.attach_backing()?;
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        self.command_nodata(
+            SetScanout {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_SET_SCANOUT),
+                rect,
+                scanout_id: 0,
+                resource_id,
+            },
+            &[],
+        )?;
+        self.flush(rect)
+    }
+
+    /// Tells the host which guest frames back the current framebuffer.
+    /// Physically contiguous frames are coalesced into a single entry.
+    fn attach_backing(&mut self) -> Result<(), VirtioError> {
+        let Some(framebuffer) = self.framebuffer.as_ref() else {
+            return Result::Ok(());
+        };
+
+        let mut entries: Vec<MemEntry> = Vec::new();
+        for frame in &framebuffer.frames {
+            let address = frame.start_address().as_u64();
+            match entries.last_mut() {
+                Some(entry) if entry.addr + u64::from(entry.length) == address => {
+                    entry.length += PAGE_SIZE as u32;
+                }
+                _ => entries.push(MemEntry {
+                    addr: address,
+                    length: PAGE_SIZE as u32,
+                    padding: 0,
+                }),
+            }
+        }
+
+        // The entries are handed to the device in frames of their own
+        let entries_per_frame = PAGE_SIZE / size_of::<MemEntry>();
+        let mut entry_frames = Vec::new();
+        let mut buffers = Vec::new();
+        for chunk in entries.chunks(entries_per_frame) {
+            let frame = match alloc_frame() {
+                Some(frame) => frame,
+                None => {
+                    entry_frames.into_iter().for_each(dealloc_frame);
+                    return Result::Err(VirtioError::OutOfMemory);
+                }
+            };
+            let address = frame_ptr(frame) as u64;
+            for (i, entry) in chunk.iter().enumerate() {
+                unsafe { core::ptr::write_volatile((address as *mut MemEntry).add(i), *entry) };
+            }
+            buffers.push(Buffer {
+                addr: frame.start_address(),
+                len: size_of_val(chunk) as u32,
+                device_writable: false,
+            });
+            entry_frames.push(frame);
+        }
+
+        let result = self.command_nodata(
+            AttachBacking {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+                resource_id: framebuffer.resource_id,
+                nr_entries: entries.len() as u32,
+            },
+            &buffers,
+        );
+        // The device may still read frames it never let go of
+        if !matches!(result, Result::Err(VirtioError::Unresponsive)) {
+            entry_frames.into_iter().for_each(dealloc_frame);
+        }
+        result
+    }
+
+    /// Disables the scanout and frees the current framebuffer, if any
+    fn release_framebuffer(
+        &mut self,
+        mapper: &mut impl Mapper<Size4KiB>,
+    ) -> Result<(), VirtioError> {
+        let Some(framebuffer) = self.framebuffer.take() else {
+            return Result::Ok(());
+        };
+
+        // A resource id of 0 disables the scanout
+        self.command_nodata(
+            SetScanout {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_SET_SCANOUT),
+                rect: Rect::default(),
+                scanout_id: 0,
+                resource_id: 0,
+            },
+            &[],
+        )?;
+        self.command_nodata(
+            ResourceId {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING),
+                resource_id: framebuffer.resource_id,
+                padding: 0,
+            },
+            &[],
+        )?;
+        self.command_nodata(
+            ResourceId {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_UNREF),
+                resource_id: framebuffer.resource_id,
+                padding: 0,
+            },
+            &[],
+        )?;
+
+        for i in 0..framebuffer.frames.len() as u64 {
+            let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(
+                GPU_FRAMEBUFFER_START + i * PAGE_SIZE as u64,
+            ));
+            remove_mapped_frame(page, mapper);
+        }
+        Result::Ok(())
+    }
+
+    /// Copies a region of the framebuffer to the host and displays it
+    ///
+    /// # Arguments
+    /// * `rect` - The region to update, clipped to the current mode
+    pub fn flush(&mut self, rect: Rect) -> Result<(), VirtioError> {
+        let Some(framebuffer) = self.framebuffer.as_ref() else {
+            return Result::Ok(());
+        };
+        let resource_id = framebuffer.resource_id;
+        let rect = Rect {
+            x: rect.x.min(framebuffer.width),
+            y: rect.y.min(framebuffer.height),
+            width: rect.width.min(framebuffer.width.saturating_sub(rect.x)),
+            height: rect.height.min(framebuffer.height.saturating_sub(rect.y)),
+        };
+        if rect.width == 0 || rect.height == 0 {
+            return Result::Ok(());
+        }
+        let offset = (u64::from(rect.y) * u64::from(framebuffer.width) + u64::from(rect.x))
+            * u64::from(BYTES_PER_PIXEL);
+
+        self.command_nodata(
+            TransferToHost2D {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+                rect,
+                offset,
+                resource_id,
+                padding: 0,
+            },
+            &[],
+        )?;
+        self.command_nodata(
+            ResourceFlush {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+                rect,
+                resource_id,
+                padding: 0,
+            },
+            &[],
+        )
+    }
+
+    /// Copies the entire framebuffer to the host and displays it
+    pub fn flush_all(&mut self) -> Result<(), VirtioError> {
+        match self.mode() {
+            Some((width, height)) => self.flush(Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            }),
+            None => Result::Ok(()),
+        }
+    }
+}
+
+impl Drop for VirtioGpu {
+    fn drop(&mut self) {
+        if self.device.reset().is_ok() {
+            dealloc_frame(self.request_frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        devices::{manager::find_device_data, virtio::DeviceStatus},
+        memory::MAPPER,
+    };
+
+    #[test_case]
+    fn test_mode_switch() {
+        let gpu = find_device_data::<VirtioGpu>().expect("No virtio gpu found");
+        let mut gpu = gpu.lock();
+        let mut mapper = MAPPER.lock();
+        let original = gpu.mode().unwrap();
+
+        // Modes that do not fit are refused, keeping the current one
+        assert!(gpu.set_mode(0, 480, &mut *mapper).is_err());
+        assert!(gpu.set_mode(1 << 20, 1 << 20, &mut *mapper).is_err());
+        assert_eq!(gpu.mode(), Some(original));
+
+        gpu.set_mode(640, 480, &mut *mapper).unwrap();
+        assert_eq!(gpu.mode(), Some((640, 480)));
+        let pixels = gpu.framebuffer().unwrap();
+        assert_eq!(pixels.len(), 640 * 480);
+        pixels.fill(0x00FF_0000);
+        // Flushes are clipped to the mode, and empty ones send nothing
+        gpu.flush(Rect {
+            x: 600,
+            y: 400,
+            width: 100,
+            height: 100,
+        })
+        .unwrap();
+        gpu.flush(Rect {
+            x: 640,
+            y: 0,
+            width: 10,
+            height: 10,
+        })
+        .unwrap();
+
+        gpu.set_mode(original.0, original.1, &mut *mapper).unwrap();
+        assert_eq!(gpu.mode(), Some(original));
+        // Every request was answered, so the device was never reset
+        assert!(gpu.device.status().contains(DeviceStatus::DRIVER_OK));
+    }
+}
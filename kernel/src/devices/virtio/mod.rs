@@ -0,0 +1,423 @@
+//! Virtio PCI transport.
+//!
+//! Implements the modern (virtio 1.x) PCI transport shared by all virtio
+//! device drivers:
+//! - Locating the common, notify, ISR, and device configuration structures
+//!   through the vendor specific PCI capabilities
+//! - Device status handshake and feature negotiation
+//! - Virtqueue setup and notification
+
+use x86_64::structures::paging::OffsetPageTable;
+
+use crate::{
+    debug_println,
+    devices::{
+        drivers::PciMatch,
+        pci::{
+            read_config, read_config_u8, write_pci_command, BarError, DeviceInfo, PCICommand,
+            CAP_ID_VENDOR,
+        },
+    },
+    time,
+};
+
+pub mod blk;
+pub mod gpu;
+pub mod net;
+pub mod queue;
+pub mod rng;
+
+use queue::VirtQueue;
+
+/// PCI vendor ID used by all virtio devices
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+/// Modern virtio devices use PCI device ID 0x1040 + virtio device type
+const VIRTIO_MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+/// virtio_pci_cap cfg_type values
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// Offsets into the common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_MSIX_CONFIG: u64 = 0x10;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// MSI-X entry value meaning no interrupt is raised
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xFFFF;
+
+/// Feature bit every modern device must negotiate
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Maximum number of polling iterations while waiting on the device
+const MAX_ITERATIONS: usize = 1_000_000;
+/// How long a request may take before the device is reset
+const REQUEST_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// The virtio device types supported by drivers in this module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum VirtioDeviceType {
+    Network = 1,
+    Block = 2,
+    Entropy = 4,
+    Gpu = 16,
+}
+
+bitflags::bitflags! {
+    /// Values of the device status field
+    #[derive(Debug, Clone, Copy)]
+    pub struct DeviceStatus: u8 {
+        const ACKNOWLEDGE = 1;
+        const DRIVER = 2;
+        const DRIVER_OK = 4;
+        const FEATURES_OK = 8;
+        const DEVICE_NEEDS_RESET = 64;
+        const FAILED = 128;
+    }
+}
+
+#[derive(Debug)]
+/// Errors that can occur while talking to a virtio device
+pub enum VirtioError {
+    /// The device does not expose a required capability structure
+    MissingCapability,
+    /// The device did not accept the negotiated features
+    FeaturesRejected,
+    /// The requested queue does not exist on the device
+    QueueUnavailable,
+    /// No descriptors were free to submit a request
+    QueueFull,
+    /// A frame could not be allocated for a queue or buffer
+    OutOfMemory,
+    /// The device did not complete a request in time
+    Timeout,
+    /// The device was reset after a request failed, and takes no more
+    Reset,
+    /// The device did not let go of a request's buffers, even when reset,
+    /// so they must never be reused
+    Unresponsive,
+    /// The device returned an unexpected response
+    BadResponse,
+    /// A configuration structure's BAR could not be mapped
+    Bar(BarError),
+    /// The request was malformed or lies outside the device
+    BadRequest,
+    /// The device reported that it could not carry out a request
+    RequestFailed,
+}
+
+/// A modern virtio PCI device with its configuration structures mapped
+pub struct VirtioPciDevice {
+    /// Kernel virtual address of the common configuration structure
+    common_cfg: u64,
+    /// Kernel virtual address of the start of the notification area
+    notify_base: u64,
+    /// Multiplier applied to a queue's notify offset
+    notify_off_multiplier: u32,
+    /// Kernel virtual address of the ISR status byte
+    isr: u64,
+    /// Kernel virtual address of the device specific configuration
+    device_cfg: u64,
+}
+
+/// Builds the driver registry match entry for a modern virtio device type
+pub const fn virtio_pci_id(device_type: VirtioDeviceType) -> PciMatch {
+    PciMatch::Device {
+        vendor_id: VIRTIO_VENDOR_ID,
+        device_id: VIRTIO_MODERN_DEVICE_ID_BASE + device_type as u16,
+    }
+}
+
+impl VirtioPciDevice {
+    /// Finds the configuration structures through the device's vendor
+    /// specific capabilities, mapping all of them and enabling bus mastering.
+    pub fn new(device: &DeviceInfo, mapper: &mut OffsetPageTable) -> Result<Self, VirtioError> {
+        let mut common_cfg = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_cfg = None;
+
+        for capability in device.capabilities.iter() {
+            if capability.id != CAP_ID_VENDOR {
+                continue;
+            }
+            let cap_ptr = capability.offset;
+            let cfg_type = read_config_u8(device.bus, device.device, device.function, cap_ptr + 3);
+            let bar = read_config_u8(device.bus, device.device, device.function, cap_ptr + 4);
+            let offset: u64 =
+                read_config(device.bus, device.device, device.function, cap_ptr + 8).into();
+            let length: u64 =
+                read_config(device.bus, device.device, device.function, cap_ptr + 12).into();
+
+            let region = (bar, offset, length);
+            match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG => common_cfg = common_cfg.or(Some(region)),
+                VIRTIO_PCI_CAP_NOTIFY_CFG => {
+                    let multiplier =
+                        read_config(device.bus, device.device, device.function, cap_ptr + 16);
+                    notify = notify.or(Some((region, multiplier)));
+                }
+                VIRTIO_PCI_CAP_ISR_CFG => isr = isr.or(Some(region)),
+                VIRTIO_PCI_CAP_DEVICE_CFG => device_cfg = device_cfg.or(Some(region)),
+                _ => (),
+            }
+        }
+
+        let common_cfg = common_cfg.ok_or(VirtioError::MissingCapability)?;
+        let (notify, notify_off_multiplier) = notify.ok_or(VirtioError::MissingCapability)?;
+        let isr = isr.ok_or(VirtioError::MissingCapability)?;
+        // Structures often share a BAR, so each is sized only once
+        let bars = device.bars();
+        let mut map = |(index, offset, length): (u8, u64, u64)| {
+            bars.iter()
+                .find(|(bar_index, _)| *bar_index == index)
+                .ok_or(BarError::Missing)
+                .and_then(|(_, bar)| bar.map(mapper, offset, length))
+                .map_err(VirtioError::Bar)
+        };
+        let common_cfg = map(common_cfg)?;
+        let notify_base = map(notify)?;
+        let isr = map(isr)?;
+        // Not every device type has device specific configuration
+        let device_cfg = device_cfg.map(&mut map).transpose()?.unwrap_or(0);
+
+        // Drivers poll or use MSI, so the legacy line, which may be shared,
+        // is kept from being raised
+        write_pci_command(
+            device.bus,
+            device.device,
+            device.function,
+            device.command
+                | PCICommand::MEMORY_SPACE
+                | PCICommand::BUS_MASTER
+                | PCICommand::INTERRUPT_DISABLE,
+        );
+
+        let virtio = VirtioPciDevice {
+            common_cfg,
+            notify_base,
+            notify_off_multiplier,
+            isr,
+            device_cfg,
+        };
+        debug_println!(
+            "Virtio device {:X} on bus {} device {} mapped",
+            device.device_id,
+            device.bus,
+            device.device
+        );
+        Result::Ok(virtio)
+    }
+
+    fn read_common<T: Copy>(&self, offset: u64) -> T {
+        unsafe { core::ptr::read_volatile((self.common_cfg + offset) as *const T) }
+    }
+
+    fn write_common<T: Copy>(&self, offset: u64, value: T) {
+        unsafe { core::ptr::write_volatile((self.common_cfg + offset) as *mut T, value) }
+    }
+
+    /// Returns the current device status
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_retain(self.read_common(COMMON_DEVICE_STATUS))
+    }
+
+    fn set_status(&self, status: DeviceStatus) {
+        self.write_common(COMMON_DEVICE_STATUS, status.bits());
+    }
+
+    fn add_status(&self, status: DeviceStatus) {
+        self.set_status(self.status() | status);
+    }
+
+    /// Resets the device and waits for the reset to complete
+    pub fn reset(&self) -> Result<(), VirtioError> {
+        self.set_status(DeviceStatus::empty());
+        for _ in 0..MAX_ITERATIONS {
+            if self.status().is_empty() {
+                return Result::Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Result::Err(VirtioError::Timeout)
+    }
+
+    /// Resets the device and negotiates features. The device will be left in
+    /// the FEATURES_OK state, queues should then be set up before calling
+    /// `finish_init`.
+    ///
+    /// # Arguments
+    /// * `supported` - The device specific features this driver understands
+    ///
+    /// # Returns
+    /// The set of features that was negotiated with the device
+    pub fn begin_init(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.reset()?;
+        self.add_status(DeviceStatus::ACKNOWLEDGE);
+        self.add_status(DeviceStatus::DRIVER);
+
+        self.write_common::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let low: u64 = self.read_common::<u32>(COMMON_DEVICE_FEATURE).into();
+        self.write_common::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1);
+        let high: u64 = self.read_common::<u32>(COMMON_DEVICE_FEATURE).into();
+        let device_features = (high << 32) | low;
+
+        let negotiated = device_features & (supported | VIRTIO_F_VERSION_1);
+        if negotiated & VIRTIO_F_VERSION_1 == 0 {
+            self.add_status(DeviceStatus::FAILED);
+            return Result::Err(VirtioError::FeaturesRejected);
+        }
+
+        self.write_common::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0);
+        self.write_common::<u32>(COMMON_DRIVER_FEATURE, negotiated as u32);
+        self.write_common::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1);
+        self.write_common::<u32>(COMMON_DRIVER_FEATURE, (negotiated >> 32) as u32);
+
+        self.add_status(DeviceStatus::FEATURES_OK);
+        if !self.status().contains(DeviceStatus::FEATURES_OK) {
+            self.add_status(DeviceStatus::FAILED);
+            return Result::Err(VirtioError::FeaturesRejected);
+        }
+
+        Result::Ok(negotiated)
+    }
+
+    /// Tells the device that the driver is fully set up
+    pub fn finish_init(&self) {
+        self.add_status(DeviceStatus::DRIVER_OK);
+    }
+
+    /// Returns the number of queues the device exposes
+    pub fn num_queues(&self) -> u16 {
+        self.read_common(COMMON_NUM_QUEUES)
+    }
+
+    /// Allocates and registers the virtqueue with the given index
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue on the device
+    /// * `max_size` - The largest queue size the driver wants to use
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<VirtQueue, VirtioError> {
+        if index >= self.num_queues() {
+            return Result::Err(VirtioError::QueueUnavailable);
+        }
+        self.write_common(COMMON_QUEUE_SELECT, index);
+        let device_size: u16 = self.read_common(COMMON_QUEUE_SIZE);
+        if device_size == 0 {
+            return Result::Err(VirtioError::QueueUnavailable);
+        }
+        let size = device_size.min(max_size);
+        let notify_off: u16 = self.read_common(COMMON_QUEUE_NOTIFY_OFF);
+
+        let queue = VirtQueue::new(index, size, notify_off)?;
+
+        self.write_common(COMMON_QUEUE_SIZE, size);
+        self.write_common(COMMON_QUEUE_DESC, queue.descriptor_area().as_u64());
+        self.write_common(COMMON_QUEUE_DRIVER, queue.driver_area().as_u64());
+        self.write_common(COMMON_QUEUE_DEVICE, queue.device_area().as_u64());
+        self.write_common::<u16>(COMMON_QUEUE_ENABLE, 1);
+
+        Result::Ok(queue)
+    }
+
+    /// Has the device signal used buffers of a queue through an MSI-X table
+    /// entry, and configuration changes through none. MSI-X must already be
+    /// enabled on the device.
+    pub fn set_queue_msix_entry(&self, index: u16, entry: u16) -> Result<(), VirtioError> {
+        self.write_common(COMMON_MSIX_CONFIG, VIRTIO_MSI_NO_VECTOR);
+        self.write_common(COMMON_QUEUE_SELECT, index);
+        self.write_common(COMMON_QUEUE_MSIX_VECTOR, entry);
+        // The device reads back NO_VECTOR if it could not take the entry
+        if self.read_common::<u16>(COMMON_QUEUE_MSIX_VECTOR) != entry {
+            return Result::Err(VirtioError::QueueUnavailable);
+        }
+        Result::Ok(())
+    }
+
+    /// Notifies the device that new buffers are available in a queue
+    pub fn notify(&self, queue: &VirtQueue) {
+        let address = self.notify_base
+            + u64::from(queue.notify_off()) * u64::from(self.notify_off_multiplier);
+        unsafe { core::ptr::write_volatile(address as *mut u16, queue.index()) };
+    }
+
+    /// Reads (and thereby acknowledges) the ISR status register
+    pub fn read_isr(&self) -> u8 {
+        unsafe { core::ptr::read_volatile(self.isr as *const u8) }
+    }
+
+    /// Reads a value out of the device specific configuration structure
+    pub fn read_device_config<T: Copy>(&self, offset: u64) -> T {
+        assert!(self.device_cfg != 0, "Device has no device configuration");
+        unsafe { core::ptr::read_volatile((self.device_cfg + offset) as *const T) }
+    }
+
+    /// Writes a value into the device specific configuration structure
+    pub fn write_device_config<T: Copy>(&self, offset: u64, value: T) {
+        assert!(self.device_cfg != 0, "Device has no device configuration");
+        unsafe { core::ptr::write_volatile((self.device_cfg + offset) as *mut T, value) }
+    }
+
+    /// Submits a chain of buffers, notifies the device, and polls until the
+    /// device has used them or REQUEST_TIMEOUT_NS passes.
+    ///
+    /// A device that leaves the chain unused, or uses another one, is reset
+    /// so that it lets go of every buffer it holds, and the chain goes back
+    /// to the queue. Requests to a reset device fail with `Reset`.
+    ///
+    /// # Returns
+    /// The number of bytes the device wrote into the chain
+    pub fn submit_and_wait(
+        &self,
+        queue: &mut VirtQueue,
+        buffers: &[queue::Buffer],
+    ) -> Result<u32, VirtioError> {
+        if !self.status().contains(DeviceStatus::DRIVER_OK) {
+            return Result::Err(VirtioError::Reset);
+        }
+        let head = queue.submit(buffers)?;
+        self.notify(queue);
+
+        let deadline = time::monotonic_ns().saturating_add(REQUEST_TIMEOUT_NS);
+        let mut polls = 0;
+        let error = loop {
+            match queue.pop_used() {
+                Some((id, length)) if id == head => return Result::Ok(length),
+                // pop_used has already recycled the other chain
+                Some(_) => break VirtioError::BadResponse,
+                None => (),
+            }
+            polls += 1;
+            // Count polls instead while the clock is not calibrated
+            let expired = if time::tsc_frequency() == 0 {
+                polls >= MAX_ITERATIONS
+            } else {
+                time::monotonic_ns() >= deadline
+            };
+            if expired {
+                break VirtioError::Timeout;
+            }
+            core::hint::spin_loop();
+        };
+
+        // Until the device lets go, it may still write into the chain
+        self.reset().map_err(|_| VirtioError::Unresponsive)?;
+        queue.recycle(head);
+        Result::Err(error)
+    }
+}
@@ -0,0 +1,327 @@
+//! Virtio network device driver.
+//!
+//! Moves Ethernet frames through the device's receive and transmit queues.
+//! Every receive buffer is a frame of its own, posted when the device is
+//! set up and posted again once the packet in it has been taken. Sends
+//! copy the packet into a free transmit frame and return without waiting
+//! for the device; frames it has finished with are taken back by later
+//! sends. The receive queue's MSI-X interrupt tells the network stack that
+//! packets have arrived, and the stack polls the device as well, so a
+//! device without a working interrupt still receives.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::structures::paging::{OffsetPageTable, PhysFrame};
+
+use crate::{
+    constants::memory::PAGE_SIZE,
+    debug_println,
+    devices::{
+        dma::frame_ptr,
+        drivers::{find_device, DriverError, PciDriver, PciMatch},
+        manager::{DeviceClass, DeviceHandle, DeviceState, DEVICE_MANAGER},
+        pci::{disable_msi, enable_msi, DeviceInfo, MsiKind},
+    },
+    interrupts::x2apic,
+    memory::frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+    net::{
+        ethernet::{MacAddress, MAX_FRAME},
+        frames_received, NetDevice, NetError,
+    },
+};
+
+use super::{
+    queue::{Buffer, VirtQueue},
+    virtio_pci_id, VirtioDeviceType, VirtioError, VirtioPciDevice, VIRTIO_F_VERSION_1,
+    VIRTIO_VENDOR_ID,
+};
+
+/// PCI device ID of a transitional virtio-net device, which QEMU creates
+/// by default
+const TRANSITIONAL_NET_DEVICE_ID: u16 = 0x1000;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+/// Descriptors used in each queue, and so the buffers posted in each
+const QUEUE_SIZE: u16 = 16;
+
+/// The device has a MAC address in its configuration
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// Offset of the MAC address in the device configuration
+const CONFIG_MAC: u64 = 0;
+
+/// Locally administered address used when the device does not offer one
+const FALLBACK_MAC: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 0x01]);
+
+/// Header before every packet in both directions. Without offloads the
+/// driver leaves it zeroed, and ignores the one the device writes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct NetHeader {
+    flags: u8,
+    gso_type: u8,
+    header_len: u16,
+    gso_size: u16,
+    checksum_start: u16,
+    checksum_offset: u16,
+    /// Only present for VERSION_1 devices, which are the only ones driven
+    num_buffers: u16,
+}
+
+const HEADER_SIZE: usize = size_of::<NetHeader>();
+
+/// A virtio-net device with its queues and buffers
+pub struct VirtioNet {
+    device: VirtioPciDevice,
+    receive: VirtQueue,
+    transmit: VirtQueue,
+    /// Frames posted for receiving, by the descriptor holding them
+    receiving: BTreeMap<u16, PhysFrame>,
+    /// Frames the device is sending from, by the descriptor holding them
+    sending: BTreeMap<u16, PhysFrame>,
+    /// Transmit frames not in use
+    idle: Vec<PhysFrame>,
+    mac: MacAddress,
+}
+
+/// The virtio network devices this driver supports
+const NET_ID_TABLE: &[PciMatch] = &[
+    virtio_pci_id(VirtioDeviceType::Network),
+    PciMatch::Device {
+        vendor_id: VIRTIO_VENDOR_ID,
+        device_id: TRANSITIONAL_NET_DEVICE_ID,
+    },
+];
+
+/// Registry entry for the virtio network driver
+pub static VIRTIO_NET_DRIVER: PciDriver = PciDriver {
+    name: "Virtio net",
+    id_table: NET_ID_TABLE,
+    probe: probe_virtio_net,
+};
+
+/// Finds the FIRST virtio network device on the PCI bus
+pub fn find_virtio_net(devices: &[Arc<Mutex<DeviceInfo>>]) -> Option<Arc<Mutex<DeviceInfo>>> {
+    find_device(devices, NET_ID_TABLE)
+}
+
+/// Probe function for the driver registry
+fn probe_virtio_net(
+    handle: DeviceHandle,
+    net_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), DriverError> {
+    let net = initialize_virtio_net(net_arc, mapper).map_err(DriverError::Virtio)?;
+    let mut manager = DEVICE_MANAGER.lock();
+    manager.set_state(handle, DeviceState::Active);
+    let node = manager.register("virtio network".into(), DeviceClass::Network, Some(handle));
+    manager.bind(node, VIRTIO_NET_DRIVER.name);
+    manager.activate(node, net);
+    Result::Ok(())
+}
+
+/// Sets up a virtio network device, with every receive buffer posted
+pub fn initialize_virtio_net(
+    net_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<VirtioNet, VirtioError> {
+    let info = net_arc.lock();
+    let device = VirtioPciDevice::new(&info, mapper)?;
+    let features = device.begin_init(VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC)?;
+    let receive = device.setup_queue(RECEIVE_QUEUE, QUEUE_SIZE)?;
+    let transmit = device.setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE)?;
+    let mac = if features & VIRTIO_NET_F_MAC != 0 {
+        let mut address = [0; 6];
+        for (i, byte) in address.iter_mut().enumerate() {
+            *byte = device.read_device_config(CONFIG_MAC + i as u64);
+        }
+        MacAddress(address)
+    } else {
+        FALLBACK_MAC
+    };
+
+    // Frames are only owned once the device holds them, so its Drop frees
+    // them if the rest cannot be had
+    let mut net = VirtioNet {
+        device,
+        receive,
+        transmit,
+        receiving: BTreeMap::new(),
+        sending: BTreeMap::new(),
+        idle: Vec::with_capacity(QUEUE_SIZE as usize),
+        mac,
+    };
+    for _ in 0..QUEUE_SIZE {
+        let frame = alloc_frame_zeroed().ok_or(VirtioError::OutOfMemory)?;
+        net.post_receive(frame)?;
+        net.idle
+            .push(alloc_frame_zeroed().ok_or(VirtioError::OutOfMemory)?);
+    }
+
+    let core = x2apic::current_core_id() as u32;
+    match enable_msi(&info, mapper, core, handle_virtio_net_interrupt) {
+        Ok(interrupt) if interrupt.kind == MsiKind::MsiX => {
+            if net.device.set_queue_msix_entry(RECEIVE_QUEUE, 0).is_err() {
+                disable_msi(&info, interrupt);
+            }
+        }
+        Ok(interrupt) => disable_msi(&info, interrupt),
+        Err(e) => {
+            debug_println!("Virtio net has no interrupt: {:?}", e);
+        }
+    }
+    net.device.finish_init();
+    // The device may only be notified once it is running
+    net.device.notify(&net.receive);
+
+    Result::Ok(net)
+}
+
+/// Tells the network stack packets have arrived. Called from the interrupt
+/// handler; MSI-X interrupts need no acknowledging.
+pub fn handle_virtio_net_interrupt() {
+    frames_received();
+}
+
+impl VirtioNet {
+    /// Hands a frame to the device to receive a packet into. The device
+    /// still needs to be notified.
+    fn post_receive(&mut self, frame: PhysFrame) -> Result<(), VirtioError> {
+        let head = match self.receive.submit(&[Buffer {
+            addr: frame.start_address(),
+            len: PAGE_SIZE as u32,
+            device_writable: true,
+        }]) {
+            Ok(head) => head,
+            Err(e) => {
+                dealloc_frame(frame);
+                return Result::Err(e);
+            }
+        };
+        self.receiving.insert(head, frame);
+        Result::Ok(())
+    }
+
+    /// Takes back the transmit frames the device has sent
+    fn reclaim_sent(&mut self) {
+        while let Some((head, _)) = self.transmit.pop_used() {
+            if let Some(frame) = self.sending.remove(&head) {
+                self.idle.push(frame);
+            }
+        }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME {
+            return Err(NetError::TooLarge);
+        }
+        self.reclaim_sent();
+        let buffer = self.idle.pop().ok_or(NetError::DeviceBusy)?;
+        unsafe {
+            let data = frame_ptr(buffer);
+            core::ptr::write_volatile(data as *mut NetHeader, NetHeader::default());
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), data.add(HEADER_SIZE), frame.len());
+        }
+        let chain = [Buffer {
+            addr: buffer.start_address(),
+            len: (HEADER_SIZE + frame.len()) as u32,
+            device_writable: false,
+        }];
+        match self.transmit.submit(&chain) {
+            Ok(head) => {
+                self.sending.insert(head, buffer);
+                self.device.notify(&self.transmit);
+                Ok(())
+            }
+            Err(_) => {
+                self.idle.push(buffer);
+                Err(NetError::DeviceBusy)
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let (head, written) = self.receive.pop_used()?;
+        let frame = self.receiving.remove(&head)?;
+        let len = (written as usize).clamp(HEADER_SIZE, PAGE_SIZE) - HEADER_SIZE;
+        let mut packet = alloc::vec![0; len];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                frame_ptr(frame).add(HEADER_SIZE),
+                packet.as_mut_ptr(),
+                len,
+            );
+        }
+        // The descriptor just used is free, so the frame always goes back
+        if self.post_receive(frame).is_ok() {
+            self.device.notify(&self.receive);
+        }
+        Some(packet)
+    }
+}
+
+impl Drop for VirtioNet {
+    fn drop(&mut self) {
+        let _ = self.device.reset();
+        let frames = self.receiving.values().chain(self.sending.values());
+        frames.copied().for_each(dealloc_frame);
+        self.idle.drain(..).for_each(dealloc_frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::net::{DEFAULT_ADDRESS, DEFAULT_GATEWAY},
+        devices::manager::find_device_data,
+        net::{
+            arp::{ArpPacket, OP_REPLY},
+            ethernet::{self, Frame, ETHERTYPE_ARP},
+        },
+        time,
+    };
+
+    /// Asks QEMU's user network for the gateway's address, which it always
+    /// answers
+    #[test_case]
+    fn test_arp_round_trip() {
+        let device = find_device_data::<VirtioNet>().expect("No virtio network device found");
+        let mut net = device.lock();
+        let request = ArpPacket::request(net.mac, DEFAULT_ADDRESS, DEFAULT_GATEWAY);
+        let frame = ethernet::build(
+            MacAddress::BROADCAST,
+            net.mac,
+            ETHERTYPE_ARP,
+            &request.encode(),
+        );
+        net.transmit(&frame).unwrap();
+
+        let deadline = time::monotonic_ns() + 2_000_000_000;
+        let reply = loop {
+            assert!(
+                time::monotonic_ns() < deadline,
+                "No ARP reply from the gateway"
+            );
+            let Some(received) = net.receive() else {
+                core::hint::spin_loop();
+                continue;
+            };
+            let packet = Frame::parse(&received)
+                .filter(|frame| frame.ethertype == ETHERTYPE_ARP)
+                .and_then(|frame| ArpPacket::parse(frame.payload));
+            if let Some(packet) = packet.filter(|packet| packet.operation == OP_REPLY) {
+                break packet;
+            }
+        };
+        assert_eq!(reply.sender_ip, DEFAULT_GATEWAY);
+        assert_eq!(reply.target_mac, net.mac);
+    }
+}
@@ -0,0 +1,310 @@
+//! Split virtqueues.
+//!
+//! The descriptor table, available ring, and used ring of a queue all live
+//! in a single physical frame which is accessed through the HHDM.
+
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+
+use crate::memory::{
+    frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+    HHDM_OFFSET,
+};
+
+use super::VirtioError;
+
+/// Largest queue size that fits all three rings into a single frame
+pub const MAX_QUEUE_SIZE: u16 = 128;
+
+/// Descriptor continues via the next field
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// Buffer is device write-only (otherwise device read-only)
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A physically contiguous buffer handed to the device
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    /// Physical address of the buffer
+    pub addr: PhysAddr,
+    /// Length of the buffer in bytes
+    pub len: u32,
+    /// Whether the device writes into this buffer
+    pub device_writable: bool,
+}
+
+/// A split virtqueue
+pub struct VirtQueue {
+    /// Index of this queue on its device
+    index: u16,
+    /// Number of descriptors in the queue
+    size: u16,
+    /// Notify offset reported by the device for this queue
+    notify_off: u16,
+    /// Physical address of the frame backing the queue
+    phys: PhysAddr,
+    /// Kernel virtual address of the frame backing the queue
+    base: u64,
+    /// Byte offset of the available ring
+    avail_offset: u64,
+    /// Byte offset of the used ring
+    used_offset: u64,
+    /// Head of the free descriptor list
+    free_head: u16,
+    /// Number of free descriptors
+    num_free: u16,
+    /// Next available ring index to write
+    avail_idx: u16,
+    /// Last used ring index processed
+    last_used_idx: u16,
+}
+
+impl VirtQueue {
+    /// Allocates a queue with `size` descriptors, which must be a power of
+    /// two no larger than MAX_QUEUE_SIZE
+    pub fn new(index: u16, size: u16, notify_off: u16) -> Result<Self, VirtioError> {
+        let size = size.min(MAX_QUEUE_SIZE);
+        assert!(size.is_power_of_two(), "Queue size must be a power of two");
+
+        let frame = alloc_frame_zeroed().ok_or(VirtioError::OutOfMemory)?;
+        let phys = frame.start_address();
+        let base = HHDM_OFFSET.as_u64() + phys.as_u64();
+
+        let avail_offset = 16 * u64::from(size);
+        // flags + idx + ring + used_event
+        let used_offset = (avail_offset + 6 + 2 * u64::from(size)).next_multiple_of(4);
+
+        let mut queue = VirtQueue {
+            index,
+            size,
+            notify_off,
+            phys,
+            base,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size {
+            let mut descriptor = queue.read_descriptor(i);
+            descriptor.next = (i + 1) % size;
+            queue.write_descriptor(i, descriptor);
+        }
+        Result::Ok(queue)
+    }
+
+    /// Index of this queue on its device
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Notify offset of this queue
+    pub fn notify_off(&self) -> u16 {
+        self.notify_off
+    }
+
+    /// Physical address of the descriptor table
+    pub fn descriptor_area(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Physical address of the available ring
+    pub fn driver_area(&self) -> PhysAddr {
+        self.phys + self.avail_offset
+    }
+
+    /// Physical address of the used ring
+    pub fn device_area(&self) -> PhysAddr {
+        self.phys + self.used_offset
+    }
+
+    fn read_descriptor(&self, index: u16) -> Descriptor {
+        let address = self.base + 16 * u64::from(index);
+        unsafe { core::ptr::read_volatile(address as *const Descriptor) }
+    }
+
+    fn write_descriptor(&mut self, index: u16, descriptor: Descriptor) {
+        let address = self.base + 16 * u64::from(index);
+        unsafe { core::ptr::write_volatile(address as *mut Descriptor, descriptor) }
+    }
+
+    /// Places a chain of buffers on the available ring. The device still
+    /// needs to be notified afterwards.
+    ///
+    /// # Returns
+    /// The index of the head descriptor of the chain
+    pub fn submit(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.num_free) {
+            return Result::Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut current = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut descriptor = self.read_descriptor(current);
+            let next = descriptor.next;
+            descriptor.addr = buffer.addr.as_u64();
+            descriptor.len = buffer.len;
+            descriptor.flags = if buffer.device_writable {
+                VIRTQ_DESC_F_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                descriptor.flags |= VIRTQ_DESC_F_NEXT;
+            }
+            self.write_descriptor(current, descriptor);
+            if i + 1 < buffers.len() {
+                current = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.num_free -= buffers.len() as u16;
+
+        let ring_slot =
+            self.base + self.avail_offset + 4 + 2 * u64::from(self.avail_idx % self.size);
+        unsafe { core::ptr::write_volatile(ring_slot as *mut u16, head) };
+        // The descriptors must be visible before the index moves
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        let idx_address = self.base + self.avail_offset + 2;
+        unsafe { core::ptr::write_volatile(idx_address as *mut u16, self.avail_idx) };
+        fence(Ordering::SeqCst);
+
+        Result::Ok(head)
+    }
+
+    /// Returns true if the device has used a buffer we have not yet processed
+    pub fn has_used(&self) -> bool {
+        let idx_address = self.base + self.used_offset + 2;
+        let used_idx = unsafe { core::ptr::read_volatile(idx_address as *const u16) };
+        used_idx != self.last_used_idx
+    }
+
+    /// Takes the next used chain off of the used ring and frees its
+    /// descriptors
+    ///
+    /// # Returns
+    /// The head descriptor index and the number of bytes written by the
+    /// device, or None if there is nothing new
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return Option::None;
+        }
+        fence(Ordering::SeqCst);
+
+        let element =
+            self.base + self.used_offset + 4 + 8 * u64::from(self.last_used_idx % self.size);
+        let id = unsafe { core::ptr::read_volatile(element as *const u32) } as u16;
+        let length = unsafe { core::ptr::read_volatile((element + 4) as *const u32) };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        self.recycle(id);
+        Option::Some((id, length))
+    }
+
+    /// Returns the chain starting at `head` to the free list. Only chains
+    /// the device has used, or let go of by being reset, may be recycled.
+    pub fn recycle(&mut self, head: u16) {
+        let mut last = head;
+        let mut freed = 1;
+        loop {
+            let descriptor = self.read_descriptor(last);
+            if descriptor.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            last = descriptor.next;
+            freed += 1;
+        }
+        let mut tail = self.read_descriptor(last);
+        tail.next = self.free_head;
+        self.write_descriptor(last, tail);
+        self.free_head = head;
+        self.num_free += freed;
+    }
+}
+
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        dealloc_frame(x86_64::structures::paging::PhysFrame::containing_address(
+            self.phys,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Plays the device, handing the chain at `head` back on the used ring
+    fn complete(queue: &VirtQueue, head: u16, length: u32) {
+        let idx_address = queue.base + queue.used_offset + 2;
+        let used_idx = unsafe { core::ptr::read_volatile(idx_address as *const u16) };
+        let element = queue.base + queue.used_offset + 4 + 8 * u64::from(used_idx % queue.size);
+        unsafe {
+            core::ptr::write_volatile(element as *mut u32, head.into());
+            core::ptr::write_volatile((element + 4) as *mut u32, length);
+            core::ptr::write_volatile(idx_address as *mut u16, used_idx.wrapping_add(1));
+        }
+    }
+
+    fn buffers(count: usize) -> Vec<Buffer> {
+        (0..count)
+            .map(|i| Buffer {
+                addr: PhysAddr::new(0x1000 * i as u64),
+                len: 16,
+                device_writable: i + 1 == count,
+            })
+            .collect()
+    }
+
+    #[test_case]
+    fn test_queue_chains() {
+        let mut queue = VirtQueue::new(0, 4, 0).unwrap();
+        assert!(matches!(queue.submit(&[]), Err(VirtioError::QueueFull)));
+        let first = queue.submit(&buffers(3)).unwrap();
+        assert!(matches!(
+            queue.submit(&buffers(2)),
+            Err(VirtioError::QueueFull)
+        ));
+        assert!(queue.pop_used().is_none());
+
+        // Only the last buffer of a chain ends it, and only it is writable
+        let mut current = first;
+        for _ in 0..2 {
+            let descriptor = queue.read_descriptor(current);
+            assert_eq!(descriptor.flags, VIRTQ_DESC_F_NEXT);
+            current = descriptor.next;
+        }
+        assert_eq!(queue.read_descriptor(current).flags, VIRTQ_DESC_F_WRITE);
+
+        // Used chains go back to the free list
+        complete(&queue, first, 7);
+        assert_eq!(queue.pop_used(), Some((first, 7)));
+        assert_eq!(queue.num_free, 4);
+        assert!(queue.pop_used().is_none());
+
+        // as do ones the device gave up by being reset
+        let second = queue.submit(&buffers(4)).unwrap();
+        assert_eq!(queue.num_free, 0);
+        queue.recycle(second);
+        assert_eq!(queue.num_free, 4);
+        let third = queue.submit(&buffers(4)).unwrap();
+        complete(&queue, third, 0);
+        assert_eq!(queue.pop_used(), Some((third, 0)));
+        assert_eq!(queue.num_free, 4);
+    }
+}
@@ -0,0 +1,119 @@
+//! Virtio entropy device driver.
+//!
+//! Requests random bytes from the host through the device's single request
+//! queue.
+
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::structures::paging::{OffsetPageTable, PhysFrame};
+
+use crate::{
+    constants::memory::PAGE_SIZE,
+    devices::{
+        dma::frame_ptr,
+        drivers::{find_device, DriverError, PciDriver, PciMatch},
+        manager::{DeviceClass, DeviceHandle, DeviceState, DEVICE_MANAGER},
+        pci::DeviceInfo,
+    },
+    memory::frame_allocator::{alloc_frame, dealloc_frame},
+};
+
+use super::{
+    queue::{Buffer, VirtQueue},
+    virtio_pci_id, VirtioDeviceType, VirtioError, VirtioPciDevice,
+};
+
+/// Index of the request queue
+const REQUEST_QUEUE: u16 = 0;
+/// Number of descriptors used for the request queue
+const REQUEST_QUEUE_SIZE: u16 = 8;
+
+/// A virtio entropy device
+pub struct VirtioRng {
+    device: VirtioPciDevice,
+    requests: VirtQueue,
+    /// Frame the device writes random bytes into
+    buffer_frame: PhysFrame,
+}
+
+/// The virtio entropy devices this driver supports
+const RNG_ID_TABLE: &[PciMatch] = &[virtio_pci_id(VirtioDeviceType::Entropy)];
+
+/// Registry entry for the virtio entropy driver
+pub static VIRTIO_RNG_DRIVER: PciDriver = PciDriver {
+    name: "Virtio rng",
+    id_table: RNG_ID_TABLE,
+    probe: probe_virtio_rng,
+};
+
+/// Finds the FIRST virtio entropy device on the PCI bus
+pub fn find_virtio_rng(devices: &[Arc<Mutex<DeviceInfo>>]) -> Option<Arc<Mutex<DeviceInfo>>> {
+    find_device(devices, RNG_ID_TABLE)
+}
+
+/// Probe function for the driver registry
+fn probe_virtio_rng(
+    handle: DeviceHandle,
+    rng_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), DriverError> {
+    let rng = initialize_virtio_rng(rng_arc, mapper).map_err(DriverError::Virtio)?;
+    let mut manager = DEVICE_MANAGER.lock();
+    manager.set_state(handle, DeviceState::Active);
+    let entropy = manager.register("virtio entropy".into(), DeviceClass::Entropy, Some(handle));
+    manager.bind(entropy, VIRTIO_RNG_DRIVER.name);
+    manager.activate(entropy, rng);
+    Result::Ok(())
+}
+
+/// Sets up a virtio entropy device
+pub fn initialize_virtio_rng(
+    rng_arc: &Arc<Mutex<DeviceInfo>>,
+    mapper: &mut OffsetPageTable,
+) -> Result<VirtioRng, VirtioError> {
+    let device = VirtioPciDevice::new(&rng_arc.lock(), mapper)?;
+    device.begin_init(0)?;
+    let requests = device.setup_queue(REQUEST_QUEUE, REQUEST_QUEUE_SIZE)?;
+    device.finish_init();
+
+    let buffer_frame = alloc_frame().ok_or(VirtioError::OutOfMemory)?;
+    Result::Ok(VirtioRng {
+        device,
+        requests,
+        buffer_frame,
+    })
+}
+
+impl VirtioRng {
+    /// Fills the start of `buffer` with random bytes from the host
+    ///
+    /// # Returns
+    /// The number of bytes written, which may be less than requested
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, VirtioError> {
+        let length = buffer.len().min(PAGE_SIZE);
+        if length == 0 {
+            return Result::Ok(0);
+        }
+        let written = self.device.submit_and_wait(
+            &mut self.requests,
+            &[Buffer {
+                addr: self.buffer_frame.start_address(),
+                len: length as u32,
+                device_writable: true,
+            }],
+        )? as usize;
+        let written = written.min(length);
+
+        let source = frame_ptr(self.buffer_frame);
+        unsafe { core::ptr::copy_nonoverlapping(source, buffer.as_mut_ptr(), written) };
+        Result::Ok(written)
+    }
+}
+
+impl Drop for VirtioRng {
+    fn drop(&mut self) {
+        if self.device.reset().is_ok() {
+            dealloc_frame(self.buffer_frame);
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! A kernel-wide error type.
+//!
+//! Subsystems keep their own error enums, which say exactly what went wrong
+//! in their terms. Where a failure crosses into another subsystem, or out
+//! to a user program, it is converted into a KError: the kind of failure,
+//! which decides the errno, a description of the original error, and what
+//! the layers above were doing when it happened. Everything is a static
+//! string, so errors can be built and passed along without the heap.
+
+use core::fmt;
+
+use crate::{
+    constants::syscalls::{
+        EACCES, EAGAIN, EBADF, EBUSY, ECHILD, EEXIST, EFAULT, EINVAL, EIO, ELOOP, EMFILE,
+        ENAMETOOLONG, ENOENT, ENOEXEC, ENOMEM, ENOSPC, ENOSYS, ENOTEMPTY, EPERM, EPIPE, EROFS,
+        ESPIPE, ESRCH, ETIMEDOUT,
+    },
+    devices::sd_card::SDCardError,
+    filesys::FsError,
+    interrupts::x2apic::X2ApicError,
+    ipc::pipe::PipeError,
+    net::NetError,
+    processes::{
+        cgroup::CgroupError, fd_table::FdError, process::ProcessError, signal::SignalError,
+    },
+};
+
+pub type KResult<T> = Result<T, KError>;
+
+/// Most contexts an error keeps. Contexts added past this are dropped, so
+/// those nearest the original error survive.
+const MAX_CONTEXTS: usize = 4;
+
+/// Broad classes of failure, each reported to user programs as one errno
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotPermitted,
+    NotFound,
+    NoSuchProcess,
+    Io,
+    NotExecutable,
+    BadDescriptor,
+    NoChildren,
+    /// Something the kernel could hand out has run out, and trying later
+    /// may work
+    TryAgain,
+    /// Memory, or a free range of addresses, has run out
+    OutOfMemory,
+    AccessDenied,
+    /// A user pointer was not mapped as required
+    Fault,
+    Busy,
+    AlreadyExists,
+    InvalidArgument,
+    TooManyOpen,
+    NoSpace,
+    NotSeekable,
+    ReadOnly,
+    NameTooLong,
+    NotSupported,
+    NotEmpty,
+    TooDeep,
+    TimedOut,
+    /// Written to a pipe nobody reads any more
+    BrokenPipe,
+}
+
+impl ErrorKind {
+    /// The errno user programs see, before it is negated
+    pub fn errno(self) -> i64 {
+        match self {
+            ErrorKind::NotPermitted => EPERM,
+            ErrorKind::NotFound => ENOENT,
+            ErrorKind::NoSuchProcess => ESRCH,
+            ErrorKind::Io => EIO,
+            ErrorKind::NotExecutable => ENOEXEC,
+            ErrorKind::BadDescriptor => EBADF,
+            ErrorKind::NoChildren => ECHILD,
+            ErrorKind::TryAgain => EAGAIN,
+            ErrorKind::OutOfMemory => ENOMEM,
+            ErrorKind::AccessDenied => EACCES,
+            ErrorKind::Fault => EFAULT,
+            ErrorKind::Busy => EBUSY,
+            ErrorKind::AlreadyExists => EEXIST,
+            ErrorKind::InvalidArgument => EINVAL,
+            ErrorKind::TooManyOpen => EMFILE,
+            ErrorKind::NoSpace => ENOSPC,
+            ErrorKind::NotSeekable => ESPIPE,
+            ErrorKind::ReadOnly => EROFS,
+            ErrorKind::NameTooLong => ENAMETOOLONG,
+            ErrorKind::NotSupported => ENOSYS,
+            ErrorKind::NotEmpty => ENOTEMPTY,
+            ErrorKind::TooDeep => ELOOP,
+            ErrorKind::TimedOut => ETIMEDOUT,
+            ErrorKind::BrokenPipe => EPIPE,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorKind::NotPermitted => "operation not permitted",
+            ErrorKind::NotFound => "not found",
+            ErrorKind::NoSuchProcess => "no such process",
+            ErrorKind::Io => "I/O error",
+            ErrorKind::NotExecutable => "not an executable",
+            ErrorKind::BadDescriptor => "bad file descriptor",
+            ErrorKind::NoChildren => "no child processes",
+            ErrorKind::TryAgain => "resource temporarily unavailable",
+            ErrorKind::OutOfMemory => "out of memory",
+            ErrorKind::AccessDenied => "permission denied",
+            ErrorKind::Fault => "bad address",
+            ErrorKind::Busy => "busy",
+            ErrorKind::AlreadyExists => "already exists",
+            ErrorKind::InvalidArgument => "invalid argument",
+            ErrorKind::TooManyOpen => "too many open files",
+            ErrorKind::NoSpace => "no space left",
+            ErrorKind::NotSeekable => "not seekable",
+            ErrorKind::ReadOnly => "read-only filesystem",
+            ErrorKind::NameTooLong => "name too long",
+            ErrorKind::NotSupported => "not supported",
+            ErrorKind::NotEmpty => "not empty",
+            ErrorKind::TooDeep => "nested too deeply",
+            ErrorKind::TimedOut => "timed out",
+            ErrorKind::BrokenPipe => "broken pipe",
+        }
+    }
+}
+
+/// A failure on its way up through the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KError {
+    kind: ErrorKind,
+    /// Describes the error this was converted from
+    source: &'static str,
+    /// What was being done when the error happened, innermost first
+    contexts: [&'static str; MAX_CONTEXTS],
+    depth: usize,
+}
+
+impl KError {
+    pub const fn new(kind: ErrorKind, source: &'static str) -> Self {
+        KError {
+            kind,
+            source,
+            contexts: [""; MAX_CONTEXTS],
+            depth: 0,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The errno user programs see, before it is negated
+    pub fn errno(&self) -> i64 {
+        self.kind.errno()
+    }
+
+    pub fn source(&self) -> &'static str {
+        self.source
+    }
+
+    /// Records what was being done when the error happened
+    pub fn context(mut self, context: &'static str) -> Self {
+        if self.depth < MAX_CONTEXTS {
+            self.contexts[self.depth] = context;
+            self.depth += 1;
+        }
+        self
+    }
+
+    /// The recorded contexts, outermost first
+    pub fn contexts(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.contexts[..self.depth].iter().rev().copied()
+    }
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for context in self.contexts() {
+            write!(f, "{}: ", context)?;
+        }
+        write!(f, "{}", self.source)
+    }
+}
+
+impl From<ErrorKind> for KError {
+    fn from(kind: ErrorKind) -> Self {
+        KError::new(kind, kind.description())
+    }
+}
+
+/// Adds context to the error of a Result, converting it to a KError
+pub trait ResultExt<T> {
+    fn context(self, context: &'static str) -> KResult<T>;
+}
+
+impl<T, E: Into<KError>> ResultExt<T> for Result<T, E> {
+    fn context(self, context: &'static str) -> KResult<T> {
+        self.map_err(|error| error.into().context(context))
+    }
+}
+
+impl From<FsError> for KError {
+    fn from(error: FsError) -> Self {
+        let (kind, source) = match error {
+            FsError::NotFound => (ErrorKind::NotFound, "file not found"),
+            FsError::AlreadyExists => (ErrorKind::AlreadyExists, "file already exists"),
+            FsError::InvalidName => (ErrorKind::InvalidArgument, "invalid file name"),
+            FsError::InvalidOffset => (ErrorKind::InvalidArgument, "invalid file offset"),
+            FsError::IOError => (ErrorKind::Io, "block device error"),
+            FsError::NotSupported => (ErrorKind::NotSupported, "not supported by filesystem"),
+            FsError::NoSpace => (ErrorKind::NoSpace, "filesystem full"),
+            FsError::DirectoryNotEmpty => (ErrorKind::NotEmpty, "directory not empty"),
+            FsError::Busy => (ErrorKind::Busy, "filesystem busy"),
+            FsError::ReadOnly => (ErrorKind::ReadOnly, "filesystem mounted read-only"),
+            FsError::PermissionDenied => (ErrorKind::AccessDenied, "file permission denied"),
+            FsError::TooDeep => (ErrorKind::TooDeep, "directories nested too deeply"),
+        };
+        KError::new(kind, source)
+    }
+}
+
+impl From<FdError> for KError {
+    fn from(error: FdError) -> Self {
+        match error {
+            FdError::BadDescriptor => KError::new(ErrorKind::BadDescriptor, "descriptor not open"),
+            FdError::TooManyOpen => KError::new(ErrorKind::TooManyOpen, "descriptor table full"),
+        }
+    }
+}
+
+impl From<ProcessError> for KError {
+    fn from(error: ProcessError) -> Self {
+        match error {
+            ProcessError::NoFreePid => KError::new(ErrorKind::TryAgain, "no free PID"),
+            ProcessError::NotFound(_) => KError::new(ErrorKind::NoSuchProcess, "no such process"),
+            ProcessError::Exec(error) => KError::from(error).context("reading executable"),
+            ProcessError::NotExecutable => {
+                KError::new(ErrorKind::NotExecutable, "not an ELF image")
+            }
+            ProcessError::NoChildren => KError::new(ErrorKind::NoChildren, "no matching child"),
+            ProcessError::Unsupported(_) => KError::new(
+                ErrorKind::NotExecutable,
+                "executable needs missing features",
+            ),
+            ProcessError::OutOfMemory => {
+                KError::new(ErrorKind::OutOfMemory, "no frames to load the image")
+            }
+        }
+    }
+}
+
+impl From<SignalError> for KError {
+    fn from(error: SignalError) -> Self {
+        let (kind, source) = match error {
+            SignalError::InvalidSignal => (ErrorKind::InvalidArgument, "no such signal"),
+            SignalError::Uncatchable => (ErrorKind::InvalidArgument, "signal cannot be caught"),
+        };
+        KError::new(kind, source)
+    }
+}
+
+impl From<CgroupError> for KError {
+    fn from(error: CgroupError) -> Self {
+        let (kind, source) = match error {
+            CgroupError::NotFound => (ErrorKind::NotFound, "no such group"),
+            CgroupError::AlreadyExists => (ErrorKind::AlreadyExists, "group already exists"),
+            CgroupError::InvalidWeight => (ErrorKind::InvalidArgument, "invalid group weight"),
+            CgroupError::RootGroup => (ErrorKind::NotPermitted, "root group cannot be removed"),
+            CgroupError::NotEmpty => (ErrorKind::Busy, "group still in use"),
+        };
+        KError::new(kind, source)
+    }
+}
+
+impl From<SDCardError> for KError {
+    fn from(error: SDCardError) -> Self {
+        let source = match error {
+            SDCardError::CommandInhibited => "SD command inhibited",
+            SDCardError::CommandStoppedDueToError => "SD command stopped by an error",
+            SDCardError::SDTimeout => return KError::new(ErrorKind::TimedOut, "SD card timed out"),
+            SDCardError::FrequencyUnableToBeSet => "SD clock could not be set",
+            SDCardError::VoltageUnableToBeSet => "SD voltage could not be set",
+            SDCardError::GenericSDError => "SD card error",
+        };
+        KError::new(ErrorKind::Io, source)
+    }
+}
+
+impl From<NetError> for KError {
+    fn from(error: NetError) -> Self {
+        let (kind, source) = match error {
+            NetError::NoInterface => (ErrorKind::NotSupported, "no network interface"),
+            NetError::AddressInUse => (ErrorKind::AlreadyExists, "port already bound"),
+            NetError::TooLarge => (ErrorKind::InvalidArgument, "datagram too large"),
+            NetError::DeviceBusy => (ErrorKind::TryAgain, "network device busy"),
+        };
+        KError::new(kind, source)
+    }
+}
+
+impl From<PipeError> for KError {
+    fn from(error: PipeError) -> Self {
+        let (kind, source) = match error {
+            PipeError::WouldBlock => (ErrorKind::TryAgain, "pipe would block"),
+            PipeError::Broken => (ErrorKind::BrokenPipe, "pipe has no reader"),
+        };
+        KError::new(kind, source)
+    }
+}
+
+impl From<X2ApicError> for KError {
+    fn from(error: X2ApicError) -> Self {
+        let (kind, source) = match error {
+            X2ApicError::NotSupported => (ErrorKind::NotSupported, "x2APIC not supported"),
+            X2ApicError::NotEnabled => (ErrorKind::NotSupported, "x2APIC not enabled"),
+            X2ApicError::EnableFailed => (ErrorKind::Io, "x2APIC could not be enabled"),
+            X2ApicError::InvalidVector => (ErrorKind::InvalidArgument, "invalid interrupt vector"),
+            X2ApicError::ConfigurationFailed => (ErrorKind::Io, "x2APIC configuration failed"),
+            X2ApicError::TimerError => (ErrorKind::Io, "APIC timer error"),
+            X2ApicError::CoreOutOfRange => (ErrorKind::InvalidArgument, "core ID out of range"),
+        };
+        KError::new(kind, source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::BoundedWriter;
+    use core::fmt::Write;
+
+    #[test_case]
+    fn test_error_chain() {
+        let result: Result<(), FsError> = Err(FsError::NotFound);
+        let error = result
+            .context("resolving path")
+            .context("opening file")
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.errno(), ENOENT);
+
+        let mut buffer = [0; 64];
+        let mut writer = BoundedWriter::new(&mut buffer);
+        write!(writer, "{}", error).unwrap();
+        assert_eq!(
+            writer.as_str(),
+            "opening file: resolving path: file not found"
+        );
+
+        // The innermost contexts are kept once there is no room
+        let mut error = KError::from(ErrorKind::Fault);
+        for _ in 0..MAX_CONTEXTS {
+            error = error.context("inner");
+        }
+        let error = error.context("outer");
+        assert!(error.contexts().all(|context| context == "inner"));
+
+        // Nested errors keep what the inner layer was doing
+        let error = KError::from(ProcessError::Exec(FsError::IOError));
+        assert_eq!(error.errno(), EIO);
+        assert_eq!(error.contexts().next(), Some("reading executable"));
+    }
+}
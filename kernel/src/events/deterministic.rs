@@ -0,0 +1,173 @@
+//! Reproducible event scheduling for tests.
+//!
+//! A `Simulation` owns the event runners of several simulated cores and
+//! drives them from the core running the test, one poll at a time, with no
+//! timer interrupts involved. Which core polls next is drawn from a seeded
+//! generator, so any interleaving a test hits can be replayed from the seed
+//! it prints. While a simulation exists the monotonic clock is virtual and
+//! only moves when the test advances it, so sleeps and timeouts expire at
+//! the same step on every run.
+
+use alloc::vec::Vec;
+use core::future::Future;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use super::EventRunner;
+use crate::{serial_println, time};
+
+/// Simulated cores running events in a seeded order
+pub struct Simulation {
+    runners: Vec<EventRunner>,
+    rng: SmallRng,
+    seed: u64,
+    /// Core polled at each step
+    trace: Vec<usize>,
+}
+
+impl Simulation {
+    /// Creates `cores` idle simulated cores and stops the clock at 0
+    pub fn new(cores: usize, seed: u64) -> Self {
+        assert!(cores > 0, "A simulation needs at least one core");
+        serial_println!("simulation: {} cores, seed {:#x}", cores, seed);
+        time::use_virtual_clock(0);
+        Simulation {
+            runners: (0..cores).map(|_| EventRunner::init()).collect(),
+            rng: SmallRng::seed_from_u64(seed),
+            seed,
+            trace: Vec::new(),
+        }
+    }
+
+    /// Queues an event on a simulated core
+    ///
+    /// # Arguments
+    /// * `core` - Index of the simulated core
+    /// * `future` - The event's work
+    /// * `priority` - The event's priority, as for `schedule_kernel`
+    pub fn schedule(
+        &mut self,
+        core: usize,
+        future: impl Future<Output = ()> + 'static + Send,
+        priority: usize,
+    ) {
+        self.runners[core].schedule(future, priority, 0);
+    }
+
+    /// Polls one event on a core picked by the generator among those with
+    /// pending events
+    ///
+    /// # Returns
+    /// False if no core has anything to run
+    pub fn step(&mut self) -> bool {
+        let busy: Vec<usize> = (0..self.runners.len())
+            .filter(|&core| self.runners[core].has_runnable_events())
+            .collect();
+        if busy.is_empty() {
+            return false;
+        }
+        let core = busy[self.rng.gen_range(0..busy.len())];
+        self.runners[core].run_next();
+        self.trace.push(core);
+        true
+    }
+
+    /// Steps until `done` returns true, every core is idle, or `max_steps`
+    /// steps have run
+    ///
+    /// # Returns
+    /// Whether `done` returned true
+    pub fn run_until(&mut self, max_steps: usize, mut done: impl FnMut() -> bool) -> bool {
+        for _ in 0..max_steps {
+            if done() {
+                return true;
+            }
+            if !self.step() {
+                break;
+            }
+        }
+        done()
+    }
+
+    /// Moves the virtual clock forward
+    pub fn advance_clock(&mut self, ns: u64) {
+        time::advance_virtual_clock(ns);
+    }
+
+    /// Seed the interleaving was drawn from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The simulated core polled at each step so far
+    pub fn trace(&self) -> &[usize] {
+        &self.trace
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        time::use_real_clock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::sleep_until;
+    use alloc::sync::Arc;
+    use core::{
+        future::poll_fn,
+        sync::atomic::{AtomicBool, Ordering},
+        task::Poll,
+    };
+    use spin::Mutex;
+
+    /// Runs two cores that each log a few values, yielding in between
+    fn interleave(seed: u64) -> (Vec<usize>, Vec<(usize, u32)>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut simulation = Simulation::new(2, seed);
+        for core in 0..2 {
+            let log = log.clone();
+            let mut next = 0;
+            simulation.schedule(
+                core,
+                poll_fn(move |_| {
+                    log.lock().push((core, next));
+                    next += 1;
+                    if next == 4 {
+                        Poll::Ready(())
+                    } else {
+                        Poll::Pending
+                    }
+                }),
+                0,
+            );
+        }
+        assert!(simulation.run_until(100, || log.lock().len() == 8));
+        let trace = simulation.trace().to_vec();
+        let log = log.lock().clone();
+        (trace, log)
+    }
+
+    #[test_case]
+    fn test_same_seed_same_interleaving() {
+        assert_eq!(interleave(0x7a05), interleave(0x7a05));
+
+        let mut simulation = Simulation::new(1, 1);
+        let deadline = time::monotonic_ns() + 1000;
+        let woke = Arc::new(AtomicBool::new(false));
+        let flag = woke.clone();
+        simulation.schedule(
+            0,
+            async move {
+                sleep_until(deadline).await;
+                flag.store(true, Ordering::Relaxed);
+            },
+            0,
+        );
+        // Only the test moves the clock, however long the run takes
+        assert!(!simulation.run_until(50, || woke.load(Ordering::Relaxed)));
+        simulation.advance_clock(1000);
+        assert!(simulation.run_until(50, || woke.load(Ordering::Relaxed)));
+    }
+}
@@ -1,8 +1,10 @@
 use super::{Event, EventId, EventQueue};
 use alloc::{boxed::Box, sync::Arc};
-use core::future::Future;
+use core::{future::Future, sync::atomic::Ordering};
 use futures::task::ArcWake;
-use spin::Mutex;
+use spin::{Mutex, RwLock};
+
+use crate::arch::without_interrupts;
 
 impl Event {
     pub fn init(
@@ -15,17 +17,26 @@ impl Event {
         Event {
             eid: EventId::init(),
             pid,
+            name: core::any::type_name_of_val(&future),
             future: Mutex::new(Box::pin(future)),
-            rewake_queue,
+            rewake_queue: RwLock::new(rewake_queue),
             priority: priority.into(),
             scheduled_clock: scheduled_clock.into(),
+            queued_clock: scheduled_clock.into(),
+            queued_priority: priority.into(),
+            blocked: false.into(),
         }
     }
 }
 
 impl ArcWake for Event {
+    // Wakes may come from interrupt handlers, so the queue is only ever
+    // locked with interrupts off
     fn wake_by_ref(arc: &Arc<Self>) {
-        let mut wlock = arc.rewake_queue.write();
-        wlock.push_back(arc.clone());
+        without_interrupts(|| {
+            arc.blocked.store(false, Ordering::SeqCst);
+            let queue = arc.rewake_queue.read().clone();
+            queue.write().push_back(arc.clone());
+        });
     }
 }
@@ -1,89 +1,177 @@
-use super::{Event, EventId, EventQueue, EventRunner};
+use super::{
+    Event, EventId, EventQueue, EventRunner, FairnessStats, StealStats, CORE_LOADS, NO_EVENT,
+    POLL_STARTED_NS, RUNNING_EVENT_IDS, RUNNING_EVENT_PIDS, SHOULD_YIELD, UNPLACED_LOAD,
+};
 
 use alloc::{
-    collections::{btree_set::BTreeSet, vec_deque::VecDeque},
+    boxed::Box,
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet, vec_deque::VecDeque},
     sync::Arc,
 };
 use futures::task::waker_ref;
 use spin::rwlock::RwLock;
-use x86_64::instructions::interrupts;
 
 use core::{
-    future::Future,
-    sync::atomic::Ordering,
+    future::{self, Future},
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
 
 use crate::{
-    constants::events::{NUM_EVENT_PRIORITIES, PRIORITY_INC_DELAY},
-    serial_println,
+    arch,
+    constants::{
+        events::{
+            DRR_POLL_COST, IDLE_PRIORITY, LONG_POLL_NS, NUM_EVENT_PRIORITIES, PRIORITY_INC_DELAY,
+            STEAL_MIN_QUEUED,
+        },
+        processes::KERNEL_PID,
+    },
+    memory::accounting::{charge_to, Subsystem},
+    processes::cgroup::{GroupId, CGROUPS},
+    serial_println, time,
+    tracing::{self, TraceEvent},
 };
 
 impl EventRunner {
+    // A runner whose load placement ignores
     pub fn init() -> EventRunner {
         EventRunner {
             event_queues: core::array::from_fn(|_| RwLock::new(VecDeque::new())),
+            idle_queue: RwLock::new(VecDeque::new()),
             rewake_queue: Arc::new(RwLock::new(VecDeque::new())),
             pending_events: RwLock::new(BTreeSet::new()),
+            blocked_events: RwLock::new(BTreeSet::new()),
             current_event: None,
             clock: 0,
+            group_deficits: BTreeMap::new(),
+            fairness: FairnessStats::default(),
+            stolen: AtomicU64::new(0),
+            given: AtomicU64::new(0),
+            load: &UNPLACED_LOAD,
+            long_poll_ns: LONG_POLL_NS,
+            long_polls: 0,
+        }
+    }
+
+    // The runner of a core, counted in that core's load
+    pub fn for_core(cpuid: u32) -> EventRunner {
+        EventRunner {
+            load: &CORE_LOADS[cpuid as usize],
+            ..Self::init()
         }
     }
 
     pub fn run_loop(&mut self) -> ! {
         loop {
-            loop {
-                if self.have_pending_events() {
-                    break;
-                }
+            while self.has_runnable_events() {
+                self.run_next();
+            }
 
-                self.current_event = self.next_event();
+            // Out of work, so help the busiest core before halting
+            if super::steal_work(arch::core_id(), self) {
+                continue;
+            }
 
-                let event = self
-                    .current_event
-                    .as_ref()
-                    .expect("Have pending events, but empty waiting queues.");
+            arch::enable_interrupts_and_wait();
+        }
+    }
 
-                if self.contains_event(event.eid) {
-                    self.clock += 1;
+    // Polls the next event once. There must be pending events.
+    pub fn run_next(&mut self) {
+        self.current_event = self.next_event();
+
+        let event = self
+            .current_event
+            .as_ref()
+            .expect("Have pending events, but empty waiting queues.");
+
+        if self.contains_event(event.eid) {
+            self.clock += 1;
+            let running = RUNNING_EVENT_IDS.get(arch::core_id() as usize);
+            let running_pid = RUNNING_EVENT_PIDS.get(arch::core_id() as usize);
+            running.inspect(|id| id.store(event.eid.0, Ordering::Relaxed));
+            running_pid.inspect(|pid| pid.store(event.pid, Ordering::Relaxed));
+            // Each poll starts with a fresh budget
+            if let Some(started) = POLL_STARTED_NS.get(arch::core_id() as usize) {
+                started.store(time::monotonic_ns(), Ordering::Relaxed);
+            }
+            if let Some(should_yield) = SHOULD_YIELD.get(arch::core_id() as usize) {
+                should_yield.store(false, Ordering::Relaxed);
+            }
 
-                    let waker = waker_ref(event);
-                    let mut context: Context<'_> = Context::from_waker(&waker);
+            let waker = waker_ref(event);
+            let mut context: Context<'_> = Context::from_waker(&waker);
 
-                    let mut future_guard = event.future.lock();
+            let mut future_guard = event.future.lock();
+            let locked_ns = time::monotonic_ns();
 
-                    let ready: bool = future_guard.as_mut().poll(&mut context) != Poll::Pending;
+            let ready: bool = future_guard.as_mut().poll(&mut context) != Poll::Pending;
+            if ready {
+                // A copy left queued by a wake, which another core may
+                // have stolen, must find nothing left to poll
+                *future_guard = Box::pin(future::ready(()));
+            }
 
-                    drop(future_guard);
+            drop(future_guard);
+            let held_ns = time::monotonic_ns().saturating_sub(locked_ns);
+            if held_ns >= self.long_poll_ns {
+                self.long_polls += 1;
+                Self::report_long_poll(event, held_ns);
+            }
+            running.inspect(|id| id.store(NO_EVENT, Ordering::Relaxed));
+            running_pid.inspect(|pid| pid.store(KERNEL_PID, Ordering::Relaxed));
+            tracing::record(TraceEvent::EventPolled {
+                eid: event.eid.0,
+                pid: event.pid,
+                ready,
+            });
 
-                    if !ready {
-                        let priority = event.priority.load(Ordering::Relaxed);
-                        Self::enqueue(&self.event_queues[priority], event.clone());
-                    } else {
-                        let mut write_lock = self.pending_events.write();
-                        write_lock.remove(&event.eid.0);
-                    }
+            if ready {
+                let mut write_lock = self.pending_events.write();
+                if write_lock.remove(&event.eid.0) {
+                    self.load.fetch_sub(1, Ordering::Relaxed);
                 }
-
-                self.current_event = None;
+            } else if event.blocked.load(Ordering::SeqCst) {
+                // Its wake queues it again
+                self.blocked_events.write().insert(event.eid.0);
+            } else {
+                self.make_ready(event.clone());
             }
+        }
 
-            // TODO do a lil work-stealing
+        self.current_event = None;
+    }
 
-            interrupts::enable_and_hlt();
-        }
+    // A poll that held its future too long keeps anything else wanting
+    // the future, and every event queued behind it, waiting
+    fn report_long_poll(event: &Event, held_ns: u64) {
+        tracing::record(TraceEvent::LongPoll {
+            eid: event.eid.0,
+            pid: event.pid,
+            name: event.name,
+            held_ns,
+        });
+        serial_println!(
+            "Long poll: pid {} event {} ({}) held its future for {} us",
+            event.pid,
+            event.eid.0,
+            event.name,
+            held_ns / 1000
+        );
     }
 
     // Schedules an event with a specified priority level [0, NUM_EVENT_PRIORITIES)
+    // or IDLE_PRIORITY
     pub fn schedule(
         &mut self,
         future: impl Future<Output = ()> + 'static + Send,
         priority_level: usize,
         pid: u32,
     ) {
-        if priority_level >= NUM_EVENT_PRIORITIES {
+        if priority_level > IDLE_PRIORITY {
             panic!("Invalid event priority: {}", priority_level);
         } else {
+            let _charge = charge_to(Subsystem::Events);
             let event = Arc::new(Event::init(
                 future,
                 self.rewake_queue.clone(),
@@ -92,11 +180,17 @@ impl EventRunner {
                 self.clock,
             ));
 
-            Self::enqueue(&self.event_queues[priority_level], event.clone());
+            self.make_ready(event.clone());
+            tracing::record(TraceEvent::EventScheduled {
+                eid: event.eid.0,
+                pid,
+                priority: priority_level,
+            });
 
             let mut write_lock = self.pending_events.write();
 
             write_lock.insert(event.eid.0);
+            self.load.fetch_add(1, Ordering::Relaxed);
         }
     }
 
@@ -104,8 +198,104 @@ impl EventRunner {
         self.current_event.as_ref()
     }
 
-    fn have_pending_events(&self) -> bool {
-        self.pending_events.read().is_empty()
+    // Returns true if any event above idle priority is queued
+    pub fn has_normal_work(&self) -> bool {
+        self.event_queues
+            .iter()
+            .any(|queue| !queue.read().is_empty())
+            || arch::without_interrupts(|| {
+                self.rewake_queue
+                    .read()
+                    .iter()
+                    .any(|event| event.priority.load(Ordering::Relaxed) != IDLE_PRIORITY)
+            })
+    }
+
+    // Returns true if a pending event is queued or woken, rather than
+    // blocked
+    pub fn has_runnable_events(&self) -> bool {
+        self.pending_events.read().len() > self.blocked_events.read().len()
+            || arch::without_interrupts(|| !self.rewake_queue.read().is_empty())
+    }
+
+    // Number of pending events waiting for a wake
+    pub fn blocked_events(&self) -> usize {
+        self.blocked_events.read().len()
+    }
+
+    // Number of events queued above idle priority, not counting wakes
+    pub fn queued_events(&self) -> usize {
+        self.event_queues
+            .iter()
+            .map(|queue| queue.read().len())
+            .sum()
+    }
+
+    pub fn steal_stats(&self) -> StealStats {
+        StealStats {
+            stolen: self.stolen.load(Ordering::Relaxed),
+            given: self.given.load(Ordering::Relaxed),
+        }
+    }
+
+    // Takes an event from `victim` if it has enough queued to spare one,
+    // and queues it here
+    pub fn steal_from(&self, victim: &EventRunner) -> bool {
+        if victim.queued_events() < STEAL_MIN_QUEUED {
+            return false;
+        }
+        let Some(event) = victim.try_steal() else {
+            return false;
+        };
+
+        *event.rewake_queue.write() = self.rewake_queue.clone();
+        // Aging starts over against this runner's clock
+        event.scheduled_clock.store(self.clock, Ordering::Relaxed);
+        self.pending_events.write().insert(event.eid.0);
+        self.load.fetch_add(1, Ordering::Relaxed);
+        self.make_ready(event);
+        self.stolen.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    // Removes the newest event of the least urgent normal queue that has
+    // one another core can run. Idle events stay with their core.
+    pub fn try_steal(&self) -> Option<Arc<Event>> {
+        for queue in self.event_queues.iter().rev() {
+            let mut queue = queue.write();
+            let Some(position) = queue.iter().rposition(|event| Self::can_move(event)) else {
+                continue;
+            };
+            let event = queue.remove(position)?;
+            if self.pending_events.write().remove(&event.eid.0) {
+                self.load.fetch_sub(1, Ordering::Relaxed);
+            }
+            self.given.fetch_add(1, Ordering::Relaxed);
+            return Some(event);
+        }
+        None
+    }
+
+    // Returns true if no core is polling the event, or an event of its
+    // process, which could still be running on the process' stack
+    fn can_move(event: &Event) -> bool {
+        let running = |pid| {
+            RUNNING_EVENT_PIDS
+                .iter()
+                .any(|running| running.load(Ordering::Relaxed) == pid)
+        };
+        !RUNNING_EVENT_IDS
+            .iter()
+            .any(|id| id.load(Ordering::Relaxed) == event.eid.0)
+            && (event.pid == KERNEL_PID || !running(event.pid))
+    }
+
+    fn queue_for(&self, priority: usize) -> &EventQueue {
+        if priority == IDLE_PRIORITY {
+            &self.idle_queue
+        } else {
+            &self.event_queues[priority]
+        }
     }
 
     fn contains_event(&self, eid: EventId) -> bool {
@@ -127,6 +317,23 @@ impl EventRunner {
         queue.write().push_back(event);
     }
 
+    // Queues an event at its priority and starts timing its wait
+    fn make_ready(&self, event: Arc<Event>) {
+        let priority = event.priority.load(Ordering::Relaxed);
+        event.queued_clock.store(self.clock, Ordering::Relaxed);
+        event.queued_priority.store(priority, Ordering::Relaxed);
+        Self::enqueue(self.queue_for(priority), event);
+    }
+
+    // Records how long a popped event waited in the queues
+    fn record_wait(&mut self, event: &Event) {
+        let priority = event.queued_priority.load(Ordering::Relaxed);
+        let wait = self.clock - event.queued_clock.load(Ordering::Relaxed);
+        if let Some(max_wait) = self.fairness.max_wait.get_mut(priority) {
+            *max_wait = (*max_wait).max(wait);
+        }
+    }
+
     fn reprioritize(&mut self) {
         for i in 1..NUM_EVENT_PRIORITIES {
             let scheduled_clock = Self::front_clock(&self.event_queues[i]);
@@ -139,6 +346,7 @@ impl EventRunner {
 
                         e.priority.swap(i - 1, Ordering::Relaxed);
                         e.scheduled_clock.swap(self.clock, Ordering::Relaxed);
+                        self.fairness.promotions += 1;
                         serial_println!("{:?} priority {} -> {} @ {}", e.eid, i, i - 1, self.clock);
                     });
                 }
@@ -147,7 +355,17 @@ impl EventRunner {
     }
 
     fn next_event(&mut self) -> Option<Arc<Event>> {
-        let rewake = Self::try_pop(&self.rewake_queue);
+        let mut rewake = arch::without_interrupts(|| Self::try_pop(&self.rewake_queue));
+        if let Some(event) = &rewake {
+            self.blocked_events.write().remove(&event.eid.0);
+        }
+        // Woken idle events still wait for normal work to drain
+        if let Some(event) = rewake.take_if(|event| {
+            event.priority.load(Ordering::Relaxed) == IDLE_PRIORITY && self.has_normal_work()
+        }) {
+            Self::enqueue(&self.idle_queue, event);
+        }
+
         if rewake.is_some() {
             rewake
         } else {
@@ -156,13 +374,304 @@ impl EventRunner {
             self.reprioritize();
 
             for i in 0..NUM_EVENT_PRIORITIES {
-                event = Self::try_pop(&self.event_queues[i]);
-                if event.is_some() {
+                event = self.pop_fair(i);
+                if let Some(event) = &event {
+                    self.record_wait(event);
                     break;
                 }
             }
 
-            event
+            event.or_else(|| Self::try_pop(&self.idle_queue))
+        }
+    }
+
+    // Pops the first event of a priority level whose CPU group has enough
+    // credit to be polled. When no group does, every group with queued
+    // events earns credit in proportion to its share, scaled so the largest
+    // share earns exactly one poll, and the search repeats.
+    fn pop_fair(&mut self, priority: usize) -> Option<Arc<Event>> {
+        let mut queue = self.event_queues[priority].write();
+        if queue.is_empty() {
+            return None;
+        }
+
+        let groups = CGROUPS.read();
+        let event_groups: VecDeque<GroupId> =
+            queue.iter().map(|e| groups.group_of(e.pid)).collect();
+
+        loop {
+            let eligible = event_groups.iter().position(|group| {
+                self.group_deficits.get(group).copied().unwrap_or(0) >= DRR_POLL_COST
+            });
+            if let Some(position) = eligible {
+                if let Some(deficit) = self.group_deficits.get_mut(&event_groups[position]) {
+                    *deficit -= DRR_POLL_COST;
+                }
+                return queue.remove(position);
+            }
+
+            let runnable: BTreeSet<GroupId> = event_groups.iter().copied().collect();
+            // Groups without queued events do not bank credit
+            self.group_deficits
+                .retain(|group, _| runnable.contains(group));
+
+            let shares: BTreeMap<GroupId, u64> = runnable
+                .iter()
+                .map(|&group| (group, groups.direct_share(group).max(1)))
+                .collect();
+            let largest = shares.values().copied().max().unwrap_or(1);
+            for (group, share) in shares {
+                let credit = (DRR_POLL_COST as u128 * share as u128 / largest as u128) as u64;
+                *self.group_deficits.entry(group).or_insert(0) += credit.max(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::yield_if_needed;
+    use core::{
+        future::poll_fn,
+        sync::atomic::{AtomicBool, AtomicUsize},
+    };
+
+    #[test_case]
+    fn test_low_priority_progress_under_flood() {
+        const FLOOD: u64 = 16;
+        let mut runner = EventRunner::init();
+        for _ in 0..FLOOD {
+            runner.schedule(poll_fn(|_| Poll::<()>::Pending), 0, 0);
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        let flag = done.clone();
+        let lowest = NUM_EVENT_PRIORITIES - 1;
+        runner.schedule(
+            async move { flag.store(true, Ordering::Relaxed) },
+            lowest,
+            0,
+        );
+
+        // Aging lifts the event one level every PRIORITY_INC_DELAY polls,
+        // then it waits behind the whole flood at priority 0
+        let bound = lowest as u64 * PRIORITY_INC_DELAY + FLOOD + 1;
+        let mut polls = 0;
+        while !done.load(Ordering::Relaxed) {
+            assert!(polls < bound, "Low priority event starved");
+            runner.run_next();
+            polls += 1;
         }
+
+        assert_eq!(runner.fairness.promotions, lowest as u64);
+        assert!(runner.fairness.max_wait[lowest] < bound);
+    }
+
+    #[test_case]
+    fn test_steal_from_busy_runner() {
+        let mut busy = EventRunner::init();
+        let mut idle = EventRunner::init();
+        let ran = Arc::new(AtomicBool::new(false));
+
+        // A lone event is left where it is
+        busy.schedule(async {}, 0, 0);
+        assert!(!idle.steal_from(&busy));
+
+        let flag = ran.clone();
+        let lowest = NUM_EVENT_PRIORITIES - 1;
+        busy.schedule(
+            async move { flag.store(true, Ordering::Relaxed) },
+            lowest,
+            0,
+        );
+        assert!(idle.steal_from(&busy));
+        assert_eq!(busy.queued_events(), 1);
+
+        // The stolen event runs on its new runner, and only there
+        idle.run_next();
+        assert!(ran.load(Ordering::Relaxed));
+        assert!(!idle.has_runnable_events());
+        busy.run_next();
+        assert!(!busy.has_runnable_events());
+
+        assert_eq!(
+            idle.steal_stats(),
+            StealStats {
+                stolen: 1,
+                given: 0
+            }
+        );
+        assert_eq!(
+            busy.steal_stats(),
+            StealStats {
+                stolen: 0,
+                given: 1
+            }
+        );
+    }
+
+    #[test_case]
+    fn test_load_tracks_pending_events() {
+        static LOAD: AtomicUsize = AtomicUsize::new(0);
+        let mut runner = EventRunner {
+            load: &LOAD,
+            ..EventRunner::init()
+        };
+
+        runner.schedule(async {}, 0, 0);
+        runner.schedule(poll_fn(|_| Poll::<()>::Pending), 1, 0);
+        assert_eq!(LOAD.load(Ordering::Relaxed), 2);
+
+        // Only the event that completes stops counting
+        runner.run_next();
+        runner.run_next();
+        assert_eq!(LOAD.load(Ordering::Relaxed), 1);
+
+        let thief = EventRunner::init();
+        runner.schedule(async {}, 0, 0);
+        assert!(thief.steal_from(&runner));
+        assert_eq!(LOAD.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn test_blocked_event_waits_for_wake() {
+        let mut runner = EventRunner::init();
+        let waker = Arc::new(spin::Mutex::new(None));
+        let slot = waker.clone();
+        runner.schedule(
+            poll_fn(move |cx| {
+                *slot.lock() = Some(cx.waker().clone());
+                Poll::<()>::Pending
+            }),
+            0,
+            0,
+        );
+        let event = runner.event_queues[0].read().front().unwrap().clone();
+        event.blocked.store(true, Ordering::SeqCst);
+
+        // Not queued again after its poll
+        runner.run_next();
+        assert_eq!(runner.blocked_events(), 1);
+        assert!(!runner.has_runnable_events());
+
+        waker.lock().take().unwrap().wake();
+        assert!(runner.has_runnable_events());
+        runner.run_next();
+        assert_eq!(runner.blocked_events(), 0);
+        // Without blocking again it is polled like any other event
+        assert!(runner.has_runnable_events());
+    }
+
+    #[test_case]
+    fn test_idle_priority() {
+        let mut runner = EventRunner::init();
+        let idle_polls = Arc::new(AtomicUsize::new(0));
+        let waker = Arc::new(spin::Mutex::new(None));
+        let (counter, slot) = (idle_polls.clone(), waker.clone());
+        runner.schedule(
+            poll_fn(move |cx| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                *slot.lock() = Some(cx.waker().clone());
+                Poll::<()>::Pending
+            }),
+            IDLE_PRIORITY,
+            0,
+        );
+        let idle = runner.idle_queue.read().front().unwrap().clone();
+
+        // Normal work runs first, for longer than aging would take to lift
+        // any other event to the top priority
+        let lowest = NUM_EVENT_PRIORITIES - 1;
+        let normal_polls = 2 * lowest as u64 * PRIORITY_INC_DELAY;
+        let remaining = Arc::new(AtomicU64::new(normal_polls));
+        let left = remaining.clone();
+        runner.schedule(
+            poll_fn(move |_| match left.fetch_sub(1, Ordering::Relaxed) {
+                1 => Poll::Ready(()),
+                _ => Poll::Pending,
+            }),
+            lowest,
+            0,
+        );
+        for _ in 0..normal_polls {
+            runner.run_next();
+        }
+        assert_eq!(remaining.load(Ordering::Relaxed), 0);
+        assert_eq!(idle_polls.load(Ordering::Relaxed), 0);
+        assert_eq!(idle.priority.load(Ordering::Relaxed), IDLE_PRIORITY);
+
+        // Once normal work drains the idle event runs, and blocks
+        idle.blocked.store(true, Ordering::SeqCst);
+        runner.run_next();
+        assert_eq!(idle_polls.load(Ordering::Relaxed), 1);
+        assert_eq!(runner.blocked_events(), 1);
+
+        // Woken while normal work is queued, it goes back to the idle queue
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        runner.schedule(
+            async move { flag.store(true, Ordering::Relaxed) },
+            lowest,
+            0,
+        );
+        waker.lock().take().unwrap().wake();
+        runner.run_next();
+        assert!(ran.load(Ordering::Relaxed));
+        assert_eq!(idle_polls.load(Ordering::Relaxed), 1);
+        assert_eq!(runner.idle_queue.read().len(), 1);
+        assert_eq!(runner.blocked_events(), 0);
+
+        runner.run_next();
+        assert_eq!(idle_polls.load(Ordering::Relaxed), 2);
+        assert_eq!(idle.priority.load(Ordering::Relaxed), IDLE_PRIORITY);
+    }
+
+    #[test_case]
+    fn test_yield_at_checkpoint() {
+        let mut runner = EventRunner::init();
+        let steps = Arc::new(AtomicUsize::new(0));
+        let counter = steps.clone();
+        runner.schedule(
+            async move {
+                counter.fetch_add(1, Ordering::Relaxed);
+                // As the timer does once the poll has used up its budget
+                SHOULD_YIELD[arch::core_id() as usize].store(true, Ordering::Relaxed);
+                yield_if_needed().await;
+                counter.fetch_add(1, Ordering::Relaxed);
+                // Nothing asked for a yield, so this checkpoint passes
+                yield_if_needed().await;
+                counter.fetch_add(1, Ordering::Relaxed);
+            },
+            0,
+            0,
+        );
+
+        runner.run_next();
+        assert_eq!(steps.load(Ordering::Relaxed), 1);
+        runner.run_next();
+        assert_eq!(steps.load(Ordering::Relaxed), 3);
+        assert!(!runner.has_runnable_events());
+    }
+
+    #[test_case]
+    fn test_long_poll_reported() {
+        const PID: u32 = 0x1519;
+        let mut runner = EventRunner::init();
+        runner.schedule(async {}, 0, PID);
+        runner.run_next();
+        assert_eq!(runner.long_polls, 0);
+
+        // With no allowance every poll is too long
+        let mut strict = EventRunner {
+            long_poll_ns: 0,
+            ..EventRunner::init()
+        };
+        strict.schedule(async {}, 0, PID);
+        strict.run_next();
+        assert_eq!(strict.long_polls, 1);
+        assert!(tracing::merged().iter().any(|record| matches!(
+            record.event,
+            TraceEvent::LongPoll { pid: PID, name, .. } if name.contains("event_runner")
+        )));
     }
 }
@@ -1,64 +1,476 @@
-use crate::prelude::*;
-use core::task::{Context, Poll, Waker};
-use core::{future::Future, pin::Pin};
-use futures::future;
+//! Blocking primitives for events: an async mutex with priority
+//! inheritance, and a wait queue drivers can sleep on.
+//!
+//! A spin lock held by a low priority event keeps every more urgent event
+//! that wants it waiting until the holder happens to be polled again, which
+//! may be a long time once medium priority work is queued. `PriorityMutex`
+//! remembers the event holding it, and each waiter raises the holder to its
+//! own priority while it waits. The holder returns to its old priority when
+//! it unlocks.
+//!
+//! The raised priority is read the next time the holder is queued, so a
+//! holder that is already sitting in a queue keeps its place there until
+//! it is woken again. Idle events are not raised, as with `PriorityBoost`.
+//!
+//! Waiters get the lock in arrival order. When the lock is released the
+//! longest waiting waiter is notified and the lock is kept for it, so an
+//! event that comes along before the waiter is polled cannot take it first.
+//!
+//! `WaitQueue` lets an event sleep until something, usually an interrupt
+//! handler, wakes it, instead of spinning on a status register. Waiting
+//! events are blocked in their runner, so they are not polled at all until
+//! the wake. Wakes only take locks with interrupts off, so they are safe to
+//! call from interrupt handlers.
 
-use rand::rngs::SmallRng;
-use rand::{Rng, SeedableRng};
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    future::{poll_fn, Future},
+    ops::{Deref, DerefMut},
+    pin::{pin, Pin},
+    sync::atomic::Ordering,
+    task::{Context, Poll},
+};
+use futures::task::noop_waker_ref;
+use spin::Mutex;
 
-// BELOW FOR TESTING/DEMONSTRATION PURPOSES
+use super::{block_current_event, current_event, timer, Event};
+use crate::{
+    arch::without_interrupts,
+    constants::events::IDLE_PRIORITY,
+    ipc::wait_queue::{Ticket, WakerQueue},
+};
 
-struct RandomFuture {
-    prob: f64,
-    rng: SmallRng,
-    waker: Option<Waker>,
+struct State {
+    locked: bool,
+    /// The event holding the lock and its priority before any waiter
+    /// raised it
+    holder: Option<(Arc<Event>, usize)>,
+    waiters: WakerQueue,
 }
 
-impl RandomFuture {
-  fn new(prob: f64, seed: u64) -> Self {
-      RandomFuture {
-        prob: prob,
-        rng: SmallRng::seed_from_u64(seed),
-        waker: None // Waker is created upon polling Pending
-      }
-  }
+/// A mutex for events that lends waiters' priorities to the holder
+pub struct PriorityMutex<T: ?Sized> {
+    state: Mutex<State>,
+    data: UnsafeCell<T>,
 }
 
-impl Future for RandomFuture {
-    type Output = ();
+unsafe impl<T: ?Sized + Send> Send for PriorityMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for PriorityMutex<T> {}
+
+/// Access to the data of a locked `PriorityMutex`, which is unlocked when
+/// the guard is dropped
+pub struct PriorityMutexGuard<'a, T: ?Sized> {
+    mutex: &'a PriorityMutex<T>,
+}
+
+/// Takes a waiter out of the queue when its lock future is dropped, passing
+/// the lock on if it had been kept for the waiter
+struct Waiter<'a, T: ?Sized> {
+    mutex: &'a PriorityMutex<T>,
+    ticket: Option<Ticket>,
+}
+
+impl<T: ?Sized> Drop for Waiter<'_, T> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket.take() {
+            let mut state = self.mutex.state.lock();
+            if state.waiters.remove(ticket) {
+                state.waiters.wake_one();
+            }
+        }
+    }
+}
 
-  // Required method
-  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-    let prob: f64 = self.prob;
-    let res = self.rng.gen_bool(prob);
+impl<T> PriorityMutex<T> {
+    pub const fn new(data: T) -> Self {
+        PriorityMutex {
+            state: Mutex::new(State {
+                locked: false,
+                holder: None,
+                waiters: WakerQueue::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> PriorityMutex<T> {
+    /// Waits for the lock, raising the holder to the running event's
+    /// priority in the meantime
+    pub async fn lock(&self) -> PriorityMutexGuard<'_, T> {
+        let mut waiter = Waiter {
+            mutex: self,
+            ticket: None,
+        };
+        poll_fn(|cx| self.poll_lock(&mut waiter.ticket, cx, current_event())).await
+    }
+
+    /// Returns true if an event holds the lock
+    pub fn is_locked(&self) -> bool {
+        self.state.lock().locked
+    }
 
-    let poll = match res {
-      true => {
-        match &self.waker {
-          Some(waker) => {waker.wake_by_ref();}
-          None => ()
+    /// Takes the lock if it is free and not kept for a waiter, or queues
+    /// the waiter and raises the holder
+    ///
+    /// # Arguments
+    /// * `ticket` - The waiter's place in the queue, None until it queues
+    /// * `cx` - Context of the task polling the waiter
+    /// * `event` - The event the waiter runs in, None outside of an event
+    fn poll_lock(
+        &self,
+        ticket: &mut Option<Ticket>,
+        cx: &mut Context<'_>,
+        event: Option<Arc<Event>>,
+    ) -> Poll<PriorityMutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        let my_turn = match *ticket {
+            Some(ticket) => state.waiters.is_notified(ticket),
+            None => state.waiters.waiting() == 0 && state.waiters.notified() == 0,
         };
-        Poll::Ready(())
-      },
-      false => {
-        self.waker = Some(cx.waker().clone());
+        if !state.locked && my_turn {
+            if let Some(ticket) = ticket.take() {
+                state.waiters.remove(ticket);
+            }
+            state.locked = true;
+            state.holder = event.map(|event| {
+                let priority = event.priority.load(Ordering::Relaxed);
+                (event, priority)
+            });
+            return Poll::Ready(PriorityMutexGuard { mutex: self });
+        }
+
+        state.waiters.register(ticket, cx.waker());
+        if let (Some((holder, previous)), Some(event)) = (&state.holder, event) {
+            if *previous != IDLE_PRIORITY {
+                holder
+                    .priority
+                    .fetch_min(event.priority.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+        }
         Poll::Pending
-      }
-    };
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        state.locked = false;
+        if let Some((holder, previous)) = state.holder.take() {
+            holder.priority.store(previous, Ordering::Relaxed);
+        }
+        // A waiter already notified has the lock kept for it
+        if state.waiters.notified() == 0 {
+            state.waiters.wake_one();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for PriorityMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for PriorityMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for PriorityMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Events waiting to be woken, in arrival order
+pub struct WaitQueue {
+    /// Only locked with interrupts off, since wakes come from handlers
+    waiters: Mutex<WakerQueue>,
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future of one `WaitQueue::wait`
+pub struct Wait<'a> {
+    queue: &'a WaitQueue,
+    ticket: Option<Ticket>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(WakerQueue::new()),
+        }
+    }
 
-    poll
-  }
+    /// Waits for the next `wake_one` or `wake_all`. A wake that comes
+    /// before the wait is first polled is not remembered, so use
+    /// `wait_until` to wait for a condition.
+    ///
+    /// The event is not polled again until it is woken, so it should not
+    /// wait on this together with futures that expect to be polled again
+    /// without a wake.
+    pub fn wait(&self) -> Wait<'_> {
+        Wait {
+            queue: self,
+            ticket: None,
+        }
+    }
+
+    /// Waits until `condition` returns true, checking it after each wake
+    pub async fn wait_until(&self, condition: impl FnMut() -> bool) {
+        self.until(condition, true).await
+    }
+
+    /// Like `wait_until`, but gives up once the monotonic clock reaches
+    /// `deadline_ns`, with the timer waking the event for it
+    ///
+    /// # Returns
+    /// False if the deadline came first
+    pub async fn wait_until_deadline(
+        &self,
+        deadline_ns: u64,
+        mut condition: impl FnMut() -> bool,
+    ) -> bool {
+        let mut met = false;
+        first_of(
+            self.wait_until(|| {
+                met = condition();
+                met
+            }),
+            timer::deadline(deadline_ns),
+        )
+        .await;
+        met
+    }
+
+    /// Like `wait_until`, but the event stays runnable and the condition is
+    /// also checked each time the runner gets back to it. For waiters that
+    /// cannot count on the wake coming, such as a driver whose interrupt
+    /// may not be routed, or a caller polling the future by hand.
+    pub async fn poll_until(&self, condition: impl FnMut() -> bool) {
+        self.until(condition, false).await
+    }
+
+    async fn until(&self, mut condition: impl FnMut() -> bool, block: bool) {
+        let mut wait = self.wait();
+        poll_fn(|cx| {
+            // Queued before checking, so a wake between the check and
+            // going to sleep is not lost
+            let woken = wait.poll_queued(cx).is_ready();
+            if condition() {
+                return Poll::Ready(());
+            }
+            if woken {
+                wait = self.wait();
+                let _ = wait.poll_queued(cx);
+            }
+            if block {
+                block_current_event();
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Wakes the longest waiting event
+    ///
+    /// # Returns
+    /// False if no event was waiting
+    pub fn wake_one(&self) -> bool {
+        without_interrupts(|| self.waiters.lock().wake_one())
+    }
+
+    /// Wakes every waiting event
+    pub fn wake_all(&self) {
+        without_interrupts(|| self.waiters.lock().wake_all())
+    }
+
+    /// Number of events waiting for a wake
+    pub fn waiting(&self) -> usize {
+        without_interrupts(|| self.waiters.lock().waiting())
+    }
+}
+
+impl Wait<'_> {
+    /// Completes if the waiter has been woken, or queues it
+    fn poll_queued(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        without_interrupts(|| {
+            let mut waiters = self.queue.waiters.lock();
+            if let Some(ticket) = self.ticket {
+                if waiters.is_notified(ticket) {
+                    waiters.remove(ticket);
+                    self.ticket = None;
+                    return Poll::Ready(());
+                }
+            }
+            waiters.register(&mut self.ticket, cx.waker());
+            Poll::Pending
+        })
+    }
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let poll = self.poll_queued(cx);
+        if poll.is_pending() {
+            block_current_event();
+        }
+        poll
+    }
+}
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket.take() {
+            without_interrupts(|| {
+                let mut waiters = self.queue.waiters.lock();
+                // A wake meant for this waiter goes to the next one
+                if waiters.remove(ticket) {
+                    waiters.wake_one();
+                }
+            });
+        }
+    }
+}
+
+/// Waits for whichever of two futures completes first, dropping the
+/// other. Each must be woken when it can make progress, since either may
+/// block the event.
+pub async fn first_of(a: impl Future<Output = ()>, b: impl Future<Output = ()>) {
+    let (mut a, mut b) = (pin!(a), pin!(b));
+    poll_fn(|cx| {
+        if a.as_mut().poll(cx).is_ready() || b.as_mut().poll(cx).is_ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
 }
 
-async fn rand_delay(seed: u32) -> u32 {
-  serial_println!("Awaiting random delay");
-  let foo = RandomFuture::new(0.4, seed as u64);
-  foo.await;
-  seed
+/// Polls a future until it completes, for callers that cannot await, such
+/// as the synchronous `BlockDevice` methods of drivers with async
+/// transfers. Nothing wakes the future, so one that waits with
+/// `WaitQueue::wait_until` spins here instead of sleeping, and the event it
+/// blocked is left runnable.
+pub fn run_to_completion<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(noop_waker_ref());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        // The event keeps running this loop, so it must not be left blocked
+        // with no one to wake it
+        if let Some(event) = current_event() {
+            event.blocked.store(false, Ordering::SeqCst);
+        }
+        core::hint::spin_loop();
+    }
 }
 
-pub async fn print_nums_after_rand_delay(seed: u32) -> () {
-  let res= future::join(rand_delay(seed), rand_delay(seed*2)).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use core::{pin::pin, sync::atomic::AtomicBool};
+    use futures::task::noop_waker_ref;
+    use spin::RwLock;
+
+    fn event(priority: usize) -> Arc<Event> {
+        let queue = Arc::new(RwLock::new(VecDeque::new()));
+        Arc::new(Event::init(async {}, queue, priority, 0, 0))
+    }
+
+    #[test_case]
+    fn test_priority_inheritance() {
+        let mutex = PriorityMutex::new(0);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let (low, medium, high) = (event(3), event(2), event(0));
+
+        let Poll::Ready(mut guard) = mutex.poll_lock(&mut None, &mut cx, Some(low.clone())) else {
+            panic!("Free mutex not taken");
+        };
+        *guard += 1;
+
+        // Each waiter lends the holder its priority, the most urgent wins
+        let mut tickets = [None, None];
+        assert!(mutex
+            .poll_lock(&mut tickets[0], &mut cx, Some(medium.clone()))
+            .is_pending());
+        assert_eq!(low.priority.load(Ordering::Relaxed), 2);
+        assert!(mutex
+            .poll_lock(&mut tickets[1], &mut cx, Some(high.clone()))
+            .is_pending());
+        assert_eq!(low.priority.load(Ordering::Relaxed), 0);
+
+        // Unlocking restores the holder and keeps the lock for the first
+        // waiter, even against a newcomer
+        drop(guard);
+        assert_eq!(low.priority.load(Ordering::Relaxed), 3);
+        let mut newcomer = None;
+        assert!(mutex
+            .poll_lock(&mut newcomer, &mut cx, Some(event(0)))
+            .is_pending());
+        assert!(mutex
+            .poll_lock(&mut tickets[1], &mut cx, Some(high.clone()))
+            .is_pending());
+        let Poll::Ready(guard) = mutex.poll_lock(&mut tickets[0], &mut cx, Some(medium)) else {
+            panic!("Lock not handed to the first waiter");
+        };
+        assert_eq!(*guard, 1);
+        assert!(mutex.is_locked());
+    }
+
+    #[test_case]
+    fn test_wait_queue() {
+        let queue = WaitQueue::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // A wake with nobody waiting is not remembered
+        assert!(!queue.wake_one());
+        let mut first = pin!(queue.wait());
+        let mut second = pin!(queue.wait());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(queue.waiting(), 2);
+
+        // Woken in the order they waited
+        assert!(queue.wake_one());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert!(first.as_mut().poll(&mut cx).is_ready());
+        queue.wake_all();
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(queue.waiting(), 0);
+
+        // The condition is checked after every wake
+        let ready = AtomicBool::new(false);
+        let mut until = pin!(queue.wait_until(|| ready.load(Ordering::Relaxed)));
+        assert!(until.as_mut().poll(&mut cx).is_pending());
+        queue.wake_all();
+        assert!(until.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(queue.waiting(), 1);
+        ready.store(true, Ordering::Relaxed);
+        queue.wake_all();
+        assert!(until.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(queue.waiting(), 0);
 
-    serial_println!("Random results: {} {}", res.0, res.1);
+        // Polling waiters are queued for wakes all the same
+        ready.store(false, Ordering::Relaxed);
+        let mut polled = pin!(queue.poll_until(|| ready.load(Ordering::Relaxed)));
+        assert!(polled.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(queue.waiting(), 1);
+        ready.store(true, Ordering::Relaxed);
+        assert!(polled.as_mut().poll(&mut cx).is_ready());
+    }
 }
@@ -4,18 +4,34 @@ use alloc::{
     sync::Arc,
 };
 use spin::{mutex::Mutex, rwlock::RwLock};
-use x86_64::instructions::interrupts::without_interrupts;
 
 use core::{
-    future::Future,
+    future::{poll_fn, Future},
     pin::Pin,
-    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    task::Poll,
 };
 
-use crate::constants::events::NUM_EVENT_PRIORITIES;
+use crate::{
+    arch::{core_id, early_core_id, without_interrupts},
+    constants::{
+        events::{IDLE_PRIORITY, KERNEL_POLL_BUDGET_NS, NUM_EVENT_PRIORITIES},
+        idt::WAKE_VECTOR,
+        processes::KERNEL_PID,
+        MAX_CORES,
+    },
+    interrupts::x2apic,
+    kexpect,
+    processes::{cgroup::GroupId, process::process_priority},
+    sync, time,
+};
 
+#[cfg(test)]
+pub mod deterministic;
 mod event;
 mod event_runner;
+pub mod futures;
+pub mod timer;
 
 // Thread-safe future that remains pinned to a heap address throughout its lifetime
 type SendFuture = Mutex<Pin<Box<dyn Future<Output = ()> + 'static + Send>>>;
@@ -39,24 +55,90 @@ impl EventId {
 struct Event {
     eid: EventId,
     pid: u32,
+    // Type name of the future, to tell events apart in reports
+    name: &'static str,
     future: SendFuture,
-    rewake_queue: Arc<EventQueue>,
+    // Where wakes put the event, changed when another core steals it
+    rewake_queue: RwLock<Arc<EventQueue>>,
     priority: AtomicUsize,
     scheduled_clock: AtomicU64,
+    // Runner clock and priority when the event last became ready, kept
+    // through promotions so its whole wait is measured
+    queued_clock: AtomicU64,
+    queued_priority: AtomicUsize,
+    // Set by a future that will be woken when it can make progress, so the
+    // runner does not poll it again until then. Cleared by the wake.
+    blocked: AtomicBool,
+}
+
+/// Measures of how fairly a runner has served its priority levels. Times
+/// are in runner clock ticks, one per event polled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FairnessStats {
+    /// Longest an event waited to be polled, by the priority it was
+    /// queued at. Events woken by a waker skip the queues and are not
+    /// counted.
+    pub max_wait: [u64; NUM_EVENT_PRIORITIES],
+    /// Number of times aging promoted an event to a more urgent priority
+    pub promotions: u64,
+}
+
+/// How much work a runner has moved to or from other cores
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StealStats {
+    /// Events this core took from busier cores after running out of its own
+    pub stolen: u64,
+    /// Events idle cores took from this one
+    pub given: u64,
 }
 
 // Schedules and runs events within a single core
 struct EventRunner {
     event_queues: [EventQueue; NUM_EVENT_PRIORITIES],
+    idle_queue: EventQueue,
     rewake_queue: Arc<EventQueue>,
     pending_events: RwLock<BTreeSet<u64>>,
+    // Pending events left out of the queues until they are woken
+    blocked_events: RwLock<BTreeSet<u64>>,
     current_event: Option<Arc<Event>>,
     clock: u64,
+    // Deficit round robin credit of each CPU group with queued events
+    group_deficits: BTreeMap<GroupId, u64>,
+    fairness: FairnessStats,
+    // Updated by other cores while they steal, so kept atomic
+    stolen: AtomicU64,
+    given: AtomicU64,
+    // Number of pending events, read by placement on other cores
+    load: &'static AtomicUsize,
+    // Polls holding their future for at least this long are reported
+    long_poll_ns: u64,
+    long_polls: u64,
 }
 
 // Global mapping of cores to events
 // TODO will need to expand when distributed, like most globals
-static EVENT_RUNNERS: RwLock<BTreeMap<u32, RwLock<EventRunner>>> = RwLock::new(BTreeMap::new());
+// ID of the event each core is polling, kept outside the runners so it
+// can be read without a lock
+const NO_EVENT: u64 = u64::MAX;
+static RUNNING_EVENT_IDS: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(NO_EVENT) }; MAX_CORES];
+// PID of the event each core is polling. A process' events are not stolen
+// while one of them runs, since the core may still be on its stack.
+static RUNNING_EVENT_PIDS: [AtomicU32; MAX_CORES] =
+    [const { AtomicU32::new(KERNEL_PID) }; MAX_CORES];
+// When each core started polling its current event, so the timer can tell
+// how long kernel work has held the core
+static POLL_STARTED_NS: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+// Set by the timer once a core's current poll has run past its budget, and
+// cleared when the next poll starts
+static SHOULD_YIELD: [AtomicBool; MAX_CORES] = [const { AtomicBool::new(false) }; MAX_CORES];
+// Events pending on each core's runner, so placement can compare cores
+// without locking their runners
+static CORE_LOADS: [AtomicUsize; MAX_CORES] = [const { AtomicUsize::new(0) }; MAX_CORES];
+// Load of runners that belong to no core, such as simulated ones
+static UNPLACED_LOAD: AtomicUsize = AtomicUsize::new(0);
+
+static EVENT_RUNNERS: sync::RwLock<BTreeMap<u32, RwLock<EventRunner>>> =
+    sync::RwLock::new("event runners", BTreeMap::new());
 
 /// # Safety
 ///
@@ -74,46 +156,135 @@ pub fn schedule_kernel(
     priority_level: usize,
 ) {
     let runners = EVENT_RUNNERS.read();
-    let mut runner = runners.get(&cpuid).expect("No runner found").write();
+    let mut runner = kexpect!(runners.get(&cpuid), (), "No runner for core {}", cpuid).write();
 
-    runner.schedule(future, priority_level, 0);
+    runner.schedule(future, priority_level, KERNEL_PID);
 }
 
-pub fn schedule_process(
-    cpuid: u32,
-    future: impl Future<Output = ()> + 'static + Send,
-    pid: u32, // 0 as kernel/sentinel
-) {
+/// Schedules maintenance work that only runs when the core has nothing else
+/// to do. Long running idle work should await `idle_yield` between units of
+/// work so that newly arrived events are not delayed.
+pub fn schedule_idle(cpuid: u32, future: impl Future<Output = ()> + 'static + Send) {
     without_interrupts(|| {
         let runners = EVENT_RUNNERS.read();
-        let mut runner = runners.get(&cpuid).expect("No runner found").write();
+        let mut runner = kexpect!(runners.get(&cpuid), (), "No runner for core {}", cpuid).write();
 
-        runner.schedule(future, NUM_EVENT_PRIORITIES - 1, pid);
+        runner.schedule(future, IDLE_PRIORITY, KERNEL_PID);
+    });
+}
+
+/// Returns true if a core has events queued above idle priority
+pub fn normal_work_pending(cpuid: u32) -> bool {
+    let runners = EVENT_RUNNERS.read();
+    runners
+        .get(&cpuid)
+        .is_some_and(|runner| runner.read().has_normal_work())
+}
+
+/// Yields once if normal work is waiting on the current core, otherwise
+/// completes immediately
+pub fn idle_yield() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    poll_fn(move |_| {
+        if !yielded && normal_work_pending(core_id()) {
+            yielded = true;
+            // The runner requeues pending events, no wake needed
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    })
+}
+
+/// Asks the core to yield at its next checkpoint if its current event has
+/// been polled for longer than `KERNEL_POLL_BUDGET_NS`. Called from the
+/// timer interrupt when it finds kernel work running, which it does not
+/// preempt.
+pub fn check_poll_budget(cpuid: u32) {
+    let core = cpuid as usize;
+    if RUNNING_EVENT_IDS[core].load(Ordering::Relaxed) == NO_EVENT {
+        return;
+    }
+    let started = POLL_STARTED_NS[core].load(Ordering::Relaxed);
+    if time::monotonic_ns().saturating_sub(started) >= KERNEL_POLL_BUDGET_NS {
+        SHOULD_YIELD[core].store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether the timer has asked the current core to yield
+pub fn should_yield() -> bool {
+    SHOULD_YIELD[core_id() as usize].load(Ordering::Relaxed)
+}
+
+/// A checkpoint for long running kernel futures. Yields once if the
+/// current poll has used up its budget, otherwise completes immediately.
+/// Only await it where no lock is held.
+pub fn yield_if_needed() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    poll_fn(move |_| {
+        if !yielded && SHOULD_YIELD[core_id() as usize].swap(false, Ordering::Relaxed) {
+            yielded = true;
+            // The runner requeues pending events, no wake needed
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    })
+}
+
+/// Completes once the monotonic clock reaches `deadline_ns`
+pub fn sleep_until(deadline_ns: u64) -> impl Future<Output = ()> {
+    poll_fn(move |_| {
+        if time::monotonic_ns() >= deadline_ns {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+}
+
+/// Schedules a process' ring 3 execution at the priority stored in its PCB
+pub fn schedule_process(cpuid: u32, future: impl Future<Output = ()> + 'static + Send, pid: u32) {
+    without_interrupts(|| {
+        let priority = process_priority(pid);
+        let runners = EVENT_RUNNERS.read();
+        let mut runner = kexpect!(runners.get(&cpuid), (), "No runner for core {}", cpuid).write();
+
+        runner.schedule(future, priority, pid);
     });
 }
 
 pub fn register_event_runner(cpuid: u32) {
     without_interrupts(|| {
-        let runner = EventRunner::init();
+        let runner = EventRunner::for_core(cpuid);
         let mut write_lock = EVENT_RUNNERS.write();
 
         write_lock.insert(cpuid, RwLock::new(runner));
     });
 }
 
+/// Returns the PID of the event running on a core, or KERNEL_PID if it is
+/// running kernel work or has no event runner yet
 pub fn current_running_event_pid(cpuid: u32) -> u32 {
     let runners = EVENT_RUNNERS.read();
-    let runner = runners.get(&cpuid).expect("No runner found").write();
+    let Some(runner) = runners.get(&cpuid) else {
+        return KERNEL_PID;
+    };
+    let runner = runner.write();
 
     match runner.current_running_event() {
         Some(e) => e.pid,
-        None => 0,
+        None => KERNEL_PID,
     }
 }
 
 pub fn current_running_event_priority(cpuid: u32) -> usize {
     let runners = EVENT_RUNNERS.read();
-    let runner = runners.get(&cpuid).expect("No runner found").write();
+    let runner = kexpect!(
+        runners.get(&cpuid),
+        NUM_EVENT_PRIORITIES - 1,
+        "No runner for core {}",
+        cpuid
+    )
+    .write();
 
     match runner.current_running_event() {
         Some(e) => e.priority.load(Ordering::Relaxed),
@@ -121,6 +292,163 @@ pub fn current_running_event_priority(cpuid: u32) -> usize {
     }
 }
 
+/// Returns the fairness metrics of a core's event runner
+pub fn fairness_stats(cpuid: u32) -> FairnessStats {
+    let runners = EVENT_RUNNERS.read();
+    runners
+        .get(&cpuid)
+        .map_or_else(FairnessStats::default, |runner| runner.read().fairness)
+}
+
+/// Returns the number of events pending on a core, including the one it
+/// is running
+pub fn core_load(cpuid: u32) -> usize {
+    CORE_LOADS
+        .get(cpuid as usize)
+        .map_or(0, |load| load.load(Ordering::Relaxed))
+}
+
+/// Picks the core with an event runner that has the fewest pending
+/// events, preferring the current core among equally loaded ones
+pub fn least_loaded_core() -> u32 {
+    let here = core_id();
+    let runners = EVENT_RUNNERS.read();
+    runners
+        .keys()
+        .copied()
+        .min_by_key(|&core| (core_load(core), core != here))
+        .unwrap_or(here)
+}
+
+/// Schedules the first run of a new process on the least loaded core,
+/// waking that core if it is not this one
+///
+/// # Returns
+/// The core the process was placed on
+pub fn place_process(future: impl Future<Output = ()> + 'static + Send, pid: u32) -> u32 {
+    let core = least_loaded_core();
+    schedule_process(core, future, pid);
+    if core != core_id() {
+        // It may be halted with nothing to do
+        x2apic::send_ipi(core, WAKE_VECTOR);
+    }
+    core
+}
+
+/// Returns the number of events on a core that are waiting to be woken
+pub fn blocked_events(cpuid: u32) -> usize {
+    let runners = EVENT_RUNNERS.read();
+    runners
+        .get(&cpuid)
+        .map_or(0, |runner| runner.read().blocked_events())
+}
+
+/// Returns how many events a core's runner has stolen and given away
+pub fn steal_stats(cpuid: u32) -> StealStats {
+    let runners = EVENT_RUNNERS.read();
+    runners
+        .get(&cpuid)
+        .map_or_else(StealStats::default, |runner| runner.read().steal_stats())
+}
+
+/// Moves an event from the core with the most queued work to `thief`, the
+/// runner of `cpuid`, once it has run out of its own
+///
+/// # Returns
+/// Whether an event was stolen
+fn steal_work(cpuid: u32, thief: &EventRunner) -> bool {
+    // Interrupt handlers may schedule onto the runners read locked here
+    without_interrupts(|| {
+        let runners = EVENT_RUNNERS.read();
+        let victim = runners
+            .iter()
+            .filter(|(&core, _)| core != cpuid)
+            .map(|(_, runner)| runner.read())
+            .max_by_key(|runner| runner.queued_events());
+        victim.is_some_and(|victim| thief.steal_from(&victim))
+    })
+}
+
+/// Adds the event runner table to the lock contention report
+pub fn track_locks() {
+    EVENT_RUNNERS.track();
+}
+
+/// Returns the ID of the event the current core is polling, if any. Takes
+/// no locks, so lock instrumentation can call it.
+pub fn current_event_id() -> Option<u64> {
+    let id = RUNNING_EVENT_IDS
+        .get(early_core_id() as usize)?
+        .load(Ordering::Relaxed);
+    (id != NO_EVENT).then_some(id)
+}
+
+/// Returns the event running on the current core, if any
+fn current_event() -> Option<Arc<Event>> {
+    let runners = EVENT_RUNNERS.read();
+    let runner = runners.get(&(core_id()))?.read();
+    runner.current_running_event().cloned()
+}
+
+/// Keeps the running event out of the ready queues after its current poll
+/// until it is woken. Only futures that are sure to be woken may call this;
+/// others rely on the runner polling pending events again.
+pub fn block_current_event() {
+    if let Some(event) = current_event() {
+        event.blocked.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Returns the priority of the event running on the current core, or None
+/// outside of an event
+pub fn current_event_priority() -> Option<usize> {
+    current_event().map(|event| event.priority.load(Ordering::Relaxed))
+}
+
+/// Temporarily raises the priority of the running event, for example while
+/// it works on behalf of a more urgent event. The event returns to the
+/// priority it had before when the boost is dropped.
+#[derive(Default)]
+pub struct PriorityBoost {
+    /// The boosted event and its priority before the boost
+    boosted: Option<(Arc<Event>, usize)>,
+}
+
+impl PriorityBoost {
+    /// Creates a boost that has not raised anything yet
+    pub fn new() -> Self {
+        PriorityBoost { boosted: None }
+    }
+
+    /// Raises the running event to `priority` if that is more urgent than
+    /// its current priority. Idle events are never raised, and the call
+    /// does nothing outside of an event.
+    pub fn raise_to(&mut self, priority: usize) {
+        let priority = priority.min(NUM_EVENT_PRIORITIES - 1);
+        if self.boosted.is_none() {
+            let Some(event) = current_event() else {
+                return;
+            };
+            let previous = event.priority.load(Ordering::Relaxed);
+            if previous == IDLE_PRIORITY {
+                return;
+            }
+            self.boosted = Some((event, previous));
+        }
+        if let Some((event, _)) = &self.boosted {
+            event.priority.fetch_min(priority, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for PriorityBoost {
+    fn drop(&mut self) {
+        if let Some((event, previous)) = self.boosted.take() {
+            event.priority.store(previous, Ordering::Relaxed);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct EventInfo {
     pub priority: usize,
@@ -132,7 +460,17 @@ pub struct EventInfo {
 // most likely it isn't finding any event
 pub fn current_running_event_info(cpuid: u32) -> EventInfo {
     let runners = EVENT_RUNNERS.read();
-    let runner = runners.get(&cpuid).expect("No runner found").write();
+    // Treat the core as running kernel work, which callers reject
+    let runner = kexpect!(
+        runners.get(&cpuid),
+        EventInfo {
+            priority: NUM_EVENT_PRIORITIES - 1,
+            pid: KERNEL_PID,
+        },
+        "No runner for core {}",
+        cpuid
+    )
+    .write();
 
     match runner.current_running_event() {
         Some(e) => EventInfo {
@@ -141,7 +479,7 @@ pub fn current_running_event_info(cpuid: u32) -> EventInfo {
         },
         None => EventInfo {
             priority: NUM_EVENT_PRIORITIES - 1,
-            pid: 0,
+            pid: KERNEL_PID,
         },
     }
 }
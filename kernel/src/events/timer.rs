@@ -0,0 +1,123 @@
+//! Deadlines that wake blocked events.
+//!
+//! `sleep_until` only checks the clock when its event is polled, which a
+//! blocked event is not until something wakes it. A `Deadline` registers
+//! its waker here instead, and the timer interrupt wakes every waker whose
+//! deadline has passed on each tick. Deadlines are therefore only as fine
+//! as the timer period, and are never met while the clock is not
+//! calibrated.
+
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+use crate::{arch::without_interrupts, time};
+
+/// Wakers by deadline, then by registration so deadlines can be equal.
+/// Only locked with interrupts off, since the timer wakes them.
+static TIMERS: Mutex<BTreeMap<(u64, u64), Waker>> = Mutex::new(BTreeMap::new());
+
+/// Completes once the monotonic clock reaches its deadline, waking its
+/// event for it even if the event is blocked
+pub struct Deadline {
+    deadline_ns: u64,
+    /// Key of the waker in TIMERS, None while not registered
+    key: Option<(u64, u64)>,
+}
+
+/// Completes once the monotonic clock reaches `deadline_ns`. Unlike
+/// `sleep_until`, it can be raced against futures that block the event.
+pub fn deadline(deadline_ns: u64) -> Deadline {
+    Deadline {
+        deadline_ns,
+        key: None,
+    }
+}
+
+impl Future for Deadline {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if time::monotonic_ns() >= self.deadline_ns {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let deadline_ns = self.deadline_ns;
+        let key = *self
+            .key
+            .get_or_insert_with(|| (deadline_ns, NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+        without_interrupts(|| TIMERS.lock().insert(key, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+impl Deadline {
+    fn unregister(&mut self) {
+        if let Some(key) = self.key.take() {
+            without_interrupts(|| TIMERS.lock().remove(&key));
+        }
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Wakes the events whose deadlines have passed. Called from the timer
+/// interrupt.
+pub fn wake_expired() {
+    let now = time::monotonic_ns();
+    let mut timers = TIMERS.lock();
+    while let Some(entry) = timers.first_entry() {
+        if entry.key().0 > now {
+            break;
+        }
+        entry.remove().wake();
+    }
+}
+
+/// Number of deadlines waiting for the timer
+pub fn pending() -> usize {
+    without_interrupts(|| TIMERS.lock().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::pin::pin;
+    use futures::task::noop_waker_ref;
+
+    #[test_case]
+    fn test_deadline() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let before = pending();
+
+        // A deadline that has passed completes without registering
+        assert!(pin!(deadline(0)).poll(&mut cx).is_ready());
+        assert_eq!(pending(), before);
+
+        // Later ones wait for the timer, once however often polled
+        let mut later = pin!(deadline(u64::MAX));
+        assert!(later.as_mut().poll(&mut cx).is_pending());
+        assert!(later.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(pending(), before + 1);
+        without_interrupts(wake_expired);
+        assert_eq!(pending(), before + 1);
+
+        // and stop waiting when dropped
+        {
+            let mut dropped = pin!(deadline(u64::MAX - 1));
+            assert!(dropped.as_mut().poll(&mut cx).is_pending());
+            assert_eq!(pending(), before + 2);
+        }
+        assert_eq!(pending(), before + 1);
+    }
+}
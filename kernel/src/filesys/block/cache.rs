@@ -0,0 +1,373 @@
+//! Write-back block cache.
+//!
+//! Sits in front of a block device and keeps recently used blocks in memory.
+//! Writes only update the cached copy and mark it dirty. Dirty blocks reach
+//! the device when they are evicted, when the cache is flushed, or when the
+//! writeback daemon finds them too old.
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    filesys::{BlockDevice, FsError},
+    time,
+};
+
+use super::writeback;
+
+/// A cached copy of one block
+struct CachedBlock {
+    data: Box<[u8]>,
+    /// Value of the access counter when the block was last used
+    last_used: u64,
+    /// Monotonic time the block was first written since it was last clean
+    dirty_since: Option<u64>,
+}
+
+/// Cache state shared between a `CachedBlockDevice` and the writeback
+/// daemon
+pub struct BlockCache {
+    device: Box<dyn BlockDevice>,
+    blocks: BTreeMap<u64, CachedBlock>,
+    /// Maximum number of blocks kept in memory
+    capacity: usize,
+    /// Number of dirty blocks
+    dirty: usize,
+    access_counter: u64,
+}
+
+impl BlockCache {
+    fn new(device: Box<dyn BlockDevice>, capacity: usize) -> Self {
+        BlockCache {
+            device,
+            blocks: BTreeMap::new(),
+            capacity: capacity.max(1),
+            dirty: 0,
+            access_counter: 0,
+        }
+    }
+
+    fn touch(&mut self) -> u64 {
+        self.access_counter += 1;
+        self.access_counter
+    }
+
+    fn validate(&self, block_num: u64, buf_len: usize) -> Result<(), FsError> {
+        if block_num >= self.device.total_blocks() || buf_len != self.device.block_size() {
+            return Err(FsError::IOError);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.validate(block_num, buf.len())?;
+        let now = self.touch();
+        if let Some(block) = self.blocks.get_mut(&block_num) {
+            block.last_used = now;
+            buf.copy_from_slice(&block.data);
+            return Ok(());
+        }
+
+        self.device.read_block(block_num, buf)?;
+        self.insert(block_num, buf.into(), None)
+    }
+
+    fn write(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.validate(block_num, buf.len())?;
+        let now = self.touch();
+        if let Some(block) = self.blocks.get_mut(&block_num) {
+            block.last_used = now;
+            block.data.copy_from_slice(buf);
+            if block.dirty_since.is_none() {
+                block.dirty_since = Some(time::monotonic_ns());
+                self.dirty += 1;
+            }
+            return Ok(());
+        }
+
+        self.insert(block_num, buf.into(), Some(time::monotonic_ns()))?;
+        self.dirty += 1;
+        Ok(())
+    }
+
+    /// Reads blocks from the device without caching them. Cached copies are
+    /// never older than the device's, so they replace what was read.
+    fn read_direct(&mut self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let block_size = self.device.block_size();
+        let count = (buf.len() / block_size) as u64;
+        if buf.len() % block_size != 0
+            || block_num.saturating_add(count) > self.device.total_blocks()
+        {
+            return Err(FsError::IOError);
+        }
+        self.device.read_blocks(block_num, buf)?;
+        for (&cached, block) in self.blocks.range(block_num..block_num + count) {
+            let start = (cached - block_num) as usize * block_size;
+            buf[start..start + block_size].copy_from_slice(&block.data);
+        }
+        Ok(())
+    }
+
+    /// Writes blocks to the device without caching them, dropping cached
+    /// copies they make stale
+    fn write_direct(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let block_size = self.device.block_size();
+        let count = (buf.len() / block_size) as u64;
+        if buf.len() % block_size != 0
+            || block_num.saturating_add(count) > self.device.total_blocks()
+        {
+            return Err(FsError::IOError);
+        }
+        self.device.write_blocks(block_num, buf)?;
+        let stale: Vec<u64> = self
+            .blocks
+            .range(block_num..block_num + count)
+            .map(|(&cached, _)| cached)
+            .collect();
+        for cached in stale {
+            if let Some(block) = self.blocks.remove(&cached) {
+                if block.dirty_since.is_some() {
+                    self.dirty -= 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a block, evicting the least recently used block if full
+    fn insert(
+        &mut self,
+        block_num: u64,
+        data: Box<[u8]>,
+        dirty_since: Option<u64>,
+    ) -> Result<(), FsError> {
+        if self.blocks.len() >= self.capacity {
+            self.evict_one()?;
+        }
+        let last_used = self.access_counter;
+        self.blocks.insert(
+            block_num,
+            CachedBlock {
+                data,
+                last_used,
+                dirty_since,
+            },
+        );
+        Ok(())
+    }
+
+    fn evict_one(&mut self) -> Result<(), FsError> {
+        let Some(victim) = self
+            .blocks
+            .iter()
+            .min_by_key(|(_, block)| block.last_used)
+            .map(|(&block_num, _)| block_num)
+        else {
+            return Ok(());
+        };
+        self.write_back(victim)?;
+        self.blocks.remove(&victim);
+        Ok(())
+    }
+
+    /// Writes a block to the device if it is dirty
+    fn write_back(&mut self, block_num: u64) -> Result<(), FsError> {
+        let Some(block) = self.blocks.get_mut(&block_num) else {
+            return Ok(());
+        };
+        if block.dirty_since.is_some() {
+            self.device.write_block(block_num, &block.data)?;
+            block.dirty_since = None;
+            self.dirty -= 1;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty block to the device, in block order
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        let dirty: Vec<u64> = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty_since.is_some())
+            .map(|(&block_num, _)| block_num)
+            .collect();
+        for block_num in dirty {
+            self.write_back(block_num)?;
+        }
+        self.device.flush()
+    }
+
+    /// Returns the percentage of the cache's capacity that is dirty
+    pub fn dirty_percent(&self) -> usize {
+        self.dirty * 100 / self.capacity
+    }
+
+    /// Writes back up to `limit` blocks that are either older than `max_age_ns`
+    /// or, oldest first, needed to bring the dirty percentage down to
+    /// `dirty_ratio`.
+    ///
+    /// # Returns
+    /// The number of blocks written
+    pub fn write_back_batch(
+        &mut self,
+        max_age_ns: u64,
+        dirty_ratio: usize,
+        limit: usize,
+    ) -> Result<usize, FsError> {
+        let now = time::monotonic_ns();
+        let mut by_age: Vec<(u64, u64)> = self
+            .blocks
+            .iter()
+            .filter_map(|(&block_num, block)| block.dirty_since.map(|since| (since, block_num)))
+            .collect();
+        by_age.sort_unstable();
+
+        let over_ratio = self.dirty.saturating_sub(self.capacity * dirty_ratio / 100);
+        let mut targets: Vec<u64> = by_age
+            .iter()
+            .enumerate()
+            .filter(|&(i, &(since, _))| i < over_ratio || now.saturating_sub(since) >= max_age_ns)
+            .map(|(_, &(_, block_num))| block_num)
+            .take(limit)
+            .collect();
+        // Write in block order, which is kinder to the device
+        targets.sort_unstable();
+
+        for &block_num in &targets {
+            self.write_back(block_num)?;
+        }
+        Ok(targets.len())
+    }
+}
+
+/// A block device whose reads and writes go through a write-back cache
+pub struct CachedBlockDevice {
+    cache: Arc<Mutex<BlockCache>>,
+    block_size: usize,
+    total_blocks: u64,
+}
+
+impl CachedBlockDevice {
+    /// Wraps a device in a cache and registers the cache with the writeback
+    /// daemon
+    ///
+    /// # Arguments
+    /// * `device` - The device to cache
+    /// * `capacity` - Maximum number of blocks to keep in memory
+    pub fn new(device: Box<dyn BlockDevice>, capacity: usize) -> Self {
+        let block_size = device.block_size();
+        let total_blocks = device.total_blocks();
+        let cache = Arc::new(Mutex::new(BlockCache::new(device, capacity)));
+        writeback::register(&cache);
+        CachedBlockDevice {
+            cache,
+            block_size,
+            total_blocks,
+        }
+    }
+
+    /// Returns the shared cache state
+    pub fn cache(&self) -> &Arc<Mutex<BlockCache>> {
+        &self.cache
+    }
+}
+
+impl BlockDevice for CachedBlockDevice {
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.cache.lock().read(block_num, buf)
+    }
+
+    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.cache.lock().write(block_num, buf)
+    }
+
+    fn read_blocks_direct(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.cache.lock().read_direct(block_num, buf)
+    }
+
+    fn write_blocks_direct(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.cache.lock().write_direct(block_num, buf)
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.total_blocks
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.cache.lock().flush()
+    }
+}
+
+impl Drop for CachedBlockDevice {
+    fn drop(&mut self) {
+        let _ = self.cache.lock().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesys::block::memory::MemoryBlockDevice;
+    use alloc::vec;
+
+    #[test_case]
+    fn test_cache_writes_back() {
+        let mut device = CachedBlockDevice::new(Box::new(MemoryBlockDevice::new(16, 512)), 4);
+        let data = vec![0xAB; 512];
+        device.write_block(3, &data).unwrap();
+        assert_eq!(device.cache().lock().dirty_percent(), 25);
+
+        let mut read = vec![0; 512];
+        device.read_block(3, &mut read).unwrap();
+        assert_eq!(read, data);
+
+        // Nothing is old enough and the ratio is not exceeded
+        assert_eq!(
+            device
+                .cache()
+                .lock()
+                .write_back_batch(u64::MAX, 100, 8)
+                .unwrap(),
+            0
+        );
+        // Everything is older than 0ns
+        assert_eq!(
+            device.cache().lock().write_back_batch(0, 100, 8).unwrap(),
+            1
+        );
+        assert_eq!(device.cache().lock().dirty_percent(), 0);
+
+        // Filling the cache evicts and writes back the oldest block
+        for block in 4..10 {
+            device.write_block(block, &data).unwrap();
+        }
+        device.flush().unwrap();
+        assert_eq!(device.cache().lock().dirty_percent(), 0);
+    }
+
+    #[test_case]
+    fn test_direct_transfers() {
+        let mut device = CachedBlockDevice::new(Box::new(MemoryBlockDevice::new(16, 512)), 4);
+        let cached = vec![0x11; 512];
+        device.write_block(2, &cached).unwrap();
+
+        // Direct reads see dirty cached blocks but do not fill the cache
+        let mut read = vec![0; 512 * 3];
+        device.read_blocks_direct(1, &mut read).unwrap();
+        assert_eq!(&read[512..1024], &cached[..]);
+        assert!(read[..512].iter().chain(&read[1024..]).all(|&b| b == 0));
+        assert_eq!(device.cache().lock().blocks.len(), 1);
+
+        // Direct writes replace what was cached
+        let direct = vec![0x22; 512 * 2];
+        device.write_blocks_direct(2, &direct).unwrap();
+        assert_eq!(device.cache().lock().dirty_percent(), 0);
+        let mut block = vec![0; 512];
+        device.read_block(2, &mut block).unwrap();
+        assert_eq!(block, &direct[..512]);
+    }
+}
@@ -1,7 +1,13 @@
 //! In-memory block device implementation
 
-use crate::filesys::{BlockDevice, FsError};
-use alloc::{vec, vec::Vec};
+use crate::{
+    filesys::{
+        block::stats::{DiskStats, IoDirection},
+        BlockDevice, FsError,
+    },
+    processes::rusage::account_block_io,
+};
+use alloc::{sync::Arc, vec, vec::Vec};
 use core::result::Result;
 
 /// Block device that stores data in memory
@@ -11,13 +17,24 @@ pub struct MemoryBlockDevice {
 
     /// Size of each block in bytes
     block_size: usize,
+
+    stats: Arc<DiskStats>,
 }
 
 impl MemoryBlockDevice {
     /// Creates a new memory block device with given size
     pub fn new(total_blocks: u64, block_size: usize) -> Self {
         let blocks = (0..total_blocks).map(|_| vec![0; block_size]).collect();
-        Self { blocks, block_size }
+        Self {
+            blocks,
+            block_size,
+            stats: DiskStats::register("mem"),
+        }
+    }
+
+    /// Returns the device's I/O statistics
+    pub fn stats(&self) -> &Arc<DiskStats> {
+        &self.stats
     }
 
     /// Validates block number is within bounds
@@ -40,17 +57,26 @@ impl MemoryBlockDevice {
 impl BlockDevice for MemoryBlockDevice {
     /// Reads block into buffer
     fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
-        self.validate_block(block_num)?;
-        self.validate_buffer(buf)?;
-        buf.copy_from_slice(&self.blocks[block_num as usize]);
+        self.stats.record(IoDirection::Read, 1, || {
+            self.validate_block(block_num)?;
+            self.validate_buffer(buf)?;
+            buf.copy_from_slice(&self.blocks[block_num as usize]);
+            Ok(())
+        })?;
+        account_block_io(1, 0);
         Ok(())
     }
 
     /// Writes buffer to block
     fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
-        self.validate_block(block_num)?;
-        self.validate_buffer(buf)?;
-        self.blocks[block_num as usize].copy_from_slice(buf);
+        let stats = self.stats.clone();
+        stats.record(IoDirection::Write, 1, || {
+            self.validate_block(block_num)?;
+            self.validate_buffer(buf)?;
+            self.blocks[block_num as usize].copy_from_slice(buf);
+            Ok(())
+        })?;
+        account_block_io(0, 1);
         Ok(())
     }
 
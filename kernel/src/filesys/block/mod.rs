@@ -1 +1,6 @@
+pub use taos_fs::partition;
+
+pub mod cache;
 pub mod memory;
+pub mod stats;
+pub mod writeback;
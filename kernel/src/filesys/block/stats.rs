@@ -0,0 +1,309 @@
+//! Per-disk I/O statistics.
+//!
+//! Every block device driver registers a `DiskStats` when it sets up a disk
+//! and runs each transfer through `DiskStats::record`. Besides operation
+//! and block counts, the stats keep how long transfers took, as totals and
+//! as a histogram, and how many were in flight over time: `busy_ns` is the
+//! time at least one was, and `weighted_ns` adds up the number in flight
+//! over time, so dividing it by the time since registration gives the
+//! average queue depth.
+//!
+//! `/proc/diskstats` has a line per disk holding, in order: the name, reads,
+//! blocks read, ns spent reading, writes, blocks written, ns spent writing,
+//! failed transfers, transfers in flight, `busy_ns`, `weighted_ns`, and the
+//! `LATENCY_BUCKETS` latency histogram counts. `/proc/iostat` holds the
+//! same numbers for people, rendered by `iostat`.
+
+use alloc::{
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::time;
+
+/// Buckets of the latency histogram. Bucket 0 counts transfers under 1us,
+/// bucket `i` those from 2^(i-1) up to 2^i us, and the last one everything
+/// slower.
+pub const LATENCY_BUCKETS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoDirection {
+    Read,
+    Write,
+}
+
+/// Transfers in flight and how long they have been
+#[derive(Debug, Default)]
+struct Depth {
+    in_flight: u64,
+    last_change_ns: u64,
+    busy_ns: u64,
+    weighted_ns: u64,
+}
+
+impl Depth {
+    /// Accounts for the time since the in flight count last changed
+    fn advance(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.last_change_ns);
+        if self.in_flight > 0 {
+            self.busy_ns += elapsed;
+        }
+        self.weighted_ns += self.in_flight * elapsed;
+        self.last_change_ns = now;
+    }
+}
+
+/// I/O statistics of one disk
+#[derive(Debug)]
+pub struct DiskStats {
+    name: String,
+    registered_ns: u64,
+    /// Indexed by `IoDirection`
+    ops: [AtomicU64; 2],
+    blocks: [AtomicU64; 2],
+    ticks_ns: [AtomicU64; 2],
+    errors: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS],
+    depth: Mutex<Depth>,
+}
+
+/// The statistics of a disk at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskSnapshot {
+    pub name: String,
+    pub reads: u64,
+    pub read_blocks: u64,
+    pub read_ns: u64,
+    pub writes: u64,
+    pub write_blocks: u64,
+    pub write_ns: u64,
+    pub errors: u64,
+    pub in_flight: u64,
+    pub busy_ns: u64,
+    pub weighted_ns: u64,
+    /// Time the disk has been registered for
+    pub uptime_ns: u64,
+    pub latency: [u64; LATENCY_BUCKETS],
+}
+
+impl DiskSnapshot {
+    /// Average number of transfers in flight since the disk was registered,
+    /// in hundredths
+    pub fn average_depth_hundredths(&self) -> u64 {
+        (self.weighted_ns as u128 * 100)
+            .checked_div(self.uptime_ns as u128)
+            .unwrap_or(0) as u64
+    }
+
+    /// Average time a transfer took, in microseconds
+    pub fn average_latency_us(&self) -> u64 {
+        ((self.read_ns + self.write_ns) / 1000)
+            .checked_div(self.reads + self.writes)
+            .unwrap_or(0)
+    }
+}
+
+/// Every registered disk. Dropped disks are pruned when listed.
+static DISKS: Mutex<Vec<Weak<DiskStats>>> = Mutex::new(Vec::new());
+
+impl DiskStats {
+    /// Creates the statistics of a new disk and lists them. The disk is
+    /// named after `prefix` and the lowest number no disk in use has, like
+    /// `nvme0` and `nvme1`.
+    pub fn register(prefix: &str) -> Arc<DiskStats> {
+        let mut disks = DISKS.lock();
+        disks.retain(|disk| disk.strong_count() > 0);
+        let name = (0..)
+            .map(|index| format!("{}{}", prefix, index))
+            .find(|name| {
+                disks
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .all(|disk| disk.name != *name)
+            })
+            .expect("Some index is free");
+        let now = time::monotonic_ns();
+        let stats = Arc::new(DiskStats {
+            name,
+            registered_ns: now,
+            ops: Default::default(),
+            blocks: Default::default(),
+            ticks_ns: Default::default(),
+            errors: AtomicU64::new(0),
+            latency: Default::default(),
+            depth: Mutex::new(Depth {
+                last_change_ns: now,
+                ..Depth::default()
+            }),
+        });
+        disks.push(Arc::downgrade(&stats));
+        stats
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs a transfer of `blocks` blocks, accounting for it
+    ///
+    /// # Returns
+    /// Whatever the transfer returned
+    pub fn record<T, E>(
+        &self,
+        direction: IoDirection,
+        blocks: u64,
+        transfer: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let started = time::monotonic_ns();
+        {
+            let mut depth = self.depth.lock();
+            depth.advance(started);
+            depth.in_flight += 1;
+        }
+
+        let result = transfer();
+
+        let finished = time::monotonic_ns();
+        {
+            let mut depth = self.depth.lock();
+            depth.advance(finished);
+            depth.in_flight -= 1;
+        }
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            return result;
+        }
+        let latency_ns = finished.saturating_sub(started);
+        let index = direction as usize;
+        self.ops[index].fetch_add(1, Ordering::Relaxed);
+        self.blocks[index].fetch_add(blocks, Ordering::Relaxed);
+        self.ticks_ns[index].fetch_add(latency_ns, Ordering::Relaxed);
+        let bucket = (u64::BITS - (latency_ns / 1000).leading_zeros()) as usize;
+        self.latency[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    pub fn snapshot(&self) -> DiskSnapshot {
+        let now = time::monotonic_ns();
+        let (in_flight, busy_ns, weighted_ns) = {
+            let mut depth = self.depth.lock();
+            depth.advance(now);
+            (depth.in_flight, depth.busy_ns, depth.weighted_ns)
+        };
+        let read = IoDirection::Read as usize;
+        let write = IoDirection::Write as usize;
+        DiskSnapshot {
+            name: self.name.clone(),
+            reads: self.ops[read].load(Ordering::Relaxed),
+            read_blocks: self.blocks[read].load(Ordering::Relaxed),
+            read_ns: self.ticks_ns[read].load(Ordering::Relaxed),
+            writes: self.ops[write].load(Ordering::Relaxed),
+            write_blocks: self.blocks[write].load(Ordering::Relaxed),
+            write_ns: self.ticks_ns[write].load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            in_flight,
+            busy_ns,
+            weighted_ns,
+            uptime_ns: now.saturating_sub(self.registered_ns),
+            latency: core::array::from_fn(|i| self.latency[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Returns the statistics of every disk still in use
+pub fn snapshot_all() -> Vec<DiskSnapshot> {
+    let disks: Vec<Arc<DiskStats>> = {
+        let mut disks = DISKS.lock();
+        disks.retain(|disk| disk.strong_count() > 0);
+        disks.iter().filter_map(Weak::upgrade).collect()
+    };
+    disks.iter().map(|disk| disk.snapshot()).collect()
+}
+
+/// Renders the I/O statistics of every disk for people, like iostat
+pub fn iostat() -> String {
+    let mut report = format!(
+        "{:<10} {:>8} {:>10} {:>8} {:>10} {:>6} {:>8} {:>10} {:>6}\n",
+        "Device",
+        "Reads",
+        "Blk read",
+        "Writes",
+        "Blk wrtn",
+        "Errors",
+        "Avg qu",
+        "Await us",
+        "Util%"
+    );
+    let disks = snapshot_all();
+    for disk in &disks {
+        let depth = disk.average_depth_hundredths();
+        report += &format!(
+            "{:<10} {:>8} {:>10} {:>8} {:>10} {:>6} {:>5}.{:02} {:>10} {:>5}%\n",
+            disk.name,
+            disk.reads,
+            disk.read_blocks,
+            disk.writes,
+            disk.write_blocks,
+            disk.errors,
+            depth / 100,
+            depth % 100,
+            disk.average_latency_us(),
+            (disk.busy_ns * 100)
+                .checked_div(disk.uptime_ns)
+                .unwrap_or(0)
+        );
+    }
+    for disk in &disks {
+        report += &format!("{} latency histogram (us):\n", disk.name);
+        for (bucket, &count) in disk.latency.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let lower = if bucket == 0 { 0 } else { 1u64 << (bucket - 1) };
+            report += &format!("  {:>6} .. : {}\n", lower, count);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::filesys::{block::memory::MemoryBlockDevice, BlockDevice, FsError};
+
+    #[test_case]
+    fn test_disk_stats() {
+        let mut device = MemoryBlockDevice::new(8, 512);
+        let name = String::from(device.stats().name());
+        let data = vec![0x5A; 512];
+        device.write_block(1, &data).unwrap();
+        let mut read = vec![0; 512];
+        device.read_block(1, &mut read).unwrap();
+        device.read_block(2, &mut read).unwrap();
+        assert!(matches!(
+            device.read_block(8, &mut read),
+            Err(FsError::IOError)
+        ));
+
+        let disk = snapshot_all()
+            .into_iter()
+            .find(|disk| disk.name == name)
+            .expect("Disk is listed");
+        assert_eq!((disk.reads, disk.read_blocks), (2, 2));
+        assert_eq!((disk.writes, disk.write_blocks), (1, 1));
+        assert_eq!(disk.errors, 1);
+        assert_eq!(disk.in_flight, 0);
+        assert_eq!(disk.latency.iter().sum::<u64>(), 3);
+        assert!(disk.busy_ns <= disk.uptime_ns);
+
+        // Dropped disks are no longer listed
+        drop(device);
+        assert!(snapshot_all().iter().all(|disk| disk.name != name));
+    }
+}
@@ -0,0 +1,118 @@
+//! Writeback daemon.
+//!
+//! An idle priority task that periodically writes back dirty blocks of every
+//! block cache. A block is written once it has been dirty for longer than
+//! the configured age, and the oldest blocks are written early whenever the
+//! dirty share of a cache exceeds the configured ratio. This bounds how much
+//! is lost on a crash without making every write synchronous.
+//!
+//! fsync and unmount flush a cache directly. They take the same lock as the
+//! daemon, so a flush never interleaves with a writeback batch.
+
+use alloc::{
+    boxed::Box,
+    format,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::{
+    events::{idle_yield, sleep_until},
+    serial_println,
+    shutdown::{self, StopStage, DEFAULT_STOP_TIMEOUT_NS},
+    time::{self, NANOS_PER_SEC},
+};
+
+use super::cache::BlockCache;
+
+/// Time between writeback passes
+const WRITEBACK_INTERVAL_NS: u64 = NANOS_PER_SEC / 2;
+/// Most blocks written while holding a cache's lock
+const WRITEBACK_BATCH: usize = 16;
+
+/// Blocks dirty for longer than this are written back
+static MAX_DIRTY_AGE_NS: AtomicU64 = AtomicU64::new(5 * NANOS_PER_SEC);
+/// Percentage of a cache that may be dirty before blocks are written early
+static DIRTY_RATIO: AtomicUsize = AtomicUsize::new(20);
+
+/// Caches the daemon looks after. Dropped caches are pruned on each pass.
+static CACHES: Mutex<Vec<Weak<Mutex<BlockCache>>>> = Mutex::new(Vec::new());
+
+/// Adds a cache to the daemon's list
+pub fn register(cache: &Arc<Mutex<BlockCache>>) {
+    CACHES.lock().push(Arc::downgrade(cache));
+}
+
+/// Sets how long a block may stay dirty before the daemon writes it back
+pub fn set_max_dirty_age_ms(ms: u64) {
+    MAX_DIRTY_AGE_NS.store(ms.saturating_mul(1_000_000), Ordering::Relaxed);
+}
+
+/// Sets the percentage of a cache that may be dirty before blocks are
+/// written back early
+pub fn set_dirty_ratio(percent: usize) {
+    DIRTY_RATIO.store(percent.min(100), Ordering::Relaxed);
+}
+
+/// Writes back due blocks of one cache, yielding between batches
+async fn write_back_cache(cache: &Mutex<BlockCache>) {
+    loop {
+        let written = cache.lock().write_back_batch(
+            MAX_DIRTY_AGE_NS.load(Ordering::Relaxed),
+            DIRTY_RATIO.load(Ordering::Relaxed),
+            WRITEBACK_BATCH,
+        );
+        match written {
+            Ok(count) if count == WRITEBACK_BATCH => idle_yield().await,
+            Ok(_) => return,
+            Err(e) => {
+                // The blocks stay dirty and are retried on the next pass
+                serial_println!("Writeback failed: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Writes back every dirty block of every cache on shutdown
+pub fn register_stop_hooks() {
+    shutdown::register_stop_hook(
+        "block caches",
+        StopStage::BlockDevices,
+        DEFAULT_STOP_TIMEOUT_NS,
+        || {
+            Box::pin(async {
+                let caches: Vec<_> = shutdown::lock(&CACHES)
+                    .await
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .collect();
+                for cache in caches {
+                    shutdown::lock(&cache)
+                        .await
+                        .flush()
+                        .map_err(|e| format!("{:?}", e))?;
+                }
+                Ok(())
+            })
+        },
+    );
+}
+
+/// Runs forever, should be scheduled with `schedule_idle`
+pub async fn writeback_daemon() {
+    loop {
+        sleep_until(time::monotonic_ns() + WRITEBACK_INTERVAL_NS).await;
+
+        let caches: Vec<Arc<Mutex<BlockCache>>> = {
+            let mut caches = CACHES.lock();
+            caches.retain(|cache| cache.strong_count() > 0);
+            caches.iter().filter_map(Weak::upgrade).collect()
+        };
+        for cache in caches {
+            write_back_cache(&cache).await;
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! Device files.
+//!
+//! Nodes at the root stand for devices drivers have bound, and only exist
+//! while such a device does:
+//! - `audio` takes signed 16 bit little endian stereo PCM and plays it on
+//!   the first AC'97 controller. A write queues as many whole samples as
+//!   the DMA ring has room for and returns their size, so writers keep
+//!   writing the rest, and fails with `Busy` while the ring is full.
+//!
+//! The `devices` directory holds a file for every device the device
+//! manager tracks, named after its handle, such as `dev3`. The file is a
+//! list of `name value` lines rendered when it is opened, like the `stat`
+//! files of procfs, and goes away when the device is removed.
+//!
+//! Nothing is stored, so nodes cannot be created, removed or renamed.
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::devices::{
+    ac97::Ac97,
+    manager::{find_device_data, DeviceHandle, DeviceNode, DeviceState, DEVICE_MANAGER},
+};
+
+use super::{
+    DirEntry, FileMetadata, FilePermissions, FileSystem, FileTimes, FsError, SeekFrom, StatFs,
+};
+
+/// What a path inside devfs refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Node {
+    Root,
+    Audio,
+    /// The directory of files named after device handles
+    Devices,
+    Device(DeviceHandle),
+}
+
+/// Nodes at the root
+const NODES: [(&str, Node); 2] = [("audio", Node::Audio), ("devices", Node::Devices)];
+
+impl Node {
+    /// Whether the device behind the node is present
+    fn present(self) -> bool {
+        match self {
+            Node::Root | Node::Devices => true,
+            Node::Audio => find_device_data::<Ac97>().is_some(),
+            Node::Device(handle) => DEVICE_MANAGER
+                .lock()
+                .get(handle)
+                .is_some_and(|node| node.state != DeviceState::Removed),
+        }
+    }
+
+    fn is_dir(self) -> bool {
+        matches!(self, Node::Root | Node::Devices)
+    }
+}
+
+/// A node opened through devfs. Device files keep what they held when
+/// opened.
+struct OpenFile {
+    node: Node,
+    contents: Vec<u8>,
+    position: u64,
+}
+
+pub struct DevFs {
+    open_files: BTreeMap<usize, OpenFile>,
+    next_fd: usize,
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DevFs {
+    pub const fn new() -> Self {
+        DevFs {
+            open_files: BTreeMap::new(),
+            next_fd: 0,
+        }
+    }
+
+    fn resolve(path: &str) -> Result<Node, FsError> {
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        let node = match parts[..] {
+            [] => Node::Root,
+            ["devices", handle] => Node::Device(handle.parse().map_err(|_| FsError::NotFound)?),
+            [name] => NODES
+                .iter()
+                .find(|(node_name, _)| *node_name == name)
+                .map(|(_, node)| *node)
+                .ok_or(FsError::NotFound)?,
+            _ => return Err(FsError::NotFound),
+        };
+        if !node.present() {
+            return Err(FsError::NotFound);
+        }
+        Ok(node)
+    }
+
+    fn open_file_mut(&mut self, fd: usize) -> &mut OpenFile {
+        self.open_files
+            .get_mut(&fd)
+            .expect("Invalid file descriptor")
+    }
+}
+
+/// Renders the file of a device
+fn render_device(node: &DeviceNode) -> String {
+    let children: Vec<String> = node
+        .children
+        .iter()
+        .map(|child| child.to_string())
+        .collect();
+    let lines = [
+        ("name", node.name.clone()),
+        ("class", format!("{:?}", node.class)),
+        ("state", format!("{:?}", node.state)),
+        ("driver", node.driver.unwrap_or("none").into()),
+        (
+            "parent",
+            node.parent
+                .map_or("none".into(), |parent| parent.to_string()),
+        ),
+        ("children", children.join(" ")),
+    ];
+    lines
+        .iter()
+        .map(|(name, value)| format!("{} {}\n", name, value))
+        .collect()
+}
+
+/// Queues PCM on the first AC'97 controller
+fn write_audio(buf: &[u8]) -> Result<usize, FsError> {
+    let ac97 = find_device_data::<Ac97>().ok_or(FsError::NotFound)?;
+    let samples: Vec<i16> = buf
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect();
+    if samples.is_empty() {
+        return Ok(0);
+    }
+    let queued = ac97.lock().queue_samples(&samples);
+    match queued {
+        0 => Err(FsError::Busy),
+        queued => Ok(queued * 2),
+    }
+}
+
+fn node_metadata(node: Node) -> FileMetadata {
+    let is_dir = node.is_dir();
+    FileMetadata {
+        size: 0,
+        is_dir,
+        created: 0,
+        modified: 0,
+        accessed: 0,
+        permissions: FilePermissions {
+            readable: node != Node::Audio,
+            writable: node == Node::Audio,
+            executable: is_dir,
+        },
+        mode: None,
+        uid: 0,
+        gid: 0,
+    }
+}
+
+impl FileSystem for DevFs {
+    fn create_file(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn create_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn remove_file(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn remove_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn open_file(&mut self, path: &str) -> Result<usize, FsError> {
+        let node = Self::resolve(path)?;
+        let contents = match node {
+            Node::Root | Node::Devices => return Err(FsError::NotSupported),
+            Node::Audio => Vec::new(),
+            Node::Device(handle) => DEVICE_MANAGER
+                .lock()
+                .get(handle)
+                .map(render_device)
+                .ok_or(FsError::NotFound)?
+                .into_bytes(),
+        };
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.open_files.insert(
+            fd,
+            OpenFile {
+                node,
+                contents,
+                position: 0,
+            },
+        );
+        Ok(fd)
+    }
+
+    fn close_file(&mut self, fd: usize) {
+        self.open_files
+            .remove(&fd)
+            .expect("Cannot close an invalid file descriptor.");
+    }
+
+    fn write_file(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        match self.open_file_mut(fd).node {
+            Node::Audio => write_audio(buf),
+            Node::Root | Node::Devices | Node::Device(_) => Err(FsError::NotSupported),
+        }
+    }
+
+    fn seek_file(&mut self, fd: usize, pos: SeekFrom) -> Result<u64, FsError> {
+        let file = self.open_file_mut(fd);
+        if file.node == Node::Audio {
+            return Err(FsError::NotSupported);
+        }
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (file.contents.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => file.position.checked_add_signed(offset),
+        }
+        .ok_or(FsError::InvalidOffset)?;
+        file.position = new_pos;
+        Ok(new_pos)
+    }
+
+    fn read_file(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let file = self.open_file_mut(fd);
+        if file.node == Node::Audio {
+            return Err(FsError::NotSupported);
+        }
+        let start = (file.position as usize).min(file.contents.len());
+        let read = buf.len().min(file.contents.len() - start);
+        buf[..read].copy_from_slice(&file.contents[start..start + read]);
+        file.position += read as u64;
+        Ok(read)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        match Self::resolve(path)? {
+            Node::Root => Ok(NODES
+                .iter()
+                .filter(|(_, node)| node.present())
+                .map(|(name, node)| DirEntry {
+                    name: name.to_string(),
+                    metadata: node_metadata(*node),
+                })
+                .collect()),
+            Node::Devices => Ok(DEVICE_MANAGER
+                .lock()
+                .devices()
+                .map(|device| DirEntry {
+                    name: device.handle.to_string(),
+                    metadata: node_metadata(Node::Device(device.handle)),
+                })
+                .collect()),
+            Node::Audio | Node::Device(_) => Err(FsError::NotSupported),
+        }
+    }
+
+    fn metadata(&self, path: &str) -> Result<FileMetadata, FsError> {
+        Ok(node_metadata(Self::resolve(path)?))
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "dev"
+    }
+
+    fn set_times(&mut self, _path: &str, _times: FileTimes) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn set_permissions(
+        &mut self,
+        _path: &str,
+        _permissions: FilePermissions,
+    ) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        // Takes up no space
+        Ok(StatFs::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn test_audio_node() {
+        let mut fs = DevFs::new();
+        assert!(fs.read_dir("/").unwrap().iter().any(|e| e.name == "audio"));
+        assert!(!fs.metadata("/audio").unwrap().is_dir);
+        assert!(matches!(fs.open_file("/speaker"), Err(FsError::NotFound)));
+        assert!(matches!(fs.open_file("/audio/x"), Err(FsError::NotFound)));
+        assert!(matches!(fs.create_file("/x"), Err(FsError::NotSupported)));
+
+        let ac97 = find_device_data::<Ac97>().unwrap();
+        ac97.lock().stop().unwrap();
+        let fd = fs.open_file("/audio").unwrap();
+        // Only whole samples are taken, and as many as the ring holds
+        assert_eq!(fs.write_file(fd, &[0]).unwrap(), 0);
+        assert_eq!(fs.write_file(fd, &[0; 5]).unwrap(), 4);
+        let silence = vec![0; 64 * 4096];
+        let mut written = 4;
+        while let Ok(count) = fs.write_file(fd, &silence) {
+            assert!(count > 0 && count % 2 == 0);
+            written += count;
+        }
+        assert!(written < silence.len());
+        assert!(matches!(
+            fs.read_file(fd, &mut [0; 2]),
+            Err(FsError::NotSupported)
+        ));
+        fs.close_file(fd);
+        ac97.lock().stop().unwrap();
+    }
+
+    #[test_case]
+    fn test_device_nodes() {
+        let mut fs = DevFs::new();
+        assert!(fs.metadata("/devices").unwrap().is_dir);
+        let handles: Vec<String> = DEVICE_MANAGER
+            .lock()
+            .devices()
+            .map(|node| node.handle.to_string())
+            .collect();
+        let entries: Vec<String> = fs
+            .read_dir("/devices")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(entries, handles);
+
+        // The serial console is registered first
+        let fd = fs.open_file("/devices/dev0").unwrap();
+        let mut buf = [0u8; 512];
+        let read = fs.read_file(fd, &mut buf).unwrap();
+        let contents = core::str::from_utf8(&buf[..read]).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "name serial console",
+                "class Serial",
+                "state Active",
+                "driver uart 16550"
+            ]
+        );
+        assert_eq!(fs.read_file(fd, &mut buf).unwrap(), 0);
+        assert_eq!(fs.seek_file(fd, SeekFrom::Start(5)).unwrap(), 5);
+        assert_eq!(fs.read_file(fd, &mut buf[..6]).unwrap(), 6);
+        assert_eq!(&buf[..6], b"serial");
+        assert!(matches!(
+            fs.write_file(fd, b"x"),
+            Err(FsError::NotSupported)
+        ));
+        fs.close_file(fd);
+
+        // Only the names handles are displayed with, for devices that exist
+        for path in [
+            "/devices/dev00",
+            "/devices/serial",
+            "/devices/dev4294967295",
+        ] {
+            assert!(matches!(fs.open_file(path), Err(FsError::NotFound)));
+        }
+        assert!(matches!(
+            fs.open_file("/devices"),
+            Err(FsError::NotSupported)
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+//! ext2 filesystem constants
+
+/// Magic number identifying an ext2 superblock
+pub const EXT2_MAGIC: u16 = 0xEF53;
+
+/// Byte offset of the superblock from the start of the device, whatever
+/// the block size
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+
+/// Size of the superblock in bytes
+pub const SUPERBLOCK_SIZE: usize = 1024;
+
+/// Block size `format` creates filesystems with
+pub const FORMAT_BLOCK_SIZE: usize = 1024;
+
+/// Bytes of disk `format` sets aside one inode for
+pub const BYTES_PER_INODE: u64 = 4096;
+
+/// Inode size of revision 0 filesystems, and the size `format` uses
+pub const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Fewest data blocks a block group may have left over after its metadata
+pub const MIN_GROUP_DATA_BLOCKS: u32 = 8;
+
+/// Inode number of the root directory
+pub const ROOT_INODE: u32 = 2;
+
+/// First inode number not reserved by ext2
+pub const FIRST_INODE: u32 = 11;
+
+/// Number of direct block pointers in an inode
+pub const DIRECT_BLOCKS: usize = 12;
+
+/// Index of the singly indirect block pointer in an inode
+pub const SINGLE_INDIRECT: usize = 12;
+
+/// Index of the doubly indirect block pointer in an inode
+pub const DOUBLE_INDIRECT: usize = 13;
+
+/// Revision with variable inode sizes and feature flags
+pub const DYNAMIC_REV: u32 = 1;
+
+/// Incompatible feature: directory entries record the file type
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+
+/// Read-only compatible features this driver can write without breaking:
+/// sparse superblock backups and large files
+pub const SUPPORTED_RO_COMPAT: u32 = 0x0001 | 0x0002;
+
+/// Filesystem state: cleanly unmounted
+pub const STATE_VALID: u16 = 1;
+
+/// Error behaviour: continue as if nothing happened
+pub const ERRORS_CONTINUE: u16 = 1;
+
+/// Mask of the file type bits of a mode
+pub const S_IFMT: u16 = 0o170000;
+
+/// File type: regular file
+pub const S_IFREG: u16 = 0o100000;
+
+/// File type: directory
+pub const S_IFDIR: u16 = 0o040000;
+
+/// File type: symbolic link
+pub const S_IFLNK: u16 = 0o120000;
+
+/// Mask of the permission, setuid, setgid and sticky bits of a mode
+pub const PERMISSION_MASK: u16 = 0o7777;
+
+/// Permissions of newly created files
+pub const DEFAULT_FILE_MODE: u16 = 0o644;
+
+/// Permissions of newly created directories
+pub const DEFAULT_DIR_MODE: u16 = 0o755;
+
+/// Permissions of symbolic links, which are never checked
+pub const SYMLINK_MODE: u16 = 0o777;
+
+/// Read bits for owner, group and others
+pub const READ_BITS: u16 = 0o444;
+
+/// Write bits for owner, group and others
+pub const WRITE_BITS: u16 = 0o222;
+
+/// Write bit for the owner
+pub const OWNER_WRITE_BIT: u16 = 0o200;
+
+/// Execute bits for owner, group and others
+pub const EXECUTE_BITS: u16 = 0o111;
+
+/// Directory entry file type: regular file
+pub const FT_REG_FILE: u8 = 1;
+
+/// Directory entry file type: directory
+pub const FT_DIR: u8 = 2;
+
+/// Directory entry file type: symbolic link
+pub const FT_SYMLINK: u8 = 7;
+
+/// Longest name a directory entry can hold
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Longest symbolic link target stored in the inode's block pointers
+/// rather than in a data block
+pub const FAST_SYMLINK_MAX: usize = 60;
@@ -0,0 +1,76 @@
+//! ext2 directory entry structure
+//!
+//! A directory's data blocks hold a chain of variable length entries. Each
+//! entry's record length reaches to the next entry, and the last entry of
+//! a block reaches to the end of the block, so removing an entry just adds
+//! its length to the one before it.
+
+use super::{constants::*, FsError};
+use crate::filesys::layout::OnDisk;
+use taos_fs::on_disk;
+
+/// Fixed part of a directory entry, followed by the name (8 bytes)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DirEntryHeader {
+    /// Inode the entry names, 0 for an unused entry
+    pub inode: u32,
+    /// Bytes from the start of this entry to the next
+    pub rec_len: u16,
+    pub name_len: u8,
+    pub file_type: u8,
+}
+
+on_disk!(DirEntryHeader, 8 bytes {
+    0 => inode: u32,
+    4 => rec_len: u16,
+    6 => name_len: u8,
+    7 => file_type: u8,
+});
+
+/// Size of the fixed part of a directory entry
+pub const HEADER_SIZE: usize = core::mem::size_of::<DirEntryHeader>();
+
+/// Bytes an entry with a name of `name_len` bytes needs, as entries are
+/// 4 byte aligned
+pub fn entry_size(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len).next_multiple_of(4)
+}
+
+/// Reads the entry at `offset` of a directory block
+///
+/// # Returns
+/// The entry's header and name. Fails if the entry does not fit in the
+/// block, which means the directory is corrupt.
+pub fn read_entry(block: &[u8], offset: usize) -> Result<(DirEntryHeader, &[u8]), FsError> {
+    if offset + HEADER_SIZE > block.len() {
+        return Err(FsError::IOError);
+    }
+    let header = DirEntryHeader::from_bytes(&block[offset..]);
+    let rec_len = header.rec_len as usize;
+    let name_end = offset + HEADER_SIZE + header.name_len as usize;
+    if rec_len < HEADER_SIZE || offset + rec_len > block.len() || name_end > offset + rec_len {
+        return Err(FsError::IOError);
+    }
+    Ok((header, &block[offset + HEADER_SIZE..name_end]))
+}
+
+/// Writes an entry at `offset` of a directory block
+pub fn write_entry(
+    block: &mut [u8],
+    offset: usize,
+    inode: u32,
+    rec_len: usize,
+    name: &str,
+    file_type: u8,
+) {
+    debug_assert!(name.len() <= MAX_NAME_LENGTH);
+    let header = DirEntryHeader {
+        inode,
+        rec_len: rec_len as u16,
+        name_len: name.len() as u8,
+        file_type,
+    };
+    header.to_bytes(&mut block[offset..]);
+    block[offset + HEADER_SIZE..offset + HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+}
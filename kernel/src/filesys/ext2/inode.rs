@@ -0,0 +1,140 @@
+//! ext2 inode structure
+
+use super::constants::*;
+use crate::filesys::layout::{put_u16_le_at, u16_le_at};
+use taos_fs::on_disk;
+
+/// On-disk inode, the first 128 bytes of each inode table slot
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Inode {
+    /// File type and permission bits
+    pub mode: u16,
+    /// Low 16 bits of the owner user ID
+    pub uid: u16,
+    /// Low 32 bits of the size in bytes
+    pub size: u32,
+    /// Last access time
+    pub atime: u32,
+    /// Last inode change time
+    pub ctime: u32,
+    /// Last modification time
+    pub mtime: u32,
+    /// Deletion time, 0 while the inode is in use
+    pub dtime: u32,
+    /// Low 16 bits of the owner group ID
+    pub gid: u16,
+    pub links_count: u16,
+    /// Number of 512 byte sectors allocated, including indirect blocks
+    pub blocks: u32,
+    pub flags: u32,
+    pub osd1: u32,
+    /// Direct, singly, doubly and triply indirect block pointers. Fast
+    /// symbolic links store their target here instead.
+    pub block: [u32; 15],
+    pub generation: u32,
+    pub file_acl: u32,
+    /// High 32 bits of the size of regular files
+    pub size_high: u32,
+    pub fragment_addr: u32,
+    /// Fragment fields, then the high 16 bits of the uid and gid
+    pub osd2: [u8; 12],
+}
+
+on_disk!(Inode, 128 bytes {
+    0 => mode: u16,
+    2 => uid: u16,
+    4 => size: u32,
+    8 => atime: u32,
+    12 => ctime: u32,
+    16 => mtime: u32,
+    20 => dtime: u32,
+    24 => gid: u16,
+    26 => links_count: u16,
+    28 => blocks: u32,
+    32 => flags: u32,
+    36 => osd1: u32,
+    40 => block: [u32; 15],
+    100 => generation: u32,
+    104 => file_acl: u32,
+    108 => size_high: u32,
+    112 => fragment_addr: u32,
+    116 => osd2: [u8; 12],
+});
+
+impl Inode {
+    /// Creates an inode with all timestamps set to `now`
+    pub fn new(mode: u16, now: u32) -> Self {
+        Self {
+            mode,
+            uid: 0,
+            size: 0,
+            atime: now,
+            ctime: now,
+            mtime: now,
+            dtime: 0,
+            gid: 0,
+            links_count: 1,
+            blocks: 0,
+            flags: 0,
+            osd1: 0,
+            block: [0; 15],
+            generation: 0,
+            file_acl: 0,
+            size_high: 0,
+            fragment_addr: 0,
+            osd2: [0; 12],
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    /// Returns true for symbolic links whose target is stored in the
+    /// block pointers
+    pub fn is_fast_symlink(&self) -> bool {
+        self.is_symlink() && self.blocks == 0
+    }
+
+    /// File type recorded in directory entries pointing at this inode
+    pub fn file_type(&self) -> u8 {
+        match self.mode & S_IFMT {
+            S_IFDIR => FT_DIR,
+            S_IFLNK => FT_SYMLINK,
+            _ => FT_REG_FILE,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        if self.mode & S_IFMT == S_IFREG {
+            (self.size_high as u64) << 32 | self.size as u64
+        } else {
+            self.size as u64
+        }
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size as u32;
+        self.size_high = (size >> 32) as u32;
+    }
+
+    pub fn uid(&self) -> u32 {
+        (u16_le_at(&self.osd2, 4) as u32) << 16 | self.uid as u32
+    }
+
+    pub fn gid(&self) -> u32 {
+        (u16_le_at(&self.osd2, 6) as u32) << 16 | self.gid as u32
+    }
+
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.uid = uid as u16;
+        self.gid = gid as u16;
+        put_u16_le_at(&mut self.osd2, 4, (uid >> 16) as u16);
+        put_u16_le_at(&mut self.osd2, 6, (gid >> 16) as u16);
+    }
+}
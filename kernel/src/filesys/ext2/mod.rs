@@ -0,0 +1,1327 @@
+//! ext2 filesystem implementation
+//!
+//! Unlike FAT16, ext2 keeps everything but a file's name in its inode, so
+//! files have Unix modes, owners and access, change and modification times,
+//! and symbolic links can be stored. The driver handles filesystems with
+//! the filetype feature and files reachable through doubly indirect blocks.
+//! Every change is written through to the device, including the free
+//! counts in the group descriptors and the superblock.
+
+use super::*;
+use alloc::{collections::BTreeMap, vec};
+use core::{cmp::min, mem::size_of};
+
+mod constants;
+mod dir_entry;
+mod inode;
+mod superblock;
+
+use constants::*;
+pub use dir_entry::DirEntryHeader;
+use dir_entry::{entry_size, read_entry, write_entry};
+pub use inode::Inode;
+pub use superblock::{GroupDescriptor, Superblock};
+
+use crate::{
+    filesys::layout::{put_u32_le_at, u32_le_at, OnDisk},
+    processes::kthread,
+    time,
+};
+
+/// Largest block size the driver accepts
+const MAX_BLOCK_SIZE: usize = 4096;
+
+/// Current time as stored in inodes
+fn now() -> u32 {
+    time::realtime_secs().clamp(0, u32::MAX as i64) as u32
+}
+
+/// An open file
+struct OpenFile {
+    inode: u32,
+    position: u64,
+}
+
+/// ext2 filesystem driver
+pub struct Ext2<'a> {
+    /// Underlying block device
+    pub device: Box<dyn BlockDevice + 'a>,
+    superblock: Superblock,
+    groups: Vec<GroupDescriptor>,
+    /// Filesystem block size in bytes
+    block_size: usize,
+    /// Device blocks per filesystem block
+    device_blocks: u64,
+    /// Size of an inode table slot in bytes
+    inode_size: usize,
+    /// Open files by descriptor
+    open_files: BTreeMap<usize, OpenFile>,
+    next_fd: usize,
+}
+
+impl<'a> Ext2<'a> {
+    /// Creates an empty filesystem with 1 KiB blocks on the device
+    pub fn format(device: Box<dyn BlockDevice + 'a>) -> Result<Self, FsError> {
+        let device_block_size = device.block_size();
+        if device_block_size == 0 || FORMAT_BLOCK_SIZE % device_block_size != 0 {
+            return Err(FsError::NotSupported);
+        }
+        let device_blocks = (FORMAT_BLOCK_SIZE / device_block_size) as u64;
+        let blocks_per_group = (FORMAT_BLOCK_SIZE * 8) as u32;
+        // With 1 KiB blocks, block 0 holds the boot sector and the
+        // superblock takes block 1
+        let first_data_block = 1;
+        let descriptors_per_block = (FORMAT_BLOCK_SIZE / size_of::<GroupDescriptor>()) as u32;
+
+        let mut blocks_count = min(device.total_blocks() / device_blocks, u32::MAX as u64) as u32;
+        // Drop a last group too small to hold its own metadata
+        let (group_count, inodes_per_group, descriptor_blocks, overhead) = loop {
+            if blocks_count <= first_data_block {
+                return Err(FsError::NoSpace);
+            }
+            let data_blocks = blocks_count - first_data_block;
+            let group_count = data_blocks.div_ceil(blocks_per_group);
+            let group_bytes = min(data_blocks, blocks_per_group) as u64 * FORMAT_BLOCK_SIZE as u64;
+            let inodes_per_group = ((group_bytes / BYTES_PER_INODE) as u32)
+                .next_multiple_of(8)
+                .max(16);
+            let descriptor_blocks = group_count.div_ceil(descriptors_per_block);
+            let inode_table_blocks = (inodes_per_group as usize * GOOD_OLD_INODE_SIZE)
+                .div_ceil(FORMAT_BLOCK_SIZE) as u32;
+            // Superblock, descriptors, both bitmaps and the inode table
+            let overhead = 1 + descriptor_blocks + 2 + inode_table_blocks;
+
+            let last_group_blocks = data_blocks - (group_count - 1) * blocks_per_group;
+            if last_group_blocks >= overhead + MIN_GROUP_DATA_BLOCKS {
+                break (
+                    group_count,
+                    inodes_per_group,
+                    descriptor_blocks,
+                    inode_table_blocks,
+                );
+            }
+            if group_count == 1 {
+                return Err(FsError::NoSpace);
+            }
+            blocks_count -= last_group_blocks;
+        };
+        let inode_table_blocks = overhead - descriptor_blocks - 3;
+
+        let now = now();
+        let mut superblock = Superblock::zeroed();
+        superblock.inodes_count = inodes_per_group * group_count;
+        superblock.blocks_count = blocks_count;
+        superblock.first_data_block = first_data_block;
+        superblock.blocks_per_group = blocks_per_group;
+        superblock.frags_per_group = blocks_per_group;
+        superblock.inodes_per_group = inodes_per_group;
+        superblock.write_time = now;
+        superblock.max_mount_count = -1;
+        superblock.magic = EXT2_MAGIC;
+        superblock.state = STATE_VALID;
+        superblock.errors = ERRORS_CONTINUE;
+        superblock.last_check = now;
+        superblock.rev_level = DYNAMIC_REV;
+        superblock.first_inode = FIRST_INODE;
+        superblock.inode_size = GOOD_OLD_INODE_SIZE as u16;
+        superblock.feature_incompat = FEATURE_INCOMPAT_FILETYPE;
+        superblock.volume_name[..4].copy_from_slice(b"taos");
+
+        let groups = (0..group_count)
+            .map(|group| {
+                let start = first_data_block + group * blocks_per_group;
+                let group_blocks = min(blocks_per_group, blocks_count - start);
+                let block_bitmap = start + 1 + descriptor_blocks;
+                let reserved_inodes = if group == 0 { FIRST_INODE - 1 } else { 0 };
+                GroupDescriptor {
+                    block_bitmap,
+                    inode_bitmap: block_bitmap + 1,
+                    inode_table: block_bitmap + 2,
+                    free_blocks_count: (group_blocks - overhead) as u16,
+                    free_inodes_count: (inodes_per_group - reserved_inodes) as u16,
+                    used_dirs_count: 0,
+                    pad: 0,
+                    reserved: [0; 12],
+                }
+            })
+            .collect::<Vec<_>>();
+        superblock.free_blocks_count = groups.iter().map(|g| g.free_blocks_count as u32).sum();
+        superblock.free_inodes_count = groups.iter().map(|g| g.free_inodes_count as u32).sum();
+
+        let mut fs = Self {
+            device,
+            superblock,
+            groups,
+            block_size: FORMAT_BLOCK_SIZE,
+            device_blocks,
+            inode_size: GOOD_OLD_INODE_SIZE,
+            open_files: BTreeMap::new(),
+            next_fd: 0,
+        };
+
+        for group in 0..group_count {
+            let start = first_data_block + group * blocks_per_group;
+            let group_blocks = min(blocks_per_group, blocks_count - start);
+            let descriptor = fs.groups[group as usize];
+
+            let mut bitmap = vec![0u8; FORMAT_BLOCK_SIZE];
+            // Bits past the end of the group are set so they are never
+            // allocated
+            mark_range(&mut bitmap, 0, overhead);
+            mark_range(&mut bitmap, group_blocks, blocks_per_group);
+            fs.write_block(descriptor.block_bitmap, &bitmap)?;
+
+            bitmap.fill(0);
+            if group == 0 {
+                mark_range(&mut bitmap, 0, FIRST_INODE - 1);
+            }
+            mark_range(&mut bitmap, inodes_per_group, blocks_per_group);
+            fs.write_block(descriptor.inode_bitmap, &bitmap)?;
+
+            let zeros = vec![0u8; FORMAT_BLOCK_SIZE];
+            for block in 0..inode_table_blocks {
+                fs.write_block(descriptor.inode_table + block, &zeros)?;
+            }
+
+            // Every group keeps a backup of the superblock and descriptors
+            fs.write_superblock_copy(group)?;
+            kthread::yield_if_needed();
+        }
+
+        // The root directory is its own parent
+        fs.groups[0].used_dirs_count += 1;
+        fs.init_directory(ROOT_INODE, ROOT_INODE, DEFAULT_DIR_MODE)?;
+        fs.write_metadata(0)?;
+        Ok(fs)
+    }
+
+    /// Opens an existing filesystem
+    pub fn new(device: Box<dyn BlockDevice + 'a>) -> Result<Self, FsError> {
+        let device_block_size = device.block_size();
+        if device_block_size == 0 || SUPERBLOCK_SIZE % device_block_size != 0 {
+            return Err(FsError::NotSupported);
+        }
+        let mut raw = vec![0u8; SUPERBLOCK_SIZE];
+        let first = (SUPERBLOCK_OFFSET / device_block_size) as u64;
+        for (i, chunk) in raw.chunks_mut(device_block_size).enumerate() {
+            device.read_block(first + i as u64, chunk)?;
+        }
+        let superblock = Superblock::from_bytes(&raw);
+
+        if superblock.magic != EXT2_MAGIC {
+            return Err(FsError::IOError);
+        }
+        let block_size = superblock.block_size();
+        if block_size > MAX_BLOCK_SIZE
+            || superblock.feature_incompat & !FEATURE_INCOMPAT_FILETYPE != 0
+            || superblock.feature_ro_compat & !SUPPORTED_RO_COMPAT != 0
+        {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut fs = Self {
+            device,
+            superblock,
+            groups: Vec::new(),
+            block_size,
+            device_blocks: (block_size / device_block_size) as u64,
+            inode_size: superblock.inode_size(),
+            open_files: BTreeMap::new(),
+            next_fd: 0,
+        };
+
+        // The descriptor table starts in the block after the superblock
+        let mut buf = vec![0u8; block_size];
+        let per_block = block_size / size_of::<GroupDescriptor>();
+        let group_count = superblock.group_count() as usize;
+        for index in 0..group_count.div_ceil(per_block) {
+            fs.read_block(superblock.first_data_block + 1 + index as u32, &mut buf)?;
+            for slot in 0..min(per_block, group_count - index * per_block) {
+                fs.groups.push(GroupDescriptor::read_slot(&buf, slot));
+            }
+        }
+        Ok(fs)
+    }
+
+    /// Sets the permission bits of a file, directory or symbolic link
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file
+    /// * `mode` - Permission, setuid, setgid and sticky bits. File type bits
+    ///   are ignored.
+    pub fn set_mode(&mut self, path: &str, mode: u16) -> Result<(), FsError> {
+        let ino = self.resolve(path)?;
+        let mut inode = self.read_inode(ino)?;
+        inode.mode = inode.mode & S_IFMT | mode & PERMISSION_MASK;
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Sets the owner of a file, directory or symbolic link
+    pub fn set_owner(&mut self, path: &str, uid: u32, gid: u32) -> Result<(), FsError> {
+        let ino = self.resolve(path)?;
+        let mut inode = self.read_inode(ino)?;
+        inode.set_owner(uid, gid);
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Creates a symbolic link at `path` pointing to `target`. Paths are not
+    /// resolved through symbolic links, so following one is up to the
+    /// caller.
+    pub fn symlink(&mut self, target: &str, path: &str) -> Result<(), FsError> {
+        if target.is_empty() || target.len() >= self.block_size {
+            return Err(FsError::InvalidName);
+        }
+        let (parent, name) = self.resolve_parent(path)?;
+        if self.lookup(parent, name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let ino = self.allocate_inode(self.inode_group(parent), false)?;
+        let mut inode = Inode::new(S_IFLNK | SYMLINK_MODE, now());
+        if target.len() < FAST_SYMLINK_MAX {
+            let mut raw = [0u8; FAST_SYMLINK_MAX];
+            raw[..target.len()].copy_from_slice(target.as_bytes());
+            inode.block = OnDisk::from_bytes(&raw);
+            inode.set_size(target.len() as u64);
+        } else {
+            self.write_at(ino, &mut inode, 0, target.as_bytes())?;
+        }
+        self.write_inode(ino, &inode)?;
+        self.add_entry(parent, name, ino, FT_SYMLINK)
+    }
+
+    /// Returns the target of a symbolic link
+    pub fn read_link(&self, path: &str) -> Result<String, FsError> {
+        let inode = self.read_inode(self.resolve(path)?)?;
+        if !inode.is_symlink() {
+            return Err(FsError::InvalidName);
+        }
+        let size = inode.size() as usize;
+        let mut target = vec![0u8; size];
+        if inode.is_fast_symlink() {
+            let mut raw = [0u8; FAST_SYMLINK_MAX];
+            inode.block.to_bytes(&mut raw);
+            target.copy_from_slice(&raw[..size]);
+        } else {
+            self.read_at(&inode, 0, &mut target)?;
+        }
+        String::from_utf8(target).map_err(|_| FsError::IOError)
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        let device_block_size = self.block_size / self.device_blocks as usize;
+        let first = block as u64 * self.device_blocks;
+        for (i, chunk) in buf.chunks_mut(device_block_size).enumerate() {
+            self.device.read_block(first + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block: u32, buf: &[u8]) -> Result<(), FsError> {
+        let device_block_size = self.block_size / self.device_blocks as usize;
+        let first = block as u64 * self.device_blocks;
+        for (i, chunk) in buf.chunks(device_block_size).enumerate() {
+            self.device.write_block(first + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the superblock and the descriptor of `group` to the primary
+    /// copies
+    fn write_metadata(&mut self, group: usize) -> Result<(), FsError> {
+        self.superblock.write_time = now();
+        let mut buf = vec![0u8; self.block_size];
+        let superblock_block = (SUPERBLOCK_OFFSET / self.block_size) as u32;
+        let offset = SUPERBLOCK_OFFSET % self.block_size;
+        self.read_block(superblock_block, &mut buf)?;
+        self.superblock.to_bytes(&mut buf[offset..]);
+        self.write_block(superblock_block, &buf)?;
+
+        let per_block = self.block_size / size_of::<GroupDescriptor>();
+        let table_block = self.superblock.first_data_block + 1 + (group / per_block) as u32;
+        self.read_block(table_block, &mut buf)?;
+        self.groups[group].write_slot(&mut buf, group % per_block);
+        self.write_block(table_block, &buf)
+    }
+
+    /// Writes a backup of the superblock and whole descriptor table at the
+    /// start of `group`. Only used by `format`, whose block size keeps the
+    /// superblock in a block of its own.
+    fn write_superblock_copy(&mut self, group: u32) -> Result<(), FsError> {
+        let start = self.superblock.first_data_block + group * self.superblock.blocks_per_group;
+        let mut superblock = self.superblock;
+        superblock.block_group_nr = group as u16;
+        let mut buf = vec![0u8; self.block_size];
+        superblock.to_bytes(&mut buf);
+        self.write_block(start, &buf)?;
+
+        let per_block = self.block_size / size_of::<GroupDescriptor>();
+        for (index, chunk) in self.groups.clone().chunks(per_block).enumerate() {
+            buf.fill(0);
+            for (slot, descriptor) in chunk.iter().enumerate() {
+                descriptor.write_slot(&mut buf, slot);
+            }
+            self.write_block(start + 1 + index as u32, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Group an inode belongs to, where its blocks are allocated first
+    fn inode_group(&self, ino: u32) -> usize {
+        ((ino - 1) / self.superblock.inodes_per_group) as usize
+    }
+
+    /// Returns the block holding an inode and the inode's offset in it
+    fn inode_position(&self, ino: u32) -> Result<(u32, usize), FsError> {
+        if ino == 0 || ino > self.superblock.inodes_count {
+            return Err(FsError::IOError);
+        }
+        let index = ((ino - 1) % self.superblock.inodes_per_group) as usize;
+        let byte = index * self.inode_size;
+        let table = self.groups[self.inode_group(ino)].inode_table;
+        Ok((
+            table + (byte / self.block_size) as u32,
+            byte % self.block_size,
+        ))
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode, FsError> {
+        let (block, offset) = self.inode_position(ino)?;
+        let mut buf = vec![0u8; self.block_size];
+        self.read_block(block, &mut buf)?;
+        Ok(Inode::from_bytes(&buf[offset..]))
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> Result<(), FsError> {
+        let (block, offset) = self.inode_position(ino)?;
+        let mut buf = vec![0u8; self.block_size];
+        self.read_block(block, &mut buf)?;
+        inode.to_bytes(&mut buf[offset..]);
+        self.write_block(block, &buf)
+    }
+
+    /// Returns the first group at or after `goal` that `has_room` accepts,
+    /// wrapping around
+    fn find_group(
+        &self,
+        goal: usize,
+        has_room: impl Fn(&GroupDescriptor) -> bool,
+    ) -> Option<usize> {
+        let count = self.groups.len();
+        (0..count)
+            .map(|i| (goal + i) % count)
+            .find(|&group| has_room(&self.groups[group]))
+    }
+
+    /// Sets the first clear bit below `limit` in a bitmap block
+    ///
+    /// # Returns
+    /// The index of the bit. Fails if every bit is set, which means the
+    /// free counts do not match the bitmap.
+    fn take_bit(&mut self, bitmap_block: u32, limit: u32) -> Result<u32, FsError> {
+        let mut bitmap = vec![0u8; self.block_size];
+        self.read_block(bitmap_block, &mut bitmap)?;
+        let bit = (0..limit)
+            .find(|&bit| bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0)
+            .ok_or(FsError::IOError)?;
+        bitmap[bit as usize / 8] |= 1 << (bit % 8);
+        self.write_block(bitmap_block, &bitmap)?;
+        Ok(bit)
+    }
+
+    fn clear_bit(&mut self, bitmap_block: u32, bit: u32) -> Result<(), FsError> {
+        let mut bitmap = vec![0u8; self.block_size];
+        self.read_block(bitmap_block, &mut bitmap)?;
+        bitmap[bit as usize / 8] &= !(1 << (bit % 8));
+        self.write_block(bitmap_block, &bitmap)
+    }
+
+    /// Allocates a zeroed block, preferably in `goal_group`
+    fn allocate_block(&mut self, goal_group: usize) -> Result<u32, FsError> {
+        let group = self
+            .find_group(goal_group, |g| g.free_blocks_count > 0)
+            .ok_or(FsError::NoSpace)?;
+        let bitmap = self.groups[group].block_bitmap;
+        let bit = self.take_bit(bitmap, self.superblock.blocks_per_group)?;
+        self.groups[group].free_blocks_count -= 1;
+        self.superblock.free_blocks_count -= 1;
+        self.write_metadata(group)?;
+
+        let block = self.superblock.first_data_block
+            + group as u32 * self.superblock.blocks_per_group
+            + bit;
+        // Old contents must not leak into files or indirect blocks
+        self.write_block(block, &vec![0u8; self.block_size])?;
+        Ok(block)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), FsError> {
+        let relative = block - self.superblock.first_data_block;
+        let group = (relative / self.superblock.blocks_per_group) as usize;
+        self.clear_bit(
+            self.groups[group].block_bitmap,
+            relative % self.superblock.blocks_per_group,
+        )?;
+        self.groups[group].free_blocks_count += 1;
+        self.superblock.free_blocks_count += 1;
+        self.write_metadata(group)
+    }
+
+    /// Allocates an inode number, preferably in `goal_group`
+    fn allocate_inode(&mut self, goal_group: usize, is_dir: bool) -> Result<u32, FsError> {
+        let group = self
+            .find_group(goal_group, |g| g.free_inodes_count > 0)
+            .ok_or(FsError::NoSpace)?;
+        let bitmap = self.groups[group].inode_bitmap;
+        let bit = self.take_bit(bitmap, self.superblock.inodes_per_group)?;
+        self.groups[group].free_inodes_count -= 1;
+        if is_dir {
+            self.groups[group].used_dirs_count += 1;
+        }
+        self.superblock.free_inodes_count -= 1;
+        self.write_metadata(group)?;
+        Ok(group as u32 * self.superblock.inodes_per_group + bit + 1)
+    }
+
+    fn free_inode(&mut self, ino: u32, is_dir: bool) -> Result<(), FsError> {
+        let group = self.inode_group(ino);
+        self.clear_bit(
+            self.groups[group].inode_bitmap,
+            (ino - 1) % self.superblock.inodes_per_group,
+        )?;
+        self.groups[group].free_inodes_count += 1;
+        if is_dir {
+            self.groups[group].used_dirs_count -= 1;
+        }
+        self.superblock.free_inodes_count += 1;
+        self.write_metadata(group)
+    }
+
+    /// Number of block pointers in an indirect block
+    fn pointers_per_block(&self) -> u32 {
+        (self.block_size / 4) as u32
+    }
+
+    fn read_pointer(&self, table: u32, slot: u32) -> Result<u32, FsError> {
+        let mut buf = vec![0u8; self.block_size];
+        self.read_block(table, &mut buf)?;
+        Ok(u32_le_at(&buf, slot as usize * 4))
+    }
+
+    /// Returns the disk block holding block `index` of a file, 0 for a hole
+    fn file_block(&self, inode: &Inode, index: u32) -> Result<u32, FsError> {
+        let pointers = self.pointers_per_block();
+        if (index as usize) < DIRECT_BLOCKS {
+            return Ok(inode.block[index as usize]);
+        }
+        let index = index - DIRECT_BLOCKS as u32;
+        if index < pointers {
+            return match inode.block[SINGLE_INDIRECT] {
+                0 => Ok(0),
+                table => self.read_pointer(table, index),
+            };
+        }
+        let index = index - pointers;
+        if index < pointers * pointers {
+            let table = match inode.block[DOUBLE_INDIRECT] {
+                0 => return Ok(0),
+                outer => self.read_pointer(outer, index / pointers)?,
+            };
+            return match table {
+                0 => Ok(0),
+                table => self.read_pointer(table, index % pointers),
+            };
+        }
+        Err(FsError::InvalidOffset)
+    }
+
+    /// Allocates a block for `inode` and counts it in the inode's sectors
+    fn allocate_for(&mut self, ino: u32, inode: &mut Inode) -> Result<u32, FsError> {
+        let block = self.allocate_block(self.inode_group(ino))?;
+        inode.blocks += (self.block_size / 512) as u32;
+        Ok(block)
+    }
+
+    /// Returns the pointer in `slot` of an indirect block, allocating a
+    /// block for it if it is 0
+    fn ensure_pointer(
+        &mut self,
+        ino: u32,
+        inode: &mut Inode,
+        table: u32,
+        slot: u32,
+    ) -> Result<u32, FsError> {
+        let mut buf = vec![0u8; self.block_size];
+        self.read_block(table, &mut buf)?;
+        let offset = slot as usize * 4;
+        match u32_le_at(&buf, offset) {
+            0 => {
+                let block = self.allocate_for(ino, inode)?;
+                put_u32_le_at(&mut buf, offset, block);
+                self.write_block(table, &buf)?;
+                Ok(block)
+            }
+            block => Ok(block),
+        }
+    }
+
+    /// Returns the pointer in `slot` of the inode's block pointers,
+    /// allocating a block for it if it is 0
+    fn ensure_root(&mut self, ino: u32, inode: &mut Inode, slot: usize) -> Result<u32, FsError> {
+        if inode.block[slot] == 0 {
+            inode.block[slot] = self.allocate_for(ino, inode)?;
+        }
+        Ok(inode.block[slot])
+    }
+
+    /// Returns the disk block holding block `index` of a file, allocating
+    /// it and any indirect blocks leading to it
+    fn map_file_block(&mut self, ino: u32, inode: &mut Inode, index: u32) -> Result<u32, FsError> {
+        let pointers = self.pointers_per_block();
+        if (index as usize) < DIRECT_BLOCKS {
+            return self.ensure_root(ino, inode, index as usize);
+        }
+        let index = index - DIRECT_BLOCKS as u32;
+        if index < pointers {
+            let table = self.ensure_root(ino, inode, SINGLE_INDIRECT)?;
+            return self.ensure_pointer(ino, inode, table, index);
+        }
+        let index = index - pointers;
+        if index < pointers * pointers {
+            let outer = self.ensure_root(ino, inode, DOUBLE_INDIRECT)?;
+            let table = self.ensure_pointer(ino, inode, outer, index / pointers)?;
+            return self.ensure_pointer(ino, inode, table, index % pointers);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Frees every block of an inode, including indirect blocks
+    fn free_file_blocks(&mut self, inode: &mut Inode) -> Result<(), FsError> {
+        if inode.is_fast_symlink() {
+            // The block pointers hold the link target
+            inode.block = [0; 15];
+            return Ok(());
+        }
+        for slot in 0..DIRECT_BLOCKS {
+            if inode.block[slot] != 0 {
+                self.free_block(inode.block[slot])?;
+            }
+        }
+        if inode.block[SINGLE_INDIRECT] != 0 {
+            self.free_indirect(inode.block[SINGLE_INDIRECT], 1)?;
+        }
+        if inode.block[DOUBLE_INDIRECT] != 0 {
+            self.free_indirect(inode.block[DOUBLE_INDIRECT], 2)?;
+        }
+        inode.block = [0; 15];
+        inode.blocks = 0;
+        Ok(())
+    }
+
+    /// Frees an indirect block and the `depth` levels of blocks below it
+    fn free_indirect(&mut self, table: u32, depth: u32) -> Result<(), FsError> {
+        let mut buf = vec![0u8; self.block_size];
+        self.read_block(table, &mut buf)?;
+        for slot in 0..self.pointers_per_block() as usize {
+            match u32_le_at(&buf, slot * 4) {
+                0 => {}
+                block if depth > 1 => self.free_indirect(block, depth - 1)?,
+                block => self.free_block(block)?,
+            }
+        }
+        self.free_block(table)
+    }
+
+    /// Reads file contents starting at `offset`
+    ///
+    /// # Returns
+    /// The number of bytes read, 0 at or past the end of the file
+    fn read_at(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let total = min(buf.len() as u64, size - offset) as usize;
+        let mut block_buf = vec![0u8; self.block_size];
+        let mut done = 0;
+        while done < total {
+            let position = offset + done as u64;
+            let index = (position / self.block_size as u64) as u32;
+            let start = (position % self.block_size as u64) as usize;
+            let count = min(self.block_size - start, total - done);
+            match self.file_block(inode, index)? {
+                // Holes read as zeros
+                0 => buf[done..done + count].fill(0),
+                block => {
+                    self.read_block(block, &mut block_buf)?;
+                    buf[done..done + count].copy_from_slice(&block_buf[start..start + count]);
+                }
+            }
+            done += count;
+        }
+        Ok(total)
+    }
+
+    /// Writes file contents starting at `offset`, growing the file if
+    /// needed. Writing past the end leaves a hole. The caller writes the
+    /// inode back.
+    fn write_at(
+        &mut self,
+        ino: u32,
+        inode: &mut Inode,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<usize, FsError> {
+        let mut block_buf = vec![0u8; self.block_size];
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index =
+                u32::try_from(position / self.block_size as u64).map_err(|_| FsError::NoSpace)?;
+            let start = (position % self.block_size as u64) as usize;
+            let count = min(self.block_size - start, buf.len() - done);
+            let block = match self.map_file_block(ino, inode, index) {
+                Ok(block) => block,
+                // Keep what was written before the disk filled up
+                Err(_) if done > 0 => break,
+                Err(e) => return Err(e),
+            };
+            if count < self.block_size {
+                self.read_block(block, &mut block_buf)?;
+            }
+            block_buf[start..start + count].copy_from_slice(&buf[done..done + count]);
+            self.write_block(block, &block_buf)?;
+            done += count;
+        }
+        let end = offset + done as u64;
+        if end > inode.size() {
+            inode.set_size(end);
+        }
+        Ok(done)
+    }
+
+    /// Calls `f` with the inode and name of each used entry of a directory,
+    /// until it returns Some
+    fn scan_dir<T>(
+        &self,
+        dir: &Inode,
+        mut f: impl FnMut(u32, &[u8]) -> Option<T>,
+    ) -> Result<Option<T>, FsError> {
+        let mut buf = vec![0u8; self.block_size];
+        let blocks = dir.size().div_ceil(self.block_size as u64) as u32;
+        for index in 0..blocks {
+            match self.file_block(dir, index)? {
+                0 => continue,
+                block => self.read_block(block, &mut buf)?,
+            }
+            let mut offset = 0;
+            while offset < self.block_size {
+                let (header, name) = read_entry(&buf, offset)?;
+                if header.inode != 0 {
+                    if let Some(found) = f(header.inode, name) {
+                        return Ok(Some(found));
+                    }
+                }
+                offset += header.rec_len as usize;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the inode a directory entry named `name` points at
+    fn lookup(&self, dir_ino: u32, name: &str) -> Result<u32, FsError> {
+        let dir = self.read_inode(dir_ino)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotFound);
+        }
+        self.scan_dir(&dir, |ino, entry_name| {
+            (entry_name == name.as_bytes()).then_some(ino)
+        })?
+        .ok_or(FsError::NotFound)
+    }
+
+    /// Returns the inode `path` names
+    fn resolve(&self, path: &str) -> Result<u32, FsError> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(ROOT_INODE, |dir, name| self.lookup(dir, name))
+    }
+
+    /// Returns the directory that holds `path` and the final component of
+    /// `path`, which must be a valid name
+    fn resolve_parent<'p>(&self, path: &'p str) -> Result<(u32, &'p str), FsError> {
+        let (parent, name) = split_path(path.trim_end_matches('/'));
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.len() > MAX_NAME_LENGTH
+            || name.contains('\0')
+        {
+            return Err(FsError::InvalidName);
+        }
+        let parent = self.resolve(parent)?;
+        if !self.read_inode(parent)?.is_dir() {
+            return Err(FsError::NotFound);
+        }
+        Ok((parent, name))
+    }
+
+    /// Adds an entry to a directory, growing it by a block if no block has
+    /// room
+    fn add_entry(
+        &mut self,
+        dir_ino: u32,
+        name: &str,
+        ino: u32,
+        file_type: u8,
+    ) -> Result<(), FsError> {
+        let needed = entry_size(name.len());
+        let mut dir = self.read_inode(dir_ino)?;
+        let mut buf = vec![0u8; self.block_size];
+        let blocks = dir.size().div_ceil(self.block_size as u64) as u32;
+
+        'blocks: for index in 0..blocks {
+            let block = match self.file_block(&dir, index)? {
+                0 => continue,
+                block => block,
+            };
+            self.read_block(block, &mut buf)?;
+            let mut offset = 0;
+            while offset < self.block_size {
+                let (mut header, _) = read_entry(&buf, offset)?;
+                let rec_len = header.rec_len as usize;
+                let used = match header.inode {
+                    0 => 0,
+                    _ => entry_size(header.name_len as usize),
+                };
+                if rec_len >= used + needed {
+                    // Split the free space at the end of this entry off
+                    if used > 0 {
+                        header.rec_len = used as u16;
+                        header.to_bytes(&mut buf[offset..]);
+                    }
+                    write_entry(
+                        &mut buf,
+                        offset + used,
+                        ino,
+                        rec_len - used,
+                        name,
+                        file_type,
+                    );
+                    self.write_block(block, &buf)?;
+                    break 'blocks;
+                }
+                offset += rec_len;
+            }
+            if index + 1 == blocks {
+                // No room anywhere, so the new entry gets a block of its own
+                let block = self.map_file_block(dir_ino, &mut dir, blocks)?;
+                buf.fill(0);
+                write_entry(&mut buf, 0, ino, self.block_size, name, file_type);
+                self.write_block(block, &buf)?;
+                dir.set_size((blocks as u64 + 1) * self.block_size as u64);
+            }
+        }
+
+        let now = now();
+        dir.mtime = now;
+        dir.ctime = now;
+        self.write_inode(dir_ino, &dir)
+    }
+
+    /// Removes the entry named `name` from a directory
+    ///
+    /// # Returns
+    /// The inode the entry pointed at
+    fn remove_entry(&mut self, dir_ino: u32, name: &str) -> Result<u32, FsError> {
+        let mut dir = self.read_inode(dir_ino)?;
+        let mut buf = vec![0u8; self.block_size];
+        let blocks = dir.size().div_ceil(self.block_size as u64) as u32;
+
+        for index in 0..blocks {
+            let block = match self.file_block(&dir, index)? {
+                0 => continue,
+                block => block,
+            };
+            self.read_block(block, &mut buf)?;
+            let mut previous = None;
+            let mut offset = 0;
+            while offset < self.block_size {
+                let (mut header, entry_name) = read_entry(&buf, offset)?;
+                if header.inode == 0 || entry_name != name.as_bytes() {
+                    previous = Some(offset);
+                    offset += header.rec_len as usize;
+                    continue;
+                }
+
+                let ino = header.inode;
+                match previous {
+                    // Merge the entry into the one before it
+                    Some(previous) => {
+                        let (mut before, _) = read_entry(&buf, previous)?;
+                        before.rec_len += header.rec_len;
+                        before.to_bytes(&mut buf[previous..]);
+                    }
+                    // The first entry of a block is marked unused instead
+                    None => {
+                        header.inode = 0;
+                        header.to_bytes(&mut buf[offset..]);
+                    }
+                }
+                self.write_block(block, &buf)?;
+
+                let now = now();
+                dir.mtime = now;
+                dir.ctime = now;
+                self.write_inode(dir_ino, &dir)?;
+                return Ok(ino);
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    /// Points the ".." entry of a directory at a new parent
+    fn set_parent(&mut self, dir_ino: u32, parent: u32) -> Result<(), FsError> {
+        let dir = self.read_inode(dir_ino)?;
+        let block = self.file_block(&dir, 0)?;
+        let mut buf = vec![0u8; self.block_size];
+        self.read_block(block, &mut buf)?;
+        let mut offset = 0;
+        while offset < self.block_size {
+            let (mut header, name) = read_entry(&buf, offset)?;
+            if name == b".." {
+                header.inode = parent;
+                header.to_bytes(&mut buf[offset..]);
+                return self.write_block(block, &buf);
+            }
+            offset += header.rec_len as usize;
+        }
+        Err(FsError::IOError)
+    }
+
+    /// Writes a new directory inode holding "." and ".."
+    fn init_directory(&mut self, ino: u32, parent: u32, mode: u16) -> Result<(), FsError> {
+        let mut inode = Inode::new(S_IFDIR | mode, now());
+        // The parent's entry and "."
+        inode.links_count = 2;
+        let block = self.map_file_block(ino, &mut inode, 0)?;
+
+        let mut buf = vec![0u8; self.block_size];
+        let dot_size = entry_size(1);
+        write_entry(&mut buf, 0, ino, dot_size, ".", FT_DIR);
+        write_entry(
+            &mut buf,
+            dot_size,
+            parent,
+            self.block_size - dot_size,
+            "..",
+            FT_DIR,
+        );
+        self.write_block(block, &buf)?;
+
+        inode.set_size(self.block_size as u64);
+        self.write_inode(ino, &inode)
+    }
+
+    /// Adds `delta` to an inode's link count
+    fn adjust_links(&mut self, ino: u32, delta: i16) -> Result<(), FsError> {
+        let mut inode = self.read_inode(ino)?;
+        inode.links_count = inode.links_count.saturating_add_signed(delta);
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Drops a link to an inode, freeing it once no links are left
+    fn unlink_inode(&mut self, ino: u32) -> Result<(), FsError> {
+        let mut inode = self.read_inode(ino)?;
+        let is_dir = inode.is_dir();
+        // A directory's "." entry does not keep it alive
+        inode.links_count = match is_dir {
+            true => 0,
+            false => inode.links_count.saturating_sub(1),
+        };
+        inode.ctime = now();
+        if inode.links_count > 0 {
+            return self.write_inode(ino, &inode);
+        }
+
+        self.free_file_blocks(&mut inode)?;
+        inode.set_size(0);
+        inode.dtime = inode.ctime;
+        self.write_inode(ino, &inode)?;
+        self.free_inode(ino, is_dir)
+    }
+
+    fn file_metadata(&self, inode: &Inode) -> FileMetadata {
+        FileMetadata {
+            size: inode.size(),
+            is_dir: inode.is_dir(),
+            // ext2 records when the inode last changed, not when it was
+            // created
+            created: 0,
+            modified: inode.mtime as u64,
+            accessed: inode.atime as u64,
+            permissions: FilePermissions {
+                readable: inode.mode & READ_BITS != 0,
+                writable: inode.mode & WRITE_BITS != 0,
+                executable: inode.mode & EXECUTE_BITS != 0,
+            },
+            mode: Some(inode.mode),
+            uid: inode.uid(),
+            gid: inode.gid(),
+        }
+    }
+
+    fn open_file_mut(&mut self, fd: usize) -> &mut OpenFile {
+        self.open_files
+            .get_mut(&fd)
+            .expect("Invalid file descriptor.")
+    }
+}
+
+/// Sets bits `[start, end)` of a bitmap
+fn mark_range(bitmap: &mut [u8], start: u32, end: u32) {
+    for bit in start..end {
+        bitmap[bit as usize / 8] |= 1 << (bit % 8);
+    }
+}
+
+/// Splits a path into its parent directory and final component
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => ("", path),
+    }
+}
+
+impl FileSystem for Ext2<'_> {
+    fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        if self.lookup(parent, name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let ino = self.allocate_inode(self.inode_group(parent), false)?;
+        let inode = Inode::new(S_IFREG | DEFAULT_FILE_MODE, now());
+        self.write_inode(ino, &inode)?;
+        self.add_entry(parent, name, ino, FT_REG_FILE)
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        if self.lookup(parent, name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let ino = self.allocate_inode(self.inode_group(parent), true)?;
+        self.init_directory(ino, parent, DEFAULT_DIR_MODE)?;
+        self.add_entry(parent, name, ino, FT_DIR)?;
+        // The new directory's ".." links to the parent
+        self.adjust_links(parent, 1)
+    }
+
+    fn remove_file(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let ino = self.lookup(parent, name)?;
+        if self.read_inode(ino)?.is_dir() {
+            return Err(FsError::NotSupported);
+        }
+        if self.open_files.values().any(|file| file.inode == ino) {
+            return Err(FsError::Busy);
+        }
+
+        self.remove_entry(parent, name)?;
+        self.unlink_inode(ino)
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let ino = self.lookup(parent, name)?;
+        let dir = self.read_inode(ino)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotSupported);
+        }
+        let has_children = self
+            .scan_dir(&dir, |_, name| {
+                (name != b"." && name != b"..").then_some(())
+            })?
+            .is_some();
+        if has_children {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        self.remove_entry(parent, name)?;
+        self.unlink_inode(ino)?;
+        self.adjust_links(parent, -1)
+    }
+
+    fn open_file(&mut self, path: &str) -> Result<usize, FsError> {
+        let ino = self.resolve(path)?;
+        if self.read_inode(ino)?.is_dir() {
+            return Err(FsError::NotSupported);
+        }
+
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.open_files.insert(
+            fd,
+            OpenFile {
+                inode: ino,
+                position: 0,
+            },
+        );
+        Ok(fd)
+    }
+
+    fn close_file(&mut self, fd: usize) {
+        self.open_files
+            .remove(&fd)
+            .expect("Cannot close an invalid file descriptor.");
+    }
+
+    fn write_file(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let file = self.open_file_mut(fd);
+        let (ino, position) = (file.inode, file.position);
+
+        let mut inode = self.read_inode(ino)?;
+        let written = self.write_at(ino, &mut inode, position, buf)?;
+        let now = now();
+        inode.mtime = now;
+        inode.ctime = now;
+        self.write_inode(ino, &inode)?;
+
+        self.open_file_mut(fd).position += written as u64;
+        Ok(written)
+    }
+
+    fn seek_file(&mut self, fd: usize, pos: SeekFrom) -> Result<u64, FsError> {
+        let ino = self.open_file_mut(fd).inode;
+        let size = self.read_inode(ino)?.size();
+        let file = self.open_file_mut(fd);
+
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => file.position.checked_add_signed(offset),
+        }
+        .ok_or(FsError::InvalidOffset)?;
+
+        // Seeking past the end is allowed, and writing there leaves a hole
+        file.position = new_pos;
+        Ok(new_pos)
+    }
+
+    fn read_file(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let file = self.open_file_mut(fd);
+        let (ino, position) = (file.inode, file.position);
+
+        let inode = self.read_inode(ino)?;
+        let read = self.read_at(&inode, position, buf)?;
+        self.open_file_mut(fd).position += read as u64;
+        Ok(read)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.read_inode(self.resolve(path)?)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut children = Vec::new();
+        self.scan_dir(&dir, |ino, name| {
+            if name != b"." && name != b".." {
+                children.push((ino, String::from_utf8_lossy(name).into_owned()));
+            }
+            None::<()>
+        })?;
+
+        children
+            .into_iter()
+            .map(|(ino, name)| {
+                let inode = self.read_inode(ino)?;
+                Ok(DirEntry {
+                    name,
+                    metadata: self.file_metadata(&inode),
+                })
+            })
+            .collect()
+    }
+
+    fn metadata(&self, path: &str) -> Result<FileMetadata, FsError> {
+        let inode = self.read_inode(self.resolve(path)?)?;
+        Ok(self.file_metadata(&inode))
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        // Metadata is written through, so only the device needs flushing
+        self.device.flush()
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn set_permissions(&mut self, path: &str, permissions: FilePermissions) -> Result<(), FsError> {
+        // Like chmod a+r, u+w and a+x, or a-r, a-w and a-x
+        let ino = self.resolve(path)?;
+        let mut inode = self.read_inode(ino)?;
+        let mut mode = inode.mode;
+        for (wanted, set, clear) in [
+            (permissions.readable, READ_BITS, READ_BITS),
+            (permissions.writable, OWNER_WRITE_BIT, WRITE_BITS),
+            (permissions.executable, EXECUTE_BITS, EXECUTE_BITS),
+        ] {
+            // Bits already granted are left alone
+            if wanted && mode & clear == 0 {
+                mode |= set;
+            } else if !wanted {
+                mode &= !clear;
+            }
+        }
+        inode.mode = mode;
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    fn set_times(&mut self, path: &str, times: FileTimes) -> Result<(), FsError> {
+        let ino = self.resolve(path)?;
+        let mut inode = self.read_inode(ino)?;
+        if let Some(accessed) = times.accessed {
+            inode.atime = accessed.min(u32::MAX as u64) as u32;
+        }
+        if let Some(modified) = times.modified {
+            inode.mtime = modified.min(u32::MAX as u64) as u32;
+        }
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        Ok(StatFs {
+            block_size: self.block_size as u64,
+            total_blocks: self.superblock.blocks_count as u64,
+            free_blocks: self.superblock.free_blocks_count as u64,
+            total_inodes: self.superblock.inodes_count as u64,
+            free_inodes: self.superblock.free_inodes_count as u64,
+        })
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (src_parent, src_name) = self.resolve_parent(from)?;
+        let ino = self.lookup(src_parent, src_name)?;
+        let (dest_parent, dest_name) = self.resolve_parent(to)?;
+
+        match self.lookup(dest_parent, dest_name) {
+            Ok(existing) if existing == ino => return Ok(()),
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut inode = self.read_inode(ino)?;
+        let moves_dir = inode.is_dir() && src_parent != dest_parent;
+        if moves_dir {
+            // A directory cannot be moved below itself
+            let mut ancestor = dest_parent;
+            while ancestor != ROOT_INODE {
+                if ancestor == ino {
+                    return Err(FsError::InvalidName);
+                }
+                ancestor = self.lookup(ancestor, "..")?;
+            }
+        }
+
+        // Add the new link before removing the old one. A crash in between
+        // leaves the file reachable under both names rather than neither.
+        self.add_entry(dest_parent, dest_name, ino, inode.file_type())?;
+        self.remove_entry(src_parent, src_name)?;
+
+        if moves_dir {
+            self.set_parent(ino, dest_parent)?;
+            self.adjust_links(src_parent, -1)?;
+            self.adjust_links(dest_parent, 1)?;
+        }
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesys::block::memory::MemoryBlockDevice;
+
+    #[test_case]
+    fn test_ext2_files_and_modes() {
+        let device = Box::new(MemoryBlockDevice::new(1024, 512));
+        let mut fs = Ext2::format(device).expect("Failed to format filesystem");
+        let empty = fs.statfs().unwrap();
+
+        fs.create_dir("/bin").unwrap();
+        fs.create_file("/bin/prog").unwrap();
+        let metadata = fs.metadata("/bin/prog").unwrap();
+        assert_eq!(metadata.mode, Some(S_IFREG | DEFAULT_FILE_MODE));
+        assert!(!metadata.permissions.executable);
+
+        fs.set_mode("/bin/prog", 0o750).unwrap();
+        fs.set_owner("/bin/prog", 1000, 100_000).unwrap();
+        let metadata = fs.metadata("/bin/prog").unwrap();
+        assert_eq!(metadata.mode, Some(S_IFREG | 0o750));
+        assert!(metadata.permissions.executable);
+        assert_eq!((metadata.uid, metadata.gid), (1000, 100_000));
+
+        // Large enough to need the doubly indirect block
+        let block_size = fs.block_size;
+        let pointers = block_size / 4;
+        let size = (DIRECT_BLOCKS + pointers + 2) * block_size;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let fd = fs.open_file("/bin/prog").unwrap();
+        assert_eq!(fs.write_file(fd, &data).unwrap(), size);
+        fs.seek_file(fd, SeekFrom::Start(0)).unwrap();
+        let mut contents = vec![0u8; size];
+        let mut read = 0;
+        while read < size {
+            read += fs.read_file(fd, &mut contents[read..]).unwrap();
+        }
+        assert!(contents == data);
+        fs.close_file(fd);
+
+        fs.symlink("/bin/prog", "/prog").unwrap();
+        assert_eq!(fs.read_link("/prog").unwrap(), "/bin/prog");
+
+        // The filesystem reads back the same from disk
+        let device = core::mem::replace(&mut fs.device, Box::new(MemoryBlockDevice::new(1, 512)));
+        let mut fs = Ext2::new(device).expect("Failed to open filesystem");
+        assert_eq!(fs.metadata("/bin/prog").unwrap().size, size as u64);
+        let names: Vec<String> = fs
+            .read_dir("/")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, ["bin", "prog"]);
+
+        fs.create_dir("/lib").unwrap();
+        fs.rename("/bin", "/lib/bin").unwrap();
+        assert!(matches!(
+            fs.rename("/lib", "/lib/bin/lib"),
+            Err(FsError::InvalidName)
+        ));
+        assert!(matches!(
+            fs.remove_dir("/lib"),
+            Err(FsError::DirectoryNotEmpty)
+        ));
+        fs.remove_file("/lib/bin/prog").unwrap();
+        fs.remove_dir("/lib/bin").unwrap();
+        fs.remove_dir("/lib").unwrap();
+        fs.remove_file("/prog").unwrap();
+
+        assert_eq!(
+            fs.statfs().unwrap(),
+            empty,
+            "Freed blocks and inodes should be free again"
+        );
+    }
+}
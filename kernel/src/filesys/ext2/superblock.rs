@@ -0,0 +1,138 @@
+//! ext2 superblock and block group descriptor structures
+
+use super::constants::*;
+use taos_fs::on_disk;
+
+/// Superblock, stored 1024 bytes into the device (1024 bytes)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    /// Blocks only the superuser may allocate
+    pub reserved_blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    /// Block holding the superblock, 1 for 1 KiB blocks and 0 otherwise
+    pub first_data_block: u32,
+    /// Block size is 1024 << log_block_size
+    pub log_block_size: u32,
+    pub log_frag_size: u32,
+    pub blocks_per_group: u32,
+    pub frags_per_group: u32,
+    pub inodes_per_group: u32,
+    /// Last mount time
+    pub mount_time: u32,
+    /// Last write time
+    pub write_time: u32,
+    pub mount_count: u16,
+    pub max_mount_count: i16,
+    pub magic: u16,
+    pub state: u16,
+    pub errors: u16,
+    pub minor_rev_level: u16,
+    pub last_check: u32,
+    pub check_interval: u32,
+    pub creator_os: u32,
+    pub rev_level: u32,
+    pub default_reserved_uid: u16,
+    pub default_reserved_gid: u16,
+    /// First inode not reserved by the filesystem (revision 1)
+    pub first_inode: u32,
+    /// Size of an inode on disk (revision 1)
+    pub inode_size: u16,
+    /// Group this copy of the superblock is stored in
+    pub block_group_nr: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub uuid: [u8; 16],
+    pub volume_name: [u8; 16],
+    pub last_mounted: [u8; 64],
+    pub algorithm_usage_bitmap: u32,
+    /// Performance hints, journaling and padding, all unused
+    pub reserved: [u8; 820],
+}
+
+on_disk!(Superblock, 1024 bytes {
+    0 => inodes_count: u32,
+    4 => blocks_count: u32,
+    8 => reserved_blocks_count: u32,
+    12 => free_blocks_count: u32,
+    16 => free_inodes_count: u32,
+    20 => first_data_block: u32,
+    24 => log_block_size: u32,
+    28 => log_frag_size: u32,
+    32 => blocks_per_group: u32,
+    36 => frags_per_group: u32,
+    40 => inodes_per_group: u32,
+    44 => mount_time: u32,
+    48 => write_time: u32,
+    52 => mount_count: u16,
+    54 => max_mount_count: i16,
+    56 => magic: u16,
+    58 => state: u16,
+    60 => errors: u16,
+    62 => minor_rev_level: u16,
+    64 => last_check: u32,
+    68 => check_interval: u32,
+    72 => creator_os: u32,
+    76 => rev_level: u32,
+    80 => default_reserved_uid: u16,
+    82 => default_reserved_gid: u16,
+    84 => first_inode: u32,
+    88 => inode_size: u16,
+    90 => block_group_nr: u16,
+    92 => feature_compat: u32,
+    96 => feature_incompat: u32,
+    100 => feature_ro_compat: u32,
+    104 => uuid: [u8; 16],
+    120 => volume_name: [u8; 16],
+    136 => last_mounted: [u8; 64],
+    200 => algorithm_usage_bitmap: u32,
+    204 => reserved: [u8; 820],
+});
+
+impl Superblock {
+    pub fn block_size(&self) -> usize {
+        1024 << self.log_block_size
+    }
+
+    pub fn inode_size(&self) -> usize {
+        if self.rev_level >= DYNAMIC_REV {
+            self.inode_size as usize
+        } else {
+            GOOD_OLD_INODE_SIZE
+        }
+    }
+
+    pub fn group_count(&self) -> u32 {
+        (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+}
+
+/// Block group descriptor (32 bytes)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct GroupDescriptor {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    /// First block of the group's inode table
+    pub inode_table: u32,
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub used_dirs_count: u16,
+    pub pad: u16,
+    pub reserved: [u8; 12],
+}
+
+on_disk!(GroupDescriptor, 32 bytes {
+    0 => block_bitmap: u32,
+    4 => inode_bitmap: u32,
+    8 => inode_table: u32,
+    12 => free_blocks_count: u16,
+    14 => free_inodes_count: u16,
+    16 => used_dirs_count: u16,
+    18 => pad: u16,
+    20 => reserved: [u8; 12],
+});
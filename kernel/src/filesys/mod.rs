@@ -1,86 +1,162 @@
 use alloc::{boxed::Box, string::String, vec::Vec};
-use core::result::Result;
+
+pub use taos_fs::{
+    fat16, layout, BlockDevice, DirEntry, File, FileMetadata, FilePermissions, FileSystem,
+    FileTimes, FsError, SeekFrom, StatFs,
+};
 
 pub mod block;
-pub mod fat16;
+pub mod devfs;
+pub mod ext2;
+pub mod ninep;
+pub mod procfs;
 pub mod vfs;
 
-#[derive(Debug)]
-pub enum FsError {
-    NotFound,
-    AlreadyExists,
-    InvalidName,
-    IOError,
-    NotSupported,
-    InvalidOffset,
-    NoSpace,
-    DirectoryNotEmpty,
-}
+#[cfg(test)]
+mod tests {
+    use alloc::{format, sync::Arc, vec};
+    use core::sync::atomic::{AtomicBool, Ordering};
 
-pub trait BlockDevice: Send + Sync {
-    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError>;
-    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError>;
-    fn block_size(&self) -> usize;
-    fn total_blocks(&self) -> u64;
-}
+    use super::{fat16::Fat16, *};
+    use crate::{
+        devices::{manager::find_device_data, sd_card::SDCardInfo},
+        events::schedule_kernel,
+        filesys::block::memory::MemoryBlockDevice,
+    };
 
-/// Represents a file in the filesystem
-pub trait File {
-    fn read_with_device(
-        &mut self,
-        device: &mut dyn BlockDevice,
-        buf: &mut [u8],
-    ) -> Result<usize, FsError>;
-    fn write_with_device(
-        &mut self,
-        device: &mut dyn BlockDevice,
-        buf: &[u8],
-    ) -> Result<usize, FsError>;
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError>;
-    fn flush(&mut self) -> Result<(), FsError>;
-    fn size(&self) -> u64;
-}
+    #[test_case]
+    fn fat_test() {
+        let sd_card = find_device_data::<SDCardInfo>().unwrap().lock().clone();
+        let device = Box::new(sd_card);
 
-#[derive(Debug, Clone)]
-pub struct DirEntry {
-    pub name: String,
-    pub metadata: FileMetadata,
-}
+        // Format the filesystem
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        let empty = fs.statfs().expect("Failed to stat filesystem");
+        assert_eq!(empty.free_blocks, empty.total_blocks);
 
-#[derive(Debug, Clone)]
-pub struct FileMetadata {
-    pub size: u64,
-    pub is_dir: bool,
-    pub created: u64,
-    pub modified: u64,
-    pub permissions: FilePermissions,
-}
+        // Test directory operations
+        fs.create_dir("/test_dir")
+            .expect("Failed to create directory");
+        fs.create_dir("/test_dir/subdir")
+            .expect("Failed to create subdirectory");
 
-#[derive(Debug, Clone)]
-pub struct FilePermissions {
-    pub readable: bool,
-    pub writable: bool,
-    pub executable: bool,
-}
+        // Create a test file
+        fs.create_file("/test_dir/test.txt")
+            .expect("FailedBlockDevice to create file");
+        assert_eq!(
+            fs.statfs().unwrap().free_blocks,
+            empty.free_blocks - 3,
+            "Each new entry takes a cluster"
+        );
+        let fd = fs
+            .open_file("/test_dir/test.txt")
+            .expect("Failed to open file");
 
-pub enum SeekFrom {
-    Start(u64),
-    Current(i64),
-    End(i64),
-}
+        // Write test data
+        let test_data = b"Hello, TaOS FAT16!";
+        let written = fs.write_file(fd, test_data).expect("Failed to write");
+        assert_eq!(written, test_data.len(), "Write length mismatch");
+
+        fs.seek_file(fd, SeekFrom::Start(0))
+            .expect("Failed to seek to start");
+        let mut read_buf = [0u8; 32];
+        let read = fs.read_file(fd, &mut read_buf).expect("Failed to read");
+        assert_eq!(read, test_data.len(), "Read length mismatch");
+        assert_eq!(&read_buf[..read], test_data, "Read data mismatch");
+
+        // List directory contents
+        let entries = fs.read_dir("/test_dir").expect("Failed to read directory");
+        assert!(!entries.is_empty(), "Directory should not be empty");
+        assert_eq!(entries.len(), 2, "Directory should have exactly 2 entries"); // subdir and test.txt
+
+        // Check file metadata
+        let metadata = fs
+            .metadata("/test_dir/test.txt")
+            .expect("Failed to get metadata");
+        assert_eq!(metadata.size, test_data.len() as u64, "File size mismatch");
+        assert!(!metadata.is_dir, "Should not be a directory");
+
+        // Test partial reads
+        fs.seek_file(fd, SeekFrom::Start(7))
+            .expect("Failed to seek");
+        let mut partial_buf = [0u8; 4];
+        let read = fs.read_file(fd, &mut partial_buf).expect("Failed to read");
+        assert_eq!(read, 4, "Partial read length mismatch");
+        assert_eq!(&partial_buf[..read], b"TaOS", "Partial read data mismatch");
+
+        // // Close file and test removal
+        fs.close_file(fd);
+
+        fs.remove_file("/test_dir/test.txt")
+            .expect("Failed to remove file");
+        fs.remove_dir("/test_dir/subdir")
+            .expect("Failed to remove subdirectory");
+        fs.remove_dir("/test_dir")
+            .expect("Failed to remove directory");
+
+        // Verify root is empty
+        let root_entries = fs.read_dir("/").expect("Failed to read root directory");
+        assert_eq!(root_entries.len(), 0, "Root directory should be empty");
+        assert_eq!(
+            fs.statfs().expect("Failed to stat filesystem"),
+            empty,
+            "Removed clusters should be free again"
+        );
+    }
+
+    /// Writes files in `dir`, reading each back, and removes every other one
+    fn churn(fs: &Fat16, dir: &str) {
+        for i in 0..8u8 {
+            let path = format!("{}/file{}", dir, i);
+            fs.create_file(&path).unwrap();
+            let fd = fs.open_file(&path).unwrap();
+            let data = vec![i; 3000];
+            fs.write_file(fd, &data).unwrap();
+            fs.seek_file(fd, SeekFrom::Start(0)).unwrap();
+            let mut read_back = vec![0u8; data.len()];
+            let mut read = 0;
+            while read < read_back.len() {
+                read += fs.read_file(fd, &mut read_back[read..]).unwrap();
+            }
+            assert_eq!(read_back, data);
+            fs.close_file(fd);
+            if i % 2 == 1 {
+                fs.remove_file(&path).unwrap();
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_fat_concurrent_cores() {
+        const AP: u32 = 1;
+        const PRIORITY: usize = 3;
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        let device = Box::new(MemoryBlockDevice::new(2048, 512));
+        let fs = Arc::new(Fat16::format(device).expect("Failed to format filesystem"));
+        fs.create_dir("/bsp").unwrap();
+        fs.create_dir("/ap").unwrap();
+
+        let ap_fs = fs.clone();
+        schedule_kernel(
+            AP,
+            async move {
+                churn(&ap_fs, "/ap");
+                DONE.store(true, Ordering::Release);
+            },
+            PRIORITY,
+        );
+        churn(&fs, "/bsp");
+        while !DONE.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
 
-/// The main filesystem trait that must be implemented by all filesystem types
-pub trait FileSystem {
-    fn create_file(&mut self, path: &str) -> Result<(), FsError>;
-    fn create_dir(&mut self, path: &str) -> Result<(), FsError>;
-    fn remove_file(&mut self, path: &str) -> Result<(), FsError>;
-    fn remove_dir(&mut self, path: &str) -> Result<(), FsError>;
-    fn open_file(&mut self, path: &str) -> Result<usize, FsError>;
-    fn close_file(&mut self, fd: usize);
-    fn write_file(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError>;
-    fn seek_file(&mut self, fd: usize, pos: SeekFrom) -> Result<u64, FsError>;
-    fn read_file(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError>;
-    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError>;
-    fn metadata(&self, path: &str) -> Result<FileMetadata, FsError>;
-    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError>;
+        for dir in ["/bsp", "/ap"] {
+            let entries = fs.read_dir(dir).unwrap();
+            assert_eq!(entries.len(), 4);
+            assert!(entries.iter().all(|entry| entry.metadata.size == 3000));
+        }
+        let report = fs.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
 }
@@ -0,0 +1,275 @@
+//! 9P authentication.
+//!
+//! A client that wants to attach as a user first sends Tauth, which opens
+//! an auth fid. It proves its identity by writing the user's secret to that
+//! fid, and then names the fid in Tattach. `Authenticator` tracks the auth
+//! fids of one connection through those steps, checking secrets against the
+//! kernel's `CredentialStore`.
+//!
+//! The user "none" may attach without authenticating, and is mapped to
+//! `NOBODY_UID`.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use spin::RwLock;
+
+/// Numeric user ID the VFS checks permissions against
+pub type Uid = u32;
+
+/// 9P fid number
+pub type Fid = u32;
+
+/// User every unauthenticated attach runs as
+pub const NOBODY_UID: Uid = 65534;
+
+/// Name of the user that needs no authentication
+pub const ANONYMOUS_USER: &str = "none";
+
+/// Fid value meaning "no auth fid" in Tattach
+pub const NOFID: Fid = u32::MAX;
+
+/// Errors from authenticating a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The user is not in the credential store
+    UnknownUser,
+    /// The written secret does not match
+    BadSecret,
+    /// The fid is already in use
+    FidInUse,
+    /// The fid is not an auth fid
+    UnknownFid,
+    /// Tattach named an auth fid that has not been verified
+    NotAuthenticated,
+    /// Tattach names a different user or tree than Tauth did
+    Mismatch,
+    /// The user "none" needs no authentication
+    NotRequired,
+}
+
+/// A user that may authenticate
+struct User {
+    uid: Uid,
+    secret: Vec<u8>,
+}
+
+/// Table of users and their secrets
+pub struct CredentialStore {
+    users: BTreeMap<String, User>,
+}
+
+/// The kernel's users
+pub static CREDENTIALS: RwLock<CredentialStore> = RwLock::new(CredentialStore::new());
+
+impl Default for CredentialStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CredentialStore {
+    pub const fn new() -> Self {
+        CredentialStore {
+            users: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a user or replaces its uid and secret
+    pub fn add_user(&mut self, uname: &str, uid: Uid, secret: &[u8]) {
+        self.users.insert(
+            uname.to_string(),
+            User {
+                uid,
+                secret: secret.to_vec(),
+            },
+        );
+    }
+
+    pub fn remove_user(&mut self, uname: &str) {
+        self.users.remove(uname);
+    }
+
+    /// Maps a user name to its uid
+    pub fn uid_of(&self, uname: &str) -> Option<Uid> {
+        if uname == ANONYMOUS_USER {
+            return Some(NOBODY_UID);
+        }
+        self.users.get(uname).map(|user| user.uid)
+    }
+
+    /// Checks a user's secret
+    ///
+    /// # Returns
+    /// The user's uid if the secret matches
+    pub fn verify(&self, uname: &str, secret: &[u8]) -> Result<Uid, AuthError> {
+        let user = self.users.get(uname).ok_or(AuthError::UnknownUser)?;
+        if constant_time_eq(&user.secret, secret) {
+            Ok(user.uid)
+        } else {
+            Err(AuthError::BadSecret)
+        }
+    }
+}
+
+/// Compares secrets without leaking the position of the first difference
+/// through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Progress of an auth fid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthState {
+    /// Opened by Tauth, waiting for the secret to be written
+    AwaitingSecret,
+    /// The secret matched
+    Verified(Uid),
+    /// A wrong secret was written. The fid must be clunked.
+    Failed,
+}
+
+/// An auth fid opened by Tauth
+struct AuthFid {
+    uname: String,
+    aname: String,
+    state: AuthState,
+}
+
+/// Auth fids of one 9P connection
+#[derive(Default)]
+pub struct Authenticator {
+    fids: BTreeMap<Fid, AuthFid>,
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Authenticator {
+            fids: BTreeMap::new(),
+        }
+    }
+
+    /// Handles Tauth by opening an auth fid
+    ///
+    /// # Arguments
+    /// * `afid` - The fid the client chose for authentication
+    /// * `uname` - The user to authenticate as
+    /// * `aname` - The tree the client will attach to
+    pub fn auth(&mut self, afid: Fid, uname: &str, aname: &str) -> Result<(), AuthError> {
+        if uname == ANONYMOUS_USER {
+            return Err(AuthError::NotRequired);
+        }
+        if CREDENTIALS.read().uid_of(uname).is_none() {
+            return Err(AuthError::UnknownUser);
+        }
+        if afid == NOFID || self.fids.contains_key(&afid) {
+            return Err(AuthError::FidInUse);
+        }
+        self.fids.insert(
+            afid,
+            AuthFid {
+                uname: uname.to_string(),
+                aname: aname.to_string(),
+                state: AuthState::AwaitingSecret,
+            },
+        );
+        Ok(())
+    }
+
+    /// Handles Twrite to an auth fid, which carries the secret
+    ///
+    /// # Returns
+    /// The number of bytes consumed
+    pub fn write(&mut self, afid: Fid, data: &[u8]) -> Result<usize, AuthError> {
+        let fid = self.fids.get_mut(&afid).ok_or(AuthError::UnknownFid)?;
+        if fid.state != AuthState::AwaitingSecret {
+            return Err(AuthError::BadSecret);
+        }
+        match CREDENTIALS.read().verify(&fid.uname, data) {
+            Ok(uid) => {
+                fid.state = AuthState::Verified(uid);
+                Ok(data.len())
+            }
+            Err(e) => {
+                fid.state = AuthState::Failed;
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the state of an auth fid, which Tread reports to the client
+    pub fn state(&self, afid: Fid) -> Option<AuthState> {
+        self.fids.get(&afid).map(|fid| fid.state)
+    }
+
+    /// Handles Tattach, deciding which user the new fid acts as
+    ///
+    /// # Arguments
+    /// * `afid` - The auth fid, or NOFID for the anonymous user
+    /// * `uname` - The user to attach as
+    /// * `aname` - The tree to attach to
+    pub fn attach(&self, afid: Fid, uname: &str, aname: &str) -> Result<Uid, AuthError> {
+        if afid == NOFID {
+            return if uname == ANONYMOUS_USER {
+                Ok(NOBODY_UID)
+            } else {
+                Err(AuthError::NotAuthenticated)
+            };
+        }
+        let fid = self.fids.get(&afid).ok_or(AuthError::UnknownFid)?;
+        if fid.uname != uname || fid.aname != aname {
+            return Err(AuthError::Mismatch);
+        }
+        match fid.state {
+            AuthState::Verified(uid) => Ok(uid),
+            _ => Err(AuthError::NotAuthenticated),
+        }
+    }
+
+    /// Handles Tclunk of an auth fid
+    pub fn clunk(&mut self, afid: Fid) -> Result<(), AuthError> {
+        self.fids
+            .remove(&afid)
+            .map(|_| ())
+            .ok_or(AuthError::UnknownFid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_auth_fid_state_machine() {
+        CREDENTIALS.write().add_user("glenda", 1000, b"secret");
+        let mut auth = Authenticator::new();
+
+        assert_eq!(auth.attach(NOFID, "none", ""), Ok(NOBODY_UID));
+        assert_eq!(
+            auth.attach(NOFID, "glenda", ""),
+            Err(AuthError::NotAuthenticated)
+        );
+
+        auth.auth(1, "glenda", "").unwrap();
+        assert_eq!(
+            auth.attach(1, "glenda", ""),
+            Err(AuthError::NotAuthenticated)
+        );
+        assert_eq!(auth.write(1, b"secret"), Ok(6));
+        assert_eq!(auth.attach(1, "glenda", "other"), Err(AuthError::Mismatch));
+        assert_eq!(auth.attach(1, "glenda", ""), Ok(1000));
+
+        auth.auth(2, "glenda", "").unwrap();
+        assert_eq!(auth.write(2, b"wrong!"), Err(AuthError::BadSecret));
+        assert_eq!(auth.state(2), Some(AuthState::Failed));
+        assert_eq!(auth.write(2, b"secret"), Err(AuthError::BadSecret));
+        auth.clunk(2).unwrap();
+
+        CREDENTIALS.write().remove_user("glenda");
+    }
+}
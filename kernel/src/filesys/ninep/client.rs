@@ -0,0 +1,739 @@
+//! A 9P2000 client, mounting a tree another server exports into the VFS.
+//!
+//! A `Plan9Client` is one connection, attached to the tree when it is made.
+//! Every path the VFS hands it is walked from the root fid, and each open
+//! file keeps the fid it was opened on along with its own offset, since 9P
+//! reads and writes say where they start. Requests are sent one at a time
+//! through a `Transport` and wait for their reply.
+//!
+//! The VFS calls filesystems with its lock held, so a mounted client must
+//! not talk to a server that needs the same VFS. A `Plan9Server` is itself
+//! a transport, which connects a client to the local VFS directly, but such
+//! a client can only be used outside the VFS. `EndpointTransport` carries
+//! requests over an IPC connection to a server running elsewhere, such as
+//! `serve_endpoint` on another core.
+//!
+//! 9P can only rename a file within its directory, and has no way to
+//! report free space, so `statfs` reports none.
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::cmp::min;
+
+use spin::Mutex;
+
+use super::{
+    auth::{Fid, NOFID},
+    message::{
+        Qid, Rmessage, Stat, Tmessage, DMDIR, IO_HEADER_SIZE, MAX_WALK_NAMES, NOTAG, ORDWR, OREAD,
+        QTDIR, VERSION,
+    },
+    server::{Plan9Server, MAX_MSIZE},
+};
+use crate::{
+    filesys::{
+        DirEntry, FileMetadata, FilePermissions, FileSystem, FileTimes, FsError, SeekFrom, StatFs,
+    },
+    ipc::{
+        bytes::Bytes,
+        channel::{TryRecvError, TrySendError},
+        registry::Endpoint,
+    },
+    time,
+};
+
+/// The fid of the root of the tree, made by Tattach
+const ROOT_FID: Fid = 0;
+/// Only one request is outstanding at a time, so all share a tag
+const TAG: u16 = 1;
+
+/// How long a reply may take before the server is given up on
+const REPLY_TIMEOUT_NS: u64 = 5_000_000_000;
+/// Polls for a reply before giving up while the clock is not calibrated
+const MAX_POLLS: u64 = 100_000_000;
+
+/// Permissions of files and directories the client creates
+const FILE_PERM: u32 = 0o644;
+const DIR_PERM: u32 = 0o755;
+
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+
+/// Carries requests to a server and brings its replies back
+pub trait Transport: Send {
+    /// Sends one request and waits for the reply, both in wire format
+    fn rpc(&mut self, request: &[u8]) -> Result<Vec<u8>, FsError>;
+}
+
+impl Transport for Plan9Server {
+    fn rpc(&mut self, request: &[u8]) -> Result<Vec<u8>, FsError> {
+        Ok(self.serve(request))
+    }
+}
+
+/// A transport over an IPC connection to the server
+pub struct EndpointTransport {
+    endpoint: Endpoint,
+}
+
+impl EndpointTransport {
+    pub fn new(endpoint: Endpoint) -> Self {
+        EndpointTransport { endpoint }
+    }
+}
+
+impl Transport for EndpointTransport {
+    /// Spins until the reply arrives, as filesystems are called with the
+    /// VFS locked and cannot switch out
+    ///
+    /// # Returns
+    /// `FsError::IOError` if the server is gone or does not answer in time
+    fn rpc(&mut self, request: &[u8]) -> Result<Vec<u8>, FsError> {
+        let deadline = time::monotonic_ns().saturating_add(REPLY_TIMEOUT_NS);
+        let mut polls = 0;
+        let mut timed_out = || {
+            polls += 1;
+            core::hint::spin_loop();
+            // Count polls instead while the clock is not calibrated
+            if time::tsc_frequency() == 0 {
+                polls >= MAX_POLLS
+            } else {
+                time::monotonic_ns() >= deadline
+            }
+        };
+
+        // Replies to requests that timed out are of no use any more
+        while self.endpoint.try_recv().is_ok() {}
+
+        let mut request = Bytes::from(request);
+        loop {
+            match self.endpoint.try_send(request) {
+                Ok(()) => break,
+                Err(TrySendError::Full(unsent)) if !timed_out() => request = unsent,
+                Err(_) => return Err(FsError::IOError),
+            }
+        }
+        loop {
+            match self.endpoint.try_recv() {
+                Ok(reply) => return Ok(reply.to_vec()),
+                Err(TryRecvError::Empty) if !timed_out() => {}
+                Err(_) => return Err(FsError::IOError),
+            }
+        }
+    }
+}
+
+/// A file opened through the client
+struct OpenFile {
+    fid: Fid,
+    offset: u64,
+    /// The most bytes one read or write moves
+    iounit: u32,
+}
+
+/// The state of a connection, locked by every call since reading
+/// directories and metadata needs it as well
+struct Session {
+    transport: Box<dyn Transport>,
+    msize: u32,
+    root_qid: Qid,
+    next_fid: Fid,
+    /// Fids clunked and free to be used again
+    free_fids: Vec<Fid>,
+    files: BTreeMap<usize, OpenFile>,
+    next_fd: usize,
+}
+
+/// A tree exported by a 9P server, mounted as a filesystem
+pub struct Plan9Client {
+    session: Mutex<Session>,
+}
+
+impl Plan9Client {
+    /// Starts a session over `transport` and attaches to a tree
+    ///
+    /// # Arguments
+    /// * `uname` - The user to attach as. The client does not authenticate,
+    ///   so the server must let this user in without.
+    /// * `aname` - The tree to attach to, empty for the server's default
+    ///
+    /// # Returns
+    /// `FsError::NotSupported` if the server does not speak 9P2000
+    pub fn connect(
+        transport: Box<dyn Transport>,
+        uname: &str,
+        aname: &str,
+    ) -> Result<Self, FsError> {
+        let mut session = Session {
+            transport,
+            msize: MAX_MSIZE,
+            root_qid: Qid {
+                kind: QTDIR,
+                version: 0,
+                path: 0,
+            },
+            next_fid: ROOT_FID + 1,
+            free_fids: Vec::new(),
+            files: BTreeMap::new(),
+            next_fd: 0,
+        };
+        let version = Tmessage::Version {
+            msize: MAX_MSIZE,
+            version: VERSION.to_string(),
+        };
+        let Rmessage::Version { msize, version } = session.call(version)? else {
+            return Err(FsError::IOError);
+        };
+        if version != VERSION || msize <= IO_HEADER_SIZE as u32 {
+            return Err(FsError::NotSupported);
+        }
+        session.msize = min(msize, MAX_MSIZE);
+
+        let attach = Tmessage::Attach {
+            fid: ROOT_FID,
+            afid: NOFID,
+            uname: uname.to_string(),
+            aname: aname.to_string(),
+        };
+        let Rmessage::Attach { qid } = session.call(attach)? else {
+            return Err(FsError::IOError);
+        };
+        session.root_qid = qid;
+        Ok(Plan9Client {
+            session: Mutex::new(session),
+        })
+    }
+}
+
+impl Session {
+    /// Sends a request and waits for its reply
+    ///
+    /// # Returns
+    /// The reply, or the error an Rerror reply stands for
+    fn call(&mut self, message: Tmessage) -> Result<Rmessage, FsError> {
+        let tag = if matches!(message, Tmessage::Version { .. }) {
+            NOTAG
+        } else {
+            TAG
+        };
+        let reply = self.transport.rpc(&message.encode(tag))?;
+        match Rmessage::decode(&reply) {
+            Ok((reply_tag, _)) if reply_tag != tag => Err(FsError::IOError),
+            Ok((_, Rmessage::Error { ename })) => Err(error_of(&ename)),
+            Ok((_, reply)) => Ok(reply),
+            Err(_) => Err(FsError::IOError),
+        }
+    }
+
+    fn allocate_fid(&mut self) -> Fid {
+        self.free_fids.pop().unwrap_or_else(|| {
+            let fid = self.next_fid;
+            self.next_fid += 1;
+            fid
+        })
+    }
+
+    /// Forgets a fid, which the server does even if the clunk fails
+    fn clunk(&mut self, fid: Fid) {
+        let _ = self.call(Tmessage::Clunk { fid });
+        self.free_fids.push(fid);
+    }
+
+    /// Walks from the root to `path`
+    ///
+    /// # Returns
+    /// A new fid for the file and its qid
+    fn walk(&mut self, path: &str) -> Result<(Fid, Qid), FsError> {
+        let names: Vec<String> = path
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+        let fid = self.allocate_fid();
+        let mut qid = self.root_qid;
+        let mut from = ROOT_FID;
+        // A walk with no names clones the root
+        let mut chunks: Vec<&[String]> = names.chunks(MAX_WALK_NAMES).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        for chunk in chunks {
+            let walked = match self.call(Tmessage::Walk {
+                fid: from,
+                newfid: fid,
+                names: chunk.to_vec(),
+            }) {
+                Ok(Rmessage::Walk { qids }) if qids.len() == chunk.len() => Ok(qids),
+                // The walk stopped part way
+                Ok(Rmessage::Walk { .. }) => Err(FsError::NotFound),
+                Ok(_) => Err(FsError::IOError),
+                Err(e) => Err(e),
+            };
+            match walked {
+                Ok(qids) => qid = qids.last().copied().unwrap_or(qid),
+                Err(e) => {
+                    // After the first chunk the fid exists, and stays where
+                    // the last whole walk left it
+                    if from == fid {
+                        self.clunk(fid);
+                    } else {
+                        self.free_fids.push(fid);
+                    }
+                    return Err(e);
+                }
+            }
+            from = fid;
+        }
+        Ok((fid, qid))
+    }
+
+    /// Runs `f` on a fid walked to `path`, clunking it afterwards
+    fn with_fid<T>(
+        &mut self,
+        path: &str,
+        f: impl FnOnce(&mut Self, Fid, Qid) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let (fid, qid) = self.walk(path)?;
+        let result = f(self, fid, qid);
+        self.clunk(fid);
+        result
+    }
+
+    fn stat(&mut self, fid: Fid) -> Result<Stat, FsError> {
+        let Rmessage::Stat { stat } = self.call(Tmessage::Stat { fid })? else {
+            return Err(FsError::IOError);
+        };
+        Ok(stat)
+    }
+
+    fn wstat(&mut self, path: &str, stat: Stat) -> Result<(), FsError> {
+        self.with_fid(path, |session, fid, _| {
+            match session.call(Tmessage::Wstat { fid, stat })? {
+                Rmessage::Wstat => Ok(()),
+                _ => Err(FsError::IOError),
+            }
+        })
+    }
+
+    /// Creates `path` as a file or directory, from its parent
+    fn create(&mut self, path: &str, perm: u32) -> Result<(), FsError> {
+        let (parent, name) = split_path(path)?;
+        self.with_fid(parent, |session, fid, _| {
+            let create = Tmessage::Create {
+                fid,
+                name: name.to_string(),
+                perm,
+                mode: OREAD,
+            };
+            match session.call(create)? {
+                Rmessage::Create { .. } => Ok(()),
+                _ => Err(FsError::IOError),
+            }
+        })
+    }
+
+    /// Removes `path` if it is a directory exactly when `dir` is set
+    fn remove(&mut self, path: &str, dir: bool) -> Result<(), FsError> {
+        let (fid, qid) = self.walk(path)?;
+        if (qid.kind & QTDIR != 0) != dir {
+            self.clunk(fid);
+            return Err(FsError::NotSupported);
+        }
+        // Tremove forgets the fid whether or not the file goes
+        let result = self.call(Tmessage::Remove { fid });
+        self.free_fids.push(fid);
+        match result? {
+            Rmessage::Remove => Ok(()),
+            _ => Err(FsError::IOError),
+        }
+    }
+
+    /// Opens a walked fid for reading and writing, or for reading if the
+    /// file cannot be written
+    ///
+    /// # Returns
+    /// The iounit of the opened file
+    fn open(&mut self, fid: Fid) -> Result<u32, FsError> {
+        let reply = match self.call(Tmessage::Open { fid, mode: ORDWR }) {
+            Ok(reply) => reply,
+            Err(FsError::PermissionDenied | FsError::ReadOnly) => {
+                self.call(Tmessage::Open { fid, mode: OREAD })?
+            }
+            Err(e) => return Err(e),
+        };
+        let Rmessage::Open { iounit, .. } = reply else {
+            return Err(FsError::IOError);
+        };
+        Ok(self.iounit(iounit))
+    }
+
+    /// The most a read or write of a file may move, given the iounit the
+    /// server sent when it was opened
+    fn iounit(&self, iounit: u32) -> u32 {
+        let most = self.msize - IO_HEADER_SIZE as u32;
+        if iounit == 0 {
+            most
+        } else {
+            min(iounit, most)
+        }
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        self.with_fid(path, |session, fid, qid| {
+            if qid.kind & QTDIR == 0 {
+                return Err(FsError::NotSupported);
+            }
+            let Rmessage::Open { iounit, .. } =
+                session.call(Tmessage::Open { fid, mode: OREAD })?
+            else {
+                return Err(FsError::IOError);
+            };
+            let count = session.iounit(iounit);
+
+            // Directories read as whole stats, until a read returns none
+            let mut entries = Vec::new();
+            let mut offset = 0;
+            loop {
+                let Rmessage::Read { data } =
+                    session.call(Tmessage::Read { fid, offset, count })?
+                else {
+                    return Err(FsError::IOError);
+                };
+                if data.is_empty() {
+                    return Ok(entries);
+                }
+                let mut rest = &data[..];
+                while !rest.is_empty() {
+                    let (stat, used) = Stat::decode(rest).map_err(|_| FsError::IOError)?;
+                    entries.push(DirEntry {
+                        metadata: metadata_of(&stat),
+                        name: stat.name,
+                    });
+                    rest = &rest[used..];
+                }
+                offset += data.len() as u64;
+            }
+        })
+    }
+
+    fn file(&mut self, fd: usize) -> Result<&mut OpenFile, FsError> {
+        self.files.get_mut(&fd).ok_or(FsError::NotFound)
+    }
+}
+
+impl FileSystem for Plan9Client {
+    fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        self.session.get_mut().create(path, FILE_PERM)
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        self.session.get_mut().create(path, DMDIR | DIR_PERM)
+    }
+
+    fn remove_file(&mut self, path: &str) -> Result<(), FsError> {
+        self.session.get_mut().remove(path, false)
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        self.session.get_mut().remove(path, true)
+    }
+
+    fn open_file(&mut self, path: &str) -> Result<usize, FsError> {
+        let session = self.session.get_mut();
+        let (fid, qid) = session.walk(path)?;
+        if qid.kind & QTDIR != 0 {
+            session.clunk(fid);
+            return Err(FsError::NotSupported);
+        }
+        let iounit = match session.open(fid) {
+            Ok(iounit) => iounit,
+            Err(e) => {
+                session.clunk(fid);
+                return Err(e);
+            }
+        };
+        let fd = session.next_fd;
+        session.next_fd += 1;
+        session.files.insert(
+            fd,
+            OpenFile {
+                fid,
+                offset: 0,
+                iounit,
+            },
+        );
+        Ok(fd)
+    }
+
+    fn close_file(&mut self, fd: usize) {
+        let session = self.session.get_mut();
+        let file = session
+            .files
+            .remove(&fd)
+            .expect("Cannot close an invalid file descriptor.");
+        session.clunk(file.fid);
+    }
+
+    fn write_file(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let session = self.session.get_mut();
+        let &mut OpenFile {
+            fid,
+            offset,
+            iounit,
+        } = session.file(fd)?;
+        let mut written = 0;
+        while written < buf.len() {
+            let end = min(buf.len(), written + iounit as usize);
+            let write = Tmessage::Write {
+                fid,
+                offset: offset + written as u64,
+                data: buf[written..end].to_vec(),
+            };
+            let Rmessage::Write { count } = session.call(write)? else {
+                return Err(FsError::IOError);
+            };
+            if count == 0 {
+                break;
+            }
+            written += count as usize;
+        }
+        session.file(fd)?.offset += written as u64;
+        Ok(written)
+    }
+
+    fn seek_file(&mut self, fd: usize, pos: SeekFrom) -> Result<u64, FsError> {
+        let session = self.session.get_mut();
+        let &mut OpenFile { fid, offset, .. } = session.file(fd)?;
+        let new_offset = match pos {
+            SeekFrom::Start(start) => Some(start),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => session.stat(fid)?.length.checked_add_signed(delta),
+        }
+        .ok_or(FsError::InvalidOffset)?;
+        session.file(fd)?.offset = new_offset;
+        Ok(new_offset)
+    }
+
+    fn read_file(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let session = self.session.get_mut();
+        let &mut OpenFile {
+            fid,
+            offset,
+            iounit,
+        } = session.file(fd)?;
+        let count = min(buf.len(), iounit as usize) as u32;
+        let Rmessage::Read { data } = session.call(Tmessage::Read { fid, offset, count })? else {
+            return Err(FsError::IOError);
+        };
+        let read = min(data.len(), buf.len());
+        buf[..read].copy_from_slice(&data[..read]);
+        session.file(fd)?.offset += read as u64;
+        Ok(read)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        self.session.lock().read_dir(path)
+    }
+
+    fn metadata(&self, path: &str) -> Result<FileMetadata, FsError> {
+        let stat = self
+            .session
+            .lock()
+            .with_fid(path, |session, fid, _| session.stat(fid))?;
+        Ok(metadata_of(&stat))
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_parent, _) = split_path(from)?;
+        let (to_parent, name) = split_path(to)?;
+        if from_parent.trim_end_matches('/') != to_parent.trim_end_matches('/') {
+            return Err(FsError::NotSupported);
+        }
+        let stat = Stat {
+            name: name.to_string(),
+            ..Stat::unchanged()
+        };
+        self.session.get_mut().wstat(from, stat)
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        // Writes are sent as they are made
+        Ok(())
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "9p"
+    }
+
+    fn set_times(&mut self, path: &str, times: FileTimes) -> Result<(), FsError> {
+        let unchanged = Stat::unchanged();
+        let stat = Stat {
+            atime: times.accessed.map_or(unchanged.atime, |secs| secs as u32),
+            mtime: times.modified.map_or(unchanged.mtime, |secs| secs as u32),
+            ..unchanged
+        };
+        self.session.get_mut().wstat(path, stat)
+    }
+
+    fn set_permissions(&mut self, path: &str, permissions: FilePermissions) -> Result<(), FsError> {
+        let session = self.session.get_mut();
+        let current = session.with_fid(path, |session, fid, _| session.stat(fid))?;
+        let bits = |allowed: bool, bits: u32| if allowed { bits } else { 0 };
+        let mode = (current.mode & !0o777)
+            | bits(permissions.readable, 0o444)
+            | bits(permissions.writable, 0o222)
+            | bits(permissions.executable, 0o111);
+        let stat = Stat {
+            mode,
+            ..Stat::unchanged()
+        };
+        session.wstat(path, stat)
+    }
+
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        Ok(StatFs::default())
+    }
+}
+
+impl Drop for Plan9Client {
+    fn drop(&mut self) {
+        let session = self.session.get_mut();
+        let fids: Vec<Fid> = session.files.values().map(|file| file.fid).collect();
+        for fid in fids {
+            session.clunk(fid);
+        }
+        session.clunk(ROOT_FID);
+    }
+}
+
+/// Splits a path into its parent directory and last name
+fn split_path(path: &str) -> Result<(&str, &str), FsError> {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(slash) if slash + 1 < path.len() => Ok((&path[..slash + 1], &path[slash + 1..])),
+        _ => Err(FsError::InvalidName),
+    }
+}
+
+fn metadata_of(stat: &Stat) -> FileMetadata {
+    let is_dir = stat.mode & DMDIR != 0;
+    let kind = if is_dir { S_IFDIR } else { S_IFREG };
+    FileMetadata {
+        size: stat.length,
+        is_dir,
+        created: 0,
+        modified: stat.mtime as u64,
+        accessed: stat.atime as u64,
+        permissions: FilePermissions {
+            readable: stat.mode & 0o400 != 0,
+            writable: stat.mode & 0o200 != 0,
+            executable: stat.mode & 0o100 != 0,
+        },
+        mode: Some(kind | (stat.mode & 0o777) as u16),
+        // Servers name owners, which only map back to IDs when numeric
+        uid: stat.uid.parse().unwrap_or(0),
+        gid: stat.gid.parse().unwrap_or(0),
+    }
+}
+
+/// The error an Rerror stands for, from the strings this kernel's server
+/// sends and the usual Plan 9 ones
+fn error_of(ename: &str) -> FsError {
+    match ename {
+        "not found" | "file does not exist" => FsError::NotFound,
+        "already exists" | "file already exists" => FsError::AlreadyExists,
+        "invalid argument" | "bad file name" => FsError::InvalidName,
+        "not empty" | "directory not empty" => FsError::DirectoryNotEmpty,
+        "no space left" => FsError::NoSpace,
+        "busy" => FsError::Busy,
+        "read-only filesystem" => FsError::ReadOnly,
+        "permission denied" => FsError::PermissionDenied,
+        "nested too deeply" => FsError::TooDeep,
+        "not supported" | "is a directory" | "not a directory" | "wstat cannot change this" => {
+            FsError::NotSupported
+        }
+        _ => FsError::IOError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesys::{
+        block::memory::MemoryBlockDevice,
+        fat16::Fat16,
+        vfs::{self, MountOptions},
+    };
+
+    #[test_case]
+    fn test_client_over_loopback() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        vfs::mount("/shared", Box::new(fs), MountOptions::empty()).unwrap();
+        let server = Plan9Server::new("/shared").unwrap();
+        let mut client = Plan9Client::connect(Box::new(server), "none", "").unwrap();
+
+        client.create_dir("/docs").unwrap();
+        client.create_file("/docs/notes").unwrap();
+        assert!(matches!(
+            client.create_file("/docs/notes"),
+            Err(FsError::AlreadyExists)
+        ));
+
+        // Writes larger than a message are split
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let fd = client.open_file("/docs/notes").unwrap();
+        assert_eq!(client.write_file(fd, &data).unwrap(), data.len());
+        assert_eq!(client.seek_file(fd, SeekFrom::End(-4)).unwrap(), 9996);
+        let mut buf = [0; 8];
+        assert_eq!(client.read_file(fd, &mut buf).unwrap(), 4);
+        assert_eq!(buf[..4], data[9996..]);
+        client.close_file(fd);
+        assert_eq!(
+            vfs::lock().metadata("/shared/docs/notes").unwrap().size,
+            10000
+        );
+
+        let entries = client.read_dir("/docs").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "notes");
+        assert_eq!(entries[0].metadata.size, 10000);
+        assert!(client.metadata("/docs").unwrap().is_dir);
+        assert!(matches!(
+            client.open_file("/docs"),
+            Err(FsError::NotSupported)
+        ));
+        assert!(matches!(
+            client.metadata("/docs/missing"),
+            Err(FsError::NotFound)
+        ));
+
+        // Files can only be renamed within their directory
+        client.rename("/docs/notes", "/docs/renamed").unwrap();
+        assert!(client.metadata("/docs/notes").is_err());
+        assert!(matches!(
+            client.rename("/docs/renamed", "/moved"),
+            Err(FsError::NotSupported)
+        ));
+        assert!(matches!(
+            client.remove_dir("/docs"),
+            Err(FsError::DirectoryNotEmpty)
+        ));
+        client.remove_file("/docs/renamed").unwrap();
+        client.remove_dir("/docs").unwrap();
+        assert!(client.read_dir("/").unwrap().is_empty());
+
+        drop(client);
+        vfs::umount("/shared").unwrap();
+
+        // Nobody is serving the other end of the connection
+        let (ours, unserved) = Endpoint::pair(1);
+        drop(unserved);
+        let transport = Box::new(EndpointTransport::new(ours));
+        assert!(Plan9Client::connect(transport, "none", "").is_err());
+    }
+}
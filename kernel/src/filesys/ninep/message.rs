@@ -0,0 +1,825 @@
+//! 9P2000 messages and their wire format.
+//!
+//! Every message starts with a header of its total size (4 bytes), its
+//! type (1 byte) and a tag (2 bytes) the reply echoes, followed by the
+//! type's fields. Integers are little-endian, strings are a 2 byte length
+//! and UTF-8 bytes, and data is a 4 byte length and the bytes. Both the
+//! requests (`Tmessage`) and the replies (`Rmessage`) can be encoded and
+//! decoded, so one codec serves servers and clients alike.
+
+use alloc::{string::String, vec::Vec};
+
+use super::auth::Fid;
+
+/// The only protocol version spoken
+pub const VERSION: &str = "9P2000";
+
+/// Version a server answers with when it speaks none the client offered
+pub const UNKNOWN_VERSION: &str = "unknown";
+
+/// Tag of Tversion, which is sent outside any other exchange
+pub const NOTAG: u16 = u16::MAX;
+
+/// Bytes of size, type and tag before a message's fields
+pub const HEADER_SIZE: usize = 7;
+
+/// Bytes of a Twrite or Rread before its data, so a message of `msize`
+/// bytes carries `msize - IO_HEADER_SIZE` bytes of data
+pub const IO_HEADER_SIZE: usize = 24;
+
+/// Qid type bits
+pub const QTDIR: u8 = 0x80;
+pub const QTAUTH: u8 = 0x08;
+pub const QTFILE: u8 = 0x00;
+
+/// Mode bit of a directory in a `Stat` and in Tcreate's permissions
+pub const DMDIR: u32 = 0x8000_0000;
+
+/// Open modes. The low two bits say how the file is accessed, the rest
+/// are flags.
+pub const OREAD: u8 = 0;
+pub const OWRITE: u8 = 1;
+pub const ORDWR: u8 = 2;
+pub const OEXEC: u8 = 3;
+pub const OTRUNC: u8 = 0x10;
+pub const ORCLOSE: u8 = 0x40;
+
+/// Most names a single Twalk may walk through
+pub const MAX_WALK_NAMES: usize = 16;
+
+const TVERSION: u8 = 100;
+const RVERSION: u8 = 101;
+const TAUTH: u8 = 102;
+const RAUTH: u8 = 103;
+const TATTACH: u8 = 104;
+const RATTACH: u8 = 105;
+const RERROR: u8 = 107;
+const TFLUSH: u8 = 108;
+const RFLUSH: u8 = 109;
+const TWALK: u8 = 110;
+const RWALK: u8 = 111;
+const TOPEN: u8 = 112;
+const ROPEN: u8 = 113;
+const TCREATE: u8 = 114;
+const RCREATE: u8 = 115;
+const TREAD: u8 = 116;
+const RREAD: u8 = 117;
+const TWRITE: u8 = 118;
+const RWRITE: u8 = 119;
+const TCLUNK: u8 = 120;
+const RCLUNK: u8 = 121;
+const TREMOVE: u8 = 122;
+const RREMOVE: u8 = 123;
+const TSTAT: u8 = 124;
+const RSTAT: u8 = 125;
+const TWSTAT: u8 = 126;
+const RWSTAT: u8 = 127;
+
+/// Why bytes could not be decoded as a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end before the message does
+    Truncated,
+    /// The size field does not match the bytes given
+    BadSize,
+    /// The type byte is not a message of the expected direction
+    UnknownType(u8),
+    /// A string is not UTF-8
+    BadString,
+    /// Bytes are left over after the last field
+    TrailingBytes,
+    /// A walk names more than `MAX_WALK_NAMES` elements
+    TooManyNames,
+}
+
+/// The server's unique identification of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Qid {
+    /// `QTDIR`, `QTAUTH` or `QTFILE`
+    pub kind: u8,
+    /// Changes whenever the file does
+    pub version: u32,
+    /// Unique among the server's files
+    pub path: u64,
+}
+
+/// What Tstat reports of a file, and what Twstat changes
+///
+/// In Twstat, fields that should stay as they are hold all ones, or the
+/// empty string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub kind: u16,
+    pub dev: u32,
+    pub qid: Qid,
+    /// Permission bits, with `DMDIR` for directories
+    pub mode: u32,
+    /// Seconds since the Unix epoch
+    pub atime: u32,
+    pub mtime: u32,
+    pub length: u64,
+    /// Last element of the path, "/" for the root
+    pub name: String,
+    pub uid: String,
+    pub gid: String,
+    /// User who last modified the file
+    pub muid: String,
+}
+
+impl Stat {
+    /// A Twstat stat that changes nothing
+    pub fn unchanged() -> Self {
+        Stat {
+            kind: u16::MAX,
+            dev: u32::MAX,
+            qid: Qid {
+                kind: u8::MAX,
+                version: u32::MAX,
+                path: u64::MAX,
+            },
+            mode: u32::MAX,
+            atime: u32::MAX,
+            mtime: u32::MAX,
+            length: u64::MAX,
+            name: String::new(),
+            uid: String::new(),
+            gid: String::new(),
+            muid: String::new(),
+        }
+    }
+
+    /// Encodes the stat with its leading size, as directory reads return
+    /// it
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.stat(self);
+        writer.0
+    }
+
+    /// Decodes one stat from the start of `bytes`, as in a directory read
+    ///
+    /// # Returns
+    /// The stat and the number of bytes it took
+    pub fn decode(bytes: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let stat = reader.stat()?;
+        Ok((stat, reader.pos))
+    }
+}
+
+/// Requests, sent by clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tmessage {
+    /// Starts a session, agreeing on the largest message and the version
+    Version {
+        msize: u32,
+        version: String,
+    },
+    /// Opens an auth fid for authenticating as `uname`
+    Auth {
+        afid: Fid,
+        uname: String,
+        aname: String,
+    },
+    /// Makes `fid` the root of the tree `aname`
+    Attach {
+        fid: Fid,
+        afid: Fid,
+        uname: String,
+        aname: String,
+    },
+    /// Abandons the request tagged `oldtag`
+    Flush {
+        oldtag: u16,
+    },
+    /// Makes `newfid` the file reached by walking `names` from `fid`
+    Walk {
+        fid: Fid,
+        newfid: Fid,
+        names: Vec<String>,
+    },
+    Open {
+        fid: Fid,
+        mode: u8,
+    },
+    /// Creates `name` in the directory `fid`, which becomes the new file,
+    /// opened with `mode`
+    Create {
+        fid: Fid,
+        name: String,
+        perm: u32,
+        mode: u8,
+    },
+    Read {
+        fid: Fid,
+        offset: u64,
+        count: u32,
+    },
+    Write {
+        fid: Fid,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Forgets a fid
+    Clunk {
+        fid: Fid,
+    },
+    /// Removes the file and forgets the fid
+    Remove {
+        fid: Fid,
+    },
+    Stat {
+        fid: Fid,
+    },
+    Wstat {
+        fid: Fid,
+        stat: Stat,
+    },
+}
+
+/// Replies, sent by servers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rmessage {
+    Version {
+        msize: u32,
+        version: String,
+    },
+    Auth {
+        aqid: Qid,
+    },
+    /// The request failed
+    Error {
+        ename: String,
+    },
+    Attach {
+        qid: Qid,
+    },
+    Flush,
+    /// The qids of the elements walked, fewer than asked if the walk
+    /// stopped early
+    Walk {
+        qids: Vec<Qid>,
+    },
+    /// `iounit` is the most bytes a read or write of the file moves at
+    /// once, 0 if only the message size limits it
+    Open {
+        qid: Qid,
+        iounit: u32,
+    },
+    Create {
+        qid: Qid,
+        iounit: u32,
+    },
+    Read {
+        data: Vec<u8>,
+    },
+    Write {
+        count: u32,
+    },
+    Clunk,
+    Remove,
+    Stat {
+        stat: Stat,
+    },
+    Wstat,
+}
+
+impl Tmessage {
+    pub fn encode(&self, tag: u16) -> Vec<u8> {
+        let mut writer = Writer::default();
+        match self {
+            Tmessage::Version { msize, version } => {
+                writer.header(TVERSION, tag);
+                writer.u32(*msize);
+                writer.string(version);
+            }
+            Tmessage::Auth { afid, uname, aname } => {
+                writer.header(TAUTH, tag);
+                writer.u32(*afid);
+                writer.string(uname);
+                writer.string(aname);
+            }
+            Tmessage::Attach {
+                fid,
+                afid,
+                uname,
+                aname,
+            } => {
+                writer.header(TATTACH, tag);
+                writer.u32(*fid);
+                writer.u32(*afid);
+                writer.string(uname);
+                writer.string(aname);
+            }
+            Tmessage::Flush { oldtag } => {
+                writer.header(TFLUSH, tag);
+                writer.u16(*oldtag);
+            }
+            Tmessage::Walk { fid, newfid, names } => {
+                writer.header(TWALK, tag);
+                writer.u32(*fid);
+                writer.u32(*newfid);
+                writer.u16(names.len() as u16);
+                names.iter().for_each(|name| writer.string(name));
+            }
+            Tmessage::Open { fid, mode } => {
+                writer.header(TOPEN, tag);
+                writer.u32(*fid);
+                writer.u8(*mode);
+            }
+            Tmessage::Create {
+                fid,
+                name,
+                perm,
+                mode,
+            } => {
+                writer.header(TCREATE, tag);
+                writer.u32(*fid);
+                writer.string(name);
+                writer.u32(*perm);
+                writer.u8(*mode);
+            }
+            Tmessage::Read { fid, offset, count } => {
+                writer.header(TREAD, tag);
+                writer.u32(*fid);
+                writer.u64(*offset);
+                writer.u32(*count);
+            }
+            Tmessage::Write { fid, offset, data } => {
+                writer.header(TWRITE, tag);
+                writer.u32(*fid);
+                writer.u64(*offset);
+                writer.data(data);
+            }
+            Tmessage::Clunk { fid } => {
+                writer.header(TCLUNK, tag);
+                writer.u32(*fid);
+            }
+            Tmessage::Remove { fid } => {
+                writer.header(TREMOVE, tag);
+                writer.u32(*fid);
+            }
+            Tmessage::Stat { fid } => {
+                writer.header(TSTAT, tag);
+                writer.u32(*fid);
+            }
+            Tmessage::Wstat { fid, stat } => {
+                writer.header(TWSTAT, tag);
+                writer.u32(*fid);
+                writer.sized_stat(stat);
+            }
+        }
+        writer.finish()
+    }
+
+    /// Decodes a whole request
+    ///
+    /// # Returns
+    /// The request's tag and the request
+    pub fn decode(bytes: &[u8]) -> Result<(u16, Self), DecodeError> {
+        let (kind, tag, mut reader) = Reader::message(bytes)?;
+        let message = match kind {
+            TVERSION => Tmessage::Version {
+                msize: reader.u32()?,
+                version: reader.string()?,
+            },
+            TAUTH => Tmessage::Auth {
+                afid: reader.u32()?,
+                uname: reader.string()?,
+                aname: reader.string()?,
+            },
+            TATTACH => Tmessage::Attach {
+                fid: reader.u32()?,
+                afid: reader.u32()?,
+                uname: reader.string()?,
+                aname: reader.string()?,
+            },
+            TFLUSH => Tmessage::Flush {
+                oldtag: reader.u16()?,
+            },
+            TWALK => {
+                let fid = reader.u32()?;
+                let newfid = reader.u32()?;
+                let count = reader.u16()? as usize;
+                if count > MAX_WALK_NAMES {
+                    return Err(DecodeError::TooManyNames);
+                }
+                let names = (0..count)
+                    .map(|_| reader.string())
+                    .collect::<Result<_, _>>()?;
+                Tmessage::Walk { fid, newfid, names }
+            }
+            TOPEN => Tmessage::Open {
+                fid: reader.u32()?,
+                mode: reader.u8()?,
+            },
+            TCREATE => Tmessage::Create {
+                fid: reader.u32()?,
+                name: reader.string()?,
+                perm: reader.u32()?,
+                mode: reader.u8()?,
+            },
+            TREAD => Tmessage::Read {
+                fid: reader.u32()?,
+                offset: reader.u64()?,
+                count: reader.u32()?,
+            },
+            TWRITE => Tmessage::Write {
+                fid: reader.u32()?,
+                offset: reader.u64()?,
+                data: reader.data()?,
+            },
+            TCLUNK => Tmessage::Clunk { fid: reader.u32()? },
+            TREMOVE => Tmessage::Remove { fid: reader.u32()? },
+            TSTAT => Tmessage::Stat { fid: reader.u32()? },
+            TWSTAT => Tmessage::Wstat {
+                fid: reader.u32()?,
+                stat: reader.sized_stat()?,
+            },
+            kind => return Err(DecodeError::UnknownType(kind)),
+        };
+        reader.finish()?;
+        Ok((tag, message))
+    }
+}
+
+impl Rmessage {
+    pub fn encode(&self, tag: u16) -> Vec<u8> {
+        let mut writer = Writer::default();
+        match self {
+            Rmessage::Version { msize, version } => {
+                writer.header(RVERSION, tag);
+                writer.u32(*msize);
+                writer.string(version);
+            }
+            Rmessage::Auth { aqid } => {
+                writer.header(RAUTH, tag);
+                writer.qid(aqid);
+            }
+            Rmessage::Error { ename } => {
+                writer.header(RERROR, tag);
+                writer.string(ename);
+            }
+            Rmessage::Attach { qid } => {
+                writer.header(RATTACH, tag);
+                writer.qid(qid);
+            }
+            Rmessage::Flush => writer.header(RFLUSH, tag),
+            Rmessage::Walk { qids } => {
+                writer.header(RWALK, tag);
+                writer.u16(qids.len() as u16);
+                qids.iter().for_each(|qid| writer.qid(qid));
+            }
+            Rmessage::Open { qid, iounit } => {
+                writer.header(ROPEN, tag);
+                writer.qid(qid);
+                writer.u32(*iounit);
+            }
+            Rmessage::Create { qid, iounit } => {
+                writer.header(RCREATE, tag);
+                writer.qid(qid);
+                writer.u32(*iounit);
+            }
+            Rmessage::Read { data } => {
+                writer.header(RREAD, tag);
+                writer.data(data);
+            }
+            Rmessage::Write { count } => {
+                writer.header(RWRITE, tag);
+                writer.u32(*count);
+            }
+            Rmessage::Clunk => writer.header(RCLUNK, tag),
+            Rmessage::Remove => writer.header(RREMOVE, tag),
+            Rmessage::Stat { stat } => {
+                writer.header(RSTAT, tag);
+                writer.sized_stat(stat);
+            }
+            Rmessage::Wstat => writer.header(RWSTAT, tag),
+        }
+        writer.finish()
+    }
+
+    /// Decodes a whole reply
+    ///
+    /// # Returns
+    /// The tag of the request answered, and the reply
+    pub fn decode(bytes: &[u8]) -> Result<(u16, Self), DecodeError> {
+        let (kind, tag, mut reader) = Reader::message(bytes)?;
+        let message = match kind {
+            RVERSION => Rmessage::Version {
+                msize: reader.u32()?,
+                version: reader.string()?,
+            },
+            RAUTH => Rmessage::Auth {
+                aqid: reader.qid()?,
+            },
+            RERROR => Rmessage::Error {
+                ename: reader.string()?,
+            },
+            RATTACH => Rmessage::Attach { qid: reader.qid()? },
+            RFLUSH => Rmessage::Flush,
+            RWALK => {
+                let count = reader.u16()? as usize;
+                if count > MAX_WALK_NAMES {
+                    return Err(DecodeError::TooManyNames);
+                }
+                let qids = (0..count).map(|_| reader.qid()).collect::<Result<_, _>>()?;
+                Rmessage::Walk { qids }
+            }
+            ROPEN => Rmessage::Open {
+                qid: reader.qid()?,
+                iounit: reader.u32()?,
+            },
+            RCREATE => Rmessage::Create {
+                qid: reader.qid()?,
+                iounit: reader.u32()?,
+            },
+            RREAD => Rmessage::Read {
+                data: reader.data()?,
+            },
+            RWRITE => Rmessage::Write {
+                count: reader.u32()?,
+            },
+            RCLUNK => Rmessage::Clunk,
+            RREMOVE => Rmessage::Remove,
+            RSTAT => Rmessage::Stat {
+                stat: reader.sized_stat()?,
+            },
+            RWSTAT => Rmessage::Wstat,
+            kind => return Err(DecodeError::UnknownType(kind)),
+        };
+        reader.finish()?;
+        Ok((tag, message))
+    }
+}
+
+/// Reads the size field at the start of a message
+///
+/// # Returns
+/// The size of the whole message, or None if fewer than 4 bytes are given
+pub fn message_size(bytes: &[u8]) -> Option<usize> {
+    let size = bytes.get(..4)?;
+    Some(u32::from_le_bytes(size.try_into().unwrap()) as usize)
+}
+
+/// Builds a message, leaving room for its size at the front
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn header(&mut self, kind: u8, tag: u16) {
+        self.u32(0);
+        self.u8(kind);
+        self.u16(tag);
+    }
+
+    /// Fills in the size and returns the message
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a string, cut short at 64 KiB - 1 bytes
+    fn string(&mut self, value: &str) {
+        let mut len = value.len().min(u16::MAX as usize);
+        while !value.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.u16(len as u16);
+        self.0.extend_from_slice(&value.as_bytes()[..len]);
+    }
+
+    fn data(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.0.extend_from_slice(data);
+    }
+
+    fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.kind);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+
+    /// Writes a stat, starting with the size of the rest of it
+    fn stat(&mut self, stat: &Stat) {
+        let start = self.0.len();
+        self.u16(0);
+        self.u16(stat.kind);
+        self.u32(stat.dev);
+        self.qid(&stat.qid);
+        self.u32(stat.mode);
+        self.u32(stat.atime);
+        self.u32(stat.mtime);
+        self.u64(stat.length);
+        self.string(&stat.name);
+        self.string(&stat.uid);
+        self.string(&stat.gid);
+        self.string(&stat.muid);
+        let size = (self.0.len() - start - 2) as u16;
+        self.0[start..start + 2].copy_from_slice(&size.to_le_bytes());
+    }
+
+    /// Writes a stat as Rstat and Twstat carry it, with a second size in
+    /// front
+    fn sized_stat(&mut self, stat: &Stat) {
+        let encoded = stat.encode();
+        self.u16(encoded.len() as u16);
+        self.0.extend_from_slice(&encoded);
+    }
+}
+
+/// Takes the fields of a message apart
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Checks a message's size and reads its header
+    ///
+    /// # Returns
+    /// The message's type and tag, and a reader at its first field
+    fn message(bytes: &'a [u8]) -> Result<(u8, u16, Self), DecodeError> {
+        let size = message_size(bytes).ok_or(DecodeError::Truncated)?;
+        if size != bytes.len() {
+            return Err(DecodeError::BadSize);
+        }
+        let mut reader = Reader { bytes, pos: 4 };
+        let kind = reader.u8()?;
+        let tag = reader.u16()?;
+        Ok((kind, tag, reader))
+    }
+
+    /// Fails if any bytes are left
+    fn finish(&self) -> Result<(), DecodeError> {
+        if self.pos == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes)
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.pos.checked_add(len).ok_or(DecodeError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(DecodeError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        core::str::from_utf8(bytes)
+            .map(String::from)
+            .map_err(|_| DecodeError::BadString)
+    }
+
+    fn data(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn qid(&mut self) -> Result<Qid, DecodeError> {
+        Ok(Qid {
+            kind: self.u8()?,
+            version: self.u32()?,
+            path: self.u64()?,
+        })
+    }
+
+    fn stat(&mut self) -> Result<Stat, DecodeError> {
+        let size = self.u16()? as usize;
+        let mut fields = Reader {
+            bytes: self.take(size)?,
+            pos: 0,
+        };
+        let stat = Stat {
+            kind: fields.u16()?,
+            dev: fields.u32()?,
+            qid: fields.qid()?,
+            mode: fields.u32()?,
+            atime: fields.u32()?,
+            mtime: fields.u32()?,
+            length: fields.u64()?,
+            name: fields.string()?,
+            uid: fields.string()?,
+            gid: fields.string()?,
+            muid: fields.string()?,
+        };
+        // Newer dialects append fields, which 9P2000 ignores
+        Ok(stat)
+    }
+
+    fn sized_stat(&mut self) -> Result<Stat, DecodeError> {
+        let size = self.u16()? as usize;
+        let mut inner = Reader {
+            bytes: self.take(size)?,
+            pos: 0,
+        };
+        let stat = inner.stat()?;
+        inner.finish()?;
+        Ok(stat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec};
+
+    #[test_case]
+    fn test_message_round_trip() {
+        let stat = Stat {
+            kind: 0,
+            dev: 0,
+            qid: Qid {
+                kind: QTDIR,
+                version: 3,
+                path: 0x1234,
+            },
+            mode: DMDIR | 0o755,
+            atime: 1,
+            mtime: 2,
+            length: 0,
+            name: "dir".to_string(),
+            uid: "glenda".to_string(),
+            gid: "glenda".to_string(),
+            muid: String::new(),
+        };
+        let requests = [
+            Tmessage::Version {
+                msize: 8192,
+                version: VERSION.to_string(),
+            },
+            Tmessage::Walk {
+                fid: 1,
+                newfid: 2,
+                names: vec!["a".to_string(), "b".to_string()],
+            },
+            Tmessage::Write {
+                fid: 2,
+                offset: 5,
+                data: b"hello".to_vec(),
+            },
+            Tmessage::Wstat {
+                fid: 2,
+                stat: stat.clone(),
+            },
+        ];
+        for request in requests {
+            let bytes = request.encode(7);
+            assert_eq!(message_size(&bytes), Some(bytes.len()));
+            assert_eq!(Tmessage::decode(&bytes), Ok((7, request)));
+        }
+        let reply = Rmessage::Stat { stat };
+        assert_eq!(Rmessage::decode(&reply.encode(9)), Ok((9, reply)));
+
+        // The wire format of a Tclunk, byte for byte
+        let clunk = Tmessage::Clunk { fid: 0x0102 }.encode(NOTAG);
+        assert_eq!(clunk, [11, 0, 0, 0, 120, 0xFF, 0xFF, 2, 1, 0, 0]);
+
+        assert_eq!(Tmessage::decode(&clunk[..10]), Err(DecodeError::BadSize));
+        let mut reply_type = clunk.clone();
+        reply_type[4] = 121;
+        assert_eq!(
+            Tmessage::decode(&reply_type),
+            Err(DecodeError::UnknownType(121))
+        );
+    }
+}
@@ -0,0 +1,11 @@
+//! 9P2000 file protocol support.
+//!
+//! - `message` encodes and decodes the protocol's messages
+//! - `auth` checks who a connection attaches as
+//! - `server` exports a VFS subtree to a client over any transport
+//! - `client` mounts a tree a server exports as a filesystem
+
+pub mod auth;
+pub mod client;
+pub mod message;
+pub mod server;
@@ -0,0 +1,861 @@
+//! A 9P2000 server exporting a subtree of the VFS.
+//!
+//! A `Plan9Server` is one client connection. It keeps the fids the client
+//! has made, each naming a VFS path and, once opened, a VFS descriptor or
+//! a snapshot of a directory's entries. Requests come in and replies go
+//! out in wire format, and how the bytes travel is left to the caller, or
+//! to `serve_endpoint` for a connection made through the IPC registry.
+//! Requests are answered one at a time, in order, so Tflush never has
+//! anything to abandon.
+//!
+//! Filesystems have no inode numbers to offer, so qid paths are hashes of
+//! VFS paths and qid versions are modification times. Filesystems cannot
+//! shorten files either, so an open with `OTRUNC` re-creates the file,
+//! resetting its times and permissions. Twstat can rename a file within
+//! its directory and set its times, and refuses any other change.
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::cmp::min;
+
+use super::{
+    auth::{AuthError, Authenticator, Fid, NOFID},
+    message::{
+        message_size, Qid, Rmessage, Stat, Tmessage, DMDIR, HEADER_SIZE, IO_HEADER_SIZE, NOTAG,
+        OEXEC, ORCLOSE, ORDWR, OREAD, OTRUNC, OWRITE, QTAUTH, QTDIR, QTFILE, UNKNOWN_VERSION,
+        VERSION,
+    },
+};
+use crate::{
+    error::KError,
+    filesys::{
+        vfs::{self, normalize},
+        FileMetadata, FileTimes, FsError, SeekFrom,
+    },
+    ipc::{bytes::Bytes, registry::Endpoint},
+};
+
+/// Largest message the server accepts or sends, room for 8 KiB of data
+pub const MAX_MSIZE: u32 = 8192 + IO_HEADER_SIZE as u32;
+
+/// Smallest message size a client may ask for
+const MIN_MSIZE: u32 = 256;
+
+/// The low bits of an open mode, which say how the file is accessed
+const ACCESS_MASK: u8 = 3;
+
+const UNKNOWN_FID: &str = "unknown fid";
+const FID_IN_USE: &str = "fid already in use";
+const FID_OPEN: &str = "fid is open";
+const FID_NOT_OPEN: &str = "fid is not open";
+const BAD_MODE: &str = "file not open for this access";
+const NOT_DIRECTORY: &str = "not a directory";
+const IS_DIRECTORY: &str = "is a directory";
+const BAD_NAME: &str = "bad file name";
+const BAD_OFFSET: &str = "bad offset in directory read";
+const CANNOT_CHANGE: &str = "wstat cannot change this";
+const MALFORMED: &str = "malformed message";
+const TOO_LONG: &str = "message larger than msize";
+
+/// A fid opened by Topen or Tcreate
+struct Opened {
+    mode: u8,
+    kind: OpenKind,
+}
+
+enum OpenKind {
+    /// A VFS descriptor
+    File(usize),
+    /// The directory's entries as encoded stats, from when it was opened
+    Dir {
+        entries: Vec<Vec<u8>>,
+        /// The first entry the next read returns
+        next: usize,
+        /// The offset the next read must ask for
+        offset: u64,
+    },
+}
+
+/// A fid naming a file in the exported tree
+struct ServerFid {
+    /// Normalized VFS path
+    path: String,
+    opened: Option<Opened>,
+}
+
+/// The server side of one 9P connection
+pub struct Plan9Server {
+    /// Normalized VFS path of the exported tree
+    root: String,
+    /// The largest message agreed on by Tversion
+    msize: u32,
+    auth: Authenticator,
+    fids: BTreeMap<Fid, ServerFid>,
+}
+
+impl Plan9Server {
+    /// Creates a connection exporting the VFS tree at `root`
+    ///
+    /// # Returns
+    /// `FsError::InvalidName` if `root` is not absolute
+    pub fn new(root: &str) -> Result<Self, FsError> {
+        Ok(Plan9Server {
+            root: normalize(root)?,
+            msize: MAX_MSIZE,
+            auth: Authenticator::new(),
+            fids: BTreeMap::new(),
+        })
+    }
+
+    /// Handles one request in wire format
+    ///
+    /// # Returns
+    /// The reply in wire format, Rerror if the request cannot be decoded
+    pub fn serve(&mut self, request: &[u8]) -> Vec<u8> {
+        let tag = request
+            .get(HEADER_SIZE - 2..HEADER_SIZE)
+            .map_or(NOTAG, |tag| u16::from_le_bytes([tag[0], tag[1]]));
+        if message_size(request).is_some_and(|size| size > self.msize as usize) {
+            return error(TOO_LONG).encode(tag);
+        }
+        match Tmessage::decode(request) {
+            Ok((tag, message)) => self.handle(message).encode(tag),
+            Err(_) => error(MALFORMED).encode(tag),
+        }
+    }
+
+    /// Answers the requests arriving on a connection, until the client
+    /// goes away
+    pub async fn serve_endpoint(&mut self, endpoint: &Endpoint) {
+        while let Some(request) = endpoint.recv().await {
+            let reply = Bytes::from(self.serve(&request));
+            if endpoint.send(reply).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Handles one decoded request
+    pub fn handle(&mut self, message: Tmessage) -> Rmessage {
+        let reply = match message {
+            Tmessage::Version { msize, version } => Ok(self.version(msize, &version)),
+            Tmessage::Auth { afid, uname, aname } => self.auth(afid, &uname, &aname),
+            Tmessage::Attach {
+                fid,
+                afid,
+                uname,
+                aname,
+            } => self.attach(fid, afid, &uname, &aname),
+            Tmessage::Flush { .. } => Ok(Rmessage::Flush),
+            Tmessage::Walk { fid, newfid, names } => self.walk(fid, newfid, &names),
+            Tmessage::Open { fid, mode } => self.open(fid, mode),
+            Tmessage::Create {
+                fid,
+                name,
+                perm,
+                mode,
+            } => self.create(fid, &name, perm, mode),
+            Tmessage::Read { fid, offset, count } => self.read(fid, offset, count),
+            Tmessage::Write { fid, offset, data } => self.write(fid, offset, &data),
+            Tmessage::Clunk { fid } => self.clunk(fid, false).map(|()| Rmessage::Clunk),
+            Tmessage::Remove { fid } => self.clunk(fid, true).map(|()| Rmessage::Remove),
+            Tmessage::Stat { fid } => self.stat(fid),
+            Tmessage::Wstat { fid, stat } => self.wstat(fid, &stat),
+        };
+        reply.unwrap_or_else(error)
+    }
+
+    fn version(&mut self, msize: u32, version: &str) -> Rmessage {
+        // A new session forgets everything the old one made
+        let fids: Vec<Fid> = self.fids.keys().copied().collect();
+        for fid in fids {
+            let _ = self.clunk(fid, false);
+        }
+        self.auth = Authenticator::new();
+
+        // Dialects such as 9P2000.u fall back to plain 9P2000
+        let known = version == VERSION || version.starts_with("9P2000.");
+        if !known || msize < MIN_MSIZE {
+            return Rmessage::Version {
+                msize: self.msize,
+                version: UNKNOWN_VERSION.to_string(),
+            };
+        }
+        self.msize = min(msize, MAX_MSIZE);
+        Rmessage::Version {
+            msize: self.msize,
+            version: VERSION.to_string(),
+        }
+    }
+
+    fn auth(&mut self, afid: Fid, uname: &str, aname: &str) -> Result<Rmessage, &'static str> {
+        if self.fids.contains_key(&afid) {
+            return Err(FID_IN_USE);
+        }
+        self.auth.auth(afid, uname, aname).map_err(auth_error)?;
+        Ok(Rmessage::Auth {
+            aqid: Qid {
+                kind: QTAUTH,
+                version: 0,
+                path: afid as u64,
+            },
+        })
+    }
+
+    fn attach(
+        &mut self,
+        fid: Fid,
+        afid: Fid,
+        uname: &str,
+        aname: &str,
+    ) -> Result<Rmessage, &'static str> {
+        if fid == NOFID || self.is_in_use(fid) {
+            return Err(FID_IN_USE);
+        }
+        // The VFS has no per-user permissions, so who attached only
+        // decides whether the attach is allowed
+        self.auth.attach(afid, uname, aname).map_err(auth_error)?;
+        let path = if aname.is_empty() {
+            self.root.clone()
+        } else {
+            self.join(&self.root, aname)?
+        };
+        let metadata = vfs::lock().metadata(&path).map_err(fs_error)?;
+        if !metadata.is_dir {
+            return Err(NOT_DIRECTORY);
+        }
+        let qid = qid_of(&path, &metadata);
+        self.fids.insert(fid, ServerFid { path, opened: None });
+        Ok(Rmessage::Attach { qid })
+    }
+
+    fn walk(&mut self, fid: Fid, newfid: Fid, names: &[String]) -> Result<Rmessage, &'static str> {
+        let from = self.fids.get(&fid).ok_or(UNKNOWN_FID)?;
+        if from.opened.is_some() {
+            return Err(FID_OPEN);
+        }
+        if newfid != fid && self.is_in_use(newfid) {
+            return Err(FID_IN_USE);
+        }
+        let mut path = from.path.clone();
+        let mut qids = Vec::new();
+        let mut vfs = vfs::lock();
+        for name in names {
+            // Only directories can be walked through, and a walk that
+            // fails part way reports how far it got
+            let step = match vfs.metadata(&path) {
+                Ok(metadata) if !metadata.is_dir => Err(NOT_DIRECTORY),
+                Ok(_) => self.step(&path, name),
+                Err(e) => Err(fs_error(e)),
+            }
+            .and_then(|next| {
+                let metadata = vfs.metadata(&next).map_err(fs_error)?;
+                Ok((next, metadata))
+            });
+            match step {
+                Ok((next, metadata)) => {
+                    qids.push(qid_of(&next, &metadata));
+                    path = next;
+                }
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        drop(vfs);
+        if qids.len() == names.len() {
+            self.fids.insert(newfid, ServerFid { path, opened: None });
+        }
+        Ok(Rmessage::Walk { qids })
+    }
+
+    fn open(&mut self, fid: Fid, mode: u8) -> Result<Rmessage, &'static str> {
+        let iounit = self.iounit();
+        let state = self.fids.get_mut(&fid).ok_or(UNKNOWN_FID)?;
+        let qid = open_fid(state, mode)?;
+        Ok(Rmessage::Open { qid, iounit })
+    }
+
+    fn create(
+        &mut self,
+        fid: Fid,
+        name: &str,
+        perm: u32,
+        mode: u8,
+    ) -> Result<Rmessage, &'static str> {
+        let iounit = self.iounit();
+        let state = self.fids.get(&fid).ok_or(UNKNOWN_FID)?;
+        if state.opened.is_some() {
+            return Err(FID_OPEN);
+        }
+        if name == "." || name == ".." {
+            return Err(BAD_NAME);
+        }
+        let path = self.step(&state.path, name)?;
+        {
+            let mut vfs = vfs::lock();
+            if !vfs.metadata(&state.path).map_err(fs_error)?.is_dir {
+                return Err(NOT_DIRECTORY);
+            }
+            if perm & DMDIR != 0 {
+                vfs.create_dir(&path)
+            } else {
+                vfs.create_file(&path)
+            }
+            .map_err(fs_error)?;
+        }
+
+        // The fid now stands for the new file
+        let state = self.fids.get_mut(&fid).ok_or(UNKNOWN_FID)?;
+        state.path = path;
+        let qid = open_fid(state, mode & !OTRUNC)?;
+        Ok(Rmessage::Create { qid, iounit })
+    }
+
+    fn read(&mut self, fid: Fid, offset: u64, count: u32) -> Result<Rmessage, &'static str> {
+        let count = min(count, self.iounit()) as usize;
+        if self.auth.state(fid).is_some() {
+            // Secrets are only ever written
+            return Ok(Rmessage::Read { data: Vec::new() });
+        }
+        let state = self.fids.get_mut(&fid).ok_or(UNKNOWN_FID)?;
+        let opened = state.opened.as_mut().ok_or(FID_NOT_OPEN)?;
+        if !matches!(opened.mode & ACCESS_MASK, OREAD | ORDWR | OEXEC) {
+            return Err(BAD_MODE);
+        }
+        let data = match &mut opened.kind {
+            OpenKind::File(fd) => {
+                let mut vfs = vfs::lock();
+                vfs.seek(*fd, SeekFrom::Start(offset)).map_err(fs_error)?;
+                let mut data = vec![0; count];
+                let mut read = 0;
+                while read < count {
+                    match vfs.read(*fd, &mut data[read..]).map_err(fs_error)? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+                data.truncate(read);
+                data
+            }
+            OpenKind::Dir {
+                entries,
+                next,
+                offset: expected,
+            } => {
+                // Directories are read from the start or from where the
+                // last read stopped, in whole entries
+                if offset == 0 {
+                    *next = 0;
+                    *expected = 0;
+                } else if offset != *expected {
+                    return Err(BAD_OFFSET);
+                }
+                let mut data = Vec::new();
+                while let Some(entry) = entries.get(*next) {
+                    if data.len() + entry.len() > count {
+                        break;
+                    }
+                    data.extend_from_slice(entry);
+                    *next += 1;
+                }
+                *expected += data.len() as u64;
+                data
+            }
+        };
+        Ok(Rmessage::Read { data })
+    }
+
+    fn write(&mut self, fid: Fid, offset: u64, data: &[u8]) -> Result<Rmessage, &'static str> {
+        if self.auth.state(fid).is_some() {
+            let count = self.auth.write(fid, data).map_err(auth_error)?;
+            return Ok(Rmessage::Write {
+                count: count as u32,
+            });
+        }
+        let state = self.fids.get_mut(&fid).ok_or(UNKNOWN_FID)?;
+        let opened = state.opened.as_ref().ok_or(FID_NOT_OPEN)?;
+        if !matches!(opened.mode & ACCESS_MASK, OWRITE | ORDWR) {
+            return Err(BAD_MODE);
+        }
+        let OpenKind::File(fd) = opened.kind else {
+            return Err(IS_DIRECTORY);
+        };
+        let mut vfs = vfs::lock();
+        vfs.seek(fd, SeekFrom::Start(offset)).map_err(fs_error)?;
+        let mut written = 0;
+        while written < data.len() {
+            match vfs.write(fd, &data[written..]).map_err(fs_error)? {
+                0 => break,
+                n => written += n,
+            }
+        }
+        Ok(Rmessage::Write {
+            count: written as u32,
+        })
+    }
+
+    /// Handles Tclunk, and Tremove if `remove` is set. The fid is
+    /// forgotten even if removing the file fails.
+    fn clunk(&mut self, fid: Fid, remove: bool) -> Result<(), &'static str> {
+        if self.auth.state(fid).is_some() {
+            return self.auth.clunk(fid).map_err(auth_error);
+        }
+        let state = self.fids.remove(&fid).ok_or(UNKNOWN_FID)?;
+        let mut vfs = vfs::lock();
+        let mut remove = remove;
+        if let Some(opened) = state.opened {
+            if let OpenKind::File(fd) = opened.kind {
+                let _ = vfs.close(fd);
+            }
+            remove |= opened.mode & ORCLOSE != 0;
+        }
+        if !remove {
+            return Ok(());
+        }
+        if state.path == self.root {
+            return Err(fs_error(FsError::PermissionDenied));
+        }
+        let metadata = vfs.metadata(&state.path).map_err(fs_error)?;
+        if metadata.is_dir {
+            vfs.remove_dir(&state.path)
+        } else {
+            vfs.remove_file(&state.path)
+        }
+        .map_err(fs_error)
+    }
+
+    fn stat(&mut self, fid: Fid) -> Result<Rmessage, &'static str> {
+        let state = self.fids.get(&fid).ok_or(UNKNOWN_FID)?;
+        let metadata = vfs::lock().metadata(&state.path).map_err(fs_error)?;
+        Ok(Rmessage::Stat {
+            stat: stat_of(&state.path, &metadata),
+        })
+    }
+
+    fn wstat(&mut self, fid: Fid, stat: &Stat) -> Result<Rmessage, &'static str> {
+        let state = self.fids.get(&fid).ok_or(UNKNOWN_FID)?;
+        let mut vfs = vfs::lock();
+        let metadata = vfs.metadata(&state.path).map_err(fs_error)?;
+
+        // Every change is checked before any is made
+        let unchanged = Stat::unchanged();
+        let current = stat_of(&state.path, &metadata);
+        let keeps = |new: u64, old: u64, dont_touch: u64| new == dont_touch || new == old;
+        if stat.kind != unchanged.kind
+            || stat.dev != unchanged.dev
+            || !keeps(stat.mode as u64, current.mode as u64, u32::MAX as u64)
+            || !keeps(stat.length, current.length, u64::MAX)
+            || !stat.uid.is_empty()
+            || !stat.gid.is_empty()
+            || !stat.muid.is_empty()
+        {
+            return Err(CANNOT_CHANGE);
+        }
+        let renamed = if stat.name.is_empty() || stat.name == current.name {
+            None
+        } else if state.path == self.root || stat.name.contains('/') || stat.name == ".." {
+            return Err(BAD_NAME);
+        } else {
+            let parent = parent_of(&state.path);
+            Some(self.step(parent, &stat.name)?)
+        };
+
+        let times = FileTimes {
+            accessed: (stat.atime != u32::MAX).then_some(stat.atime as u64),
+            modified: (stat.mtime != u32::MAX).then_some(stat.mtime as u64),
+        };
+        if times.accessed.is_some() || times.modified.is_some() {
+            vfs.set_times(&state.path, times).map_err(fs_error)?;
+        }
+        if let Some(renamed) = renamed {
+            vfs.rename(&state.path, &renamed).map_err(fs_error)?;
+            drop(vfs);
+            if let Some(state) = self.fids.get_mut(&fid) {
+                state.path = renamed;
+            }
+        }
+        Ok(Rmessage::Wstat)
+    }
+
+    /// Whether a fid names a file or an auth fid
+    fn is_in_use(&self, fid: Fid) -> bool {
+        self.fids.contains_key(&fid) || self.auth.state(fid).is_some()
+    }
+
+    /// The most data a read or write moves at once
+    fn iounit(&self) -> u32 {
+        self.msize - IO_HEADER_SIZE as u32
+    }
+
+    /// Walks one name from `path`. ".." stops at the root of the tree.
+    fn step(&self, path: &str, name: &str) -> Result<String, &'static str> {
+        match name {
+            ".." if path == self.root => Ok(path.to_string()),
+            ".." => Ok(parent_of(path).to_string()),
+            "" | "." => Err(BAD_NAME),
+            name if name.contains('/') => Err(BAD_NAME),
+            name => self.join(path, name),
+        }
+    }
+
+    /// Appends a relative path to `path`, failing if the result leaves the
+    /// exported tree
+    fn join(&self, path: &str, relative: &str) -> Result<String, &'static str> {
+        let joined = normalize(&format!("{}/{}", path, relative)).map_err(fs_error)?;
+        let inside = self.root == "/"
+            || joined == self.root
+            || joined
+                .strip_prefix(self.root.as_str())
+                .is_some_and(|rest| rest.starts_with('/'));
+        if inside {
+            Ok(joined)
+        } else {
+            Err(BAD_NAME)
+        }
+    }
+}
+
+impl Drop for Plan9Server {
+    fn drop(&mut self) {
+        // Open files hold VFS descriptors
+        let fids: Vec<Fid> = self.fids.keys().copied().collect();
+        for fid in fids {
+            let _ = self.clunk(fid, false);
+        }
+    }
+}
+
+/// Checks that the fid may be opened with `mode`, then opens it
+fn open_fid(state: &mut ServerFid, mode: u8) -> Result<Qid, &'static str> {
+    if state.opened.is_some() {
+        return Err(FID_OPEN);
+    }
+    let mut vfs = vfs::lock();
+    let mut metadata = vfs.metadata(&state.path).map_err(fs_error)?;
+    let kind = if metadata.is_dir {
+        if !matches!(mode & ACCESS_MASK, OREAD | OEXEC) || mode & OTRUNC != 0 {
+            return Err(IS_DIRECTORY);
+        }
+        let entries = vfs
+            .read_dir(&state.path)
+            .map_err(fs_error)?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| {
+                let path = if state.path == "/" {
+                    format!("/{}", entry.name)
+                } else {
+                    format!("{}/{}", state.path, entry.name)
+                };
+                stat_of(&path, &entry.metadata).encode()
+            })
+            .collect();
+        OpenKind::Dir {
+            entries,
+            next: 0,
+            offset: 0,
+        }
+    } else {
+        if mode & OTRUNC != 0 && metadata.size > 0 {
+            vfs.remove_file(&state.path).map_err(fs_error)?;
+            vfs.create_file(&state.path).map_err(fs_error)?;
+            metadata = vfs.metadata(&state.path).map_err(fs_error)?;
+        }
+        OpenKind::File(vfs.open(&state.path).map_err(fs_error)?)
+    };
+    state.opened = Some(Opened { mode, kind });
+    Ok(qid_of(&state.path, &metadata))
+}
+
+/// Returns the directory holding `path`, which must not be the root
+fn parent_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(slash) => &path[..slash],
+    }
+}
+
+fn qid_of(path: &str, metadata: &FileMetadata) -> Qid {
+    // FNV-1a, so the same path always gets the same qid
+    let hash = path.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    });
+    Qid {
+        kind: if metadata.is_dir { QTDIR } else { QTFILE },
+        version: metadata.modified as u32,
+        path: hash,
+    }
+}
+
+fn stat_of(path: &str, metadata: &FileMetadata) -> Stat {
+    let permissions = metadata.mode.map_or_else(
+        || {
+            let bits = |allowed: bool, bits: u32| if allowed { bits } else { 0 };
+            bits(metadata.permissions.readable, 0o444)
+                | bits(metadata.permissions.writable, 0o222)
+                | bits(metadata.permissions.executable, 0o111)
+        },
+        |mode| mode as u32 & 0o777,
+    );
+    let name = match path.rsplit('/').next() {
+        Some("") | None => "/",
+        Some(name) => name,
+    };
+    Stat {
+        kind: 0,
+        dev: 0,
+        qid: qid_of(path, metadata),
+        mode: if metadata.is_dir {
+            DMDIR | permissions
+        } else {
+            permissions
+        },
+        atime: metadata.accessed as u32,
+        mtime: metadata.modified as u32,
+        length: if metadata.is_dir { 0 } else { metadata.size },
+        name: name.to_string(),
+        uid: metadata.uid.to_string(),
+        gid: metadata.gid.to_string(),
+        muid: String::new(),
+    }
+}
+
+fn error(ename: &str) -> Rmessage {
+    Rmessage::Error {
+        ename: ename.to_string(),
+    }
+}
+
+fn fs_error(error: FsError) -> &'static str {
+    KError::from(error).kind().description()
+}
+
+fn auth_error(error: AuthError) -> &'static str {
+    match error {
+        AuthError::UnknownUser => "unknown user",
+        AuthError::BadSecret => "authentication failed",
+        AuthError::FidInUse => FID_IN_USE,
+        AuthError::UnknownFid => UNKNOWN_FID,
+        AuthError::NotAuthenticated => "not authenticated",
+        AuthError::Mismatch => "auth fid is for another user or tree",
+        AuthError::NotRequired => "authentication not required",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesys::{
+        block::memory::MemoryBlockDevice,
+        fat16::Fat16,
+        vfs::{self, MountOptions},
+    };
+    use alloc::boxed::Box;
+
+    /// Sends a request through the wire format and decodes the reply
+    fn call(server: &mut Plan9Server, message: Tmessage) -> Rmessage {
+        let (tag, reply) = Rmessage::decode(&server.serve(&message.encode(1))).unwrap();
+        assert_eq!(tag, 1);
+        reply
+    }
+
+    fn walk(names: &[&str], fid: Fid, newfid: Fid) -> Tmessage {
+        Tmessage::Walk {
+            fid,
+            newfid,
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test_case]
+    fn test_serve_vfs_tree() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        vfs::mount("/export", Box::new(fs), MountOptions::empty()).unwrap();
+        {
+            let mut vfs = vfs::lock();
+            vfs.create_dir("/export/docs").unwrap();
+            vfs.create_file("/export/docs/readme").unwrap();
+            let fd = vfs.open("/export/docs/readme").unwrap();
+            vfs.write(fd, b"hello 9p").unwrap();
+            vfs.close(fd).unwrap();
+        }
+
+        let mut server = Plan9Server::new("/export").unwrap();
+        let version = Tmessage::Version {
+            msize: 1 << 20,
+            version: "9P2000.u".to_string(),
+        };
+        assert_eq!(
+            call(&mut server, version),
+            Rmessage::Version {
+                msize: MAX_MSIZE,
+                version: VERSION.to_string(),
+            }
+        );
+        let attach = |uname: &str| Tmessage::Attach {
+            fid: 0,
+            afid: NOFID,
+            uname: uname.to_string(),
+            aname: String::new(),
+        };
+        assert!(matches!(
+            call(&mut server, attach("glenda")),
+            Rmessage::Error { .. }
+        ));
+        let Rmessage::Attach { qid } = call(&mut server, attach("none")) else {
+            panic!("Attach failed");
+        };
+        assert_eq!(qid.kind, QTDIR);
+
+        // A walk that fails part way says how far it got, and leaves the
+        // new fid unused. ".." never leaves the tree.
+        let Rmessage::Walk { qids } = call(&mut server, walk(&["docs", "missing"], 0, 1)) else {
+            panic!("Walk failed");
+        };
+        assert_eq!(qids.len(), 1);
+        assert!(matches!(
+            call(
+                &mut server,
+                Tmessage::Open {
+                    fid: 1,
+                    mode: OREAD
+                }
+            ),
+            Rmessage::Error { .. }
+        ));
+        let Rmessage::Walk { qids } = call(&mut server, walk(&["..", "docs", "readme"], 0, 1))
+        else {
+            panic!("Walk failed");
+        };
+        assert_eq!(qids[0], qid);
+        assert_eq!(qids[2].kind, QTFILE);
+
+        assert!(matches!(
+            call(
+                &mut server,
+                Tmessage::Open {
+                    fid: 1,
+                    mode: OREAD
+                }
+            ),
+            Rmessage::Open { .. }
+        ));
+        let read = |offset, count| Tmessage::Read {
+            fid: 1,
+            offset,
+            count,
+        };
+        assert_eq!(
+            call(&mut server, read(6, 100)),
+            Rmessage::Read {
+                data: b"9p".to_vec()
+            }
+        );
+        assert!(matches!(
+            call(
+                &mut server,
+                Tmessage::Write {
+                    fid: 1,
+                    offset: 0,
+                    data: b"no".to_vec()
+                }
+            ),
+            Rmessage::Error { .. }
+        ));
+        assert_eq!(
+            call(&mut server, Tmessage::Clunk { fid: 1 }),
+            Rmessage::Clunk
+        );
+
+        // Creating opens the new file through the directory's fid
+        call(&mut server, walk(&["docs"], 0, 2));
+        let create = Tmessage::Create {
+            fid: 2,
+            name: "notes".to_string(),
+            perm: 0o644,
+            mode: ORDWR,
+        };
+        assert!(matches!(call(&mut server, create), Rmessage::Create { .. }));
+        let write = Tmessage::Write {
+            fid: 2,
+            offset: 0,
+            data: b"written over 9p".to_vec(),
+        };
+        assert_eq!(call(&mut server, write), Rmessage::Write { count: 15 });
+        call(&mut server, Tmessage::Clunk { fid: 2 });
+        let mut contents = [0; 32];
+        {
+            let mut vfs = vfs::lock();
+            let fd = vfs.open("/export/docs/notes").unwrap();
+            let read = vfs.read(fd, &mut contents).unwrap();
+            assert_eq!(&contents[..read], b"written over 9p");
+            vfs.close(fd).unwrap();
+        }
+
+        // Directories read as whole stats, continuing where the last
+        // read stopped
+        call(&mut server, walk(&["docs"], 0, 3));
+        call(
+            &mut server,
+            Tmessage::Open {
+                fid: 3,
+                mode: OREAD,
+            },
+        );
+        let mut names = Vec::new();
+        let mut offset = 0;
+        loop {
+            let read = Tmessage::Read {
+                fid: 3,
+                offset,
+                count: 80,
+            };
+            let Rmessage::Read { data } = call(&mut server, read) else {
+                panic!("Directory read failed");
+            };
+            if data.is_empty() {
+                break;
+            }
+            let (stat, used) = Stat::decode(&data).unwrap();
+            assert_eq!(used, data.len());
+            names.push(stat.name);
+            offset += data.len() as u64;
+        }
+        names.sort();
+        assert_eq!(names, ["notes", "readme"]);
+        call(&mut server, Tmessage::Clunk { fid: 3 });
+
+        // Renaming keeps the fid on the file, removing forgets the fid
+        call(&mut server, walk(&["docs", "notes"], 0, 4));
+        let stat = Stat {
+            name: "renamed".to_string(),
+            ..Stat::unchanged()
+        };
+        assert_eq!(
+            call(&mut server, Tmessage::Wstat { fid: 4, stat }),
+            Rmessage::Wstat
+        );
+        let Rmessage::Stat { stat } = call(&mut server, Tmessage::Stat { fid: 4 }) else {
+            panic!("Stat failed");
+        };
+        assert_eq!((stat.name.as_str(), stat.length), ("renamed", 15));
+        assert_eq!(
+            call(&mut server, Tmessage::Remove { fid: 4 }),
+            Rmessage::Remove
+        );
+        assert!(vfs::lock().metadata("/export/docs/renamed").is_err());
+        assert!(matches!(
+            call(&mut server, Tmessage::Stat { fid: 4 }),
+            Rmessage::Error { .. }
+        ));
+
+        drop(server);
+        vfs::umount("/export").unwrap();
+    }
+}
@@ -0,0 +1,463 @@
+//! A read-only view of the process table.
+//!
+//! Every process gets a directory named after its PID holding a `stat`
+//! file. The file is a list of `name value` lines rendered from a
+//! `ProcessSnapshot` when it is opened, so reads through one descriptor
+//! are consistent with each other while a process keeps running. Nothing is
+//! stored: directories and sizes are worked out from the process table on
+//! every call.
+//!
+//! Files at the root report on the kernel as a whole: `meminfo` holds the
+//! heap usage of each kernel subsystem, in the same format, and `diskstats`
+//! the I/O statistics of each disk, a line per disk in the format described
+//! in `block::stats`. `iostat` holds the same statistics laid out for
+//! people. `devices` lists the device tree as `lsdev` does.
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{
+    constants::memory::HEAP_SIZE,
+    devices::manager::DEVICE_MANAGER,
+    filesys::block::stats,
+    memory::accounting::{self, Subsystem},
+    processes::{self, ProcessSnapshot},
+};
+
+use super::{
+    DirEntry, FileMetadata, FilePermissions, FileSystem, FileTimes, FsError, SeekFrom, StatFs,
+};
+
+/// Name of the file in each process directory
+const STAT_FILE: &str = "stat";
+
+/// Renders the contents of a file when it is opened
+type Render = fn() -> String;
+
+/// Files at the root, and what renders them
+const ROOT_FILES: [(&str, Render); 4] = [
+    ("meminfo", render_meminfo),
+    ("diskstats", render_diskstats),
+    ("iostat", stats::iostat),
+    ("devices", render_devices),
+];
+
+/// What a path inside procfs refers to
+enum Node {
+    Root,
+    /// An index into `ROOT_FILES`
+    RootFile(usize),
+    Process(u32),
+    Stat(u32),
+}
+
+/// A `stat` file as it was when opened
+struct OpenFile {
+    contents: Vec<u8>,
+    position: u64,
+}
+
+pub struct ProcFs {
+    open_files: BTreeMap<usize, OpenFile>,
+    next_fd: usize,
+}
+
+impl Default for ProcFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProcFs {
+    pub const fn new() -> Self {
+        ProcFs {
+            open_files: BTreeMap::new(),
+            next_fd: 0,
+        }
+    }
+
+    fn resolve(path: &str) -> Result<Node, FsError> {
+        let mut parts = path.split('/').filter(|part| !part.is_empty());
+        let Some(pid) = parts.next() else {
+            return Ok(Node::Root);
+        };
+        if let Some(index) = ROOT_FILES.iter().position(|(name, _)| *name == pid) {
+            return match parts.next() {
+                None => Ok(Node::RootFile(index)),
+                Some(_) => Err(FsError::NotFound),
+            };
+        }
+        let pid: u32 = pid.parse().map_err(|_| FsError::NotFound)?;
+        processes::snapshot(pid).map_err(|_| FsError::NotFound)?;
+        match (parts.next(), parts.next()) {
+            (None, _) => Ok(Node::Process(pid)),
+            (Some(STAT_FILE), None) => Ok(Node::Stat(pid)),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn open_file_mut(&mut self, fd: usize) -> &mut OpenFile {
+        self.open_files
+            .get_mut(&fd)
+            .expect("Invalid file descriptor")
+    }
+}
+
+/// Renders the `stat` file of a process
+fn render_stat(process: &ProcessSnapshot) -> String {
+    let sched = &process.sched;
+    let usage = &process.usage;
+    let lines = [
+        ("state", format!("{:?}", process.state)),
+        (
+            "wait_reason",
+            process
+                .wait_reason
+                .map_or("none".into(), |reason| format!("{:?}", reason)),
+        ),
+        ("priority", process.priority.to_string()),
+        ("kernel_thread", (process.kernel_thread as u8).to_string()),
+        ("syscalls", sched.syscalls.to_string()),
+        ("page_faults", sched.page_faults.to_string()),
+        ("voluntary_switches", sched.voluntary_switches().to_string()),
+        (
+            "involuntary_switches",
+            sched.involuntary_switches().to_string(),
+        ),
+        ("preempted", sched.preempted.to_string()),
+        ("blocked", sched.blocked.to_string()),
+        ("yielded", sched.yielded.to_string()),
+        (
+            "utime_ns",
+            usage.ru_utime.as_nanos().unwrap_or(0).to_string(),
+        ),
+        (
+            "stime_ns",
+            usage.ru_stime.as_nanos().unwrap_or(0).to_string(),
+        ),
+        ("open_files", process.open_files.to_string()),
+    ];
+    lines
+        .iter()
+        .map(|(name, value)| format!("{} {}\n", name, value))
+        .collect()
+}
+
+fn stat_contents(pid: u32) -> Result<Vec<u8>, FsError> {
+    let process = processes::snapshot(pid).map_err(|_| FsError::NotFound)?;
+    Ok(render_stat(&process).into_bytes())
+}
+
+/// Renders the `meminfo` file
+fn render_meminfo() -> String {
+    let mut contents = format!("heap_size {}\n", HEAP_SIZE);
+    for subsystem in Subsystem::ALL {
+        let usage = accounting::usage(subsystem);
+        contents += &format!(
+            "{0}_bytes {1}\n{0}_allocations {2}\n",
+            subsystem.name(),
+            usage.bytes,
+            usage.allocations
+        );
+    }
+    contents
+}
+
+/// Renders the `devices` file
+fn render_devices() -> String {
+    DEVICE_MANAGER.lock().lsdev()
+}
+
+/// Renders the `diskstats` file
+fn render_diskstats() -> String {
+    let mut contents = String::new();
+    for disk in stats::snapshot_all() {
+        contents += &format!(
+            "{} {} {} {} {} {} {} {} {} {} {}",
+            disk.name,
+            disk.reads,
+            disk.read_blocks,
+            disk.read_ns,
+            disk.writes,
+            disk.write_blocks,
+            disk.write_ns,
+            disk.errors,
+            disk.in_flight,
+            disk.busy_ns,
+            disk.weighted_ns
+        );
+        for count in disk.latency {
+            contents += &format!(" {}", count);
+        }
+        contents.push('\n');
+    }
+    contents
+}
+
+fn node_metadata(size: u64, is_dir: bool) -> FileMetadata {
+    FileMetadata {
+        size,
+        is_dir,
+        created: 0,
+        modified: 0,
+        accessed: 0,
+        permissions: FilePermissions {
+            readable: true,
+            writable: false,
+            executable: is_dir,
+        },
+        mode: None,
+        uid: 0,
+        gid: 0,
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn create_file(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn remove_file(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn remove_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn open_file(&mut self, path: &str) -> Result<usize, FsError> {
+        let contents = match Self::resolve(path)? {
+            Node::Stat(pid) => stat_contents(pid)?,
+            Node::RootFile(index) => (ROOT_FILES[index].1)().into_bytes(),
+            Node::Root | Node::Process(_) => return Err(FsError::NotSupported),
+        };
+
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.open_files.insert(
+            fd,
+            OpenFile {
+                contents,
+                position: 0,
+            },
+        );
+        Ok(fd)
+    }
+
+    fn close_file(&mut self, fd: usize) {
+        self.open_files
+            .remove(&fd)
+            .expect("Cannot close an invalid file descriptor.");
+    }
+
+    fn write_file(&mut self, _fd: usize, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn seek_file(&mut self, fd: usize, pos: SeekFrom) -> Result<u64, FsError> {
+        let file = self.open_file_mut(fd);
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (file.contents.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => file.position.checked_add_signed(offset),
+        }
+        .ok_or(FsError::InvalidOffset)?;
+        file.position = new_pos;
+        Ok(new_pos)
+    }
+
+    fn read_file(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let file = self.open_file_mut(fd);
+        let start = (file.position as usize).min(file.contents.len());
+        let read = buf.len().min(file.contents.len() - start);
+        buf[..read].copy_from_slice(&file.contents[start..start + read]);
+        file.position += read as u64;
+        Ok(read)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        match Self::resolve(path)? {
+            Node::Root => {
+                let mut entries: Vec<DirEntry> = ROOT_FILES
+                    .iter()
+                    .map(|(name, render)| DirEntry {
+                        name: (*name).into(),
+                        metadata: node_metadata(render().len() as u64, false),
+                    })
+                    .collect();
+                processes::for_each(|pid, _| {
+                    entries.push(DirEntry {
+                        name: pid.to_string(),
+                        metadata: node_metadata(0, true),
+                    })
+                });
+                Ok(entries)
+            }
+            Node::Process(pid) => Ok(alloc::vec![DirEntry {
+                name: STAT_FILE.into(),
+                metadata: node_metadata(stat_contents(pid)?.len() as u64, false),
+            }]),
+            Node::RootFile(_) | Node::Stat(_) => Err(FsError::NotSupported),
+        }
+    }
+
+    fn metadata(&self, path: &str) -> Result<FileMetadata, FsError> {
+        Ok(match Self::resolve(path)? {
+            Node::Root | Node::Process(_) => node_metadata(0, true),
+            Node::RootFile(index) => node_metadata((ROOT_FILES[index].1)().len() as u64, false),
+            Node::Stat(pid) => node_metadata(stat_contents(pid)?.len() as u64, false),
+        })
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "proc"
+    }
+
+    fn set_times(&mut self, _path: &str, _times: FileTimes) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn set_permissions(
+        &mut self,
+        _path: &str,
+        _permissions: FilePermissions,
+    ) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        // Takes up no space
+        Ok(StatFs::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesys::BlockDevice,
+        processes::{
+            process::{create_process, get_process, remove_process},
+            rusage::SwitchReason,
+            test_binaries,
+        },
+    };
+    use core::sync::atomic::Ordering;
+
+    #[test_case]
+    fn test_process_stat() {
+        let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+        {
+            let process = get_process(pid).unwrap();
+            process.stats.syscalls.fetch_add(3, Ordering::Relaxed);
+            process.stats.record_switch(SwitchReason::Preempted);
+            process.stats.record_switch(SwitchReason::Blocked);
+            process.stats.record_switch(SwitchReason::Yielded);
+        }
+
+        let mut fs = ProcFs::new();
+        let name = pid.to_string();
+        assert!(fs.read_dir("/").unwrap().iter().any(|e| e.name == name));
+        assert!(fs.metadata(&format!("/{}", pid)).unwrap().is_dir);
+
+        let fd = fs.open_file(&format!("/{}/stat", pid)).unwrap();
+        let mut buf = [0u8; 512];
+        let read = fs.read_file(fd, &mut buf).unwrap();
+        fs.close_file(fd);
+        let stat = core::str::from_utf8(&buf[..read]).unwrap();
+        for line in [
+            "state New",
+            "wait_reason none",
+            "syscalls 3",
+            "voluntary_switches 2",
+            "involuntary_switches 1",
+            "yielded 1",
+        ] {
+            assert!(stat.lines().any(|l| l == line), "{} missing", line);
+        }
+
+        remove_process(pid);
+        assert!(matches!(
+            fs.open_file(&format!("/{}/stat", pid)),
+            Err(FsError::NotFound)
+        ));
+        assert!(matches!(fs.create_file("/x"), Err(FsError::ReadOnly)));
+    }
+
+    #[test_case]
+    fn test_meminfo() {
+        let mut fs = ProcFs::new();
+        assert!(fs
+            .read_dir("/")
+            .unwrap()
+            .iter()
+            .any(|e| e.name == "meminfo"));
+
+        let fd = fs.open_file("/meminfo").unwrap();
+        let mut buf = [0u8; 512];
+        let read = fs.read_file(fd, &mut buf).unwrap();
+        fs.close_file(fd);
+        let meminfo = core::str::from_utf8(&buf[..read]).unwrap();
+        for subsystem in Subsystem::ALL {
+            let name = format!("{}_bytes ", subsystem.name());
+            assert!(
+                meminfo.lines().any(|l| l.starts_with(&name)),
+                "{} missing",
+                name
+            );
+        }
+        assert!(matches!(fs.open_file("/meminfo/x"), Err(FsError::NotFound)));
+
+        // A line per disk, starting with its name
+        let mut disk = crate::filesys::block::memory::MemoryBlockDevice::new(4, 512);
+        let fd = fs.open_file("/diskstats").unwrap();
+        let mut buf = [0u8; 4096];
+        let read = fs.read_file(fd, &mut buf).unwrap();
+        fs.close_file(fd);
+        let diskstats = core::str::from_utf8(&buf[..read]).unwrap();
+        let name = format!("{} ", disk.stats().name());
+        assert!(diskstats.lines().any(|l| l.starts_with(&name)));
+
+        // The same counters for people, after one write and two reads
+        let block = [0x5A; 512];
+        disk.write_block(0, &block).unwrap();
+        disk.read_block(0, &mut [0; 512]).unwrap();
+        disk.read_block(1, &mut [0; 512]).unwrap();
+        let fd = fs.open_file("/iostat").unwrap();
+        let read = fs.read_file(fd, &mut buf).unwrap();
+        fs.close_file(fd);
+        let iostat = core::str::from_utf8(&buf[..read]).unwrap();
+        assert!(iostat.starts_with("Device "));
+        let row: Vec<_> = iostat
+            .lines()
+            .find(|l| l.starts_with(&name))
+            .expect("Disk is listed")
+            .split_whitespace()
+            .collect();
+        // Reads, blocks read, writes, blocks written and errors
+        assert_eq!(row[1..6], ["2", "2", "1", "1", "0"]);
+
+        // The device tree, starting with the serial console
+        let fd = fs.open_file("/devices").unwrap();
+        let read = fs.read_file(fd, &mut buf).unwrap();
+        fs.close_file(fd);
+        let devices = core::str::from_utf8(&buf[..read]).unwrap();
+        assert!(devices.starts_with("dev0 serial console [Serial, Active]"));
+    }
+}
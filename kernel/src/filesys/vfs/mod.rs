@@ -1 +1,1144 @@
+//! Virtual filesystem layer.
+//!
+//! Filesystems are mounted at absolute paths and every path is dispatched
+//! to the filesystem with the longest matching mount point. Files opened
+//! through the VFS get descriptors that are unique across all mounts, and
+//! the VFS tracks which mount each descriptor and each process' working
+//! directory refers to so busy filesystems cannot be unmounted.
+//!
+//! Mount options are enforced here rather than by each filesystem: writes
+//! to read-only mounts fail before reaching the filesystem, and `sync`
+//! mounts are flushed after every modifying operation.
+//!
+//! Access times follow relatime by default: a read only records the access
+//! if the previous access is older than the last modification or more than
+//! a day old, and is checked at most once per open file.
+//!
+//! Files opened with `OpenFlags::DIRECT` are read and written with the
+//! filesystem's direct path, which moves whole blocks between the device
+//! and the caller's buffer without copying them through block caches.
+//! Program loading and `copy` use it, since they move whole files once.
 
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use bitflags::bitflags;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+
+use crate::{
+    memory::accounting::{charge_to, ChargeGuard, Subsystem},
+    serial_println,
+    shutdown::{self, StopStage, DEFAULT_STOP_TIMEOUT_NS},
+    time,
+};
+
+use super::{
+    DirEntry, FileMetadata, FilePermissions, FileSystem, FileTimes, FsError, SeekFrom, StatFs,
+};
+
+/// Identifies a mounted filesystem
+pub type MountId = u32;
+
+bitflags! {
+    /// Options a filesystem is mounted with
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MountOptions: u32 {
+        /// Reject every operation that would modify the filesystem
+        const READ_ONLY = 1 << 0;
+        /// Reject running programs stored on the filesystem
+        const NO_EXEC = 1 << 1;
+        /// Flush the filesystem after every modifying operation
+        const SYNC = 1 << 2;
+        /// Never update access times on read
+        const NO_ATIME = 1 << 3;
+        /// Update access times on every read
+        const STRICT_ATIME = 1 << 4;
+    }
+}
+
+/// Bytes moved per read and write by `copy`, enough for a direct transfer
+/// to cover many blocks
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Deepest directory nesting `remove_dir_all` descends into
+const MAX_TREE_DEPTH: usize = 64;
+
+/// Under relatime, an access time older than this is always updated
+const RELATIME_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+bitflags! {
+    /// Options a file is opened with
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OpenFlags: u32 {
+        /// Move aligned whole blocks straight between the device and the
+        /// caller's buffer, bypassing caches
+        const DIRECT = 1 << 0;
+    }
+}
+
+impl MountOptions {
+    /// Parses a comma separated option string such as "ro,noexec". Unknown
+    /// options are rejected.
+    pub fn parse(options: &str) -> Result<Self, FsError> {
+        let mut parsed = MountOptions::empty();
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            parsed |= match option {
+                "ro" => MountOptions::READ_ONLY,
+                "rw" => MountOptions::empty(),
+                "noexec" => MountOptions::NO_EXEC,
+                "sync" => MountOptions::SYNC,
+                "noatime" => MountOptions::NO_ATIME,
+                "strictatime" => MountOptions::STRICT_ATIME,
+                "relatime" => MountOptions::empty(),
+                _ => return Err(FsError::NotSupported),
+            };
+        }
+        Ok(parsed)
+    }
+
+    /// Formats the options the way /proc/mounts does
+    pub fn describe(&self) -> String {
+        let mut options = vec![if self.contains(MountOptions::READ_ONLY) {
+            "ro"
+        } else {
+            "rw"
+        }];
+        if self.contains(MountOptions::NO_EXEC) {
+            options.push("noexec");
+        }
+        if self.contains(MountOptions::SYNC) {
+            options.push("sync");
+        }
+        options.push(if self.contains(MountOptions::NO_ATIME) {
+            "noatime"
+        } else if self.contains(MountOptions::STRICT_ATIME) {
+            "strictatime"
+        } else {
+            "relatime"
+        });
+        options.join(",")
+    }
+}
+
+/// A filesystem attached to the namespace
+struct Mount {
+    id: MountId,
+    /// Normalized absolute path of the mount point
+    path: String,
+    options: MountOptions,
+    fs: Box<dyn FileSystem + Send>,
+}
+
+impl Mount {
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.options.contains(MountOptions::READ_ONLY) {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Called after a modifying operation succeeded
+    fn finish_write(&mut self) -> Result<(), FsError> {
+        if self.options.contains(MountOptions::SYNC) {
+            self.fs.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Metadata of the filesystem's root, which is what the mount point
+    /// shows. Filesystems that keep no entry for their root directory get
+    /// a plain directory.
+    fn root_metadata(&self) -> FileMetadata {
+        self.fs.metadata("/").unwrap_or(FileMetadata {
+            size: 0,
+            is_dir: true,
+            created: 0,
+            modified: 0,
+            accessed: 0,
+            permissions: FilePermissions {
+                readable: true,
+                writable: !self.options.contains(MountOptions::READ_ONLY),
+                executable: true,
+            },
+            mode: None,
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    /// Decides whether a read should record a new access time
+    ///
+    /// # Arguments
+    /// * `metadata` - The file's current timestamps
+    /// * `now` - Current time in seconds since the Unix epoch
+    fn should_update_atime(&self, metadata: &FileMetadata, now: u64) -> bool {
+        if self
+            .options
+            .intersects(MountOptions::READ_ONLY | MountOptions::NO_ATIME)
+        {
+            return false;
+        }
+        self.options.contains(MountOptions::STRICT_ATIME)
+            || metadata.accessed <= metadata.modified
+            || now.saturating_sub(metadata.accessed) >= RELATIME_INTERVAL_SECS
+    }
+}
+
+/// Identifies one version of a file's contents: which file it is, when it
+/// was last changed, and a hash of what it holds. FAT only keeps
+/// modification times to two seconds, so the time alone cannot tell apart
+/// two versions of the same size written one after the other.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileVersion {
+    pub mount: MountId,
+    /// Normalized path of the file relative to its mount
+    pub path: String,
+    pub modified: u64,
+    pub size: u64,
+    /// FNV-1a hash of the contents
+    pub hash: u64,
+}
+
+/// A program read into memory by `Vfs::read_executable`
+#[derive(Debug)]
+pub struct Executable {
+    pub version: FileVersion,
+    pub bytes: Vec<u8>,
+}
+
+/// A file opened through the VFS
+struct OpenFile {
+    mount: MountId,
+    /// Descriptor within the mount's filesystem
+    fs_fd: usize,
+    /// Path of the file relative to its mount
+    path: String,
+    flags: OpenFlags,
+    /// Whether a read has already considered updating the access time
+    atime_checked: bool,
+}
+
+/// The mount table, open files, and working directories
+pub struct Vfs {
+    mounts: Vec<Mount>,
+    files: BTreeMap<usize, OpenFile>,
+    /// Working directory of each process that has set one
+    cwds: BTreeMap<u32, String>,
+    next_mount: MountId,
+    next_fd: usize,
+}
+
+/// The kernel's namespace
+pub static VFS: Mutex<Vfs> = Mutex::new(Vfs::new());
+
+/// The namespace, locked by `lock`
+pub struct VfsGuard {
+    vfs: MutexGuard<'static, Vfs>,
+    _charge: ChargeGuard,
+}
+
+impl Deref for VfsGuard {
+    type Target = Vfs;
+
+    fn deref(&self) -> &Vfs {
+        &self.vfs
+    }
+}
+
+impl DerefMut for VfsGuard {
+    fn deref_mut(&mut self) -> &mut Vfs {
+        &mut self.vfs
+    }
+}
+
+/// Locks the namespace, charging what is allocated while it is held to
+/// the filesystem
+pub fn lock() -> VfsGuard {
+    VfsGuard {
+        _charge: charge_to(Subsystem::Filesystem),
+        vfs: VFS.lock(),
+    }
+}
+
+/// Normalizes an absolute path, resolving `.` and `..` and removing
+/// duplicate and trailing slashes
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidName);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    let mut normalized = String::new();
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Returns the part of `path` below `mount_point`, or None if the path is
+/// not under it. Both must be normalized.
+fn strip_mount_point<'p>(path: &'p str, mount_point: &str) -> Option<&'p str> {
+    if mount_point == "/" {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(mount_point)?;
+    match rest {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+impl Default for Vfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vfs {
+    pub const fn new() -> Self {
+        Vfs {
+            mounts: Vec::new(),
+            files: BTreeMap::new(),
+            cwds: BTreeMap::new(),
+            next_mount: 0,
+            next_fd: 0,
+        }
+    }
+
+    /// Attaches a filesystem at `path`
+    pub fn mount(
+        &mut self,
+        path: &str,
+        fs: Box<dyn FileSystem + Send>,
+        options: MountOptions,
+    ) -> Result<MountId, FsError> {
+        let path = normalize(path)?;
+        if self.mounts.iter().any(|mount| mount.path == path) {
+            return Err(FsError::AlreadyExists);
+        }
+        let id = self.next_mount;
+        self.next_mount += 1;
+        self.mounts.push(Mount {
+            id,
+            path,
+            options,
+            fs,
+        });
+        Ok(id)
+    }
+
+    /// Flushes and detaches the filesystem mounted at `path`
+    ///
+    /// # Returns
+    /// `FsError::Busy` if files are open on it, a process' working directory
+    /// is inside it, or another filesystem is mounted below it
+    pub fn umount(&mut self, path: &str) -> Result<(), FsError> {
+        let path = normalize(path)?;
+        let index = self
+            .mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or(FsError::NotFound)?;
+        let id = self.mounts[index].id;
+
+        let has_open_files = self.files.values().any(|file| file.mount == id);
+        let has_cwds = self
+            .cwds
+            .values()
+            .any(|cwd| self.resolve_index(cwd).is_ok_and(|(i, _)| i == index));
+        let has_submounts = self
+            .mounts
+            .iter()
+            .any(|mount| mount.id != id && strip_mount_point(&mount.path, &path).is_some());
+        if has_open_files || has_cwds || has_submounts {
+            return Err(FsError::Busy);
+        }
+
+        self.mounts[index].fs.sync()?;
+        self.mounts.remove(index);
+        Ok(())
+    }
+
+    /// Finds the mount with the longest mount point containing `path`
+    ///
+    /// # Returns
+    /// The index of the mount and the path relative to its root
+    fn resolve_index<'p>(&self, path: &'p str) -> Result<(usize, &'p str), FsError> {
+        self.mounts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, mount)| {
+                strip_mount_point(path, &mount.path).map(|rest| (i, mount.path.len(), rest))
+            })
+            .max_by_key(|&(_, length, _)| length)
+            .map(|(i, _, rest)| (i, rest))
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Runs `f` on the filesystem holding `path` with the path relative to it
+    fn with_fs<T>(
+        &mut self,
+        path: &str,
+        f: impl FnOnce(&mut dyn FileSystem, &str) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let path = normalize(path)?;
+        let (index, relative) = self.resolve_index(&path)?;
+        f(self.mounts[index].fs.as_mut(), relative)
+    }
+
+    /// Like `with_fs` for operations that modify the filesystem
+    fn with_writable_fs<T>(
+        &mut self,
+        path: &str,
+        f: impl FnOnce(&mut dyn FileSystem, &str) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let path = normalize(path)?;
+        let (index, relative) = self.resolve_index(&path)?;
+        let mount = &mut self.mounts[index];
+        mount.check_writable()?;
+        let result = f(mount.fs.as_mut(), relative)?;
+        mount.finish_write()?;
+        Ok(result)
+    }
+
+    fn file_mount(&mut self, fd: usize) -> Result<(&mut Mount, usize), FsError> {
+        let file = self.files.get(&fd).ok_or(FsError::NotFound)?;
+        let (mount, fs_fd) = (file.mount, file.fs_fd);
+        let mount = self
+            .mounts
+            .iter_mut()
+            .find(|m| m.id == mount)
+            .ok_or(FsError::NotFound)?;
+        Ok((mount, fs_fd))
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<usize, FsError> {
+        self.open_with(path, OpenFlags::empty())
+    }
+
+    pub fn open_with(&mut self, path: &str, flags: OpenFlags) -> Result<usize, FsError> {
+        let path = normalize(path)?;
+        let (index, relative) = self.resolve_index(&path)?;
+        let mount = &mut self.mounts[index];
+        let fs_fd = mount.fs.open_file(relative)?;
+
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(
+            fd,
+            OpenFile {
+                mount: mount.id,
+                fs_fd,
+                path: relative.to_string(),
+                flags,
+                atime_checked: false,
+            },
+        );
+        Ok(fd)
+    }
+
+    /// Opens the file behind `fd` again, with a position of its own and
+    /// no open flags
+    pub fn reopen(&mut self, fd: usize) -> Result<usize, FsError> {
+        let file = self.files.get(&fd).ok_or(FsError::NotFound)?;
+        let (mount_id, path) = (file.mount, file.path.clone());
+        let mount = self
+            .mounts
+            .iter_mut()
+            .find(|m| m.id == mount_id)
+            .ok_or(FsError::NotFound)?;
+        let fs_fd = mount.fs.open_file(&path)?;
+
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(
+            fd,
+            OpenFile {
+                mount: mount_id,
+                fs_fd,
+                path,
+                flags: OpenFlags::empty(),
+                atime_checked: false,
+            },
+        );
+        Ok(fd)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), FsError> {
+        let (mount, fs_fd) = self.file_mount(fd)?;
+        mount.fs.close_file(fs_fd);
+        self.files.remove(&fd);
+        Ok(())
+    }
+
+    /// Returns the flags a file was opened with
+    pub fn open_flags(&self, fd: usize) -> Result<OpenFlags, FsError> {
+        Ok(self.files.get(&fd).ok_or(FsError::NotFound)?.flags)
+    }
+
+    pub fn read(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let direct = self.open_flags(fd)?.contains(OpenFlags::DIRECT);
+        let (mount, fs_fd) = self.file_mount(fd)?;
+        let read = if direct {
+            mount.fs.read_file_direct(fs_fd, buf)?
+        } else {
+            mount.fs.read_file(fs_fd, buf)?
+        };
+        if read > 0 {
+            self.record_access(fd);
+        }
+        Ok(read)
+    }
+
+    /// Updates the access time of an open file after a read, following the
+    /// mount's atime policy
+    fn record_access(&mut self, fd: usize) {
+        let Some(file) = self.files.get_mut(&fd) else {
+            return;
+        };
+        if file.atime_checked {
+            return;
+        }
+        let Some(mount) = self.mounts.iter_mut().find(|m| m.id == file.mount) else {
+            return;
+        };
+        file.atime_checked = !mount.options.contains(MountOptions::STRICT_ATIME);
+
+        let now = time::realtime_secs().max(0) as u64;
+        let Ok(metadata) = mount.fs.metadata(&file.path) else {
+            return;
+        };
+        if mount.should_update_atime(&metadata, now) {
+            // Failing to record an access must not fail the read
+            let _ = mount.fs.set_times(
+                &file.path,
+                FileTimes {
+                    accessed: Some(now),
+                    modified: None,
+                },
+            );
+        }
+    }
+
+    pub fn write(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let direct = self.open_flags(fd)?.contains(OpenFlags::DIRECT);
+        let (mount, fs_fd) = self.file_mount(fd)?;
+        mount.check_writable()?;
+        let written = if direct {
+            mount.fs.write_file_direct(fs_fd, buf)?
+        } else {
+            mount.fs.write_file(fs_fd, buf)?
+        };
+        mount.finish_write()?;
+        Ok(written)
+    }
+
+    pub fn seek(&mut self, fd: usize, pos: SeekFrom) -> Result<u64, FsError> {
+        let (mount, fs_fd) = self.file_mount(fd)?;
+        mount.fs.seek_file(fs_fd, pos)
+    }
+
+    pub fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        self.with_writable_fs(path, |fs, path| fs.create_file(path))
+    }
+
+    pub fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        self.with_writable_fs(path, |fs, path| fs.create_dir(path))
+    }
+
+    pub fn remove_file(&mut self, path: &str) -> Result<(), FsError> {
+        self.with_writable_fs(path, |fs, path| fs.remove_file(path))
+    }
+
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        self.with_writable_fs(path, |fs, path| fs.remove_dir(path))
+    }
+
+    /// Removes a directory and everything below it, depth first. Fails
+    /// with `FsError::Busy` without removing anything if the tree contains
+    /// a mount point, an open file, or a working directory.
+    pub fn remove_dir_all(&mut self, path: &str) -> Result<(), FsError> {
+        let path = normalize(path)?;
+        let (index, relative) = self.resolve_index(&path)?;
+        let id = self.mounts[index].id;
+
+        let has_mounts = self
+            .mounts
+            .iter()
+            .any(|mount| strip_mount_point(&mount.path, &path).is_some());
+        let has_open_files = self
+            .files
+            .values()
+            .any(|file| file.mount == id && strip_mount_point(&file.path, relative).is_some());
+        let has_cwds = self
+            .cwds
+            .values()
+            .any(|cwd| strip_mount_point(cwd, &path).is_some());
+        if has_mounts || has_open_files || has_cwds {
+            return Err(FsError::Busy);
+        }
+
+        let relative = relative.to_string();
+        self.with_writable_fs(&path, |fs, _| remove_tree(fs, &relative, 0))
+    }
+
+    /// Lists a directory. Filesystems mounted directly below it are listed
+    /// with the metadata of their root, in place of any entry they cover,
+    /// even if the directory itself is on no filesystem.
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let path = normalize(path)?;
+        let children: Vec<(String, FileMetadata)> = self
+            .mounts
+            .iter()
+            .filter_map(|mount| {
+                let rest = strip_mount_point(&mount.path, &path)?.strip_prefix('/')?;
+                (!rest.is_empty() && !rest.contains('/'))
+                    .then(|| (rest.to_string(), mount.root_metadata()))
+            })
+            .collect();
+
+        let mut entries = match self.with_fs(&path, |fs, path| fs.read_dir(path)) {
+            Err(FsError::NotFound) if !children.is_empty() => Vec::new(),
+            entries => entries?,
+        };
+        for (name, metadata) in children {
+            entries.retain(|entry| entry.name != name);
+            entries.push(DirEntry { name, metadata });
+        }
+        Ok(entries)
+    }
+
+    pub fn metadata(&mut self, path: &str) -> Result<FileMetadata, FsError> {
+        let path = normalize(path)?;
+        let (index, relative) = self.resolve_index(&path)?;
+        let mount = &self.mounts[index];
+        if relative == "/" {
+            return Ok(mount.root_metadata());
+        }
+        mount.fs.metadata(relative)
+    }
+
+    /// Renames within one filesystem. Moving between mounts is not
+    /// supported.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let from = normalize(from)?;
+        let to = normalize(to)?;
+        let (from_index, from_relative) = self.resolve_index(&from)?;
+        let (to_index, to_relative) = self.resolve_index(&to)?;
+        if from_index != to_index {
+            return Err(FsError::NotSupported);
+        }
+        let mount = &mut self.mounts[from_index];
+        mount.check_writable()?;
+        mount.fs.rename(from_relative, to_relative)?;
+        mount.finish_write()
+    }
+
+    /// Sets the access and modification times of `path`
+    pub fn set_times(&mut self, path: &str, times: FileTimes) -> Result<(), FsError> {
+        self.with_writable_fs(path, |fs, path| fs.set_times(path, times))
+    }
+
+    /// Copies a file, possibly between mounts, a chunk at a time
+    ///
+    /// # Arguments
+    /// * `src` - The file to copy
+    /// * `dst` - Path of the copy, which must not exist
+    /// * `preserve` - Also copy the access and modification times and the
+    ///   permissions
+    ///
+    /// # Returns
+    /// The number of bytes copied
+    pub fn copy(&mut self, src: &str, dst: &str, preserve: bool) -> Result<u64, FsError> {
+        let metadata = self.metadata(src)?;
+        if metadata.is_dir {
+            return Err(FsError::NotSupported);
+        }
+        self.create_file(dst)?;
+
+        let src_fd = self.open_with(src, OpenFlags::DIRECT)?;
+        let copied = match self.open_with(dst, OpenFlags::DIRECT) {
+            Ok(dst_fd) => {
+                let copied = self.copy_contents(src_fd, dst_fd);
+                self.close(dst_fd)?;
+                copied
+            }
+            Err(e) => Err(e),
+        };
+        self.close(src_fd)?;
+        let copied = copied?;
+
+        if preserve {
+            self.set_times(
+                dst,
+                FileTimes {
+                    accessed: Some(metadata.accessed),
+                    modified: Some(metadata.modified),
+                },
+            )?;
+            // Last, as it may make the copy read-only
+            self.with_writable_fs(dst, |fs, path| {
+                fs.set_permissions(path, metadata.permissions)
+            })?;
+        }
+        Ok(copied)
+    }
+
+    fn copy_contents(&mut self, src_fd: usize, dst_fd: usize) -> Result<u64, FsError> {
+        let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+        let mut copied = 0;
+        loop {
+            let read = self.read(src_fd, &mut buf)?;
+            if read == 0 {
+                return Ok(copied);
+            }
+            let mut written = 0;
+            while written < read {
+                match self.write(dst_fd, &buf[written..read])? {
+                    0 => return Err(FsError::IOError),
+                    count => written += count,
+                }
+            }
+            copied += read as u64;
+        }
+    }
+
+    /// Reports the space usage of the filesystem holding `path`
+    pub fn statfs(&mut self, path: &str) -> Result<StatFs, FsError> {
+        self.with_fs(path, |fs, _| fs.statfs())
+    }
+
+    /// Reads a whole program into memory so it can be run
+    ///
+    /// # Returns
+    /// The program and the version of the file it was read from, or
+    /// `FsError::PermissionDenied` if the file is on a noexec mount
+    pub fn read_executable(&mut self, path: &str) -> Result<Executable, FsError> {
+        let path = normalize(path)?;
+        let (index, relative) = self.resolve_index(&path)?;
+        let mount = &mut self.mounts[index];
+        if mount.options.contains(MountOptions::NO_EXEC) {
+            return Err(FsError::PermissionDenied);
+        }
+        let metadata = mount.fs.metadata(relative)?;
+        // Filesystems without modes cannot mark files executable, so only
+        // those with modes are held to the execute bits
+        if metadata.is_dir || (metadata.mode.is_some() && !metadata.permissions.executable) {
+            return Err(FsError::PermissionDenied);
+        }
+
+        let fd = mount.fs.open_file(relative)?;
+        let mut contents = vec![0; metadata.size as usize];
+        let mut read = 0;
+        let result = loop {
+            if read == contents.len() {
+                break Ok(());
+            }
+            match mount.fs.read_file_direct(fd, &mut contents[read..]) {
+                Ok(0) => break Err(FsError::IOError),
+                Ok(count) => read += count,
+                Err(e) => break Err(e),
+            }
+        };
+        mount.fs.close_file(fd);
+        let version = FileVersion {
+            mount: mount.id,
+            path: String::from(relative),
+            modified: metadata.modified,
+            size: metadata.size,
+            hash: contents
+                .iter()
+                .fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+                    (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+                }),
+        };
+        result.map(|()| Executable {
+            version,
+            bytes: contents,
+        })
+    }
+
+    /// Lists mounts one per line in /proc/mounts format
+    pub fn mounts(&self) -> String {
+        self.mounts
+            .iter()
+            .map(|mount| {
+                format!(
+                    "{} {} {} {} 0 0\n",
+                    mount.fs.fs_type(),
+                    mount.path,
+                    mount.fs.fs_type(),
+                    mount.options.describe()
+                )
+            })
+            .collect()
+    }
+
+    /// Flushes every mounted filesystem
+    pub fn sync_all(&mut self) -> Result<(), FsError> {
+        for mount in self.mounts.iter_mut() {
+            mount.fs.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Sets the working directory of a process
+    pub fn set_cwd(&mut self, pid: u32, path: &str) -> Result<(), FsError> {
+        let path = normalize(path)?;
+        let (index, relative) = self.resolve_index(&path)?;
+        // Filesystems have no entry for their own root
+        if relative != "/" && !self.mounts[index].fs.metadata(relative)?.is_dir {
+            return Err(FsError::NotFound);
+        }
+        self.cwds.insert(pid, path);
+        Ok(())
+    }
+
+    /// Returns the working directory of a process
+    pub fn cwd(&self, pid: u32) -> String {
+        self.cwds
+            .get(&pid)
+            .cloned()
+            .unwrap_or_else(|| "/".to_string())
+    }
+
+    /// Forgets the working directory of an exited process
+    pub fn clear_cwd(&mut self, pid: u32) {
+        self.cwds.remove(&pid);
+    }
+}
+
+/// Deletes the contents of a directory and then the directory. Traversal is
+/// by path, so a directory that contains itself through a corrupted
+/// filesystem shows up as ever deeper paths and is stopped by the depth
+/// limit.
+fn remove_tree(fs: &mut dyn FileSystem, path: &str, depth: usize) -> Result<(), FsError> {
+    if depth > MAX_TREE_DEPTH {
+        return Err(FsError::TooDeep);
+    }
+    for entry in fs.read_dir(path)? {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        let child = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+        if entry.metadata.is_dir {
+            remove_tree(fs, &child, depth + 1)?;
+        } else {
+            fs.remove_file(&child)?;
+        }
+    }
+    fs.remove_dir(path)
+}
+
+/// Prints the size and usage of every mounted filesystem, like df
+pub fn df() {
+    let mut vfs = lock();
+    serial_println!(
+        "{:<16} {:>12} {:>12} {:>12} {:>4}",
+        "Mounted on",
+        "Size",
+        "Used",
+        "Avail",
+        "Use%"
+    );
+    for mount in vfs.mounts.iter_mut() {
+        let Ok(stats) = mount.fs.statfs() else {
+            serial_println!("{:<16} (unavailable)", mount.path);
+            continue;
+        };
+        let size = stats.total_blocks * stats.block_size;
+        let avail = stats.free_blocks * stats.block_size;
+        let used = size - avail;
+        serial_println!(
+            "{:<16} {:>12} {:>12} {:>12} {:>3}%",
+            mount.path,
+            size,
+            used,
+            avail,
+            (used * 100).checked_div(size).unwrap_or(0)
+        );
+    }
+}
+
+/// Attaches a filesystem at `path`
+pub fn mount(
+    path: &str,
+    fs: Box<dyn FileSystem + Send>,
+    options: MountOptions,
+) -> Result<MountId, FsError> {
+    lock().mount(path, fs, options)
+}
+
+/// Copies a file, see `Vfs::copy`
+pub fn copy(src: &str, dst: &str, preserve: bool) -> Result<u64, FsError> {
+    lock().copy(src, dst, preserve)
+}
+
+/// Flushes and detaches the filesystem mounted at `path`, failing with
+/// `FsError::Busy` if it is in use
+pub fn umount(path: &str) -> Result<(), FsError> {
+    lock().umount(path)
+}
+
+/// Flushes every mount on shutdown, before the block caches under them are
+/// flushed
+pub fn register_stop_hooks() {
+    shutdown::register_stop_hook(
+        "filesystems",
+        StopStage::Filesystems,
+        DEFAULT_STOP_TIMEOUT_NS,
+        || {
+            Box::pin(async {
+                let mut vfs = shutdown::lock(&VFS).await;
+                vfs.sync_all().map_err(|e| format!("{:?}", e))
+            })
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesys::{block::memory::MemoryBlockDevice, ext2::Ext2, fat16::Fat16};
+
+    #[test_case]
+    fn test_normalize() {
+        assert_eq!(normalize("/").unwrap(), "/");
+        assert_eq!(normalize("//a/./b/../c/").unwrap(), "/a/c");
+        assert_eq!(normalize("/..").unwrap(), "/");
+        assert!(normalize("relative").is_err());
+    }
+
+    #[test_case]
+    fn test_umount_busy() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+
+        let mut vfs = Vfs::new();
+        vfs.mount("/mnt", Box::new(fs), MountOptions::empty())
+            .unwrap();
+        vfs.create_file("/mnt/busy.txt").unwrap();
+
+        let fd = vfs.open("/mnt/busy.txt").unwrap();
+        assert!(matches!(vfs.umount("/mnt"), Err(FsError::Busy)));
+        vfs.close(fd).unwrap();
+
+        vfs.set_cwd(1, "/mnt").unwrap();
+        assert!(matches!(vfs.umount("/mnt"), Err(FsError::Busy)));
+        vfs.clear_cwd(1);
+
+        vfs.umount("/mnt").unwrap();
+        assert!(matches!(vfs.open("/mnt/busy.txt"), Err(FsError::NotFound)));
+    }
+
+    #[test_case]
+    fn test_mount_points_listed() {
+        let mut vfs = Vfs::new();
+        for path in ["/sd", "/data"] {
+            let device = Box::new(MemoryBlockDevice::new(256, 512));
+            let fs = Fat16::format(device).expect("Failed to format filesystem");
+            vfs.mount(path, Box::new(fs), MountOptions::empty())
+                .unwrap();
+        }
+        vfs.create_file("/sd/file").unwrap();
+
+        // Nothing is mounted at the root, but the mount points still show
+        let mut names: Vec<String> = vfs
+            .read_dir("/")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["data", "sd"]);
+        assert!(vfs.metadata("/sd").unwrap().is_dir);
+        assert!(!vfs.metadata("/sd/file").unwrap().is_dir);
+
+        // A mount covers the directory it is mounted on
+        let mut vfs = Vfs::new();
+        for path in ["/", "/mnt"] {
+            let device = Box::new(MemoryBlockDevice::new(256, 512));
+            let fs = Fat16::format(device).expect("Failed to format filesystem");
+            if path == "/mnt" {
+                vfs.create_dir("/mnt").unwrap();
+                vfs.create_file("/file").unwrap();
+            }
+            vfs.mount(path, Box::new(fs), MountOptions::empty())
+                .unwrap();
+        }
+        let entries = vfs.read_dir("/").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.iter().filter(|e| e.name == "mnt").count(), 1);
+    }
+
+    #[test_case]
+    fn test_read_only_mount() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        fs.create_file("/prog").unwrap();
+
+        let mut vfs = Vfs::new();
+        let options = MountOptions::parse("ro,noexec").unwrap();
+        vfs.mount("/ro", Box::new(fs), options).unwrap();
+
+        assert!(matches!(vfs.create_file("/ro/new"), Err(FsError::ReadOnly)));
+        let fd = vfs.open("/ro/prog").unwrap();
+        assert!(matches!(vfs.write(fd, b"data"), Err(FsError::ReadOnly)));
+        vfs.close(fd).unwrap();
+        assert!(matches!(
+            vfs.read_executable("/ro/prog"),
+            Err(FsError::PermissionDenied)
+        ));
+        assert_eq!(vfs.mounts(), "fat16 /ro fat16 ro,noexec,relatime 0 0\n");
+    }
+
+    #[test_case]
+    fn test_execute_bits() {
+        let device = Box::new(MemoryBlockDevice::new(512, 512));
+        let mut fs = Ext2::format(device).expect("Failed to format filesystem");
+        fs.create_file("/prog").unwrap();
+
+        let mut vfs = Vfs::new();
+        vfs.mount("/", Box::new(fs), MountOptions::empty()).unwrap();
+        assert!(matches!(
+            vfs.read_executable("/prog"),
+            Err(FsError::PermissionDenied)
+        ));
+
+        vfs.with_writable_fs("/prog", |fs, path| {
+            fs.set_permissions(
+                path,
+                FilePermissions {
+                    readable: true,
+                    writable: true,
+                    executable: true,
+                },
+            )
+        })
+        .unwrap();
+        let executable = vfs.read_executable("/prog").unwrap();
+        assert!(executable.bytes.is_empty());
+        assert_eq!(executable.version.path, "/prog");
+        assert_eq!(vfs.mounts(), "ext2 / ext2 rw,relatime 0 0\n");
+    }
+
+    #[test_case]
+    fn test_relatime() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let mut mount = Mount {
+            id: 0,
+            path: "/".into(),
+            options: MountOptions::empty(),
+            fs: Box::new(Fat16::format(device).expect("Failed to format filesystem")),
+        };
+        let day = RELATIME_INTERVAL_SECS;
+        let mut metadata = FileMetadata {
+            size: 0,
+            is_dir: false,
+            created: 0,
+            modified: 10 * day,
+            accessed: 10 * day + 1,
+            permissions: FilePermissions {
+                readable: true,
+                writable: true,
+                executable: false,
+            },
+            mode: None,
+            uid: 0,
+            gid: 0,
+        };
+
+        // Accessed since the last write and recently
+        assert!(!mount.should_update_atime(&metadata, 10 * day + 2));
+        // Accessed over a day ago
+        assert!(mount.should_update_atime(&metadata, 11 * day + 1));
+        // Written since the last access
+        metadata.modified = 10 * day + 5;
+        assert!(mount.should_update_atime(&metadata, 10 * day + 6));
+
+        mount.options = MountOptions::NO_ATIME;
+        assert!(!mount.should_update_atime(&metadata, 20 * day));
+        mount.options = MountOptions::STRICT_ATIME;
+        metadata.modified = 0;
+        assert!(mount.should_update_atime(&metadata, 10 * day + 2));
+    }
+
+    #[test_case]
+    fn test_remove_dir_all() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        let mut vfs = Vfs::new();
+        vfs.mount("/", Box::new(fs), MountOptions::empty()).unwrap();
+
+        vfs.create_dir("/tree").unwrap();
+        vfs.create_dir("/tree/sub").unwrap();
+        vfs.create_file("/tree/sub/file").unwrap();
+        vfs.create_file("/tree/top").unwrap();
+
+        let fd = vfs.open("/tree/sub/file").unwrap();
+        assert!(matches!(vfs.remove_dir_all("/tree"), Err(FsError::Busy)));
+        vfs.close(fd).unwrap();
+
+        vfs.remove_dir_all("/tree").unwrap();
+        assert!(vfs.read_dir("/").unwrap().is_empty());
+    }
+
+    #[test_case]
+    fn test_copy_between_mounts() {
+        let mut vfs = Vfs::new();
+        for path in ["/", "/mnt"] {
+            let device = Box::new(MemoryBlockDevice::new(256, 512));
+            let fs = Fat16::format(device).expect("Failed to format filesystem");
+            vfs.mount(path, Box::new(fs), MountOptions::empty())
+                .unwrap();
+        }
+
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        vfs.create_file("/src").unwrap();
+        let fd = vfs.open("/src").unwrap();
+        vfs.write(fd, &data).unwrap();
+        vfs.close(fd).unwrap();
+        vfs.set_times(
+            "/src",
+            FileTimes {
+                accessed: None,
+                modified: Some(1_000_000_000),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(vfs.copy("/src", "/mnt/dst", true).unwrap(), 5000);
+        let metadata = vfs.metadata("/mnt/dst").unwrap();
+        assert_eq!(metadata.size, 5000);
+        assert_eq!(metadata.modified, 1_000_000_000);
+
+        let fd = vfs.open("/mnt/dst").unwrap();
+        let mut copy = vec![0u8; 5000];
+        let mut read = 0;
+        while read < copy.len() {
+            read += vfs.read(fd, &mut copy[read..]).unwrap();
+        }
+        vfs.close(fd).unwrap();
+        assert_eq!(copy, data);
+    }
+}
@@ -0,0 +1,132 @@
+//! Formatting into fixed buffers without the heap.
+//!
+//! `alloc::format!` cannot be used before the heap is mapped, while the
+//! heap is exhausted, or from code that may run with the heap's lock held,
+//! such as a panic. `BoundedWriter` formats into a buffer the caller owns,
+//! often on the stack, and drops whatever does not fit. `snprintf` wraps it
+//! with the C semantics for buffers handed in by user programs.
+
+use core::fmt::{self, Write};
+
+/// Formats into a fixed buffer, dropping what does not fit. Output is cut
+/// at a character boundary, so what was written is always valid UTF-8.
+pub struct BoundedWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+    /// Bytes the whole output takes, including what was dropped
+    needed: usize,
+}
+
+impl<'a> BoundedWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        BoundedWriter {
+            buffer,
+            length: 0,
+            needed: 0,
+        }
+    }
+
+    /// Bytes written to the buffer
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Bytes the whole output takes, which is more than `len` if some was
+    /// dropped
+    pub fn needed(&self) -> usize {
+        self.needed
+    }
+
+    /// Whether some of the output did not fit
+    pub fn truncated(&self) -> bool {
+        self.needed > self.length
+    }
+
+    /// The output that fit
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters of str slices are ever copied in
+        unsafe { core::str::from_utf8_unchecked(self.as_bytes()) }
+    }
+}
+
+impl Write for BoundedWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.needed += s.len();
+        // Once something was dropped, later output would leave a gap
+        if self.needed - s.len() > self.length {
+            return Ok(());
+        }
+        let room = self.buffer.len() - self.length;
+        let mut taken = s.len().min(room);
+        while !s.is_char_boundary(taken) {
+            taken -= 1;
+        }
+        self.buffer[self.length..self.length + taken].copy_from_slice(&s.as_bytes()[..taken]);
+        self.length += taken;
+        Ok(())
+    }
+}
+
+/// Formats into `buffer` like C's vsnprintf: output that does not fit is
+/// dropped, and the output is NUL terminated unless the buffer is empty
+///
+/// # Returns
+/// The length of the whole output without the terminator, so a result of
+/// `buffer.len()` or more means it was cut short
+pub fn snprintf(buffer: &mut [u8], args: fmt::Arguments) -> usize {
+    let Some(last) = buffer.len().checked_sub(1) else {
+        let mut writer = BoundedWriter::new(buffer);
+        let _ = writer.write_fmt(args);
+        return writer.needed();
+    };
+    let mut writer = BoundedWriter::new(&mut buffer[..last]);
+    let _ = writer.write_fmt(args);
+    let (length, needed) = (writer.len(), writer.needed());
+    buffer[length] = 0;
+    needed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_bounded_writer_truncates() {
+        let mut buffer = [0u8; 8];
+        let mut writer = BoundedWriter::new(&mut buffer);
+        write!(writer, "core {}", 1).unwrap();
+        assert!(!writer.truncated());
+        write!(writer, " panicked").unwrap();
+        assert!(writer.truncated());
+        assert_eq!(writer.len(), 8);
+        assert_eq!(writer.needed(), 15);
+        write!(writer, "!").unwrap();
+        assert_eq!(writer.as_str(), "core 1 p");
+
+        // Never splits a character
+        let mut buffer = [0u8; 4];
+        let mut writer = BoundedWriter::new(&mut buffer);
+        write!(writer, "ab\u{e9}\u{e9}").unwrap();
+        assert_eq!(writer.as_str(), "ab\u{e9}");
+    }
+
+    #[test_case]
+    fn test_snprintf() {
+        let mut buffer = [0xFFu8; 8];
+        assert_eq!(snprintf(&mut buffer, format_args!("pid {}", 42)), 6);
+        assert_eq!(&buffer[..7], b"pid 42\0");
+
+        assert_eq!(snprintf(&mut buffer, format_args!("pid {}", 1234567)), 11);
+        assert_eq!(&buffer, b"pid 123\0");
+
+        assert_eq!(snprintf(&mut [], format_args!("{}", "lost")), 4);
+    }
+}
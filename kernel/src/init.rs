@@ -2,6 +2,7 @@
 //!
 //! Handles the initialization of kernel subsystems and CPU cores.
 
+use alloc::boxed::Box;
 use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use limine::{
     request::SmpRequest,
@@ -10,14 +11,31 @@ use limine::{
 };
 
 use crate::{
-    constants::processes::SYSCALL_BINARY,
-    debug, devices,
-    events::{register_event_runner, run_loop, schedule_process},
+    debug,
+    devices::{self, manager::DEVICE_MANAGER},
+    events::{self, place_process, register_event_runner, run_loop, schedule_idle},
+    filesys::{
+        block::writeback::{self, writeback_daemon},
+        devfs::DevFs,
+        procfs::ProcFs,
+        vfs::{self, MountOptions},
+    },
     interrupts::{self, idt},
-    logging,
-    memory::{self},
-    processes::process::{create_process, run_process_ring3},
-    trace,
+    klog, logging,
+    memory::{
+        self,
+        accounting::{charged, Subsystem},
+        frame_allocator::FRAME_ALLOCATOR,
+        zero_pool::zeroing_daemon,
+        MAPPER,
+    },
+    net,
+    processes::{
+        cgroup::CGROUPS,
+        process::{create_process, run_process_ring3, PROCESS_TABLE},
+        test_binaries,
+    },
+    random, time, trace,
 };
 
 extern crate alloc;
@@ -39,6 +57,17 @@ static BOOT_COMPLETE: AtomicBool = AtomicBool::new(false);
 /// Counter tracking number of initialized CPUs
 static CPU_COUNT: AtomicU64 = AtomicU64::new(0);
 
+/// Adds the global locks most likely to be contended to the lock
+/// contention report
+fn track_locks() {
+    FRAME_ALLOCATOR.track();
+    MAPPER.track();
+    PROCESS_TABLE.track();
+    CGROUPS.track();
+    DEVICE_MANAGER.track();
+    events::track_locks();
+}
+
 /// Initializes kernel subsystems for the Bootstrap Processor (BSP)
 ///
 /// # Returns
@@ -46,22 +75,45 @@ static CPU_COUNT: AtomicU64 = AtomicU64::new(0);
 pub fn init() -> u32 {
     assert!(BASE_REVISION.is_supported());
     interrupts::init(0);
+    time::init();
 
     memory::init(0);
     devices::init(0);
+    random::init();
+    net::init();
     // Should be kept after devices in case logging gets complicated
     // Right now log writes to serial, but if it were to switch to VGA, this would be important
     logging::init(0);
+    klog::report_location();
+
+    track_locks();
+
+    vfs::register_stop_hooks();
+    vfs::mount("/proc", Box::new(ProcFs::new()), MountOptions::READ_ONLY)
+        .expect("Failed to mount procfs");
+    vfs::mount("/dev", Box::new(DevFs::new()), MountOptions::empty())
+        .expect("Failed to mount devfs");
+    writeback::register_stop_hooks();
+    interrupts::register_stop_hooks();
 
     debug!("Waking cores");
     let bsp_id = wake_cores();
+    memory::kernel_image::protect(&mut *MAPPER.lock());
 
     register_event_runner(bsp_id);
+    schedule_idle(bsp_id, charged(Subsystem::Filesystem, writeback_daemon()));
+    schedule_idle(bsp_id, zeroing_daemon());
+    schedule_idle(bsp_id, random::harvest_daemon());
+    #[cfg(feature = "heap-redzones")]
+    schedule_idle(bsp_id, memory::heap::redzone_daemon());
     idt::enable();
 
-    let pid = create_process(SYSCALL_BINARY);
+    let pid = create_process(
+        test_binaries::by_name("syscall_test").expect("Missing syscall_test binary"),
+    )
+    .expect("Failed to create syscall_test process");
     unsafe {
-        schedule_process(bsp_id, run_process_ring3(pid), pid);
+        place_process(run_process_ring3(pid), pid);
     }
 
     bsp_id
@@ -79,6 +131,8 @@ pub fn init() -> u32 {
 /// - Must never return
 #[no_mangle]
 unsafe extern "C" fn secondary_cpu_main(cpu: &Cpu) -> ! {
+    // Must happen before the BSP stops waiting for us
+    time::sync_ap_tsc(cpu.id);
     CPU_COUNT.fetch_add(1, Ordering::SeqCst);
     interrupts::init(cpu.id);
     memory::init(cpu.id);
@@ -118,6 +172,7 @@ fn wake_cores() -> u32 {
 
     // Wait for all APs to initialize
     while CPU_COUNT.load(Ordering::SeqCst) < cpu_count - 1 {
+        time::serve_tsc_sync();
         core::hint::spin_loop();
     }
 
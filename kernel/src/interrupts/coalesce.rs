@@ -0,0 +1,249 @@
+//! Interrupt coalescing for devices that interrupt once per completion.
+//!
+//! A device that raises an interrupt per packet or per command can keep a
+//! core busy with nothing but wakes. A driver's interrupt handler records
+//! its completions in a `Coalescer` instead of waking the driver, and the
+//! coalescer hands them on as one batch once enough have arrived or the
+//! oldest has waited long enough. Registered coalescers are checked for
+//! expired batches on every timer tick, so a lone completion is not left
+//! waiting for a batch that never fills.
+//!
+//! The statistics show how well a configuration works: events per batch is
+//! the number of driver wakes each handled batch saved.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::{arch::without_interrupts, events::futures::WaitQueue, time};
+
+/// When a coalescer hands on a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalesceConfig {
+    /// Events that fill a batch. 1 hands on every event at once.
+    pub max_events: u32,
+    /// Longest the first event of a batch waits for it to fill, in
+    /// nanoseconds
+    pub max_delay_ns: u64,
+}
+
+impl CoalesceConfig {
+    /// Hands on every event as it comes, as without coalescing
+    pub const IMMEDIATE: CoalesceConfig = CoalesceConfig {
+        max_events: 1,
+        max_delay_ns: 0,
+    };
+}
+
+/// How a coalescer has batched events so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoalesceStats {
+    pub events: u64,
+    /// Batches handed on, each one driver wake
+    pub batches: u64,
+    /// Batches handed on because they filled
+    pub full_batches: u64,
+    /// Batches handed on because their first event waited too long
+    pub timed_out_batches: u64,
+    pub largest_batch: u32,
+}
+
+impl CoalesceStats {
+    /// Average events per batch, 0 before the first batch
+    pub fn events_per_batch(&self) -> u64 {
+        self.events.checked_div(self.batches).unwrap_or(0)
+    }
+}
+
+struct State {
+    config: CoalesceConfig,
+    /// Events in the batch being filled
+    pending: u32,
+    /// When the first of them arrived
+    first_ns: u64,
+    /// Events handed on that the driver has not taken yet
+    ready: u32,
+    stats: CoalesceStats,
+}
+
+/// Batches the events of one device
+pub struct Coalescer {
+    /// Only locked with interrupts off, since handlers record into it
+    state: Mutex<State>,
+    handed_on: WaitQueue,
+}
+
+impl Coalescer {
+    pub const fn new(config: CoalesceConfig) -> Self {
+        Coalescer {
+            state: Mutex::new(State {
+                config,
+                pending: 0,
+                first_ns: 0,
+                ready: 0,
+                stats: CoalesceStats {
+                    events: 0,
+                    batches: 0,
+                    full_batches: 0,
+                    timed_out_batches: 0,
+                    largest_batch: 0,
+                },
+            }),
+            handed_on: WaitQueue::new(),
+        }
+    }
+
+    /// Changes the thresholds. A batch already being filled is measured
+    /// against the new ones from its next event or timer tick on.
+    pub fn configure(&self, config: CoalesceConfig) {
+        without_interrupts(|| self.state.lock().config = config);
+    }
+
+    pub fn config(&self) -> CoalesceConfig {
+        without_interrupts(|| self.state.lock().config)
+    }
+
+    pub fn stats(&self) -> CoalesceStats {
+        without_interrupts(|| self.state.lock().stats)
+    }
+
+    /// Records `count` events, called from the device's interrupt handler
+    ///
+    /// # Returns
+    /// True if a batch was handed on
+    pub fn record(&self, count: u32) -> bool {
+        self.record_at(count, time::monotonic_ns())
+    }
+
+    fn record_at(&self, count: u32, now: u64) -> bool {
+        let handed_on = without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.pending == 0 {
+                state.first_ns = now;
+            }
+            state.pending += count;
+            state.stats.events += count as u64;
+            if state.pending >= state.config.max_events {
+                state.stats.full_batches += 1;
+                Self::hand_on(&mut state);
+                true
+            } else if now.saturating_sub(state.first_ns) >= state.config.max_delay_ns {
+                state.stats.timed_out_batches += 1;
+                Self::hand_on(&mut state);
+                true
+            } else {
+                false
+            }
+        });
+        if handed_on {
+            self.handed_on.wake_all();
+        }
+        handed_on
+    }
+
+    /// Hands on the batch being filled if its first event has waited too
+    /// long
+    ///
+    /// # Returns
+    /// True if a batch was handed on
+    pub fn flush_expired(&self, now: u64) -> bool {
+        let handed_on = without_interrupts(|| {
+            let mut state = self.state.lock();
+            let expired = state.pending > 0
+                && now.saturating_sub(state.first_ns) >= state.config.max_delay_ns;
+            if expired {
+                state.stats.timed_out_batches += 1;
+                Self::hand_on(&mut state);
+            }
+            expired
+        });
+        if handed_on {
+            self.handed_on.wake_all();
+        }
+        handed_on
+    }
+
+    fn hand_on(state: &mut State) {
+        let batch = state.pending;
+        state.ready += batch;
+        state.pending = 0;
+        state.stats.batches += 1;
+        state.stats.largest_batch = state.stats.largest_batch.max(batch);
+    }
+
+    /// Takes the events handed on so far without waiting
+    ///
+    /// # Returns
+    /// The number of events taken, 0 if none were ready
+    pub fn take(&self) -> u32 {
+        without_interrupts(|| core::mem::take(&mut self.state.lock().ready))
+    }
+
+    /// Waits for a batch to be handed on and takes it
+    ///
+    /// # Returns
+    /// The number of events in the batch, at least 1
+    pub async fn next_batch(&self) -> u32 {
+        let mut events = 0;
+        self.handed_on
+            .wait_until(|| {
+                events = self.take();
+                events > 0
+            })
+            .await;
+        events
+    }
+}
+
+/// Coalescers whose batches the timer expires
+static REGISTERED: Mutex<Vec<&'static Coalescer>> = Mutex::new(Vec::new());
+
+/// Has the timer hand on batches of `coalescer` that waited too long
+pub fn register(coalescer: &'static Coalescer) {
+    without_interrupts(|| REGISTERED.lock().push(coalescer));
+}
+
+/// Hands on every registered batch that has waited too long. Called from
+/// the timer interrupt.
+pub fn flush_expired() {
+    let now = time::monotonic_ns();
+    for coalescer in REGISTERED.lock().iter() {
+        coalescer.flush_expired(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_coalescing() {
+        let coalescer = Coalescer::new(CoalesceConfig {
+            max_events: 4,
+            max_delay_ns: 1_000,
+        });
+
+        // Three events stay together, the fourth fills the batch
+        assert!(!coalescer.record_at(2, 100));
+        assert!(!coalescer.record_at(1, 500));
+        assert_eq!(coalescer.take(), 0);
+        assert!(coalescer.record_at(1, 600));
+        assert_eq!(coalescer.take(), 4);
+
+        // A lone event goes once it has waited long enough
+        assert!(!coalescer.record_at(1, 2_000));
+        assert!(!coalescer.flush_expired(2_500));
+        assert!(coalescer.flush_expired(3_000));
+        assert!(!coalescer.flush_expired(9_000));
+        assert_eq!(coalescer.take(), 1);
+
+        coalescer.configure(CoalesceConfig::IMMEDIATE);
+        assert!(coalescer.record_at(1, 10_000));
+
+        let stats = coalescer.stats();
+        assert_eq!(stats.events, 6);
+        assert_eq!(stats.batches, 3);
+        assert_eq!((stats.full_batches, stats.timed_out_batches), (2, 1));
+        assert_eq!(stats.largest_batch, 4);
+        assert_eq!(stats.events_per_batch(), 2);
+    }
+}
@@ -6,29 +6,57 @@
 //! - Timer interrupt handling
 //! - Functions to enable/disable interrupts
 
-use core::arch::naked_asm;
+use core::{
+    arch::naked_asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use lazy_static::lazy_static;
 use x86_64::{
     instructions::interrupts,
-    structures::{
-        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
-        paging::{OffsetPageTable, Page, PageTable},
-    },
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
     VirtAddr,
 };
 
 use crate::{
     constants::{
-        idt::{SYSCALL_HANDLER, TIMER_VECTOR, TLB_SHOOTDOWN_VECTOR},
-        syscalls::{SYSCALL_EXIT, SYSCALL_PRINT},
+        idt::{
+            DEVICE_VECTOR_BASE, DEVICE_VECTOR_COUNT, SYSCALL_HANDLER, TIMER_VECTOR,
+            TLB_SHOOTDOWN_VECTOR, WAKE_VECTOR,
+        },
+        processes::{KERNEL_PID, SIGNAL_EXIT_BASE},
+        syscalls::{
+            ENOSYS, SIGSEGV, SYSCALL_BRK, SYSCALL_CLOCK_GETTIME, SYSCALL_CLOSE, SYSCALL_DUP,
+            SYSCALL_DUP2, SYSCALL_EXEC, SYSCALL_EXIT, SYSCALL_KILL, SYSCALL_MMAP, SYSCALL_MPROTECT,
+            SYSCALL_MSYNC, SYSCALL_MUNMAP, SYSCALL_NICE, SYSCALL_OPEN, SYSCALL_PIPE, SYSCALL_PRINT,
+            SYSCALL_READ, SYSCALL_REBOOT, SYSCALL_SBRK, SYSCALL_SEEK, SYSCALL_SETTIMEOFDAY,
+            SYSCALL_SIGACTION, SYSCALL_SIGRETURN, SYSCALL_STATFS, SYSCALL_UNAME, SYSCALL_UTIMENSAT,
+            SYSCALL_WAITPID, SYSCALL_WRITE,
+        },
+    },
+    events::{check_poll_budget, current_running_event_info, schedule_process, timer, EventInfo},
+    interrupts::{
+        coalesce,
+        x2apic::{self, current_core_id, TLB_SHOOTDOWN_ADDR},
     },
-    events::{current_running_event_info, schedule_process, EventInfo},
-    interrupts::x2apic::{self, current_core_id, TLB_SHOOTDOWN_ADDR},
-    memory::{paging::create_mapping, HHDM_OFFSET},
+    memory::{kernel_image, tlb},
     prelude::*,
-    processes::process::{run_process_ring3, ProcessState, PROCESS_TABLE},
-    syscalls::syscall_handlers::sys_exit,
+    processes::{
+        process::{
+            copy_current_on_write, exit_process, fault_current_mapping, grow_current_heap,
+            grow_current_stack, run_process_ring3, ProcessState, PROCESS_TABLE,
+        },
+        registers::Registers,
+        rusage::{with_current_stats, SwitchReason},
+        signal::{deliver_pending, terminate_current},
+    },
+    syscalls::syscall_handlers::{
+        sys_brk, sys_clock_gettime, sys_close, sys_dup, sys_dup2, sys_exec, sys_exit, sys_kill,
+        sys_mmap, sys_mprotect, sys_msync, sys_munmap, sys_nice, sys_open, sys_pipe, sys_print,
+        sys_read, sys_reboot, sys_sbrk, sys_seek, sys_settimeofday, sys_sigaction, sys_sigreturn,
+        sys_statfs, sys_uname, sys_utimensat, sys_waitpid, sys_write,
+    },
+    tracing::{self, TraceEvent},
 };
 
 lazy_static! {
@@ -50,10 +78,59 @@ lazy_static! {
             .set_handler_fn(naked_syscall_handler)
             .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
         idt[TLB_SHOOTDOWN_VECTOR].set_handler_fn(tlb_shootdown_handler);
+        idt[WAKE_VECTOR].set_handler_fn(wake_handler);
+        for (i, handler) in DEVICE_HANDLERS.iter().enumerate() {
+            idt[DEVICE_VECTOR_BASE + i as u8].set_handler_fn(*handler);
+        }
         idt
     };
 }
 
+/// Handlers of the device vectors, as the addresses of the functions
+/// drivers passed to allocate_vector, 0 while a vector is free
+static DEVICE_VECTORS: [AtomicUsize; DEVICE_VECTOR_COUNT] =
+    [const { AtomicUsize::new(0) }; DEVICE_VECTOR_COUNT];
+
+/// Entry points of the device vectors, each dispatching to its driver
+const DEVICE_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); DEVICE_VECTOR_COUNT] = [
+    device_handler::<0>,
+    device_handler::<1>,
+    device_handler::<2>,
+    device_handler::<3>,
+    device_handler::<4>,
+    device_handler::<5>,
+    device_handler::<6>,
+    device_handler::<7>,
+    device_handler::<8>,
+    device_handler::<9>,
+    device_handler::<10>,
+    device_handler::<11>,
+    device_handler::<12>,
+    device_handler::<13>,
+    device_handler::<14>,
+    device_handler::<15>,
+];
+
+/// Sets aside a device vector whose interrupts call `handler`. The handler
+/// runs in interrupt context, and the interrupt is acknowledged after it.
+///
+/// # Returns
+/// The vector, or None if every device vector is taken
+pub fn allocate_vector(handler: fn()) -> Option<u8> {
+    DEVICE_VECTORS.iter().enumerate().find_map(|(i, slot)| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| DEVICE_VECTOR_BASE + i as u8)
+    })
+}
+
+/// Returns a vector from allocate_vector. The device must no longer raise
+/// it.
+pub fn free_vector(vector: u8) {
+    let index = usize::from(vector - DEVICE_VECTOR_BASE);
+    DEVICE_VECTORS[index].store(0, Ordering::Release);
+}
+
 /// Loads the IDT for the specified CPU core.
 pub fn init_idt(_cpu_id: u32) {
     IDT.load();
@@ -131,18 +208,62 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::{Cr2, Cr3};
+    use x86_64::registers::control::Cr2;
 
-    let faulting_address = Cr2::read().expect("Cannot read faulting address").as_u64();
-    let pml4 = Cr3::read().0;
-    let new_pml4_phys = pml4.start_address();
-    let new_pml4_virt = VirtAddr::new((*HHDM_OFFSET).as_u64()) + new_pml4_phys.as_u64();
-    let new_pml4_ptr: *mut PageTable = new_pml4_virt.as_mut_ptr();
+    let faulting_address = Cr2::read().expect("Cannot read faulting address");
+
+    // The first touch of a page in a stack area or the heap maps it
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && (grow_current_stack(faulting_address) || grow_current_heap(faulting_address))
+    {
+        return;
+    }
+
+    // Pages of a program's data get a private copy on first write
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && copy_current_on_write(faulting_address)
+    {
+        return;
+    }
+
+    // File pages are read in on first touch, and shared ones are made
+    // writable on first write
+    if fault_current_mapping(
+        faulting_address,
+        error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+    ) {
+        return;
+    }
 
-    let mut mapper =
-        unsafe { OffsetPageTable::new(&mut *new_pml4_ptr, VirtAddr::new((*HHDM_OFFSET).as_u64())) };
+    // Anything else a process does wrong ends it, rather than the kernel
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        let pid = current_running_event_info(current_core_id() as u32).pid;
+        serial_println!(
+            "Process {} faulted at {:?} from {:?}",
+            pid,
+            faulting_address,
+            stack_frame.instruction_pointer
+        );
+        terminate_current(pid, SIGSEGV);
+    }
 
-    let stack_pointer = stack_frame.stack_pointer.as_u64();
+    if let Some(section) = kernel_image::section_of(faulting_address) {
+        let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "Execute of"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "Write to"
+        } else {
+            "Access to"
+        };
+        panic!(
+            "{} kernel {} at {:?} from {:?}",
+            access,
+            section.name(),
+            faulting_address,
+            stack_frame.instruction_pointer
+        );
+    }
 
     serial_println!(
         "EXCEPTION: PAGE FAULT\nFaulting Address: {:?}\nError Code: {:X}\n{:#?}",
@@ -151,13 +272,6 @@ extern "x86-interrupt" fn page_fault_handler(
         stack_frame
     );
 
-    let page = Page::containing_address(VirtAddr::new(faulting_address));
-
-    // check for stack growth
-    if stack_pointer - 64 <= faulting_address && faulting_address < (*HHDM_OFFSET).as_u64() {
-        create_mapping(page, &mut mapper, None);
-    }
-
     panic!("PAGE FAULT!");
 }
 
@@ -165,27 +279,47 @@ extern "x86-interrupt" fn page_fault_handler(
 #[naked]
 pub extern "x86-interrupt" fn naked_syscall_handler(_: InterruptStackFrame) {
     unsafe {
+        // Everything is saved, as for the timer, so a syscall that blocks
+        // can save the process' registers and resume it later
         naked_asm!(
-            // Push registers to save them
-            "push rax",
-            "push rdi",
-            "push rsi",
-            "push rdx",
-            "push rcx",
-            "push r8",
-            "push r9",
-            "mov	rdi, rsp",
-            // Call the syscall_handler
-            "call syscall_handler",
-            // Restore registers
-            "pop r9",
-            "pop r8",
-            "pop rcx",
-            "pop rdx",
-            "pop rsi",
-            "pop rdi",
-            "pop rax",
-            "iretq"
+            "
+            push rbp
+            push r15
+            push r14
+            push r13
+            push r12
+            push r11
+            push r10
+            push r9
+            push r8
+            push rdi
+            push rsi
+            push rdx
+            push rcx
+            push rbx
+            push rax
+
+            cld
+            mov	rdi, rsp
+            call syscall_handler
+
+            pop rax
+            pop rbx
+            pop rcx
+            pop rdx
+            pop rsi
+            pop rdi
+            pop r8
+            pop r9
+            pop r10
+            pop r11
+            pop r12
+            pop r13
+            pop r14
+            pop r15
+            pop rbp
+            iretq
+      "
         );
     }
 }
@@ -202,15 +336,20 @@ fn syscall_handler(rsp: u64) {
     let p6: u64;
     let stack_ptr: *const u64 = rsp as *const u64;
     unsafe {
-        syscall_num = *stack_ptr.add(6);
+        syscall_num = *stack_ptr.add(0);
         p1 = *stack_ptr.add(5);
         p2 = *stack_ptr.add(4);
         p3 = *stack_ptr.add(3);
         p4 = *stack_ptr.add(2);
-        p5 = *stack_ptr.add(1);
-        p6 = *stack_ptr.add(0);
+        p5 = *stack_ptr.add(6);
+        p6 = *stack_ptr.add(7);
     }
 
+    with_current_stats(|stats| {
+        stats.enter_kernel();
+        stats.syscalls.fetch_add(1, Ordering::Relaxed);
+    });
+
     // temporarily, just print the parameter registers
     serial_println!("Parameter 1: {}", p1);
     serial_println!("Parameter 2: {}", p2);
@@ -219,12 +358,45 @@ fn syscall_handler(rsp: u64) {
     serial_println!("Parameter 5: {}", p5);
     serial_println!("Parameter 6: {}", p6);
 
-    match syscall_num as u32 {
-        SYSCALL_EXIT => sys_exit(),
-        SYSCALL_PRINT => serial_println!("Hello world!"),
-        _ => panic!("Unknown syscall: {}", syscall_num),
+    let result: i64 = match syscall_num as u32 {
+        SYSCALL_EXIT => sys_exit(p1),
+        SYSCALL_PRINT => sys_print(p1, p2, rsp),
+        SYSCALL_CLOCK_GETTIME => sys_clock_gettime(p1, p2),
+        SYSCALL_SETTIMEOFDAY => sys_settimeofday(p1),
+        SYSCALL_STATFS => sys_statfs(p1, p2),
+        SYSCALL_UTIMENSAT => sys_utimensat(p1, p2),
+        SYSCALL_REBOOT => sys_reboot(p1),
+        SYSCALL_NICE => sys_nice(p1),
+        SYSCALL_OPEN => sys_open(p1, p2),
+        SYSCALL_READ => sys_read(p1, p2, p3, rsp),
+        SYSCALL_WRITE => sys_write(p1, p2, p3, rsp),
+        SYSCALL_CLOSE => sys_close(p1),
+        SYSCALL_SEEK => sys_seek(p1, p2, p3),
+        SYSCALL_DUP => sys_dup(p1),
+        SYSCALL_DUP2 => sys_dup2(p1, p2),
+        SYSCALL_EXEC => sys_exec(p1),
+        SYSCALL_WAITPID => sys_waitpid(p1, p2, p3, rsp),
+        SYSCALL_UNAME => sys_uname(p1),
+        SYSCALL_MMAP => sys_mmap(p1, p2, p3, p4, p5, p6),
+        SYSCALL_MSYNC => sys_msync(p1, p2, p3),
+        SYSCALL_MUNMAP => sys_munmap(p1, p2),
+        SYSCALL_MPROTECT => sys_mprotect(p1, p2, p3),
+        SYSCALL_PIPE => sys_pipe(p1),
+        SYSCALL_KILL => sys_kill(p1, p2),
+        SYSCALL_SIGACTION => sys_sigaction(p1, p2, p3),
+        SYSCALL_SIGRETURN => sys_sigreturn(rsp),
+        SYSCALL_BRK => sys_brk(p1),
+        SYSCALL_SBRK => sys_sbrk(p1),
+        _ => -ENOSYS,
     };
 
+    with_current_stats(|stats| stats.leave_kernel());
+
+    // The saved rax is restored on return, so it carries the result
+    unsafe {
+        *(stack_ptr as *mut u64).add(0) = result as u64;
+    }
+
     x2apic::send_eoi();
 }
 #[naked]
@@ -276,20 +448,35 @@ extern "x86-interrupt" fn naked_timer_handler(_: InterruptStackFrame) {
 
 #[no_mangle]
 extern "C" fn timer_handler(rsp: u64) {
+    // Only takes locks that are held with interrupts off, so it is safe
+    // whatever was interrupted
+    coalesce::flush_expired();
+    timer::wake_expired();
+
+    // Code interrupted in ring 0, kernel threads included, is not preempted
+    // as it may hold a lock the next event needs. The CS pushed with the
+    // interrupt frame has the interrupted privilege level.
+    if unsafe { *(rsp as *const u64).add(16) } & 3 == 0 {
+        // It is asked to yield at its next checkpoint instead
+        check_poll_budget(x2apic::current_core_id() as u32);
+        x2apic::send_eoi();
+        return;
+    }
     let cpuid: u32 = x2apic::current_core_id() as u32;
     let event: EventInfo = current_running_event_info(cpuid);
-    if event.pid == 0 {
+    // Kernel work is not preempted
+    if event.pid == KERNEL_PID {
         x2apic::send_eoi();
         return;
     }
 
-    // Get PCB from PID
     let preemption_info = unsafe {
-        let mut process_table = PROCESS_TABLE.write();
-        let process = process_table
-            .get_mut(&event.pid)
-            .expect("Process not found");
-
+        // Held while the PCB changes, so snapshots never see it half saved
+        let process_table = PROCESS_TABLE.write();
+        let Some(process) = process_table.get(&event.pid) else {
+            x2apic::send_eoi();
+            return;
+        };
         let pcb = process.pcb.get();
 
         if (*pcb).state != ProcessState::Running {
@@ -298,33 +485,34 @@ extern "C" fn timer_handler(rsp: u64) {
         }
 
         // save registers to the PCB
-        let stack_ptr: *const u64 = rsp as *const u64;
-
-        (*pcb).registers.rax = *stack_ptr.add(0);
-        (*pcb).registers.rbx = *stack_ptr.add(1);
-        (*pcb).registers.rcx = *stack_ptr.add(2);
-        (*pcb).registers.rdx = *stack_ptr.add(3);
-        (*pcb).registers.rsi = *stack_ptr.add(4);
-        (*pcb).registers.rdi = *stack_ptr.add(5);
-        (*pcb).registers.r8 = *stack_ptr.add(6);
-        (*pcb).registers.r9 = *stack_ptr.add(7);
-        (*pcb).registers.r10 = *stack_ptr.add(8);
-        (*pcb).registers.r11 = *stack_ptr.add(9);
-        (*pcb).registers.r12 = *stack_ptr.add(10);
-        (*pcb).registers.r13 = *stack_ptr.add(11);
-        (*pcb).registers.r14 = *stack_ptr.add(12);
-        (*pcb).registers.r15 = *stack_ptr.add(13);
-        (*pcb).registers.rbp = *stack_ptr.add(14);
-        // saved from interrupt stack frame
-        (*pcb).registers.rsp = *stack_ptr.add(18);
-        (*pcb).registers.rip = *stack_ptr.add(15);
-        (*pcb).registers.rflags = *stack_ptr.add(17);
+        (*pcb).registers = Registers::from_stack(rsp);
 
         (*pcb).state = ProcessState::Blocked;
+        process.stats.enter_kernel();
+        process.stats.record_switch(SwitchReason::Preempted);
 
         ((*pcb).kernel_rsp, (*pcb).kernel_rip)
     };
 
+    // Outside the table lock, since a handler's frame may need stack pages
+    if let Some(signal) = deliver_pending(event.pid) {
+        serial_println!("Process {} killed by signal {}", event.pid, signal);
+        if let Ok(preemption_info) = exit_process(event.pid, SIGNAL_EXIT_BASE + signal as i32) {
+            unsafe {
+                core::arch::asm!(
+                    "mov rsp, {0}",
+                    "push {1}",
+                    in(reg) preemption_info.0,
+                    in(reg) preemption_info.1
+                );
+
+                x2apic::send_eoi();
+
+                core::arch::asm!("ret");
+            }
+        }
+    }
+
     unsafe {
         schedule_process(cpuid, run_process_ring3(event.pid), event.pid);
 
@@ -346,16 +534,35 @@ extern "C" fn timer_handler(rsp: u64) {
 // invalidate its TLB rather than doing this in parallel. While this is slow, this is of low
 // priority to fix
 extern "x86-interrupt" fn tlb_shootdown_handler(_: InterruptStackFrame) {
+    tracing::record(TraceEvent::IpiReceived {
+        vector: TLB_SHOOTDOWN_VECTOR,
+    });
     let core = current_core_id();
     {
         let mut addresses = TLB_SHOOTDOWN_ADDR.lock();
-        let vaddr_to_invalidate = addresses[core];
-        if vaddr_to_invalidate != 0 {
-            unsafe {
-                core::arch::asm!("invlpg [{}]", in (reg) vaddr_to_invalidate, options(nostack, preserves_flags));
-            }
-            addresses[core] = 0;
+        let (start, pages) = addresses[core];
+        if pages != 0 {
+            tlb::flush_local(VirtAddr::new(start), pages);
+            addresses[core] = (0, 0);
         }
     }
     x2apic::send_eoi();
 }
+
+// Only has to bring the core out of hlt, its runner then finds the new work
+extern "x86-interrupt" fn wake_handler(_: InterruptStackFrame) {
+    tracing::record(TraceEvent::IpiReceived {
+        vector: WAKE_VECTOR,
+    });
+    x2apic::send_eoi();
+}
+
+extern "x86-interrupt" fn device_handler<const N: usize>(_: InterruptStackFrame) {
+    let handler = DEVICE_VECTORS[N].load(Ordering::Acquire);
+    // A vector freed while its interrupt was in flight has nothing to run
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    x2apic::send_eoi();
+}
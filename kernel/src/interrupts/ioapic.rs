@@ -0,0 +1,190 @@
+//! I/O APIC routing for legacy ISA and PCI interrupts.
+//!
+//! Devices that are not on PCI, such as the UARTs, raise ISA IRQ lines,
+//! which the I/O APIC turns into interrupts for a core's x2APIC. Only the
+//! first I/O APIC is driven, at IOAPIC_BASE, and each ISA IRQ is assumed
+//! to be wired to the pin of the same number, as on QEMU's machines. The
+//! interrupt source overrides in the ACPI MADT are not read yet.
+//!
+//! PCI devices without MSI raise the interrupt line in their configuration
+//! space, which QEMU's PCI to ISA bridge hands on to the pin of the same
+//! number, level triggered and active high. Devices often share a line,
+//! so every handler registered for PCI lines runs on one vector and must
+//! check whether its own device raised the interrupt.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::{
+    constants::x2apic::IOAPIC_BASE,
+    devices::pci::map_mmio_region,
+    interrupts::idt::{allocate_vector, without_interrupts},
+    memory::MAPPER,
+};
+
+/// Offsets of the register select and data window from the base
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+/// Indirect registers
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+/// Redirection entry bit that keeps the pin from raising interrupts
+const REDIRECTION_MASKED: u64 = 1 << 16;
+/// Redirection entry bit for level triggered pins
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+/// Highest APIC ID a redirection entry can name in physical mode
+const MAX_DESTINATION: u32 = 0xFF;
+
+/// Kernel virtual address of the I/O APIC, once mapped
+static IOAPIC: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Number of handlers that can share the legacy PCI lines
+const MAX_PCI_HANDLERS: usize = 8;
+
+/// Handlers run for every legacy PCI interrupt, 0 for an empty slot.
+/// Read without a lock so the dispatcher never spins in an interrupt.
+static PCI_HANDLERS: [AtomicUsize; MAX_PCI_HANDLERS] =
+    [const { AtomicUsize::new(0) }; MAX_PCI_HANDLERS];
+
+/// Which legacy PCI lines are routed, and to which vector
+struct PciLines {
+    /// The vector every line is delivered as, once allocated
+    vector: Option<u8>,
+    /// Bit n is set once line n is routed
+    routed: u64,
+}
+
+static PCI_LINES: Mutex<PciLines> = Mutex::new(PciLines {
+    vector: None,
+    routed: 0,
+});
+
+/// Runs `f` on the I/O APIC's registers, mapping them first if needed
+fn with_ioapic<T>(f: impl FnOnce(u64) -> T) -> T {
+    let mut ioapic = IOAPIC.lock();
+    let base =
+        *ioapic.get_or_insert_with(|| map_mmio_region(&mut MAPPER.lock(), IOAPIC_BASE, 0x20));
+    f(base)
+}
+
+unsafe fn read(base: u64, register: u32) -> u32 {
+    unsafe {
+        core::ptr::write_volatile((base + IOREGSEL) as *mut u32, register);
+        core::ptr::read_volatile((base + IOWIN) as *const u32)
+    }
+}
+
+unsafe fn write(base: u64, register: u32, value: u32) {
+    unsafe {
+        core::ptr::write_volatile((base + IOREGSEL) as *mut u32, register);
+        core::ptr::write_volatile((base + IOWIN) as *mut u32, value);
+    }
+}
+
+/// Number of pins, and so of redirection entries
+fn pins(base: u64) -> u32 {
+    (unsafe { read(base, IOAPICVER) } >> 16 & 0xFF) + 1
+}
+
+unsafe fn write_entry(base: u64, pin: u32, entry: u64) {
+    unsafe {
+        // Masked while the halves disagree
+        write(base, IOREDTBL + 2 * pin, REDIRECTION_MASKED as u32);
+        write(base, IOREDTBL + 2 * pin + 1, (entry >> 32) as u32);
+        write(base, IOREDTBL + 2 * pin, entry as u32);
+    }
+}
+
+/// Delivers an ISA IRQ as `vector` to the core with x2APIC ID `apic_id`.
+/// ISA IRQs are edge triggered and active high, so acknowledging the
+/// vector at the x2APIC is all a handler needs to do.
+///
+/// # Returns
+/// False if the I/O APIC has no such pin or cannot reach the core
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u32) -> bool {
+    route(irq, vector, apic_id, 0)
+}
+
+/// Calls `handler` whenever a legacy PCI interrupt line is raised, routing
+/// `line` to the core with x2APIC ID `apic_id` if no one has routed it yet.
+///
+/// Lines are shared between devices and all of them arrive on one vector,
+/// so `handler` runs for interrupts from other devices too. It must return
+/// at once if its device has nothing pending, and otherwise clear the
+/// device's interrupt status, since the line is level triggered and is
+/// raised again as soon as the vector is acknowledged.
+///
+/// # Returns
+/// False if there is no free handler slot or vector, or the I/O APIC has
+/// no such pin or cannot reach the core
+pub fn handle_pci_irq(line: u8, handler: fn(), apic_id: u32) -> bool {
+    if u32::from(line) >= u64::BITS {
+        return false;
+    }
+    without_interrupts(|| {
+        let mut lines = PCI_LINES.lock();
+        let vector = match lines.vector {
+            Some(vector) => vector,
+            None => {
+                let Some(vector) = allocate_vector(dispatch_pci_irq) else {
+                    return false;
+                };
+                lines.vector = Some(vector);
+                vector
+            }
+        };
+        let Some(slot) = PCI_HANDLERS
+            .iter()
+            .find(|slot| slot.load(Ordering::Acquire) == 0)
+        else {
+            return false;
+        };
+        // Registered before routing, so the first interrupt has a handler
+        slot.store(handler as usize, Ordering::Release);
+        if lines.routed & (1 << line) == 0 {
+            if !route(line, vector, apic_id, REDIRECTION_LEVEL) {
+                slot.store(0, Ordering::Release);
+                return false;
+            }
+            lines.routed |= 1 << line;
+        }
+        true
+    })
+}
+
+/// Runs every registered handler, as the shared line does not say which
+/// device raised it
+fn dispatch_pci_irq() {
+    for slot in &PCI_HANDLERS {
+        let handler = slot.load(Ordering::Acquire);
+        if handler != 0 {
+            let handler: fn() = unsafe { core::mem::transmute(handler) };
+            handler();
+        }
+    }
+}
+
+/// Fixed delivery to a physical destination, active high, with `trigger`
+/// choosing edge or level triggering
+fn route(pin: u8, vector: u8, apic_id: u32, trigger: u64) -> bool {
+    if apic_id > MAX_DESTINATION {
+        return false;
+    }
+    with_ioapic(|base| {
+        if u32::from(pin) >= pins(base) {
+            return false;
+        }
+        let entry = (u64::from(apic_id) << 56) | trigger | u64::from(vector);
+        unsafe { write_entry(base, u32::from(pin), entry) };
+        true
+    })
+}
+
+/// Stops an ISA IRQ from raising interrupts
+pub fn mask_isa_irq(irq: u8) {
+    with_ioapic(|base| {
+        if u32::from(irq) < pins(base) {
+            unsafe { write_entry(base, u32::from(irq), REDIRECTION_MASKED) };
+        }
+    })
+}
@@ -4,12 +4,20 @@
 //! - Global Descriptor Table (GDT)
 //! - Interrupt Descriptor Table (IDT)
 //! - Advanced Programmable Interrupt Controller (x2APIC)
+//! - I/O APIC routing of legacy ISA interrupts
 //! - Exception handlers and interrupt handling
 
-use crate::constants::x2apic::CPU_FREQUENCY;
+use alloc::boxed::Box;
 
+use crate::{
+    constants::x2apic::CPU_FREQUENCY,
+    shutdown::{self, StopStage, DEFAULT_STOP_TIMEOUT_NS},
+};
+
+pub mod coalesce;
 pub mod gdt;
 pub mod idt;
+pub mod ioapic;
 pub mod x2apic;
 
 /// Initialize interrupt handling for a CPU core.
@@ -32,3 +40,19 @@ pub fn init(cpu_id: u32) {
         x2apic::init_ap().expect("Failed to initialize core APIC");
     }
 }
+
+/// Stops interrupt delivery on the shutting down core once devices are
+/// flushed
+pub fn register_stop_hooks() {
+    shutdown::register_stop_hook(
+        "interrupts",
+        StopStage::Interrupts,
+        DEFAULT_STOP_TIMEOUT_NS,
+        || {
+            Box::pin(async {
+                idt::disable();
+                Ok(())
+            })
+        },
+    );
+}
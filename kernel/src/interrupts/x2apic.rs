@@ -6,8 +6,18 @@
 //! - Timer masking/unmasking
 //! - End-of-interrupt (EOI) handling
 
-use crate::constants::{idt::TIMER_VECTOR, MAX_CORES};
-use core::sync::atomic::{AtomicU32, Ordering};
+use crate::{
+    constants::{idt::TIMER_VECTOR, MAX_CORES},
+    devices::{
+        drivers::DriverError,
+        manager::{DeviceHandle, PowerOps},
+    },
+    tracing::{self, TraceEvent},
+};
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU32, Ordering},
+};
 use raw_cpuid::CpuId;
 use spin::Mutex;
 use x86_64::{instructions::port::Port, registers::model_specific::Msr};
@@ -61,8 +71,9 @@ pub enum PitError {
 static mut APIC_MANAGER: X2ApicManager = X2ApicManager::new();
 /// Stores calibrated timer count value shared between cores
 static CALIBRATED_TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
-/// Global to manage what addresses to invalidate when shootdowns happen
-pub static TLB_SHOOTDOWN_ADDR: Mutex<[u64; MAX_CORES]> = Mutex::new([0; MAX_CORES]);
+/// Global to manage what addresses to invalidate when shootdowns happen:
+/// the first address and the number of pages for each core
+pub static TLB_SHOOTDOWN_ADDR: Mutex<[(u64, u64); MAX_CORES]> = Mutex::new([(0, 0); MAX_CORES]);
 
 /// Manages x2APIC instances for all CPU cores
 pub struct X2ApicManager {
@@ -273,47 +284,74 @@ impl Pit {
         }
     }
 
-    /// Calibrates the APIC timer using PIT as a reference clock
-    ///
-    /// # Arguments
-    /// * `hz` - Desired APIC timer frequency in Hertz
-    ///
-    /// # Returns
-    /// Timer count value needed to achieve the requested frequency
-    pub fn calibrate_apic_timer(&mut self, hz: u32) -> Result<u32, PitError> {
-        unsafe {
-            X2ApicManager::mask_timer().map_err(|_| PitError::CalibrationFailed)?;
-
-            // Set divider to 1
-            Msr::new(X2APIC_TIMER_DCR).write(0xB);
-
-            let initial = u32::MAX;
-            Msr::new(X2APIC_TIMER_ICR).write(initial as u64);
+    /// Runs channel 2 at 20 Hz and busy waits until its output has toggled
+    /// `changes` times. Each toggle is 25ms.
+    fn wait_half_periods(&mut self, changes: u32) -> Result<(), PitError> {
+        let pit_divider = PIT_FREQUENCY as u32 / 20;
+        if pit_divider > 0xFFFF {
+            return Err(PitError::InvalidDuration);
+        }
 
+        unsafe {
             // Start PIT measurement
             self.control.write(1);
             self.command.write(0b10110110);
 
-            let pit_divider = PIT_FREQUENCY as u32 / 20;
-            if pit_divider > 0xFFFF {
-                return Err(PitError::InvalidDuration);
-            }
-
             self.channel_2.write((pit_divider & 0xFF) as u8);
             self.channel_2.write((pit_divider >> 8) as u8);
 
             let mut last = self.control.read() & 0x20;
-            let mut changes = 0;
+            let mut seen = 0;
 
-            while changes < 40 {
+            while seen < changes {
                 let t = self.control.read() & 0x20;
                 if t != last {
-                    changes += 1;
+                    seen += 1;
                     last = t;
                 }
             }
 
             self.control.write(0);
+        }
+        Ok(())
+    }
+
+    /// Measures the frequency of the time stamp counter against the PIT
+    ///
+    /// # Returns
+    /// TSC ticks per second
+    pub fn calibrate_tsc(&mut self) -> Result<u64, PitError> {
+        const CHANGES: u32 = 8;
+        let start = unsafe { _rdtsc() };
+        self.wait_half_periods(CHANGES)?;
+        let end = unsafe { _rdtsc() };
+
+        let elapsed = end.wrapping_sub(start);
+        if elapsed == 0 {
+            return Err(PitError::CalibrationFailed);
+        }
+        // Each change is half of a 20 Hz period
+        Ok(elapsed * 40 / CHANGES as u64)
+    }
+
+    /// Calibrates the APIC timer using PIT as a reference clock
+    ///
+    /// # Arguments
+    /// * `hz` - Desired APIC timer frequency in Hertz
+    ///
+    /// # Returns
+    /// Timer count value needed to achieve the requested frequency
+    pub fn calibrate_apic_timer(&mut self, hz: u32) -> Result<u32, PitError> {
+        unsafe {
+            X2ApicManager::mask_timer().map_err(|_| PitError::CalibrationFailed)?;
+
+            // Set divider to 1
+            Msr::new(X2APIC_TIMER_DCR).write(0xB);
+
+            let initial = u32::MAX;
+            Msr::new(X2APIC_TIMER_ICR).write(initial as u64);
+
+            self.wait_half_periods(40)?;
 
             // Calculate ticks
             let final_count = Msr::new(X2APIC_TIMER_CCR).read() as u32;
@@ -349,6 +387,10 @@ pub fn current_core_id() -> usize {
 #[inline(always)]
 pub fn send_ipi(target_id: u32, vector: u8) {
     X2ApicManager::send_ipi(target_id, vector).expect("Failed sending IPI");
+    tracing::record(TraceEvent::IpiSent {
+        target: target_id,
+        vector,
+    });
 }
 
 /// Mask the APIC timer
@@ -362,3 +404,19 @@ pub fn mask_timer() {
 pub fn unmask_timer() {
     X2ApicManager::unmask_timer().expect("Failed to unmask timer");
 }
+
+/// Power hooks for the APIC timer of the core running the suspend
+pub static TIMER_POWER_OPS: PowerOps = PowerOps {
+    suspend: suspend_timer,
+    resume: resume_timer,
+};
+
+fn suspend_timer(_handle: DeviceHandle) -> Result<(), DriverError> {
+    mask_timer();
+    Ok(())
+}
+
+fn resume_timer(_handle: DeviceHandle) -> Result<(), DriverError> {
+    unmask_timer();
+    Ok(())
+}
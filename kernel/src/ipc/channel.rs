@@ -0,0 +1,333 @@
+//! Bounded multi-producer multi-consumer channels.
+//!
+//! Blocked senders and receivers wait in `WakerQueue`s and are served in
+//! arrival order. A slot freed by a receive is reserved for the sender it
+//! wakes, and a value sent is reserved for the receiver it wakes, so no
+//! task can starve behind others that happen to be polled first.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use spin::Mutex;
+
+use crate::memory::accounting::{charge_to, Subsystem};
+
+use super::wait_queue::{Ticket, WakerQueue};
+
+/// Returned by sends when every receiver is gone, with the unsent value
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Why a value could not be sent without waiting
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+/// Why a value could not be received without waiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Every sender is gone and the channel is drained
+    Closed,
+}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+    send_waiters: WakerQueue,
+    recv_waiters: WakerQueue,
+}
+
+impl<T> State<T> {
+    /// Slots not already promised to a notified sender
+    fn unreserved_slots(&self) -> usize {
+        (self.capacity - self.buffer.len()).saturating_sub(self.send_waiters.notified())
+    }
+
+    /// Values not already promised to a notified receiver
+    fn unreserved_values(&self) -> usize {
+        self.buffer
+            .len()
+            .saturating_sub(self.recv_waiters.notified())
+    }
+
+    fn push(&mut self, value: T) {
+        self.buffer.push_back(value);
+        self.recv_waiters.wake_one();
+    }
+
+    fn pop(&mut self) -> T {
+        let value = self.buffer.pop_front().expect("Channel is not empty");
+        self.send_waiters.wake_one();
+        value
+    }
+}
+
+/// Creates a channel holding up to `capacity` values
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "Channel capacity must be positive");
+    let _charge = charge_to(Subsystem::Ipc);
+    let state = Arc::new(Mutex::new(State {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receivers: 1,
+        send_waiters: WakerQueue::new(),
+        recv_waiters: WakerQueue::new(),
+    }));
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+/// Sending half of a channel
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends a value, waiting for room if the channel is full
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            sender: self,
+            value: Some(value),
+            ticket: None,
+        }
+    }
+
+    /// Sends a value if there is room. Fails if other senders are waiting,
+    /// as they are first in line.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.state.lock();
+        if state.receivers == 0 {
+            return Err(TrySendError::Closed(value));
+        }
+        if state.send_waiters.waiting() > 0 || state.unreserved_slots() == 0 {
+            return Err(TrySendError::Full(value));
+        }
+        state.push(value);
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.state.lock().senders += 1;
+        Sender {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            // Receivers waiting on an empty channel must see it close
+            state.recv_waiters.wake_all();
+        }
+    }
+}
+
+/// Future returned by `Sender::send`
+pub struct SendFuture<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    ticket: Option<Ticket>,
+}
+
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut state = this.sender.state.lock();
+        let value = this.value.take().expect("Send polled after completion");
+
+        if state.receivers == 0 {
+            if let Some(ticket) = this.ticket.take() {
+                state.send_waiters.remove(ticket);
+            }
+            return Poll::Ready(Err(SendError(value)));
+        }
+
+        let may_send = match this.ticket {
+            // Woken senders own the slot that was freed for them
+            Some(ticket) => state.send_waiters.is_notified(ticket),
+            None => state.send_waiters.waiting() == 0 && state.unreserved_slots() > 0,
+        };
+        if may_send {
+            if let Some(ticket) = this.ticket.take() {
+                state.send_waiters.remove(ticket);
+            }
+            state.push(value);
+            return Poll::Ready(Ok(()));
+        }
+
+        state.send_waiters.register(&mut this.ticket, cx.waker());
+        this.value = Some(value);
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            let mut state = self.sender.state.lock();
+            // Hand a slot reserved for us to the next sender
+            if state.send_waiters.remove(ticket) {
+                state.send_waiters.wake_one();
+            }
+        }
+    }
+}
+
+/// Receiving half of a channel
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives a value, waiting for one if the channel is empty
+    ///
+    /// # Returns
+    /// None once every sender is gone and the channel is drained
+    pub fn recv(&self) -> RecvFuture<'_, T> {
+        RecvFuture {
+            receiver: self,
+            ticket: None,
+        }
+    }
+
+    /// Receives a value if one is available and no other receiver is
+    /// waiting for it
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.state.lock();
+        if state.recv_waiters.waiting() > 0 || state.unreserved_values() == 0 {
+            return Err(if state.senders == 0 && state.buffer.is_empty() {
+                TryRecvError::Closed
+            } else {
+                TryRecvError::Empty
+            });
+        }
+        Ok(state.pop())
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.state.lock().receivers += 1;
+        Receiver {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            // Senders waiting on a full channel must see it close
+            state.send_waiters.wake_all();
+        }
+    }
+}
+
+/// Future returned by `Receiver::recv`
+pub struct RecvFuture<'a, T> {
+    receiver: &'a Receiver<T>,
+    ticket: Option<Ticket>,
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut state = this.receiver.state.lock();
+
+        let may_receive = match this.ticket {
+            Some(ticket) => state.recv_waiters.is_notified(ticket) && !state.buffer.is_empty(),
+            None => state.recv_waiters.waiting() == 0 && state.unreserved_values() > 0,
+        };
+        if may_receive {
+            if let Some(ticket) = this.ticket.take() {
+                state.recv_waiters.remove(ticket);
+            }
+            return Poll::Ready(Some(state.pop()));
+        }
+
+        if state.senders == 0 && state.buffer.is_empty() {
+            if let Some(ticket) = this.ticket.take() {
+                state.recv_waiters.remove(ticket);
+            }
+            return Poll::Ready(None);
+        }
+
+        state.recv_waiters.register(&mut this.ticket, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for RecvFuture<'_, T> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            let mut state = self.receiver.state.lock();
+            // Hand a value reserved for us to the next receiver
+            if state.recv_waiters.remove(ticket) {
+                state.recv_waiters.wake_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use futures::task::noop_waker_ref;
+
+    #[test_case]
+    fn test_many_producers_are_served_in_order() {
+        let (sender, receiver) = channel::<u32>(1);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // The first send fills the channel, the rest queue up in order
+        let mut sends: Vec<_> = (0..4).map(|i| sender.send(i)).collect();
+        for send in sends.iter_mut() {
+            let _ = Pin::new(send).poll(&mut cx);
+        }
+
+        let mut received = Vec::new();
+        let mut done = [true, false, false, false];
+        while received.len() < 4 {
+            received.push(receiver.try_recv().unwrap());
+            // Poll the latest arrivals first, which must not let them
+            // jump ahead of earlier senders
+            for (send, done) in sends.iter_mut().zip(done.iter_mut()).rev() {
+                if !*done {
+                    *done = Pin::new(send).poll(&mut cx).is_ready();
+                }
+            }
+        }
+        assert_eq!(received, [0, 1, 2, 3]);
+
+        drop(sends);
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+    }
+}
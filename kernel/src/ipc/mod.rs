@@ -0,0 +1,9 @@
+//! Communication between events.
+
+pub mod bytes;
+pub mod channel;
+pub mod oneshot;
+pub mod pipe;
+pub mod priority;
+pub mod registry;
+pub mod wait_queue;
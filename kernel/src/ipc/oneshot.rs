@@ -0,0 +1,150 @@
+//! Channels that carry a single value.
+//!
+//! Cheaper than a bounded channel for request and response patterns: there
+//! is one sender, one receiver, and so at most one waker to remember.
+
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+use crate::memory::accounting::{charge_to, Subsystem};
+
+/// The sender was dropped without sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+struct Inner<T> {
+    value: Option<T>,
+    receiver_waker: Option<Waker>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+/// Creates a oneshot channel
+pub fn oneshot<T>() -> (Sender<T>, Receiver<T>) {
+    let _charge = charge_to(Subsystem::Ipc);
+    let inner = Arc::new(Mutex::new(Inner {
+        value: None,
+        receiver_waker: None,
+        sender_alive: true,
+        receiver_alive: true,
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+/// Sending half of a oneshot channel
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends the value, consuming the sender
+    ///
+    /// # Returns
+    /// The value back if the receiver is gone
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut inner = self.inner.lock();
+        if !inner.receiver_alive {
+            return Err(value);
+        }
+        inner.value = Some(value);
+        if let Some(waker) = inner.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns true if the receiver is gone, so there is no point sending
+    pub fn is_closed(&self) -> bool {
+        !self.inner.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        inner.sender_alive = false;
+        if let Some(waker) = inner.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiving half of a oneshot channel. Awaiting it yields the value.
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes the value if it has been sent
+    ///
+    /// # Returns
+    /// Ok(None) if the value has not been sent yet, Err if it never will be
+    pub fn try_recv(&mut self) -> Result<Option<T>, Canceled> {
+        let mut inner = self.inner.lock();
+        match inner.value.take() {
+            Some(value) => Ok(Some(value)),
+            None if inner.sender_alive => Ok(None),
+            None => Err(Canceled),
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.inner.lock();
+        if let Some(value) = inner.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if !inner.sender_alive {
+            return Poll::Ready(Err(Canceled));
+        }
+        match &inner.receiver_waker {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            _ => inner.receiver_waker = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().receiver_alive = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test_case]
+    fn test_oneshot() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let (sender, mut receiver) = oneshot::<u32>();
+        assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Pending);
+        sender.send(7).unwrap();
+        assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Ready(Ok(7)));
+
+        let (sender, mut receiver) = oneshot::<u32>();
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(Canceled));
+
+        let (sender, receiver) = oneshot::<u32>();
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(7), Err(7));
+    }
+}
@@ -0,0 +1,246 @@
+//! Anonymous pipes.
+//!
+//! A pipe is a bounded ring buffer of bytes with one `PipeReader` and one
+//! `PipeWriter`. Reads take whatever bytes are buffered and writes add as
+//! many as fit, so either may transfer fewer bytes than asked. A reader
+//! finding the pipe empty waits for a writer, and a writer finding it full
+//! waits for a reader, unless the other end is gone: once the writer is
+//! dropped reads see the end of the stream, and once the reader is dropped
+//! writes fail.
+//!
+//! Processes hold each end behind an `Arc` in their descriptor tables, so
+//! an end closes when the last descriptor referring to it does.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::fmt;
+use spin::Mutex;
+
+use crate::{
+    constants::processes::PIPE_CAPACITY,
+    events::futures::WaitQueue,
+    memory::accounting::{charge_to, Subsystem},
+};
+
+/// Why a pipe could not be read or written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeError {
+    /// The pipe is empty, or full, and the other end is still open
+    WouldBlock,
+    /// The reader is gone, so nothing written would be read
+    Broken,
+}
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+struct Pipe {
+    state: Mutex<PipeState>,
+    /// Readers waiting for bytes or for the writer to close
+    readers: WaitQueue,
+    /// Writers waiting for room or for the reader to close
+    writers: WaitQueue,
+}
+
+/// The end of a pipe bytes are read from
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+/// The end of a pipe bytes are written to
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+/// Creates a pipe holding up to PIPE_CAPACITY bytes
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let _charge = charge_to(Subsystem::Ipc);
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readers: WaitQueue::new(),
+        writers: WaitQueue::new(),
+    });
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl PipeReader {
+    /// Reads the bytes already buffered, without waiting
+    ///
+    /// # Returns
+    /// The number of bytes read, 0 once the writer is gone and everything
+    /// it wrote has been read, or `PipeError::WouldBlock` if the pipe is
+    /// empty but the writer may still write
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, PipeError> {
+        let read = {
+            let mut state = self.pipe.state.lock();
+            if state.buffer.is_empty() && !buf.is_empty() {
+                return if state.writer_open {
+                    Err(PipeError::WouldBlock)
+                } else {
+                    Ok(0)
+                };
+            }
+            let read = buf.len().min(state.buffer.len());
+            for (byte, value) in buf.iter_mut().zip(state.buffer.drain(..read)) {
+                *byte = value;
+            }
+            read
+        };
+        if read > 0 {
+            self.pipe.writers.wake_all();
+        }
+        Ok(read)
+    }
+
+    /// Waits until a read would not block
+    pub async fn readable(&self) {
+        self.pipe
+            .readers
+            .wait_until(|| {
+                let state = self.pipe.state.lock();
+                !state.buffer.is_empty() || !state.writer_open
+            })
+            .await
+    }
+
+    /// Reads at least one byte, waiting for the writer if the pipe is
+    /// empty
+    ///
+    /// # Returns
+    /// The number of bytes read, 0 at the end of the stream
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        loop {
+            match self.try_read(buf) {
+                Ok(read) => return read,
+                Err(_) => self.readable().await,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PipeReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PipeReader")
+            .field("buffered", &self.pipe.state.lock().buffer.len())
+            .finish()
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        state.reader_open = false;
+        // Nothing will read what is left
+        state.buffer.clear();
+        drop(state);
+        self.pipe.writers.wake_all();
+    }
+}
+
+impl PipeWriter {
+    /// Writes as many bytes as there is room for, without waiting
+    ///
+    /// # Returns
+    /// The number of bytes written, `PipeError::WouldBlock` if the pipe is
+    /// full, or `PipeError::Broken` if the reader is gone
+    pub fn try_write(&self, data: &[u8]) -> Result<usize, PipeError> {
+        let written = {
+            let mut state = self.pipe.state.lock();
+            if !state.reader_open {
+                return Err(PipeError::Broken);
+            }
+            let written = data.len().min(PIPE_CAPACITY - state.buffer.len());
+            if written == 0 && !data.is_empty() {
+                return Err(PipeError::WouldBlock);
+            }
+            state.buffer.extend(&data[..written]);
+            written
+        };
+        if written > 0 {
+            self.pipe.readers.wake_all();
+        }
+        Ok(written)
+    }
+
+    /// Waits until a write would not block
+    pub async fn writable(&self) {
+        self.pipe
+            .writers
+            .wait_until(|| {
+                let state = self.pipe.state.lock();
+                state.buffer.len() < PIPE_CAPACITY || !state.reader_open
+            })
+            .await
+    }
+
+    /// Writes at least one byte, waiting for the reader if the pipe is
+    /// full
+    ///
+    /// # Returns
+    /// The number of bytes written, or `PipeError::Broken` if the reader
+    /// is gone
+    pub async fn write(&self, data: &[u8]) -> Result<usize, PipeError> {
+        loop {
+            match self.try_write(data) {
+                Err(PipeError::WouldBlock) => self.writable().await,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PipeWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PipeWriter")
+            .field("buffered", &self.pipe.state.lock().buffer.len())
+            .finish()
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.state.lock().writer_open = false;
+        self.pipe.readers.wake_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::futures::run_to_completion;
+    use alloc::vec;
+
+    #[test_case]
+    fn test_pipe() {
+        let (reader, writer) = pipe();
+        let mut buf = [0; 8];
+        assert_eq!(reader.try_read(&mut buf), Err(PipeError::WouldBlock));
+        assert_eq!(writer.try_write(b"hello"), Ok(5));
+        assert_eq!(reader.try_read(&mut buf[..3]), Ok(3));
+        assert_eq!(&buf[..3], b"hel");
+
+        // Writes stop short at the capacity
+        let fill = vec![0xAB; PIPE_CAPACITY];
+        assert_eq!(writer.try_write(&fill), Ok(PIPE_CAPACITY - 2));
+        assert_eq!(writer.try_write(b"x"), Err(PipeError::WouldBlock));
+        assert_eq!(run_to_completion(reader.read(&mut buf)), 8);
+        assert_eq!(&buf[..2], b"lo");
+        run_to_completion(writer.writable());
+
+        // The reader sees what is left, then the end of the stream
+        drop(writer);
+        let mut rest = vec![0; PIPE_CAPACITY];
+        assert_eq!(reader.try_read(&mut rest), Ok(PIPE_CAPACITY - 8));
+        assert_eq!(run_to_completion(reader.read(&mut buf)), 0);
+
+        let (reader, writer) = pipe();
+        drop(reader);
+        assert_eq!(writer.try_write(b"lost"), Err(PipeError::Broken));
+    }
+}
@@ -0,0 +1,270 @@
+//! Channels that carry the priority of their senders.
+//!
+//! An event serving requests over a channel runs at its own priority, which
+//! may be far below that of the events waiting on it. Messages sent here are
+//! tagged with the priority of the sending event, and the receiving event can
+//! adopt the most urgent priority still pending on the channel for as long as
+//! it holds the returned `PriorityBoost`. A high priority request then cannot
+//! sit behind medium priority work just because the service is low priority.
+
+use core::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::sync::Arc;
+
+use super::channel::{self, SendError, TryRecvError, TrySendError};
+use crate::{
+    constants::events::NUM_EVENT_PRIORITIES,
+    events::{current_event_priority, PriorityBoost},
+    memory::accounting::{charge_to, Subsystem},
+};
+
+/// A value and the priority of the event it was sent for
+struct Tagged<T> {
+    value: T,
+    priority: Option<usize>,
+}
+
+/// Number of tagged messages queued or waiting to be queued, per priority
+struct Pending {
+    counts: [AtomicUsize; NUM_EVENT_PRIORITIES],
+}
+
+impl Pending {
+    fn add(&self, priority: Option<usize>) {
+        if let Some(priority) = priority {
+            self.counts[priority].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&self, priority: Option<usize>) {
+        if let Some(priority) = priority {
+            self.counts[priority].fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the most urgent priority with a message pending
+    fn most_urgent(&self) -> Option<usize> {
+        self.counts
+            .iter()
+            .position(|count| count.load(Ordering::Relaxed) > 0)
+    }
+}
+
+/// Counts a message as pending until it reaches the channel. Dropping it
+/// before then, for example when a send is canceled, takes the count back.
+struct Registration<'a> {
+    pending: &'a Pending,
+    priority: Option<usize>,
+}
+
+impl<'a> Registration<'a> {
+    fn new(pending: &'a Pending, priority: Option<usize>) -> Self {
+        pending.add(priority);
+        Registration { pending, priority }
+    }
+
+    /// The message is in the channel, and the receiver takes the count back
+    fn handed_over(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.pending.remove(self.priority);
+    }
+}
+
+/// Creates a priority-tagged channel holding up to `capacity` values
+pub fn priority_channel<T>(capacity: usize) -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let _charge = charge_to(Subsystem::Ipc);
+    let (sender, receiver) = channel::channel(capacity);
+    let pending = Arc::new(Pending {
+        counts: Default::default(),
+    });
+    (
+        PrioritySender {
+            sender,
+            pending: pending.clone(),
+        },
+        PriorityReceiver { receiver, pending },
+    )
+}
+
+/// Sending half of a priority-tagged channel
+pub struct PrioritySender<T> {
+    sender: channel::Sender<Tagged<T>>,
+    pending: Arc<Pending>,
+}
+
+/// Clamps a priority to the range of normal event priorities
+fn normal_priority(priority: Option<usize>) -> Option<usize> {
+    priority.map(|p| p.min(NUM_EVENT_PRIORITIES - 1))
+}
+
+impl<T> PrioritySender<T> {
+    /// Sends a value tagged with the priority of the running event,
+    /// waiting for room if the channel is full
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.send_with_priority(value, current_event_priority())
+            .await
+    }
+
+    /// Sends a value with an explicit priority tag, or untagged
+    ///
+    /// # Arguments
+    /// * `value` - The value to send
+    /// * `priority` - Priority the receiver should adopt while this value
+    ///   is pending, None to not affect the receiver
+    pub async fn send_with_priority(
+        &self,
+        value: T,
+        priority: Option<usize>,
+    ) -> Result<(), SendError<T>> {
+        let priority = normal_priority(priority);
+        // A sender blocked on a full channel counts as pending too
+        let registration = Registration::new(&self.pending, priority);
+        match self.sender.send(Tagged { value, priority }).await {
+            Ok(()) => {
+                registration.handed_over();
+                Ok(())
+            }
+            Err(SendError(tagged)) => Err(SendError(tagged.value)),
+        }
+    }
+
+    /// Sends a value with an explicit priority tag if there is room
+    pub fn try_send_with_priority(
+        &self,
+        value: T,
+        priority: Option<usize>,
+    ) -> Result<(), TrySendError<T>> {
+        let priority = normal_priority(priority);
+        let registration = Registration::new(&self.pending, priority);
+        match self.sender.try_send(Tagged { value, priority }) {
+            Ok(()) => {
+                registration.handed_over();
+                Ok(())
+            }
+            Err(TrySendError::Full(tagged)) => Err(TrySendError::Full(tagged.value)),
+            Err(TrySendError::Closed(tagged)) => Err(TrySendError::Closed(tagged.value)),
+        }
+    }
+}
+
+impl<T> Clone for PrioritySender<T> {
+    fn clone(&self) -> Self {
+        PrioritySender {
+            sender: self.sender.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+/// Receiving half of a priority-tagged channel
+pub struct PriorityReceiver<T> {
+    receiver: channel::Receiver<Tagged<T>>,
+    pending: Arc<Pending>,
+}
+
+impl<T> PriorityReceiver<T> {
+    /// Receives a value at the receiving event's own priority
+    ///
+    /// # Returns
+    /// None once every sender is gone and the channel is drained
+    pub async fn recv(&self) -> Option<T> {
+        let message = self.receiver.recv().await?;
+        self.pending.remove(message.priority);
+        Some(message.value)
+    }
+
+    /// Receives a value, raising the receiving event to the most urgent
+    /// priority pending on the channel while it waits. The returned boost
+    /// keeps the event at that priority until it is dropped, which should
+    /// happen once the value has been handled.
+    ///
+    /// # Returns
+    /// None once every sender is gone and the channel is drained
+    pub async fn recv_inheriting(&self) -> Option<(T, PriorityBoost)> {
+        let mut boost = PriorityBoost::new();
+        let mut recv = self.receiver.recv();
+        let message = poll_fn(|cx| {
+            if let Some(priority) = self.pending.most_urgent() {
+                boost.raise_to(priority);
+            }
+            Pin::new(&mut recv).poll(cx)
+        })
+        .await?;
+
+        if let Some(priority) = message.priority {
+            boost.raise_to(priority);
+        }
+        self.pending.remove(message.priority);
+        Some((message.value, boost))
+    }
+
+    /// Receives a value if one is available, at the receiving event's own
+    /// priority
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let message = self.receiver.try_recv()?;
+        self.pending.remove(message.priority);
+        Ok(message.value)
+    }
+
+    /// Returns the most urgent priority tagged on a pending message, so a
+    /// receiver that holds a boost across several values can raise it
+    /// further
+    pub fn most_urgent_pending(&self) -> Option<usize> {
+        self.pending.most_urgent()
+    }
+}
+
+impl<T> Clone for PriorityReceiver<T> {
+    fn clone(&self) -> Self {
+        PriorityReceiver {
+            receiver: self.receiver.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{
+        pin::pin,
+        task::{Context, Poll},
+    };
+    use futures::task::noop_waker_ref;
+
+    #[test_case]
+    fn test_pending_priorities() {
+        let (sender, receiver) = priority_channel::<u32>(4);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        sender.try_send_with_priority(0, Some(2)).unwrap();
+        sender.try_send_with_priority(1, Some(1)).unwrap();
+        sender.try_send_with_priority(2, None).unwrap();
+        assert_eq!(receiver.most_urgent_pending(), Some(1));
+
+        // Values still arrive in order, whatever their priority
+        match pin!(receiver.recv_inheriting()).poll(&mut cx) {
+            Poll::Ready(Some((value, _boost))) => assert_eq!(value, 0),
+            _ => panic!("Value was not received"),
+        }
+        assert_eq!(receiver.most_urgent_pending(), Some(1));
+
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.most_urgent_pending(), None);
+        assert_eq!(receiver.try_recv(), Ok(2));
+
+        // A failed send takes its priority back
+        drop(receiver);
+        assert!(sender.try_send_with_priority(3, Some(0)).is_err());
+        assert_eq!(sender.pending.most_urgent(), None);
+    }
+}
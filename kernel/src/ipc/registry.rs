@@ -0,0 +1,202 @@
+//! Channels found by name.
+//!
+//! A service binds a name and accepts connections on it, and anything in
+//! the kernel that knows the name connects, without the two having to
+//! share a channel beforehand. Each connection is a pair of `Endpoint`s,
+//! one for each side, carrying `Bytes` messages both ways. Channels are
+//! shared between cores, so the two sides can run on different event
+//! runners.
+//!
+//! A name stays bound for as long as its `Listener` lives. Connections
+//! already made outlive it.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use spin::Mutex;
+
+use super::{
+    bytes::Bytes,
+    channel::{channel, Receiver, RecvFuture, SendFuture, Sender, TryRecvError, TrySendError},
+};
+use crate::memory::accounting::{charge_to, Subsystem};
+
+/// Connections a listener may have waiting to be accepted
+const BACKLOG: usize = 8;
+
+/// Why a name could not be bound or connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryError {
+    /// Names must not be empty
+    InvalidName,
+    /// Another listener has the name
+    AlreadyBound,
+    /// Nothing is bound to the name
+    NotFound,
+    /// The listener has too many connections waiting already
+    Refused,
+}
+
+/// Listeners by name, each with where its connections go
+static REGISTRY: Mutex<BTreeMap<String, Sender<Endpoint>>> = Mutex::new(BTreeMap::new());
+
+/// One side of a connection
+pub struct Endpoint {
+    sender: Sender<Bytes>,
+    receiver: Receiver<Bytes>,
+}
+
+impl Endpoint {
+    /// Creates both sides of a connection, each able to queue up to
+    /// `capacity` messages for the other
+    pub fn pair(capacity: usize) -> (Endpoint, Endpoint) {
+        let (to_second, from_first) = channel(capacity);
+        let (to_first, from_second) = channel(capacity);
+        (
+            Endpoint {
+                sender: to_second,
+                receiver: from_second,
+            },
+            Endpoint {
+                sender: to_first,
+                receiver: from_first,
+            },
+        )
+    }
+
+    /// Sends a message to the other side, waiting for room
+    pub fn send(&self, message: Bytes) -> SendFuture<'_, Bytes> {
+        self.sender.send(message)
+    }
+
+    pub fn try_send(&self, message: Bytes) -> Result<(), TrySendError<Bytes>> {
+        self.sender.try_send(message)
+    }
+
+    /// Waits for a message from the other side, None once it is gone
+    pub fn recv(&self) -> RecvFuture<'_, Bytes> {
+        self.receiver.recv()
+    }
+
+    pub fn try_recv(&self) -> Result<Bytes, TryRecvError> {
+        self.receiver.try_recv()
+    }
+}
+
+/// A bound name, accepting connections
+pub struct Listener {
+    name: String,
+    pending: Receiver<Endpoint>,
+}
+
+impl Listener {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waits for a connection
+    pub async fn accept(&self) -> Endpoint {
+        // The registry holds the sender for as long as the name is bound
+        self.pending
+            .recv()
+            .await
+            .expect("Name is bound while listening")
+    }
+
+    /// Takes a connection that is already waiting
+    pub fn try_accept(&self) -> Option<Endpoint> {
+        self.pending.try_recv().ok()
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        REGISTRY.lock().remove(&self.name);
+    }
+}
+
+/// Binds `name`, so others can connect to it
+pub fn bind(name: &str) -> Result<Listener, RegistryError> {
+    if name.is_empty() {
+        return Err(RegistryError::InvalidName);
+    }
+    let _charge = charge_to(Subsystem::Ipc);
+    let mut registry = REGISTRY.lock();
+    if registry.contains_key(name) {
+        return Err(RegistryError::AlreadyBound);
+    }
+    let (sender, pending) = channel(BACKLOG);
+    registry.insert(name.into(), sender);
+    Ok(Listener {
+        name: name.into(),
+        pending,
+    })
+}
+
+/// Connects to the listener bound to `name`
+///
+/// # Arguments
+/// * `capacity` - Messages each side may queue for the other
+///
+/// # Returns
+/// This side of the connection. The listener gets the other side when it
+/// accepts.
+pub fn connect(name: &str, capacity: usize) -> Result<Endpoint, RegistryError> {
+    let _charge = charge_to(Subsystem::Ipc);
+    let registry = REGISTRY.lock();
+    let listener = registry.get(name).ok_or(RegistryError::NotFound)?;
+    let (ours, theirs) = Endpoint::pair(capacity);
+    match listener.try_send(theirs) {
+        Ok(()) => Ok(ours),
+        Err(TrySendError::Full(_)) => Err(RegistryError::Refused),
+        Err(TrySendError::Closed(_)) => Err(RegistryError::NotFound),
+    }
+}
+
+/// Whether anything is bound to `name`
+pub fn lookup(name: &str) -> bool {
+    REGISTRY.lock().contains_key(name)
+}
+
+/// Every bound name, in order
+pub fn names() -> Vec<String> {
+    REGISTRY.lock().keys().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_named_connection() {
+        assert_eq!(bind("").err(), Some(RegistryError::InvalidName));
+        let listener = bind("test/echo").unwrap();
+        assert_eq!(bind("test/echo").err(), Some(RegistryError::AlreadyBound));
+        assert!(lookup("test/echo"));
+        assert!(names().iter().any(|name| name == "test/echo"));
+        assert!(listener.try_accept().is_none());
+
+        let client = connect("test/echo", 2).unwrap();
+        let server = listener.try_accept().unwrap();
+        let message = Bytes::from(b"Tversion".to_vec());
+        client.try_send(message.slice(1..)).unwrap();
+        assert_eq!(&*server.try_recv().unwrap(), b"version");
+        server.try_send(message.clone()).unwrap();
+        assert_eq!(client.try_recv().unwrap(), message);
+        assert_eq!(client.try_recv().err(), Some(TryRecvError::Empty));
+
+        // The backlog is bounded
+        let waiting: Vec<Endpoint> = (0..BACKLOG)
+            .map(|_| connect("test/echo", 1).unwrap())
+            .collect();
+        assert_eq!(connect("test/echo", 1).err(), Some(RegistryError::Refused));
+        drop(waiting);
+
+        // Connections outlive the name
+        drop(listener);
+        assert!(!lookup("test/echo"));
+        assert_eq!(connect("test/echo", 1).err(), Some(RegistryError::NotFound));
+        client.try_send(Bytes::from(&b"still here"[..])).unwrap();
+        assert_eq!(&*server.try_recv().unwrap(), b"still here");
+        drop(server);
+        assert_eq!(client.try_recv().err(), Some(TryRecvError::Closed));
+    }
+}
@@ -0,0 +1,163 @@
+//! FIFO queues of blocked futures.
+//!
+//! Unlike a single waker slot, a `WakerQueue` remembers every waiter, and
+//! waking hands out notifications in the order the waiters arrived. A
+//! notified waiter keeps its place until it removes itself, so the owner of
+//! the queue can reserve a resource for it: for example a channel holds a
+//! freed slot for the sender it notified instead of letting whichever sender
+//! is polled first take it.
+//!
+//! The queue has no lock of its own and is meant to live inside the state
+//! it guards.
+
+use alloc::collections::VecDeque;
+use core::task::Waker;
+
+use crate::memory::accounting::{charge_to, Subsystem};
+
+/// Identifies a waiter's place in a queue
+pub type Ticket = u64;
+
+struct Waiter {
+    ticket: Ticket,
+    waker: Waker,
+    notified: bool,
+}
+
+/// Waiters in arrival order
+pub struct WakerQueue {
+    waiters: VecDeque<Waiter>,
+    next_ticket: Ticket,
+    /// Number of waiters that have been notified but not removed
+    notified: usize,
+}
+
+impl Default for WakerQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WakerQueue {
+    pub const fn new() -> Self {
+        WakerQueue {
+            waiters: VecDeque::new(),
+            next_ticket: 0,
+            notified: 0,
+        }
+    }
+
+    /// Adds a waiter at the back of the queue, or updates the waker of one
+    /// already queued without losing its place
+    ///
+    /// # Arguments
+    /// * `ticket` - The waiter's ticket, None if it is not queued yet
+    /// * `waker` - Waker of the task polling the waiter
+    pub fn register(&mut self, ticket: &mut Option<Ticket>, waker: &Waker) {
+        if let Some(waiter) = ticket.and_then(|t| self.find_mut(t)) {
+            if !waiter.waker.will_wake(waker) {
+                waiter.waker = waker.clone();
+            }
+            return;
+        }
+
+        let new_ticket = self.next_ticket;
+        self.next_ticket += 1;
+        let _charge = charge_to(Subsystem::Ipc);
+        self.waiters.push_back(Waiter {
+            ticket: new_ticket,
+            waker: waker.clone(),
+            notified: false,
+        });
+        *ticket = Some(new_ticket);
+    }
+
+    fn find_mut(&mut self, ticket: Ticket) -> Option<&mut Waiter> {
+        self.waiters.iter_mut().find(|w| w.ticket == ticket)
+    }
+
+    /// Returns true if the waiter has been notified
+    pub fn is_notified(&self, ticket: Ticket) -> bool {
+        self.waiters
+            .iter()
+            .any(|w| w.ticket == ticket && w.notified)
+    }
+
+    /// Removes a waiter, whether it completed or gave up
+    ///
+    /// # Returns
+    /// True if the waiter had been notified. A waiter giving up should then
+    /// pass the notification on with `wake_one`.
+    pub fn remove(&mut self, ticket: Ticket) -> bool {
+        let Some(index) = self.waiters.iter().position(|w| w.ticket == ticket) else {
+            return false;
+        };
+        let waiter = self.waiters.remove(index).expect("Index is in bounds");
+        if waiter.notified {
+            self.notified -= 1;
+        }
+        waiter.notified
+    }
+
+    /// Number of notified waiters that have not removed themselves yet
+    pub fn notified(&self) -> usize {
+        self.notified
+    }
+
+    /// Number of waiters still waiting for a notification
+    pub fn waiting(&self) -> usize {
+        self.waiters.len() - self.notified
+    }
+
+    /// Notifies and wakes the longest waiting waiter that has not been
+    /// notified yet
+    ///
+    /// # Returns
+    /// False if no waiter was waiting
+    pub fn wake_one(&mut self) -> bool {
+        let Some(waiter) = self.waiters.iter_mut().find(|w| !w.notified) else {
+            return false;
+        };
+        waiter.notified = true;
+        waiter.waker.wake_by_ref();
+        self.notified += 1;
+        true
+    }
+
+    /// Notifies and wakes every waiter, in arrival order
+    pub fn wake_all(&mut self) {
+        while self.wake_one() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test_case]
+    fn test_fifo_notification() {
+        let mut queue = WakerQueue::new();
+        let mut tickets = [None; 3];
+        for ticket in tickets.iter_mut() {
+            queue.register(ticket, noop_waker_ref());
+        }
+        // Registering again keeps the place
+        queue.register(&mut tickets[0], noop_waker_ref());
+        assert_eq!(queue.waiting(), 3);
+
+        assert!(queue.wake_one());
+        assert!(queue.is_notified(tickets[0].unwrap()));
+        assert!(!queue.is_notified(tickets[1].unwrap()));
+
+        // A notified waiter giving up reports it so the wake can move on
+        assert!(queue.remove(tickets[0].unwrap()));
+        assert!(queue.wake_one());
+        assert!(queue.is_notified(tickets[1].unwrap()));
+
+        queue.wake_all();
+        assert_eq!(queue.notified(), 2);
+        assert_eq!(queue.waiting(), 0);
+        assert!(!queue.wake_one());
+    }
+}
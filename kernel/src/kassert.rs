@@ -0,0 +1,86 @@
+//! Assertions that contain faults in release builds.
+//!
+//! `assert!` and `expect` stop the whole kernel, which is right while
+//! developing but wrong for an invariant a driver or the scheduler can
+//! recover from by failing one request. `kassert!` and `kexpect!` panic in
+//! debug builds, so broken invariants are still caught by the tests, while
+//! release builds log the failure, count it, and return early from the
+//! enclosing function with the value given.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Failed assertions that release builds recovered from
+static RECOVERED: AtomicU64 = AtomicU64::new(0);
+
+/// Number of failed assertions that were recovered from since boot
+pub fn recovered_assertions() -> u64 {
+    RECOVERED.load(Ordering::Relaxed)
+}
+
+/// Reports a failed assertion. Panics in debug builds, otherwise logs it.
+#[doc(hidden)]
+pub fn assertion_failed(file: &str, line: u32, message: fmt::Arguments) {
+    RECOVERED.fetch_add(1, Ordering::Relaxed);
+    #[cfg(debug_assertions)]
+    panic!("Assertion failed at {}:{}: {}", file, line, message);
+    #[cfg(not(debug_assertions))]
+    crate::error!("Recovered from assertion at {}:{}: {}", file, line, message);
+}
+
+/// Asserts an invariant that has a recovery path. When it does not hold,
+/// debug builds panic and release builds log it and return `$ret` from the
+/// enclosing function.
+///
+/// ```ignore
+/// kassert!(command < 64, Err(SDCardError::GenericSDError));
+/// kassert!(index < len, Err(Error::OutOfRange), "index {} of {}", index, len);
+/// ```
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr, $ret:expr $(,)?) => {
+        $crate::kassert!($cond, $ret, "{}", stringify!($cond))
+    };
+    ($cond:expr, $ret:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::kassert::assertion_failed(file!(), line!(), format_args!($($arg)+));
+            #[allow(clippy::unused_unit)]
+            return $ret;
+        }
+    };
+}
+
+/// Unwraps an Option that should always be Some. When it is None, debug
+/// builds panic and release builds log it and return `$ret` from the
+/// enclosing function.
+#[macro_export]
+macro_rules! kexpect {
+    ($value:expr, $ret:expr, $($arg:tt)+) => {
+        match $value {
+            Some(value) => value,
+            None => {
+                $crate::kassert::assertion_failed(file!(), line!(), format_args!($($arg)+));
+                #[allow(clippy::unused_unit)]
+            return $ret;
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    fn checked_half(value: u32) -> Result<u32, ()> {
+        kassert!(value % 2 == 0, Err(()));
+        let half = kexpect!(value.checked_div(2), Err(()), "division by 2 failed");
+        Ok(half)
+    }
+
+    #[test_case]
+    fn test_holding_invariants() {
+        let before = super::recovered_assertions();
+        assert_eq!(checked_half(8), Ok(4));
+        assert_eq!(super::recovered_assertions(), before);
+    }
+}
@@ -0,0 +1,168 @@
+//! Kernel log ring for post-mortem debugging.
+//!
+//! Everything printed to the serial port, including panic messages, is
+//! also copied into a ring buffer in its own page aligned `.klog` section.
+//! The ring is static data, so it is valid from the first instruction,
+//! needs neither the heap nor a lock, and is still in RAM after a triple
+//! fault. With `-no-reboot`, QEMU stops instead of resetting, and the last
+//! messages can be recovered from a `dump-guest-memory` or `pmemsave` of
+//! the guest even when the serial output was lost or never flushed.
+//!
+//! Host tools find the ring by scanning for `KLOG_MAGIC`. The layout, all
+//! little endian, is:
+//!
+//! | Offset | Size       | Field                                   |
+//! |--------|------------|-----------------------------------------|
+//! | 0      | 8          | `KLOG_MAGIC`                            |
+//! | 8      | 4          | Layout version, `KLOG_VERSION`          |
+//! | 12     | 4          | Capacity in bytes                       |
+//! | 16     | 8          | Total bytes ever written                |
+//! | 24     | capacity   | Data, byte `n` at offset `n % capacity` |
+//!
+//! Once more than the capacity has been written, the oldest bytes are
+//! overwritten. Cores reserve their bytes before copying them, so
+//! concurrent messages never overwrite each other, but a message being
+//! written when the machine died may be cut short.
+
+use alloc::vec::Vec;
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+use limine::request::KernelAddressRequest;
+
+use crate::{format::BoundedWriter, info};
+
+/// Marks the start of the ring in memory images
+pub const KLOG_MAGIC: [u8; 8] = *b"TAOSKLOG";
+
+/// Version of the layout host tools can expect
+pub const KLOG_VERSION: u32 = 1;
+
+/// Bytes of messages the ring keeps
+pub const KLOG_CAPACITY: usize = 16 * 1024;
+
+/// Longest message formatted on the stack before it is written
+const KLOG_LINE_MAX: usize = 256;
+
+#[used]
+#[link_section = ".requests"]
+static KERNEL_ADDRESS_REQUEST: KernelAddressRequest = KernelAddressRequest::new();
+
+/// The ring, laid out as described in the module documentation
+#[repr(C, align(4096))]
+struct KlogRing {
+    magic: [u8; 8],
+    version: u32,
+    capacity: u32,
+    /// Total bytes ever written. The next byte goes to `head % capacity`.
+    head: AtomicU64,
+    data: [AtomicU8; KLOG_CAPACITY],
+}
+
+#[used]
+#[link_section = ".klog"]
+static KLOG: KlogRing = KlogRing::new();
+
+impl KlogRing {
+    const fn new() -> Self {
+        KlogRing {
+            magic: KLOG_MAGIC,
+            version: KLOG_VERSION,
+            capacity: KLOG_CAPACITY as u32,
+            head: AtomicU64::new(0),
+            data: [const { AtomicU8::new(0) }; KLOG_CAPACITY],
+        }
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        // Only the last capacity bytes of a long message survive anyway
+        let bytes = &bytes[bytes.len().saturating_sub(KLOG_CAPACITY)..];
+        let start = self.head.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        for (i, &byte) in bytes.iter().enumerate() {
+            let index = (start as usize + i) % KLOG_CAPACITY;
+            self.data[index].store(byte, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the bytes still in the ring, oldest first
+    fn contents(&self) -> Vec<u8> {
+        let head = self.head.load(Ordering::Relaxed) as usize;
+        let start = head.saturating_sub(KLOG_CAPACITY);
+        (start..head)
+            .map(|n| self.data[n % KLOG_CAPACITY].load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// Appends bytes to the kernel log ring. Safe to call from any context,
+/// including interrupt and panic handlers.
+pub fn write(bytes: &[u8]) {
+    KLOG.write(bytes);
+}
+
+/// Appends formatted text to the kernel log ring without allocating. A
+/// message that fits `KLOG_LINE_MAX` is written in one piece, so messages
+/// of other cores cannot land in the middle of it.
+pub fn write_fmt(args: fmt::Arguments) {
+    let mut buffer = [0; KLOG_LINE_MAX];
+    let mut writer = BoundedWriter::new(&mut buffer);
+    let _ = writer.write_fmt(args);
+    if writer.truncated() {
+        let _ = RingWriter.write_fmt(args);
+    } else {
+        write(writer.as_bytes());
+    }
+}
+
+/// Returns the messages still in the ring, oldest first
+pub fn contents() -> Vec<u8> {
+    KLOG.contents()
+}
+
+/// Logs where the ring is in physical memory, so a `pmemsave` of just the
+/// ring can be taken from the QEMU monitor
+pub fn report_location() {
+    let Some(kernel) = KERNEL_ADDRESS_REQUEST.get_response() else {
+        return;
+    };
+    let virt = &KLOG as *const KlogRing as u64;
+    let phys = virt - kernel.virtual_base() + kernel.physical_base();
+    info!(
+        "klog: ring at physical {:#x}, {} bytes",
+        phys,
+        core::mem::size_of::<KlogRing>()
+    );
+}
+
+struct RingWriter;
+
+impl Write for RingWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_ring_wraps() {
+        write(b"klog test marker");
+        assert!(contents().ends_with(b"klog test marker"));
+
+        // Filling the ring drops the oldest bytes
+        let head = KLOG.head.load(Ordering::Relaxed);
+        write_fmt(format_args!("{:x<1$}", "", KLOG_CAPACITY - 4));
+        let contents = contents();
+        assert_eq!(contents.len(), KLOG_CAPACITY);
+        assert_eq!(&contents[..4], b"rker");
+        assert_eq!(
+            KLOG.head.load(Ordering::Relaxed),
+            head + KLOG_CAPACITY as u64 - 4
+        );
+        assert_eq!(KLOG.magic, KLOG_MAGIC);
+    }
+}
@@ -10,18 +10,33 @@
 //! The TAOS operating system
 extern crate alloc;
 
-use x86_64::instructions::hlt;
-
+pub mod arch;
+pub mod cmdline;
 pub mod constants;
 pub mod devices;
+pub mod error;
 pub mod events;
 pub mod filesys;
+pub mod format;
 pub mod init;
 pub mod interrupts;
+pub mod ipc;
+pub mod kassert;
+pub mod klog;
 pub mod logging;
 pub mod memory;
+pub mod net;
+pub mod panic;
 pub mod processes;
+pub mod random;
+pub mod shutdown;
+#[cfg(test)]
+mod stress;
+pub mod sync;
 pub mod syscalls;
+pub mod time;
+pub mod tracing;
+pub mod version;
 
 pub use devices::serial;
 
@@ -47,7 +62,7 @@ macro_rules! debug_println {
 
 pub fn idle_loop() -> ! {
     loop {
-        hlt();
+        arch::wait_for_interrupt();
     }
 }
 
@@ -72,14 +87,18 @@ pub fn test_runner(tests: &[&dyn Testable]) {
     for test in tests {
         test.run();
     }
-    exit_qemu(QemuExitCode::Success);
+    shutdown::shutdown(shutdown::ShutdownAction::PowerOff(QemuExitCode::Success));
 }
 
 pub fn test_panic_handler(info: &core::panic::PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    idle_loop();
+    panic::begin_panic();
+    panic::panic_print(format_args!("[failed]\n\nError: {}\n\n", info));
+    // Stop hooks need the heap
+    if !memory::heap::heap_ready() {
+        exit_qemu(QemuExitCode::Failed);
+        idle_loop();
+    }
+    shutdown::shutdown(shutdown::ShutdownAction::PowerOff(QemuExitCode::Failed));
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -87,15 +106,12 @@ pub fn test_panic_handler(info: &core::panic::PanicInfo) -> ! {
 pub enum QemuExitCode {
     Success = 0x10,
     Failed = 0x11,
+    /// A core panicked while handling a panic
+    RecursivePanic = 0x12,
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
-    }
+    unsafe { arch::port_write_u32(0xf4, exit_code as u32) };
 }
 
 #[cfg(test)]
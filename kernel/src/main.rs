@@ -11,7 +11,7 @@ use limine::request::{RequestsEndMarker, RequestsStartMarker};
 use taos::events::run_loop;
 
 extern crate alloc;
-use taos::{debug, serial_println};
+use taos::debug;
 
 /// Marks the start of Limine boot protocol requests.
 #[used]
@@ -46,7 +46,8 @@ extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn rust_panic(info: &core::panic::PanicInfo) -> ! {
-    serial_println!("Kernel panic: {}", info);
+    taos::panic::begin_panic();
+    taos::panic::panic_print(format_args!("Kernel panic: {}\n", info));
     taos::idle_loop();
 }
 
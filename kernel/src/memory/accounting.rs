@@ -0,0 +1,313 @@
+//! Heap usage by subsystem.
+//!
+//! Every heap allocation is charged to the subsystem the core was working
+//! for when it was made. Code enters a subsystem with `charge_to`, which
+//! holds for the rest of the scope, and async work is wrapped with
+//! `charged`, which enters the subsystem around each poll, so a charge
+//! never leaks into whatever else the core runs while a task waits.
+//! Allocations made outside any charge count as `Subsystem::Other`.
+//!
+//! `AccountingAllocator` stores the subsystem in a byte after each
+//! allocation, so freeing, or growing, an allocation credits the subsystem
+//! that made it, whoever frees it. Usage is read with `usage`, from
+//! `/proc/meminfo`, or printed with `print_usage`.
+
+use alloc::boxed::Box;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    future::Future,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use crate::{arch, constants::MAX_CORES, serial_println};
+
+/// A part of the kernel heap usage is charged to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Subsystem {
+    /// Anything made outside a charge
+    Other,
+    Filesystem,
+    Ipc,
+    Events,
+    Devices,
+}
+
+const SUBSYSTEM_COUNT: usize = 5;
+
+impl Subsystem {
+    pub const ALL: [Subsystem; SUBSYSTEM_COUNT] = [
+        Subsystem::Other,
+        Subsystem::Filesystem,
+        Subsystem::Ipc,
+        Subsystem::Events,
+        Subsystem::Devices,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Other => "other",
+            Subsystem::Filesystem => "filesystem",
+            Subsystem::Ipc => "ipc",
+            Subsystem::Events => "events",
+            Subsystem::Devices => "devices",
+        }
+    }
+
+    fn from_tag(tag: u8) -> Self {
+        Self::ALL
+            .get(tag as usize)
+            .copied()
+            .unwrap_or(Subsystem::Other)
+    }
+}
+
+/// Heap usage of one subsystem
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubsystemUsage {
+    /// Bytes in live allocations, not counting the allocator's overhead
+    pub bytes: usize,
+    /// Live allocations
+    pub allocations: usize,
+    /// Allocations made since boot
+    pub total_allocations: u64,
+}
+
+struct Counters {
+    bytes: AtomicUsize,
+    allocations: AtomicUsize,
+    total_allocations: AtomicU64,
+}
+
+static COUNTERS: [Counters; SUBSYSTEM_COUNT] = [const {
+    Counters {
+        bytes: AtomicUsize::new(0),
+        allocations: AtomicUsize::new(0),
+        total_allocations: AtomicU64::new(0),
+    }
+}; SUBSYSTEM_COUNT];
+
+/// Subsystem each core is charging to
+static CURRENT: [AtomicU8; MAX_CORES] = [const { AtomicU8::new(0) }; MAX_CORES];
+
+/// Charges in effect on any core. While there are none, allocating skips
+/// looking up the core.
+static ACTIVE_CHARGES: AtomicUsize = AtomicUsize::new(0);
+
+/// The subsystem allocations on this core are charged to
+pub fn current() -> Subsystem {
+    if ACTIVE_CHARGES.load(Ordering::Acquire) == 0 {
+        return Subsystem::Other;
+    }
+    // Allocations are made before the core's x2APIC is enabled
+    CURRENT
+        .get(arch::early_core_id() as usize)
+        .map_or(Subsystem::Other, |tag| {
+            Subsystem::from_tag(tag.load(Ordering::Relaxed))
+        })
+}
+
+/// Charges allocations on this core to a subsystem until dropped. Must not
+/// be held across an await, use `charged` for async work.
+#[must_use]
+pub struct ChargeGuard {
+    core: usize,
+    previous: u8,
+}
+
+/// Charges allocations on this core to `subsystem` for as long as the
+/// returned guard lives. Charges nest.
+pub fn charge_to(subsystem: Subsystem) -> ChargeGuard {
+    let core = arch::early_core_id() as usize;
+    ACTIVE_CHARGES.fetch_add(1, Ordering::AcqRel);
+    let previous = CURRENT
+        .get(core)
+        .map_or(0, |tag| tag.swap(subsystem as u8, Ordering::Relaxed));
+    ChargeGuard { core, previous }
+}
+
+impl Drop for ChargeGuard {
+    fn drop(&mut self) {
+        if let Some(tag) = CURRENT.get(self.core) {
+            tag.store(self.previous, Ordering::Relaxed);
+        }
+        ACTIVE_CHARGES.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A future whose polls are charged to a subsystem
+pub struct Charged<F> {
+    subsystem: Subsystem,
+    future: Pin<Box<F>>,
+}
+
+/// Charges every poll of `future` to `subsystem`
+pub fn charged<F: Future>(subsystem: Subsystem, future: F) -> Charged<F> {
+    let _charge = charge_to(subsystem);
+    Charged {
+        subsystem,
+        future: Box::pin(future),
+    }
+}
+
+impl<F: Future> Future for Charged<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let _charge = charge_to(self.subsystem);
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// Returns the heap usage of a subsystem
+pub fn usage(subsystem: Subsystem) -> SubsystemUsage {
+    let counters = &COUNTERS[subsystem as usize];
+    SubsystemUsage {
+        bytes: counters.bytes.load(Ordering::Relaxed),
+        allocations: counters.allocations.load(Ordering::Relaxed),
+        total_allocations: counters.total_allocations.load(Ordering::Relaxed),
+    }
+}
+
+/// Prints the heap usage of every subsystem
+pub fn print_usage() {
+    serial_println!(
+        "{:<12} {:>12} {:>12} {:>12}",
+        "Subsystem",
+        "Bytes",
+        "Live",
+        "Total"
+    );
+    for subsystem in Subsystem::ALL {
+        let usage = usage(subsystem);
+        serial_println!(
+            "{:<12} {:>12} {:>12} {:>12}",
+            subsystem.name(),
+            usage.bytes,
+            usage.allocations,
+            usage.total_allocations
+        );
+    }
+}
+
+fn charge(subsystem: Subsystem, size: usize) {
+    let counters = &COUNTERS[subsystem as usize];
+    counters.bytes.fetch_add(size, Ordering::Relaxed);
+    counters.allocations.fetch_add(1, Ordering::Relaxed);
+    counters.total_allocations.fetch_add(1, Ordering::Relaxed);
+}
+
+fn credit(subsystem: Subsystem, size: usize) {
+    let counters = &COUNTERS[subsystem as usize];
+    counters.bytes.fetch_sub(size, Ordering::Relaxed);
+    counters.allocations.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Wraps the heap allocator, charging each allocation to the current
+/// subsystem
+pub struct AccountingAllocator<A> {
+    inner: A,
+}
+
+impl<A> AccountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        AccountingAllocator { inner }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+/// The layout asked of the inner allocator, with room for the tag
+fn tagged(layout: Layout) -> Option<Layout> {
+    Layout::from_size_align(layout.size().checked_add(1)?, layout.align()).ok()
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for AccountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(tagged) = tagged(layout) else {
+            return ptr::null_mut();
+        };
+        let ptr = unsafe { self.inner.alloc(tagged) };
+        if !ptr.is_null() {
+            let subsystem = current();
+            unsafe { ptr.add(layout.size()).write(subsystem as u8) };
+            charge(subsystem, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let Some(tagged) = tagged(layout) else {
+            return ptr::null_mut();
+        };
+        let ptr = unsafe { self.inner.alloc_zeroed(tagged) };
+        if !ptr.is_null() {
+            let subsystem = current();
+            unsafe { ptr.add(layout.size()).write(subsystem as u8) };
+            charge(subsystem, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let subsystem = Subsystem::from_tag(unsafe { ptr.add(layout.size()).read() });
+        credit(subsystem, layout.size());
+        // Allocating succeeded with this layout, so it is valid
+        let tagged =
+            unsafe { Layout::from_size_align_unchecked(layout.size() + 1, layout.align()) };
+        unsafe { self.inner.dealloc(ptr, tagged) };
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let Some(new_tagged) = new_size.checked_add(1) else {
+            return ptr::null_mut();
+        };
+        let tag = unsafe { ptr.add(layout.size()).read() };
+        let tagged =
+            unsafe { Layout::from_size_align_unchecked(layout.size() + 1, layout.align()) };
+        let new_ptr = unsafe { self.inner.realloc(ptr, tagged, new_tagged) };
+        if !new_ptr.is_null() {
+            // Stays charged to whoever made it
+            unsafe { new_ptr.add(new_size).write(tag) };
+            let counters = &COUNTERS[Subsystem::from_tag(tag) as usize];
+            counters.bytes.fetch_add(new_size, Ordering::Relaxed);
+            counters.bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test_case]
+    fn test_charged_allocations() {
+        let before = usage(Subsystem::Devices);
+        let buffer: Vec<u8> = {
+            let _charge = charge_to(Subsystem::Devices);
+            {
+                let _nested = charge_to(Subsystem::Ipc);
+                assert_eq!(current(), Subsystem::Ipc);
+            }
+            assert_eq!(current(), Subsystem::Devices);
+            Vec::with_capacity(4096)
+        };
+        assert_eq!(current(), Subsystem::Other);
+
+        let during = usage(Subsystem::Devices);
+        assert!(during.bytes >= before.bytes + 4096);
+        assert!(during.total_allocations > before.total_allocations);
+
+        // Freed outside the charge, still credited to the devices
+        drop(buffer);
+        assert!(usage(Subsystem::Devices).bytes + 4096 <= during.bytes);
+    }
+}
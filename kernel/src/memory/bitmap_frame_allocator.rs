@@ -4,20 +4,55 @@
 //! - Represents each frame in physical memory as a bit and stores metadata to check against memory leaks
 use crate::{
     constants::memory::{BITMAP_ENTRY_SIZE, FRAME_SIZE, FULL_BITMAP_ENTRY},
+    memory::{
+        boot_frame_allocator::BootHandoff,
+        regions::{top_of_usable_memory, usable_frames},
+    },
     serial_println,
 };
-use limine::{memory_map::EntryType, response::MemoryMapResponse};
 use x86_64::{
     structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
-use alloc::{boxed::Box, vec, vec::Vec};
+use alloc::{boxed::Box, vec};
+
+/// Counts that must agree for frame accounting to be right
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameAudit {
+    /// Whole usable frames in the memory map
+    pub usable_in_map: usize,
+    /// Whole usable frames the allocator counted at init
+    pub usable_frames: usize,
+    /// The allocator's free frame counter
+    pub free_frames: usize,
+    /// Clear bits anywhere in the bitmap
+    pub free_in_bitmap: usize,
+    /// Clear bits for frames inside usable regions
+    pub free_usable: usize,
+}
+
+impl FrameAudit {
+    /// Returns true if the counter matches the bitmap, and only usable
+    /// frames are ever free
+    pub fn is_consistent(&self) -> bool {
+        self.usable_in_map == self.usable_frames
+            && self.free_frames == self.free_in_bitmap
+            && self.free_in_bitmap == self.free_usable
+    }
+}
+
+/// Index of a frame's bit in the bitmap
+fn frame_index(frame: PhysFrame) -> usize {
+    frame.start_address().as_u64() as usize / FRAME_SIZE
+}
 
 // Holds bitmapand metadata for allocator
 pub struct BitmapFrameAllocator {
-    // Total frames usable in physical memory
+    // Frames up to the top of usable physical memory, the bitmap's length
     total_frames: usize,
+    // Whole frames inside usable regions of the memory map
+    usable_frames: usize,
     // Total usable frames that are free
     free_frames: usize,
     // Index of next frame to look at for allocation
@@ -33,28 +68,19 @@ pub struct BitmapFrameAllocator {
 impl BitmapFrameAllocator {
     /// Initializes bitmap with free and occupied regions in physical memory and updates bitmap metadata.
     ///
+    /// Every whole frame of a usable region starts free, then the frames the
+    /// boot allocator handed out are marked used. Each of those must be a
+    /// free usable frame, otherwise the boot allocator handed out memory it
+    /// did not own or handed out a frame twice.
+    ///
     /// # Arguments:
-    /// * 'memory_map' - map from Limine telling which parts of physical memory are usable
-    /// * 'initial_frames' - iterator of frames from previous allocator to tell which frames are already in use
+    /// * 'handoff' - frames the boot allocator allocated, which stay in use
     ///
     /// # Safety
     /// Unsafe due to requiring to interface directly with memory regions given by the bootloader
-    pub unsafe fn init(
-        memory_map: &'static MemoryMapResponse,
-        initial_frames: impl Iterator<Item = PhysFrame>,
-    ) -> Self {
-        let initial_frames_vec: Vec<PhysFrame> = initial_frames.collect();
-
+    pub unsafe fn init(handoff: BootHandoff) -> Self {
         // get the total number of frames (top of usable memory)
-        let mut true_end: usize = 0;
-        for entry in memory_map.entries().iter() {
-            if entry.entry_type == EntryType::USABLE {
-                let end_addr = entry.base + entry.length;
-                if end_addr as usize > true_end {
-                    true_end = end_addr as usize;
-                }
-            }
-        }
+        let true_end = top_of_usable_memory() as usize;
         serial_println!("The top of physmem is: {}", { true_end });
         let total_frames = true_end.div_ceil(FRAME_SIZE);
         let bitmap_size = total_frames.div_ceil(BITMAP_ENTRY_SIZE);
@@ -62,35 +88,52 @@ impl BitmapFrameAllocator {
         let bitmap = vec![FULL_BITMAP_ENTRY; bitmap_size].into_boxed_slice();
         let mut allocator = Self {
             total_frames,
+            usable_frames: 0,
             free_frames: 0,
-            to_allocate: initial_frames_vec.len(),
+            to_allocate: handoff.frames.len(),
             bitmap,
             allocate_count: 0,
             free_count: 0,
         };
 
-        for entry in memory_map.entries().iter() {
-            if entry.entry_type == EntryType::USABLE {
-                allocator.free_region(entry.base as usize, entry.length as usize);
-                allocator.free_frames += (entry.length as usize).div_ceil(FRAME_SIZE);
-            }
+        for frame in usable_frames() {
+            allocator.clear_bit_init(frame_index(frame));
         }
-        for frame in initial_frames_vec {
+        allocator.usable_frames = allocator.free_frames;
+
+        for frame in handoff.frames {
+            assert!(
+                !allocator.is_frame_used(frame),
+                "Boot allocator handed off {:?}, which is not a free usable frame",
+                frame
+            );
             allocator.mark_frame_used(frame);
         }
+
+        let audit = allocator.audit();
+        assert!(
+            audit.is_consistent(),
+            "Frame accounting is off after handoff: {:?}",
+            audit
+        );
         allocator
     }
 
-    /// Mark the region [base, base + length) as free in the bitmap.
-    ///
-    /// # Arguments:
-    /// * 'base' - index of which bits should be set to 0
-    /// * 'length' - length of range that should be set to 0
-    fn free_region(&mut self, base: usize, length: usize) {
-        let start_frame = base / FRAME_SIZE;
-        let end_frame = (base + length) / FRAME_SIZE;
-        for frame_index in start_frame..end_frame {
-            self.clear_bit_init(frame_index); // set to 0 = free
+    /// Recounts the bitmap and compares it against the memory map and the
+    /// allocator's counters
+    pub fn audit(&self) -> FrameAudit {
+        let free_usable = usable_frames()
+            .filter(|&frame| !self.is_bit_set(frame_index(frame)))
+            .count();
+        let free_in_bitmap = (0..self.total_frames)
+            .filter(|&index| !self.is_bit_set(index))
+            .count();
+        FrameAudit {
+            usable_in_map: usable_frames().count(),
+            usable_frames: self.usable_frames,
+            free_frames: self.free_frames,
+            free_in_bitmap,
+            free_usable,
         }
     }
 
@@ -102,7 +145,7 @@ impl BitmapFrameAllocator {
     /// # Returns:
     /// whether that specific frame is in use or not per bitmap
     pub fn is_frame_used(&mut self, frame: PhysFrame) -> bool {
-        self.is_bit_set(frame.start_address().as_u64() as usize / FRAME_SIZE)
+        self.is_bit_set(frame_index(frame))
     }
 
     /// Mark a specific frame as used (1).
@@ -110,7 +153,7 @@ impl BitmapFrameAllocator {
     /// # Arguments:
     /// * 'frame' - frame to be marked as used
     fn mark_frame_used(&mut self, frame: PhysFrame) {
-        self.set_bit(frame.start_address().as_u64() as usize / FRAME_SIZE);
+        self.set_bit(frame_index(frame));
     }
 
     /// Mark a specific frame as free (0).
@@ -118,7 +161,7 @@ impl BitmapFrameAllocator {
     /// # Arguments:
     /// * 'frame' - frame to be marked as free
     fn mark_frame_free(&mut self, frame: PhysFrame) {
-        self.clear_bit(frame.start_address().as_u64() as usize / FRAME_SIZE);
+        self.clear_bit(frame_index(frame));
     }
 
     /// set a particular bit (1), taking in frame_index (usize)
@@ -149,12 +192,10 @@ impl BitmapFrameAllocator {
         let bit_index = frame_index % 64;
 
         let mask = 1 << bit_index;
-        if self.bitmap[byte_index] == 0 {
-            assert!(
-                self.bitmap[byte_index] == 1,
-                "Trying to double free a frame!"
-            );
-        }
+        assert!(
+            self.bitmap[byte_index] & mask != 0,
+            "Trying to double free a frame!"
+        );
         self.bitmap[byte_index] &= !mask;
         self.free_frames += 1;
     }
@@ -162,8 +203,8 @@ impl BitmapFrameAllocator {
     /// clear a particular bit (0), taking in frame_index (usize)
     ///
     /// Asserts ONLY that index is within total frames - ONLY use
-    /// this in init since you may deal with garbage data so may not
-    /// only clear 1's.
+    /// this in init, where overlapping regions may clear a bit twice.
+    /// Only a bit that was set counts towards the free frames.
     ///
     /// # Arguments:
     /// * 'frame_index' - frame that will be cleared in bitmap
@@ -174,8 +215,10 @@ impl BitmapFrameAllocator {
         let bit_index = frame_index % 64;
 
         let mask = 1 << bit_index;
-        self.bitmap[byte_index] &= !mask;
-        self.free_frames += 1;
+        if self.bitmap[byte_index] & mask != 0 {
+            self.bitmap[byte_index] &= !mask;
+            self.free_frames += 1;
+        }
     }
 
     /// check if bit is set to 1 at frame_index.
@@ -223,7 +266,7 @@ unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     /// None if no frame available, otherwise first available frame
     ///
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        if self.free_frames == self.total_frames {
+        if self.free_frames == 0 {
             return None;
         }
         loop {
@@ -253,3 +296,23 @@ impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
         self.mark_frame_free(frame);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::frame_allocator::{alloc_frame, dealloc_frame, with_bitmap_frame_allocator};
+
+    #[test_case]
+    fn test_frame_accounting() {
+        let before = with_bitmap_frame_allocator(|allocator| allocator.audit());
+        assert!(before.is_consistent(), "{:?}", before);
+
+        let frame = alloc_frame().expect("Out of frames");
+        let during = with_bitmap_frame_allocator(|allocator| allocator.audit());
+        assert!(during.is_consistent(), "{:?}", during);
+        assert_eq!(during.free_frames, before.free_frames - 1);
+
+        dealloc_frame(frame);
+        let after = with_bitmap_frame_allocator(|allocator| allocator.audit());
+        assert_eq!(after, before);
+    }
+}
@@ -3,20 +3,13 @@
 //! - Provides a method to allocate memory before a heap is set up
 //! - Finds contiguous PhysFrames while avoiding unusable regions of memory
 
-use crate::constants::memory::{FRAME_SIZE, HEAP_SIZE, HEAP_START, MAX_ALLOCATED_FRAMES};
-use limine::{
-    memory_map::EntryType,
-    request::{KernelAddressRequest, MemoryMapRequest},
-    response::MemoryMapResponse,
+use crate::{
+    constants::memory::{HEAP_SIZE, HEAP_START, MAX_ALLOCATED_FRAMES},
+    memory::regions::usable_frames,
 };
-use x86_64::{
-    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
-    PhysAddr,
-};
-
-#[used]
-#[link_section = ".requests"]
-static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
+use alloc::vec::Vec;
+use limine::request::KernelAddressRequest;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
 
 #[used]
 #[link_section = ".requests"]
@@ -28,24 +21,22 @@ extern "C" {
 
 /// Boot frame allocator, necessary to set up frame mappings before heap init
 ///
-/// * `memory_map`: Limine memory map response
-/// * `next`: the next frame to allocate
-/// * `first_frame`: the very first frame allocated
-/// * `last_frame`: the very last frame allocated
-/// * `allocated_count`: the number of allocated frames
+/// * `next`: the number of frames allocated, which is also the index of the
+///   next usable frame to allocate
 /// * `kernel_start`: where the kernel starts in physical memory, given by Limine
 /// * `kernel_end`: where the kernel ends in physical memory, given by Limine
 pub struct BootIntoFrameAllocator {
-    pub memory_map: &'static MemoryMapResponse,
     next: usize,
-    // we note the first and last frame allocated to reallocate later
-    first_frame: Option<PhysFrame>,
-    last_frame: Option<PhysFrame>,
-    allocated_count: usize,
     kernel_start: u64,
     kernel_end: u64,
 }
 
+/// The frames the boot allocator handed out. The bitmap allocator starts
+/// with exactly these frames in use.
+pub struct BootHandoff {
+    pub frames: Vec<PhysFrame>,
+}
+
 impl BootIntoFrameAllocator {
     /// Init function
     ///
@@ -55,9 +46,6 @@ impl BootIntoFrameAllocator {
     /// # Safety
     /// This function is unsafe as it deals directly with memory map / physmem
     pub unsafe fn init() -> Self {
-        let memory_map: &MemoryMapResponse = MEMORY_MAP_REQUEST
-            .get_response()
-            .expect("Memory map request failed");
         let kernel_address_response = KERNEL_ADDRESS_REQUEST
             .get_response()
             .expect("Kernel Address request failed");
@@ -67,11 +55,7 @@ impl BootIntoFrameAllocator {
         let kernel_end = unsafe { ((_kernel_end) - virtual_kernel_address) + kernel_start };
 
         BootIntoFrameAllocator {
-            memory_map,
             next: 0,
-            first_frame: None,
-            last_frame: None,
-            allocated_count: 0,
             kernel_start,
             kernel_end,
         }
@@ -82,35 +66,22 @@ impl BootIntoFrameAllocator {
     /// # Returns
     /// Returns an iterator of usable PhysFrames
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
-        self.memory_map
-            .entries()
-            .iter()
-            .filter(|r| r.entry_type == EntryType::USABLE)
-            .flat_map(|r| (r.base..(r.base + r.length)).step_by(4096))
-            .filter(move |&addr| {
-                addr < self.kernel_start
-                    || addr >= self.kernel_end
-                    || addr < HEAP_START as u64
-                    || addr > (HEAP_START as u64).wrapping_add(HEAP_SIZE as u64)
-            })
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        usable_frames().filter(move |frame| {
+            let addr = frame.start_address().as_u64();
+            addr < self.kernel_start
+                || addr >= self.kernel_end
+                || addr < HEAP_START as u64
+                || addr > (HEAP_START as u64).wrapping_add(HEAP_SIZE as u64)
+        })
     }
 
-    /// Give all frames allocated so far
-    ///
-    /// # Returns
-    /// Returns an iterator over all frames allocated so far
-    pub fn allocated_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
-        let start_addr = self
-            .first_frame
-            .expect("No frames allocated yet")
-            .start_address()
-            .as_u64();
-
-        (0..self.allocated_count).map(move |i| {
-            let addr = start_addr + (i as u64 * FRAME_SIZE as u64);
-            PhysFrame::containing_address(PhysAddr::new(addr))
-        })
+    /// Lists every frame allocated so far, for the bitmap allocator to take
+    /// over. Allocation walks the usable frames in order, so these are
+    /// exactly the first `next` of them. Needs the heap.
+    pub fn handoff(&self) -> BootHandoff {
+        BootHandoff {
+            frames: self.usable_frames().take(self.next).collect(),
+        }
     }
 }
 
@@ -120,17 +91,10 @@ unsafe impl FrameAllocator<Size4KiB> for BootIntoFrameAllocator {
     /// # Returns
     /// Either a PhysFrame or None (if out of frames)
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = Some(self.usable_frames().nth(self.next)?);
+        let frame = self.usable_frames().nth(self.next)?;
+        assert!(self.next < MAX_ALLOCATED_FRAMES);
         self.next += 1;
-
-        assert!(self.allocated_count < MAX_ALLOCATED_FRAMES);
-        if self.first_frame.is_none() {
-            self.first_frame = frame;
-        }
-        self.last_frame = frame;
-        self.allocated_count += 1;
-
-        frame
+        Some(frame)
     }
 }
 
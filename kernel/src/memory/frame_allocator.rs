@@ -2,16 +2,20 @@
 //! Contains a GlobalFrameAllocator, which is a wrapper around
 //! the BootIntoFrameAllocator and the BitmapFrameAllocator
 
-use crate::memory::{
-    bitmap_frame_allocator::BitmapFrameAllocator, boot_frame_allocator::BootIntoFrameAllocator,
+use crate::{
+    memory::{
+        bitmap_frame_allocator::BitmapFrameAllocator, boot_frame_allocator::BootIntoFrameAllocator,
+        zero_pool,
+    },
+    sync::Mutex,
 };
-use spin::Mutex;
 
 use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
 
 /// Global frame allocator that makes it so we just have one actual allocator throughout codebase
 /// Requires some basic synchronization
-pub static FRAME_ALLOCATOR: Mutex<Option<GlobalFrameAllocator>> = Mutex::new(None);
+pub static FRAME_ALLOCATOR: Mutex<Option<GlobalFrameAllocator>> =
+    Mutex::new("frame allocator", None);
 
 /// Enum of supported allocators
 pub enum GlobalFrameAllocator {
@@ -54,10 +58,28 @@ impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
 
 /// Exposed function to allocate a frame that runs the global's allocate_frame
 ///
+/// Falls back to the pool of zeroed frames once the allocator runs out.
+/// The frame's contents are undefined, use `alloc_frame_zeroed` for any frame
+/// that may be mapped into user space.
+///
 /// # Returns
 /// The allocated frame
 pub fn alloc_frame() -> Option<PhysFrame> {
-    with_generic_allocator(|allocator| allocator.allocate_frame())
+    with_generic_allocator(|allocator| allocator.allocate_frame()).or_else(zero_pool::take)
+}
+
+/// Allocates a frame filled with zeroes, preferably one the zeroing daemon
+/// prepared ahead of time
+///
+/// # Returns
+/// The allocated frame
+pub fn alloc_frame_zeroed() -> Option<PhysFrame> {
+    if let Some(frame) = zero_pool::take() {
+        return Some(frame);
+    }
+    let frame = with_generic_allocator(|allocator| allocator.allocate_frame())?;
+    zero_pool::zero_frame(frame);
+    Some(frame)
 }
 
 /// Exposed function to deallocate a frame that runs the global's deallocate_frame
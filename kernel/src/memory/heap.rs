@@ -6,19 +6,48 @@ use crate::{
     memory::{frame_allocator::FRAME_ALLOCATOR, paging::create_mapping, MAPPER},
     serial_println,
 };
+use core::sync::atomic::{AtomicBool, Ordering};
 use talc::{ClaimOnOom, Span, Talc, Talck};
 use x86_64::{
     structures::paging::{mapper::MapToError, Page, Size4KiB},
     VirtAddr,
 };
 
-use super::{bitmap_frame_allocator::BitmapFrameAllocator, frame_allocator::GlobalFrameAllocator};
+#[cfg(feature = "heap-redzones")]
+use crate::{
+    constants::memory::RED_ZONE_CHECK_INTERVAL_NS,
+    events::sleep_until,
+    memory::redzone::{self, RedZoneAllocator},
+    time,
+};
+
+use super::{
+    accounting::AccountingAllocator, bitmap_frame_allocator::BitmapFrameAllocator,
+    frame_allocator::GlobalFrameAllocator,
+};
+
+/// Set once every heap page is mapped
+static HEAP_READY: AtomicBool = AtomicBool::new(false);
 
+#[cfg(not(feature = "heap-redzones"))]
+#[global_allocator]
+static ALLOCATOR: AccountingAllocator<Talck<spin::Mutex<()>, ClaimOnOom>> =
+    AccountingAllocator::new(
+        Talc::new(unsafe {
+            ClaimOnOom::new(Span::new(HEAP_START, HEAP_START.wrapping_add(HEAP_SIZE)))
+        })
+        .lock(),
+    );
+
+#[cfg(feature = "heap-redzones")]
 #[global_allocator]
-static ALLOCATOR: Talck<spin::Mutex<()>, ClaimOnOom> = Talc::new(unsafe {
-    ClaimOnOom::new(Span::new(HEAP_START, HEAP_START.wrapping_add(HEAP_SIZE)))
-})
-.lock();
+static ALLOCATOR: AccountingAllocator<RedZoneAllocator<Talck<spin::Mutex<()>, ClaimOnOom>>> =
+    AccountingAllocator::new(RedZoneAllocator::new(
+        Talc::new(unsafe {
+            ClaimOnOom::new(Span::new(HEAP_START, HEAP_START.wrapping_add(HEAP_SIZE)))
+        })
+        .lock(),
+    ));
 
 /// Initialize the heap and switch to using the bitmap frame_allocator
 ///
@@ -36,6 +65,7 @@ pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
     for page in page_range {
         create_mapping(page, &mut *MAPPER.lock(), None);
     }
+    HEAP_READY.store(true, Ordering::Release);
 
     switch_allocator();
 
@@ -44,16 +74,31 @@ pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
     Ok(())
 }
 
+/// Returns true once the heap can be allocated from
+pub fn heap_ready() -> bool {
+    HEAP_READY.load(Ordering::Acquire)
+}
+
+/// Checks the red zones of every heap allocation now and then, to catch
+/// overruns of buffers that live a long time. Runs forever, should be
+/// scheduled with `schedule_idle`.
+#[cfg(feature = "heap-redzones")]
+pub async fn redzone_daemon() {
+    loop {
+        if let Err(error) = ALLOCATOR.inner().check_all() {
+            redzone::report(error);
+        }
+        sleep_until(time::monotonic_ns() + RED_ZONE_CHECK_INTERVAL_NS).await;
+    }
+}
+
 /// Switches the allocator from the boot into frame allocator to the bitmap frame allocator
 fn switch_allocator() {
     let mut alloc = FRAME_ALLOCATOR.lock();
     match *alloc {
         Some(GlobalFrameAllocator::Boot(ref boot_alloc)) => {
             unsafe {
-                let bitmap_frame_allocator = BitmapFrameAllocator::init(
-                    boot_alloc.memory_map,
-                    boot_alloc.allocated_frames(),
-                );
+                let bitmap_frame_allocator = BitmapFrameAllocator::init(boot_alloc.handoff());
                 *alloc = Some(GlobalFrameAllocator::Bitmap(bitmap_frame_allocator));
 
                 serial_println!("new frame allocator set");
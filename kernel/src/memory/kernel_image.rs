@@ -0,0 +1,148 @@
+//! Protection of the kernel image.
+//!
+//! Nothing the kernel controls guarantees how the bootloader mapped the
+//! kernel image. Once boot is done, `protect` remaps text as read-only and
+//! executable, rodata as read-only, and data and bss as non-executable,
+//! using the section bounds from the linker script. A stray write into code or constants then
+//! faults at the write instead of corrupting the kernel, and the page
+//! fault handler names the section that was hit.
+
+use core::ptr::addr_of;
+
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags},
+    structures::paging::{Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{debug, memory::paging::protect_range};
+
+extern "C" {
+    static _text_start: u8;
+    static _text_end: u8;
+    static _rodata_start: u8;
+    static _rodata_end: u8;
+    static _data_start: u8;
+    static _kernel_end: u64;
+}
+
+/// A part of the kernel image with its own permissions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelSection {
+    Text,
+    Rodata,
+    /// Data, the kernel log ring, and bss
+    Data,
+}
+
+impl KernelSection {
+    const ALL: [KernelSection; 3] = [
+        KernelSection::Text,
+        KernelSection::Rodata,
+        KernelSection::Data,
+    ];
+
+    /// The section's virtual address range, end exclusive
+    fn bounds(self) -> (u64, u64) {
+        unsafe {
+            match self {
+                KernelSection::Text => (addr_of!(_text_start) as u64, addr_of!(_text_end) as u64),
+                KernelSection::Rodata => {
+                    (addr_of!(_rodata_start) as u64, addr_of!(_rodata_end) as u64)
+                }
+                KernelSection::Data => (addr_of!(_data_start) as u64, addr_of!(_kernel_end) as u64),
+            }
+        }
+    }
+
+    /// The flags the section is mapped with after `protect`
+    fn flags(self) -> PageTableFlags {
+        match self {
+            KernelSection::Text => PageTableFlags::PRESENT,
+            KernelSection::Rodata => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            KernelSection::Data => {
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+            }
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KernelSection::Text => "text",
+            KernelSection::Rodata => "rodata",
+            KernelSection::Data => "data",
+        }
+    }
+}
+
+/// Returns the section of the kernel image holding an address
+pub fn section_of(address: VirtAddr) -> Option<KernelSection> {
+    KernelSection::ALL.into_iter().find(|section| {
+        let (start, end) = section.bounds();
+        (start..end).contains(&address.as_u64())
+    })
+}
+
+/// Remaps every section of the kernel image with its own permissions. Must
+/// run on the BSP once every core is up, as it shoots down their TLBs.
+pub fn protect(mapper: &mut impl Mapper<Size4KiB>) {
+    // Without WP the kernel could still write to read-only pages
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+    for section in KernelSection::ALL {
+        let (start, end) = section.bounds();
+        if start == end {
+            continue;
+        }
+        let pages = Page::range_inclusive(
+            Page::containing_address(VirtAddr::new(start)),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        let updated = protect_range(pages, mapper, section.flags());
+        debug!(
+            "Kernel {} protected: {} of {} pages",
+            section.name(),
+            updated,
+            pages.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::{mapper::TranslateResult, Translate};
+
+    use super::*;
+    use crate::memory::MAPPER;
+
+    static CONSTANT: [u8; 4] = *b"taos";
+    static mut MUTABLE: u64 = 0;
+
+    fn flags_of(address: u64) -> PageTableFlags {
+        match MAPPER.lock().translate(VirtAddr::new(address)) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => panic!("Kernel image is not mapped"),
+        }
+    }
+
+    #[test_case]
+    fn test_kernel_sections() {
+        let text = flags_of as usize as u64;
+        let rodata = CONSTANT.as_ptr() as u64;
+        let data = addr_of!(MUTABLE) as u64;
+        assert_eq!(section_of(VirtAddr::new(text)), Some(KernelSection::Text));
+        assert_eq!(
+            section_of(VirtAddr::new(rodata)),
+            Some(KernelSection::Rodata)
+        );
+        assert_eq!(section_of(VirtAddr::new(data)), Some(KernelSection::Data));
+
+        let text = flags_of(text);
+        assert!(!text.contains(PageTableFlags::WRITABLE));
+        assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+        let rodata = flags_of(rodata);
+        assert!(!rodata.contains(PageTableFlags::WRITABLE));
+        assert!(rodata.contains(PageTableFlags::NO_EXECUTE));
+        assert!(flags_of(data).contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+        assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+    }
+}
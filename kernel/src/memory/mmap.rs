@@ -0,0 +1,353 @@
+//! Files mapped into a process' address space.
+//!
+//! A `FileMapping` is an area whose pages are read from a file the first
+//! time they are touched. Each mapping has pages of its own, so mappings of
+//! the same file do not see each other's writes until they are written
+//! back and the pages read in again.
+//!
+//! Shared writable mappings keep track of which pages were written without
+//! scanning for dirty bits: a page is first mapped read-only, and the
+//! first write to it faults, which maps it writable. A writable page of a
+//! shared mapping is therefore always one that may differ from the file.
+//! `sync` writes those pages back through the VFS and maps them read-only
+//! again, so the next write is noticed as well. Pages past the end of the
+//! file are never written back, so syncing does not grow the file.
+//!
+//! Private mappings map their pages writable straight away and never write
+//! them back.
+//!
+//! Unmapping or reprotecting part of a mapping splits it, so that each
+//! mapping is one contiguous area with one set of flags. The pieces share
+//! the open file.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::cmp::min;
+
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        page::PageRangeInclusive,
+        OffsetPageTable, Page, PageTableFlags, Translate,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    constants::{
+        memory::PAGE_SIZE,
+        processes::{MMAP_START, STACK_START},
+        syscalls::{PROT_READ, PROT_WRITE},
+    },
+    filesys::{vfs, FsError, SeekFrom},
+    memory::{
+        paging::{map_range, protect_range, unmap_range},
+        vma::Vma,
+        HHDM_OFFSET,
+    },
+    processes::fd_table::VfsFile,
+};
+
+/// How a fault in a file mapping was handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingFault {
+    /// The page was read in from the file
+    PagedIn,
+    /// A read-only page of a shared mapping was written, and is now
+    /// writable and dirty
+    Dirtied,
+}
+
+/// Flags for the pages of a mapping with the given PROT_ bits. Mappings
+/// are always readable and never executable.
+///
+/// # Returns
+/// The flags, or None if `prot` lacks PROT_READ or has unknown bits
+pub fn protection_to_pagetable_flags(prot: u64) -> Option<PageTableFlags> {
+    if prot & !(PROT_READ | PROT_WRITE) != 0 || prot & PROT_READ == 0 {
+        return None;
+    }
+    let mut flags =
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    Some(flags)
+}
+
+/// An area of user addresses backed by part of a file
+#[derive(Debug)]
+pub struct FileMapping {
+    pub vma: Vma,
+    /// The mapping's own open file, so its position is not shared with any
+    /// descriptor. Pieces split off the mapping share it, and every access
+    /// seeks first.
+    file: Arc<VfsFile>,
+    /// Offset in the file of the area's first page
+    offset: u64,
+    /// Whether writes are written back to the file
+    shared: bool,
+}
+
+impl FileMapping {
+    /// Describes a mapping of `len` bytes of `file`, from `offset`, at
+    /// `start`, whose pages get `flags`. Nothing is mapped until pages are
+    /// touched.
+    pub fn new(
+        start: VirtAddr,
+        len: u64,
+        file: VfsFile,
+        offset: u64,
+        flags: PageTableFlags,
+        shared: bool,
+    ) -> Self {
+        FileMapping {
+            vma: Vma {
+                start,
+                end: start + len.next_multiple_of(PAGE_SIZE as u64),
+                flags,
+            },
+            file: Arc::new(file),
+            offset,
+            shared,
+        }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Every page of the mapping
+    pub fn pages(&self) -> PageRangeInclusive {
+        Page::range_inclusive(
+            Page::containing_address(self.vma.start),
+            self.vma.top_page(),
+        )
+    }
+
+    /// Splits the mapping at `at`, a page boundary inside it. The mapping
+    /// keeps the pages before `at`, and the rest, already mapped or not,
+    /// belong to the mapping returned.
+    pub fn split_off(&mut self, at: VirtAddr) -> FileMapping {
+        assert!(self.vma.start < at && at < self.vma.end && at.is_aligned(PAGE_SIZE as u64));
+        let rest = FileMapping {
+            vma: Vma {
+                start: at,
+                ..self.vma
+            },
+            file: Arc::clone(&self.file),
+            offset: self.offset + (at - self.vma.start),
+            shared: self.shared,
+        };
+        self.vma.end = at;
+        rest
+    }
+
+    /// Gives the mapping's pages new flags, including those already
+    /// mapped. Dirty pages of a shared mapping losing write access are
+    /// written back first, since a read-only page counts as clean.
+    ///
+    /// # Returns
+    /// The filesystem's error if a page could not be written back, in
+    /// which case the flags are left as they were
+    pub fn protect(
+        &mut self,
+        flags: PageTableFlags,
+        mapper: &mut OffsetPageTable,
+    ) -> Result<(), FsError> {
+        if flags == self.vma.flags {
+            return Ok(());
+        }
+        if !flags.contains(PageTableFlags::WRITABLE) {
+            self.sync(self.pages(), mapper)?;
+        }
+        self.vma.flags = flags;
+        // Pages of a shared mapping made writable stay clean until written
+        protect_range(self.pages(), mapper, self.clean_flags());
+        Ok(())
+    }
+
+    /// Flags of a page that has not been written since it was read in or
+    /// written back
+    fn clean_flags(&self) -> PageTableFlags {
+        if self.shared {
+            self.vma.flags - PageTableFlags::WRITABLE
+        } else {
+            self.vma.flags
+        }
+    }
+
+    fn file_offset(&self, page: Page) -> u64 {
+        self.offset + (page.start_address() - self.vma.start)
+    }
+
+    /// Handles a fault at `addr`, which must lie in the mapping, by reading
+    /// the page in or by letting a shared page be written
+    ///
+    /// # Returns
+    /// How the fault was handled, or None if the access is not allowed or
+    /// the page could not be read
+    pub fn fault(
+        &self,
+        addr: VirtAddr,
+        write: bool,
+        mapper: &mut OffsetPageTable,
+    ) -> Option<MappingFault> {
+        if write && !self.vma.flags.contains(PageTableFlags::WRITABLE) {
+            return None;
+        }
+        let page = Page::containing_address(addr);
+        let pages = Page::range_inclusive(page, page);
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => {
+                if !write || flags.contains(PageTableFlags::WRITABLE) {
+                    return None;
+                }
+                protect_range(pages, mapper, self.vma.flags);
+                Some(MappingFault::Dirtied)
+            }
+            TranslateResult::NotMapped => {
+                let flags = if write {
+                    self.vma.flags
+                } else {
+                    self.clean_flags()
+                };
+                let frame = map_range(pages, mapper, flags).ok()?[0];
+                // The process is stopped in the fault, so nothing sees the
+                // page before it is filled
+                let data = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        (*HHDM_OFFSET + frame.start_address().as_u64()).as_mut_ptr::<u8>(),
+                        PAGE_SIZE,
+                    )
+                };
+                if self.read_page(page, data).is_err() {
+                    unmap_range(pages, mapper, true);
+                    return None;
+                }
+                Some(MappingFault::PagedIn)
+            }
+            TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
+
+    /// Fills `data` from the file at the page's offset. Whatever lies past
+    /// the end of the file is left as it is.
+    fn read_page(&self, page: Page, data: &mut [u8]) -> Result<(), FsError> {
+        let mut vfs = vfs::lock();
+        vfs.seek(self.file.fd, SeekFrom::Start(self.file_offset(page)))?;
+        let mut filled = 0;
+        while filled < data.len() {
+            let read = vfs.read(self.file.fd, &mut data[filled..])?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty pages of `pages` back to the file and maps them
+    /// read-only again. The process must not be running, so no page is
+    /// written while it is being copied.
+    ///
+    /// # Returns
+    /// The number of pages written back. On failure the pages before the
+    /// one that failed are clean, and the rest stay dirty.
+    pub fn sync(
+        &self,
+        pages: PageRangeInclusive,
+        mapper: &mut OffsetPageTable,
+    ) -> Result<usize, FsError> {
+        if !self.shared || pages.is_empty() {
+            return Ok(0);
+        }
+        let mut written = 0;
+        let result = self.write_back(pages, mapper, &mut written);
+        // Every page before one that failed has been written back
+        let clean = match &result {
+            Ok(()) => Some(pages),
+            Err((failed, _)) if *failed > pages.start => {
+                Some(Page::range_inclusive(pages.start, *failed - 1))
+            }
+            Err(_) => None,
+        };
+        if let Some(clean) = clean.filter(|_| written > 0) {
+            protect_range(clean, mapper, self.clean_flags());
+        }
+        result.map(|()| written).map_err(|(_, error)| error)
+    }
+
+    /// Copies each dirty page of `pages` to the file, counting them in
+    /// `written`
+    ///
+    /// # Returns
+    /// The page that could not be written back, and why
+    fn write_back(
+        &self,
+        pages: PageRangeInclusive,
+        mapper: &mut OffsetPageTable,
+        written: &mut usize,
+    ) -> Result<(), (Page, FsError)> {
+        let mut vfs = vfs::lock();
+        let size = vfs
+            .seek(self.file.fd, SeekFrom::End(0))
+            .map_err(|error| (pages.start, error))?;
+        for page in pages {
+            let TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } = mapper.translate(page.start_address())
+            else {
+                continue;
+            };
+            if !flags.contains(PageTableFlags::WRITABLE) {
+                continue;
+            }
+            let offset = self.file_offset(page);
+            if offset < size {
+                let len = min(PAGE_SIZE as u64, size - offset) as usize;
+                let data = unsafe {
+                    core::slice::from_raw_parts(
+                        (*HHDM_OFFSET + frame.start_address().as_u64()).as_ptr::<u8>(),
+                        len,
+                    )
+                };
+                vfs.seek(self.file.fd, SeekFrom::Start(offset))
+                    .map_err(|error| (page, error))?;
+                let mut done = 0;
+                while done < len {
+                    match vfs.write(self.file.fd, &data[done..]) {
+                        Ok(0) => return Err((page, FsError::NoSpace)),
+                        Ok(count) => done += count,
+                        Err(error) => return Err((page, error)),
+                    }
+                }
+            }
+            *written += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Finds room for `len` bytes of mappings between MMAP_START and the
+/// stack area, next to none of `mappings`
+///
+/// # Returns
+/// The lowest free page aligned address, or None if there is no room
+pub fn find_free_area(mappings: &[FileMapping], len: u64) -> Option<VirtAddr> {
+    let len = len.checked_next_multiple_of(PAGE_SIZE as u64)?;
+    let mut taken: Vec<(u64, u64)> = mappings
+        .iter()
+        .map(|mapping| (mapping.vma.start.as_u64(), mapping.vma.end.as_u64()))
+        .collect();
+    taken.sort_unstable();
+    let mut start = MMAP_START;
+    for (used_start, used_end) in taken {
+        if start.checked_add(len)? <= used_start {
+            break;
+        }
+        start = start.max(used_end);
+    }
+    (start.checked_add(len)? <= STACK_START).then(|| VirtAddr::new(start))
+}
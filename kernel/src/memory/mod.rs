@@ -3,24 +3,32 @@
 //! Provides an interface for paging and mapping frames of memory
 //! Implements TLB shootdowns
 
+pub mod accounting;
 pub mod bitmap_frame_allocator;
 pub mod boot_frame_allocator;
 pub mod frame_allocator;
 pub mod heap;
+pub mod kernel_image;
+pub mod mmap;
 pub mod paging;
+pub mod redzone;
+pub mod regions;
 pub mod tlb;
+pub mod vma;
+pub mod zero_pool;
 
 use boot_frame_allocator::BootIntoFrameAllocator;
 use frame_allocator::{GlobalFrameAllocator, FRAME_ALLOCATOR};
 use lazy_static::lazy_static;
 use limine::request::HhdmRequest;
-use spin::Mutex;
 use x86_64::{
     registers::model_specific::{Efer, EferFlags},
     structures::paging::OffsetPageTable,
     VirtAddr,
 };
 
+use crate::sync::Mutex;
+
 #[used]
 #[link_section = ".requests"]
 pub static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
@@ -31,7 +39,7 @@ extern "C" {
 
 lazy_static! {
     // The kernel mapper
-    pub static ref MAPPER: Mutex<OffsetPageTable<'static>> = Mutex::new(unsafe { paging::init() });
+    pub static ref MAPPER: Mutex<OffsetPageTable<'static>> = Mutex::new("kernel mapper", unsafe { paging::init() });
     // Start of kernel virtual memory
     pub static ref HHDM_OFFSET: VirtAddr = VirtAddr::new(
         HHDM_REQUEST
@@ -2,21 +2,37 @@
 // however it could be used in a plethora of places later so I am keeping it for now
 #![allow(dead_code)]
 
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
 use x86_64::{
     structures::paging::{
-        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        page::PageRangeInclusive,
+        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
     },
     VirtAddr,
 };
 
 use crate::{
-    constants::memory::EPHEMERAL_KERNEL_MAPPINGS_START,
+    constants::memory::{EPHEMERAL_KERNEL_MAPPINGS_START, PAGE_SIZE, USER_SPACE_END},
     memory::{
-        frame_allocator::{alloc_frame, dealloc_frame, FRAME_ALLOCATOR},
-        tlb::tlb_shootdown,
+        frame_allocator::{alloc_frame, alloc_frame_zeroed, dealloc_frame, FRAME_ALLOCATOR},
+        tlb::{tlb_shootdown, tlb_shootdown_range},
     },
 };
 
+/// The bits of a virtual address that page tables translate
+const ADDRESS_MASK: u64 = (1 << 48) - 1;
+
+/// Marks a page whose frame belongs to something other than the address
+/// space, such as a cached program image. Unmapping the page never frees
+/// the frame.
+pub const BORROWED_FRAME: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Marks a read-only page that becomes a writable private copy of itself
+/// on the first write to it
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_10;
+
 use super::HHDM_OFFSET;
 
 static mut NEXT_EPH_OFFSET: u64 = 0;
@@ -50,8 +66,8 @@ pub unsafe fn active_level_4_table() -> &'static mut PageTable {
     &mut *page_table_ptr
 }
 
-/// Creates a mapping
-/// Default flags: PRESENT | WRITABLE
+/// Creates a mapping to a newly allocated, zeroed frame
+/// Default flags: PRESENT | WRITABLE | USER_ACCESSIBLE
 ///
 /// # Arguments
 /// * `page` - a Page that we want to map
@@ -65,7 +81,7 @@ pub fn create_mapping(
     mapper: &mut impl Mapper<Size4KiB>,
     flags: Option<PageTableFlags>,
 ) -> PhysFrame {
-    let frame = alloc_frame().expect("no more frames");
+    let frame = alloc_frame_zeroed().expect("no more frames");
 
     let _ = unsafe {
         mapper
@@ -87,24 +103,295 @@ pub fn create_mapping(
     frame
 }
 
-/// Updates an existing mapping
+/// Maps a page to a frame that is already allocated. The page must not be
+/// mapped yet, so no TLB can hold it.
+pub fn map_frame(
+    page: Page,
+    frame: PhysFrame,
+    mapper: &mut impl Mapper<Size4KiB>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().expect("Global allocator not initialized");
+    unsafe { mapper.map_to(page, frame, flags, allocator) }?.ignore();
+    Ok(())
+}
+
+/// Returns every page of the user half of an address space
+pub fn user_pages() -> PageRangeInclusive {
+    Page::range_inclusive(
+        Page::containing_address(VirtAddr::new(0)),
+        Page::containing_address(VirtAddr::new(USER_SPACE_END - 1)),
+    )
+}
+
+/// Maps every page of a range to newly allocated, zeroed frames. The
+/// pages must not be mapped yet, so no TLB can hold them.
 ///
-/// Performs a TLB shootdown if the new frame is different than the old
+/// # Arguments
+/// * `pages` - the pages to map
+/// * `mapper` - anything that implements a the Mapper trait
+/// * `flags` - flags of every new mapping
+///
+/// # Returns
+/// The frames mapped, in page order. On failure nothing stays mapped.
+pub fn map_range(
+    pages: PageRangeInclusive,
+    mapper: &mut impl Mapper<Size4KiB>,
+    flags: PageTableFlags,
+) -> Result<Vec<PhysFrame>, MapToError<Size4KiB>> {
+    let mut frames = Vec::with_capacity(pages.len() as usize);
+    for _ in pages {
+        match alloc_frame_zeroed() {
+            Some(frame) => frames.push(frame),
+            None => {
+                frames.into_iter().for_each(dealloc_frame);
+                return Err(MapToError::FrameAllocationFailed);
+            }
+        }
+    }
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().expect("Global allocator not initialized");
+    for (mapped, (page, &frame)) in pages.zip(frames.iter()).enumerate() {
+        if let Err(e) = unsafe { mapper.map_to(page, frame, flags, allocator) } {
+            for (page, _) in pages.zip(frames.iter()).take(mapped) {
+                let _ = mapper.unmap(page).map(|(_, flush)| flush.ignore());
+            }
+            drop(guard);
+            frames.into_iter().for_each(dealloc_frame);
+            return Err(e);
+        }
+    }
+    Ok(frames)
+}
+
+/// Unmaps every mapped page of a range with one walk of the page tables,
+/// then flushes the range from every TLB with a single shootdown. Pages that
+/// are not mapped are skipped, so a range may cover a whole address space.
+/// Page tables the walk leaves empty are freed as well.
 ///
 /// # Arguments
-/// * `page` - a Page that we want to map, must already be mapped
+/// * `pages` - the pages to unmap
+/// * `mapper` - the page table to unmap them from
+/// * `free_frames` - whether to deallocate the frames that were mapped.
+///   Borrowed frames are never deallocated.
+///
+/// # Returns
+/// The number of pages unmapped
+pub fn unmap_range(
+    pages: PageRangeInclusive,
+    mapper: &mut OffsetPageTable,
+    free_frames: bool,
+) -> usize {
+    let released = walk_range(pages, mapper, true);
+    let count = released.frames.len() + released.borrowed;
+    if free_frames {
+        released.frames.into_iter().for_each(dealloc_frame);
+    }
+    released.tables.into_iter().for_each(dealloc_frame);
+    count
+}
+
+/// Frees the page tables under a range that no longer map anything,
+/// without touching the pages still mapped there. Meant for windows like
+/// the ephemeral kernel mappings, where sweeping the whole kernel page
+/// table would be slow and could free tables other address spaces share.
+///
+/// # Arguments
+/// * `pages` - the pages whose tables to reclaim
+/// * `mapper` - the page table holding them
+///
+/// # Returns
+/// The number of page tables freed
+pub fn clean_up_range(pages: PageRangeInclusive, mapper: &mut OffsetPageTable) -> usize {
+    let released = walk_range(pages, mapper, false);
+    let count = released.tables.len();
+    released.tables.into_iter().for_each(dealloc_frame);
+    count
+}
+
+/// Frames a walk of the page tables took out of use
+#[derive(Default)]
+struct Released {
+    /// Frames the unmapped pages pointed to, other than borrowed ones
+    frames: Vec<PhysFrame>,
+    /// Unmapped pages whose frames were borrowed
+    borrowed: usize,
+    /// Page tables left empty
+    tables: Vec<PhysFrame>,
+}
+
+/// Walks the page tables under a range, optionally unmapping its pages,
+/// and unlinks the tables left empty. Flushes the range from every TLB if
+/// anything changed, so the released frames can be freed once this returns.
+fn walk_range(pages: PageRangeInclusive, mapper: &mut OffsetPageTable, unmap: bool) -> Released {
+    let mut released = Released::default();
+    if pages.is_empty() {
+        return released;
+    }
+    // Walk in the 48 bit address space the tables index, without the sign
+    // extension of canonical addresses
+    let first = pages.start.start_address().as_u64() & ADDRESS_MASK;
+    let last = (pages.end.start_address().as_u64() & ADDRESS_MASK) + (PAGE_SIZE as u64 - 1);
+    let hhdm = mapper.phys_offset().as_u64();
+
+    unsafe {
+        walk_level(
+            mapper.level_4_table_mut(),
+            4,
+            0,
+            first..=last,
+            hhdm,
+            unmap,
+            &mut released,
+        );
+    }
+
+    // Paging structure caches may still point at the freed tables
+    if !released.frames.is_empty() || released.borrowed > 0 || !released.tables.is_empty() {
+        tlb_shootdown_range(pages.start.start_address(), pages.len());
+    }
+    released
+}
+
+/// Visits the entries of one table that fall inside `range`, descending
+/// into lower tables. Clears level 1 entries if `unmap` is set and unlinks
+/// lower tables that end up empty.
+///
+/// # Safety
+/// `table` must be a page table of the given level, reachable through the
+/// HHDM at `hhdm`
+unsafe fn walk_level(
+    table: &mut PageTable,
+    level: u32,
+    base: u64,
+    range: RangeInclusive<u64>,
+    hhdm: u64,
+    unmap: bool,
+    released: &mut Released,
+) {
+    let span = 1u64 << (12 + 9 * (level - 1));
+    for (index, entry) in table.iter_mut().enumerate() {
+        let entry_first = base + index as u64 * span;
+        let entry_last = entry_first + (span - 1);
+        if entry.is_unused() || entry_last < *range.start() || entry_first > *range.end() {
+            continue;
+        }
+        if level == 1 {
+            if unmap {
+                if entry.flags().contains(BORROWED_FRAME) {
+                    released.borrowed += 1;
+                } else {
+                    released
+                        .frames
+                        .push(PhysFrame::containing_address(entry.addr()));
+                }
+                entry.set_unused();
+            }
+        } else if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let next = unsafe { &mut *((hhdm + entry.addr().as_u64()) as *mut PageTable) };
+            unsafe {
+                walk_level(
+                    next,
+                    level - 1,
+                    entry_first,
+                    range.clone(),
+                    hhdm,
+                    unmap,
+                    released,
+                )
+            };
+
+            // Every address space copies the kernel half of the level 4
+            // table, so the tables it points to are never freed
+            let shared = level == 4 && entry_first >= USER_SPACE_END;
+            if !shared && next.iter().all(|e| e.is_unused()) {
+                released
+                    .tables
+                    .push(PhysFrame::containing_address(entry.addr()));
+                entry.set_unused();
+            }
+        }
+    }
+}
+
+/// Changes the flags of every mapped page of a range, then flushes the
+/// range from every TLB with a single shootdown. Pages that are not mapped
+/// are skipped.
+///
+/// # Arguments
+/// * `pages` - the pages to update
 /// * `mapper` - anything that implements a the Mapper trait
+/// * `flags` - the new flags
+///
+/// # Returns
+/// The number of pages updated
+pub fn protect_range(
+    pages: PageRangeInclusive,
+    mapper: &mut impl Mapper<Size4KiB>,
+    flags: PageTableFlags,
+) -> usize {
+    let mut updated = 0;
+    for page in pages {
+        if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+            flush.ignore();
+            updated += 1;
+        }
+    }
+    if updated > 0 {
+        tlb_shootdown_range(pages.start.start_address(), pages.len());
+    }
+    updated
+}
+
+/// Updates an existing mapping, pointing it at a new frame, giving it new
+/// flags, or both
+///
+/// Performs a TLB shootdown if the frame or the flags change
+///
+/// # Arguments
+/// * `page` - a Page that we want to map, must already be mapped
+/// * `mapper` - anything that implements the Mapper and Translate traits
 /// * `frame` - the PhysFrame<Size4KiB> to map to
-pub fn update_mapping(page: Page, mapper: &mut impl Mapper<Size4KiB>, frame: PhysFrame<Size4KiB>) {
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+/// * `flags` - the new flags, or None to keep the page's current flags
+pub fn update_mapping(
+    page: Page,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame: PhysFrame<Size4KiB>,
+    flags: Option<PageTableFlags>,
+) {
+    let TranslateResult::Mapped {
+        frame: MappedFrame::Size4KiB(old_frame),
+        flags: old_flags,
+        ..
+    } = mapper.translate(page.start_address())
+    else {
+        panic!("Update failed, page is not mapped to a 4KiB frame");
+    };
+    // Accessed and dirty describe the old frame, not the new mapping
+    let flags = flags
+        .unwrap_or(old_flags)
+        .difference(PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+
+    if old_frame == frame {
+        if flags != old_flags.difference(PageTableFlags::ACCESSED | PageTableFlags::DIRTY) {
+            unsafe { mapper.update_flags(page, flags) }
+                .expect("Updating flags failed")
+                .ignore();
+            tlb_shootdown(page.start_address());
+        }
+        return;
+    }
 
-    let (old_frame, _) = mapper
+    mapper
         .unmap(page)
-        .expect("Unmap failed, frame likely was not mapped already");
-
-    if old_frame != frame {
-        let _ = unsafe {
-            mapper.map_to(
+        .expect("Unmap failed, frame likely was not mapped already")
+        .1
+        .ignore();
+    unsafe {
+        mapper
+            .map_to(
                 page,
                 frame,
                 flags,
@@ -113,10 +400,46 @@ pub fn update_mapping(page: Page, mapper: &mut impl Mapper<Size4KiB>, frame: Phy
                     .as_mut()
                     .expect("Global allocator not initialized"),
             )
-        };
+            .expect("Mapping failed")
+            .ignore();
+    }
+    tlb_shootdown(page.start_address());
+}
 
-        tlb_shootdown(page.start_address());
+/// Gives a copy-on-write page a private copy of its frame, mapped writable
+///
+/// # Returns
+/// Whether the page was copied. False if it is not a mapped copy-on-write
+/// page, or if no frame is free for the copy.
+pub fn copy_on_write(page: Page, mapper: &mut OffsetPageTable) -> bool {
+    let TranslateResult::Mapped {
+        frame: MappedFrame::Size4KiB(frame),
+        flags,
+        ..
+    } = mapper.translate(page.start_address())
+    else {
+        return false;
+    };
+    if !flags.contains(COPY_ON_WRITE) {
+        return false;
+    }
+    let Some(copy) = alloc_frame() else {
+        return false;
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (*HHDM_OFFSET + frame.start_address().as_u64()).as_ptr::<u8>(),
+            (*HHDM_OFFSET + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+            PAGE_SIZE,
+        );
+    }
+    let private = (flags - COPY_ON_WRITE - BORROWED_FRAME) | PageTableFlags::WRITABLE;
+    update_mapping(page, mapper, copy, Some(private));
+    // Nothing else can hold a frame the address space owned
+    if !flags.contains(BORROWED_FRAME) {
+        dealloc_frame(frame);
     }
+    true
 }
 
 /// Removes an existing mapping
@@ -328,6 +651,159 @@ mod tests {
         remove_mapped_frame(page, &mut *mapper);
     }
 
+    // Test the range operations, including ranges with holes
+    #[test_case]
+    fn test_range_operations() {
+        let mut mapper = MAPPER.lock();
+
+        let start: Page = Page::containing_address(VirtAddr::new(0x500000000));
+        let pages = Page::range_inclusive(start, start + 7);
+        let frames = map_range(pages, &mut *mapper, PageTableFlags::PRESENT).expect("Map failed");
+        for (page, frame) in pages.zip(frames) {
+            assert_eq!(mapper.translate_page(page).ok(), Some(frame));
+        }
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        assert_eq!(protect_range(pages, &mut *mapper, flags), 8);
+        let pte = unsafe { get_page_table_entry(start + 3, &mapper) }.expect("Getting PTE Failed");
+        assert!(pte.flags().contains(PageTableFlags::WRITABLE));
+
+        // Unmapping a larger range skips the pages that were never mapped
+        let wider = Page::range_inclusive(start - 4, start + 12);
+        assert_eq!(unmap_range(wider, &mut mapper, true), 8);
+        assert!(mapper.translate_page(start).is_err());
+        assert_eq!(unmap_range(wider, &mut mapper, true), 0);
+        // and took the tables it emptied with it
+        assert_eq!(clean_up_range(wider, &mut mapper), 0);
+
+        // Removing a single mapping leaves its tables for clean_up_range
+        create_mapping(start, &mut *mapper, None);
+        remove_mapped_frame(start, &mut *mapper);
+        assert!(clean_up_range(wider, &mut mapper) > 0);
+        assert_eq!(clean_up_range(wider, &mut mapper), 0);
+    }
+
+    /// Returns the frames of the level 3, 2 and 1 tables that map `page`
+    fn table_frames(page: Page, mapper: &OffsetPageTable) -> Vec<PhysFrame> {
+        let hhdm = mapper.phys_offset().as_u64();
+        let mut table = mapper.level_4_table();
+        let mut frames = Vec::new();
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            let frame = PhysFrame::containing_address(table[index].addr());
+            frames.push(frame);
+            table = unsafe { &*((hhdm + frame.start_address().as_u64()) as *const PageTable) };
+        }
+        frames
+    }
+
+    // Unmapping frees the tables it empties, but never the ones linked from
+    // the kernel half of the level 4 table
+    #[test_case]
+    fn test_table_reclamation() {
+        let mut mapper = MAPPER.lock();
+
+        // A level 4 slot of its own, so every table below it is new
+        let user: Page = Page::containing_address(VirtAddr::new(0x7000_0000_0000));
+        assert!(mapper.level_4_table()[user.p4_index()].is_unused());
+        let frame = create_mapping(user, &mut *mapper, None);
+        let tables = table_frames(user, &mapper);
+
+        let released = walk_range(Page::range_inclusive(user, user), &mut mapper, true);
+        assert_eq!(released.frames, [frame]);
+        assert_eq!(released.tables.len(), 3);
+        assert!(tables.iter().all(|table| released.tables.contains(table)));
+        assert!(mapper.level_4_table()[user.p4_index()].is_unused());
+        released.frames.into_iter().for_each(dealloc_frame);
+        released.tables.into_iter().for_each(dealloc_frame);
+
+        // The last level 4 slot is shared by every address space. Nothing
+        // else is mapped this far into the ephemeral window, so the level 2
+        // and 1 tables are new.
+        let kernel: Page = Page::containing_address(VirtAddr::new(0xFFFF_FFF0_0000_0000));
+        let l4_entry = mapper.level_4_table()[kernel.p4_index()].addr();
+        let frame = create_mapping(kernel, &mut *mapper, None);
+        let tables = table_frames(kernel, &mapper);
+        assert_eq!(tables[0].start_address(), l4_entry);
+
+        let released = walk_range(Page::range_inclusive(kernel, kernel), &mut mapper, true);
+        assert_eq!(released.frames, [frame]);
+        assert_eq!(released.tables, [tables[2], tables[1]]);
+        assert_eq!(mapper.level_4_table()[kernel.p4_index()].addr(), l4_entry);
+        released.frames.into_iter().for_each(dealloc_frame);
+        released.tables.into_iter().for_each(dealloc_frame);
+
+        // Through the public API, the count covers only the mapped frames
+        create_mapping(user, &mut *mapper, None);
+        assert_eq!(
+            unmap_range(Page::range_inclusive(user, user), &mut mapper, true),
+            1
+        );
+        assert!(mapper.level_4_table()[user.p4_index()].is_unused());
+    }
+
+    // A copy-on-write page gets its own copy, and the frame it borrowed
+    // outlives the mapping
+    #[test_case]
+    fn test_copy_on_write() {
+        let mut mapper = MAPPER.lock();
+
+        let page: Page = Page::containing_address(VirtAddr::new(0x500000000));
+        let borrowed = alloc_frame_zeroed().expect("Could not allocate frame");
+        let shared = (*HHDM_OFFSET + borrowed.start_address().as_u64()).as_mut_ptr::<u64>();
+        unsafe { write_volatile(shared, 42) };
+        let flags = PageTableFlags::PRESENT | BORROWED_FRAME | COPY_ON_WRITE;
+        map_frame(page, borrowed, &mut *mapper, flags).expect("Mapping failed");
+        assert!(!copy_on_write(page + 1, &mut mapper));
+
+        assert!(copy_on_write(page, &mut mapper));
+        let copy = mapper.translate_page(page).expect("Translation failed");
+        assert_ne!(copy, borrowed);
+        let pte = unsafe { get_page_table_entry(page, &mapper) }.expect("Getting PTE Failed");
+        assert!(pte.flags().contains(PageTableFlags::WRITABLE));
+        assert!(!pte.flags().intersects(BORROWED_FRAME | COPY_ON_WRITE));
+        unsafe {
+            write_volatile(page.start_address().as_mut_ptr::<u64>(), 7);
+            assert_eq!(read_volatile(shared), 42);
+        }
+        assert!(!copy_on_write(page, &mut mapper));
+        remove_mapped_frame(page, &mut *mapper);
+
+        // Unmapping a borrowed page counts it but leaves the frame alone
+        let pages = Page::range_inclusive(page, page);
+        map_frame(page, borrowed, &mut *mapper, flags).expect("Mapping failed");
+        assert_eq!(unmap_range(pages, &mut mapper, true), 1);
+        assert_eq!(unsafe { read_volatile(shared) }, 42);
+        dealloc_frame(borrowed);
+    }
+
+    #[test_case]
+    fn test_update_mapping_flags() {
+        let mut mapper = MAPPER.lock();
+
+        let page: Page = Page::containing_address(VirtAddr::new(0x500000000));
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        create_mapping(page, &mut *mapper, Some(flags));
+
+        // Moving to a new frame keeps the flags the page already had
+        let new_frame = alloc_frame().expect("Could not allocate frame");
+        update_mapping(page, &mut *mapper, new_frame, None);
+        assert_eq!(mapper.translate_page(page).ok(), Some(new_frame));
+        let pte = unsafe { get_page_table_entry(page, &mapper) }.expect("Getting PTE Failed");
+        assert!(pte.flags().contains(flags));
+        assert!(!pte.flags().contains(PageTableFlags::WRITABLE));
+
+        // Keeping the frame but changing the flags must leave the page mapped
+        let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        update_mapping(page, &mut *mapper, new_frame, Some(writable));
+        assert_eq!(mapper.translate_page(page).ok(), Some(new_frame));
+        let pte = unsafe { get_page_table_entry(page, &mapper) }.expect("Getting PTE Failed");
+        assert!(pte.flags().contains(writable));
+        assert!(!pte.flags().contains(PageTableFlags::USER_ACCESSIBLE));
+
+        remove_mapped_frame(page, &mut *mapper);
+    }
+
     // Test that contiguous mappings work correctly. Allocates 8 pages in a row.
     #[test_case]
     fn test_contiguous_mapping() {
@@ -397,7 +873,7 @@ mod tests {
             let new_frame = alloc_frame().expect("Could not find a new frame");
 
             // could say page already mapped, which would be really dumb
-            update_mapping(page, &mut *mapper, new_frame);
+            update_mapping(page, &mut *mapper, new_frame, None);
 
             unsafe {
                 page.start_address()
@@ -0,0 +1,389 @@
+//! Poisoned red zones around heap allocations.
+//!
+//! `RedZoneAllocator` wraps another allocator and surrounds every
+//! allocation with bytes filled with `POISON`. The zones are checked when
+//! the allocation is freed and, with the `heap-redzones` feature, by an
+//! idle priority daemon that sweeps every live allocation. A write past
+//! either end of a buffer is caught close to where it happened instead of
+//! silently corrupting a neighbouring allocation or Talc's metadata.
+//!
+//! Each allocation is laid out as:
+//!
+//! | Region      | Size                                         |
+//! |-------------|----------------------------------------------|
+//! | `Header`    | `size_of::<Header>()`                        |
+//! | Front zone  | At least `RED_ZONE_SIZE`, padded for `align` |
+//! | Allocation  | `layout.size()`                              |
+//! | Back zone   | `RED_ZONE_SIZE`                              |
+//!
+//! Headers link every live allocation into a list, so keeping track of
+//! them needs no heap. Each header records the return addresses of the
+//! frames that made the allocation, which `addr2line` maps back to the
+//! call site. They are found by following frame pointers, so the kernel
+//! should be built with `-C force-frame-pointers=yes` for them to be
+//! useful. Without frame pointers the walk stops at the first frame that
+//! does not look like one, and the call site is left partly empty.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::asm,
+    mem::{align_of, size_of},
+    ptr,
+};
+use spin::Mutex;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::memory::HHDM_OFFSET;
+
+/// Byte the red zones are filled with
+pub const POISON: u8 = 0xFD;
+
+/// Bytes of poison at each end of an allocation
+pub const RED_ZONE_SIZE: usize = 32;
+
+/// Return addresses recorded for each allocation
+pub const CALL_SITE_DEPTH: usize = 6;
+
+/// Distinguishes headers from whatever else is on the heap
+const HEADER_MAGIC: u64 = 0x7265_647A_6F6E_6521;
+
+/// Frame pointers further apart than this are taken to be garbage
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+/// Bookkeeping in front of each allocation
+#[repr(C)]
+struct Header {
+    /// `HEADER_MAGIC` xor the header's address, so a header copied
+    /// elsewhere does not pass for a live one
+    canary: u64,
+    next: *mut Header,
+    prev: *mut Header,
+    /// The layout the caller asked for
+    size: usize,
+    align: usize,
+    call_site: [u64; CALL_SITE_DEPTH],
+}
+
+/// Which part of an allocation was found overwritten
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// The header, by an underrun longer than the front zone
+    Header,
+    /// The front zone, at this many bytes before the allocation
+    Underrun(usize),
+    /// The back zone, at this many bytes past the end of the allocation
+    Overrun(usize),
+}
+
+/// A corrupted allocation, with where it was made
+#[derive(Debug, Clone, Copy)]
+pub struct RedZoneError {
+    pub corruption: Corruption,
+    pub ptr: *mut u8,
+    pub size: usize,
+    pub call_site: [u64; CALL_SITE_DEPTH],
+}
+
+/// Live allocations, linked through their headers
+struct LiveList {
+    head: *mut Header,
+    count: usize,
+}
+
+// Headers are only touched with the list locked
+unsafe impl Send for LiveList {}
+
+/// An allocator that puts red zones around every allocation of `A`
+pub struct RedZoneAllocator<A> {
+    inner: A,
+    live: Mutex<LiveList>,
+}
+
+impl<A> RedZoneAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        RedZoneAllocator {
+            inner,
+            live: Mutex::new(LiveList {
+                head: ptr::null_mut(),
+                count: 0,
+            }),
+        }
+    }
+
+    /// Number of allocations not yet freed
+    pub fn live_allocations(&self) -> usize {
+        self.live.lock().count
+    }
+
+    /// Checks the red zones of every live allocation
+    ///
+    /// # Returns
+    /// The first corrupted allocation found, if any
+    pub fn check_all(&self) -> Result<usize, RedZoneError> {
+        let live = self.live.lock();
+        let mut header = live.head;
+        while !header.is_null() {
+            unsafe {
+                check(header)?;
+                header = (*header).next;
+            }
+        }
+        Ok(live.count)
+    }
+}
+
+/// Bytes from the start of the block to the allocation, leaving room for
+/// the header and a front zone at least `RED_ZONE_SIZE` long
+fn front_size(align: usize) -> usize {
+    (size_of::<Header>() + RED_ZONE_SIZE).next_multiple_of(align)
+}
+
+/// The layout of the whole block an allocation of `layout` takes up
+fn block_layout(layout: Layout) -> Option<Layout> {
+    let align = layout.align().max(align_of::<Header>());
+    let size = front_size(align)
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE_SIZE)?;
+    Layout::from_size_align(size, align).ok()
+}
+
+fn header_of(ptr: *mut u8, align: usize) -> *mut Header {
+    unsafe { ptr.sub(front_size(align)) as *mut Header }
+}
+
+/// Returns the addresses the current call chain will return to, skipping
+/// the allocator's own frames as far as `CALL_SITE_DEPTH` allows
+fn call_site() -> [u64; CALL_SITE_DEPTH] {
+    let mut addresses = [0; CALL_SITE_DEPTH];
+    let mut frame: u64;
+    let stack: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack));
+        asm!("mov {}, rsp", out(reg) stack, options(nomem, nostack));
+    }
+
+    // Without frame pointers rbp may hold anything, so only follow it
+    // while it points a little way up the current stack, into memory that
+    // is really there
+    let mut floor = stack;
+    for address in addresses.iter_mut() {
+        if frame % 8 != 0 || frame < floor || frame - floor > MAX_FRAME_SIZE {
+            break;
+        }
+        if !is_mapped(VirtAddr::new_truncate(frame))
+            || !is_mapped(VirtAddr::new_truncate(frame + 15))
+        {
+            break;
+        }
+        unsafe {
+            *address = *((frame + 8) as *const u64);
+            floor = frame + 16;
+            frame = *(frame as *const u64);
+        }
+    }
+    addresses
+}
+
+/// Walks the active page tables to see whether `addr` can be read. The
+/// mapper is not used since its lock may be held by whoever is allocating.
+fn is_mapped(addr: VirtAddr) -> bool {
+    let (mut frame, _) = Cr3::read();
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, index) in indices.into_iter().enumerate() {
+        let table = (*HHDM_OFFSET + frame.start_address().as_u64()).as_ptr::<PageTable>();
+        let entry = unsafe { &(*table)[index] };
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        // A 1 GiB or 2 MiB page covers the rest of the address
+        if level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        frame = match entry.frame() {
+            Ok(next) => next,
+            Err(_) => return false,
+        };
+    }
+    true
+}
+
+/// Checks the header and both red zones of an allocation
+///
+/// # Safety
+/// `header` must be the header of a live allocation
+unsafe fn check(header: *mut Header) -> Result<(), RedZoneError> {
+    let h = &*header;
+    let front = front_size(h.align.max(align_of::<Header>()));
+    let ptr = (header as *mut u8).add(front);
+    let error = |corruption| RedZoneError {
+        corruption,
+        ptr,
+        size: h.size,
+        call_site: h.call_site,
+    };
+
+    if h.canary != HEADER_MAGIC ^ header as u64 {
+        return Err(RedZoneError {
+            corruption: Corruption::Header,
+            ptr,
+            size: 0,
+            call_site: [0; CALL_SITE_DEPTH],
+        });
+    }
+    // Closest to the allocation first, since that is where overruns start
+    let front_zone = front - size_of::<Header>();
+    for offset in 1..=front_zone {
+        if *ptr.sub(offset) != POISON {
+            return Err(error(Corruption::Underrun(offset)));
+        }
+    }
+    for offset in 0..RED_ZONE_SIZE {
+        if *ptr.add(h.size + offset) != POISON {
+            return Err(error(Corruption::Overrun(offset)));
+        }
+    }
+    Ok(())
+}
+
+/// Panics with everything known about a corrupted allocation
+pub fn report(error: RedZoneError) -> ! {
+    panic!(
+        "Heap red zone corrupted: {:?} in the {} byte allocation at {:p}, allocated from {:#x?}",
+        error.corruption, error.size, error.ptr, error.call_site
+    );
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for RedZoneAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(block) = block_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let base = self.inner.alloc(block);
+        if base.is_null() {
+            return base;
+        }
+
+        let front = front_size(block.align());
+        let header = base as *mut Header;
+        let ptr = base.add(front);
+        ptr::write_bytes(
+            base.add(size_of::<Header>()),
+            POISON,
+            front - size_of::<Header>(),
+        );
+        ptr::write_bytes(ptr.add(layout.size()), POISON, RED_ZONE_SIZE);
+
+        let mut live = self.live.lock();
+        header.write(Header {
+            canary: HEADER_MAGIC ^ header as u64,
+            next: live.head,
+            prev: ptr::null_mut(),
+            size: layout.size(),
+            align: layout.align(),
+            call_site: call_site(),
+        });
+        if !live.head.is_null() {
+            (*live.head).prev = header;
+        }
+        live.head = header;
+        live.count += 1;
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let block = block_layout(layout).expect("Freed a layout that could not be allocated");
+        let header = header_of(ptr, block.align());
+
+        let mut live = self.live.lock();
+        if let Err(error) = check(header) {
+            drop(live);
+            report(error);
+        }
+        let h = &*header;
+        if h.size != layout.size() {
+            drop(live);
+            panic!(
+                "Allocation of {} bytes at {:p} freed as {} bytes",
+                h.size,
+                ptr,
+                layout.size()
+            );
+        }
+        if h.prev.is_null() {
+            live.head = h.next;
+        } else {
+            (*h.prev).next = h.next;
+        }
+        if !h.next.is_null() {
+            (*h.next).prev = h.prev;
+        }
+        live.count -= 1;
+        // A double free now fails the canary check
+        (*header).canary = 0;
+        drop(live);
+
+        self.inner.dealloc(header as *mut u8, block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands blocks out from the kernel heap
+    struct Heap;
+
+    unsafe impl GlobalAlloc for Heap {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            alloc::alloc::alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            alloc::alloc::dealloc(ptr, layout)
+        }
+    }
+
+    #[test_case]
+    fn test_red_zones() {
+        let allocator = RedZoneAllocator::new(Heap);
+        let small = Layout::from_size_align(13, 1).unwrap();
+        let aligned = Layout::from_size_align(40, 64).unwrap();
+
+        unsafe {
+            let a = allocator.alloc(small);
+            let b = allocator.alloc(aligned);
+            assert_eq!(b as usize % 64, 0);
+            ptr::write_bytes(a, 0xFF, small.size());
+            ptr::write_bytes(b, 0xFF, aligned.size());
+            assert_eq!(allocator.check_all().unwrap(), 2);
+
+            // One byte too far either way is caught
+            *a.add(small.size()) = 0;
+            let error = allocator.check_all().unwrap_err();
+            assert_eq!(error.corruption, Corruption::Overrun(0));
+            assert_eq!((error.ptr, error.size), (a, small.size()));
+            *a.add(small.size()) = POISON;
+
+            *b.sub(3) = 0;
+            assert_eq!(
+                allocator.check_all().unwrap_err().corruption,
+                Corruption::Underrun(3)
+            );
+            *b.sub(3) = POISON;
+
+            allocator.dealloc(a, small);
+            allocator.dealloc(b, aligned);
+        }
+        assert_eq!(allocator.live_allocations(), 0);
+        assert_eq!(allocator.check_all().unwrap(), 0);
+    }
+}
@@ -0,0 +1,176 @@
+//! The physical memory map.
+//!
+//! Wraps the memory map Limine hands over at boot in typed regions, so the
+//! frame allocators, drivers and diagnostics all read it the same way. The
+//! map lives in bootloader reclaimable memory, which the kernel never
+//! reclaims, so it stays valid after boot and reading it needs no heap.
+
+use alloc::{format, string::String};
+use limine::{memory_map::EntryType, request::MemoryMapRequest};
+use x86_64::{structures::paging::PhysFrame, PhysAddr};
+
+use crate::constants::memory::FRAME_SIZE;
+
+#[used]
+#[link_section = ".requests"]
+static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
+
+/// What a region of physical memory holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Free RAM the frame allocators hand out
+    Usable,
+    /// Firmware or device memory the kernel must not touch
+    Reserved,
+    /// ACPI tables, free once they have been parsed
+    AcpiReclaimable,
+    /// Memory the firmware keeps for itself across sleep states
+    AcpiNvs,
+    /// RAM that failed the firmware's tests
+    BadMemory,
+    /// Bootloader structures, including this map
+    BootloaderReclaimable,
+    /// The kernel image and boot modules
+    Kernel,
+    /// The boot framebuffer
+    Framebuffer,
+}
+
+impl RegionKind {
+    fn from_entry_type(entry_type: EntryType) -> Self {
+        match entry_type {
+            EntryType::USABLE => RegionKind::Usable,
+            EntryType::ACPI_RECLAIMABLE => RegionKind::AcpiReclaimable,
+            EntryType::ACPI_NVS => RegionKind::AcpiNvs,
+            EntryType::BAD_MEMORY => RegionKind::BadMemory,
+            EntryType::BOOTLOADER_RECLAIMABLE => RegionKind::BootloaderReclaimable,
+            EntryType::KERNEL_AND_MODULES => RegionKind::Kernel,
+            EntryType::FRAMEBUFFER => RegionKind::Framebuffer,
+            _ => RegionKind::Reserved,
+        }
+    }
+
+    /// Returns true if the region is backed by RAM rather than a device or
+    /// a hole
+    pub fn is_ram(&self) -> bool {
+        !matches!(self, RegionKind::Reserved | RegionKind::Framebuffer)
+    }
+
+    /// Name used in /proc/iomem
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegionKind::Usable => "System RAM",
+            RegionKind::Reserved => "Reserved",
+            RegionKind::AcpiReclaimable => "ACPI Tables",
+            RegionKind::AcpiNvs => "ACPI Non-volatile Storage",
+            RegionKind::BadMemory => "Bad RAM",
+            RegionKind::BootloaderReclaimable => "Bootloader",
+            RegionKind::Kernel => "Kernel",
+            RegionKind::Framebuffer => "Framebuffer",
+        }
+    }
+}
+
+/// A contiguous range of physical memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub length: u64,
+    pub kind: RegionKind,
+}
+
+impl MemoryRegion {
+    /// First address past the region
+    pub fn end(&self) -> u64 {
+        self.start + self.length
+    }
+
+    /// Returns true if the region shares any byte with `[start, start + length)`
+    pub fn overlaps(&self, start: u64, length: u64) -> bool {
+        length > 0 && start < self.end() && self.start < start.saturating_add(length)
+    }
+
+    /// Returns the frames that lie entirely inside the region
+    pub fn frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let first = self.start.div_ceil(FRAME_SIZE as u64);
+        let end = self.end() / FRAME_SIZE as u64;
+        (first..end)
+            .map(|index| PhysFrame::containing_address(PhysAddr::new(index * FRAME_SIZE as u64)))
+    }
+}
+
+/// Returns every region of the memory map, in ascending address order
+///
+/// # Panics
+/// Panics if the bootloader did not provide a memory map
+pub fn regions() -> impl Iterator<Item = MemoryRegion> {
+    MEMORY_MAP_REQUEST
+        .get_response()
+        .expect("Memory map request failed")
+        .entries()
+        .iter()
+        .map(|entry| MemoryRegion {
+            start: entry.base,
+            length: entry.length,
+            kind: RegionKind::from_entry_type(entry.entry_type),
+        })
+}
+
+/// Returns the regions the frame allocators may hand out
+pub fn usable_regions() -> impl Iterator<Item = MemoryRegion> {
+    regions().filter(|region| region.kind == RegionKind::Usable)
+}
+
+/// Returns every frame the frame allocators may hand out
+pub fn usable_frames() -> impl Iterator<Item = PhysFrame> {
+    usable_regions().flat_map(|region| region.frames())
+}
+
+/// First address past the highest usable region
+pub fn top_of_usable_memory() -> u64 {
+    usable_regions()
+        .map(|region| region.end())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns true if any of `[start, start + length)` is RAM. Drivers check
+/// this before mapping a device BAR, since a BAR placed over RAM means the
+/// device or firmware is misconfigured.
+pub fn overlaps_ram(start: u64, length: u64) -> bool {
+    regions().any(|region| region.kind.is_ram() && region.overlaps(start, length))
+}
+
+/// Formats the memory map like /proc/iomem, one region per line
+pub fn iomem() -> String {
+    regions()
+        .filter(|region| region.length > 0)
+        .map(|region| {
+            format!(
+                "{:08x}-{:08x} : {}\n",
+                region.start,
+                region.end() - 1,
+                region.kind.name()
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_memory_map() {
+        let mut previous_end = 0;
+        for region in regions() {
+            assert!(region.start >= previous_end, "Regions overlap");
+            previous_end = region.end();
+        }
+
+        let usable = usable_regions().next().expect("No usable memory");
+        assert!(overlaps_ram(usable.start, 1));
+        assert!(!usable.overlaps(usable.end(), 1));
+        assert!(top_of_usable_memory() >= usable.end());
+    }
+}
@@ -1,34 +1,224 @@
 //! Translation Lookaside Buffer Shootdowns
 //!
-//! - Exposes a function to perform TLB Shootdowns
+//! - Exposes functions to perform TLB Shootdowns of a page or a range of pages
 
 use crate::{
-    constants::{idt::TLB_SHOOTDOWN_VECTOR, MAX_CORES},
+    constants::{
+        idt::TLB_SHOOTDOWN_VECTOR,
+        memory::{PAGE_SIZE, TLB_FULL_FLUSH_PAGES},
+        MAX_CORES,
+    },
     interrupts::x2apic::{current_core_id, send_ipi, TLB_SHOOTDOWN_ADDR},
 };
-use core::arch::asm;
-use x86_64::VirtAddr;
+use x86_64::{instructions::tlb, VirtAddr};
 
 /// Sends an inter-process interrupt to all other cores to clear TLB entry with a specific VA
 ///
 /// # Arguments:
 /// * target_vaddr: VA that has to be flushed in all TLBs
 pub fn tlb_shootdown(target_vaddr: VirtAddr) {
+    tlb_shootdown_range(target_vaddr, 1);
+}
+
+/// Clears the TLB entries of a range of pages on every core with a single
+/// inter-process interrupt per core
+///
+/// # Arguments:
+/// * start: VA of the first page to flush
+/// * pages: number of pages to flush
+pub fn tlb_shootdown_range(start: VirtAddr, pages: u64) {
+    if pages == 0 {
+        return;
+    }
     let current_core = current_core_id();
-    let vaddr = target_vaddr.as_u64();
 
     {
         // Acquire the lock and update all cores except the current one.
         let mut addresses = TLB_SHOOTDOWN_ADDR.lock();
         for core in 0..MAX_CORES {
             if core != current_core {
-                addresses[core] = vaddr;
+                // A core that has not handled the previous shootdown yet only
+                // takes one interrupt for both, so it must flush both ranges
+                addresses[core] = merge_pending(addresses[core], (start.as_u64(), pages));
                 send_ipi(core as u32, TLB_SHOOTDOWN_VECTOR);
             }
         }
     }
 
-    unsafe {
-        asm!("invlpg [{}]", in(reg) vaddr, options(nostack, preserves_flags));
+    flush_local(start, pages);
+}
+
+/// Widens a core's pending shootdown to also cover another range
+///
+/// # Arguments:
+/// * pending: the (start, pages) the core has yet to flush, 0 pages if none
+/// * range: the (start, pages) to add
+///
+/// # Returns
+/// The smallest range covering both
+fn merge_pending(pending: (u64, u64), range: (u64, u64)) -> (u64, u64) {
+    if pending.1 == 0 {
+        return range;
+    }
+    let end =
+        |(start, pages): (u64, u64)| start.saturating_add(pages.saturating_mul(PAGE_SIZE as u64));
+    let start = pending.0.min(range.0);
+    let end = end(pending).max(end(range));
+    (start, (end - start).div_ceil(PAGE_SIZE as u64))
+}
+
+/// Clears the TLB entries of a range of pages on the current core, flushing
+/// the whole TLB if the range is large
+///
+/// # Arguments:
+/// * start: VA of the first page to flush
+/// * pages: number of pages to flush
+pub fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > TLB_FULL_FLUSH_PAGES {
+        tlb::flush_all();
+        return;
+    }
+    for page in 0..pages {
+        tlb::flush(start + page * PAGE_SIZE as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::{
+        arch::without_interrupts,
+        events::schedule_kernel,
+        memory::{
+            frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+            paging::{create_mapping, remove_mapped_frame, update_mapping},
+            MAPPER,
+        },
+    };
+    use x86_64::{
+        instructions::interrupts,
+        structures::paging::{Mapper, Page},
+    };
+
+    const AP: u32 = 1;
+    const PRIORITY: usize = 3;
+    const ROUNDS: u64 = 64;
+
+    // Handshake of the unmap storm, each holding the last round done
+    static CACHED: AtomicU64 = AtomicU64::new(0);
+    static REMAPPED: AtomicU64 = AtomicU64::new(0);
+    static CHECKED: AtomicU64 = AtomicU64::new(0);
+    static SEEN: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+    fn read(page: Page) -> u64 {
+        unsafe { page.start_address().as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(page: Page, value: u64) {
+        unsafe {
+            page.start_address()
+                .as_mut_ptr::<u64>()
+                .write_volatile(value)
+        }
+    }
+
+    fn expected(round: u64, window: usize) -> u64 {
+        0x1000 + round * 2 + window as u64
+    }
+
+    /// Caches both windows with interrupts off, so every shootdown of a
+    /// round is still pending when the core takes the interrupt, then reads
+    /// them once the shootdown has been handled
+    async fn reader(windows: [Page; 2]) {
+        let core = current_core_id();
+        for round in 1..=ROUNDS {
+            interrupts::disable();
+            windows.iter().for_each(|&page| {
+                read(page);
+            });
+            CACHED.store(round, Ordering::SeqCst);
+            while REMAPPED.load(Ordering::SeqCst) != round {
+                core::hint::spin_loop();
+            }
+            interrupts::enable();
+
+            while without_interrupts(|| TLB_SHOOTDOWN_ADDR.lock()[core].1 != 0) {
+                core::hint::spin_loop();
+            }
+            for (seen, &page) in SEEN.iter().zip(windows.iter()) {
+                seen.store(read(page), Ordering::SeqCst);
+            }
+            CHECKED.store(round, Ordering::SeqCst);
+        }
+    }
+
+    #[test_case]
+    fn test_pending_ranges_merge() {
+        let page = PAGE_SIZE as u64;
+        assert_eq!(merge_pending((0, 0), (page, 2)), (page, 2));
+        assert_eq!(merge_pending((page, 1), (page, 1)), (page, 1));
+        assert_eq!(merge_pending((page * 4, 1), (page, 2)), (page, 4));
+        assert_eq!(merge_pending((page, 8), (page * 2, 1)), (page, 8));
+    }
+
+    // One core caches two windows while the other unmaps and remaps both,
+    // sending three shootdowns before the first can be handled. The
+    // reader must never see a frame from an earlier round.
+    #[test_case]
+    fn test_shootdown_unmap_storm() {
+        let windows = [
+            Page::containing_address(VirtAddr::new(0x500200000)),
+            Page::containing_address(VirtAddr::new(0x500204000)),
+        ];
+        {
+            let mut mapper = MAPPER.lock();
+            for &page in windows.iter() {
+                create_mapping(page, &mut *mapper, None);
+            }
+        }
+        CACHED.store(0, Ordering::SeqCst);
+        REMAPPED.store(0, Ordering::SeqCst);
+        CHECKED.store(0, Ordering::SeqCst);
+
+        schedule_kernel(AP, async move { reader(windows).await }, PRIORITY);
+
+        for round in 1..=ROUNDS {
+            while CACHED.load(Ordering::SeqCst) != round {
+                core::hint::spin_loop();
+            }
+            {
+                let mut mapper = MAPPER.lock();
+                // The first window is unmapped and mapped again...
+                remove_mapped_frame(windows[0], &mut *mapper);
+                create_mapping(windows[0], &mut *mapper, None);
+                write(windows[0], expected(round, 0));
+
+                // ...and the second moved to a new frame
+                let old = mapper.translate_page(windows[1]).unwrap();
+                let frame = alloc_frame_zeroed().expect("no more frames");
+                update_mapping(windows[1], &mut *mapper, frame, None);
+                dealloc_frame(old);
+                write(windows[1], expected(round, 1));
+            }
+            REMAPPED.store(round, Ordering::SeqCst);
+
+            while CHECKED.load(Ordering::SeqCst) != round {
+                core::hint::spin_loop();
+            }
+            for (window, seen) in SEEN.iter().enumerate() {
+                assert_eq!(seen.load(Ordering::SeqCst), expected(round, window));
+            }
+        }
+
+        // Every shootdown has been handled
+        let addresses = without_interrupts(|| *TLB_SHOOTDOWN_ADDR.lock());
+        assert_eq!(addresses[AP as usize], (0, 0));
+
+        let mut mapper = MAPPER.lock();
+        for &page in windows.iter() {
+            remove_mapped_frame(page, &mut *mapper);
+        }
     }
 }
@@ -0,0 +1,80 @@
+//! Areas of a process' address space that are mapped on demand.
+//!
+//! A `Vma` reserves a range of user addresses without backing it. Pages in
+//! the range get a zeroed frame the first time they are touched, from the
+//! page fault handler, and touching anything outside every area is still a
+//! fault. Process stacks are one such area: the loader maps their top page
+//! and the rest grows down as it is used, up to the size the binary asked
+//! for. The heap is another, from the end of the program's segments to the
+//! program break that brk moves. File mappings, in `mmap`, are the last.
+
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::constants::{memory::PAGE_SIZE, processes::STACK_START};
+
+/// A range of user addresses backed on first touch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    /// First address in the area, page aligned
+    pub start: VirtAddr,
+    /// First address past the area, page aligned
+    pub end: VirtAddr,
+    /// Flags the pages are mapped with
+    pub flags: PageTableFlags,
+}
+
+/// Flags of the private data areas, which are never executable
+const DATA_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+impl Vma {
+    /// The stack of a process, `size` bytes ending at its top
+    pub fn stack(size: usize) -> Self {
+        Vma {
+            start: VirtAddr::new(STACK_START),
+            end: VirtAddr::new(STACK_START + size as u64),
+            flags: DATA_FLAGS,
+        }
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// The last page of the area, which a stack starts out using
+    pub fn top_page(&self) -> Page {
+        Page::containing_address(self.end - 1u64)
+    }
+}
+
+/// The heap of a process, which starts out empty and grows and shrinks as
+/// the program break moves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heap {
+    /// First address of the heap, page aligned
+    pub start: VirtAddr,
+    /// The program break, the first address past the heap
+    pub brk: VirtAddr,
+}
+
+impl Heap {
+    /// An empty heap at `start`, which must be page aligned
+    pub fn new(start: VirtAddr) -> Self {
+        Heap { start, brk: start }
+    }
+
+    /// The pages backing the heap, up to the end of the page holding the
+    /// last byte before the break
+    pub fn area(&self) -> Vma {
+        Vma {
+            start: self.start,
+            end: self.brk.align_up(PAGE_SIZE as u64),
+            flags: DATA_FLAGS,
+        }
+    }
+}
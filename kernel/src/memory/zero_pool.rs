@@ -0,0 +1,79 @@
+//! Pool of pre-zeroed frames.
+//!
+//! Every frame that ends up mapped into user space must be zeroed, or it
+//! leaks whatever the previous owner left in it. Frames are zeroed when they
+//! are allocated rather than when they are freed, since many frames are
+//! overwritten by their new owner anyway. To keep zeroing off the
+//! allocation path, an idle priority daemon keeps a small pool of frames
+//! zeroed ahead of time. `alloc_frame_zeroed` takes from the pool and only
+//! zeroes inline when the pool is empty.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::{
+    constants::memory::{FRAME_SIZE, ZERO_POOL_REFILL_INTERVAL_NS, ZERO_POOL_TARGET},
+    events::{idle_yield, sleep_until},
+    memory::{frame_allocator::alloc_frame, HHDM_OFFSET},
+    time,
+};
+
+static ZEROED_FRAMES: Mutex<Vec<PhysFrame>> = Mutex::new(Vec::new());
+
+/// Fills a frame with zeroes through the HHDM
+pub fn zero_frame(frame: PhysFrame) {
+    let virt = *HHDM_OFFSET + frame.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, FRAME_SIZE) };
+}
+
+/// Takes a zeroed frame from the pool, if it has one
+pub fn take() -> Option<PhysFrame> {
+    ZEROED_FRAMES.lock().pop()
+}
+
+/// Number of zeroed frames waiting in the pool
+pub fn pooled() -> usize {
+    ZEROED_FRAMES.lock().len()
+}
+
+/// Keeps the pool topped up. Runs forever, should be scheduled with
+/// `schedule_idle`.
+pub async fn zeroing_daemon() {
+    ZEROED_FRAMES.lock().reserve(ZERO_POOL_TARGET);
+    loop {
+        while pooled() < ZERO_POOL_TARGET {
+            let Some(frame) = alloc_frame() else {
+                break;
+            };
+            zero_frame(frame);
+            ZEROED_FRAMES.lock().push(frame);
+            idle_yield().await;
+        }
+        sleep_until(time::monotonic_ns() + ZERO_POOL_REFILL_INTERVAL_NS).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::frame_allocator::{alloc_frame_zeroed, dealloc_frame};
+
+    #[test_case]
+    fn test_reused_frame_is_zeroed() {
+        let frame = alloc_frame().expect("Out of frames");
+        let virt = *HHDM_OFFSET + frame.start_address().as_u64();
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0xAA, FRAME_SIZE) };
+        dealloc_frame(frame);
+
+        // Drain the pool so the dirty frame can come back inline
+        let pooled: Vec<_> = core::iter::from_fn(take).collect();
+        let zeroed = alloc_frame_zeroed().expect("Out of frames");
+        let virt = *HHDM_OFFSET + zeroed.start_address().as_u64();
+        let bytes = unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), FRAME_SIZE) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+
+        dealloc_frame(zeroed);
+        ZEROED_FRAMES.lock().extend(pooled);
+    }
+}
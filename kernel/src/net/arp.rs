@@ -0,0 +1,141 @@
+//! ARP for IPv4 over Ethernet, and the cache of what it has learned.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::net::Ipv4Addr;
+
+use super::ethernet::{MacAddress, ETHERTYPE_IPV4};
+use crate::constants::net::MAX_NEIGHBORS;
+
+/// Length of an ARP packet for IPv4 over Ethernet
+pub const PACKET_SIZE: usize = 28;
+
+const HARDWARE_ETHERNET: u16 = 1;
+
+pub const OP_REQUEST: u16 = 1;
+pub const OP_REPLY: u16 = 2;
+
+/// An ARP request or reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Addr,
+    /// Zero in requests, which ask for it
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Asks who has `target_ip`
+    pub fn request(sender_mac: MacAddress, sender_ip: Ipv4Addr, target_ip: Ipv4Addr) -> Self {
+        ArpPacket {
+            operation: OP_REQUEST,
+            sender_mac,
+            sender_ip,
+            target_mac: MacAddress::ZERO,
+            target_ip,
+        }
+    }
+
+    /// The reply to this request from the station at `mac`
+    pub fn reply(&self, mac: MacAddress) -> Self {
+        ArpPacket {
+            operation: OP_REPLY,
+            sender_mac: mac,
+            sender_ip: self.target_ip,
+            target_mac: self.sender_mac,
+            target_ip: self.sender_ip,
+        }
+    }
+
+    /// Reads a packet from a frame's payload
+    ///
+    /// # Returns
+    /// None unless it is a well formed packet for IPv4 over Ethernet
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..PACKET_SIZE)?;
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let mac = |offset: usize| {
+            let mut address = [0; 6];
+            address.copy_from_slice(&bytes[offset..offset + 6]);
+            MacAddress(address)
+        };
+        let ip = |offset: usize| {
+            Ipv4Addr::new(
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            )
+        };
+        if u16_at(0) != HARDWARE_ETHERNET || u16_at(2) != ETHERTYPE_IPV4 {
+            return None;
+        }
+        if bytes[4] != 6 || bytes[5] != 4 {
+            return None;
+        }
+        Some(ArpPacket {
+            operation: u16_at(6),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PACKET_SIZE);
+        bytes.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        bytes.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        bytes.extend_from_slice(&[6, 4]);
+        bytes.extend_from_slice(&self.operation.to_be_bytes());
+        bytes.extend_from_slice(&self.sender_mac.0);
+        bytes.extend_from_slice(&self.sender_ip.octets());
+        bytes.extend_from_slice(&self.target_mac.0);
+        bytes.extend_from_slice(&self.target_ip.octets());
+        bytes
+    }
+}
+
+/// Hardware addresses of neighbors, learned from the ARP packets they send.
+/// Entries never expire, but once the cache is full the oldest is dropped
+/// for a new neighbor.
+#[derive(Debug, Default)]
+pub struct ArpCache {
+    entries: BTreeMap<Ipv4Addr, MacAddress>,
+    /// Addresses in the order they were learned
+    order: Vec<Ipv4Addr>,
+}
+
+impl ArpCache {
+    pub const fn new() -> Self {
+        ArpCache {
+            entries: BTreeMap::new(),
+            order: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, ip: Ipv4Addr) -> Option<MacAddress> {
+        self.entries.get(&ip).copied()
+    }
+
+    /// Remembers where `ip` is, replacing what was known
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddress) {
+        if self.entries.insert(ip, mac).is_some() {
+            return;
+        }
+        self.order.push(ip);
+        if self.order.len() > MAX_NEIGHBORS {
+            let oldest = self.order.remove(0);
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,92 @@
+//! Ethernet II framing.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Bytes of destination, source and EtherType before the payload
+pub const HEADER_SIZE: usize = 14;
+/// Shortest frame on the wire, without its checksum. Shorter frames are
+/// padded.
+pub const MIN_FRAME: usize = 60;
+/// Longest frame, without its checksum, carrying a 1500 byte payload
+pub const MAX_FRAME: usize = 1514;
+pub const MTU: usize = MAX_FRAME - HEADER_SIZE;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// A hardware address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+    pub const ZERO: MacAddress = MacAddress([0; 6]);
+
+    /// Whether frames sent to this address go to a group of stations
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// A received frame, borrowing its payload
+#[derive(Debug)]
+pub struct Frame<'a> {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+    /// Everything after the header, including any padding
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Splits a frame into its header fields and payload
+    ///
+    /// # Returns
+    /// None if the frame is too short to have a header
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        let mac = |offset: usize| {
+            let mut address = [0; 6];
+            address.copy_from_slice(&bytes[offset..offset + 6]);
+            MacAddress(address)
+        };
+        Some(Frame {
+            destination: mac(0),
+            source: mac(6),
+            ethertype: u16::from_be_bytes([bytes[12], bytes[13]]),
+            payload: &bytes[HEADER_SIZE..],
+        })
+    }
+}
+
+/// Builds a frame around `payload`, which must fit in the MTU, padding it
+/// to the shortest frame
+pub fn build(
+    destination: MacAddress,
+    source: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    debug_assert!(payload.len() <= MTU, "Payload larger than the MTU");
+    let mut frame = Vec::with_capacity(MIN_FRAME.max(HEADER_SIZE + payload.len()));
+    frame.extend_from_slice(&destination.0);
+    frame.extend_from_slice(&source.0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.resize(frame.len().max(MIN_FRAME), 0);
+    frame
+}
@@ -0,0 +1,129 @@
+//! IPv4 headers.
+//!
+//! Packets are neither fragmented nor reassembled: those sent must fit the
+//! MTU, and fragments received are dropped. Options are skipped over when
+//! received and never sent.
+
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+
+/// Length of a header without options
+pub const HEADER_SIZE: usize = 20;
+
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// Hops a packet sent may take
+const DEFAULT_TTL: u8 = 64;
+
+/// The "don't fragment" flag, with the fragment offset below it
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+/// The fields of a header this stack looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    /// Identifies the packet among those from the same source
+    pub id: u16,
+    pub ttl: u8,
+}
+
+impl Ipv4Header {
+    pub fn new(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, id: u16) -> Self {
+        Ipv4Header {
+            source,
+            destination,
+            protocol,
+            id,
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    /// Reads the header of a packet
+    ///
+    /// # Returns
+    /// The header and the payload, or None if the packet is malformed, is
+    /// a fragment, or fails its checksum
+    pub fn parse(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let header = bytes.get(..HEADER_SIZE)?;
+        if header[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (header[0] & 0xF) as usize * 4;
+        let total_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        if header_len < HEADER_SIZE || total_len < header_len || total_len > bytes.len() {
+            return None;
+        }
+        if checksum(&bytes[..header_len]) != 0 {
+            return None;
+        }
+        let flags = u16::from_be_bytes([header[6], header[7]]);
+        if flags & FLAG_MORE_FRAGMENTS != 0 || flags & FRAGMENT_OFFSET_MASK != 0 {
+            return None;
+        }
+        let address = |offset: usize| {
+            Ipv4Addr::new(
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            )
+        };
+        let parsed = Ipv4Header {
+            source: address(12),
+            destination: address(16),
+            protocol: header[9],
+            id: u16::from_be_bytes([header[4], header[5]]),
+            ttl: header[8],
+        };
+        // Link layers pad short packets, which the total length leaves out
+        Some((parsed, &bytes[header_len..total_len]))
+    }
+
+    /// Builds a packet of this header and `payload`
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let total_len = (HEADER_SIZE + payload.len()) as u16;
+        let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+        packet.extend_from_slice(&[self.ttl, self.protocol, 0, 0]);
+        packet.extend_from_slice(&self.source.octets());
+        packet.extend_from_slice(&self.destination.octets());
+        let sum = checksum(&packet);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+/// Adds up `bytes` as big-endian 16 bit words, with end-around carry, the
+/// way every Internet checksum starts
+pub fn sum_words(bytes: &[u8], mut sum: u32) -> u32 {
+    let mut words = bytes.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Folds a sum from `sum_words` into the checksum field's value. Summing
+/// data that includes its correct checksum gives zero.
+pub fn fold(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The Internet checksum of `bytes`
+pub fn checksum(bytes: &[u8]) -> u16 {
+    fold(sum_words(bytes, 0))
+}
@@ -0,0 +1,516 @@
+//! A minimal IPv4 network stack.
+//!
+//! One `Interface` sits on the network device and carries UDP over IPv4
+//! over Ethernet. It is registered with the device manager behind the
+//! device, which owns it like any driver state:
+//! - `ethernet` frames packets for the link
+//! - `arp` finds the hardware addresses of neighbors
+//! - `ipv4` builds and checks packet headers
+//! - `udp` carries datagrams between sockets
+//!
+//! Received frames are handled when a socket looks for datagrams, so
+//! nothing runs in the background. The device's receive interrupt only
+//! wakes the sockets waiting, and they poll the device themselves, which
+//! also keeps the stack working without the interrupt.
+//!
+//! The address is configured statically, to what QEMU's user networking
+//! hands out. Packets for hosts on the local network are sent to them
+//! directly and everything else goes through the gateway. A packet whose
+//! next hop has no known hardware address waits while an ARP request asks
+//! for it, and is sent once the reply comes in.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    format,
+    sync::Arc,
+    vec::Vec,
+};
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use spin::Mutex;
+
+use crate::{
+    constants::net::{
+        DEFAULT_ADDRESS, DEFAULT_GATEWAY, DEFAULT_NETMASK, EPHEMERAL_PORT_START, MAX_UNRESOLVED,
+        SOCKET_QUEUE_LEN,
+    },
+    debug_println,
+    devices::{
+        manager::{find_device_data, DeviceClass, DEVICE_MANAGER},
+        virtio::net::VirtioNet,
+    },
+    events::futures::WaitQueue,
+    ipc::channel::{channel, Receiver, Sender},
+};
+
+pub mod arp;
+pub mod ethernet;
+pub mod ipv4;
+pub mod udp;
+
+use arp::{ArpCache, ArpPacket, OP_REQUEST};
+use ethernet::{Frame, MacAddress, ETHERTYPE_ARP, ETHERTYPE_IPV4, MTU};
+use ipv4::{Ipv4Header, PROTOCOL_UDP};
+use udp::Datagram;
+
+/// Errors of the network stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// There is no network device
+    NoInterface,
+    /// The port is bound by another socket, or no ephemeral port is free
+    AddressInUse,
+    /// The data does not fit in one packet
+    TooLarge,
+    /// The device has no room for another frame right now
+    DeviceBusy,
+}
+
+/// A device sending and receiving Ethernet frames
+pub trait NetDevice: Send {
+    fn mac_address(&self) -> MacAddress;
+    /// Queues a frame for sending
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError>;
+    /// Takes the next frame received, if any
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+/// How the interface is addressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpConfig {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+}
+
+impl Default for IpConfig {
+    fn default() -> Self {
+        IpConfig {
+            address: DEFAULT_ADDRESS,
+            netmask: DEFAULT_NETMASK,
+            gateway: DEFAULT_GATEWAY,
+        }
+    }
+}
+
+impl IpConfig {
+    fn is_local(&self, ip: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_bits();
+        ip.to_bits() & mask == self.address.to_bits() & mask
+    }
+
+    fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        ip == Ipv4Addr::BROADCAST
+            || ip.to_bits() == self.address.to_bits() | !self.netmask.to_bits()
+    }
+}
+
+/// A network device with an IPv4 address and the sockets bound on it
+pub struct Interface {
+    device: Arc<Mutex<dyn NetDevice>>,
+    mac: MacAddress,
+    config: IpConfig,
+    arp: ArpCache,
+    /// Packets waiting for the hardware address of their next hop, oldest
+    /// first
+    unresolved: VecDeque<(Ipv4Addr, Vec<u8>)>,
+    sockets: BTreeMap<u16, Sender<Datagram>>,
+    /// Where the search for a free ephemeral port starts
+    next_port: u16,
+    /// Identification of the next packet sent
+    next_id: u16,
+}
+
+impl Interface {
+    pub fn new(device: Arc<Mutex<dyn NetDevice>>, config: IpConfig) -> Self {
+        let mac = device.lock().mac_address();
+        Interface {
+            device,
+            mac,
+            config,
+            arp: ArpCache::new(),
+            unresolved: VecDeque::new(),
+            sockets: BTreeMap::new(),
+            next_port: EPHEMERAL_PORT_START,
+            next_id: 0,
+        }
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    pub fn config(&self) -> IpConfig {
+        self.config
+    }
+
+    /// The hardware address of a neighbor, if it is known
+    pub fn neighbor(&self, ip: Ipv4Addr) -> Option<MacAddress> {
+        self.arp.get(ip)
+    }
+
+    /// Binds a UDP port, or a free ephemeral port if `port` is 0
+    ///
+    /// # Returns
+    /// The port bound and where its datagrams arrive
+    pub fn bind(&mut self, port: u16) -> Result<(u16, Receiver<Datagram>), NetError> {
+        let port = if port == 0 {
+            let mut ephemeral =
+                (self.next_port..=u16::MAX).chain(EPHEMERAL_PORT_START..self.next_port);
+            let port = ephemeral
+                .find(|port| !self.sockets.contains_key(port))
+                .ok_or(NetError::AddressInUse)?;
+            self.next_port = port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+            port
+        } else if self.sockets.contains_key(&port) {
+            return Err(NetError::AddressInUse);
+        } else {
+            port
+        };
+        let (sender, receiver) = channel(SOCKET_QUEUE_LEN);
+        self.sockets.insert(port, sender);
+        Ok((port, receiver))
+    }
+
+    pub fn unbind(&mut self, port: u16) {
+        self.sockets.remove(&port);
+    }
+
+    /// Sends `data` from `port` in one datagram
+    pub fn send_udp(
+        &mut self,
+        port: u16,
+        destination: SocketAddrV4,
+        data: &[u8],
+    ) -> Result<(), NetError> {
+        if data.len() > MTU - ipv4::HEADER_SIZE - udp::HEADER_SIZE {
+            return Err(NetError::TooLarge);
+        }
+        let source = SocketAddrV4::new(self.config.address, port);
+        let datagram = udp::encode(source, destination, data);
+        self.send_ipv4(*destination.ip(), PROTOCOL_UDP, &datagram)
+    }
+
+    fn send_ipv4(
+        &mut self,
+        destination: Ipv4Addr,
+        protocol: u8,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        let header = Ipv4Header::new(self.config.address, destination, protocol, self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        let packet = header.encode(payload);
+        if self.config.is_broadcast(destination) {
+            return self.transmit(MacAddress::BROADCAST, ETHERTYPE_IPV4, &packet);
+        }
+        let hop = if self.config.is_local(destination) {
+            destination
+        } else {
+            self.config.gateway
+        };
+        if let Some(mac) = self.arp.get(hop) {
+            return self.transmit(mac, ETHERTYPE_IPV4, &packet);
+        }
+        if self.unresolved.len() == MAX_UNRESOLVED {
+            self.unresolved.pop_front();
+        }
+        self.unresolved.push_back((hop, packet));
+        let request = ArpPacket::request(self.mac, self.config.address, hop);
+        self.transmit(MacAddress::BROADCAST, ETHERTYPE_ARP, &request.encode())
+    }
+
+    fn transmit(
+        &self,
+        destination: MacAddress,
+        ethertype: u16,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        let frame = ethernet::build(destination, self.mac, ethertype, payload);
+        self.device.lock().transmit(&frame)
+    }
+
+    /// Handles every frame the device has received
+    ///
+    /// # Returns
+    /// The number of frames received
+    pub fn poll(&mut self) -> usize {
+        let frames: Vec<Vec<u8>> = {
+            let mut device = self.device.lock();
+            core::iter::from_fn(|| device.receive()).collect()
+        };
+        for frame in frames.iter() {
+            self.handle_frame(frame);
+        }
+        frames.len()
+    }
+
+    /// Handles one received frame. Frames that are malformed or not for
+    /// this interface are dropped.
+    pub fn handle_frame(&mut self, bytes: &[u8]) {
+        let Some(frame) = Frame::parse(bytes) else {
+            return;
+        };
+        if frame.destination != self.mac && frame.destination != MacAddress::BROADCAST {
+            return;
+        }
+        match frame.ethertype {
+            ETHERTYPE_ARP => {
+                if let Some(packet) = ArpPacket::parse(frame.payload) {
+                    self.handle_arp(packet);
+                }
+            }
+            ETHERTYPE_IPV4 => self.handle_ipv4(frame.payload),
+            _ => {}
+        }
+    }
+
+    fn handle_arp(&mut self, packet: ArpPacket) {
+        if packet.sender_ip.is_unspecified() {
+            return;
+        }
+        let for_us = packet.target_ip == self.config.address;
+        // Only neighbors already known, or that are talking to us, are
+        // learned, as RFC 826 has it
+        if for_us || self.arp.get(packet.sender_ip).is_some() {
+            self.arp.insert(packet.sender_ip, packet.sender_mac);
+            self.send_resolved(packet.sender_ip, packet.sender_mac);
+        }
+        if for_us && packet.operation == OP_REQUEST {
+            let reply = packet.reply(self.mac);
+            let _ = self.transmit(packet.sender_mac, ETHERTYPE_ARP, &reply.encode());
+        }
+    }
+
+    /// Sends the packets that were waiting for `hop` to be resolved
+    fn send_resolved(&mut self, hop: Ipv4Addr, mac: MacAddress) {
+        let (ready, waiting) = core::mem::take(&mut self.unresolved)
+            .into_iter()
+            .partition(|(waiting_for, _)| *waiting_for == hop);
+        self.unresolved = waiting;
+        for (_, packet) in ready.iter() {
+            let _ = self.transmit(mac, ETHERTYPE_IPV4, packet);
+        }
+    }
+
+    fn handle_ipv4(&mut self, bytes: &[u8]) {
+        let Some((header, payload)) = Ipv4Header::parse(bytes) else {
+            return;
+        };
+        if header.destination != self.config.address
+            && !self.config.is_broadcast(header.destination)
+        {
+            return;
+        }
+        if header.protocol != PROTOCOL_UDP {
+            return;
+        }
+        let Some((source_port, port, data)) =
+            udp::parse(header.source, header.destination, payload)
+        else {
+            return;
+        };
+        if let Some(socket) = self.sockets.get(&port) {
+            // Like any UDP stack, datagrams nobody has room for are lost
+            let _ = socket.try_send(Datagram {
+                source: SocketAddrV4::new(header.source, source_port),
+                data: data.to_vec(),
+            });
+        }
+    }
+}
+
+/// Sockets waiting for datagrams. Woken when the device receives frames.
+static RECEIVE_WAITERS: WaitQueue = WaitQueue::new();
+
+/// Brings up the interface on the first network device, and registers it
+/// behind the device. Should be called once devices are initialized.
+pub fn init() {
+    let mut manager = DEVICE_MANAGER.lock();
+    let Some((parent, device)) = manager.find::<VirtioNet>() else {
+        debug_println!("No network device, networking is off");
+        return;
+    };
+    let interface = Interface::new(device, IpConfig::default());
+    debug_println!(
+        "Network interface {} at {}",
+        interface.mac_address(),
+        interface.config().address
+    );
+    let handle = manager.register(
+        format!("interface {}", interface.config().address),
+        DeviceClass::Network,
+        Some(parent),
+    );
+    manager.bind(handle, "ipv4");
+    manager.activate(handle, interface);
+}
+
+/// The interface on the network device, if there is one
+fn interface() -> Option<Arc<Mutex<Interface>>> {
+    find_device_data::<Interface>()
+}
+
+/// Tells the sockets waiting that frames have arrived. Called from the
+/// device's interrupt handler.
+pub fn frames_received() {
+    RECEIVE_WAITERS.wake_all();
+}
+
+/// Handles the frames the device has received
+///
+/// # Returns
+/// The number of frames handled
+pub fn poll() -> usize {
+    interface().map_or(0, |interface| interface.lock().poll())
+}
+
+/// Runs `f` on the interface
+///
+/// # Returns
+/// `NetError::NoInterface` if there is no network device
+pub fn with_interface<T>(
+    f: impl FnOnce(&mut Interface) -> Result<T, NetError>,
+) -> Result<T, NetError> {
+    let interface = interface().ok_or(NetError::NoInterface)?;
+    let mut interface = interface.lock();
+    f(&mut interface)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use arp::OP_REPLY;
+
+    /// Records what is sent, and hands out frames put in its inbox
+    struct FakeDevice {
+        sent: Vec<Vec<u8>>,
+        inbox: VecDeque<Vec<u8>>,
+    }
+
+    impl NetDevice for FakeDevice {
+        fn mac_address(&self) -> MacAddress {
+            MacAddress([2, 0, 0, 0, 0, 1])
+        }
+
+        fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError> {
+            self.sent.push(frame.to_vec());
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Option<Vec<u8>> {
+            self.inbox.pop_front()
+        }
+    }
+
+    const GATEWAY_MAC: MacAddress = MacAddress([0x52, 0x55, 10, 0, 2, 2]);
+
+    fn datagram_frame(source: SocketAddrV4, destination: SocketAddrV4, data: &[u8]) -> Vec<u8> {
+        let datagram = udp::encode(source, destination, data);
+        let packet =
+            Ipv4Header::new(*source.ip(), *destination.ip(), PROTOCOL_UDP, 1).encode(&datagram);
+        ethernet::build(
+            MacAddress([2, 0, 0, 0, 0, 1]),
+            GATEWAY_MAC,
+            ETHERTYPE_IPV4,
+            &packet,
+        )
+    }
+
+    /// The UDP datagram in a sent frame, with its destination MAC
+    fn sent_datagram(frame: &[u8]) -> (MacAddress, u16, u16, Vec<u8>) {
+        let frame = Frame::parse(frame).unwrap();
+        assert_eq!(frame.ethertype, ETHERTYPE_IPV4);
+        let (header, payload) = Ipv4Header::parse(frame.payload).unwrap();
+        let (source, destination, data) =
+            udp::parse(header.source, header.destination, payload).unwrap();
+        (frame.destination, source, destination, data.to_vec())
+    }
+
+    #[test_case]
+    fn test_udp_over_arp() {
+        let device = Arc::new(Mutex::new(FakeDevice {
+            sent: Vec::new(),
+            inbox: VecDeque::new(),
+        }));
+        let mut interface = Interface::new(device.clone(), IpConfig::default());
+        let (port, received) = interface.bind(7).unwrap();
+        assert_eq!(port, 7);
+        assert_eq!(interface.bind(7).err(), Some(NetError::AddressInUse));
+        let (ephemeral, _) = interface.bind(0).unwrap();
+        assert!(ephemeral >= EPHEMERAL_PORT_START);
+
+        // The first datagram waits for the gateway's address
+        let gateway = SocketAddrV4::new(DEFAULT_GATEWAY, 9);
+        interface.send_udp(7, gateway, b"hi").unwrap();
+        let request = device.lock().sent.pop().unwrap();
+        assert!(device.lock().sent.is_empty());
+        let frame = Frame::parse(&request).unwrap();
+        assert_eq!(
+            (frame.destination, frame.ethertype),
+            (MacAddress::BROADCAST, ETHERTYPE_ARP)
+        );
+        let request = ArpPacket::parse(frame.payload).unwrap();
+        assert_eq!(request.target_ip, DEFAULT_GATEWAY);
+
+        let reply = request.reply(GATEWAY_MAC);
+        let reply = ethernet::build(
+            interface.mac_address(),
+            GATEWAY_MAC,
+            ETHERTYPE_ARP,
+            &reply.encode(),
+        );
+        device.lock().inbox.push_back(reply);
+        assert_eq!(interface.poll(), 1);
+        assert_eq!(interface.neighbor(DEFAULT_GATEWAY), Some(GATEWAY_MAC));
+        let sent = device.lock().sent.pop().unwrap();
+        assert_eq!(sent_datagram(&sent), (GATEWAY_MAC, 7, 9, b"hi".to_vec()));
+
+        // Other networks are reached through the gateway, now known
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 53);
+        interface.send_udp(7, remote, b"far").unwrap();
+        let sent = device.lock().sent.pop().unwrap();
+        assert_eq!(sent_datagram(&sent).0, GATEWAY_MAC);
+        assert_eq!(
+            interface.send_udp(7, remote, &vec![0; MTU]),
+            Err(NetError::TooLarge)
+        );
+
+        // Datagrams are delivered by port, and dropped if corrupted or
+        // for a port nobody bound
+        let here = SocketAddrV4::new(DEFAULT_ADDRESS, 7);
+        let mut corrupted = datagram_frame(gateway, here, b"bad");
+        *corrupted.last_mut().unwrap() ^= 1;
+        device.lock().inbox.extend([
+            datagram_frame(gateway, here, b"pong"),
+            corrupted,
+            datagram_frame(gateway, SocketAddrV4::new(DEFAULT_ADDRESS, 8), b"nobody"),
+        ]);
+        assert_eq!(interface.poll(), 3);
+        assert_eq!(
+            received.try_recv(),
+            Ok(Datagram {
+                source: gateway,
+                data: b"pong".to_vec(),
+            })
+        );
+        assert!(received.try_recv().is_err());
+
+        // Requests for our address are answered
+        let neighbor = Ipv4Addr::new(10, 0, 2, 3);
+        let question = ArpPacket::request(GATEWAY_MAC, neighbor, DEFAULT_ADDRESS);
+        let question = ethernet::build(
+            MacAddress::BROADCAST,
+            GATEWAY_MAC,
+            ETHERTYPE_ARP,
+            &question.encode(),
+        );
+        device.lock().inbox.push_back(question);
+        interface.poll();
+        let answer = device.lock().sent.pop().unwrap();
+        let answer = ArpPacket::parse(Frame::parse(&answer).unwrap().payload).unwrap();
+        assert_eq!(answer.operation, OP_REPLY);
+        assert_eq!(answer.sender_mac, interface.mac_address());
+        assert_eq!(interface.neighbor(neighbor), Some(GATEWAY_MAC));
+    }
+}
@@ -0,0 +1,140 @@
+//! UDP datagrams and the sockets that send and receive them.
+
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use super::{
+    ipv4::{fold, sum_words, PROTOCOL_UDP},
+    poll, with_interface, NetError, RECEIVE_WAITERS,
+};
+use crate::ipc::channel::{Receiver, TryRecvError};
+
+/// Length of the header before the payload
+pub const HEADER_SIZE: usize = 8;
+
+/// A datagram received on a socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub source: SocketAddrV4,
+    pub data: Vec<u8>,
+}
+
+/// Sum of the pseudo header the checksum covers besides the datagram
+fn pseudo_header_sum(source: Ipv4Addr, destination: Ipv4Addr, len: usize) -> u32 {
+    let sum = sum_words(&source.octets(), 0);
+    let sum = sum_words(&destination.octets(), sum);
+    sum + PROTOCOL_UDP as u32 + len as u32
+}
+
+/// Reads a datagram from the payload of an IPv4 packet
+///
+/// # Returns
+/// The source port, destination port and payload, or None if the datagram
+/// is malformed or fails its checksum
+pub fn parse(source: Ipv4Addr, destination: Ipv4Addr, bytes: &[u8]) -> Option<(u16, u16, &[u8])> {
+    let header = bytes.get(..HEADER_SIZE)?;
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if len < HEADER_SIZE || len > bytes.len() {
+        return None;
+    }
+    let bytes = &bytes[..len];
+    // Senders may leave the checksum out by sending zero
+    let sent_checksum = u16::from_be_bytes([header[6], header[7]]);
+    if sent_checksum != 0 {
+        let sum = sum_words(bytes, pseudo_header_sum(source, destination, len));
+        if fold(sum) != 0 {
+            return None;
+        }
+    }
+    Some((
+        u16::from_be_bytes([header[0], header[1]]),
+        u16::from_be_bytes([header[2], header[3]]),
+        &bytes[HEADER_SIZE..],
+    ))
+}
+
+/// Builds a datagram, checksum included
+pub fn encode(source: SocketAddrV4, destination: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let len = HEADER_SIZE + payload.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&source.port().to_be_bytes());
+    datagram.extend_from_slice(&destination.port().to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let sum = sum_words(
+        &datagram,
+        pseudo_header_sum(*source.ip(), *destination.ip(), len),
+    );
+    // A checksum that comes out as zero is sent as all ones, since zero
+    // means there is none
+    let checksum = match fold(sum) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
+
+/// A UDP port bound on the network interface. The port is free again once
+/// the socket is dropped.
+pub struct UdpSocket {
+    port: u16,
+    received: Receiver<Datagram>,
+}
+
+impl UdpSocket {
+    /// Binds `port`, or a free ephemeral port if it is 0
+    ///
+    /// # Returns
+    /// `NetError::AddressInUse` if another socket has the port
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let (port, received) = with_interface(|interface| interface.bind(port))?;
+        Ok(UdpSocket { port, received })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data` in one datagram. The datagram may still be waiting for
+    /// the next hop's address to be resolved when this returns.
+    pub fn send_to(&self, data: &[u8], destination: SocketAddrV4) -> Result<(), NetError> {
+        with_interface(|interface| interface.send_udp(self.port, destination, data))
+    }
+
+    /// Takes a datagram already received, without waiting
+    pub fn try_recv_from(&self) -> Option<Datagram> {
+        poll();
+        self.received.try_recv().ok()
+    }
+
+    /// Waits for a datagram
+    ///
+    /// # Returns
+    /// `NetError::NoInterface` if the interface went away
+    pub async fn recv_from(&self) -> Result<Datagram, NetError> {
+        let mut received = None;
+        RECEIVE_WAITERS
+            .poll_until(|| {
+                poll();
+                match self.received.try_recv() {
+                    Ok(datagram) => received = Some(Ok(datagram)),
+                    Err(TryRecvError::Closed) => received = Some(Err(NetError::NoInterface)),
+                    Err(TryRecvError::Empty) => {}
+                }
+                received.is_some()
+            })
+            .await;
+        received.expect("Waited until received")
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let _ = with_interface(|interface| {
+            interface.unbind(self.port);
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,97 @@
+//! Panic reporting that works at any point of boot.
+//!
+//! Until the heap is mapped, the serial driver cannot be relied on and
+//! anything that allocates faults, so a panic that early is formatted into
+//! a static buffer and written straight to the console UART. Later panics
+//! go through the serial driver, unless its lock is held, for example by
+//! the code that panicked.
+//!
+//! A core that panics while already handling a panic stops right away,
+//! exiting QEMU with `QemuExitCode::RecursivePanic`, instead of faulting
+//! again inside the handler.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+
+use crate::{
+    arch,
+    constants::MAX_CORES,
+    exit_qemu,
+    format::BoundedWriter,
+    klog,
+    memory::heap::heap_ready,
+    serial::{self, CONSOLE},
+    QemuExitCode,
+};
+
+/// Offset of the line status register from the base port
+const LINE_STATUS_OFFSET: u16 = 5;
+/// Line status bit set when the transmitter can take another byte
+const TRANSMITTER_READY: u8 = 1 << 5;
+/// Polls of the line status register before a byte is written anyway
+const MAX_TRANSMIT_POLLS: usize = 10_000;
+/// Appended to messages that did not fit the buffer
+const TRUNCATED: &[u8] = b"...\n";
+
+/// Set on each core while it handles a panic
+static PANICKING: [AtomicBool; MAX_CORES] = [const { AtomicBool::new(false) }; MAX_CORES];
+
+/// Space early panics are formatted into
+static EARLY_BUFFER: Mutex<[u8; 1024]> = Mutex::new([0; 1024]);
+
+/// Marks this core as panicking. Called first by every panic handler.
+///
+/// If the core is already handling a panic it halts here: a best effort
+/// note goes to the console and QEMU exits with `QemuExitCode::RecursivePanic`.
+pub fn begin_panic() {
+    let core = arch::early_core_id() as usize;
+    let Some(panicking) = PANICKING.get(core) else {
+        return;
+    };
+    if panicking.swap(true, Ordering::SeqCst) {
+        arch::disable_interrupts();
+        write_raw(b"\nPanicked while handling a panic, halting\n");
+        exit_qemu(QemuExitCode::RecursivePanic);
+        loop {
+            arch::wait_for_interrupt();
+        }
+    }
+}
+
+/// Prints a panic message without allocating
+pub fn panic_print(args: fmt::Arguments) {
+    klog::write_fmt(args);
+    if heap_ready() {
+        if let Some(mut serial) = CONSOLE.try_lock() {
+            let _ = serial.write_fmt(args);
+            return;
+        }
+    }
+
+    let mut buffer = EARLY_BUFFER.lock();
+    let mut writer = BoundedWriter::new(&mut *buffer);
+    let _ = writer.write_fmt(args);
+    write_raw(writer.as_bytes());
+    if writer.truncated() {
+        write_raw(TRUNCATED);
+    }
+}
+
+/// Writes bytes to the console through its ports, without the serial
+/// driver
+fn write_raw(bytes: &[u8]) {
+    let port = serial::console_port();
+    for &byte in bytes {
+        for _ in 0..MAX_TRANSMIT_POLLS {
+            let status = unsafe { arch::port_read_u8(port + LINE_STATUS_OFFSET) };
+            if status & TRANSMITTER_READY != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        unsafe { arch::port_write_u8(port, byte) };
+    }
+}
@@ -0,0 +1,297 @@
+//! CPU groups.
+//!
+//! Processes can be placed in named groups that form a tree under the root
+//! group. Each group has a weight, and a group's CPU share is split between
+//! its children in proportion to their weights. Processes placed directly in
+//! a group that also has children compete with those children as if they
+//! were one more child of weight `DEFAULT_WEIGHT`.
+//!
+//! The event runners enforce the shares with deficit round robin across the
+//! groups that have queued events, see `EventRunner::pop_fair`. Events woken
+//! through their waker bypass the groups so wakeups stay prompt.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use crate::{serial_println, sync::RwLock};
+
+/// Identifies a CPU group
+pub type GroupId = u32;
+
+/// The group every process starts in, and the kernel runs in
+pub const ROOT_GROUP: GroupId = 0;
+/// Weight the processes placed directly in a group compete with
+pub const DEFAULT_WEIGHT: u32 = 100;
+/// Largest weight a group can have
+pub const MAX_WEIGHT: u32 = 10_000;
+/// A share of 1.0 in fixed point
+pub const FULL_SHARE: u64 = 1 << 32;
+
+/// Errors from managing groups
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupError {
+    /// No group with the given ID or name exists
+    NotFound,
+    /// A group with the same name already exists
+    AlreadyExists,
+    /// Weights must be between 1 and MAX_WEIGHT
+    InvalidWeight,
+    /// The root group cannot be removed
+    RootGroup,
+    /// The group still has children or processes
+    NotEmpty,
+}
+
+/// A named group of processes
+#[derive(Debug, Clone)]
+pub struct CpuGroup {
+    pub name: String,
+    pub weight: u32,
+    pub parent: Option<GroupId>,
+    pub children: Vec<GroupId>,
+}
+
+/// All groups and the group of every process not in the root group
+pub struct GroupTable {
+    groups: BTreeMap<GroupId, CpuGroup>,
+    members: BTreeMap<u32, GroupId>,
+    next_id: GroupId,
+}
+
+/// The kernel's CPU groups
+pub static CGROUPS: RwLock<GroupTable> = RwLock::new("cgroups", GroupTable::new());
+
+impl Default for GroupTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GroupTable {
+    /// Creates a table holding only the root group. The root group is
+    /// created lazily so this can be const.
+    pub const fn new() -> Self {
+        GroupTable {
+            groups: BTreeMap::new(),
+            members: BTreeMap::new(),
+            next_id: ROOT_GROUP + 1,
+        }
+    }
+
+    fn ensure_root(&mut self) {
+        self.groups.entry(ROOT_GROUP).or_insert_with(|| CpuGroup {
+            name: "root".into(),
+            weight: DEFAULT_WEIGHT,
+            parent: None,
+            children: Vec::new(),
+        });
+    }
+
+    /// Creates a group
+    ///
+    /// # Arguments
+    /// * `name` - Unique name of the group
+    /// * `parent` - The group to nest it under
+    /// * `weight` - Relative CPU weight among its siblings
+    pub fn create(
+        &mut self,
+        name: &str,
+        parent: GroupId,
+        weight: u32,
+    ) -> Result<GroupId, CgroupError> {
+        self.ensure_root();
+        if !(1..=MAX_WEIGHT).contains(&weight) {
+            return Err(CgroupError::InvalidWeight);
+        }
+        if self.find(name).is_some() {
+            return Err(CgroupError::AlreadyExists);
+        }
+        let parent_group = self.groups.get_mut(&parent).ok_or(CgroupError::NotFound)?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        parent_group.children.push(id);
+        self.groups.insert(
+            id,
+            CpuGroup {
+                name: name.into(),
+                weight,
+                parent: Some(parent),
+                children: Vec::new(),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Removes an empty group
+    pub fn remove(&mut self, id: GroupId) -> Result<(), CgroupError> {
+        if id == ROOT_GROUP {
+            return Err(CgroupError::RootGroup);
+        }
+        let group = self.groups.get(&id).ok_or(CgroupError::NotFound)?;
+        if !group.children.is_empty() || self.members.values().any(|&g| g == id) {
+            return Err(CgroupError::NotEmpty);
+        }
+        let parent = group.parent;
+        self.groups.remove(&id);
+        if let Some(parent) = parent.and_then(|p| self.groups.get_mut(&p)) {
+            parent.children.retain(|&child| child != id);
+        }
+        Ok(())
+    }
+
+    /// Finds a group by name
+    pub fn find(&self, name: &str) -> Option<GroupId> {
+        if name == "root" {
+            return Some(ROOT_GROUP);
+        }
+        self.groups
+            .iter()
+            .find(|(_, group)| group.name == name)
+            .map(|(&id, _)| id)
+    }
+
+    /// Changes the weight of a group
+    pub fn set_weight(&mut self, id: GroupId, weight: u32) -> Result<(), CgroupError> {
+        self.ensure_root();
+        if !(1..=MAX_WEIGHT).contains(&weight) {
+            return Err(CgroupError::InvalidWeight);
+        }
+        self.groups
+            .get_mut(&id)
+            .ok_or(CgroupError::NotFound)?
+            .weight = weight;
+        Ok(())
+    }
+
+    /// Moves a process into a group
+    pub fn move_process(&mut self, pid: u32, id: GroupId) -> Result<(), CgroupError> {
+        self.ensure_root();
+        if !self.groups.contains_key(&id) {
+            return Err(CgroupError::NotFound);
+        }
+        if id == ROOT_GROUP {
+            self.members.remove(&pid);
+        } else {
+            self.members.insert(pid, id);
+        }
+        Ok(())
+    }
+
+    /// Forgets an exited process
+    pub fn remove_process(&mut self, pid: u32) {
+        self.members.remove(&pid);
+    }
+
+    /// Returns the group of a process
+    pub fn group_of(&self, pid: u32) -> GroupId {
+        self.members.get(&pid).copied().unwrap_or(ROOT_GROUP)
+    }
+
+    /// Sum of the weights competing for a group's share: its children
+    /// plus its own processes
+    fn competing_weight(&self, group: &CpuGroup) -> u64 {
+        DEFAULT_WEIGHT as u64
+            + group
+                .children
+                .iter()
+                .filter_map(|child| self.groups.get(child))
+                .map(|child| child.weight as u64)
+                .sum::<u64>()
+    }
+
+    /// Returns the share of the CPU a group and its descendants get, out of
+    /// FULL_SHARE
+    pub fn group_share(&self, id: GroupId) -> u64 {
+        let Some(group) = self.groups.get(&id) else {
+            return FULL_SHARE;
+        };
+        let Some(parent_id) = group.parent else {
+            return FULL_SHARE;
+        };
+        let Some(parent) = self.groups.get(&parent_id) else {
+            return FULL_SHARE;
+        };
+        self.group_share(parent_id) * group.weight as u64 / self.competing_weight(parent)
+    }
+
+    /// Returns the share of the CPU the processes directly in a group get,
+    /// out of FULL_SHARE
+    pub fn direct_share(&self, id: GroupId) -> u64 {
+        let Some(group) = self.groups.get(&id) else {
+            return FULL_SHARE;
+        };
+        self.group_share(id) * DEFAULT_WEIGHT as u64 / self.competing_weight(group)
+    }
+
+    /// Prints the group tree with each group's effective share
+    pub fn print(&self) {
+        self.print_group(ROOT_GROUP, 0);
+    }
+
+    fn print_group(&self, id: GroupId, depth: usize) {
+        let Some(group) = self.groups.get(&id) else {
+            serial_println!("root (no groups)");
+            return;
+        };
+        let members: Vec<u32> = self
+            .members
+            .iter()
+            .filter(|(_, &g)| g == id)
+            .map(|(&pid, _)| pid)
+            .collect();
+        serial_println!(
+            "{:indent$}{} weight {} share {}% processes {:?}",
+            "",
+            group.name,
+            group.weight,
+            self.group_share(id) * 100 / FULL_SHARE,
+            members,
+            indent = depth * 2
+        );
+        for &child in &group.children {
+            self.print_group(child, depth + 1);
+        }
+    }
+}
+
+/// Creates a group under `parent`, see `GroupTable::create`
+pub fn create_group(name: &str, parent: GroupId, weight: u32) -> Result<GroupId, CgroupError> {
+    CGROUPS.write().create(name, parent, weight)
+}
+
+/// Moves a process into a group, see `GroupTable::move_process`
+pub fn move_process(pid: u32, id: GroupId) -> Result<(), CgroupError> {
+    CGROUPS.write().move_process(pid, id)
+}
+
+/// Returns the group of a process
+pub fn group_of(pid: u32) -> GroupId {
+    CGROUPS.read().group_of(pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_hierarchical_shares() {
+        let mut table = GroupTable::new();
+        let batch = table.create("batch", ROOT_GROUP, 100).unwrap();
+        let stress = table.create("stress", batch, 300).unwrap();
+        let server = table.create("server", ROOT_GROUP, 200).unwrap();
+
+        // root's own processes, batch and server compete 100:100:200
+        assert_eq!(table.direct_share(ROOT_GROUP), FULL_SHARE / 4);
+        assert_eq!(table.group_share(batch), FULL_SHARE / 4);
+        assert_eq!(table.group_share(server), FULL_SHARE / 2);
+        // batch's own processes and stress split batch's quarter 100:300
+        assert_eq!(table.group_share(stress), FULL_SHARE * 3 / 16);
+
+        table.move_process(7, stress).unwrap();
+        assert_eq!(table.group_of(7), stress);
+        assert_eq!(table.remove(stress), Err(CgroupError::NotEmpty));
+        table.remove_process(7);
+        assert_eq!(table.remove(stress), Ok(()));
+        assert_eq!(table.group_of(7), ROOT_GROUP);
+    }
+}
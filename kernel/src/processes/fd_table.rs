@@ -0,0 +1,170 @@
+//! Per-process file descriptor tables.
+//!
+//! A descriptor refers to a `FileDescriptor`, which is an open VFS file,
+//! an end of a pipe, or the serial console. Descriptors duplicated with
+//! `dup` or `dup2` share the same open file, and with it the file
+//! position, as in POSIX. The VFS file, or pipe end, is closed when the
+//! last descriptor referring to it goes away, whichever table that is in,
+//! so cloning a table gives a child process its own descriptors for the
+//! parent's open files and pipes.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    constants::processes::MAX_OPEN_FILES,
+    filesys::vfs,
+    ipc::pipe::{PipeReader, PipeWriter},
+};
+
+/// Descriptors of the standard streams
+pub const STDIN_FD: usize = 0;
+pub const STDOUT_FD: usize = 1;
+pub const STDERR_FD: usize = 2;
+
+/// Why a descriptor operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdError {
+    /// The descriptor is not open, or is out of range
+    BadDescriptor,
+    /// The table already holds MAX_OPEN_FILES descriptors
+    TooManyOpen,
+}
+
+/// A file opened through the VFS, closed once the last reference is gone.
+/// Must not be dropped while the VFS lock is held.
+#[derive(Debug)]
+pub struct VfsFile {
+    pub fd: usize,
+}
+
+impl Drop for VfsFile {
+    fn drop(&mut self) {
+        // Nobody is left to report a failure to
+        let _ = vfs::lock().close(self.fd);
+    }
+}
+
+/// What a descriptor refers to
+#[derive(Debug, Clone)]
+pub enum FileDescriptor {
+    /// A file opened through the VFS
+    File(Arc<VfsFile>),
+    /// The end of a pipe bytes are read from
+    PipeReader(Arc<PipeReader>),
+    /// The end of a pipe bytes are written to
+    PipeWriter(Arc<PipeWriter>),
+    /// The serial console. Writes go to the serial port, and reads see the
+    /// end of the file since there is no console input yet.
+    SerialConsole,
+}
+
+impl FileDescriptor {
+    /// Wraps a descriptor returned by `Vfs::open`
+    pub fn file(vfs_fd: usize) -> Self {
+        FileDescriptor::File(Arc::new(VfsFile { fd: vfs_fd }))
+    }
+}
+
+/// The descriptors a process has open
+#[derive(Debug, Clone, Default)]
+pub struct FdTable {
+    descriptors: BTreeMap<usize, FileDescriptor>,
+}
+
+impl FdTable {
+    /// Creates a table with the standard streams on the serial console
+    pub fn with_standard_streams() -> Self {
+        let mut table = FdTable::default();
+        for fd in [STDIN_FD, STDOUT_FD, STDERR_FD] {
+            table.descriptors.insert(fd, FileDescriptor::SerialConsole);
+        }
+        table
+    }
+
+    /// Number of open descriptors
+    pub fn len(&self) -> usize {
+        self.descriptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+    }
+
+    pub fn get(&self, fd: usize) -> Result<&FileDescriptor, FdError> {
+        self.descriptors.get(&fd).ok_or(FdError::BadDescriptor)
+    }
+
+    /// Adds a descriptor under the lowest free number
+    pub fn insert(&mut self, descriptor: FileDescriptor) -> Result<usize, FdError> {
+        let fd = (0..MAX_OPEN_FILES)
+            .find(|fd| !self.descriptors.contains_key(fd))
+            .ok_or(FdError::TooManyOpen)?;
+        self.descriptors.insert(fd, descriptor);
+        Ok(fd)
+    }
+
+    /// Removes a descriptor. The caller drops it, outside the VFS lock.
+    pub fn remove(&mut self, fd: usize) -> Result<FileDescriptor, FdError> {
+        self.descriptors.remove(&fd).ok_or(FdError::BadDescriptor)
+    }
+
+    /// Duplicates `fd` under the lowest free number
+    pub fn dup(&mut self, fd: usize) -> Result<usize, FdError> {
+        let descriptor = self.get(fd)?.clone();
+        self.insert(descriptor)
+    }
+
+    /// Duplicates `old` as `new`, closing what `new` referred to
+    ///
+    /// # Returns
+    /// Whatever `new` referred to, which the caller drops outside the VFS
+    /// lock
+    pub fn dup2(&mut self, old: usize, new: usize) -> Result<Option<FileDescriptor>, FdError> {
+        let descriptor = self.get(old)?.clone();
+        if new >= MAX_OPEN_FILES {
+            return Err(FdError::BadDescriptor);
+        }
+        if old == new {
+            return Ok(None);
+        }
+        Ok(self.descriptors.insert(new, descriptor))
+    }
+
+    /// Removes every descriptor, for a process that is exiting
+    pub fn take_all(&mut self) -> FdTable {
+        core::mem::take(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_dup() {
+        let mut table = FdTable::with_standard_streams();
+        assert!(matches!(
+            table.get(STDOUT_FD),
+            Ok(FileDescriptor::SerialConsole)
+        ));
+
+        // Closed descriptors are reused lowest first
+        table.remove(STDIN_FD).unwrap();
+        assert_eq!(table.dup(STDERR_FD), Ok(STDIN_FD));
+        assert_eq!(table.dup(STDERR_FD), Ok(3));
+        assert_eq!(table.remove(9).unwrap_err(), FdError::BadDescriptor);
+
+        assert!(table.dup2(STDOUT_FD, 7).unwrap().is_none());
+        assert!(table.dup2(STDOUT_FD, 7).unwrap().is_some());
+        assert_eq!(
+            table.dup2(STDOUT_FD, MAX_OPEN_FILES).unwrap_err(),
+            FdError::BadDescriptor
+        );
+        assert_eq!(table.dup2(8, 2).unwrap_err(), FdError::BadDescriptor);
+
+        while table.dup(STDOUT_FD).is_ok() {}
+        assert_eq!(table.dup(STDOUT_FD), Err(FdError::TooManyOpen));
+        assert!(table.take_all().get(MAX_OPEN_FILES - 1).is_ok());
+        assert!(table.get(STDOUT_FD).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+//! Program images shared between the processes running the same binary.
+//!
+//! Loading a program from the VFS looks its file up here first, so a
+//! binary that is started over and over is only read into frames once,
+//! and every process running it maps the same text pages. Images are kept
+//! by `FileVersion`, which includes a hash of the binary's contents: once a
+//! binary changes, new processes get a new image, while those already
+//! running the old one keep it alive through their own reference.
+//!
+//! Only `MAX_CACHED_IMAGES` images are kept. Past that, images no process
+//! is running are dropped, freeing their frames.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use spin::Mutex;
+
+use crate::{
+    constants::processes::MAX_CACHED_IMAGES,
+    filesys::vfs::FileVersion,
+    processes::{loader::ProgramImage, process::ProcessError},
+};
+
+static IMAGES: Mutex<BTreeMap<FileVersion, Arc<ProgramImage>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the image of a program read from the VFS, loading it from
+/// `elf_bytes` unless it is cached already
+///
+/// # Returns
+/// The image, or the error of `ProgramImage::new`
+pub fn shared_image(
+    version: &FileVersion,
+    elf_bytes: &[u8],
+) -> Result<Arc<ProgramImage>, ProcessError> {
+    if let Some(image) = IMAGES.lock().get(version) {
+        return Ok(Arc::clone(image));
+    }
+    // Filling the frames takes a while, so it is done without the lock.
+    // If another process loads the same version meanwhile, its image wins.
+    let loaded = Arc::new(ProgramImage::new(elf_bytes)?);
+
+    let mut images = IMAGES.lock();
+    // Older versions of the file will not be started again
+    images.retain(|cached, _| {
+        cached.mount != version.mount || cached.path != version.path || cached == version
+    });
+    let image = Arc::clone(images.entry(version.clone()).or_insert(loaded));
+    while images.len() > MAX_CACHED_IMAGES {
+        let Some(unused) = images
+            .iter()
+            .find(|(_, image)| Arc::strong_count(image) == 1)
+            .map(|(cached, _)| cached.clone())
+        else {
+            break;
+        };
+        images.remove(&unused);
+    }
+    Ok(image)
+}
+
+/// Drops every cached image no process is running
+///
+/// # Returns
+/// The number of images dropped
+pub fn drop_unused() -> usize {
+    let mut images = IMAGES.lock();
+    let before = images.len();
+    images.retain(|_, image| Arc::strong_count(image) > 1);
+    before - images.len()
+}
+
+/// Returns the number of images cached
+pub fn cached_images() -> usize {
+    IMAGES.lock().len()
+}
@@ -0,0 +1,344 @@
+//! Kernel threads.
+//!
+//! A kernel thread is a process table entry that runs kernel code in ring 0
+//! on a stack of its own, in the kernel's address space. It is scheduled
+//! through the event runner like a process, but unlike an event it can
+//! block partway through: `block_on` parks the thread on a future and hands
+//! the core back to the runner, and the thread carries on from the same
+//! point once the future completes. That lets drivers write long running
+//! work as straight line code without a userspace process to host it.
+//!
+//! Kernel threads are never preempted. The timer leaves ring 0 code alone,
+//! since it may hold locks the next event would spin on, so a thread runs
+//! until it blocks, yields or returns.
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::{arch::naked_asm, fmt, future::Future, ops::Range, task::Poll};
+use futures::future::poll_fn;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageTable, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use crate::{
+    arch::{core_id, without_interrupts},
+    constants::processes::{KERNEL_PID, KTHREAD_STACK_SIZE, PROCESS_DEFAULT_PRIORITY},
+    debug,
+    events::{current_running_event_pid, schedule_process, should_yield},
+    memory::{HHDM_OFFSET, MAPPER},
+    processes::{
+        fd_table::FdTable,
+        loader::ImageHints,
+        pid::alloc_pid,
+        process::{
+            get_process, return_process, run_process_ring3, ProcessError, ProcessState, UnsafePCB,
+            WaitReason, PCB, PROCESS_TABLE,
+        },
+        registers::Registers,
+        rusage::SwitchReason,
+        signal::Signals,
+    },
+};
+
+/// What a PCB holds for a kernel thread beyond a process' state
+pub struct KernelThread {
+    stack: Box<[u8]>,
+    /// The function the thread runs, taken when it starts
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl KernelThread {
+    /// Addresses of the thread's stack
+    pub fn stack(&self) -> Range<u64> {
+        let start = self.stack.as_ptr() as u64;
+        start..start + self.stack.len() as u64
+    }
+}
+
+impl fmt::Debug for KernelThread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KernelThread")
+            .field("stack", &format_args!("{:#x?}", self.stack()))
+            .field("started", &self.entry.is_none())
+            .finish()
+    }
+}
+
+/// Creates a kernel thread that runs `entry` once scheduled, without
+/// scheduling it
+///
+/// # Returns
+/// The thread's PID
+pub fn create(entry: impl FnOnce() + Send + 'static) -> Result<u32, ProcessError> {
+    let pid = alloc_pid()?;
+    let thread = KernelThread {
+        stack: vec![0u8; KTHREAD_STACK_SIZE].into_boxed_slice(),
+        entry: Some(Box::new(entry)),
+    };
+
+    // Enter thread_start as though called, with the return address slot
+    // leaving the stack 16 byte aligned
+    let stack_top = thread.stack().end & !0xF;
+    let registers = Registers {
+        rdi: pid as u64,
+        rsp: stack_top - 8,
+        rip: thread_start as usize as u64,
+        rflags: 0x202,
+        ..Registers::new()
+    };
+
+    let process = Arc::new(UnsafePCB::init(PCB {
+        pid,
+        parent: KERNEL_PID,
+        state: ProcessState::New,
+        kernel_rsp: 0,
+        kernel_rip: 0,
+        registers,
+        pml4_frame: kernel_pml4(),
+        priority: PROCESS_DEFAULT_PRIORITY,
+        fd_table: FdTable::default(),
+        exit_code: 0,
+        hints: ImageHints::default(),
+        stack: None,
+        heap: None,
+        kernel_thread: Some(thread),
+        wait_reason: None,
+        mappings: Vec::new(),
+        image: None,
+        signals: Signals::new(),
+        launched_by_kernel: true,
+    }));
+    PROCESS_TABLE.write().insert(pid, process);
+    debug!("Created kernel thread with PID: {}", pid);
+    Ok(pid)
+}
+
+/// Creates a kernel thread running `entry` and schedules it on this core
+///
+/// # Returns
+/// The thread's PID
+pub fn spawn(entry: impl FnOnce() + Send + 'static) -> Result<u32, ProcessError> {
+    let pid = create(entry)?;
+    unsafe {
+        schedule_process(core_id(), run_process_ring3(pid), pid);
+    }
+    Ok(pid)
+}
+
+/// Blocks the calling kernel thread until `future` completes. The core
+/// runs other events in the meantime.
+///
+/// # Panics
+/// If not called from a kernel thread
+pub fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    wait_on(WaitReason::Other, future)
+}
+
+/// Like `block_on`, with the thread shown as waiting for `reason` in the
+/// meantime
+pub fn wait_on<F>(reason: WaitReason, future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    park(future, SwitchReason::Blocked, Some(reason))
+}
+
+/// Lets the other events queued on this core run before the calling kernel
+/// thread carries on
+pub fn yield_now() {
+    let mut yielded = false;
+    park(
+        poll_fn(move |_| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            // The runner requeues pending events, no wake needed
+            Poll::Pending
+        }),
+        SwitchReason::Yielded,
+        None,
+    );
+}
+
+/// Switches the calling kernel thread out until `future` completes,
+/// counting the switch as `reason`. The thread waits for `wait`, or stays
+/// runnable if there is nothing to wait for.
+fn park<F>(future: F, reason: SwitchReason, wait: Option<WaitReason>) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let pid = current();
+    let process = get_process(pid).expect("Kernel thread switch outside a kernel thread");
+    let pcb = process.pcb.get();
+    assert!(
+        unsafe { (*pcb).kernel_thread.is_some() },
+        "Kernel thread switch from a process"
+    );
+
+    let output = Arc::new(Mutex::new(None));
+    let slot = Arc::clone(&output);
+    let enabled = interrupts::are_enabled();
+    // Nothing can resume the thread before it has switched out. The
+    // resuming event is queued on this core, where interrupts are off, and
+    // other cores do not steal it while the thread's current event runs.
+    interrupts::disable();
+    unsafe {
+        schedule_process(
+            core_id(),
+            async move {
+                *slot.lock() = Some(future.await);
+                run_process_ring3(pid).await;
+            },
+            pid,
+        );
+        match wait {
+            Some(wait) => (*pcb).wait_for(wait),
+            None => (*pcb).state = ProcessState::Blocked,
+        }
+        (*pcb).registers.rflags = if enabled { 0x202 } else { 0x2 };
+        process.stats.enter_kernel();
+        process.stats.record_switch(reason);
+        switch_out(&mut (*pcb).registers, (*pcb).kernel_rsp);
+    }
+
+    let result = output.lock().take();
+    result.expect("Kernel thread resumed before its future completed")
+}
+
+/// A checkpoint for long loops in synchronous kernel code. Yields like
+/// `yield_now` if the caller is a kernel thread that has held its core past
+/// its budget, and does nothing otherwise, since only a kernel thread can
+/// be parked partway through. Only call it where no lock is held.
+pub fn yield_if_needed() {
+    if !should_yield() {
+        return;
+    }
+    let in_thread = get_process(current())
+        .is_ok_and(|process| unsafe { (*process.pcb.get()).kernel_thread.is_some() });
+    if in_thread {
+        yield_now();
+    }
+}
+
+/// PID of the kernel thread running on this core
+fn current() -> u32 {
+    without_interrupts(|| current_running_event_pid(core_id()))
+}
+
+/// The kernel's own PML4, which every kernel thread runs on
+fn kernel_pml4() -> PhysFrame<Size4KiB> {
+    let table = MAPPER.lock().level_4_table() as *const PageTable as u64;
+    PhysFrame::containing_address(PhysAddr::new(table - HHDM_OFFSET.as_u64()))
+}
+
+/// First code a kernel thread runs
+extern "C" fn thread_start(pid: u32) -> ! {
+    let entry = get_process(pid).ok().and_then(|process| {
+        without_interrupts(|| unsafe {
+            (*process.pcb.get())
+                .kernel_thread
+                .as_mut()
+                .and_then(|thread| thread.entry.take())
+        })
+    });
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    // The stack is freed once the event that ran the thread is off it
+    interrupts::disable();
+    let kernel_rsp = match get_process(pid) {
+        Ok(process) => unsafe {
+            let pcb = process.pcb.get();
+            (*pcb).state = ProcessState::Terminated;
+            (*pcb).kernel_rsp
+        },
+        Err(e) => panic!("Kernel thread {} lost its PCB: {:?}", pid, e),
+    };
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "jmp {1}",
+            in(reg) kernel_rsp,
+            sym return_process,
+            options(noreturn)
+        );
+    }
+}
+
+/// Saves the calling thread's callee-saved registers, and where it would
+/// return to, into `registers`, then returns to the event runner. Resuming
+/// from `registers` returns from this function.
+#[naked]
+unsafe extern "C" fn switch_out(registers: *mut Registers, kernel_rsp: u64) {
+    naked_asm!(
+        "mov [rdi + 8], rbx",
+        "mov [rdi + 80], r12",
+        "mov [rdi + 88], r13",
+        "mov [rdi + 96], r14",
+        "mov [rdi + 104], r15",
+        "mov [rdi + 112], rbp",
+        // The return address is on top of the stack
+        "mov rax, [rsp]",
+        "mov [rdi + 128], rax",
+        "lea rax, [rsp + 8]",
+        "mov [rdi + 120], rax",
+        "mov rsp, rsi",
+        "jmp {0}",
+        sym return_process,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processes::snapshot;
+    use core::{
+        pin::pin,
+        sync::atomic::{AtomicBool, Ordering},
+        task::Context,
+    };
+    use futures::task::noop_waker_ref;
+
+    #[test_case]
+    fn test_kernel_thread() {
+        static RAN: AtomicBool = AtomicBool::new(false);
+
+        let pid = create(|| RAN.store(true, Ordering::Relaxed)).unwrap();
+        let thread = snapshot(pid).unwrap();
+        assert!(thread.kernel_thread);
+        assert_eq!(thread.state, ProcessState::New);
+        {
+            let process = get_process(pid).unwrap();
+            let pcb = unsafe { &*process.pcb.get() };
+            assert_eq!(pcb.pml4_frame, kernel_pml4());
+            let stack = pcb.kernel_thread.as_ref().unwrap().stack();
+            assert!(stack.contains(&pcb.registers.rsp));
+            assert_eq!((pcb.registers.rsp + 8) % 16, 0);
+        }
+
+        // Runs to completion in one poll, since it never blocks, and is
+        // removed once it returns
+        let enabled = interrupts::are_enabled();
+        let mut run = pin!(unsafe { run_process_ring3(pid) });
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(run.as_mut().poll(&mut cx).is_ready());
+        if enabled {
+            interrupts::enable();
+        }
+        assert!(RAN.load(Ordering::Relaxed));
+        assert!(matches!(
+            get_process(pid),
+            Err(ProcessError::NotFound(p)) if p == pid
+        ));
+    }
+}
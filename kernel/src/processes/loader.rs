@@ -1,130 +1,424 @@
 use crate::{
     constants::{
         memory::PAGE_SIZE,
-        processes::{STACK_SIZE, STACK_START},
+        processes::{
+            MAX_STACK_SIZE, MMAP_START, NT_TAOS_FEATURES, NT_TAOS_HEAP_SIZE, NT_TAOS_STACK_SIZE,
+            STACK_SIZE, TAOS_NOTE_NAME,
+        },
+        syscalls::SUPPORTED_FEATURES,
     },
     memory::{
-        frame_allocator::with_generic_allocator,
-        paging::{create_mapping, update_permissions},
+        frame_allocator::{alloc_frame_zeroed, dealloc_frame},
+        paging::{map_frame, map_range, BORROWED_FRAME, COPY_ON_WRITE},
+        vma::{Heap, Vma},
+        HHDM_OFFSET,
     },
+    processes::{kthread::yield_if_needed, process::ProcessError},
+};
+use alloc::vec::Vec;
+use core::{
+    cmp::{max, min},
+    ptr::copy_nonoverlapping,
 };
-use core::ptr::{copy_nonoverlapping, write_bytes};
 use goblin::{
-    elf::Elf,
+    elf::{Elf, ProgramHeader},
     elf64::program_header::{PF_W, PF_X, PT_LOAD},
 };
 use x86_64::{
     structures::paging::{
-        mapper::CleanUp, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, page::PageRangeInclusive, Mapper, Page, PageTableFlags, PhysFrame,
+        Size4KiB,
     },
     VirtAddr,
 };
 
-// We import our new helper
-use crate::memory::paging::map_kernel_frame;
+/// Resource needs a binary declares in notes named `TAOS_NOTE_NAME`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHints {
+    /// Bytes of user stack, a whole number of pages
+    pub stack_size: usize,
+    /// Bytes of heap the binary expects to use, 0 if it did not say
+    pub heap_size: usize,
+    /// Syscall feature bits the binary needs
+    pub features: u64,
+}
 
-/// Function for initializing addresss space for process using ELF executable
-///
-/// # Arguments:
-/// * 'elf_bytes' - byte stream of ELF executable to parse
-/// * 'user_mapper' - Page table for user that maps VAs from section headers to frames
-/// * 'kernel mapper' - kernel page table for mapping VAs to frames for writing ELF metadata to frames
+impl Default for ImageHints {
+    /// What a binary without notes gets
+    fn default() -> Self {
+        ImageHints {
+            stack_size: STACK_SIZE,
+            heap_size: 0,
+            features: 0,
+        }
+    }
+}
+
+/// Reads the hints from an ELF executable's TAOS notes. Notes from other
+/// vendors are skipped, and anything left unsaid keeps its default.
 ///
-/// # Returns:
-/// Virtual address of the top of user stack and entry point for process
-pub fn load_elf(
-    elf_bytes: &[u8],
-    user_mapper: &mut impl Mapper<Size4KiB>,
-    kernel_mapper: &mut OffsetPageTable<'static>,
-) -> (VirtAddr, u64) {
-    let elf = Elf::parse(elf_bytes).expect("Parsing ELF failed");
-    for ph in elf.program_headers.iter() {
-        if ph.p_type != PT_LOAD {
+/// # Returns
+/// The hints, `ProcessError::NotExecutable` if the image, one of its
+/// loadable segments or a TAOS note is malformed, or a note asks for a
+/// stack over `MAX_STACK_SIZE`, or `ProcessError::Unsupported` with the
+/// feature bits this kernel lacks
+pub fn read_hints(elf_bytes: &[u8]) -> Result<ImageHints, ProcessError> {
+    let elf = Elf::parse(elf_bytes).map_err(|_| ProcessError::NotExecutable)?;
+    check_segments(&elf, elf_bytes.len())?;
+    let mut hints = ImageHints::default();
+    let Some(notes) = elf.iter_note_headers(elf_bytes) else {
+        return Ok(hints);
+    };
+
+    for note in notes {
+        let note = note.map_err(|_| ProcessError::NotExecutable)?;
+        if note.name != TAOS_NOTE_NAME {
             continue;
         }
-
-        let virt_addr = VirtAddr::new(ph.p_vaddr);
-        let mem_size = ph.p_memsz as usize;
-        let file_size = ph.p_filesz as usize;
-        let offset = ph.p_offset as usize;
-
-        let start_page = Page::containing_address(virt_addr);
-        let end_page = Page::containing_address(virt_addr + (mem_size - 1) as u64);
-
-        // Build final page flags
-        let default_flags =
-            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
-        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
-
-        // For each page in [start_page..end_page], create user mapping,
-        // then do a kernel alias to copy data in
-        for page in Page::range_inclusive(start_page, end_page) {
-            let frame = create_mapping(page, user_mapper, Some(default_flags));
-            let kernel_alias = map_kernel_frame(kernel_mapper, frame, default_flags);
-            // now `kernel_alias` is a kernel virtual address of that same frame
-
-            let page_offset =
-                page.start_address()
-                    .as_u64()
-                    .saturating_sub(start_page.start_address().as_u64()) as usize;
-            let page_remaining = PAGE_SIZE - (page_offset % PAGE_SIZE);
-            let to_copy = core::cmp::min(file_size.saturating_sub(page_offset), page_remaining);
-
-            if to_copy > 0 {
-                let dest = kernel_alias.as_mut_ptr::<u8>();
-                let src = &elf_bytes[offset + page_offset..offset + page_offset + to_copy];
-                unsafe {
-                    copy_nonoverlapping(src.as_ptr(), dest, to_copy);
+        // Every TAOS note holds a single little-endian u64
+        let value: [u8; 8] = note
+            .desc
+            .try_into()
+            .map_err(|_| ProcessError::NotExecutable)?;
+        let value = u64::from_le_bytes(value);
+        match note.n_type {
+            NT_TAOS_STACK_SIZE => {
+                if value > MAX_STACK_SIZE as u64 {
+                    return Err(ProcessError::NotExecutable);
                 }
+                hints.stack_size = (value as usize).next_multiple_of(PAGE_SIZE).max(PAGE_SIZE);
             }
+            NT_TAOS_HEAP_SIZE => hints.heap_size = value as usize,
+            NT_TAOS_FEATURES => hints.features = value,
+            // Left for newer kernels
+            _ => {}
+        }
+    }
 
-            let bss_start = file_size.saturating_sub(page_offset);
-            if bss_start < page_remaining {
-                // i.e. if this page has some leftover space beyond file_size
-                let zero_offset_in_page = core::cmp::max(bss_start, 0);
-                let zero_len = page_remaining.saturating_sub(zero_offset_in_page);
-                if zero_len > 0 {
-                    unsafe {
-                        let dest = kernel_alias.as_mut_ptr::<u8>().add(zero_offset_in_page);
-                        write_bytes(dest, 0, zero_len);
-                    }
-                }
+    let missing = hints.features & !SUPPORTED_FEATURES;
+    if missing != 0 {
+        return Err(ProcessError::Unsupported(missing));
+    }
+    Ok(hints)
+}
+
+/// Checks that every loadable segment of an executable, and its entry
+/// point, fit the file and the part of the user half below the mapping
+/// area. Executables can be written by any process, so nothing about them
+/// is trusted.
+fn check_segments(elf: &Elf, file_len: usize) -> Result<(), ProcessError> {
+    let fits = |ph: &ProgramHeader| {
+        let in_file = ph
+            .p_offset
+            .checked_add(ph.p_filesz)
+            .is_some_and(|end| end <= file_len as u64);
+        let in_user = ph
+            .p_vaddr
+            .checked_add(ph.p_memsz)
+            .is_some_and(|end| end <= MMAP_START);
+        in_file && in_user && ph.p_filesz <= ph.p_memsz
+    };
+    let loadable = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD && ph.p_memsz > 0);
+    if elf.header.e_entry >= MMAP_START || !loadable.clone().all(fits) {
+        return Err(ProcessError::NotExecutable);
+    }
+    Ok(())
+}
+
+/// Turns a failure to map part of an image into why loading it failed.
+/// Pages that are already mapped come from segments that overlap.
+fn map_error(error: MapToError<Size4KiB>) -> ProcessError {
+    match error {
+        MapToError::FrameAllocationFailed => ProcessError::OutOfMemory,
+        _ => ProcessError::NotExecutable,
+    }
+}
+
+/// A PT_LOAD segment of a program image
+#[derive(Debug)]
+struct Segment {
+    pages: PageRangeInclusive,
+    /// Flags the segment's pages are mapped with once loaded
+    flags: PageTableFlags,
+    /// Frames holding the segment's file data, one for each of its first
+    /// pages. The pages after them hold only bss.
+    frames: Vec<PhysFrame>,
+}
+
+/// The loadable segments of an ELF executable, read into frames once so
+/// any number of address spaces can map them
+///
+/// Mapped pages borrow the image's frames: pages of read-only segments map
+/// them directly, and pages of writable segments map them copy-on-write,
+/// so a process only gets private copies of the data it writes. Pages
+/// holding only bss are private to each address space from the start.
+/// The frames are freed with the image, so it must outlive every address
+/// space it is mapped into.
+#[derive(Debug)]
+pub struct ProgramImage {
+    segments: Vec<Segment>,
+    entry: u64,
+}
+
+impl ProgramImage {
+    /// Copies the file data of an ELF executable's loadable segments into
+    /// frames
+    ///
+    /// # Returns
+    /// The image, `ProcessError::NotExecutable` if a segment does not fit
+    /// the file or the user half, or `ProcessError::OutOfMemory` if the
+    /// frames could not be had, in which case those taken are freed
+    pub fn new(elf_bytes: &[u8]) -> Result<Self, ProcessError> {
+        let elf = Elf::parse(elf_bytes).map_err(|_| ProcessError::NotExecutable)?;
+        check_segments(&elf, elf_bytes.len())?;
+        let mut image = ProgramImage {
+            segments: Vec::new(),
+            entry: elf.header.e_entry,
+        };
+        for ph in elf.program_headers.iter() {
+            if ph.p_type != PT_LOAD || ph.p_memsz == 0 {
+                continue;
             }
+            yield_if_needed();
 
+            let virt_addr = VirtAddr::new(ph.p_vaddr);
+            let file_size = ph.p_filesz as usize;
+            let offset = ph.p_offset as usize;
+            let start_page = Page::containing_address(virt_addr);
+            let end_page = Page::containing_address(virt_addr + (ph.p_memsz - 1));
+            let pages = Page::range_inclusive(start_page, end_page);
+            // Where the segment starts within its first page
+            let lead = (ph.p_vaddr % PAGE_SIZE as u64) as usize;
+
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
             if (ph.p_flags & PF_W) != 0 {
                 flags |= PageTableFlags::WRITABLE;
             }
             if (ph.p_flags & PF_X) == 0 {
                 flags |= PageTableFlags::NO_EXECUTE;
             }
-            update_permissions(page, user_mapper, flags);
-
-            let unmap_page: Page<Size4KiB> = Page::containing_address(kernel_alias);
-            // unmap the frame, but do not actually deallocate it
-            // the physical frame is still used by the process in its own mapping
-            kernel_mapper
-                .unmap(unmap_page)
-                .expect("Unmapping kernel frame failed")
-                .1
-                .flush();
-            with_generic_allocator(|allocator| unsafe { kernel_mapper.clean_up(allocator) });
-
-            update_permissions(page, user_mapper, flags);
+
+            let data_pages = if file_size == 0 {
+                0
+            } else {
+                min((lead + file_size).div_ceil(PAGE_SIZE), pages.len() as usize)
+            };
+            // Pushed before its frames are filled in, so dropping the image
+            // frees them if one cannot be had
+            image.segments.push(Segment {
+                pages,
+                flags,
+                frames: Vec::with_capacity(data_pages),
+            });
+            let segment = image.segments.last_mut().expect("Segment was just pushed");
+            for index in 0..data_pages {
+                // Zeroed, so the part of the last page past the file data is
+                // already the start of the bss
+                let frame = alloc_frame_zeroed().ok_or(ProcessError::OutOfMemory)?;
+                segment.frames.push(frame);
+                // The file data in this page, as offsets from the start of
+                // the first page
+                let first = max(index * PAGE_SIZE, lead);
+                let end = min((index + 1) * PAGE_SIZE, lead + file_size);
+                let src = &elf_bytes[offset + first - lead..offset + end - lead];
+                let dest =
+                    *HHDM_OFFSET + frame.start_address().as_u64() + (first % PAGE_SIZE) as u64;
+                unsafe { copy_nonoverlapping(src.as_ptr(), dest.as_mut_ptr::<u8>(), src.len()) };
+            }
+        }
+        Ok(image)
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// The first page past every segment, where the heap starts
+    pub fn end(&self) -> VirtAddr {
+        self.segments
+            .iter()
+            .map(|segment| segment.pages.end + 1)
+            .max()
+            .map_or(VirtAddr::zero(), |page| page.start_address())
+    }
+}
+
+impl Drop for ProgramImage {
+    fn drop(&mut self) {
+        for segment in &mut self.segments {
+            segment.frames.drain(..).for_each(dealloc_frame);
+        }
+    }
+}
+
+/// Function for initializing addresss space for process using ELF executable
+///
+/// # Arguments:
+/// * 'image' - the executable's segments, which must outlive the address space
+/// * 'stack' - the area the user stack may grow through
+/// * 'user_mapper' - Page table for user that maps VAs from section headers to frames
+///
+/// # Returns:
+/// Virtual address of the top of user stack, entry point for process, and
+/// the empty heap just past its highest segment.
+/// `ProcessError::OutOfMemory` if a frame or page table could not be had,
+/// or `ProcessError::NotExecutable` if segments overlap. Whatever was
+/// mapped is left for the caller to free with the address space.
+pub fn load_elf(
+    image: &ProgramImage,
+    stack: &Vma,
+    user_mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(VirtAddr, u64, Heap), ProcessError> {
+    for segment in &image.segments {
+        let shared_flags = if segment.flags.contains(PageTableFlags::WRITABLE) {
+            (segment.flags - PageTableFlags::WRITABLE) | BORROWED_FRAME | COPY_ON_WRITE
+        } else {
+            segment.flags | BORROWED_FRAME
+        };
+        for (page, &frame) in segment.pages.zip(&segment.frames) {
+            map_frame(page, frame, user_mapper, shared_flags).map_err(map_error)?;
+        }
+
+        // The rest of the segment is bss, mapped to zeroed frames of its own
+        let first_bss = segment.pages.start + segment.frames.len() as u64;
+        if first_bss <= segment.pages.end {
+            map_range(
+                Page::range_inclusive(first_bss, segment.pages.end),
+                user_mapper,
+                segment.flags,
+            )
+            .map_err(map_error)?;
         }
     }
 
-    // Map user stack
-    let stack_start = VirtAddr::new(STACK_START);
-    let stack_end = VirtAddr::new(STACK_START + STACK_SIZE as u64);
-    let start_page = Page::containing_address(stack_start);
-    let end_page = Page::containing_address(stack_end);
+    // Only the top of the stack is mapped up front, the page fault handler
+    // maps the rest of the area as the stack grows into it
+    let top_page = stack.top_page();
+    map_range(
+        Page::range_inclusive(top_page, top_page),
+        user_mapper,
+        stack.flags,
+    )
+    .map_err(map_error)?;
+
+    Ok((stack.end, image.entry, Heap::new(image.end())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{constants::syscalls::FEATURE_PROCESSES, processes::test_binaries};
+
+    #[test_case]
+    fn test_read_hints() {
+        let hints = read_hints(test_binaries::by_name("big_stack").unwrap()).unwrap();
+        assert_eq!(
+            hints,
+            ImageHints {
+                stack_size: 0x10000,
+                heap_size: 0x4000,
+                features: FEATURE_PROCESSES,
+            }
+        );
+
+        let plain = test_binaries::by_name("rand_regs").unwrap();
+        assert_eq!(read_hints(plain).unwrap(), ImageHints::default());
+
+        let future = test_binaries::by_name("future_syscalls").unwrap();
+        assert!(matches!(
+            read_hints(future),
+            Err(ProcessError::Unsupported(missing)) if missing == 1 << 63
+        ));
+    }
+    /// Returns big_stack with its stack size note set to `size`
+    fn with_stack_hint(size: u64) -> Vec<u8> {
+        let mut elf = test_binaries::by_name("big_stack").unwrap().to_vec();
+        let mut header = Vec::new();
+        header.extend_from_slice(&5u32.to_le_bytes());
+        header.extend_from_slice(&8u32.to_le_bytes());
+        header.extend_from_slice(&NT_TAOS_STACK_SIZE.to_le_bytes());
+        header.extend_from_slice(b"TAOS\0\0\0\0");
+        let note = elf
+            .windows(header.len())
+            .position(|window| window == header)
+            .expect("big_stack has a stack size note");
+        let desc = note + header.len();
+        elf[desc..desc + 8].copy_from_slice(&size.to_le_bytes());
+        elf
+    }
+
+    /// Returns rand_regs with one field of its first loadable segment's
+    /// program header, at `field` bytes into the header, set to `value`
+    fn with_segment_field(field: usize, value: u64) -> Vec<u8> {
+        let mut elf = test_binaries::by_name("rand_regs").unwrap().to_vec();
+        let parsed = Elf::parse(&elf).unwrap();
+        let index = parsed
+            .program_headers
+            .iter()
+            .position(|ph| ph.p_type == PT_LOAD)
+            .unwrap();
+        let header =
+            parsed.header.e_phoff as usize + index * parsed.header.e_phentsize as usize + field;
+        elf[header..header + 8].copy_from_slice(&value.to_le_bytes());
+        elf
+    }
 
-    let stack_flags =
-        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    #[test_case]
+    fn test_untrusted_segments() {
+        // Offsets of fields in an ELF64 program header
+        const P_OFFSET: usize = 8;
+        const P_VADDR: usize = 16;
+        const P_FILESZ: usize = 32;
+        const P_MEMSZ: usize = 40;
 
-    for page in Page::range_inclusive(start_page, end_page) {
-        create_mapping(page, user_mapper, Some(stack_flags));
+        let plain = test_binaries::by_name("rand_regs").unwrap();
+        assert!(ProgramImage::new(plain).is_ok());
+        let bad = [
+            // Data past the end of the file, or past the end of memory
+            with_segment_field(P_OFFSET, plain.len() as u64),
+            with_segment_field(P_OFFSET, u64::MAX),
+            // More file data than memory to hold it
+            with_segment_field(P_MEMSZ, 1),
+            with_segment_field(P_FILESZ, u64::MAX),
+            // Outside the part of the user half segments may use
+            with_segment_field(P_VADDR, 0xFFFF_8000_0000_0000),
+            with_segment_field(P_VADDR, 0x8000_0000_0000_0000),
+            with_segment_field(P_VADDR, MMAP_START - 1),
+            with_segment_field(P_MEMSZ, MMAP_START),
+        ];
+        for elf in &bad {
+            assert!(matches!(read_hints(elf), Err(ProcessError::NotExecutable)));
+            assert!(matches!(
+                ProgramImage::new(elf),
+                Err(ProcessError::NotExecutable)
+            ));
+        }
+
+        // An entry point in the kernel half is refused as well
+        let mut elf = plain.to_vec();
+        elf[24..32].copy_from_slice(&0xFFFF_FFFF_8000_0000u64.to_le_bytes());
+        assert!(matches!(read_hints(&elf), Err(ProcessError::NotExecutable)));
+        assert!(matches!(
+            ProgramImage::new(b"not an elf"),
+            Err(ProcessError::NotExecutable)
+        ));
     }
 
-    (stack_end, elf.header.e_entry)
+    #[test_case]
+    fn test_stack_hint_limit() {
+        let hints = read_hints(&with_stack_hint(MAX_STACK_SIZE as u64)).unwrap();
+        assert_eq!(hints.stack_size, MAX_STACK_SIZE);
+        // Sizes are rounded up to whole pages, and never below one
+        let hints = read_hints(&with_stack_hint(1)).unwrap();
+        assert_eq!(hints.stack_size, PAGE_SIZE);
+
+        let over = with_stack_hint(MAX_STACK_SIZE as u64 + 1);
+        assert!(matches!(
+            read_hints(&over),
+            Err(ProcessError::NotExecutable)
+        ));
+    }
 }
@@ -1,25 +1,613 @@
+pub mod cgroup;
+pub mod fd_table;
+pub mod image_cache;
+pub mod kthread;
 pub mod loader;
+pub mod pid;
 pub mod process;
 pub mod registers;
+pub mod rusage;
+pub mod signal;
+pub mod test_binaries;
+
+pub use process::{for_each, snapshot, ProcessSnapshot};
 
 #[cfg(test)]
 mod tests {
+    use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+    use core::sync::atomic::{AtomicBool, Ordering};
+
     use crate::{
-        constants::processes::INFINITE_LOOP,
+        constants::{
+            events::NUM_EVENT_PRIORITIES,
+            memory::PAGE_SIZE,
+            processes::{MAX_STACK_SIZE, MMAP_START, PROCESS_DEFAULT_PRIORITY, STACK_START},
+            syscalls::{PROT_READ, PROT_WRITE},
+        },
+        error::ErrorKind,
         events::schedule_process,
+        filesys::{
+            block::memory::MemoryBlockDevice,
+            fat16::Fat16,
+            vfs::{self, MountOptions},
+            BlockDevice, FsError,
+        },
         interrupts::x2apic,
-        processes::process::{create_process, run_process_ring3},
+        memory::{
+            mmap::{protection_to_pagetable_flags, MappingFault},
+            paging::{copy_on_write, BORROWED_FRAME, COPY_ON_WRITE},
+            vma::Vma,
+            HHDM_OFFSET,
+        },
+        processes::{
+            fd_table::VfsFile,
+            for_each, image_cache,
+            process::{
+                clear_process_frames, count_user_pages, create_child_process, create_process,
+                create_process_from_path, exec_process, fault_mapping, get_process, grow_heap,
+                grow_stack, map_file, niced_priority, protect_mappings, reap_child,
+                release_mappings, remove_process, run_process_ring3, set_brk, sync_mappings,
+                terminate_process, unmap_mappings, ProcessError, ProcessState, WaitReason, PCB,
+            },
+            snapshot, test_binaries,
+        },
+    };
+    use x86_64::{
+        structures::paging::{
+            mapper::{MappedFrame, TranslateResult},
+            Page, PageTableFlags, PhysFrame, Translate,
+        },
+        VirtAddr,
     };
 
     #[test_case]
     fn test_simple_process() {
         let cpuid = x2apic::current_core_id() as u32;
 
-        let pid = create_process(INFINITE_LOOP);
+        let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
         unsafe {
             schedule_process(cpuid, run_process_ring3(pid), pid);
         }
 
         assert!(matches!(cpuid, 0));
     }
+
+    #[test_case]
+    fn test_nice_bounds() {
+        let lowest = NUM_EVENT_PRIORITIES - 1;
+
+        assert_eq!(
+            niced_priority(PROCESS_DEFAULT_PRIORITY, 0),
+            Some(PROCESS_DEFAULT_PRIORITY)
+        );
+        assert_eq!(niced_priority(PROCESS_DEFAULT_PRIORITY, 19), Some(lowest));
+        // A niced process may return to the default, but not go above it
+        assert_eq!(niced_priority(lowest, -19), None);
+        assert_eq!(
+            niced_priority(lowest, PROCESS_DEFAULT_PRIORITY as i64 - lowest as i64),
+            Some(PROCESS_DEFAULT_PRIORITY)
+        );
+        assert_eq!(niced_priority(PROCESS_DEFAULT_PRIORITY, -1), None);
+    }
+
+    #[test_case]
+    fn test_process_snapshots() {
+        let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+        let process = snapshot(pid).unwrap();
+        assert!(matches!(process.state, ProcessState::New));
+        assert_eq!(process.priority, PROCESS_DEFAULT_PRIORITY);
+        // Only the standard streams are open
+        assert_eq!(process.open_files, 3);
+
+        let mut seen = 0;
+        for_each(|other, _| seen += (other == pid) as usize);
+        assert_eq!(seen, 1);
+
+        let pcb = get_process(pid).unwrap();
+        unsafe { (*pcb.pcb.get()).wait_for(WaitReason::BlockIo) };
+        let process = snapshot(pid).unwrap();
+        assert_eq!(process.state, ProcessState::Waiting);
+        assert_eq!(process.wait_reason, Some(WaitReason::BlockIo));
+        drop(pcb);
+
+        remove_process(pid);
+        assert!(matches!(snapshot(pid), Err(ProcessError::NotFound(p)) if p == pid));
+    }
+
+    #[test_case]
+    fn test_exec_rejects_non_elf() {
+        let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+        let process = get_process(pid).unwrap();
+        let pcb = unsafe { &mut *process.pcb.get() };
+        let (pml4_frame, rip) = (pcb.pml4_frame, pcb.registers.rip);
+
+        // The old image is only torn down once the new one is known to load
+        let result = unsafe { exec_process(pcb, b"#!/bin/sh\n", None) };
+        assert!(matches!(result, Err(ProcessError::NotExecutable)));
+        assert_eq!(pcb.pml4_frame, pml4_frame);
+        assert_eq!(pcb.registers.rip, rip);
+
+        drop(process);
+        remove_process(pid);
+    }
+
+    #[test_case]
+    fn test_wait_for_children() {
+        let elf = test_binaries::by_name("rand_regs").unwrap();
+        let exit = |pid, code| {
+            let process = get_process(pid).unwrap();
+            clear_process_frames(unsafe { &mut *process.pcb.get() });
+            terminate_process(pid, code);
+        };
+        let parent = create_process(elf).unwrap();
+        let first = create_child_process(elf, parent).unwrap();
+        let second = create_child_process(elf, parent).unwrap();
+        assert!(matches!(reap_child(parent, None), Ok(None)));
+
+        // An exited child stays around with its exit code until reaped
+        exit(first, 3);
+        assert!(matches!(
+            snapshot(first).unwrap().state,
+            ProcessState::Zombie
+        ));
+        assert!(matches!(reap_child(parent, Some(second)), Ok(None)));
+        assert!(matches!(reap_child(parent, None), Ok(Some((pid, 3))) if pid == first));
+        assert!(matches!(snapshot(first), Err(ProcessError::NotFound(_))));
+
+        // Children left behind by an exiting parent are reaped for it
+        exit(second, 0);
+        exit(parent, 0);
+        assert!(matches!(snapshot(second), Err(ProcessError::NotFound(_))));
+        assert!(matches!(snapshot(parent), Err(ProcessError::NotFound(_))));
+        assert!(matches!(
+            reap_child(parent, None),
+            Err(ProcessError::NoChildren)
+        ));
+    }
+
+    #[test_case]
+    fn test_stack_size_hint() {
+        let pid = create_process(test_binaries::by_name("big_stack").unwrap()).unwrap();
+        let process = get_process(pid).unwrap();
+        let pcb = unsafe { &mut *process.pcb.get() };
+        assert_eq!(pcb.hints.stack_size, 0x10000);
+
+        // The stack area covers the requested size, but only its top page
+        // is mapped until the rest is touched
+        let stack_top = VirtAddr::new(STACK_START + pcb.hints.stack_size as u64);
+        assert_eq!(pcb.stack, Some(Vma::stack(0x10000)));
+        assert_eq!(pcb.registers.rsp, stack_top.as_u64());
+        let bottom = VirtAddr::new(STACK_START);
+        let mapped = |pcb: &mut PCB, addr| unsafe { pcb.create_mapper() }.translate_addr(addr);
+        assert!(mapped(pcb, stack_top - 8u64).is_some());
+        assert!(mapped(pcb, bottom).is_none());
+        let pages = count_user_pages(pcb);
+
+        assert!(grow_stack(pcb, bottom + 100u64));
+        assert!(mapped(pcb, bottom).is_some());
+        assert_eq!(count_user_pages(pcb), pages + 1);
+        // Mapped pages, and anything past the area, are not the stack's
+        assert!(!grow_stack(pcb, bottom));
+        assert!(!grow_stack(pcb, bottom - 1u64));
+        assert!(!grow_stack(pcb, stack_top));
+
+        clear_process_frames(pcb);
+        drop(process);
+        remove_process(pid);
+
+        let future = test_binaries::by_name("future_syscalls").unwrap();
+        assert!(matches!(
+            create_process(future),
+            Err(ProcessError::Unsupported(_))
+        ));
+    }
+
+    #[test_case]
+    fn test_stack_growth() {
+        let pid = create_process(test_binaries::by_name("big_stack").unwrap()).unwrap();
+        let process = get_process(pid).unwrap();
+        let pcb = unsafe { &mut *process.pcb.get() };
+        let stack = pcb.stack.unwrap();
+        let mapped = |pcb: &mut PCB, addr| unsafe { pcb.create_mapper() }.translate_addr(addr);
+        let pages = count_user_pages(pcb);
+
+        // The stack grows a page at a time down to the bottom of its area,
+        // and no further than the size the binary asked for
+        let area_pages = (stack.end - stack.start) / PAGE_SIZE as u64;
+        for page in (0..area_pages - 1).rev() {
+            assert!(grow_stack(pcb, stack.start + page * PAGE_SIZE as u64));
+        }
+        assert_eq!(count_user_pages(pcb), pages + area_pages - 1);
+
+        // The page below the area is a guard, left unmapped for an overflow
+        // to fault on
+        let guard = stack.start - PAGE_SIZE as u64;
+        assert!(!grow_stack(pcb, guard));
+        assert!(!grow_stack(pcb, stack.start - 1u64));
+        assert!(mapped(pcb, guard).is_none());
+        let limit = VirtAddr::new(STACK_START + MAX_STACK_SIZE as u64);
+        assert!(!grow_stack(pcb, stack.end));
+        assert!(!grow_stack(pcb, limit - 1u64));
+        assert!(mapped(pcb, stack.end).is_none());
+
+        // Faults anywhere else are left to the other handlers
+        let heap = pcb.heap.unwrap();
+        set_brk(pcb, heap.start + PAGE_SIZE as u64).unwrap();
+        assert!(!grow_stack(pcb, heap.start));
+        assert!(grow_heap(pcb, heap.start));
+        assert!(!grow_stack(pcb, VirtAddr::new(MMAP_START)));
+        assert!(!grow_stack(pcb, VirtAddr::zero()));
+        assert!(!grow_stack(pcb, VirtAddr::new(0xFFFF_8000_0000_0000)));
+
+        // as are all faults of a process without a stack area
+        pcb.stack = None;
+        let pages = count_user_pages(pcb);
+        assert!(!grow_stack(pcb, stack.start));
+        assert_eq!(count_user_pages(pcb), pages);
+        pcb.stack = Some(stack);
+
+        clear_process_frames(pcb);
+        drop(process);
+        remove_process(pid);
+    }
+
+    #[test_case]
+    fn test_program_break() {
+        let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+        let process = get_process(pid).unwrap();
+        let pcb = unsafe { &mut *process.pcb.get() };
+        let heap = pcb.heap.unwrap();
+        assert_eq!(heap.brk, heap.start);
+        assert!(heap.start.is_aligned(PAGE_SIZE as u64));
+        assert!(!grow_heap(pcb, heap.start));
+        let pages = count_user_pages(pcb);
+
+        // Pages under the break are mapped when first touched
+        set_brk(pcb, heap.start + 0x1800u64).unwrap();
+        assert!(grow_heap(pcb, heap.start + 0x1000u64));
+        assert!(!grow_heap(pcb, heap.start + 0x1000u64));
+        assert!(!grow_heap(pcb, heap.start + 0x2000u64));
+        assert_eq!(count_user_pages(pcb), pages + 1);
+
+        // and freed as soon as the break drops below them
+        set_brk(pcb, heap.start + 0x800u64).unwrap();
+        assert_eq!(count_user_pages(pcb), pages);
+        assert!(set_brk(pcb, heap.start - 1u64).is_err());
+        assert!(set_brk(pcb, VirtAddr::new(MMAP_START + 1)).is_err());
+        assert_eq!(pcb.heap.unwrap().brk, heap.start + 0x800u64);
+
+        clear_process_frames(pcb);
+        assert!(pcb.heap.is_none());
+        drop(process);
+        remove_process(pid);
+    }
+
+    #[test_case]
+    fn test_mapped_file_writeback() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        vfs::mount("/mmap", Box::new(fs), MountOptions::empty()).unwrap();
+        let mut expected: Vec<u8> = (0..PAGE_SIZE + 100).map(|i| i as u8).collect();
+        let file = {
+            let mut vfs = vfs::lock();
+            vfs.create_file("/mmap/data").unwrap();
+            let fd = vfs.open("/mmap/data").unwrap();
+            assert_eq!(vfs.write(fd, &expected).unwrap(), expected.len());
+            fd
+        };
+        let file = VfsFile { fd: file };
+        let read_back = || {
+            let mut vfs = vfs::lock();
+            let fd = vfs.open("/mmap/data").unwrap();
+            let mut data = vec![0; 2 * PAGE_SIZE];
+            let mut read = 0;
+            loop {
+                match vfs.read(fd, &mut data[read..]).unwrap() {
+                    0 => break,
+                    count => read += count,
+                }
+            }
+            vfs.close(fd).unwrap();
+            data.truncate(read);
+            data
+        };
+        let page = |pcb: &PCB, addr: VirtAddr| {
+            let mapper = unsafe { pcb.create_mapper() };
+            let TranslateResult::Mapped { frame, flags, .. } = mapper.translate(addr) else {
+                panic!("Page is not mapped");
+            };
+            let data = unsafe {
+                core::slice::from_raw_parts_mut(
+                    (*HHDM_OFFSET + frame.start_address().as_u64()).as_mut_ptr::<u8>(),
+                    PAGE_SIZE,
+                )
+            };
+            (data, flags.contains(PageTableFlags::WRITABLE))
+        };
+
+        let read_only = protection_to_pagetable_flags(PROT_READ).unwrap();
+        let read_write = protection_to_pagetable_flags(PROT_READ | PROT_WRITE).unwrap();
+        let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+        let process = get_process(pid).unwrap();
+        let pcb = unsafe { &mut *process.pcb.get() };
+        let own = VfsFile {
+            fd: vfs::lock().reopen(file.fd).unwrap(),
+        };
+        let start = map_file(pcb, own, expected.len() as u64, 0, read_write, true).unwrap();
+        assert_eq!(start, VirtAddr::new(MMAP_START));
+
+        // Reading maps a clean copy of the file, which a write dirties
+        assert_eq!(
+            fault_mapping(pcb, start, false),
+            Some(MappingFault::PagedIn)
+        );
+        let (data, writable) = page(pcb, start);
+        assert_eq!(data, &expected[..PAGE_SIZE]);
+        assert!(!writable);
+        assert_eq!(fault_mapping(pcb, start, true), Some(MappingFault::Dirtied));
+        assert!(page(pcb, start).1);
+        data[..5].copy_from_slice(b"hello");
+
+        // The file only changes once synced, and the page is clean again
+        assert_ne!(&read_back()[..5], b"hello");
+        sync_mappings(pcb, start, PAGE_SIZE as u64).unwrap();
+        expected[..5].copy_from_slice(b"hello");
+        assert_eq!(read_back(), expected);
+        assert!(!page(pcb, start).1);
+
+        // Writing an unmapped page reads it in dirty. What lies past the
+        // end of the file is not written back.
+        let second = start + PAGE_SIZE as u64;
+        assert_eq!(
+            fault_mapping(pcb, second + 50u64, true),
+            Some(MappingFault::PagedIn)
+        );
+        let (data, writable) = page(pcb, second);
+        assert!(writable);
+        assert_eq!(data[100], 0);
+        data[99..102].fill(0xAA);
+        expected[PAGE_SIZE + 99] = 0xAA;
+
+        // A read-only mapping refuses writes, and a range with a hole is
+        // not synced
+        let own = VfsFile {
+            fd: vfs::lock().reopen(file.fd).unwrap(),
+        };
+        let other = map_file(pcb, own, PAGE_SIZE as u64, 0, read_only, false).unwrap();
+        assert_eq!(other, start + 2 * PAGE_SIZE as u64);
+        assert_eq!(fault_mapping(pcb, other, true), None);
+        let error = sync_mappings(pcb, start, 4 * PAGE_SIZE as u64).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::OutOfMemory);
+        assert_ne!(read_back(), expected);
+
+        // Taking write access away writes the page back first, and splits
+        // the mapping around it
+        protect_mappings(pcb, second, PAGE_SIZE as u64, read_only).unwrap();
+        assert_eq!(pcb.mappings.len(), 3);
+        assert_eq!(read_back(), expected);
+        assert!(!page(pcb, second).1);
+        assert_eq!(fault_mapping(pcb, second, true), None);
+        let error = protect_mappings(pcb, start, 4 * PAGE_SIZE as u64, read_write).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::OutOfMemory);
+        assert_eq!(pcb.mappings.len(), 3);
+
+        // Unmapping writes back and frees the pages in the range, leaving
+        // the rest of the mapping
+        assert_eq!(fault_mapping(pcb, start, true), Some(MappingFault::Dirtied));
+        page(pcb, start).0[..5].copy_from_slice(b"world");
+        expected[..5].copy_from_slice(b"world");
+        unmap_mappings(pcb, start, start + 1u64).unwrap();
+        assert_eq!(read_back(), expected);
+        assert_eq!(pcb.mappings.len(), 2);
+        assert!(unsafe { pcb.create_mapper() }
+            .translate_addr(start)
+            .is_none());
+        assert_eq!(fault_mapping(pcb, start, false), None);
+        assert_eq!(page(pcb, second).0[99], 0xAA);
+
+        release_mappings(pcb);
+        assert!(pcb.mappings.is_empty());
+
+        clear_process_frames(pcb);
+        drop(process);
+        remove_process(pid);
+        drop(file);
+        vfs::umount("/mmap").unwrap();
+    }
+
+    /// A memory device whose writes fail while `failing` is set
+    struct FailingDevice {
+        inner: MemoryBlockDevice,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl BlockDevice for FailingDevice {
+        fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+            self.inner.read_block(block_num, buf)
+        }
+
+        fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+            if self.failing.load(Ordering::Relaxed) {
+                return Err(FsError::IOError);
+            }
+            self.inner.write_block(block_num, buf)
+        }
+
+        fn block_size(&self) -> usize {
+            self.inner.block_size()
+        }
+
+        fn total_blocks(&self) -> u64 {
+            self.inner.total_blocks()
+        }
+    }
+
+    #[test_case]
+    fn test_mapping_write_back_failure() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let device = Box::new(FailingDevice {
+            inner: MemoryBlockDevice::new(256, 512),
+            failing: Arc::clone(&failing),
+        });
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        vfs::mount("/mmapfail", Box::new(fs), MountOptions::empty()).unwrap();
+        let contents = vec![0x11; 2 * PAGE_SIZE];
+        let fd = {
+            let mut vfs = vfs::lock();
+            vfs.create_file("/mmapfail/data").unwrap();
+            let fd = vfs.open("/mmapfail/data").unwrap();
+            assert_eq!(vfs.write(fd, &contents).unwrap(), contents.len());
+            fd
+        };
+        let writable = |pcb: &PCB, addr: VirtAddr| {
+            let TranslateResult::Mapped { flags, .. } =
+                unsafe { pcb.create_mapper() }.translate(addr)
+            else {
+                panic!("Page is not mapped");
+            };
+            flags.contains(PageTableFlags::WRITABLE)
+        };
+
+        let read_only = protection_to_pagetable_flags(PROT_READ).unwrap();
+        let read_write = protection_to_pagetable_flags(PROT_READ | PROT_WRITE).unwrap();
+        let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+        let process = get_process(pid).unwrap();
+        let pcb = unsafe { &mut *process.pcb.get() };
+        let len = contents.len() as u64;
+        let start = map_file(pcb, VfsFile { fd }, len, 0, read_write, true).unwrap();
+        let second = start + PAGE_SIZE as u64;
+        for page in [start, second] {
+            assert_eq!(fault_mapping(pcb, page, true), Some(MappingFault::PagedIn));
+        }
+
+        // Neither taking write access away nor unmapping gets anywhere
+        // while dirty pages cannot be written back
+        failing.store(true, Ordering::Relaxed);
+        let error = protect_mappings(pcb, second, PAGE_SIZE as u64, read_only).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Io);
+        assert_eq!(pcb.mappings.len(), 1);
+        assert_eq!(pcb.mappings[0].vma.flags, read_write);
+        assert!(writable(pcb, second));
+        assert!(unmap_mappings(pcb, start, start + len).is_err());
+        assert_eq!(pcb.mappings.len(), 1);
+        assert!(writable(pcb, start));
+
+        // Both go through once the device works again
+        failing.store(false, Ordering::Relaxed);
+        protect_mappings(pcb, second, PAGE_SIZE as u64, read_only).unwrap();
+        assert_eq!(pcb.mappings.len(), 2);
+        assert!(!writable(pcb, second));
+        unmap_mappings(pcb, start, start + len).unwrap();
+        assert!(pcb.mappings.is_empty());
+
+        clear_process_frames(pcb);
+        drop(process);
+        remove_process(pid);
+        vfs::umount("/mmapfail").unwrap();
+    }
+
+    #[test_case]
+    fn test_shared_program_image() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        vfs::mount("/images", Box::new(fs), MountOptions::empty()).unwrap();
+        let elf = test_binaries::by_name("syscall_conformance").unwrap();
+        let write_program = |bytes: &[u8]| {
+            let mut vfs = vfs::lock();
+            let fd = vfs.open("/images/prog").unwrap();
+            assert_eq!(vfs.write(fd, bytes).unwrap(), bytes.len());
+            vfs.close(fd).unwrap();
+        };
+        vfs::lock().create_file("/images/prog").unwrap();
+        write_program(elf);
+        let page = |pcb: &PCB, addr: u64| -> (PhysFrame, PageTableFlags) {
+            let mapper = unsafe { pcb.create_mapper() };
+            let TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } = mapper.translate(VirtAddr::new(addr))
+            else {
+                panic!("Page is not mapped");
+            };
+            (frame, flags)
+        };
+
+        let pids = [(); 2].map(|()| create_process_from_path("/images/prog").unwrap());
+        let processes = pids.map(|pid| get_process(pid).unwrap());
+        let [first, second] = processes
+            .each_ref()
+            .map(|process| unsafe { &mut *process.pcb.get() });
+        assert!(Arc::ptr_eq(
+            first.image.as_ref().unwrap(),
+            second.image.as_ref().unwrap()
+        ));
+
+        // Text is shared read-only, and data copy-on-write
+        let (text, flags) = page(first, 0x401000);
+        assert_eq!(page(second, 0x401000).0, text);
+        assert!(flags.contains(BORROWED_FRAME));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        let (data, flags) = page(first, 0x404000);
+        assert_eq!(page(second, 0x404000).0, data);
+        assert!(flags.contains(COPY_ON_WRITE));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        // The initialized data sits where the segment says, not at the
+        // start of its page
+        let initialized = unsafe {
+            core::slice::from_raw_parts(
+                (*HHDM_OFFSET + data.start_address().as_u64() + 0x538).as_ptr::<u8>(),
+                0x30,
+            )
+        };
+        assert_eq!(initialized, &elf[0x3538..0x3568]);
+        // Pages holding only bss are private from the start
+        let (bss, flags) = page(first, 0x405000);
+        assert_ne!(page(second, 0x405000).0, bss);
+        assert!(!flags.contains(BORROWED_FRAME));
+
+        // The first write gives the process a copy of its own
+        let data_page = Page::containing_address(VirtAddr::new(0x404000));
+        assert!(copy_on_write(data_page, &mut unsafe {
+            first.create_mapper()
+        }));
+        let (copy, flags) = page(first, 0x404000);
+        assert_ne!(copy, data);
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+        assert_eq!(page(second, 0x404000).0, data);
+
+        // A changed binary gets a new image
+        write_program(&[elf, b"\0"].concat());
+        let pid = create_process_from_path("/images/prog").unwrap();
+        let process = get_process(pid).unwrap();
+        let third = unsafe { &mut *process.pcb.get() };
+        assert!(!Arc::ptr_eq(
+            first.image.as_ref().unwrap(),
+            third.image.as_ref().unwrap()
+        ));
+
+        // So does one of the same size changed within FAT's two second
+        // modification time
+        write_program(&[elf, b"\x01"].concat());
+        let changed_pid = create_process_from_path("/images/prog").unwrap();
+        let changed_process = get_process(changed_pid).unwrap();
+        let fourth = unsafe { &mut *changed_process.pcb.get() };
+        assert!(!Arc::ptr_eq(
+            third.image.as_ref().unwrap(),
+            fourth.image.as_ref().unwrap()
+        ));
+
+        // The image outlives the processes sharing it, until dropped
+        for pcb in [first, second, third, fourth] {
+            clear_process_frames(pcb);
+        }
+        assert!(image_cache::drop_unused() >= 1);
+        drop(processes);
+        drop(process);
+        drop(changed_process);
+        for pid in pids.into_iter().chain([pid, changed_pid]) {
+            remove_process(pid);
+        }
+        vfs::umount("/images").unwrap();
+    }
 }
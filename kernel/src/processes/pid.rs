@@ -0,0 +1,149 @@
+//! Process ID allocation.
+//!
+//! PIDs are handed out in increasing order and wrap around after PID_MAX,
+//! like Linux, so a freed PID is reused as late as possible. On top of that
+//! a freed PID is quarantined for PID_QUARANTINE_NS and cannot be handed out
+//! again before then, however many processes come and go. KERNEL_PID is
+//! reserved and never handed out.
+
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    constants::processes::{KERNEL_PID, PID_MAX, PID_QUARANTINE_NS},
+    time,
+};
+
+use super::process::ProcessError;
+
+lazy_static::lazy_static! {
+    static ref PIDS: Mutex<PidAllocator> = Mutex::new(PidAllocator::new(PID_MAX));
+}
+
+/// Bitmap of PIDs in use, with a queue of PIDs waiting out their quarantine
+pub struct PidAllocator {
+    /// One bit per PID, set while the PID is in use or quarantined
+    used: Vec<u64>,
+    /// Freed PIDs and when they may be reused, oldest first
+    quarantine: VecDeque<(u32, u64)>,
+    /// Where the search for a free PID starts
+    next: u32,
+    max: u32,
+}
+
+impl PidAllocator {
+    /// Creates an allocator handing out PIDs from 1 to `max`
+    pub fn new(max: u32) -> Self {
+        let mut allocator = PidAllocator {
+            used: vec![0; (max as usize + 1).div_ceil(64)],
+            quarantine: VecDeque::new(),
+            next: KERNEL_PID + 1,
+            max,
+        };
+        allocator.set(KERNEL_PID, true);
+        allocator
+    }
+
+    fn is_set(&self, pid: u32) -> bool {
+        self.used[pid as usize / 64] & (1 << (pid % 64)) != 0
+    }
+
+    fn set(&mut self, pid: u32, used: bool) {
+        let word = &mut self.used[pid as usize / 64];
+        if used {
+            *word |= 1 << (pid % 64);
+        } else {
+            *word &= !(1 << (pid % 64));
+        }
+    }
+
+    /// Returns true if `pid` belongs to a process or is quarantined
+    pub fn is_used(&self, pid: u32) -> bool {
+        pid <= self.max && self.is_set(pid)
+    }
+
+    /// Hands out the next free PID after the one handed out last
+    ///
+    /// # Arguments
+    /// * `now` - Monotonic time in nanoseconds, to end quarantines
+    ///
+    /// # Returns
+    /// The PID, or `ProcessError::NoFreePid` if every PID is in use or
+    /// quarantined
+    pub fn alloc(&mut self, now: u64) -> Result<u32, ProcessError> {
+        while let Some(&(pid, until)) = self.quarantine.front() {
+            if until > now {
+                break;
+            }
+            self.set(pid, false);
+            self.quarantine.pop_front();
+        }
+
+        let candidates = (self.next..=self.max).chain(KERNEL_PID + 1..self.next);
+        for pid in candidates {
+            if !self.is_set(pid) {
+                self.set(pid, true);
+                self.next = if pid == self.max {
+                    KERNEL_PID + 1
+                } else {
+                    pid + 1
+                };
+                return Ok(pid);
+            }
+        }
+        Err(ProcessError::NoFreePid)
+    }
+
+    /// Returns a PID once its process is gone. It stays quarantined until
+    /// `now + PID_QUARANTINE_NS`. Reserved and unused PIDs are ignored.
+    pub fn free(&mut self, pid: u32, now: u64) {
+        if pid == KERNEL_PID || !self.is_used(pid) {
+            return;
+        }
+        if self.quarantine.iter().any(|&(queued, _)| queued == pid) {
+            return;
+        }
+        self.quarantine
+            .push_back((pid, now.saturating_add(PID_QUARANTINE_NS)));
+    }
+}
+
+/// Allocates a PID for a new process
+pub fn alloc_pid() -> Result<u32, ProcessError> {
+    PIDS.lock().alloc(time::monotonic_ns())
+}
+
+/// Releases the PID of a process that is gone
+pub fn free_pid(pid: u32) {
+    PIDS.lock().free(pid, time::monotonic_ns());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_pid_reuse() {
+        let mut pids = PidAllocator::new(4);
+        assert!(pids.is_used(KERNEL_PID));
+        assert_eq!(pids.alloc(0).unwrap(), 1);
+        assert_eq!(pids.alloc(0).unwrap(), 2);
+        assert_eq!(pids.alloc(0).unwrap(), 3);
+
+        // A freed PID is not reused while it is quarantined
+        pids.free(2, 0);
+        assert_eq!(pids.alloc(0).unwrap(), 4);
+        assert!(matches!(pids.alloc(0), Err(ProcessError::NoFreePid)));
+        assert!(pids.is_used(2));
+
+        // Once the quarantine is over the search wraps around to it
+        assert_eq!(pids.alloc(PID_QUARANTINE_NS).unwrap(), 2);
+        pids.free(KERNEL_PID, 0);
+        pids.free(5, 0);
+        assert!(pids.is_used(KERNEL_PID));
+        assert!(matches!(
+            pids.alloc(2 * PID_QUARANTINE_NS),
+            Err(ProcessError::NoFreePid)
+        ));
+    }
+}
@@ -1,30 +1,81 @@
 extern crate alloc;
 
 use crate::{
+    arch::{core_id, without_interrupts},
+    constants::{
+        events::NUM_EVENT_PRIORITIES,
+        memory::PAGE_SIZE,
+        processes::{
+            KERNEL_PID, MMAP_START, PROCESS_DEFAULT_PRIORITY, SIGNAL_EXIT_BASE, STACK_START,
+        },
+    },
     debug,
+    error::{ErrorKind, KResult},
+    events::current_running_event_pid,
+    filesys::{
+        vfs::{self, FileVersion, VFS},
+        FsError,
+    },
     interrupts::gdt,
+    ipc::wait_queue::{Ticket, WakerQueue},
     memory::{
-        frame_allocator::{alloc_frame, with_generic_allocator},
+        frame_allocator::{alloc_frame, dealloc_frame},
+        mmap::{find_free_area, FileMapping, MappingFault},
+        paging::{copy_on_write, map_range, unmap_range, user_pages},
+        vma::{Heap, Vma},
         HHDM_OFFSET, MAPPER,
     },
-    processes::{loader::load_elf, registers::Registers},
+    processes::{
+        cgroup::CGROUPS,
+        fd_table::{FdTable, VfsFile},
+        image_cache::shared_image,
+        kthread::KernelThread,
+        loader::{load_elf, read_hints, ImageHints, ProgramImage},
+        pid::{alloc_pid, free_pid},
+        registers::Registers,
+        rusage::{record_exit, ProcessStats, Rusage, SchedStats},
+        signal::{deliver_pending, Signals},
+    },
     serial_println,
+    sync::RwLock,
 };
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use core::{
     arch::naked_asm,
     cell::UnsafeCell,
-    sync::atomic::{AtomicU32, Ordering},
+    future::Future,
+    pin::Pin,
+    sync::atomic::Ordering,
+    task::{Context, Poll},
 };
-use spin::rwlock::RwLock;
+use spin::Mutex;
 use x86_64::{
     instructions::interrupts,
-    structures::paging::{FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
 };
 
-// process counter must be thread-safe
-// PID 0 will ONLY be used for errors/PID not found
-static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+/// Why a process could not be created or found
+#[derive(Debug)]
+pub enum ProcessError {
+    /// Every PID is in use or quarantined
+    NoFreePid,
+    /// No process has this PID
+    NotFound(u32),
+    /// The executable could not be read
+    Exec(FsError),
+    /// The executable is not an ELF image that can be loaded
+    NotExecutable,
+    /// The process has no children matching a wait
+    NoChildren,
+    /// The executable needs these syscall feature bits, which the kernel
+    /// does not have
+    Unsupported(u64),
+    /// No frames were free for the process' image or page tables
+    OutOfMemory,
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
@@ -33,25 +84,80 @@ pub enum ProcessState {
     Running,
     Blocked,
     Terminated,
+    /// Exited, with its exit code kept until the parent waits for it
+    Zombie,
+    /// Asleep in the kernel until what `wait_reason` names is done. Unlike
+    /// a blocked process it would not run even with a core free.
+    Waiting,
+}
+
+/// What a waiting process is waiting for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitReason {
+    /// A child process to exit
+    Child,
+    /// A block device, such as the SD card, to finish a request
+    BlockIo,
+    /// A message from, or room in, a channel
+    Channel,
+    /// Bytes in, or room in, a pipe
+    Pipe,
+    /// Any other kernel future
+    Other,
 }
 
 #[derive(Debug)]
 pub struct PCB {
     pub pid: u32,
+    /// The process that waits for this one, KERNEL_PID if none does
+    pub parent: u32,
     pub state: ProcessState,
     pub kernel_rsp: u64,
     pub kernel_rip: u64,
     pub registers: Registers,
     pub pml4_frame: PhysFrame<Size4KiB>, // this process' page table
+    /// Event priority the process is scheduled at, changed with nice
+    pub priority: usize,
+    /// Open files and the standard streams
+    pub fd_table: FdTable,
+    /// Set when the process exits
+    pub exit_code: i32,
+    /// Resource needs the running image declared
+    pub hints: ImageHints,
+    /// Where the user stack may grow, None for kernel threads
+    pub stack: Option<Vma>,
+    /// The heap brk moves the end of, None for kernel threads
+    pub heap: Option<Heap>,
+    /// The stack and entry point of a kernel thread, None for a process
+    /// running in ring 3
+    pub kernel_thread: Option<KernelThread>,
+    /// Set while the process is `Waiting`
+    pub wait_reason: Option<WaitReason>,
+    /// Files mapped into the address space with mmap
+    pub mappings: Vec<FileMapping>,
+    /// The program the process runs, whose frames the address space
+    /// borrows. None for kernel threads.
+    pub image: Option<Arc<ProgramImage>>,
+    /// Pending signals and what each does
+    pub signals: Signals,
+    /// Whether the kernel started the process itself, rather than as
+    /// another process' child. Only such processes may change settings of
+    /// the whole system, and a child handed to the kernel when its parent
+    /// exits does not become one.
+    pub launched_by_kernel: bool,
 }
 
 pub struct UnsafePCB {
     pub pcb: UnsafeCell<PCB>,
+    /// Resource usage, kept outside the cell so it can be updated through
+    /// a shared reference
+    pub stats: ProcessStats,
 }
 impl UnsafePCB {
-    fn init(pcb: PCB) -> Self {
+    pub(super) fn init(pcb: PCB) -> Self {
         UnsafePCB {
             pcb: UnsafeCell::new(pcb),
+            stats: ProcessStats::new(),
         }
     }
 }
@@ -62,98 +168,503 @@ type ProcessTable = Arc<RwLock<BTreeMap<u32, Arc<UnsafePCB>>>>;
 // global process table must be thread-safe
 lazy_static::lazy_static! {
     #[derive(Debug)]
-    pub static ref PROCESS_TABLE: ProcessTable = Arc::new(RwLock::new(BTreeMap::new()));
+    pub static ref PROCESS_TABLE: ProcessTable = Arc::new(RwLock::new("process table", BTreeMap::new()));
 }
 
 impl PCB {
+    /// Puts the process to sleep until `reason` is done. It wakes up when
+    /// the event that was scheduled to resume it runs it again.
+    pub fn wait_for(&mut self, reason: WaitReason) {
+        self.state = ProcessState::Waiting;
+        self.wait_reason = Some(reason);
+    }
+
     /// Creates a page table mapper for temporary use during only process creation and cleanup
     /// # Safety
     /// TODO
-    pub unsafe fn create_mapper(&mut self) -> OffsetPageTable<'_> {
+    pub unsafe fn create_mapper(&self) -> OffsetPageTable<'_> {
         let virt = *HHDM_OFFSET + self.pml4_frame.start_address().as_u64();
         let ptr = virt.as_mut_ptr::<PageTable>();
         OffsetPageTable::new(unsafe { &mut *ptr }, *HHDM_OFFSET)
     }
 }
 
-/// # Safety
-///
-/// TODO
-pub unsafe fn print_process_table(process_table: &PROCESS_TABLE) {
-    let table = process_table.read();
-    serial_println!("\nProcess Table Contents:");
-    serial_println!("========================");
+/// What diagnostic tools see of a process, copied out of its PCB
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessSnapshot {
+    pub state: ProcessState,
+    pub priority: usize,
+    /// Registers as of the last time the process entered the kernel
+    pub registers: Registers,
+    pub usage: Rusage,
+    pub sched: SchedStats,
+    pub open_files: usize,
+    pub kernel_thread: bool,
+    pub wait_reason: Option<WaitReason>,
+}
 
-    if table.is_empty() {
-        serial_println!("No processes found");
-        return;
+impl ProcessSnapshot {
+    /// Copies a process. The caller holds the process table lock, which
+    /// keeps the timer handler from saving registers halfway through.
+    fn take(process: &UnsafePCB) -> Self {
+        let pcb = unsafe { &*process.pcb.get() };
+        ProcessSnapshot {
+            state: pcb.state,
+            priority: pcb.priority,
+            registers: pcb.registers,
+            usage: process.stats.rusage(),
+            sched: process.stats.sched_stats(),
+            open_files: pcb.fd_table.len(),
+            kernel_thread: pcb.kernel_thread.is_some(),
+            wait_reason: pcb.wait_reason,
+        }
     }
+}
+
+/// Calls `f` with a snapshot of every process, in PID order. The snapshots
+/// are all taken first, so `f` runs without the process table locked and
+/// may look processes up itself.
+pub fn for_each(mut f: impl FnMut(u32, &ProcessSnapshot)) {
+    // A timer interrupt here would wait on the lock forever
+    let snapshots: Vec<(u32, ProcessSnapshot)> = without_interrupts(|| {
+        PROCESS_TABLE
+            .read()
+            .iter()
+            .map(|(&pid, process)| (pid, ProcessSnapshot::take(process)))
+            .collect()
+    });
+    for (pid, snapshot) in &snapshots {
+        f(*pid, snapshot);
+    }
+}
+
+/// Returns a snapshot of one process
+pub fn snapshot(pid: u32) -> Result<ProcessSnapshot, ProcessError> {
+    without_interrupts(|| {
+        PROCESS_TABLE
+            .read()
+            .get(&pid)
+            .map(|process| ProcessSnapshot::take(process))
+            .ok_or(ProcessError::NotFound(pid))
+    })
+}
+
+pub fn print_process_table() {
+    serial_println!("\nProcess Table Contents:");
+    serial_println!("========================");
 
-    for (pid, pcb) in table.iter() {
-        let pcb = pcb.pcb.get();
+    let mut empty = true;
+    for_each(|pid, process| {
+        empty = false;
         serial_println!(
-            "PID {}: State: {:?}, Registers: {:?}, SP: {:#x}, PC: {:#x}",
+            "PID {}: State: {:?}, Waiting for: {:?}, Registers: {:?}, SP: {:#x}, PC: {:#x}",
             pid,
-            (*pcb).state,
-            (*pcb).registers,
-            (*pcb).registers.rsp,
-            (*pcb).registers.rip
+            process.state,
+            process.wait_reason,
+            process.registers,
+            process.registers.rsp,
+            process.registers.rip
         );
+    });
+    if empty {
+        serial_println!("No processes found");
     }
     serial_println!("========================");
 }
 
-pub fn create_process(elf_bytes: &[u8]) -> u32 {
-    let pid = NEXT_PID.fetch_add(1, Ordering::SeqCst);
+/// Looks up a process in the process table
+///
+/// # Returns
+/// The process, or `ProcessError::NotFound` if it does not exist, which
+/// includes the kernel's own KERNEL_PID
+pub fn get_process(pid: u32) -> Result<Arc<UnsafePCB>, ProcessError> {
+    if pid == KERNEL_PID {
+        return Err(ProcessError::NotFound(pid));
+    }
+    PROCESS_TABLE
+        .read()
+        .get(&pid)
+        .cloned()
+        .ok_or(ProcessError::NotFound(pid))
+}
 
-    // Build a new process address space
-    let process_pml4_frame = unsafe { create_process_page_table() };
-    let mut mapper = unsafe {
-        let virt = *HHDM_OFFSET + process_pml4_frame.start_address().as_u64();
-        let ptr = virt.as_mut_ptr::<PageTable>();
-        OffsetPageTable::new(&mut *ptr, *HHDM_OFFSET)
+/// Creates a process from an ELF image and adds it to the process table
+///
+/// # Returns
+/// The new PID, or `ProcessError::NoFreePid` if every PID is taken
+pub fn create_process(elf_bytes: &[u8]) -> Result<u32, ProcessError> {
+    create_child_process(elf_bytes, KERNEL_PID)
+}
+
+/// Creates a process that `parent` can wait for, as `create_process`
+pub fn create_child_process(elf_bytes: &[u8], parent: u32) -> Result<u32, ProcessError> {
+    let hints = read_hints(elf_bytes)?;
+    spawn_image(hints, program_image(elf_bytes, None)?, parent)
+}
+
+/// Creates a process running a loaded program image
+fn spawn_image(
+    hints: ImageHints,
+    image: Arc<ProgramImage>,
+    parent: u32,
+) -> Result<u32, ProcessError> {
+    let pid = alloc_pid()?;
+    let stack = Vma::stack(hints.stack_size);
+    let (process_pml4_frame, registers, heap) = match load_image(&image, &stack) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            free_pid(pid);
+            return Err(error);
+        }
     };
-    let (stack_top, entry_point) = load_elf(elf_bytes, &mut mapper, &mut MAPPER.lock());
 
     let process = Arc::new(UnsafePCB::init(PCB {
         pid,
+        parent,
         state: ProcessState::New,
         kernel_rsp: 0,
         kernel_rip: 0,
-        registers: Registers {
-            rax: 0,
-            rbx: 0,
-            rcx: 0,
-            rdx: 0,
-            rsi: 0,
-            rdi: 0,
-            r8: 0,
-            r9: 0,
-            r10: 0,
-            r11: 0,
-            r12: 0,
-            r13: 0,
-            r14: 0,
-            r15: 0,
-            rbp: 0,
-            rsp: stack_top.as_u64(),
-            rip: entry_point,
-            rflags: 0x202,
-        },
+        registers,
         pml4_frame: process_pml4_frame,
+        priority: PROCESS_DEFAULT_PRIORITY,
+        fd_table: FdTable::with_standard_streams(),
+        exit_code: 0,
+        hints,
+        stack: Some(stack),
+        heap: Some(heap),
+        kernel_thread: None,
+        wait_reason: None,
+        mappings: Vec::new(),
+        image: Some(image),
+        signals: Signals::new(),
+        launched_by_kernel: parent == KERNEL_PID,
     }));
     let pid = unsafe { (*process.pcb.get()).pid };
+    process
+        .stats
+        .update_rss(count_user_pages(unsafe { &mut *process.pcb.get() }));
     PROCESS_TABLE.write().insert(pid, Arc::clone(&process));
     debug!("Created process with PID: {}", pid);
     // schedule process (call from main)
-    pid
+    Ok(pid)
+}
+
+/// Loads the segments of an ELF image. Images of files in the VFS are
+/// shared with every other process running the same version of the file.
+fn program_image(
+    elf_bytes: &[u8],
+    version: Option<&FileVersion>,
+) -> Result<Arc<ProgramImage>, ProcessError> {
+    match version {
+        Some(version) => shared_image(version, elf_bytes),
+        None => ProgramImage::new(elf_bytes).map(Arc::new),
+    }
+}
+
+/// Builds a new address space mapping a program image and the top of a
+/// user stack that can grow through `stack`
+///
+/// # Returns
+/// The address space's PML4, the registers to start the image with, and
+/// its empty heap, or the error of `load_elf`, in which case the address
+/// space is freed again
+fn load_image(
+    image: &ProgramImage,
+    stack: &Vma,
+) -> Result<(PhysFrame<Size4KiB>, Registers, Heap), ProcessError> {
+    let process_pml4_frame =
+        unsafe { create_process_page_table() }.ok_or(ProcessError::OutOfMemory)?;
+    let mut mapper = unsafe {
+        let virt = *HHDM_OFFSET + process_pml4_frame.start_address().as_u64();
+        let ptr = virt.as_mut_ptr::<PageTable>();
+        OffsetPageTable::new(&mut *ptr, *HHDM_OFFSET)
+    };
+    let (stack_top, entry_point, heap) = match load_elf(image, stack, &mut mapper) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            // Nothing has run in the address space, so no TLB holds it
+            unmap_range(user_pages(), &mut mapper, true);
+            dealloc_frame(process_pml4_frame);
+            return Err(error);
+        }
+    };
+
+    let registers = Registers {
+        rsp: stack_top.as_u64(),
+        rip: entry_point,
+        rflags: 0x202,
+        ..Registers::new()
+    };
+    Ok((process_pml4_frame, registers, heap))
 }
 
+/// Replaces the image a process runs with a new ELF image. The process
+/// keeps its PID and open files, but gets a fresh address space, stack,
+/// heap and registers. The caller must not return to the old image.
+///
+/// # Arguments
+/// * `elf_bytes` - The new image
+/// * `version` - The file the image was read from, so its pages can be
+///   shared with other processes running it, or None if it is not a file
+///
+/// # Returns
+/// `ProcessError::NotExecutable` if the image cannot be loaded,
+/// `ProcessError::Unsupported` if it needs syscalls the kernel lacks, or
+/// `ProcessError::OutOfMemory` if there were not enough frames to load it,
+/// in which case the old image is left as it was
+///
+/// # Safety
+/// The process must be the one running on this core, so that its address
+/// space is the active one
+pub unsafe fn exec_process(
+    pcb: &mut PCB,
+    elf_bytes: &[u8],
+    version: Option<&FileVersion>,
+) -> Result<(), ProcessError> {
+    // Once the old image is gone there is nothing to fail back to
+    let hints = read_hints(elf_bytes)?;
+    let image = program_image(elf_bytes, version)?;
+    let stack = Vma::stack(hints.stack_size);
+    let (pml4_frame, registers, heap) = load_image(&image, &stack)?;
+
+    // Leave the old address space before freeing it
+    Cr3::write(pml4_frame, Cr3Flags::empty());
+    release_mappings(pcb);
+    clear_process_frames(pcb);
+    pcb.pml4_frame = pml4_frame;
+    pcb.image = Some(image);
+    pcb.registers = registers;
+    pcb.hints = hints;
+    pcb.stack = Some(stack);
+    pcb.heap = Some(heap);
+    pcb.signals.reset_handlers();
+    Ok(())
+}
+
+/// Removes a process from the process table and releases its PID
+pub fn remove_process(pid: u32) {
+    if PROCESS_TABLE.write().remove(&pid).is_some() {
+        free_pid(pid);
+    }
+}
+
+/// Releases the files, memory and accounting of a process exiting on its
+/// own core, then finishes it off with `terminate_process`. The address
+/// space is freed while still loaded, which is fine as long as the core
+/// only touches the kernel half until it moves on.
+///
+/// # Returns
+/// The kernel stack and return address the process was entered from, to
+/// go back to the event runner with
+pub fn exit_process(pid: u32, exit_code: i32) -> Result<(u64, u64), ProcessError> {
+    let process = get_process(pid)?;
+    let return_to = unsafe {
+        let pcb = process.pcb.get();
+
+        process.stats.update_rss(count_user_pages(&mut *pcb));
+        process.stats.leave_kernel();
+        record_exit(pid, process.stats.rusage());
+        CGROUPS.write().remove_process(pid);
+        vfs::lock().clear_cwd(pid);
+        // Closes the process' files
+        drop((*pcb).fd_table.take_all());
+        release_mappings(&mut *pcb);
+        clear_process_frames(&mut *pcb);
+        ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+    };
+    terminate_process(pid, exit_code);
+    Ok(return_to)
+}
+
+/// Parents blocked in waitpid, by PID
+static CHILD_WAITERS: Mutex<BTreeMap<u32, WakerQueue>> = Mutex::new(BTreeMap::new());
+
+/// Finishes off an exiting process whose files and memory are already
+/// released. A process with a parent becomes a zombie holding its exit code
+/// until the parent reaps it, and anything else is removed straight away.
+/// Its own children are handed to the kernel, which reaps them as they
+/// exit.
+pub fn terminate_process(pid: u32, exit_code: i32) {
+    let parent = {
+        let table = PROCESS_TABLE.write();
+        for child in table.values() {
+            let child = unsafe { &mut *child.pcb.get() };
+            if child.parent == pid {
+                child.parent = KERNEL_PID;
+            }
+        }
+        let Some(process) = table.get(&pid) else {
+            return;
+        };
+        let pcb = unsafe { &mut *process.pcb.get() };
+        pcb.exit_code = exit_code;
+        pcb.state = if pcb.parent == KERNEL_PID {
+            ProcessState::Terminated
+        } else {
+            ProcessState::Zombie
+        };
+        pcb.parent
+    };
+
+    // Orphans that had already exited have nobody left to reap them
+    let orphans: Vec<u32> = PROCESS_TABLE
+        .read()
+        .iter()
+        .filter(|(_, child)| {
+            let child = unsafe { &*child.pcb.get() };
+            child.parent == KERNEL_PID && child.state == ProcessState::Zombie
+        })
+        .map(|(&pid, _)| pid)
+        .collect();
+    for orphan in orphans {
+        remove_process(orphan);
+    }
+
+    let mut waiters = CHILD_WAITERS.lock();
+    waiters.remove(&pid);
+    if parent == KERNEL_PID {
+        drop(waiters);
+        remove_process(pid);
+    } else if let Some(queue) = waiters.get_mut(&parent) {
+        queue.wake_one();
+    }
+}
+
+/// Finds an exited child of `parent`, or None if there are children but
+/// none has exited yet
+fn exited_child(parent: u32, pid: Option<u32>) -> Result<Option<(u32, i32)>, ProcessError> {
+    // Polled from the event runner, where the timer could interrupt
+    without_interrupts(|| {
+        let table = PROCESS_TABLE.read();
+        let mut children = table
+            .iter()
+            .filter(|(&child, _)| pid.is_none_or(|pid| pid == child))
+            .map(|(&child, process)| (child, unsafe { &*process.pcb.get() }))
+            .filter(|(_, pcb)| pcb.parent == parent)
+            .peekable();
+        if children.peek().is_none() {
+            return Err(ProcessError::NoChildren);
+        }
+        Ok(children
+            .find(|(_, pcb)| pcb.state == ProcessState::Zombie)
+            .map(|(child, pcb)| (child, pcb.exit_code)))
+    })
+}
+
+/// Reaps an exited child of `parent`
+///
+/// # Arguments
+/// * `parent` - The waiting process
+/// * `pid` - The child to reap, or None for any child
+///
+/// # Returns
+/// The child's PID and exit code, None if matching children exist but none
+/// has exited yet, or `ProcessError::NoChildren` if there are none
+pub fn reap_child(parent: u32, pid: Option<u32>) -> Result<Option<(u32, i32)>, ProcessError> {
+    let exited = exited_child(parent, pid)?;
+    if let Some((child, _)) = exited {
+        remove_process(child);
+    }
+    Ok(exited)
+}
+
+/// Completes once a child of `parent` has exited, or once it has no
+/// children left to wait for
+pub fn child_exit(parent: u32) -> ChildExit {
+    ChildExit {
+        parent,
+        ticket: None,
+    }
+}
+
+/// Future returned by `child_exit`
+pub struct ChildExit {
+    parent: u32,
+    ticket: Option<Ticket>,
+}
+
+impl Future for ChildExit {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // Exiting children become zombies before taking this lock to wake
+        // the parent, so one exiting while this runs is not missed
+        let mut waiters = CHILD_WAITERS.lock();
+        if !matches!(exited_child(self.parent, None), Ok(None)) {
+            if let Some(ticket) = self.ticket.take() {
+                if let Some(queue) = waiters.get_mut(&self.parent) {
+                    queue.remove(ticket);
+                }
+            }
+            return Poll::Ready(());
+        }
+        let parent = self.parent;
+        waiters
+            .entry(parent)
+            .or_default()
+            .register(&mut self.ticket, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for ChildExit {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket.take() {
+            if let Some(queue) = CHILD_WAITERS.lock().get_mut(&self.parent) {
+                if queue.remove(ticket) {
+                    queue.wake_one();
+                }
+            }
+        }
+    }
+}
+
+/// Creates a process from an ELF file in the VFS, sharing the file's
+/// pages with other processes running it
+///
+/// # Returns
+/// The new PID, or `ProcessError::Exec` with `FsError::PermissionDenied`
+/// if the file is on a noexec mount
+pub fn create_process_from_path(path: &str) -> Result<u32, ProcessError> {
+    let executable = VFS
+        .lock()
+        .read_executable(path)
+        .map_err(ProcessError::Exec)?;
+    let hints = read_hints(&executable.bytes)?;
+    let image = program_image(&executable.bytes, Some(&executable.version))?;
+    spawn_image(hints, image, KERNEL_PID)
+}
+
+/// Returns the event priority a process is scheduled at, or the default
+/// priority if it does not exist
+pub fn process_priority(pid: u32) -> usize {
+    get_process(pid).map_or(PROCESS_DEFAULT_PRIORITY, |process| unsafe {
+        (*process.pcb.get()).priority
+    })
+}
+
+/// Applies a nice increment to a process priority. A larger increment means
+/// a less urgent priority, and increments past the least urgent normal
+/// priority are clamped to it.
+///
+/// # Returns
+/// None if the increment would raise the priority above the default
+pub fn niced_priority(priority: usize, increment: i64) -> Option<usize> {
+    let target = (priority as i64).saturating_add(increment);
+    if target < PROCESS_DEFAULT_PRIORITY as i64 {
+        return None;
+    }
+    Some(target.min(NUM_EVENT_PRIORITIES as i64 - 1) as usize)
+}
+
+/// Creates a level 4 table sharing the kernel half, or None if no frame
+/// is free for it
+///
 /// # Safety
 ///
 /// TODO
-unsafe fn create_process_page_table() -> PhysFrame<Size4KiB> {
-    let frame = alloc_frame().expect("Failed to allocate PML4 frame");
+unsafe fn create_process_page_table() -> Option<PhysFrame<Size4KiB>> {
+    let frame = alloc_frame()?;
     let virt = *HHDM_OFFSET + frame.start_address().as_u64();
     let ptr = virt.as_mut_ptr::<PageTable>();
 
@@ -167,84 +678,425 @@ unsafe fn create_process_page_table() -> PhysFrame<Size4KiB> {
         }
     }
 
-    frame
+    Some(frame)
 }
 
-/// Clear the PML4 associated with the PCB
+/// Maps the page holding `addr` if it lies in the process' stack area and
+/// is not mapped yet
 ///
-/// * `pcb`: The process PCB to clear memory for
-pub fn clear_process_frames(pcb: &mut PCB) {
-    let pml4_frame = pcb.pml4_frame;
-    let mapper = unsafe { pcb.create_mapper() };
+/// # Returns
+/// Whether a page was mapped. Faults at any other address are not the
+/// stack's to handle.
+pub fn grow_stack(pcb: &mut PCB, addr: VirtAddr) -> bool {
+    match pcb.stack {
+        Some(stack) => map_area_page(pcb, &stack, addr),
+        None => false,
+    }
+}
 
-    with_generic_allocator(|deallocator| {
-        // Iterate over first 256 entries (user space)
-        for i in 0..256 {
-            let entry = &mapper.level_4_table()[i];
-            if entry.is_unused() {
-                continue;
-            }
+/// Maps the page holding `addr` if it lies below the process' program
+/// break and is not mapped yet, as `grow_stack`
+pub fn grow_heap(pcb: &mut PCB, addr: VirtAddr) -> bool {
+    match pcb.heap {
+        Some(heap) => map_area_page(pcb, &heap.area(), addr),
+        None => false,
+    }
+}
 
-            let pdpt_frame = PhysFrame::containing_address(entry.addr());
-            unsafe {
-                free_page_table(pdpt_frame, 3, deallocator, HHDM_OFFSET.as_u64());
-            }
+/// Maps a zeroed page at `addr` if it lies in `area` and is not mapped yet
+fn map_area_page(pcb: &PCB, area: &Vma, addr: VirtAddr) -> bool {
+    if !area.contains(addr) {
+        return false;
+    }
+    let page = Page::containing_address(addr);
+    let mut mapper = unsafe { pcb.create_mapper() };
+    if mapper.translate_page(page).is_ok() {
+        return false;
+    }
+    map_range(Page::range_inclusive(page, page), &mut mapper, area.flags).is_ok()
+}
+
+/// Grows the stack of the process running on this core, as `grow_stack`,
+/// counting a minor fault if it does
+pub fn grow_current_stack(addr: VirtAddr) -> bool {
+    grow_current(addr, grow_stack)
+}
+
+/// Grows the heap of the process running on this core, as `grow_heap`,
+/// counting a minor fault if it does
+pub fn grow_current_heap(addr: VirtAddr) -> bool {
+    grow_current(addr, grow_heap)
+}
+
+fn grow_current(addr: VirtAddr, grow: fn(&mut PCB, VirtAddr) -> bool) -> bool {
+    let Ok(process) = get_process(current_running_event_pid(core_id())) else {
+        return false;
+    };
+    if !grow(unsafe { &mut *process.pcb.get() }, addr) {
+        return false;
+    }
+    // Stack and heap pages start out zeroed, so mapping one is a minor fault
+    process.stats.minor_faults.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Moves the program break of a process. Pages the heap grows over are
+/// mapped when first touched, and those it shrinks away from are unmapped
+/// and freed at once.
+///
+/// # Returns
+/// `ErrorKind::OutOfMemory` if the break would fall before the start of
+/// the heap or run into the file mapping area, or the process has no heap
+pub fn set_brk(pcb: &mut PCB, brk: VirtAddr) -> KResult<()> {
+    let old = pcb.heap.ok_or(ErrorKind::OutOfMemory)?;
+    if brk < old.start || brk.as_u64() > MMAP_START {
+        return Err(ErrorKind::OutOfMemory.into());
+    }
+    let new = Heap { brk, ..old };
+    pcb.heap = Some(new);
+
+    let (new_end, old_end) = (new.area().end, old.area().end);
+    if new_end < old_end {
+        let mut mapper = unsafe { pcb.create_mapper() };
+        let pages = Page::range_inclusive(
+            Page::containing_address(new_end),
+            Page::containing_address(old_end - 1u64),
+        );
+        unmap_range(pages, &mut mapper, true);
+    }
+    Ok(())
+}
+
+/// Gives the process running on this core its own copy of a copy-on-write
+/// page it wrote to, counting the fault
+///
+/// # Returns
+/// Whether the fault was handled
+pub fn copy_current_on_write(addr: VirtAddr) -> bool {
+    let Ok(process) = get_process(current_running_event_pid(core_id())) else {
+        return false;
+    };
+    let mut mapper = unsafe { (*process.pcb.get()).create_mapper() };
+    if !copy_on_write(Page::containing_address(addr), &mut mapper) {
+        return false;
+    }
+    process.stats.minor_faults.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Maps `len` bytes of a file, from `offset`, at the lowest free address
+/// of the process' mapping area. Pages are read in as they are touched.
+///
+/// # Returns
+/// The start of the mapping, or OutOfMemory if the area has no room
+pub fn map_file(
+    pcb: &mut PCB,
+    file: VfsFile,
+    len: u64,
+    offset: u64,
+    flags: PageTableFlags,
+    shared: bool,
+) -> KResult<VirtAddr> {
+    let start = find_free_area(&pcb.mappings, len).ok_or(ErrorKind::OutOfMemory)?;
+    pcb.mappings
+        .push(FileMapping::new(start, len, file, offset, flags, shared));
+    Ok(start)
+}
+
+/// Handles a fault at `addr` if it lies in one of the process' file
+/// mappings, as `FileMapping::fault`
+pub fn fault_mapping(pcb: &PCB, addr: VirtAddr, write: bool) -> Option<MappingFault> {
+    let mapping = pcb.mappings.iter().find(|m| m.vma.contains(addr))?;
+    let mut mapper = unsafe { pcb.create_mapper() };
+    mapping.fault(addr, write, &mut mapper)
+}
+
+/// Handles a fault in a file mapping of the process running on this core,
+/// as `fault_mapping`, counting it
+///
+/// # Returns
+/// Whether the fault was handled
+pub fn fault_current_mapping(addr: VirtAddr, write: bool) -> bool {
+    let Ok(process) = get_process(current_running_event_pid(core_id())) else {
+        return false;
+    };
+    let faults = match fault_mapping(unsafe { &*process.pcb.get() }, addr, write) {
+        Some(MappingFault::PagedIn) => &process.stats.major_faults,
+        Some(MappingFault::Dirtied) => &process.stats.minor_faults,
+        None => return false,
+    };
+    faults.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Writes back the dirty pages of the process' shared file mappings that
+/// lie in `len` bytes from `start`
+///
+/// # Returns
+/// OutOfMemory if part of the range is not mapped from a file, or the
+/// filesystem's error if a page could not be written back
+pub fn sync_mappings(pcb: &PCB, start: VirtAddr, len: u64) -> KResult<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = mapping_range_end(start, len)?;
+    // A range with holes fails before anything is written back
+    let areas = covering_mappings(&pcb.mappings, start, end)?;
+    Ok(write_back_range(pcb, areas, start, end)?)
+}
+
+/// Writes back the dirty pages of `mappings` that lie between `start` and
+/// `end`
+///
+/// # Returns
+/// The filesystem's error if a page could not be written back
+fn write_back_range<'a>(
+    pcb: &PCB,
+    mappings: impl IntoIterator<Item = &'a FileMapping>,
+    start: VirtAddr,
+    end: u64,
+) -> Result<(), FsError> {
+    let mut mapper = unsafe { pcb.create_mapper() };
+    for mapping in mappings.into_iter().filter(|m| m.is_shared()) {
+        let first = start.max(mapping.vma.start);
+        let last = VirtAddr::new(end.min(mapping.vma.end.as_u64()) - 1);
+        let pages = Page::range_inclusive(
+            Page::containing_address(first),
+            Page::containing_address(last),
+        );
+        mapping.sync(pages, &mut mapper)?;
+    }
+    Ok(())
+}
+
+/// The end of a range of `len` bytes from `start` that mappings may cover
+///
+/// # Returns
+/// OutOfMemory if the range runs past the mapping area into the stack
+fn mapping_range_end(start: VirtAddr, len: u64) -> KResult<u64> {
+    Ok(start
+        .as_u64()
+        .checked_add(len)
+        .filter(|&end| end <= STACK_START)
+        .ok_or(ErrorKind::OutOfMemory)?)
+}
+
+/// The mappings overlapping the range from `start` to `end`, in address
+/// order
+///
+/// # Returns
+/// OutOfMemory if part of the range is not mapped from a file
+fn covering_mappings(
+    mappings: &[FileMapping],
+    start: VirtAddr,
+    end: u64,
+) -> KResult<Vec<&FileMapping>> {
+    let mut areas: Vec<&FileMapping> = mappings
+        .iter()
+        .filter(|m| m.vma.start.as_u64() < end && start < m.vma.end)
+        .collect();
+    areas.sort_unstable_by_key(|m| m.vma.start);
+    let mut covered = start.as_u64();
+    for mapping in &areas {
+        if mapping.vma.start.as_u64() > covered {
+            return Err(ErrorKind::OutOfMemory.into());
         }
-        unsafe { deallocator.deallocate_frame(pml4_frame) };
-    });
+        covered = covered.max(mapping.vma.end.as_u64());
+    }
+    if covered < end {
+        return Err(ErrorKind::OutOfMemory.into());
+    }
+    Ok(areas)
+}
+
+/// Splits the mapping `addr` falls inside of, if any, so that no mapping
+/// crosses it
+fn split_mappings_at(mappings: &mut Vec<FileMapping>, addr: VirtAddr) {
+    let straddling = mappings
+        .iter_mut()
+        .find(|m| m.vma.start < addr && addr < m.vma.end);
+    if let Some(mapping) = straddling {
+        let rest = mapping.split_off(addr);
+        mappings.push(rest);
+    }
+}
+
+/// Removes the parts of the process' file mappings that lie in a range.
+/// Mappings partly in the range are split, and what is left of them stays
+/// mapped. Dirty pages of shared mappings are written back before anything
+/// is unmapped. Addresses in the range that are not mapped from a file are
+/// left alone.
+///
+/// # Arguments
+/// * `start` - Start of the range
+/// * `end` - First address past the range, in the lower half
+///
+/// # Returns
+/// The filesystem's error if a dirty page could not be written back, in
+/// which case nothing is unmapped. The pages written back before the
+/// failure are clean, which the process cannot tell.
+pub fn unmap_mappings(pcb: &mut PCB, start: VirtAddr, end: VirtAddr) -> Result<(), FsError> {
+    let end_addr = end.align_up(PAGE_SIZE as u64);
+    let overlapping = pcb
+        .mappings
+        .iter()
+        .filter(|m| m.vma.start < end_addr && start < m.vma.end);
+    write_back_range(pcb, overlapping, start, end_addr.as_u64())?;
+
+    split_mappings_at(&mut pcb.mappings, start);
+    split_mappings_at(&mut pcb.mappings, end_addr);
+    let (removed, kept): (Vec<_>, Vec<_>) = core::mem::take(&mut pcb.mappings)
+        .into_iter()
+        .partition(|m| start <= m.vma.start && m.vma.end <= end_addr);
+    pcb.mappings = kept;
+
+    // Nothing is dirty any more, so the pages can go
+    let mut mapper = unsafe { pcb.create_mapper() };
+    for mapping in &removed {
+        unmap_range(mapping.pages(), &mut mapper, true);
+    }
+    Ok(())
+}
+
+/// Gives the pages of the process' file mappings that lie in `len` bytes
+/// from `start`, which must be page aligned, new flags. Mappings partly in
+/// the range are split. Dirty pages losing write access are written back
+/// before any mapping is split or changed.
+///
+/// # Returns
+/// OutOfMemory if part of the range is not mapped from a file, or the
+/// filesystem's error if a dirty page could not be written back. Either
+/// way no mapping is split and no flags change, though the pages written
+/// back before a failure are clean, which the process cannot tell.
+pub fn protect_mappings(
+    pcb: &mut PCB,
+    start: VirtAddr,
+    len: u64,
+    flags: PageTableFlags,
+) -> KResult<()> {
+    let end = mapping_range_end(start, len)?;
+    let end_addr = VirtAddr::new(end.next_multiple_of(PAGE_SIZE as u64));
+    let areas = covering_mappings(&pcb.mappings, start, end_addr.as_u64())?;
+    if !flags.contains(PageTableFlags::WRITABLE) {
+        write_back_range(pcb, areas, start, end_addr.as_u64())?;
+    }
+    split_mappings_at(&mut pcb.mappings, start);
+    split_mappings_at(&mut pcb.mappings, end_addr);
+
+    let mut mappings = core::mem::take(&mut pcb.mappings);
+    let mut mapper = unsafe { pcb.create_mapper() };
+    let result = mappings
+        .iter_mut()
+        .filter(|m| start <= m.vma.start && m.vma.end <= end_addr)
+        .try_for_each(|mapping| mapping.protect(flags, &mut mapper));
+    pcb.mappings = mappings;
+    Ok(result?)
+}
+
+/// Writes back every shared file mapping of a process and drops them all,
+/// for a process whose image is going away. The pages themselves go with
+/// the address space.
+pub fn release_mappings(pcb: &mut PCB) {
+    let mappings = core::mem::take(&mut pcb.mappings);
+    let mut mapper = unsafe { pcb.create_mapper() };
+    for mapping in &mappings {
+        // Nobody is left to report a failure to
+        if let Err(error) = mapping.sync(mapping.pages(), &mut mapper) {
+            serial_println!(
+                "Process {} lost writes to a mapped file: {:?}",
+                pcb.pid,
+                error
+            );
+        }
+    }
 }
 
-/// Helper function to recursively multi level page tables
+/// Counts the pages mapped in the user half of a process' address space
+///
+/// * `pcb`: The process PCB to count pages for
+pub fn count_user_pages(pcb: &mut PCB) -> u64 {
+    let mapper = unsafe { pcb.create_mapper() };
+    mapper
+        .level_4_table()
+        .iter()
+        .take(256)
+        .filter(|entry| !entry.is_unused())
+        .map(|entry| unsafe {
+            count_mapped_pages(
+                PhysFrame::containing_address(entry.addr()),
+                3,
+                HHDM_OFFSET.as_u64(),
+            )
+        })
+        .sum()
+}
+
+/// Helper function to recursively count the leaf pages of a page table
 ///
 /// * `frame`: the current page table frame iterating over
 /// * `level`: the current level of the page table we're on
-/// * `deallocator`:
 /// * `hhdm_offset`:
-unsafe fn free_page_table(
-    frame: PhysFrame,
-    level: u8,
-    deallocator: &mut impl FrameDeallocator<Size4KiB>,
-    hhdm_offset: u64,
-) {
+unsafe fn count_mapped_pages(frame: PhysFrame, level: u8, hhdm_offset: u64) -> u64 {
     let virt = hhdm_offset + frame.start_address().as_u64();
-    let table = unsafe { &mut *(virt as *mut PageTable) };
+    let table = unsafe { &*(virt as *const PageTable) };
 
-    for entry in table.iter_mut() {
-        if entry.is_unused() {
-            continue;
-        }
+    table
+        .iter()
+        .filter(|entry| !entry.is_unused())
+        .map(|entry| {
+            if level > 1 {
+                count_mapped_pages(
+                    PhysFrame::containing_address(entry.addr()),
+                    level - 1,
+                    hhdm_offset,
+                )
+            } else {
+                1
+            }
+        })
+        .sum()
+}
 
-        if level > 1 {
-            let child_frame = PhysFrame::containing_address(entry.addr());
-            free_page_table(child_frame, level - 1, deallocator, hhdm_offset);
-        } else {
-            // Free level one page
-            let page_frame = PhysFrame::containing_address(entry.addr());
-            deallocator.deallocate_frame(page_frame);
-        }
-        entry.set_unused();
-    }
-    deallocator.deallocate_frame(frame);
+/// Clear the PML4 associated with the PCB, freeing every frame the process
+/// owns, heap and stack included
+///
+/// * `pcb`: The process PCB to clear memory for
+pub fn clear_process_frames(pcb: &mut PCB) {
+    let pml4_frame = pcb.pml4_frame;
+    let mut mapper = unsafe { pcb.create_mapper() };
+
+    // Also frees the user half's page tables, which are left empty. Frames
+    // borrowed from the program image are left to it.
+    unmap_range(user_pages(), &mut mapper, true);
+    dealloc_frame(pml4_frame);
+    pcb.image = None;
+    pcb.heap = None;
 }
 
 use core::arch::asm;
 use x86_64::registers::control::{Cr3, Cr3Flags};
 
-/// run a process in ring 3
+/// run a process in ring 3, or a kernel thread in ring 0
 /// # Safety
 ///
 /// TODO
 #[no_mangle]
 pub async unsafe fn run_process_ring3(pid: u32) {
+    let process = match get_process(pid) {
+        Ok(process) => process,
+        Err(e) => {
+            // Gone between being scheduled and running
+            serial_println!("Not running process {}: {:?}", pid, e);
+            return;
+        }
+    };
+
     interrupts::disable();
 
-    let process = {
-        let process_table = PROCESS_TABLE.read();
-        let process = process_table
-            .get(&pid)
-            .expect("Could not find process from process table");
-        process.clone()
-    };
+    process.stats.resume_user();
+
+    // Whatever it waited for is done now that it runs
+    (*process.pcb.get()).wait_reason = None;
 
     // Do not lock lowest common denominator
     // Once kernel threads are in, will need lock around PCB
@@ -253,8 +1105,24 @@ pub async unsafe fn run_process_ring3(pid: u32) {
 
     Cr3::write((*process).pml4_frame, Cr3Flags::empty());
 
-    let user_cs = gdt::GDT.1.user_code_selector.0 as u64;
-    let user_ds = gdt::GDT.1.user_data_selector.0 as u64;
+    // Signals that came while the process waited in the kernel, now that
+    // its stack can take a handler's frame
+    if (*process).kernel_thread.is_none() {
+        if let Some(signal) = deliver_pending(pid) {
+            serial_println!("Process {} killed by signal {}", pid, signal);
+            let _ = exit_process(pid, SIGNAL_EXIT_BASE + signal as i32);
+            interrupts::enable();
+            return;
+        }
+    }
+
+    // Kernel threads are entered the same way, only staying in ring 0
+    let selectors = &gdt::GDT.1;
+    let (cs, ds) = if (*process).kernel_thread.is_some() {
+        (selectors.code_selector, selectors.data_selector)
+    } else {
+        (selectors.user_code_selector, selectors.user_data_selector)
+    };
 
     let registers = &(*process).registers.clone();
 
@@ -271,12 +1139,17 @@ pub async unsafe fn run_process_ring3(pid: u32) {
             "pop rcx",
             "pop rax",
             in("rdi") registers as *const Registers,
-            in("rsi") user_ds,
-            in("rdx") user_cs,
+            in("rsi") ds.0 as u64,
+            in("rdx") cs.0 as u64,
             in("rcx") &(*process).kernel_rsp,
             in("r8")  &(*process).state
         );
     }
+
+    // A kernel thread that returned is off its stack now, so it can go
+    if (*process).kernel_thread.is_some() && (*process).state == ProcessState::Terminated {
+        remove_process(pid);
+    }
 }
 
 #[naked]
@@ -341,7 +1214,7 @@ unsafe fn call_process(
 #[naked]
 #[allow(undefined_naked_function_abi)]
 #[no_mangle]
-unsafe fn return_process() {
+pub(super) unsafe fn return_process() {
     naked_asm!(
         "cli", //disable interrupts
         //restore callee-saved registers
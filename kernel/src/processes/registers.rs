@@ -70,6 +70,36 @@ impl Registers {
             rflags: 0,
         }
     }
+
+    /// Reads the registers an interrupt handler saved with
+    /// `push_registers!`, followed by the interrupt stack frame
+    ///
+    /// # Safety
+    /// `rsp` must point at the last register pushed
+    pub unsafe fn from_stack(rsp: u64) -> Self {
+        let stack_ptr = rsp as *const u64;
+        Self {
+            rax: *stack_ptr.add(0),
+            rbx: *stack_ptr.add(1),
+            rcx: *stack_ptr.add(2),
+            rdx: *stack_ptr.add(3),
+            rsi: *stack_ptr.add(4),
+            rdi: *stack_ptr.add(5),
+            r8: *stack_ptr.add(6),
+            r9: *stack_ptr.add(7),
+            r10: *stack_ptr.add(8),
+            r11: *stack_ptr.add(9),
+            r12: *stack_ptr.add(10),
+            r13: *stack_ptr.add(11),
+            r14: *stack_ptr.add(12),
+            r15: *stack_ptr.add(13),
+            rbp: *stack_ptr.add(14),
+            // saved from interrupt stack frame
+            rip: *stack_ptr.add(15),
+            rflags: *stack_ptr.add(17),
+            rsp: *stack_ptr.add(18),
+        }
+    }
 }
 
 impl Default for Registers {
@@ -0,0 +1,210 @@
+//! Per-process resource accounting.
+//!
+//! Counters live next to each PCB and are only ever updated atomically, so
+//! they can be bumped from the fault handler, the syscall layer, and block
+//! devices without holding the PCB. When a process exits its counters are
+//! folded into a `Rusage` which is kept until its parent collects it.
+//!
+//! Each time a process leaves its core the reason is counted as well, so
+//! scheduler work can tell processes that block or yield from ones the timer
+//! has to take the core from. procfs shows these as `SchedStats`.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::{
+    constants::memory::PAGE_SIZE,
+    events::current_running_event_pid,
+    interrupts::x2apic::current_core_id,
+    time::{self, Timespec},
+};
+
+use super::process::{get_process, UnsafePCB};
+
+/// Resource usage of a process, laid out for user programs
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rusage {
+    /// Time spent in user mode
+    pub ru_utime: Timespec,
+    /// Time spent in the kernel on behalf of the process
+    pub ru_stime: Timespec,
+    /// Largest resident set size in KiB
+    pub ru_maxrss: i64,
+    /// Page faults serviced without I/O
+    pub ru_minflt: i64,
+    /// Page faults that required I/O
+    pub ru_majflt: i64,
+    /// Blocks read from block devices
+    pub ru_inblock: i64,
+    /// Blocks written to block devices
+    pub ru_oublock: i64,
+}
+
+/// Why a process stopped running on its core
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchReason {
+    /// The timer took the core away
+    Preempted = 0,
+    /// The process waited on something, such as a child exiting
+    Blocked = 1,
+    /// The process gave the core up with nothing to wait for
+    Yielded = 2,
+}
+
+/// Scheduling counters of a process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SchedStats {
+    pub syscalls: u64,
+    /// Minor and major faults together
+    pub page_faults: u64,
+    pub preempted: u64,
+    pub blocked: u64,
+    pub yielded: u64,
+}
+
+impl SchedStats {
+    /// Switches the process asked for
+    pub fn voluntary_switches(&self) -> u64 {
+        self.blocked + self.yielded
+    }
+
+    pub fn involuntary_switches(&self) -> u64 {
+        self.preempted
+    }
+}
+
+/// Live counters of a running process
+#[derive(Debug, Default)]
+pub struct ProcessStats {
+    user_ns: AtomicU64,
+    kernel_ns: AtomicU64,
+    /// Monotonic time of the last switch between user and kernel mode
+    mode_switch_ns: AtomicU64,
+    pub minor_faults: AtomicU64,
+    pub major_faults: AtomicU64,
+    pub blocks_read: AtomicU64,
+    pub blocks_written: AtomicU64,
+    max_rss_pages: AtomicU64,
+    pub syscalls: AtomicU64,
+    /// Indexed by `SwitchReason`
+    switches: [AtomicU64; 3],
+}
+
+impl ProcessStats {
+    pub const fn new() -> Self {
+        ProcessStats {
+            user_ns: AtomicU64::new(0),
+            kernel_ns: AtomicU64::new(0),
+            mode_switch_ns: AtomicU64::new(0),
+            minor_faults: AtomicU64::new(0),
+            major_faults: AtomicU64::new(0),
+            blocks_read: AtomicU64::new(0),
+            blocks_written: AtomicU64::new(0),
+            max_rss_pages: AtomicU64::new(0),
+            syscalls: AtomicU64::new(0),
+            switches: [const { AtomicU64::new(0) }; 3],
+        }
+    }
+
+    /// Returns the time since the last mode switch and starts a new interval
+    fn switch_mode(&self) -> u64 {
+        let now = time::monotonic_ns();
+        now.saturating_sub(self.mode_switch_ns.swap(now, Ordering::Relaxed))
+    }
+
+    /// Called when the scheduler resumes the process in user mode. Time
+    /// spent waiting to be scheduled is not charged to the process.
+    pub fn resume_user(&self) {
+        self.mode_switch_ns
+            .store(time::monotonic_ns(), Ordering::Relaxed);
+    }
+
+    /// Called when the process traps into the kernel or is preempted
+    pub fn enter_kernel(&self) {
+        self.user_ns
+            .fetch_add(self.switch_mode(), Ordering::Relaxed);
+    }
+
+    /// Called when the kernel finishes work on behalf of the process
+    pub fn leave_kernel(&self) {
+        self.kernel_ns
+            .fetch_add(self.switch_mode(), Ordering::Relaxed);
+    }
+
+    /// Records the current resident set size
+    pub fn update_rss(&self, pages: u64) {
+        self.max_rss_pages.fetch_max(pages, Ordering::Relaxed);
+    }
+
+    /// Counts the process leaving its core
+    pub fn record_switch(&self, reason: SwitchReason) {
+        self.switches[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sched_stats(&self) -> SchedStats {
+        let switches =
+            |reason: SwitchReason| self.switches[reason as usize].load(Ordering::Relaxed);
+        SchedStats {
+            syscalls: self.syscalls.load(Ordering::Relaxed),
+            page_faults: self.minor_faults.load(Ordering::Relaxed)
+                + self.major_faults.load(Ordering::Relaxed),
+            preempted: switches(SwitchReason::Preempted),
+            blocked: switches(SwitchReason::Blocked),
+            yielded: switches(SwitchReason::Yielded),
+        }
+    }
+
+    /// Takes a snapshot of the counters
+    pub fn rusage(&self) -> Rusage {
+        Rusage {
+            ru_utime: Timespec::from_nanos(self.user_ns.load(Ordering::Relaxed) as i64),
+            ru_stime: Timespec::from_nanos(self.kernel_ns.load(Ordering::Relaxed) as i64),
+            ru_maxrss: (self.max_rss_pages.load(Ordering::Relaxed) * PAGE_SIZE as u64 / 1024)
+                as i64,
+            ru_minflt: self.minor_faults.load(Ordering::Relaxed) as i64,
+            ru_majflt: self.major_faults.load(Ordering::Relaxed) as i64,
+            ru_inblock: self.blocks_read.load(Ordering::Relaxed) as i64,
+            ru_oublock: self.blocks_written.load(Ordering::Relaxed) as i64,
+        }
+    }
+}
+
+/// Usage of processes that have exited but not been waited for
+static EXITED: Mutex<BTreeMap<u32, Rusage>> = Mutex::new(BTreeMap::new());
+
+/// Returns the process running on this core, if any
+fn current_process() -> Option<Arc<UnsafePCB>> {
+    get_process(current_running_event_pid(current_core_id() as u32)).ok()
+}
+
+/// Runs `f` with the counters of the process running on this core. Does
+/// nothing when the kernel is running on its own behalf.
+pub fn with_current_stats(f: impl FnOnce(&ProcessStats)) {
+    if let Some(process) = current_process() {
+        f(&process.stats);
+    }
+}
+
+/// Charges block I/O to the process running on this core
+///
+/// # Arguments
+/// * `read` - Number of blocks read
+/// * `written` - Number of blocks written
+pub fn account_block_io(read: u64, written: u64) {
+    with_current_stats(|stats| {
+        stats.blocks_read.fetch_add(read, Ordering::Relaxed);
+        stats.blocks_written.fetch_add(written, Ordering::Relaxed);
+    });
+}
+
+/// Stores the final usage of an exiting process
+pub fn record_exit(pid: u32, usage: Rusage) {
+    EXITED.lock().insert(pid, usage);
+}
+
+/// Removes and returns the final usage of an exited process
+pub fn take_exit_rusage(pid: u32) -> Option<Rusage> {
+    EXITED.lock().remove(&pid)
+}
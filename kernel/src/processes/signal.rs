@@ -0,0 +1,351 @@
+//! Signals sent to user processes.
+//!
+//! Each process has a bitmask of pending signals, which `kill` sets from
+//! any core, and an action for each signal, which only the process itself
+//! changes. Pending signals are delivered when the timer next interrupts
+//! the process in ring 3, the lowest numbered first, or just before the
+//! process goes back to ring 3 from anywhere else. Raising a signal wakes
+//! the process if it is waiting in the kernel, in waitpid or on a pipe, so
+//! it gets the signal straight away and then, if it is still alive, makes
+//! the call it was waiting in again.
+//!
+//! A signal with a handler pushes the interrupted registers on the user
+//! stack, below the red zone, with the address of the process' restorer
+//! under them as if the handler had been called from there. The process
+//! resumes in the handler with the signal number in rdi, and when the
+//! handler returns the restorer makes the sigreturn syscall, which takes
+//! the registers back off the stack. A signal without a handler ends the
+//! process, unless it is one that is ignored by default.
+
+use core::{
+    fmt,
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    constants::{
+        processes::SIGNAL_EXIT_BASE,
+        syscalls::{NSIG, SIGCHLD, SIGKILL, SIGSEGV},
+    },
+    events::futures::WaitQueue,
+    processes::{
+        process::{exit_process, get_process, PROCESS_TABLE},
+        registers::Registers,
+    },
+    serial_println,
+    syscalls::syscall_handlers::check_user_range,
+};
+
+/// Bytes below the stack pointer that code may use without moving it, as
+/// the System V ABI allows, and that a handler's frame must not overwrite
+const RED_ZONE: u64 = 128;
+
+/// Why a signal could not be sent or its action changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    /// Not a signal from 1 to NSIG - 1
+    InvalidSignal,
+    /// SIGKILL cannot be handled or ignored
+    Uncatchable,
+}
+
+/// What a signal does to the process it is delivered to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalAction {
+    /// Ends the process, or nothing for the signals ignored by default
+    Default,
+    Ignore,
+    /// Runs a function of the process
+    Handler {
+        /// Called with the signal number
+        handler: u64,
+        /// Where the handler returns to. It makes the sigreturn syscall
+        /// without touching the stack.
+        restorer: u64,
+    },
+}
+
+/// A pending signal taken for delivery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Handle {
+        signal: u32,
+        handler: u64,
+        restorer: u64,
+    },
+    /// Ends the process
+    Terminate(u32),
+}
+
+/// The signals of one process
+pub struct Signals {
+    /// Bit n is set while signal n is pending
+    pending: AtomicU64,
+    actions: [SignalAction; NSIG],
+    /// The process, while it waits in the kernel for something else
+    waiters: WaitQueue,
+}
+
+impl fmt::Debug for Signals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signals")
+            .field("pending", &self.pending())
+            .field("actions", &self.actions)
+            .finish()
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether a signal does nothing unless the process handles it
+fn ignored_by_default(signal: u32) -> bool {
+    signal == SIGCHLD
+}
+
+fn check(signal: u32) -> Result<usize, SignalError> {
+    match signal as usize {
+        signal @ 1..NSIG => Ok(signal),
+        _ => Err(SignalError::InvalidSignal),
+    }
+}
+
+impl Signals {
+    pub const fn new() -> Self {
+        Signals {
+            pending: AtomicU64::new(0),
+            actions: [SignalAction::Default; NSIG],
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Marks a signal pending, waking the process if it waits in the
+    /// kernel. Sending one that is already pending has no further effect.
+    pub fn raise(&self, signal: u32) -> Result<(), SignalError> {
+        let signal = check(signal)?;
+        self.pending.fetch_or(1 << signal, Ordering::AcqRel);
+        self.waiters.wake_all();
+        Ok(())
+    }
+
+    /// Whether a pending signal would do something if delivered now
+    pub fn deliverable(&self) -> bool {
+        let pending = self.pending();
+        (1..NSIG as u32).any(|signal| {
+            pending & (1 << signal) != 0
+                && match self.actions[signal as usize] {
+                    SignalAction::Ignore => false,
+                    SignalAction::Default => !ignored_by_default(signal),
+                    SignalAction::Handler { .. } => true,
+                }
+        })
+    }
+
+    /// Waits until a signal that does something is pending, for a process
+    /// waiting in the kernel
+    pub async fn interrupted(&self) {
+        self.waiters.wait_until(|| self.deliverable()).await
+    }
+
+    /// Bitmask of the pending signals
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Sets what a signal does. Ignoring a signal drops it if it is
+    /// pending.
+    ///
+    /// # Returns
+    /// What the signal did before
+    pub fn set_action(
+        &mut self,
+        signal: u32,
+        action: SignalAction,
+    ) -> Result<SignalAction, SignalError> {
+        let index = check(signal)?;
+        if signal == SIGKILL {
+            return Err(SignalError::Uncatchable);
+        }
+        if action == SignalAction::Ignore {
+            self.pending.fetch_and(!(1 << index), Ordering::AcqRel);
+        }
+        Ok(core::mem::replace(&mut self.actions[index], action))
+    }
+
+    /// Forgets the handlers, whose code an exec replaces. Ignored signals
+    /// stay ignored, as in POSIX.
+    pub fn reset_handlers(&mut self) {
+        for action in &mut self.actions {
+            if matches!(action, SignalAction::Handler { .. }) {
+                *action = SignalAction::Default;
+            }
+        }
+    }
+
+    /// Takes the lowest pending signal that does something, dropping those
+    /// before it that are ignored
+    pub fn take(&self) -> Option<Delivery> {
+        loop {
+            let pending = self.pending.load(Ordering::Acquire);
+            if pending == 0 {
+                return None;
+            }
+            let signal = pending.trailing_zeros();
+            self.pending.fetch_and(!(1 << signal), Ordering::AcqRel);
+            match self.actions[signal as usize] {
+                SignalAction::Ignore => {}
+                SignalAction::Default if ignored_by_default(signal) => {}
+                SignalAction::Default => return Some(Delivery::Terminate(signal)),
+                SignalAction::Handler { handler, restorer } => {
+                    return Some(Delivery::Handle {
+                        signal,
+                        handler,
+                        restorer,
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Delivers the next pending signal of a process the timer interrupted in
+/// ring 3, whose registers are saved in its PCB. Runs on the process' core
+/// in its address space, without the process table locked, since writing
+/// the handler's frame may grow the stack.
+///
+/// # Returns
+/// The signal that ends the process, if one does. A handler whose frame
+/// does not fit on the stack ends it with SIGSEGV.
+pub fn deliver_pending(pid: u32) -> Option<u32> {
+    let process = get_process(pid).ok()?;
+    let pcb = process.pcb.get();
+    let (signal, handler, restorer) = match unsafe { (*pcb).signals.take()? } {
+        Delivery::Terminate(signal) => return Some(signal),
+        Delivery::Handle {
+            signal,
+            handler,
+            restorer,
+        } => (signal, handler, restorer),
+    };
+
+    let mut registers = unsafe { (*pcb).registers };
+    let size = size_of::<Registers>() as u64;
+    // Aligned so the stack is as the ABI has it on entry to a function
+    let Some(frame) = registers
+        .rsp
+        .checked_sub(RED_ZONE + size)
+        .map(|frame| frame & !0xF)
+    else {
+        return Some(SIGSEGV);
+    };
+    let return_address = frame - 8;
+    if check_user_range(return_address, size + 8, true).is_err() {
+        return Some(SIGSEGV);
+    }
+    unsafe {
+        (frame as *mut Registers).write(registers);
+        (return_address as *mut u64).write(restorer);
+    }
+    registers.rsp = return_address;
+    registers.rip = handler;
+    registers.rdi = signal as u64;
+
+    // Held while the PCB changes, as in the timer handler
+    let _table = PROCESS_TABLE.write();
+    unsafe { (*pcb).registers = registers };
+    None
+}
+
+/// Ends the process running on this core with a signal, from a syscall or
+/// exception it entered the kernel through, and goes back to the event
+/// runner the process was entered from
+///
+/// # Returns
+/// Only if the process is already gone
+pub fn terminate_current(pid: u32, signal: u32) {
+    serial_println!("Process {} killed by signal {}", pid, signal);
+    let Ok(preemption_info) = exit_process(pid, SIGNAL_EXIT_BASE + signal as i32) else {
+        return;
+    };
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "push {1}",
+            "ret",
+            in(reg) preemption_info.0,
+            in(reg) preemption_info.1,
+            options(noreturn)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::syscalls::{SIGTERM, SIGUSR1, SIGUSR2};
+
+    #[test_case]
+    fn test_signal_actions() {
+        let mut signals = Signals::new();
+        assert_eq!(signals.raise(0), Err(SignalError::InvalidSignal));
+        assert_eq!(signals.raise(NSIG as u32), Err(SignalError::InvalidSignal));
+        assert_eq!(
+            signals.set_action(SIGKILL, SignalAction::Ignore),
+            Err(SignalError::Uncatchable)
+        );
+        assert_eq!(signals.take(), None);
+
+        let handler = SignalAction::Handler {
+            handler: 0x1000,
+            restorer: 0x2000,
+        };
+        assert_eq!(
+            signals.set_action(SIGUSR2, handler),
+            Ok(SignalAction::Default)
+        );
+        signals.set_action(SIGUSR1, SignalAction::Ignore).unwrap();
+
+        // Only signals that do something cut a wait in the kernel short
+        signals.raise(SIGUSR1).unwrap();
+        signals.raise(SIGCHLD).unwrap();
+        assert!(!signals.deliverable());
+        signals.raise(SIGTERM).unwrap();
+        assert!(signals.deliverable());
+
+        // Ignored signals are passed over, lowest first otherwise
+        for signal in [SIGTERM, SIGUSR2, SIGUSR1, SIGCHLD, SIGUSR2] {
+            signals.raise(signal).unwrap();
+        }
+        assert_eq!(
+            signals.take(),
+            Some(Delivery::Handle {
+                signal: SIGUSR2,
+                handler: 0x1000,
+                restorer: 0x2000
+            })
+        );
+        assert_eq!(signals.take(), Some(Delivery::Terminate(SIGTERM)));
+        assert_eq!(signals.take(), None);
+        assert_eq!(signals.pending(), 0);
+        assert!(!signals.deliverable());
+
+        // Ignoring a pending signal drops it
+        signals.raise(SIGTERM).unwrap();
+        signals.set_action(SIGTERM, SignalAction::Ignore).unwrap();
+        assert_eq!(signals.pending(), 0);
+
+        signals.reset_handlers();
+        assert_eq!(
+            signals.set_action(SIGUSR2, SignalAction::Default),
+            Ok(SignalAction::Default)
+        );
+        assert_eq!(
+            signals.set_action(SIGUSR1, SignalAction::Default),
+            Ok(SignalAction::Ignore)
+        );
+    }
+}
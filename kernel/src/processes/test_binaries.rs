@@ -0,0 +1,50 @@
+//! User programs embedded in the kernel for testing.
+//!
+//! The registry is generated by the build script from the executables in
+//! `src/processes/test_binaries`, named after their files.
+
+/// An embedded user program
+#[derive(Debug)]
+pub struct TestBinary {
+    /// File name of the binary
+    pub name: &'static str,
+    /// The ELF image
+    pub elf: &'static [u8],
+    /// Entry point recorded in the ELF header
+    pub entry_point: u64,
+    /// Assembly source next to the binary, if there is one
+    pub source: Option<&'static str>,
+}
+
+static TEST_BINARIES: &[TestBinary] = &include!(concat!(env!("OUT_DIR"), "/test_binaries.rs"));
+
+/// Returns every embedded test binary, sorted by name
+pub fn all() -> &'static [TestBinary] {
+    TEST_BINARIES
+}
+
+/// Returns the ELF image of the test binary named `name`
+pub fn by_name(name: &str) -> Option<&'static [u8]> {
+    TEST_BINARIES
+        .iter()
+        .find(|binary| binary.name == name)
+        .map(|binary| binary.elf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_registry() {
+        assert!(all().windows(2).all(|pair| pair[0].name < pair[1].name));
+        assert!(by_name("syscall_test").is_some());
+        // Object files and sources are not programs
+        assert!(by_name("rand_regs_exit.o").is_none());
+        assert!(by_name("rand_regs.asm").is_none());
+
+        let binary = all().iter().find(|b| b.name == "rand_regs").unwrap();
+        assert_eq!(binary.source, Some("rand_regs.asm"));
+        assert_eq!(&binary.elf[..4], b"\x7fELF");
+    }
+}
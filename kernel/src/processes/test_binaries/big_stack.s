@@ -0,0 +1,44 @@
+# Declares its resource needs in TAOS ELF notes, then writes to the bottom
+# of the larger stack it asked for and exits with status 0.
+#
+# Build with GNU binutils:
+#   as --64 -o big_stack.o big_stack.s
+#   ld -o big_stack big_stack.o
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 1
+
+    .set NT_TAOS_STACK_SIZE, 1
+    .set NT_TAOS_HEAP_SIZE, 2
+    .set NT_TAOS_FEATURES, 3
+
+    .set FEATURE_PROCESSES, 2
+
+    .set STACK_SIZE, 0x10000
+
+# Adds a note owned by TAOS holding one u64
+.macro taos_note type, value
+    .balign 4
+    .long 5                         # Name size, with the NUL
+    .long 8                         # Descriptor size
+    .long \type
+    .asciz "TAOS"
+    .balign 4
+    .quad \value
+.endm
+
+    .section .note.taos, "a", @note
+    taos_note NT_TAOS_STACK_SIZE, STACK_SIZE
+    taos_note NT_TAOS_HEAP_SIZE, 0x4000
+    taos_note NT_TAOS_FEATURES, FEATURE_PROCESSES
+
+    .text
+    .globl _start
+_start:
+    # Only mapped if the loader honoured the stack size note
+    mov qword ptr [rsp - STACK_SIZE + 8], 1
+
+    mov rax, SYS_EXIT
+    xor rdi, rdi
+    int 0x80
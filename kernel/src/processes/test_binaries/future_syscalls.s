@@ -0,0 +1,27 @@
+# Requires a syscall feature bit no kernel has yet, so the loader must
+# refuse it. Exits with status 1 if it ever runs.
+#
+# Build with GNU binutils:
+#   as --64 -o future_syscalls.o future_syscalls.s
+#   ld -o future_syscalls future_syscalls.o
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 1
+    .set NT_TAOS_FEATURES, 3
+
+    .section .note.taos, "a", @note
+    .balign 4
+    .long 5
+    .long 8
+    .long NT_TAOS_FEATURES
+    .asciz "TAOS"
+    .balign 4
+    .quad 1 << 63
+
+    .text
+    .globl _start
+_start:
+    mov rax, SYS_EXIT
+    mov rdi, 1
+    int 0x80
@@ -1,3 +1,7 @@
+section .rodata
+message: db "long loop done", 10
+message_len equ $ - message
+
 section .text
     global _start
 
@@ -10,6 +14,8 @@ _loop:
     cmp rbx, 0
     jg _loop
 
+    mov rdi, message
+    mov rsi, message_len
     int 0x80
     mov rax, 1
-    int 0x80
\ No newline at end of file
+    int 0x80
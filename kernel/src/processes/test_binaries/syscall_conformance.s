@@ -0,0 +1,351 @@
+# Syscall conformance suite. Calls every syscall with valid and invalid
+# arguments, compares each result with the expected one, and reports a
+# PASS or FAIL line per case over print. Its last act is a sigreturn with
+# a forged frame, which must end it with SIGSEGV rather than return to a
+# non-canonical address. Should the kernel let the call return, the suite
+# reports the failure and exits with the number of failures.
+#
+# Build with GNU binutils:
+#   as --64 -o syscall_conformance.o syscall_conformance.s
+#   ld -o syscall_conformance syscall_conformance.o
+
+    .intel_syntax noprefix
+
+    .set SYS_EXIT, 1
+    .set SYS_PRINT, 3
+    .set SYS_CLOCK_GETTIME, 4
+    .set SYS_SETTIMEOFDAY, 5
+    .set SYS_STATFS, 6
+    .set SYS_UTIMENSAT, 7
+    .set SYS_REBOOT, 8
+    .set SYS_NICE, 9
+    .set SYS_OPEN, 10
+    .set SYS_READ, 11
+    .set SYS_WRITE, 12
+    .set SYS_CLOSE, 13
+    .set SYS_SEEK, 14
+    .set SYS_DUP, 15
+    .set SYS_DUP2, 16
+    .set SYS_EXEC, 17
+    .set SYS_WAITPID, 18
+    .set SYS_UNAME, 19
+    .set SYS_PIPE, 22
+    .set SYS_KILL, 23
+    .set SYS_SIGACTION, 24
+    .set SYS_SIGRETURN, 25
+    .set SYS_SBRK, 27
+    .set SYS_MUNMAP, 28
+    .set SYS_MPROTECT, 29
+
+    .set REBOOT_CMD_POWER_OFF, 0x4321FEDC
+
+    .set EPERM, 1
+    .set ENOENT, 2
+    .set ESRCH, 3
+    .set EBADF, 9
+    .set ECHILD, 10
+    .set EACCES, 13
+    .set ENOMEM, 12
+    .set EFAULT, 14
+    .set EINVAL, 22
+    .set ESPIPE, 29
+    .set EPIPE, 32
+    .set ENAMETOOLONG, 36
+    .set ENOSYS, 38
+
+    .set WNOHANG, 1
+
+    .set SIGKILL, 9
+    .set SIGUSR1, 10
+    .set SIG_DFL, 0
+    .set SIG_IGN, 1
+
+    .set PATH_MAX, 4096
+    # First address past the lower half, where user memory ends
+    .set USER_END, 0x800000000000
+    .set MMAP_START, 0x600000000000
+    .set PROT_READ, 1
+    .set PROT_WRITE, 2
+    # A descriptor no process has open
+    .set BAD_FD, 1000
+    .set STDIN, 0
+    .set STDOUT, 1
+    .set KERNEL_ADDR, 0xffff800000000000
+
+# Offsets in the registers a signal handler's frame holds
+    .set REGISTERS_RSP, 15 * 8
+    .set REGISTERS_RIP, 16 * 8
+    .set REGISTERS_RFLAGS, 17 * 8
+    .set REGISTERS_SIZE, 18 * 8
+
+# Adds a case to the table: a syscall, its first three arguments and the
+# result it must return
+.macro case name, num, a1=0, a2=0, a3=0, expected=0
+    .pushsection .rodata, 1
+.Lname\@:
+    .ascii "\name"
+.Lname_end\@:
+    .popsection
+    .quad \num, \a1, \a2, \a3, \expected, .Lname\@, .Lname_end\@ - .Lname\@
+.endm
+
+    .set CASE_SIZE, 7 * 8
+
+    .section .rodata
+    .balign 8
+cases:
+    case "print writes a buffer", SYS_PRINT, hello, hello_len, 0, hello_len
+    case "print of nothing", SYS_PRINT, 0, 0, 0, 0
+    case "print from null", SYS_PRINT, 0, 5, 0, -EFAULT
+    case "print from kernel memory", SYS_PRINT, KERNEL_ADDR, 5, 0, -EFAULT
+    case "print across the user boundary", SYS_PRINT, USER_END-2, 4, 0, -EFAULT
+    case "print of a huge length", SYS_PRINT, hello, 1<<40, 0, -EINVAL
+    case "print of a wrapping length", SYS_PRINT, hello, -1, 0, -EINVAL
+
+    case "clock_gettime realtime", SYS_CLOCK_GETTIME, 0, timespec, 0, 0
+    case "clock_gettime monotonic", SYS_CLOCK_GETTIME, 1, timespec, 0, 0
+    case "clock_gettime unknown clock", SYS_CLOCK_GETTIME, 99, timespec, 0, -EINVAL
+    case "clock_gettime to null", SYS_CLOCK_GETTIME, 0, 0, 0, -EFAULT
+    case "clock_gettime to kernel memory", SYS_CLOCK_GETTIME, 0, KERNEL_ADDR, 0, -EFAULT
+    case "clock_gettime to read-only memory", SYS_CLOCK_GETTIME, 0, cases, 0, -EFAULT
+    case "clock_gettime misaligned", SYS_CLOCK_GETTIME, 0, timespec+1, 0, -EFAULT
+
+    case "settimeofday from null", SYS_SETTIMEOFDAY, 0, 0, 0, -EFAULT
+    case "settimeofday from kernel memory", SYS_SETTIMEOFDAY, KERNEL_ADDR, 0, 0, -EFAULT
+    case "settimeofday bad nanoseconds", SYS_SETTIMEOFDAY, bad_timespec, 0, 0, -EINVAL
+    # The suite is not launched by the kernel, and timespec holds the time
+    # clock_gettime read
+    case "settimeofday without privilege", SYS_SETTIMEOFDAY, timespec, 0, 0, -EPERM
+
+    case "statfs null path", SYS_STATFS, 0, statfs, 0, -EFAULT
+    case "statfs kernel path", SYS_STATFS, KERNEL_ADDR, statfs, 0, -EFAULT
+    case "statfs overlong path", SYS_STATFS, long_path, statfs, 0, -ENAMETOOLONG
+    case "statfs to null", SYS_STATFS, root, 0, 0, -EFAULT
+
+    case "utimensat null path", SYS_UTIMENSAT, 0, 0, 0, -EFAULT
+    case "utimensat overlong path", SYS_UTIMENSAT, long_path, 0, 0, -ENAMETOOLONG
+    case "utimensat times from kernel memory", SYS_UTIMENSAT, root, KERNEL_ADDR, 0, -EFAULT
+    case "utimensat bad nanoseconds", SYS_UTIMENSAT, root, bad_times, 0, -EINVAL
+
+    case "reboot unknown command", SYS_REBOOT, 0, 0, 0, -EINVAL
+    # Were the suite allowed to, this would end the run
+    case "reboot without privilege", SYS_REBOOT, REBOOT_CMD_POWER_OFF, 0, 0, -EPERM
+
+    case "nice by zero", SYS_NICE, 0, 0, 0, 0
+    case "nice lowers priority", SYS_NICE, 1, 0, 0, 1
+    case "nice restores priority", SYS_NICE, -1, 0, 0, 0
+    case "nice above the default", SYS_NICE, -1, 0, 0, -EPERM
+
+    case "open null path", SYS_OPEN, 0, 0, 0, -EFAULT
+    case "open unknown flags", SYS_OPEN, root, 0x8000, 0, -EINVAL
+    case "open overlong path", SYS_OPEN, long_path, 0, 0, -ENAMETOOLONG
+    case "open missing file", SYS_OPEN, missing, 0, 0, -ENOENT
+    case "read bad descriptor", SYS_READ, BAD_FD, timespec, 16, -EBADF
+    case "read negative descriptor", SYS_READ, -1, timespec, 16, -EBADF
+    case "write bad descriptor", SYS_WRITE, BAD_FD, hello, hello_len, -EBADF
+    case "close bad descriptor", SYS_CLOSE, BAD_FD, 0, 0, -EBADF
+    case "seek bad descriptor", SYS_SEEK, BAD_FD, 0, 0, -EBADF
+    case "seek unknown origin", SYS_SEEK, BAD_FD, 0, 9, -EINVAL
+    case "seek before the start", SYS_SEEK, BAD_FD, -1, 0, -EINVAL
+    case "seek on the console", SYS_SEEK, STDOUT, 0, 0, -ESPIPE
+    case "write to stdout", SYS_WRITE, STDOUT, hello, hello_len, hello_len
+    case "write to stdout from null", SYS_WRITE, STDOUT, 0, 5, -EFAULT
+    case "read from stdin", SYS_READ, STDIN, timespec, 16, 0
+    case "read to read-only memory", SYS_READ, STDIN, cases, 16, -EFAULT
+    case "dup stdout", SYS_DUP, STDOUT, 0, 0, 3
+    case "dup2 onto itself", SYS_DUP2, 3, 3, 0, 3
+    case "dup2 out of range", SYS_DUP2, STDOUT, BAD_FD, 0, -EBADF
+    case "dup2 bad descriptor", SYS_DUP2, BAD_FD, 4, 0, -EBADF
+    case "dup bad descriptor", SYS_DUP, BAD_FD, 0, 0, -EBADF
+    case "close duplicate", SYS_CLOSE, 3, 0, 0, 0
+    case "close closed duplicate", SYS_CLOSE, 3, 0, 0, -EBADF
+
+    # Each pipe takes descriptors 3 and 4
+    case "pipe to null", SYS_PIPE, 0, 0, 0, -EFAULT
+    case "pipe to kernel memory", SYS_PIPE, KERNEL_ADDR, 0, 0, -EFAULT
+    case "pipe to read-only memory", SYS_PIPE, cases, 0, 0, -EFAULT
+    case "pipe", SYS_PIPE, pipe_fds, 0, 0, 0
+    case "read from a pipe's write end", SYS_READ, 4, statfs, 16, -EBADF
+    case "write to a pipe's read end", SYS_WRITE, 3, hello, hello_len, -EBADF
+    case "write to a pipe", SYS_WRITE, 4, hello, hello_len, hello_len
+    case "read part of a pipe", SYS_READ, 3, statfs, 16, 16
+    case "read the rest of a pipe", SYS_READ, 3, statfs, 256, hello_len-16
+    case "seek on a pipe", SYS_SEEK, 3, 0, 0, -ESPIPE
+    case "close a pipe's write end", SYS_CLOSE, 4, 0, 0, 0
+    case "read at the end of a pipe", SYS_READ, 3, statfs, 16, 0
+    case "close a pipe's read end", SYS_CLOSE, 3, 0, 0, 0
+    case "pipe again", SYS_PIPE, pipe_fds, 0, 0, 0
+    case "close the new pipe's read end", SYS_CLOSE, 3, 0, 0, 0
+    case "write to a pipe nobody reads", SYS_WRITE, 4, hello, hello_len, -EPIPE
+    case "close the new pipe's write end", SYS_CLOSE, 4, 0, 0, 0
+
+    case "exec null path", SYS_EXEC, 0, 0, 0, -EFAULT
+    case "exec overlong path", SYS_EXEC, long_path, 0, 0, -ENAMETOOLONG
+    case "exec missing file", SYS_EXEC, missing, 0, 0, -ENOENT
+    case "exec a directory", SYS_EXEC, root, 0, 0, -EACCES
+
+    case "waitpid with no children", SYS_WAITPID, -1, 0, 0, -ECHILD
+    case "waitpid for a missing child", SYS_WAITPID, BAD_FD, timespec, WNOHANG, -ECHILD
+    case "waitpid for a process group", SYS_WAITPID, 0, 0, 0, -EINVAL
+    case "waitpid unknown options", SYS_WAITPID, -1, 0, 2, -EINVAL
+    case "waitpid status to kernel memory", SYS_WAITPID, -1, KERNEL_ADDR, 0, -EFAULT
+    case "waitpid status to read-only memory", SYS_WAITPID, -1, cases, 0, -EFAULT
+
+    case "kill with an unknown signal", SYS_KILL, 1, 99, 0, -EINVAL
+    case "kill a missing process", SYS_KILL, BAD_FD, 0, 0, -ESRCH
+    case "sigaction unknown signal", SYS_SIGACTION, 99, SIG_IGN, 0, -EINVAL
+    case "sigaction for SIGKILL", SYS_SIGACTION, SIGKILL, SIG_IGN, 0, -EINVAL
+    case "sigaction handler without a restorer", SYS_SIGACTION, SIGUSR1, print, 0, -EINVAL
+    case "sigaction handler in kernel memory", SYS_SIGACTION, SIGUSR1, KERNEL_ADDR, print, -EINVAL
+    case "sigaction ignores a signal", SYS_SIGACTION, SIGUSR1, SIG_IGN, 0, SIG_DFL
+    case "sigaction restores the default", SYS_SIGACTION, SIGUSR1, SIG_DFL, 0, SIG_IGN
+
+    case "sbrk below the heap", SYS_SBRK, -(1<<40), 0, 0, -ENOMEM
+    case "sbrk into the mapping area", SYS_SBRK, 0x600000000000, 0, 0, -ENOMEM
+    case "sbrk past the address space", SYS_SBRK, 1<<62, 0, 0, -ENOMEM
+
+    case "munmap unaligned address", SYS_MUNMAP, MMAP_START+1, 4096, 0, -EINVAL
+    case "munmap of nothing", SYS_MUNMAP, MMAP_START, 0, 0, -EINVAL
+    case "munmap past the user boundary", SYS_MUNMAP, USER_END-4096, 8192, 0, -EINVAL
+    case "munmap where nothing is mapped", SYS_MUNMAP, MMAP_START, 4096, 0, 0
+    case "mprotect unknown protection", SYS_MPROTECT, MMAP_START, 4096, 4, -EINVAL
+    case "mprotect without read access", SYS_MPROTECT, MMAP_START, 4096, PROT_WRITE, -EINVAL
+    case "mprotect unaligned address", SYS_MPROTECT, MMAP_START+1, 4096, PROT_READ, -EINVAL
+    case "mprotect of nothing", SYS_MPROTECT, MMAP_START, 0, PROT_READ, 0
+    case "mprotect where nothing is mapped", SYS_MPROTECT, MMAP_START, 4096, PROT_READ, -ENOMEM
+
+    case "uname", SYS_UNAME, utsname, 0, 0, 0
+    case "uname to null", SYS_UNAME, 0, 0, 0, -EFAULT
+    case "uname to kernel memory", SYS_UNAME, KERNEL_ADDR, 0, 0, -EFAULT
+    case "uname to read-only memory", SYS_UNAME, cases, 0, 0, -EFAULT
+
+    case "syscall 0", 0, 0, 0, 0, -ENOSYS
+    case "unassigned syscall", 0xffff, 0, 0, 0, -ENOSYS
+cases_end:
+
+hello:
+    .ascii "syscall conformance: starting\n"
+    .set hello_len, . - hello
+pass:
+    .ascii "PASS "
+fail:
+    .ascii "FAIL "
+newline:
+    .ascii "\n"
+all_passed:
+    .ascii "syscall conformance: all cases passed\n"
+    .set all_passed_len, . - all_passed
+some_failed:
+    .ascii "syscall conformance: some cases failed\n"
+    .set some_failed_len, . - some_failed
+forged_frame:
+    .ascii "sigreturn to a non-canonical address\n"
+    .set forged_frame_len, . - forged_frame
+root:
+    .asciz "/"
+missing:
+    .asciz "/no/such/file"
+
+    .section .data
+    .balign 8
+bad_timespec:
+    .quad 0, 1000000000
+bad_times:
+    .quad 0, 0
+    .quad 0, -5
+
+    .section .bss
+    .balign 8
+timespec:
+    .skip 16
+pipe_fds:
+    .skip 8
+statfs:
+    .skip 256
+utsname:
+    .skip 256
+# PATH_MAX bytes with no NUL, filled in at startup
+long_path:
+    .skip PATH_MAX + 1
+
+    .section .text
+    .global _start
+_start:
+    # Fill the overlong path
+    lea rdi, [rip + long_path]
+    mov rcx, PATH_MAX
+    mov al, 'a'
+    rep stosb
+    mov byte ptr [rdi], 0
+
+    lea rbx, [rip + cases]
+    xor r12, r12                    # Failures so far
+
+next_case:
+    lea rax, [rip + cases_end]
+    cmp rbx, rax
+    jae done
+
+    mov rax, [rbx]
+    mov rdi, [rbx + 8]
+    mov rsi, [rbx + 16]
+    mov rdx, [rbx + 24]
+    int 0x80
+
+    lea r13, [rip + pass]
+    cmp rax, [rbx + 32]
+    je report
+    lea r13, [rip + fail]
+    inc r12
+
+report:
+    mov rdi, r13
+    mov rsi, 5
+    call print
+    mov rdi, [rbx + 40]
+    mov rsi, [rbx + 48]
+    call print
+    lea rdi, [rip + newline]
+    mov rsi, 1
+    call print
+
+    add rbx, CASE_SIZE
+    jmp next_case
+
+done:
+    lea rdi, [rip + all_passed]
+    mov rsi, all_passed_len
+    test r12, r12
+    jz summary
+    lea rdi, [rip + some_failed]
+    mov rsi, some_failed_len
+summary:
+    call print
+
+    # A saved register frame, as signal delivery pushes it, whose rip is
+    # the first address past the lower half
+    sub rsp, REGISTERS_SIZE
+    mov qword ptr [rsp + REGISTERS_RSP], rsp
+    mov rax, USER_END
+    mov qword ptr [rsp + REGISTERS_RIP], rax
+    mov qword ptr [rsp + REGISTERS_RFLAGS], 0x202
+    mov rax, SYS_SIGRETURN
+    int 0x80
+    add rsp, REGISTERS_SIZE
+    inc r12
+    lea rdi, [rip + fail]
+    mov rsi, 5
+    call print
+    lea rdi, [rip + forged_frame]
+    mov rsi, forged_frame_len
+    call print
+
+    mov rax, SYS_EXIT
+    mov rdi, r12                    # Exit with the number of failures
+    int 0x80
+
+# Prints rsi bytes at rdi
+print:
+    mov rax, SYS_PRINT
+    int 0x80
+    ret
@@ -0,0 +1,308 @@
+//! Kernel entropy pool and random number generation.
+//!
+//! Entropy from the host (through virtio-rng) and from TSC jitter is mixed
+//! into a 256 bit pool key. Output is produced by the ChaCha20 block function
+//! keyed with the pool, and the key is replaced after every request so that
+//! earlier output cannot be recovered from the current state.
+//!
+//! Harvesting is slow, as the host may take a while to answer, so entropy
+//! is always gathered before the pool is locked. An idle event mixes in a
+//! fresh harvest now and then, so requests rarely have to harvest first.
+
+use core::arch::x86_64::_rdtsc;
+use spin::Mutex;
+
+use crate::{
+    debug_println,
+    devices::{manager::find_device_data, virtio::rng::VirtioRng},
+    events::sleep_until,
+    time,
+};
+
+/// The kernel's global entropy pool
+pub static ENTROPY_POOL: Mutex<EntropyPool> = Mutex::new(EntropyPool::new());
+
+/// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+/// Nonce used when stirring input into the key, distinct from output blocks
+const MIX_NONCE: [u32; 2] = [0x786d, 0];
+/// Nonce used for output blocks
+const OUTPUT_NONCE: [u32; 2] = [0, 0];
+
+/// Number of output bytes after which the pool is reseeded from the host
+const RESEED_INTERVAL: usize = 64 * 1024;
+/// Number of bytes requested from the host per harvest
+const HARVEST_SIZE: usize = 32;
+/// Number of TSC samples taken per jitter harvest
+const JITTER_SAMPLES: usize = 64;
+/// Maximum entropy the pool can hold in bits
+const POOL_BITS: usize = 256;
+/// How often the idle harvester mixes fresh entropy into the pool
+const HARVEST_INTERVAL_NS: u64 = 1_000_000_000;
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function with a 64 bit counter and 64 bit nonce
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: [u32; 2]) -> [u32; 16] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter as u32;
+    initial[13] = (counter >> 32) as u32;
+    initial[14] = nonce[0];
+    initial[15] = nonce[1];
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, initial) in state.iter_mut().zip(initial) {
+        *word = word.wrapping_add(initial);
+    }
+    state
+}
+
+/// A pool of entropy that random bytes are drawn from
+pub struct EntropyPool {
+    /// The ChaCha20 key all output is derived from
+    key: [u32; 8],
+    /// Block counter, never reused under the same key
+    counter: u64,
+    /// Estimate of how many bits of entropy have been mixed in
+    entropy_bits: usize,
+    /// Bytes handed out since the last harvest from the host
+    bytes_since_reseed: usize,
+}
+
+impl Default for EntropyPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EntropyPool {
+    /// Creates an empty pool. It must be seeded before its output is
+    /// unpredictable.
+    pub const fn new() -> Self {
+        EntropyPool {
+            key: [0; 8],
+            counter: 0,
+            entropy_bits: 0,
+            bytes_since_reseed: 0,
+        }
+    }
+
+    /// Mixes bytes into the pool
+    ///
+    /// # Arguments
+    /// * `bytes` - The input to mix in
+    /// * `entropy_bits` - A conservative estimate of the entropy of the input
+    pub fn mix(&mut self, bytes: &[u8], entropy_bits: usize) {
+        for chunk in bytes.chunks(32) {
+            for (i, word) in chunk.chunks(4).enumerate() {
+                let mut padded = [0u8; 4];
+                padded[..word.len()].copy_from_slice(word);
+                self.key[i] ^= u32::from_le_bytes(padded);
+            }
+            let block = chacha20_block(&self.key, self.counter, MIX_NONCE);
+            self.counter = self.counter.wrapping_add(1);
+            self.key.copy_from_slice(&block[..8]);
+        }
+        self.entropy_bits = (self.entropy_bits + entropy_bits).min(POOL_BITS);
+    }
+
+    /// Returns the estimated entropy of the pool in bits
+    pub fn entropy_bits(&self) -> usize {
+        self.entropy_bits
+    }
+
+    /// Returns true if the pool should be reseeded from the host
+    pub fn needs_reseed(&self) -> bool {
+        self.entropy_bits < POOL_BITS || self.bytes_since_reseed >= RESEED_INTERVAL
+    }
+
+    /// Fills `buffer` with output derived from the pool and rekeys the pool
+    pub fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(64) {
+            let block = chacha20_block(&self.key, self.counter, OUTPUT_NONCE);
+            self.counter = self.counter.wrapping_add(1);
+            for (byte, value) in chunk
+                .iter_mut()
+                .zip(block.iter().flat_map(|word| word.to_le_bytes()))
+            {
+                *byte = value;
+            }
+        }
+        let block = chacha20_block(&self.key, self.counter, OUTPUT_NONCE);
+        self.counter = self.counter.wrapping_add(1);
+        self.key.copy_from_slice(&block[..8]);
+        self.bytes_since_reseed = self.bytes_since_reseed.saturating_add(buffer.len());
+    }
+}
+
+/// Samples timing jitter of the TSC. Each sample is only credited with
+/// an eighth of a bit, as the jitter is easy to predict in a VM.
+fn harvest_jitter() -> [u8; JITTER_SAMPLES] {
+    let mut samples = [0u8; JITTER_SAMPLES];
+    let mut previous = unsafe { _rdtsc() };
+    for sample in samples.iter_mut() {
+        let mut spin = 0u64;
+        for i in 0..(previous & 0xFF) {
+            spin = core::hint::black_box(spin.wrapping_add(i));
+        }
+        let now = unsafe { _rdtsc() };
+        *sample = (now.wrapping_sub(previous) ^ spin) as u8;
+        previous = now;
+    }
+    samples
+}
+
+/// Requests random bytes from the host, if a virtio entropy device exists
+///
+/// # Returns
+/// The bytes read and how many of them are valid
+fn harvest_device() -> ([u8; HARVEST_SIZE], usize) {
+    let mut bytes = [0u8; HARVEST_SIZE];
+    let read = match find_device_data::<VirtioRng>() {
+        Some(rng) => rng.lock().read(&mut bytes).unwrap_or(0),
+        None => 0,
+    };
+    (bytes, read)
+}
+
+/// Entropy gathered without the pool locked, ready to be mixed in
+struct Harvest {
+    /// Bytes from the host, of which `read` are valid
+    device: [u8; HARVEST_SIZE],
+    read: usize,
+    jitter: [u8; JITTER_SAMPLES],
+}
+
+impl Harvest {
+    /// Gathers entropy from the host and from TSC jitter
+    fn collect() -> Self {
+        let (device, read) = harvest_device();
+        Harvest {
+            device,
+            read,
+            jitter: harvest_jitter(),
+        }
+    }
+
+    /// Mixes the harvest into `pool`. Only bytes from the host count as a
+    /// reseed.
+    fn mix_into(&self, pool: &mut EntropyPool) {
+        pool.mix(&self.jitter, JITTER_SAMPLES / 8);
+        if self.read > 0 {
+            pool.mix(&self.device[..self.read], self.read * 8);
+            pool.bytes_since_reseed = 0;
+        }
+    }
+}
+
+/// Seeds the entropy pool. Should be called once devices are initialized.
+pub fn init() {
+    let harvest = Harvest::collect();
+    let mut pool = ENTROPY_POOL.lock();
+    harvest.mix_into(&mut pool);
+    pool.bytes_since_reseed = 0;
+    debug_println!("Entropy pool seeded with {} bits", pool.entropy_bits());
+}
+
+/// Fills `buffer` with random bytes, reseeding first if the pool is
+/// running low
+pub fn fill_bytes(buffer: &mut [u8]) {
+    // Another caller may reseed while this one harvests, but mixing in a
+    // second harvest does no harm
+    let harvest = ENTROPY_POOL.lock().needs_reseed().then(Harvest::collect);
+    let mut pool = ENTROPY_POOL.lock();
+    if let Some(harvest) = harvest {
+        harvest.mix_into(&mut pool);
+    }
+    pool.fill_bytes(buffer);
+}
+
+/// Mixes a fresh harvest into the pool now and then. Runs forever, should
+/// be scheduled with `schedule_idle`.
+pub async fn harvest_daemon() {
+    loop {
+        sleep_until(time::monotonic_ns() + HARVEST_INTERVAL_NS).await;
+        let harvest = Harvest::collect();
+        harvest.mix_into(&mut ENTROPY_POOL.lock());
+    }
+}
+
+/// Returns a random u64
+pub fn random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Block function test vector from RFC 7539 section 2.3.2. The RFC's
+    /// 96 bit nonce maps onto the high counter word and our 64 bit nonce.
+    #[test_case]
+    fn test_chacha20_block() {
+        let mut key = [0u32; 8];
+        for (i, word) in key.iter_mut().enumerate() {
+            let base = (i * 4) as u8;
+            *word = u32::from_le_bytes([base, base + 1, base + 2, base + 3]);
+        }
+        let block = chacha20_block(&key, 1 | (0x09000000 << 32), [0x4a000000, 0]);
+        assert_eq!(block[0], 0xe4e7f110);
+        assert_eq!(block[1], 0x15593bd1);
+        assert_eq!(block[15], 0x4e3c50a2);
+    }
+
+    #[test_case]
+    fn test_pool_output_changes() {
+        let mut pool = EntropyPool::new();
+        pool.mix(b"some seed material", 0);
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        pool.fill_bytes(&mut first);
+        pool.fill_bytes(&mut second);
+        assert_ne!(first, second);
+    }
+
+    #[test_case]
+    fn test_harvest_reseeds() {
+        let mut pool = EntropyPool::new();
+        pool.bytes_since_reseed = RESEED_INTERVAL;
+
+        // Jitter alone is credited but is not a reseed from the host
+        let mut harvest = Harvest {
+            device: [0x5A; HARVEST_SIZE],
+            read: 0,
+            jitter: harvest_jitter(),
+        };
+        harvest.mix_into(&mut pool);
+        assert_eq!(pool.entropy_bits(), JITTER_SAMPLES / 8);
+        assert!(pool.needs_reseed());
+
+        harvest.read = HARVEST_SIZE;
+        harvest.mix_into(&mut pool);
+        assert_eq!(pool.entropy_bits(), POOL_BITS);
+        assert!(!pool.needs_reseed());
+    }
+}
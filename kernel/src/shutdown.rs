@@ -0,0 +1,178 @@
+//! Orderly shutdown and reboot.
+//!
+//! Subsystems register stop hooks for the stage they belong to. On shutdown
+//! the stages run in order, so filesystems are flushed while the block
+//! devices under them still work, and block devices are flushed before
+//! interrupts are turned off. Hooks of one stage run in registration order.
+//!
+//! Hooks are futures so a hook stuck on a lock or a device cannot hang the
+//! machine: each is polled until it completes or its timeout passes, and
+//! shutdown moves on either way. Only after every hook has run is the QEMU
+//! exit port or the reset line hit.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures::task::noop_waker_ref;
+use spin::{Mutex, MutexGuard};
+
+use crate::{
+    arch,
+    constants::ports::KEYBOARD_CONTROLLER_PORT,
+    exit_qemu, idle_loop, serial_println,
+    time::{self, NANOS_PER_SEC},
+    QemuExitCode,
+};
+
+/// Timeout for hooks that do not need a specific one
+pub const DEFAULT_STOP_TIMEOUT_NS: u64 = 2 * NANOS_PER_SEC;
+
+/// Command that pulses the CPU reset line through the keyboard controller
+const RESET_CPU_COMMAND: u8 = 0xFE;
+
+/// When a stop hook runs. Stages run in the order listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StopStage {
+    /// Flush and detach filesystems
+    Filesystems,
+    /// Flush block caches and devices
+    BlockDevices,
+    /// Stop interrupt delivery
+    Interrupts,
+}
+
+/// What happens once every hook has run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownAction {
+    /// Exit QEMU with the given code, or halt on real hardware
+    PowerOff(QemuExitCode),
+    /// Reset the machine
+    Reboot,
+}
+
+/// Future a stop hook returns. Errors are logged and do not stop shutdown.
+pub type StopFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+
+#[derive(Clone, Copy)]
+struct StopHook {
+    name: &'static str,
+    stage: StopStage,
+    timeout_ns: u64,
+    start: fn() -> StopFuture,
+}
+
+static STOP_HOOKS: Mutex<Vec<StopHook>> = Mutex::new(Vec::new());
+
+/// Set once shutdown starts, so a panic during shutdown exits right away
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Registers a hook to run on shutdown
+///
+/// # Arguments
+/// * `name` - Name logged while the hook runs
+/// * `stage` - Stage the hook runs in
+/// * `timeout_ns` - How long the hook may take before shutdown moves on
+/// * `start` - Creates the hook's future
+pub fn register_stop_hook(
+    name: &'static str,
+    stage: StopStage,
+    timeout_ns: u64,
+    start: fn() -> StopFuture,
+) {
+    STOP_HOOKS.lock().push(StopHook {
+        name,
+        stage,
+        timeout_ns,
+        start,
+    });
+}
+
+/// Waits for a lock by returning Pending while it is held, so a hook
+/// waiting on a lock kept by a stuck or panicked core still times out
+pub fn lock<T>(mutex: &Mutex<T>) -> impl Future<Output = MutexGuard<'_, T>> {
+    poll_fn(|_| mutex.try_lock().map_or(Poll::Pending, Poll::Ready))
+}
+
+/// Returns true once shutdown has started
+pub fn shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Acquire)
+}
+
+/// Runs every stop hook, then powers off or reboots
+pub fn shutdown(action: ShutdownAction) -> ! {
+    if SHUTTING_DOWN.swap(true, Ordering::AcqRel) {
+        serial_println!("shutdown: stopping again during shutdown, skipping hooks");
+    } else {
+        run_stop_hooks();
+    }
+
+    match action {
+        ShutdownAction::PowerOff(code) => exit_qemu(code),
+        ShutdownAction::Reboot => unsafe {
+            arch::port_write_u8(KEYBOARD_CONTROLLER_PORT, RESET_CPU_COMMAND);
+        },
+    }
+    idle_loop();
+}
+
+fn run_stop_hooks() {
+    // The lock may be held by whoever panicked
+    let Some(hooks) = STOP_HOOKS.try_lock() else {
+        serial_println!("shutdown: hook table is locked, skipping hooks");
+        return;
+    };
+    let mut hooks = hooks.clone();
+    hooks.sort_by_key(|hook| hook.stage);
+
+    for hook in hooks {
+        serial_println!("shutdown: stopping {} ({:?})", hook.name, hook.stage);
+        let started = time::monotonic_ns();
+        match run_with_timeout((hook.start)(), hook.timeout_ns) {
+            Some(Ok(())) => {
+                let elapsed_ms = (time::monotonic_ns() - started) / 1_000_000;
+                serial_println!("shutdown: {} stopped in {} ms", hook.name, elapsed_ms);
+            }
+            Some(Err(e)) => serial_println!("shutdown: {} failed: {}", hook.name, e),
+            None => serial_println!("shutdown: {} timed out, moving on", hook.name),
+        }
+    }
+    serial_println!("shutdown: all hooks done");
+}
+
+/// Polls a hook until it completes or `timeout_ns` passes
+///
+/// # Returns
+/// None if the hook timed out. Before the clock is calibrated, a hook that
+/// does not complete on its first poll times out.
+fn run_with_timeout(mut hook: StopFuture, timeout_ns: u64) -> Option<Result<(), String>> {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let deadline = time::monotonic_ns().saturating_add(timeout_ns);
+    loop {
+        if let Poll::Ready(result) = hook.as_mut().poll(&mut cx) {
+            return Some(result);
+        }
+        if time::tsc_frequency() == 0 || time::monotonic_ns() >= deadline {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::{pending, ready};
+
+    #[test_case]
+    fn test_hook_timeout() {
+        let done: StopFuture = Box::pin(ready(Ok(())));
+        assert_eq!(run_with_timeout(done, 0), Some(Ok(())));
+
+        let stuck: StopFuture = Box::pin(pending());
+        assert_eq!(run_with_timeout(stuck, NANOS_PER_SEC / 100), None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Resource exhaustion tests.
+//!
+//! Each test drives a kernel resource to its limit and checks that running
+//! out is reported as an error the caller can handle, and that everything
+//! works again once the resource is released. They live apart from the
+//! unit tests because they briefly starve the whole kernel.
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures::task::noop_waker_ref;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, TranslateError},
+        Mapper, Page, PageTableFlags, PhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    constants::{
+        processes::{MAX_OPEN_FILES, PID_QUARANTINE_NS},
+        syscalls::{EAGAIN, EBADF, EMFILE, ENOMEM},
+    },
+    error::KError,
+    ipc::channel::{channel, SendError, TryRecvError, TrySendError},
+    memory::{
+        frame_allocator::{
+            alloc_frame, alloc_frame_zeroed, dealloc_frame, with_bitmap_frame_allocator,
+        },
+        paging::map_range,
+        HHDM_OFFSET, MAPPER,
+    },
+    processes::{
+        fd_table::{FdError, STDOUT_FD},
+        kthread,
+        pid::{alloc_pid, free_pid},
+        process::{
+            clear_process_frames, create_process, get_process, remove_process, ProcessError,
+        },
+        test_binaries,
+    },
+    time,
+};
+
+/// Marks the end of the list of taken frames
+const LIST_END: u64 = u64::MAX;
+
+/// Takes every free frame, linking them through their first word so that
+/// holding them needs no heap
+///
+/// # Returns
+/// The physical address of the first frame, and the number taken
+fn take_all_frames() -> (u64, usize) {
+    let mut head = LIST_END;
+    let mut count = 0;
+    while let Some(frame) = alloc_frame() {
+        let addr = frame.start_address().as_u64();
+        unsafe { (*HHDM_OFFSET + addr).as_mut_ptr::<u64>().write(head) };
+        head = addr;
+        count += 1;
+    }
+    (head, count)
+}
+
+/// Frees a list of frames built by `take_all_frames`
+fn free_frames(mut head: u64) {
+    while head != LIST_END {
+        let next = unsafe { (*HHDM_OFFSET + head).as_ptr::<u64>().read() };
+        dealloc_frame(PhysFrame::containing_address(PhysAddr::new(head)));
+        head = next;
+    }
+}
+
+#[test_case]
+fn test_frame_exhaustion() {
+    let before = with_bitmap_frame_allocator(|allocator| allocator.audit());
+    let (frames, taken) = take_all_frames();
+    assert!(taken > 0);
+
+    assert!(alloc_frame().is_none());
+    assert!(alloc_frame_zeroed().is_none());
+    // Mapping fails cleanly instead of leaving half a mapping behind
+    let page = Page::containing_address(VirtAddr::new(0x600000000));
+    {
+        let mut mapper = MAPPER.lock();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        assert!(matches!(
+            map_range(Page::range_inclusive(page, page + 3), &mut *mapper, flags),
+            Err(MapToError::FrameAllocationFailed)
+        ));
+        assert!(matches!(
+            mapper.translate_page(page),
+            Err(TranslateError::PageNotMapped)
+        ));
+    }
+
+    free_frames(frames);
+    let after = with_bitmap_frame_allocator(|allocator| allocator.audit());
+    assert!(after.is_consistent(), "{:?}", after);
+    // Frames taken from the zero pool come back to the allocator
+    assert!(after.free_frames >= before.free_frames);
+    let frame = alloc_frame().expect("Frames were not freed");
+    dealloc_frame(frame);
+}
+
+#[test_case]
+fn test_channel_capacity() {
+    const CAPACITY: usize = 64;
+    let (sender, receiver) = channel::<usize>(CAPACITY);
+    for i in 0..CAPACITY {
+        sender.try_send(i).unwrap();
+    }
+
+    // A full channel hands the value back, or makes the sender wait
+    assert_eq!(sender.try_send(CAPACITY), Err(TrySendError::Full(CAPACITY)));
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut send = sender.send(CAPACITY);
+    assert!(Pin::new(&mut send).poll(&mut cx).is_pending());
+
+    assert_eq!(receiver.try_recv(), Ok(0));
+    assert_eq!(Pin::new(&mut send).poll(&mut cx), Poll::Ready(Ok(())));
+    drop(send);
+    for i in 1..=CAPACITY {
+        assert_eq!(receiver.try_recv(), Ok(i));
+    }
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+    // Once the receiver is gone, sends fail instead of filling up forever
+    drop(receiver);
+    assert_eq!(sender.try_send(0), Err(TrySendError::Closed(0)));
+    let mut send = sender.send(1);
+    assert_eq!(
+        Pin::new(&mut send).poll(&mut cx),
+        Poll::Ready(Err(SendError(1)))
+    );
+}
+
+/// Creates a process, then removes it again
+fn create_and_remove() -> Result<(), ProcessError> {
+    let pid = create_process(test_binaries::by_name("rand_regs").unwrap())?;
+    clear_process_frames(unsafe { &mut *get_process(pid).unwrap().pcb.get() });
+    remove_process(pid);
+    Ok(())
+}
+
+#[test_case]
+fn test_process_creation_without_frames() {
+    let before = with_bitmap_frame_allocator(|allocator| allocator.audit());
+    let (mut frames, _) = take_all_frames();
+
+    // Hand frames back one at a time, so that creation runs out at each
+    // step of building the page tables and loading the image
+    for _ in 0..8 {
+        let error = create_and_remove().unwrap_err();
+        assert!(matches!(error, ProcessError::OutOfMemory));
+        assert_eq!(KError::from(error).errno(), ENOMEM);
+        let next = unsafe { (*HHDM_OFFSET + frames).as_ptr::<u64>().read() };
+        dealloc_frame(PhysFrame::containing_address(PhysAddr::new(frames)));
+        frames = next;
+    }
+
+    // Failed attempts gave back everything they took
+    free_frames(frames);
+    let after = with_bitmap_frame_allocator(|allocator| allocator.audit());
+    assert!(after.is_consistent(), "{:?}", after);
+    assert!(after.free_frames >= before.free_frames);
+    create_and_remove().unwrap();
+}
+
+#[test_case]
+fn test_pid_exhaustion() {
+    let mut pids = Vec::new();
+    while let Ok(pid) = alloc_pid() {
+        pids.push(pid);
+    }
+    assert!(!pids.is_empty());
+
+    let error = create_and_remove().unwrap_err();
+    assert!(matches!(error, ProcessError::NoFreePid));
+    assert_eq!(KError::from(error).errno(), EAGAIN);
+    assert!(matches!(
+        kthread::create(|| ()),
+        Err(ProcessError::NoFreePid)
+    ));
+
+    // Freed PIDs come back once their quarantine is over
+    for pid in pids {
+        free_pid(pid);
+    }
+    let reusable = time::monotonic_ns() + PID_QUARANTINE_NS;
+    while time::monotonic_ns() < reusable {
+        core::hint::spin_loop();
+    }
+    create_and_remove().unwrap();
+}
+
+#[test_case]
+fn test_fd_exhaustion() {
+    let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+    let process = get_process(pid).unwrap();
+    let pcb = unsafe { &mut *process.pcb.get() };
+
+    // The standard streams take the first three descriptors
+    for fd in 3..MAX_OPEN_FILES {
+        assert_eq!(pcb.fd_table.dup(STDOUT_FD), Ok(fd));
+    }
+    let error = pcb.fd_table.dup(STDOUT_FD).unwrap_err();
+    assert_eq!(error, FdError::TooManyOpen);
+    assert_eq!(KError::from(error).errno(), EMFILE);
+    let error = pcb.fd_table.dup2(STDOUT_FD, MAX_OPEN_FILES).unwrap_err();
+    assert_eq!(KError::from(error).errno(), EBADF);
+
+    // Closing any descriptor makes room for exactly one more
+    pcb.fd_table.remove(10).unwrap();
+    assert_eq!(pcb.fd_table.dup(STDOUT_FD), Ok(10));
+    assert_eq!(pcb.fd_table.dup(STDOUT_FD), Err(FdError::TooManyOpen));
+
+    pcb.fd_table.take_all();
+    clear_process_frames(pcb);
+    drop(process);
+    remove_process(pid);
+}
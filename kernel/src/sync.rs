@@ -0,0 +1,342 @@
+//! Instrumented locks.
+//!
+//! `Mutex` and `RwLock` wrap the spin locks of the same name. In debug
+//! builds they count acquisitions, time how long callers spin for the
+//! lock, and remember which core and event hold it, so a lock that many
+//! cores fight over or that is held across long operations shows up in
+//! `contention_report` before it is converted to an async lock or sharded.
+//! Release builds skip the bookkeeping.
+//!
+//! Statistics of a lock are only reported once it is `track`ed, which
+//! needs a `'static` lock. Other locks keep their statistics, readable
+//! with `stats`.
+
+use alloc::{format, string::String, vec::Vec};
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{arch, events::current_event_id, time};
+
+/// Core ID stored while no core holds a lock
+const NO_OWNER: u64 = u64::MAX;
+
+static TRACKED: spin::Mutex<Vec<&'static LockStats>> = spin::Mutex::new(Vec::new());
+
+/// Contention statistics of one lock
+pub struct LockStats {
+    name: &'static str,
+    tracked: AtomicBool,
+    acquisitions: AtomicU64,
+    /// Acquisitions that had to spin
+    contended: AtomicU64,
+    /// Timestamp counter ticks spent spinning
+    total_wait: AtomicU64,
+    max_wait: AtomicU64,
+    /// Core holding the lock for writing, NO_OWNER if none
+    owner_core: AtomicU64,
+    /// Event holding the lock for writing, NO_OWNER if none
+    owner_event: AtomicU64,
+}
+
+/// Statistics of a lock at one point in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockSnapshot {
+    pub name: &'static str,
+    pub acquisitions: u64,
+    pub contended: u64,
+    pub total_wait_ns: u64,
+    pub max_wait_ns: u64,
+    /// Core and event, if any, holding the lock for writing
+    pub owner: Option<(u32, Option<u64>)>,
+}
+
+impl LockStats {
+    const fn new(name: &'static str) -> Self {
+        LockStats {
+            name,
+            tracked: AtomicBool::new(false),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            total_wait: AtomicU64::new(0),
+            max_wait: AtomicU64::new(0),
+            owner_core: AtomicU64::new(NO_OWNER),
+            owner_event: AtomicU64::new(NO_OWNER),
+        }
+    }
+
+    /// Takes a lock with `try_acquire`, falling back to `acquire` and
+    /// timing the wait if it is held
+    fn record<G>(&self, try_acquire: impl FnOnce() -> Option<G>, acquire: impl FnOnce() -> G) -> G {
+        if !cfg!(debug_assertions) {
+            return acquire();
+        }
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if let Some(guard) = try_acquire() {
+            return guard;
+        }
+
+        let start = arch::timestamp();
+        let guard = acquire();
+        let wait = arch::timestamp().saturating_sub(start);
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.total_wait.fetch_add(wait, Ordering::Relaxed);
+        self.max_wait.fetch_max(wait, Ordering::Relaxed);
+        guard
+    }
+
+    fn set_owner(&self) {
+        if cfg!(debug_assertions) {
+            self.owner_core
+                .store(arch::early_core_id() as u64, Ordering::Relaxed);
+            self.owner_event
+                .store(current_event_id().unwrap_or(NO_OWNER), Ordering::Relaxed);
+        }
+    }
+
+    fn clear_owner(&self) {
+        if cfg!(debug_assertions) {
+            self.owner_core.store(NO_OWNER, Ordering::Relaxed);
+            self.owner_event.store(NO_OWNER, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the statistics gathered so far
+    pub fn snapshot(&self) -> LockSnapshot {
+        let core = self.owner_core.load(Ordering::Relaxed);
+        let event = self.owner_event.load(Ordering::Relaxed);
+        LockSnapshot {
+            name: self.name,
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            total_wait_ns: time::tsc_to_ns(self.total_wait.load(Ordering::Relaxed)),
+            max_wait_ns: time::tsc_to_ns(self.max_wait.load(Ordering::Relaxed)),
+            owner: (core != NO_OWNER)
+                .then_some((core as u32, (event != NO_OWNER).then_some(event))),
+        }
+    }
+
+    fn track(&'static self) {
+        if !self.tracked.swap(true, Ordering::Relaxed) {
+            TRACKED.lock().push(self);
+        }
+    }
+}
+
+/// A spin lock that records contention statistics in debug builds
+pub struct Mutex<T: ?Sized> {
+    stats: LockStats,
+    inner: spin::Mutex<T>,
+}
+
+/// Guard of a locked `Mutex`
+pub struct MutexGuard<'a, T: ?Sized> {
+    stats: &'a LockStats,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex. `name` identifies it in reports.
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Mutex {
+            stats: LockStats::new(name),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Spins until the lock is free, then takes it
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let guard = self
+            .stats
+            .record(|| self.inner.try_lock(), || self.inner.lock());
+        self.stats.set_owner();
+        MutexGuard {
+            stats: &self.stats,
+            guard,
+        }
+    }
+
+    /// Takes the lock if it is free
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        if cfg!(debug_assertions) {
+            self.stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        }
+        self.stats.set_owner();
+        Some(MutexGuard {
+            stats: &self.stats,
+            guard,
+        })
+    }
+
+    /// Returns the lock's statistics
+    pub fn stats(&self) -> LockSnapshot {
+        self.stats.snapshot()
+    }
+}
+
+impl<T: ?Sized> Mutex<T>
+where
+    Self: 'static,
+{
+    /// Includes the lock in `contention_report`
+    pub fn track(&'static self) {
+        self.stats.track();
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.stats.clear_owner();
+    }
+}
+
+/// A spin reader-writer lock that records contention statistics in debug
+/// builds. Only writers are recorded as owners.
+pub struct RwLock<T: ?Sized> {
+    stats: LockStats,
+    inner: spin::RwLock<T>,
+}
+
+/// Guard of a `RwLock` locked for writing
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    stats: &'a LockStats,
+    guard: spin::RwLockWriteGuard<'a, T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates an unlocked lock. `name` identifies it in reports.
+    pub const fn new(name: &'static str, value: T) -> Self {
+        RwLock {
+            stats: LockStats::new(name),
+            inner: spin::RwLock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Spins until no writer holds the lock, then takes it for reading
+    pub fn read(&self) -> spin::RwLockReadGuard<'_, T> {
+        self.stats
+            .record(|| self.inner.try_read(), || self.inner.read())
+    }
+
+    /// Spins until nobody holds the lock, then takes it for writing
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let guard = self
+            .stats
+            .record(|| self.inner.try_write(), || self.inner.write());
+        self.stats.set_owner();
+        RwLockWriteGuard {
+            stats: &self.stats,
+            guard,
+        }
+    }
+
+    /// Returns the lock's statistics
+    pub fn stats(&self) -> LockSnapshot {
+        self.stats.snapshot()
+    }
+}
+
+impl<T: ?Sized> RwLock<T>
+where
+    Self: 'static,
+{
+    /// Includes the lock in `contention_report`
+    pub fn track(&'static self) {
+        self.stats.track();
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.stats.clear_owner();
+    }
+}
+
+/// Formats the `count` tracked locks callers spent the most time waiting
+/// for, one per line
+pub fn contention_report(count: usize) -> String {
+    let mut locks: Vec<LockSnapshot> = TRACKED.lock().iter().map(|s| s.snapshot()).collect();
+    locks.sort_by_key(|lock| core::cmp::Reverse(lock.total_wait_ns));
+
+    let mut report =
+        String::from("lock              acquired  contended  wait_us  max_us  holder\n");
+    for lock in locks.iter().take(count) {
+        let holder = match lock.owner {
+            Some((core, Some(event))) => format!("core {} event {}", core, event),
+            Some((core, None)) => format!("core {}", core),
+            None => String::from("-"),
+        };
+        report += &format!(
+            "{:<16}  {:>8}  {:>9}  {:>7}  {:>6}  {}\n",
+            lock.name,
+            lock.acquisitions,
+            lock.contended,
+            lock.total_wait_ns / 1000,
+            lock.max_wait_ns / 1000,
+            holder
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST_LOCK: Mutex<u32> = Mutex::new("test", 0);
+
+    #[test_case]
+    fn test_lock_statistics() {
+        TEST_LOCK.track();
+        TEST_LOCK.track();
+        {
+            let mut value = TEST_LOCK.lock();
+            *value += 1;
+            assert!(TEST_LOCK.try_lock().is_none());
+            if cfg!(debug_assertions) {
+                let (core, _) = TEST_LOCK.stats().owner.expect("Holder not recorded");
+                assert_eq!(core, arch::core_id());
+            }
+        }
+        assert_eq!(TEST_LOCK.stats().owner, None);
+
+        if cfg!(debug_assertions) {
+            assert_eq!(TEST_LOCK.stats().acquisitions, 1);
+            assert_eq!(contention_report(usize::MAX).matches("test ").count(), 1);
+        }
+    }
+}
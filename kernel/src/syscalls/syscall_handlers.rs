@@ -1,47 +1,1401 @@
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    future::Future,
+    mem::{align_of, size_of},
+};
+
+use x86_64::{
+    registers::{control::Cr3, rflags::RFlags},
+    structures::paging::{
+        mapper::TranslateResult, OffsetPageTable, PageTable, PageTableFlags, Translate,
+    },
+    VirtAddr,
+};
+
 use crate::{
-    events::{current_running_event_info, EventInfo},
-    processes::process::{clear_process_frames, ProcessState, PROCESS_TABLE},
-    serial_println,
+    constants::{
+        memory::PAGE_SIZE,
+        processes::{KERNEL_PID, PROCESS_DEFAULT_PRIORITY, STACK_START},
+        syscalls::{
+            CLOCK_MONOTONIC, CLOCK_REALTIME, EBADF, ECHILD, EFAULT, EINVAL, EIO, ENODEV, ENOMEM,
+            EPERM, ESPIPE, ESRCH, IO_MAX, MAP_PRIVATE, MAP_SHARED, MS_ASYNC, MS_INVALIDATE,
+            MS_SYNC, NSIG, O_CREAT, O_DIRECT, PATH_MAX, PRINT_MAX, REBOOT_CMD_POWER_OFF,
+            REBOOT_CMD_RESTART, SEEK_CUR, SEEK_END, SEEK_SET, SIGSEGV, SIG_DFL, SIG_IGN, UTIME_NOW,
+            UTIME_OMIT, WNOHANG,
+        },
+    },
+    debug,
+    error::{ErrorKind, KError, KResult},
+    events::{current_running_event_info, futures::first_of, schedule_process, EventInfo},
+    filesys::{
+        vfs::{self, OpenFlags},
+        FileTimes, FsError, SeekFrom, StatFs,
+    },
+    ipc::pipe::{pipe, PipeError, PipeReader, PipeWriter},
+    memory::{mmap::protection_to_pagetable_flags, HHDM_OFFSET},
+    processes::{
+        fd_table::{FdTable, FileDescriptor, VfsFile, STDOUT_FD},
+        process::{
+            child_exit, copy_current_on_write, count_user_pages, exec_process, exit_process,
+            fault_current_mapping, get_process, grow_current_heap, grow_current_stack, map_file,
+            niced_priority, protect_mappings, reap_child, run_process_ring3, set_brk,
+            sync_mappings, unmap_mappings, ProcessError, ProcessState, WaitReason,
+        },
+        registers::Registers,
+        rusage::SwitchReason,
+        signal::{terminate_current, SignalAction},
+    },
+    serial_print, serial_println,
+    shutdown::{shutdown, ShutdownAction},
+    time::{self, ClockId, Timespec},
+    version::Utsname,
+    QemuExitCode,
 };
 
 use crate::interrupts::x2apic;
 
-pub fn sys_exit() {
+/// First address past the lower half, where user memory ends
+const USER_END: u64 = 1 << 47;
+
+/// Terminates the calling process and returns to the event runner. The
+/// parent, if any, collects the status with waitpid.
+///
+/// # Arguments
+/// * `status` - Exit code, of which the parent sees the low 8 bits
+///
+/// # Returns
+/// Does not return on success, -ESRCH if the process is not in the process
+/// table
+pub fn sys_exit(status: u64) -> i64 {
     // TODO handle hierarchy (parent processes), resources, threads, etc.
     // TODO recursive page table walk to handle cleaning up process memory
     let cpuid: u32 = x2apic::current_core_id() as u32;
     let event: EventInfo = current_running_event_info(cpuid);
 
-    if event.pid == 0 {
+    if event.pid == KERNEL_PID {
         panic!("Calling exit from outside of process");
     }
 
+    // Nothing is left to drop by the jump below
+    let Ok(preemption_info) = exit_process(event.pid, status as i32) else {
+        return -ESRCH;
+    };
     serial_println!("Process {} exit", event.pid);
 
-    // Get PCB from PID
-    let preemption_info = unsafe {
-        let mut process_table = PROCESS_TABLE.write();
-        let process = process_table
-            .get_mut(&event.pid)
-            .expect("Process not found");
+    unsafe {
+        // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "push {1}",
+            "stc",          // Use carry flag as sentinel to run_process that we're exiting
+            "ret",
+            in(reg) preemption_info.0,
+            in(reg) preemption_info.1,
+            options(noreturn)
+        );
+    }
+}
+
+/// Replaces the calling process' image with an ELF file from the VFS. The
+/// process keeps its PID, priority and open files, and starts the new
+/// image from its entry point with a fresh stack and zeroed registers.
+///
+/// # Arguments
+/// * `path` - User pointer to the NUL terminated path of the executable
+///
+/// # Returns
+/// Does not return on success. -EFAULT for a bad pointer, -EACCES if the
+/// file is not executable or on a noexec mount, -ENOEXEC if it is not an
+/// ELF image that fits the user half, -ENOMEM if there are not enough
+/// frames to load it, or the filesystem's error. The old image keeps
+/// running on failure.
+pub fn sys_exec(path: u64) -> i64 {
+    let cpuid = x2apic::current_core_id() as u32;
+    let pid = current_running_event_info(cpuid).pid;
 
-        let pcb = process.pcb.get();
+    let preemption_info = {
+        let path = match user_path(path) {
+            Ok(path) => path,
+            Err(error) => return -error.errno(),
+        };
+        let Ok(process) = get_process(pid) else {
+            return -ESRCH;
+        };
+        let executable = match vfs::lock().read_executable(&path) {
+            Ok(executable) => executable,
+            Err(error) => return -KError::from(error).errno(),
+        };
 
-        (*pcb).state = ProcessState::Terminated;
-        clear_process_frames(&mut *pcb);
-        process_table.remove(&event.pid);
-        ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+        unsafe {
+            let pcb = process.pcb.get();
+            if let Err(error) =
+                exec_process(&mut *pcb, &executable.bytes, Some(&executable.version))
+            {
+                return -KError::from(error).errno();
+            }
+            debug!("Process {} exec {}", pid, path);
+            process.stats.update_rss(count_user_pages(&mut *pcb));
+            process.stats.leave_kernel();
+            (*pcb).state = ProcessState::Ready;
+            ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+        }
     };
+    // The path, image and process were dropped with the block above, since
+    // nothing is dropped after the jump below
 
     unsafe {
+        // Run the new image from its first instruction, as for a new process
+        schedule_process(cpuid, run_process_ring3(pid), pid);
+
+        // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "push {1}",
+            "ret",
+            in(reg) preemption_info.0,
+            in(reg) preemption_info.1,
+            options(noreturn)
+        );
+    }
+}
+
+/// Waits for a child of the calling process to exit and reaps it
+///
+/// # Arguments
+/// * `pid` - The child to wait for, or -1 for any child
+/// * `status` - User pointer to an i32 for the exit status, encoded as by
+///   Linux with the exit code in bits 8-15, or 0 to discard it
+/// * `options` - WNOHANG to return at once if no child has exited
+/// * `frame` - The registers saved on syscall entry, used to block
+///
+/// # Returns
+/// The reaped child's PID, or 0 with WNOHANG if none has exited yet.
+/// -ECHILD if there is no matching child, -EINVAL for bad options or a PID
+/// that does not name a single child or any child, -EFAULT for a bad
+/// status pointer.
+pub fn sys_waitpid(pid: u64, status: u64, options: u64, frame: u64) -> i64 {
+    let caller = current_running_event_info(x2apic::current_core_id() as u32).pid;
+
+    let child = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 && pid <= u32::MAX as i64 => Some(pid as u32),
+        // No process groups to wait on
+        _ => return -EINVAL,
+    };
+    if options & !WNOHANG != 0 {
+        return -EINVAL;
+    }
+    let status = match status {
+        0 => None,
+        addr => match user_ptr::<i32>(addr, true) {
+            Some(ptr) => Some(ptr),
+            None => return -EFAULT,
+        },
+    };
+
+    match reap_child(caller, child) {
+        Ok(Some((child, code))) => {
+            if let Some(ptr) = status {
+                unsafe { ptr.write((code & 0xff) << 8) };
+            }
+            return child as i64;
+        }
+        Ok(None) if options & WNOHANG != 0 => return 0,
+        Ok(None) => {}
+        Err(ProcessError::NoChildren) => return -ECHILD,
+        Err(error) => panic!("Unexpected waitpid failure: {:?}", error),
+    }
+
+    retry_after(frame, WaitReason::Child, child_exit(caller))
+}
+
+/// Puts the calling process to sleep until `wake` completes, then has it
+/// make the same syscall again. A signal sent to the process ends the
+/// sleep early, and is delivered before the call is made again. Anything
+/// the caller still holds is never dropped, so it must only hold what it
+/// has moved into `wake`.
+///
+/// # Arguments
+/// * `frame` - The registers saved on syscall entry
+/// * `reason` - What the process is waiting for
+/// * `wake` - Completes once the call may succeed
+///
+/// # Returns
+/// Does not return, unless the caller is not in the process table, when
+/// it returns -ESRCH
+fn retry_after(
+    frame: u64,
+    reason: WaitReason,
+    wake: impl Future<Output = ()> + Send + 'static,
+) -> i64 {
+    let cpuid = x2apic::current_core_id() as u32;
+    let caller = current_running_event_info(cpuid).pid;
+
+    let Ok(process) = get_process(caller) else {
+        return -ESRCH;
+    };
+    let preemption_info = {
+        unsafe {
+            let pcb = process.pcb.get();
+            let mut registers = Registers::from_stack(frame);
+            // Back over `int 0x80`, so the process makes the call again
+            // once it wakes
+            registers.rip -= 2;
+            (*pcb).registers = registers;
+            (*pcb).wait_for(reason);
+            process.stats.leave_kernel();
+            process.stats.record_switch(SwitchReason::Blocked);
+            ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+        }
+    };
+
+    unsafe {
+        schedule_process(
+            cpuid,
+            async move {
+                let signals = &(*process.pcb.get()).signals;
+                first_of(wake, signals.interrupted()).await;
+                run_process_ring3(caller).await;
+            },
+            caller,
+        );
+
         // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
         core::arch::asm!(
             "mov rsp, {0}",
             "push {1}",
-            "stc",          // Use carry flag as sentinel to run_process that we're exiting
             "ret",
             in(reg) preemption_info.0,
-            in(reg) preemption_info.1
+            in(reg) preemption_info.1,
+            options(noreturn)
         );
     }
 }
+
+/// Checks that a user pointer to a `T` is aligned, lies in the lower half,
+/// and is mapped user accessible in the current address space, or is in
+/// the process' stack area, its heap, or a file mapping allowing the access. Only the
+/// first and last byte are checked, so `T` must be smaller than a page.
+fn user_ptr<T>(addr: u64, writable: bool) -> Option<*mut T> {
+    let size = size_of::<T>() as u64;
+    if addr == 0 || addr % align_of::<T>() as u64 != 0 || addr.checked_add(size)? > USER_END {
+        return None;
+    }
+
+    let pml4_phys = Cr3::read().0.start_address();
+    let pml4_virt = *HHDM_OFFSET + pml4_phys.as_u64();
+    let mapper =
+        unsafe { OffsetPageTable::new(&mut *pml4_virt.as_mut_ptr::<PageTable>(), *HHDM_OFFSET) };
+
+    let mut required = PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        required |= PageTableFlags::WRITABLE;
+    }
+    for byte in [addr, addr + size - 1] {
+        match mapper.translate(VirtAddr::new(byte)) {
+            TranslateResult::Mapped { flags, .. } if flags.contains(required) => {}
+            // Stack and heap the process has not touched yet are mapped as
+            // a fault would map them
+            TranslateResult::NotMapped
+                if grow_current_stack(VirtAddr::new(byte))
+                    || grow_current_heap(VirtAddr::new(byte)) => {}
+            // Copy-on-write pages about to be written are copied as a fault
+            // would copy them
+            TranslateResult::Mapped { .. }
+                if writable && copy_current_on_write(VirtAddr::new(byte)) => {}
+            // So are file pages not read in or, for writes, not written yet
+            _ if fault_current_mapping(VirtAddr::new(byte), writable) => {}
+            _ => return None,
+        }
+    }
+    Some(addr as *mut T)
+}
+
+/// Copies a NUL terminated path out of user memory
+fn user_path(addr: u64) -> KResult<String> {
+    let mut bytes = Vec::new();
+    for i in 0..PATH_MAX as u64 {
+        let byte_addr = addr.checked_add(i).ok_or(ErrorKind::Fault)?;
+        // Mappings only change at page boundaries
+        if i == 0 || byte_addr % PAGE_SIZE as u64 == 0 {
+            user_ptr::<u8>(byte_addr, false).ok_or(ErrorKind::Fault)?;
+        }
+        match unsafe { (byte_addr as *const u8).read() } {
+            0 => {
+                return String::from_utf8(bytes)
+                    .map_err(|_| KError::new(ErrorKind::InvalidArgument, "path is not UTF-8"))
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    Err(ErrorKind::NameTooLong.into())
+}
+
+/// Checks that `len` bytes at `addr` are mapped user accessible, and
+/// writable if `writable` is set, checking every page they span
+pub(crate) fn check_user_range(addr: u64, len: u64, writable: bool) -> KResult<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(ErrorKind::Fault)?;
+    // Mappings only change at page boundaries
+    let mut page = addr;
+    while page < end {
+        user_ptr::<u8>(page, writable).ok_or(ErrorKind::Fault)?;
+        page = (page / PAGE_SIZE as u64 + 1) * PAGE_SIZE as u64;
+    }
+    Ok(())
+}
+
+/// Copies `len` bytes out of user memory
+fn user_bytes(addr: u64, len: u64) -> KResult<Vec<u8>> {
+    check_user_range(addr, len, false)?;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) }.to_vec())
+}
+
+/// Runs `f` on the descriptor table of the calling process
+///
+/// # Returns
+/// What `f` returns, or NoSuchProcess if there is no calling process
+fn with_fd_table<T>(f: impl FnOnce(&mut FdTable) -> KResult<T>) -> KResult<T> {
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let process = get_process(pid)?;
+    // Only the process itself, running on this core, uses its table
+    f(unsafe { &mut (*process.pcb.get()).fd_table })
+}
+
+/// Returns what a descriptor of the calling process refers to
+fn descriptor(fd: u64) -> KResult<FileDescriptor> {
+    with_fd_table(|table| Ok(table.get(fd as usize).cloned()?))
+}
+
+/// Writes a buffer from user memory to standard output
+///
+/// # Arguments
+/// * `buf` - User pointer to the bytes to print
+/// * `len` - Number of bytes, at most PRINT_MAX
+/// * `frame` - The registers saved on syscall entry, used to block
+///
+/// # Returns
+/// As write to STDOUT_FD, or -EINVAL if `len` is over PRINT_MAX
+pub fn sys_print(buf: u64, len: u64, frame: u64) -> i64 {
+    if len > PRINT_MAX as u64 {
+        return -EINVAL;
+    }
+    sys_write(STDOUT_FD as u64, buf, len, frame)
+}
+
+/// Opens a file. Unlike the POSIX call there is no mode argument and
+/// paths are absolute.
+///
+/// # Arguments
+/// * `path` - User pointer to a NUL terminated path
+/// * `flags` - O_CREAT to create the file if it does not exist, and
+///   O_DIRECT to move aligned whole blocks straight between the device and
+///   the caller's buffer
+///
+/// # Returns
+/// The lowest free descriptor, -EFAULT for a bad pointer, -EINVAL for
+/// unknown flags, -EMFILE if the process has MAX_OPEN_FILES open, or the
+/// filesystem's error
+pub fn sys_open(path: u64, flags: u64) -> i64 {
+    if flags & !(O_CREAT | O_DIRECT) != 0 {
+        return -EINVAL;
+    }
+    let open_flags = if flags & O_DIRECT != 0 {
+        OpenFlags::DIRECT
+    } else {
+        OpenFlags::empty()
+    };
+    let path = match user_path(path) {
+        Ok(path) => path,
+        Err(error) => return -error.errno(),
+    };
+    let opened = {
+        let mut vfs = vfs::lock();
+        let created = if flags & O_CREAT != 0 {
+            vfs.create_file(&path)
+        } else {
+            Ok(())
+        };
+        match created {
+            Ok(()) | Err(FsError::AlreadyExists) => vfs.open_with(&path, open_flags),
+            Err(error) => Err(error),
+        }
+    };
+    let descriptor = match opened {
+        Ok(vfs_fd) => FileDescriptor::file(vfs_fd),
+        Err(error) => return -KError::from(error).errno(),
+    };
+    // A full table drops the descriptor, which closes the file again
+    match with_fd_table(|table| Ok(table.insert(descriptor)?)) {
+        Ok(fd) => fd as i64,
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Reads from a descriptor into user memory. Reading an empty pipe waits
+/// until something is written to it or its writer is closed.
+///
+/// # Arguments
+/// * `fd` - Descriptor to read from
+/// * `buf` - User pointer to write the data to
+/// * `len` - Most bytes to read. At most IO_MAX are read per call.
+/// * `frame` - The registers saved on syscall entry, used to block
+///
+/// # Returns
+/// The number of bytes read, 0 at the end of the file or once a pipe's
+/// writer is closed, -EBADF for a descriptor that is not open for reading,
+/// -EFAULT for a bad pointer, or the filesystem's error
+pub fn sys_read(fd: u64, buf: u64, len: u64, frame: u64) -> i64 {
+    let len = len.min(IO_MAX as u64);
+    let descriptor = match descriptor(fd) {
+        Ok(descriptor) => descriptor,
+        Err(error) => return -error.errno(),
+    };
+    if let Err(error) = check_user_range(buf, len, true) {
+        return -error.errno();
+    }
+    let file = match descriptor {
+        FileDescriptor::File(file) => file,
+        FileDescriptor::PipeReader(reader) => return read_pipe(reader, buf, len, frame),
+        FileDescriptor::PipeWriter(_) => return -EBADF,
+        FileDescriptor::SerialConsole => return 0,
+    };
+    match read_file(file.fd, buf, len) {
+        Ok(read) => read as i64,
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Reads from a VFS file into `len` bytes of user memory at `buf`, which
+/// the caller has checked. Files opened with `OpenFlags::DIRECT` are read
+/// straight into the caller's pages, which stay mapped while the process
+/// waits in the call, and others through a kernel buffer.
+fn read_file(fd: usize, buf: u64, len: u64) -> Result<usize, FsError> {
+    let mut vfs = vfs::lock();
+    match vfs.open_flags(fd) {
+        Ok(flags) if flags.contains(OpenFlags::DIRECT) && len > 0 => {
+            let user = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len as usize) };
+            vfs.read(fd, user)
+        }
+        _ => {
+            let mut data = vec![0; len as usize];
+            vfs.read(fd, &mut data).inspect(|&read| unsafe {
+                core::ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, read)
+            })
+        }
+    }
+}
+
+/// Writes `len` bytes of user memory at `buf` to a VFS file opened with
+/// `OpenFlags::DIRECT`, straight from the caller's pages
+///
+/// # Returns
+/// None if the file is not direct, or `len` is 0, so the data should be
+/// copied in and written as for any other file
+fn write_direct(fd: usize, buf: u64, len: u64) -> Option<KResult<usize>> {
+    let mut vfs = vfs::lock();
+    let direct = vfs
+        .open_flags(fd)
+        .is_ok_and(|flags| flags.contains(OpenFlags::DIRECT));
+    if !direct || len == 0 {
+        return None;
+    }
+    if let Err(error) = check_user_range(buf, len, false) {
+        return Some(Err(error));
+    }
+    let user = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+    Some(vfs.write(fd, user).map_err(KError::from))
+}
+
+/// Writes user memory to a descriptor. Writing to a full pipe waits until
+/// there is room, and writes only as much as fits.
+///
+/// # Arguments
+/// * `fd` - Descriptor to write to
+/// * `buf` - User pointer to the data
+/// * `len` - Number of bytes. At most IO_MAX are written per call.
+/// * `frame` - The registers saved on syscall entry, used to block
+///
+/// # Returns
+/// The number of bytes written, -EBADF for a descriptor that is not open
+/// for writing, -EFAULT for a bad pointer, -EPIPE for a pipe whose reader
+/// is closed, or the filesystem's error
+pub fn sys_write(fd: u64, buf: u64, len: u64, frame: u64) -> i64 {
+    let len = len.min(IO_MAX as u64);
+    let descriptor = match descriptor(fd) {
+        Ok(descriptor) => descriptor,
+        Err(error) => return -error.errno(),
+    };
+    if let FileDescriptor::File(file) = &descriptor {
+        match write_direct(file.fd, buf, len) {
+            Some(Ok(written)) => return written as i64,
+            Some(Err(error)) => return -error.errno(),
+            None => {}
+        }
+    }
+    let data = match user_bytes(buf, len) {
+        Ok(data) => data,
+        Err(error) => return -error.errno(),
+    };
+    let written = match descriptor {
+        FileDescriptor::File(file) => vfs::lock().write(file.fd, &data),
+        FileDescriptor::PipeWriter(writer) => return write_pipe(writer, data, frame),
+        FileDescriptor::PipeReader(_) => return -EBADF,
+        FileDescriptor::SerialConsole => {
+            serial_print!("{}", String::from_utf8_lossy(&data));
+            Ok(data.len())
+        }
+    };
+    match written {
+        Ok(written) => written as i64,
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Reads from a pipe, or waits until it can be read
+fn read_pipe(reader: Arc<PipeReader>, buf: u64, len: u64, frame: u64) -> i64 {
+    let mut data = vec![0; len as usize];
+    match reader.try_read(&mut data) {
+        Ok(read) => {
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, read) };
+            read as i64
+        }
+        Err(PipeError::WouldBlock) => {
+            drop(data);
+            retry_after(
+                frame,
+                WaitReason::Pipe,
+                async move { reader.readable().await },
+            )
+        }
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Writes to a pipe, or waits until it can be written
+fn write_pipe(writer: Arc<PipeWriter>, data: Vec<u8>, frame: u64) -> i64 {
+    match writer.try_write(&data) {
+        Ok(written) => written as i64,
+        Err(PipeError::WouldBlock) => {
+            // Copied in again when the call is made again
+            drop(data);
+            retry_after(
+                frame,
+                WaitReason::Pipe,
+                async move { writer.writable().await },
+            )
+        }
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Closes a descriptor. The file itself is closed once no descriptor
+/// refers to it any more.
+///
+/// # Returns
+/// 0 on success, -EBADF for a descriptor that is not open
+pub fn sys_close(fd: u64) -> i64 {
+    match with_fd_table(|table| Ok(table.remove(fd as usize)?)) {
+        Ok(_) => 0,
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Moves the position of an open file
+///
+/// # Arguments
+/// * `fd` - Descriptor of a file
+/// * `offset` - Signed offset from `whence`
+/// * `whence` - SEEK_SET, SEEK_CUR or SEEK_END
+///
+/// # Returns
+/// The new position from the start of the file, -EBADF for a descriptor
+/// that is not open, -EINVAL for a bad origin or a negative position,
+/// -ESPIPE for the console or a pipe, or the filesystem's error
+pub fn sys_seek(fd: u64, offset: u64, whence: u64) -> i64 {
+    let offset = offset as i64;
+    let pos = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return -EINVAL,
+    };
+    let file = match descriptor(fd) {
+        Ok(FileDescriptor::File(file)) => file,
+        Ok(
+            FileDescriptor::PipeReader(_)
+            | FileDescriptor::PipeWriter(_)
+            | FileDescriptor::SerialConsole,
+        ) => return -ESPIPE,
+        Err(error) => return -error.errno(),
+    };
+    let position = vfs::lock().seek(file.fd, pos);
+    match position {
+        Ok(position) => position as i64,
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Duplicates a descriptor under the lowest free number. Both share the
+/// file position.
+///
+/// # Returns
+/// The new descriptor, -EBADF for a descriptor that is not open, or
+/// -EMFILE if the process has MAX_OPEN_FILES open
+pub fn sys_dup(fd: u64) -> i64 {
+    match with_fd_table(|table| Ok(table.dup(fd as usize)?)) {
+        Ok(new) => new as i64,
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Duplicates a descriptor as `new`, closing whatever `new` referred to
+///
+/// # Returns
+/// `new`, or -EBADF if `old` is not open or `new` is out of range
+pub fn sys_dup2(old: u64, new: u64) -> i64 {
+    match with_fd_table(|table| Ok(table.dup2(old as usize, new as usize)?)) {
+        Ok(_) => new as i64,
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Creates a pipe, with a descriptor for each end. Both are inherited by
+/// any copy of the descriptor table, and each end closes once no
+/// descriptor refers to it.
+///
+/// # Arguments
+/// * `fds` - User pointer to two i32s, set to the descriptors of the read
+///   end and the write end, in that order
+///
+/// # Returns
+/// 0 on success, -EFAULT for a bad pointer, or -EMFILE if the process
+/// does not have two descriptors free
+pub fn sys_pipe(fds: u64) -> i64 {
+    let Some(fds) = user_ptr::<[i32; 2]>(fds, true) else {
+        return -EFAULT;
+    };
+    let (reader, writer) = pipe();
+    let opened = with_fd_table(|table| {
+        let read_fd = table.insert(FileDescriptor::PipeReader(Arc::new(reader)))?;
+        match table.insert(FileDescriptor::PipeWriter(Arc::new(writer))) {
+            Ok(write_fd) => Ok([read_fd as i32, write_fd as i32]),
+            Err(error) => {
+                table.remove(read_fd)?;
+                Err(error.into())
+            }
+        }
+    });
+    match opened {
+        Ok(opened) => {
+            unsafe { fds.write(opened) };
+            0
+        }
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Writes the time of a clock to user memory
+///
+/// # Arguments
+/// * `clock` - CLOCK_REALTIME or CLOCK_MONOTONIC
+/// * `timespec` - User pointer to a Timespec
+///
+/// # Returns
+/// 0 on success, -EINVAL for an unknown clock, -EFAULT for a bad pointer
+pub fn sys_clock_gettime(clock: u64, timespec: u64) -> i64 {
+    let clock = match clock {
+        CLOCK_REALTIME => ClockId::Realtime,
+        CLOCK_MONOTONIC => ClockId::Monotonic,
+        _ => return -EINVAL,
+    };
+    let Some(timespec) = user_ptr::<Timespec>(timespec, true) else {
+        return -EFAULT;
+    };
+    unsafe { timespec.write(time::clock_gettime(clock)) };
+    0
+}
+
+/// Sets the wall clock from a Timespec in user memory. Unlike the POSIX
+/// call this takes a Timespec rather than a timeval and no timezone.
+/// Processes have no credentials yet, so only those the kernel launched
+/// itself may set the clock.
+///
+/// # Returns
+/// 0 on success, -EFAULT for a bad pointer, -EINVAL for a malformed time,
+/// -EPERM if the kernel did not launch the caller
+pub fn sys_settimeofday(timespec: u64) -> i64 {
+    let Some(timespec) = user_ptr::<Timespec>(timespec, false) else {
+        return -EFAULT;
+    };
+    let timespec = unsafe { timespec.read() };
+    let Some(nanos) = timespec.as_nanos().filter(|&nanos| nanos >= 0) else {
+        return -EINVAL;
+    };
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    if !unsafe { (*process.pcb.get()).launched_by_kernel } {
+        return -EPERM;
+    }
+    time::set_realtime_ns(nanos);
+    0
+}
+
+/// Writes the space usage of the filesystem holding a path to user memory
+///
+/// # Arguments
+/// * `path` - User pointer to a NUL terminated path
+/// * `statfs` - User pointer to a StatFs
+///
+/// # Returns
+/// 0 on success, -EFAULT for a bad pointer, or the filesystem's error
+pub fn sys_statfs(path: u64, statfs: u64) -> i64 {
+    let path = match user_path(path) {
+        Ok(path) => path,
+        Err(error) => return -error.errno(),
+    };
+    let Some(statfs) = user_ptr::<StatFs>(statfs, true) else {
+        return -EFAULT;
+    };
+    match vfs::lock().statfs(&path) {
+        Ok(stats) => {
+            unsafe { statfs.write(stats) };
+            0
+        }
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Sets the access and modification times of a file. Unlike the POSIX call
+/// there is no directory descriptor or flags argument, paths are absolute.
+///
+/// # Arguments
+/// * `path` - User pointer to a NUL terminated path
+/// * `times` - User pointer to two Timespecs, access then modification
+///   time, or 0 to set both to the current time. A tv_nsec of UTIME_NOW or
+///   UTIME_OMIT sets that time to now or leaves it unchanged.
+///
+/// # Returns
+/// 0 on success, -EFAULT for a bad pointer, -EINVAL for a malformed time,
+/// or the filesystem's error
+pub fn sys_utimensat(path: u64, times: u64) -> i64 {
+    let path = match user_path(path) {
+        Ok(path) => path,
+        Err(error) => return -error.errno(),
+    };
+    let now = time::realtime_secs().max(0) as u64;
+    let requested = if times == 0 {
+        [Timespec {
+            tv_sec: 0,
+            tv_nsec: UTIME_NOW,
+        }; 2]
+    } else {
+        let Some(times) = user_ptr::<[Timespec; 2]>(times, false) else {
+            return -EFAULT;
+        };
+        unsafe { times.read() }
+    };
+
+    let mut resolved = [None; 2];
+    for (time, requested) in resolved.iter_mut().zip(requested) {
+        *time = match requested.tv_nsec {
+            UTIME_NOW => Some(now),
+            UTIME_OMIT => None,
+            0..1_000_000_000 if requested.tv_sec >= 0 => Some(requested.tv_sec as u64),
+            _ => return -EINVAL,
+        };
+    }
+    let times = FileTimes {
+        accessed: resolved[0],
+        modified: resolved[1],
+    };
+    match vfs::lock().set_times(&path, times) {
+        Ok(()) => 0,
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Powers off or restarts the machine after running every stop hook. Like
+/// setting the clock, only processes the kernel launched itself may do so.
+///
+/// # Arguments
+/// * `cmd` - REBOOT_CMD_POWER_OFF or REBOOT_CMD_RESTART
+///
+/// # Returns
+/// Does not return on success, -EINVAL for an unknown command, -EPERM if
+/// the kernel did not launch the caller
+pub fn sys_reboot(cmd: u64) -> i64 {
+    let action = match cmd {
+        REBOOT_CMD_POWER_OFF => ShutdownAction::PowerOff(QemuExitCode::Success),
+        REBOOT_CMD_RESTART => ShutdownAction::Reboot,
+        _ => return -EINVAL,
+    };
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    if !unsafe { (*process.pcb.get()).launched_by_kernel } {
+        return -EPERM;
+    }
+    serial_println!("Process {} requested {:?}", pid, action);
+    shutdown(action)
+}
+
+/// Lowers or restores the scheduling priority of the calling process. The
+/// new priority applies from the next time the process is scheduled.
+///
+/// # Arguments
+/// * `increment` - Nice increment, positive to lower the priority
+///
+/// # Returns
+/// The new nice value, 0 at the default priority, -EPERM if the increment
+/// would raise the process above the default priority, or -ESRCH if the
+/// process is gone
+pub fn sys_nice(increment: u64) -> i64 {
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    let pcb = process.pcb.get();
+    let Some(priority) = niced_priority(unsafe { (*pcb).priority }, increment as i64) else {
+        return -EPERM;
+    };
+    unsafe { (*pcb).priority = priority };
+    (priority - PROCESS_DEFAULT_PRIORITY) as i64
+}
+
+/// Writes the kernel's name, version, build time, syscall table version and
+/// feature bits to user memory
+///
+/// # Arguments
+/// * `utsname` - User pointer to a Utsname
+///
+/// # Returns
+/// 0 on success, -EFAULT for a bad pointer
+pub fn sys_uname(utsname: u64) -> i64 {
+    let Some(utsname) = user_ptr::<Utsname>(utsname, true) else {
+        return -EFAULT;
+    };
+    // Format straight into the caller's buffer
+    unsafe { (*utsname).fill() };
+    0
+}
+
+/// Maps a file into the calling process' address space. Pages are read
+/// from the file as they are first touched. Unlike the POSIX call there
+/// are no anonymous or fixed mappings, and `addr` is only a hint, which
+/// is ignored.
+///
+/// # Arguments
+/// * `addr` - Ignored
+/// * `len` - Bytes to map, rounded up to whole pages
+/// * `prot` - PROT_READ, optionally with PROT_WRITE
+/// * `flags` - MAP_SHARED to have msync and exit write changes back to the
+///   file, or MAP_PRIVATE to keep them to the process
+/// * `fd` - Descriptor of the file
+/// * `offset` - Where in the file the mapping starts, a multiple of the
+///   page size
+///
+/// # Returns
+/// The start of the mapping, -EINVAL for a zero length, unaligned offset
+/// or bad flags, -EBADF for a descriptor that is not open, -ENODEV for the
+/// console or a pipe, -ENOMEM if there is no room for the mapping, or the
+/// filesystem's error
+pub fn sys_mmap(_addr: u64, len: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> i64 {
+    let Some(page_flags) = protection_to_pagetable_flags(prot) else {
+        return -EINVAL;
+    };
+    if len == 0 || offset % PAGE_SIZE as u64 != 0 || (flags != MAP_SHARED && flags != MAP_PRIVATE) {
+        return -EINVAL;
+    }
+    let file = match descriptor(fd) {
+        Ok(FileDescriptor::File(file)) => file,
+        Ok(
+            FileDescriptor::PipeReader(_)
+            | FileDescriptor::PipeWriter(_)
+            | FileDescriptor::SerialConsole,
+        ) => return -ENODEV,
+        Err(error) => return -error.errno(),
+    };
+    // The mapping reads and writes at offsets of its own choosing
+    let reopened = vfs::lock().reopen(file.fd);
+    let file = match reopened {
+        Ok(fd) => VfsFile { fd },
+        Err(error) => return -KError::from(error).errno(),
+    };
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    // Only the process itself, running on this core, changes its mappings
+    let pcb = unsafe { &mut *process.pcb.get() };
+    match map_file(pcb, file, len, offset, page_flags, flags == MAP_SHARED) {
+        Ok(start) => start.as_u64() as i64,
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Writes the pages of shared file mappings that changed back to their
+/// files. Writes are finished before the call returns even with MS_ASYNC,
+/// and MS_INVALIDATE has nothing to do, since mappings keep pages of their
+/// own.
+///
+/// # Arguments
+/// * `addr` - Start of the range, page aligned
+/// * `len` - Bytes in the range
+/// * `flags` - MS_SYNC or MS_ASYNC, optionally with MS_INVALIDATE
+///
+/// # Returns
+/// 0 on success, -EINVAL for an unaligned address or bad flags, -ENOMEM if
+/// part of the range is not mapped from a file, or the filesystem's error
+pub fn sys_msync(addr: u64, len: u64, flags: u64) -> i64 {
+    if addr % PAGE_SIZE as u64 != 0
+        || flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+        || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC
+    {
+        return -EINVAL;
+    }
+    let Ok(start) = VirtAddr::try_new(addr) else {
+        return -EINVAL;
+    };
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    match sync_mappings(unsafe { &*process.pcb.get() }, start, len) {
+        Ok(()) => 0,
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Removes file mappings from the calling process' address space. Dirty
+/// pages of shared mappings are written back first. Parts of the range
+/// that are not mapped are skipped, and mappings only partly in it keep
+/// the rest of their pages.
+///
+/// # Arguments
+/// * `addr` - Start of the range, page aligned
+/// * `len` - Bytes in the range, rounded up to whole pages
+///
+/// # Returns
+/// 0 on success, -EINVAL for an unaligned address, a zero length or a
+/// range outside the lower half, or -EIO if dirty pages could not be
+/// written back, in which case nothing is unmapped
+pub fn sys_munmap(addr: u64, len: u64) -> i64 {
+    if addr % PAGE_SIZE as u64 != 0 || len == 0 {
+        return -EINVAL;
+    }
+    let Some(end) = addr.checked_add(len).filter(|&end| end <= USER_END) else {
+        return -EINVAL;
+    };
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    // Every mapping lies below the stack area
+    let end = VirtAddr::new(end.min(STACK_START));
+    let start = VirtAddr::new(addr.min(end.as_u64()));
+    match unmap_mappings(unsafe { &mut *process.pcb.get() }, start, end) {
+        Ok(()) => 0,
+        Err(_) => -EIO,
+    }
+}
+
+/// Changes the protection of file mapped pages of the calling process.
+/// As with mmap, pages stay readable and never become executable.
+///
+/// # Arguments
+/// * `addr` - Start of the range, page aligned
+/// * `len` - Bytes in the range, rounded up to whole pages
+/// * `prot` - PROT_READ, optionally with PROT_WRITE
+///
+/// # Returns
+/// 0 on success, -EINVAL for an unaligned address or bad protection,
+/// -ENOMEM if part of the range is not mapped from a file, or the
+/// filesystem's error if dirty pages losing write access could not be
+/// written back
+pub fn sys_mprotect(addr: u64, len: u64, prot: u64) -> i64 {
+    let Some(flags) = protection_to_pagetable_flags(prot) else {
+        return -EINVAL;
+    };
+    if addr % PAGE_SIZE as u64 != 0 {
+        return -EINVAL;
+    }
+    if len == 0 {
+        return 0;
+    }
+    let Ok(start) = VirtAddr::try_new(addr) else {
+        return -ENOMEM;
+    };
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    match protect_mappings(unsafe { &mut *process.pcb.get() }, start, len, flags) {
+        Ok(()) => 0,
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Moves the program break, the end of the heap. As with the Linux
+/// syscall, rather than the C library wrapper, a break that cannot move
+/// is not an error: the caller compares the result with what it asked for.
+///
+/// # Arguments
+/// * `brk` - The new break, or 0 to only ask where it is
+///
+/// # Returns
+/// The break after the call, -ESRCH if the caller has no heap
+pub fn sys_brk(brk: u64) -> i64 {
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &mut *process.pcb.get() };
+    if pcb.heap.is_none() {
+        return -ESRCH;
+    }
+    if let Ok(brk) = VirtAddr::try_new(brk) {
+        // A break that cannot move stays where it was
+        let _ = set_brk(pcb, brk);
+    }
+    pcb.heap.map_or(-ESRCH, |heap| heap.brk.as_u64() as i64)
+}
+
+/// Moves the program break by `increment` bytes, shrinking the heap if it
+/// is negative
+///
+/// # Returns
+/// The old break, which is the start of any memory added, or -ENOMEM if
+/// the break cannot move that far
+pub fn sys_sbrk(increment: u64) -> i64 {
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &mut *process.pcb.get() };
+    let Some(heap) = pcb.heap else {
+        return -ENOMEM;
+    };
+    let old = heap.brk.as_u64();
+    let Some(brk) = old
+        .checked_add_signed(increment as i64)
+        .and_then(|brk| VirtAddr::try_new(brk).ok())
+    else {
+        return -ENOMEM;
+    };
+    match set_brk(pcb, brk) {
+        Ok(()) => old as i64,
+        Err(error) => -error.errno(),
+    }
+}
+
+/// Sends a signal to a process. It is delivered the next time the timer
+/// interrupts the process in user mode, or at once if the process is
+/// waiting in a syscall, which wakes for it. One that is not handled ends
+/// the process with SIGNAL_EXIT_BASE plus the signal as its exit code.
+///
+/// # Arguments
+/// * `pid` - The process to signal
+/// * `signal` - A signal from 1 to NSIG - 1, or 0 to only check that the
+///   process exists
+///
+/// # Returns
+/// 0 on success, -EINVAL for an unknown signal, -ESRCH if there is no such
+/// process, -EPERM for a kernel thread or a process the caller may not
+/// signal
+pub fn sys_kill(pid: u64, signal: u64) -> i64 {
+    let Ok(signal) = u32::try_from(signal) else {
+        return -EINVAL;
+    };
+    if signal as usize >= NSIG {
+        return -EINVAL;
+    }
+    let Some(process) = u32::try_from(pid)
+        .ok()
+        .and_then(|pid| get_process(pid).ok())
+    else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    if pcb.kernel_thread.is_some() {
+        return -EPERM;
+    }
+    let sender = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    if !may_signal(sender, pcb.pid) {
+        return -EPERM;
+    }
+    // A process that has exited has nothing left to deliver to
+    if signal == 0 || matches!(pcb.state, ProcessState::Zombie | ProcessState::Terminated) {
+        return 0;
+    }
+    match pcb.signals.raise(signal) {
+        Ok(()) => 0,
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Whether one process may signal another. Processes have no credentials
+/// yet, so one may signal itself and its descendants, and only those the
+/// kernel launched itself may signal any process.
+fn may_signal(sender: u32, target: u32) -> bool {
+    let Ok(process) = get_process(sender) else {
+        return false;
+    };
+    if unsafe { (*process.pcb.get()).launched_by_kernel } {
+        return true;
+    }
+    let mut pid = target;
+    while pid != KERNEL_PID {
+        if pid == sender {
+            return true;
+        }
+        let Ok(process) = get_process(pid) else {
+            return false;
+        };
+        pid = unsafe { (*process.pcb.get()).parent };
+    }
+    false
+}
+
+/// Sets what a signal does to the calling process
+///
+/// # Arguments
+/// * `signal` - Any signal but SIGKILL
+/// * `handler` - SIG_DFL, SIG_IGN, or the address of a function taking the
+///   signal number
+/// * `restorer` - For a handler, the address the handler returns to, of
+///   code making the sigreturn syscall without touching the stack
+///
+/// # Returns
+/// The previous handler, SIG_DFL or SIG_IGN. -EINVAL for an unknown
+/// signal, SIGKILL, or a handler or restorer outside user memory.
+pub fn sys_sigaction(signal: u64, handler: u64, restorer: u64) -> i64 {
+    let action = match handler {
+        SIG_DFL => SignalAction::Default,
+        SIG_IGN => SignalAction::Ignore,
+        _ if handler >= USER_END || restorer == 0 || restorer >= USER_END => return -EINVAL,
+        _ => SignalAction::Handler { handler, restorer },
+    };
+    let Ok(signal) = u32::try_from(signal) else {
+        return -EINVAL;
+    };
+    let pid = current_running_event_info(x2apic::current_core_id() as u32).pid;
+    let Ok(process) = get_process(pid) else {
+        return -ESRCH;
+    };
+    // Only the process itself, running on this core, changes its actions
+    match unsafe { (*process.pcb.get()).signals.set_action(signal, action) } {
+        Ok(SignalAction::Default) => SIG_DFL as i64,
+        Ok(SignalAction::Ignore) => SIG_IGN as i64,
+        Ok(SignalAction::Handler { handler, .. }) => handler as i64,
+        Err(error) => -KError::from(error).errno(),
+    }
+}
+
+/// Returns from a signal handler to where the signal interrupted the
+/// process, with the registers saved on the stack when it was delivered.
+/// Made by the restorer, with the stack as the handler's return left it.
+///
+/// # Arguments
+/// * `frame` - The registers saved on syscall entry
+///
+/// # Returns
+/// Does not return on success, -EFAULT if the saved registers cannot be
+/// read. A frame whose rip or rsp lies outside user memory ends the
+/// process with SIGSEGV.
+pub fn sys_sigreturn(frame: u64) -> i64 {
+    let cpuid = x2apic::current_core_id() as u32;
+    let pid = current_running_event_info(cpuid).pid;
+
+    let current = unsafe { Registers::from_stack(frame) };
+    let Some(saved) = user_ptr::<Registers>(current.rsp, false) else {
+        return -EFAULT;
+    };
+    let mut registers = unsafe { saved.read() };
+    // The frame is the process' own memory, so it may hold anything, and
+    // returning to a kernel or non-canonical address would fault in ring 0
+    if registers.rip >= USER_END || registers.rsp >= USER_END {
+        terminate_current(pid, SIGSEGV);
+        return -ESRCH;
+    }
+    // The handler may leave the arithmetic flags changed, but not
+    // interrupts disabled or the I/O privilege level raised
+    let user_flags = (RFlags::CARRY_FLAG
+        | RFlags::PARITY_FLAG
+        | RFlags::AUXILIARY_CARRY_FLAG
+        | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG
+        | RFlags::TRAP_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::OVERFLOW_FLAG)
+        .bits();
+    registers.rflags = (current.rflags & !user_flags) | (registers.rflags & user_flags);
+
+    let preemption_info = {
+        let Ok(process) = get_process(pid) else {
+            return -ESRCH;
+        };
+        unsafe {
+            let pcb = process.pcb.get();
+            (*pcb).registers = registers;
+            process.stats.leave_kernel();
+            (*pcb).state = ProcessState::Ready;
+            ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+        }
+    };
+
+    unsafe {
+        // Resume with every register as it was, not only rax as a syscall
+        // returning would
+        schedule_process(cpuid, run_process_ring3(pid), pid);
+
+        // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "push {1}",
+            "ret",
+            in(reg) preemption_info.0,
+            in(reg) preemption_info.1,
+            options(noreturn)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use x86_64::{instructions::interrupts, registers::control::Cr3Flags};
+
+    use super::*;
+    use crate::{
+        events::schedule_process,
+        filesys::{block::memory::MemoryBlockDevice, fat16::Fat16, vfs::MountOptions},
+        processes::{
+            process::{
+                clear_process_frames, create_child_process, create_process, remove_process,
+                run_process_ring3, terminate_process,
+            },
+            test_binaries,
+        },
+    };
+
+    #[test_case]
+    fn test_print_arguments() {
+        assert_eq!(sys_print(0x1000, PRINT_MAX as u64 + 1, 0), -EINVAL);
+        // Tests run outside any process, so there are no descriptors
+        assert_eq!(sys_print(0x1000, 1, 0), -ESRCH);
+
+        // The kernel's own address space maps nothing user accessible
+        assert_eq!(
+            check_user_range(0x1000, 1, false),
+            Err(ErrorKind::Fault.into())
+        );
+        assert_eq!(user_bytes(0, 0), Ok(Vec::new()));
+        assert_eq!(user_bytes(u64::MAX, 2), Err(ErrorKind::Fault.into()));
+    }
+
+    #[test_case]
+    fn test_direct_file_io() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        vfs::mount("/direct", Box::new(fs), MountOptions::empty()).unwrap();
+        let (direct, buffered) = {
+            let mut vfs = vfs::lock();
+            vfs.create_file("/direct/data").unwrap();
+            let direct = vfs.open_with("/direct/data", OpenFlags::DIRECT).unwrap();
+            (direct, vfs.open("/direct/data").unwrap())
+        };
+        let expected: Vec<u8> = (0..PAGE_SIZE).map(|i| (i * 7) as u8).collect();
+
+        // The top page of a new process's stack is mapped, so the transfers
+        // go straight between the file and real user pages
+        let pid = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+        let process = get_process(pid).unwrap();
+        let pcb = unsafe { &mut *process.pcb.get() };
+        let user = STACK_START + pcb.hints.stack_size as u64 - PAGE_SIZE as u64;
+        let len = PAGE_SIZE as u64;
+        let (kernel_pml4, flags) = Cr3::read();
+        interrupts::without_interrupts(|| unsafe {
+            Cr3::write(pcb.pml4_frame, Cr3Flags::empty());
+            let page = core::slice::from_raw_parts_mut(user as *mut u8, PAGE_SIZE);
+            page.copy_from_slice(&expected);
+            assert_eq!(write_direct(direct, user, len), Some(Ok(PAGE_SIZE)));
+            // Buffered files, and empty writes, are left to the copying path
+            assert_eq!(write_direct(buffered, user, len), None);
+            assert_eq!(write_direct(direct, user, 0), None);
+
+            page.fill(0);
+            vfs::lock().seek(direct, SeekFrom::Start(0)).unwrap();
+            assert_eq!(read_file(direct, user, len).unwrap(), PAGE_SIZE);
+            assert_eq!(page, &expected[..]);
+            page.fill(0);
+            assert_eq!(read_file(buffered, user, len).unwrap(), PAGE_SIZE);
+            assert_eq!(page, &expected[..]);
+            Cr3::write(kernel_pml4, flags);
+        });
+
+        // Outside the process, the same address is not the caller's memory
+        assert_eq!(
+            write_direct(direct, user, len),
+            Some(Err(ErrorKind::Fault.into()))
+        );
+
+        clear_process_frames(pcb);
+        drop(process);
+        remove_process(pid);
+        {
+            let mut vfs = vfs::lock();
+            vfs.close(direct).unwrap();
+            vfs.close(buffered).unwrap();
+        }
+        vfs::umount("/direct").unwrap();
+    }
+
+    #[test_case]
+    fn test_kill_permissions() {
+        let elf = test_binaries::by_name("rand_regs").unwrap();
+        let parent = create_process(elf).unwrap();
+        let sender = create_child_process(elf, parent).unwrap();
+        let grandchild = create_child_process(elf, sender).unwrap();
+        let unrelated = create_process(elf).unwrap();
+
+        assert!(may_signal(sender, sender));
+        assert!(may_signal(sender, grandchild));
+        assert!(!may_signal(sender, parent));
+        assert!(!may_signal(sender, unrelated));
+        // Processes the kernel launched may signal any process
+        assert!(may_signal(parent, unrelated));
+        assert!(may_signal(unrelated, grandchild));
+        // Kernel work, such as these tests, has no process to signal from
+        assert!(!may_signal(KERNEL_PID, unrelated));
+
+        for pid in [grandchild, sender, parent, unrelated] {
+            clear_process_frames(unsafe { &mut *get_process(pid).unwrap().pcb.get() });
+            remove_process(pid);
+        }
+    }
+
+    #[test_case]
+    fn test_conformance_suite() {
+        let cpuid = x2apic::current_core_id() as u32;
+        let suite = test_binaries::by_name("syscall_conformance").unwrap();
+        // The suite runs as a child whose parent has exited, which must
+        // not give it the privileges of a process the kernel launched
+        let parent = create_process(test_binaries::by_name("rand_regs").unwrap()).unwrap();
+        let pid = create_child_process(suite, parent).unwrap();
+        clear_process_frames(unsafe { &mut *get_process(parent).unwrap().pcb.get() });
+        terminate_process(parent, 0);
+        assert!(!unsafe { (*get_process(pid).unwrap().pcb.get()).launched_by_kernel });
+        unsafe {
+            schedule_process(cpuid, run_process_ring3(pid), pid);
+        }
+    }
+}
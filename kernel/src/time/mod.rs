@@ -0,0 +1,273 @@
+//! Kernel time keeping.
+//!
+//! The monotonic clock counts nanoseconds since boot using the invariant TSC,
+//! calibrated against the PIT. Each AP measures the offset of its TSC from
+//! the BSP's when it boots, so timestamps taken on different cores can be
+//! compared. Wall clock time is the monotonic clock plus an
+//! offset, initialized from the CMOS RTC and adjustable with settimeofday.
+//! All state is kept in atomics so the clocks can be read from interrupt
+//! handlers and syscalls without taking locks.
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicI64, AtomicU32, AtomicU64, AtomicU8, Ordering},
+};
+
+use crate::{
+    constants::MAX_CORES,
+    debug_println,
+    interrupts::x2apic::{current_core_id, Pit},
+};
+
+pub mod rtc;
+
+pub use taos_fs::time::DateTime;
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// TSC ticks per second, 0 until calibrated
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value at which the monotonic clock reads 0
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds between the Unix epoch and the monotonic clock's zero
+static REALTIME_OFFSET_NS: AtomicI64 = AtomicI64::new(0);
+/// Added to each core's TSC to line it up with the BSP's
+static TSC_OFFSETS: [AtomicI64; MAX_CORES] = [const { AtomicI64::new(0) }; MAX_CORES];
+
+/// Whether the monotonic clock reads `VIRTUAL_NS` instead of the TSC
+#[cfg(test)]
+static VIRTUAL_CLOCK: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+/// Time the virtual clock reads, moved only by tests
+#[cfg(test)]
+static VIRTUAL_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of round trips an AP makes to the BSP when measuring its offset
+const TSC_SYNC_ROUNDS: usize = 32;
+const NO_SYNC_OWNER: u32 = u32::MAX;
+const SYNC_IDLE: u8 = 0;
+const SYNC_REQUESTED: u8 = 1;
+const SYNC_ANSWERED: u8 = 2;
+/// The AP currently measuring its offset
+static SYNC_OWNER: AtomicU32 = AtomicU32::new(NO_SYNC_OWNER);
+static SYNC_PHASE: AtomicU8 = AtomicU8::new(SYNC_IDLE);
+/// The BSP's TSC when it answered the last request
+static SYNC_REFERENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Which clock a time is requested from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockId {
+    /// Wall clock time since the Unix epoch
+    Realtime,
+    /// Time since boot, never adjusted
+    Monotonic,
+}
+
+/// A time split into seconds and nanoseconds, as seen by user programs
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_nanos(nanos: i64) -> Self {
+        Timespec {
+            tv_sec: nanos.div_euclid(NANOS_PER_SEC as i64),
+            tv_nsec: nanos.rem_euclid(NANOS_PER_SEC as i64),
+        }
+    }
+
+    /// Returns the time in nanoseconds, or None if it is malformed or
+    /// does not fit
+    pub fn as_nanos(&self) -> Option<i64> {
+        if !(0..NANOS_PER_SEC as i64).contains(&self.tv_nsec) {
+            return None;
+        }
+        self.tv_sec
+            .checked_mul(NANOS_PER_SEC as i64)?
+            .checked_add(self.tv_nsec)
+    }
+}
+
+/// Calibrates the TSC and reads the wall clock time. Called once on the BSP
+/// before any other core starts reading the clocks.
+pub fn init() {
+    let frequency = Pit::new().calibrate_tsc().expect("Failed to calibrate TSC");
+    TSC_FREQUENCY.store(frequency, Ordering::Release);
+    BOOT_TSC.store(unsafe { _rdtsc() }, Ordering::Release);
+
+    let now = rtc::read();
+    set_realtime_ns(now.to_unix() * NANOS_PER_SEC as i64);
+    taos_fs::time::set_clock(realtime_secs);
+    debug_println!(
+        "TSC running at {} MHz, time is {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        frequency / 1_000_000,
+        now.year,
+        now.month,
+        now.day,
+        now.hour,
+        now.minute,
+        now.second
+    );
+}
+
+/// Measures the offset of this AP's TSC from the BSP's. The BSP must be
+/// calling `serve_tsc_sync` until this returns.
+///
+/// Each round the AP reads its TSC, asks the BSP for the BSP's TSC and reads
+/// its own again. Assuming the BSP answered halfway through, the offset is
+/// the difference to the midpoint. The round with the shortest round trip has
+/// the least uncertainty and is the one kept.
+///
+/// # Arguments
+/// * `core` - This core's APIC ID
+pub fn sync_ap_tsc(core: u32) {
+    assert!((core as usize) < MAX_CORES, "Core ID out of range");
+    while SYNC_OWNER
+        .compare_exchange(NO_SYNC_OWNER, core, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+
+    let mut best_round_trip = u64::MAX;
+    let mut offset = 0;
+    for _ in 0..TSC_SYNC_ROUNDS {
+        let start = unsafe { _rdtsc() };
+        SYNC_PHASE.store(SYNC_REQUESTED, Ordering::SeqCst);
+        while SYNC_PHASE.load(Ordering::SeqCst) != SYNC_ANSWERED {
+            core::hint::spin_loop();
+        }
+        let end = unsafe { _rdtsc() };
+
+        let round_trip = end.wrapping_sub(start);
+        if round_trip < best_round_trip {
+            best_round_trip = round_trip;
+            let midpoint = start.wrapping_add(round_trip / 2);
+            offset = SYNC_REFERENCE.load(Ordering::SeqCst).wrapping_sub(midpoint) as i64;
+        }
+    }
+
+    SYNC_PHASE.store(SYNC_IDLE, Ordering::SeqCst);
+    TSC_OFFSETS[core as usize].store(offset, Ordering::Release);
+    SYNC_OWNER.store(NO_SYNC_OWNER, Ordering::Release);
+}
+
+/// Answers a pending TSC sync request from an AP. Called by the BSP while it
+/// waits for the APs to boot.
+pub fn serve_tsc_sync() {
+    if SYNC_PHASE.load(Ordering::SeqCst) == SYNC_REQUESTED {
+        SYNC_REFERENCE.store(unsafe { _rdtsc() }, Ordering::SeqCst);
+        SYNC_PHASE.store(SYNC_ANSWERED, Ordering::SeqCst);
+    }
+}
+
+/// Reads the TSC, corrected to the BSP's timebase
+pub fn core_tsc() -> u64 {
+    let offset = TSC_OFFSETS
+        .get(current_core_id())
+        .map_or(0, |offset| offset.load(Ordering::Acquire));
+    unsafe { _rdtsc() }.wrapping_add(offset as u64)
+}
+
+/// Returns the TSC frequency in Hz, or 0 before calibration
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Acquire)
+}
+
+/// Converts a number of TSC ticks to nanoseconds
+pub fn tsc_to_ns(ticks: u64) -> u64 {
+    let frequency = tsc_frequency();
+    if frequency == 0 {
+        return 0;
+    }
+    (ticks as u128 * NANOS_PER_SEC as u128 / frequency as u128) as u64
+}
+
+/// Nanoseconds since boot, comparable across cores
+pub fn monotonic_ns() -> u64 {
+    #[cfg(test)]
+    if VIRTUAL_CLOCK.load(Ordering::Acquire) {
+        return VIRTUAL_NS.load(Ordering::Acquire);
+    }
+    let ticks = core_tsc().saturating_sub(BOOT_TSC.load(Ordering::Acquire));
+    tsc_to_ns(ticks)
+}
+
+/// Nanoseconds since the Unix epoch
+pub fn realtime_ns() -> i64 {
+    (monotonic_ns() as i64).saturating_add(REALTIME_OFFSET_NS.load(Ordering::Acquire))
+}
+
+/// Seconds since the Unix epoch
+pub fn realtime_secs() -> i64 {
+    realtime_ns().div_euclid(NANOS_PER_SEC as i64)
+}
+
+/// Sets the wall clock. The monotonic clock is unaffected.
+///
+/// # Arguments
+/// * `nanos` - The current time in nanoseconds since the Unix epoch
+pub fn set_realtime_ns(nanos: i64) {
+    let offset = nanos.saturating_sub(monotonic_ns() as i64);
+    REALTIME_OFFSET_NS.store(offset, Ordering::Release);
+}
+
+/// Stops the monotonic clock at `start_ns`, after which it only moves when
+/// `advance_virtual_clock` is called. Every core sees the virtual clock.
+#[cfg(test)]
+pub fn use_virtual_clock(start_ns: u64) {
+    VIRTUAL_NS.store(start_ns, Ordering::Release);
+    VIRTUAL_CLOCK.store(true, Ordering::Release);
+}
+
+/// Moves the virtual clock forward
+#[cfg(test)]
+pub fn advance_virtual_clock(ns: u64) {
+    VIRTUAL_NS.fetch_add(ns, Ordering::AcqRel);
+}
+
+/// Makes the monotonic clock follow the TSC again
+#[cfg(test)]
+pub fn use_real_clock() {
+    VIRTUAL_CLOCK.store(false, Ordering::Release);
+}
+
+/// Reads a clock
+pub fn clock_gettime(clock: ClockId) -> Timespec {
+    match clock {
+        ClockId::Realtime => Timespec::from_nanos(realtime_ns()),
+        ClockId::Monotonic => Timespec::from_nanos(monotonic_ns() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_clocks_advance() {
+        let first = monotonic_ns();
+        let before = realtime_ns();
+        for _ in 0..1000 {
+            core::hint::spin_loop();
+        }
+        assert!(monotonic_ns() > first);
+        assert!(realtime_ns() > before);
+    }
+
+    #[test_case]
+    fn test_virtual_clock() {
+        use_virtual_clock(NANOS_PER_SEC);
+        let before = monotonic_ns();
+        for _ in 0..1000 {
+            core::hint::spin_loop();
+        }
+        assert_eq!(monotonic_ns(), before);
+        advance_virtual_clock(5);
+        assert_eq!(monotonic_ns(), NANOS_PER_SEC + 5);
+        use_real_clock();
+        assert_ne!(monotonic_ns(), NANOS_PER_SEC + 5);
+    }
+}
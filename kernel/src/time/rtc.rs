@@ -0,0 +1,121 @@
+//! CMOS real time clock.
+//!
+//! Only read once at boot to find the wall clock time. The RTC may be in BCD
+//! or binary and 12 or 24 hour mode, which is reported in status register B.
+
+use x86_64::instructions::port::Port;
+
+use super::DateTime;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_CENTURY: u8 = 0x32;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+/// Set in status A while the RTC is updating its registers
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Set in status B if the registers are binary rather than BCD
+const BINARY_MODE: u8 = 1 << 2;
+/// Set in status B if hours are 0-23 rather than 1-12
+const HOUR_24_MODE: u8 = 1 << 1;
+/// Set in the hours register for PM in 12 hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+/// Raw register values, compared to detect a read torn by an update
+#[derive(PartialEq, Eq, Clone, Copy)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        // Keep NMIs enabled (bit 7 clear)
+        address.write(register & 0x7F);
+        data.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(RTC_STATUS_A) & UPDATE_IN_PROGRESS != 0
+}
+
+fn read_raw() -> RawTime {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(RTC_SECONDS),
+        minute: read_register(RTC_MINUTES),
+        hour: read_register(RTC_HOURS),
+        day: read_register(RTC_DAY),
+        month: read_register(RTC_MONTH),
+        year: read_register(RTC_YEAR),
+        century: read_register(RTC_CENTURY),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Reads the current date and time from the RTC, assumed to be in UTC
+pub fn read() -> DateTime {
+    // Read until two consecutive reads agree so an update cannot tear it
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(RTC_STATUS_B);
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = raw.hour & !HOUR_PM;
+    let convert = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    hour = convert(hour);
+    if status_b & HOUR_24_MODE == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match convert(raw.century) {
+        // No century register, assume the 21st century
+        0 => 20,
+        century => century,
+    };
+
+    DateTime {
+        year: century as u32 * 100 + convert(raw.year) as u32,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
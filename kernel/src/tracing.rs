@@ -0,0 +1,136 @@
+//! Kernel tracepoints.
+//!
+//! Each core records scheduler and IPI activity into its own fixed size ring
+//! buffer. Records are timestamped with the monotonic clock, which corrects
+//! for per-core TSC offsets, so the buffers of all cores can be merged into
+//! one ordered timeline.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{constants::MAX_CORES, interrupts::x2apic::current_core_id, serial_println, time};
+
+/// Number of records kept per core. Older records are overwritten.
+const TRACE_BUFFER_SIZE: usize = 256;
+
+/// Something that happened on a core
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// An event was added to a run queue
+    EventScheduled { eid: u64, pid: u32, priority: usize },
+    /// An event was polled
+    EventPolled { eid: u64, pid: u32, ready: bool },
+    /// A poll held its event's future for longer than the runner allows
+    LongPoll {
+        eid: u64,
+        pid: u32,
+        name: &'static str,
+        held_ns: u64,
+    },
+    /// An IPI was sent to another core
+    IpiSent { target: u32, vector: u8 },
+    /// An IPI was received
+    IpiReceived { vector: u8 },
+}
+
+/// A timestamped trace event
+#[derive(Debug, Clone, Copy)]
+pub struct TraceRecord {
+    /// Nanoseconds since boot
+    pub timestamp_ns: u64,
+    /// The core the event happened on
+    pub core: u32,
+    pub event: TraceEvent,
+}
+
+/// Ring buffer of one core's records
+struct TraceBuffer {
+    records: [Option<TraceRecord>; TRACE_BUFFER_SIZE],
+    next: usize,
+}
+
+impl TraceBuffer {
+    const fn new() -> Self {
+        TraceBuffer {
+            records: [None; TRACE_BUFFER_SIZE],
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, record: TraceRecord) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % TRACE_BUFFER_SIZE;
+    }
+}
+
+static TRACE_BUFFERS: [Mutex<TraceBuffer>; MAX_CORES] =
+    [const { Mutex::new(TraceBuffer::new()) }; MAX_CORES];
+
+/// Records an event on the current core. Safe to call from interrupt
+/// handlers.
+pub fn record(event: TraceEvent) {
+    let core = current_core_id();
+    let Some(buffer) = TRACE_BUFFERS.get(core) else {
+        return;
+    };
+    without_interrupts(|| {
+        // Taken in the same critical section as the push so a core's
+        // records are always in timestamp order
+        let timestamp_ns = time::monotonic_ns();
+        buffer.lock().push(TraceRecord {
+            timestamp_ns,
+            core: core as u32,
+            event,
+        });
+    });
+}
+
+/// Returns the records of all cores, merged in timestamp order
+pub fn merged() -> Vec<TraceRecord> {
+    let mut records: Vec<TraceRecord> = Vec::new();
+    for buffer in TRACE_BUFFERS.iter() {
+        without_interrupts(|| records.extend(buffer.lock().records.iter().flatten()));
+    }
+    records.sort_by_key(|record| record.timestamp_ns);
+    records
+}
+
+/// Prints the merged timeline of all cores
+pub fn dump() {
+    for record in merged() {
+        serial_println!(
+            "[{:>6}.{:06}] core {}: {:?}",
+            record.timestamp_ns / 1_000_000_000,
+            record.timestamp_ns % 1_000_000_000 / 1000,
+            record.core,
+            record.event
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_merged_records_are_ordered() {
+        record(TraceEvent::IpiReceived { vector: 0xFE });
+        record(TraceEvent::IpiReceived { vector: 0xFF });
+
+        let records = merged();
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].timestamp_ns <= pair[1].timestamp_ns));
+
+        let first = records
+            .iter()
+            .position(|r| r.event == TraceEvent::IpiReceived { vector: 0xFE })
+            .expect("Record missing");
+        let second = records
+            .iter()
+            .position(|r| r.event == TraceEvent::IpiReceived { vector: 0xFF })
+            .expect("Record missing");
+        assert!(first < second);
+    }
+}
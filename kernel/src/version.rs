@@ -0,0 +1,93 @@
+//! Kernel identification.
+//!
+//! The build script injects the version, taken from `git describe` when
+//! the kernel is built from a checkout, and the build time. User programs
+//! read both through uname, along with the syscall table version and the
+//! feature bits, to tell kernel builds apart while the ABI keeps changing.
+
+use crate::{
+    constants::syscalls::{SUPPORTED_FEATURES, SYSCALL_TABLE_VERSION},
+    format::snprintf,
+};
+
+/// Name of the kernel
+pub const KERNEL_NAME: &str = "TAOS";
+/// Version of the kernel crate
+pub const RELEASE: &str = env!("CARGO_PKG_VERSION");
+/// Source revision the kernel was built from
+pub const VERSION: &str = env!("TAOS_VERSION");
+
+/// Seconds since the Unix epoch when the kernel was built
+pub fn build_time() -> u64 {
+    env!("TAOS_BUILD_TIME")
+        .parse()
+        .expect("Build script passed a malformed build time")
+}
+
+/// Length of each string field of Utsname, including the terminating NUL
+pub const UTSNAME_LENGTH: usize = 65;
+
+/// Kernel identification returned by uname. Strings are NUL terminated
+/// and cut short to fit.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Utsname {
+    pub sysname: [u8; UTSNAME_LENGTH],
+    pub release: [u8; UTSNAME_LENGTH],
+    pub version: [u8; UTSNAME_LENGTH],
+    /// Seconds since the Unix epoch when the kernel was built
+    pub build_time: u64,
+    /// Incremented whenever a syscall's number or arguments change
+    pub syscall_table: u64,
+    /// FEATURE_* bits of the syscall groups the kernel supports
+    pub features: u64,
+}
+
+impl Utsname {
+    /// Identifies the running kernel
+    pub fn current() -> Self {
+        let mut uts = Self {
+            sysname: [0; UTSNAME_LENGTH],
+            release: [0; UTSNAME_LENGTH],
+            version: [0; UTSNAME_LENGTH],
+            build_time: 0,
+            syscall_table: 0,
+            features: 0,
+        };
+        uts.fill();
+        uts
+    }
+
+    /// Overwrites every field in place, so uname can fill the caller's
+    /// buffer without a copy on the kernel stack. Bytes after each string's
+    /// terminator are left as they were.
+    pub fn fill(&mut self) {
+        snprintf(&mut self.sysname, format_args!("{}", KERNEL_NAME));
+        snprintf(&mut self.release, format_args!("{}", RELEASE));
+        snprintf(&mut self.version, format_args!("{}", VERSION));
+        self.build_time = build_time();
+        self.syscall_table = SYSCALL_TABLE_VERSION;
+        self.features = SUPPORTED_FEATURES;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_utsname() {
+        let uts = Utsname::current();
+        assert_eq!(&uts.sysname[..5], b"TAOS\0");
+        assert_eq!(&uts.release[..RELEASE.len()], RELEASE.as_bytes());
+        assert!(uts.version.contains(&0));
+        assert!(uts.build_time > 0);
+        assert_eq!(uts.features, SUPPORTED_FEATURES);
+
+        // Filling in place terminates each string over whatever was there
+        let mut dirty = uts;
+        dirty.sysname = [0xFF; UTSNAME_LENGTH];
+        dirty.fill();
+        assert_eq!(&dirty.sysname[..5], b"TAOS\0");
+    }
+}
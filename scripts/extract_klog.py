@@ -0,0 +1,49 @@
+#!/usr/bin/env python3
+"""Prints the kernel log ring found in a guest memory image.
+
+Take the image from the QEMU monitor after a hang or triple fault, for
+example with `dump-guest-memory -z memory.dump` or `pmemsave 0 0x100000000
+memory.dump`, and run QEMU with -no-reboot so a triple fault does not reset
+the machine first. The ring layout is described in kernel/src/klog.rs.
+"""
+
+import struct
+import sys
+
+MAGIC = b"TAOSKLOG"
+VERSION = 1
+HEADER = struct.Struct("<8sIIQ")
+PAGE_SIZE = 4096
+
+
+def find_ring(image):
+    """Returns the data of the first ring whose header looks valid"""
+    offset = image.find(MAGIC)
+    while offset != -1:
+        # The ring starts a page, which skips copies of the magic in .rodata
+        if offset % PAGE_SIZE == 0 and offset + HEADER.size <= len(image):
+            _, version, capacity, head = HEADER.unpack_from(image, offset)
+            data_start = offset + HEADER.size
+            if version == VERSION and 0 < capacity <= len(image) - data_start:
+                return image[data_start:data_start + capacity], capacity, head
+        offset = image.find(MAGIC, offset + 1)
+    return None
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(f"usage: {sys.argv[0]} <memory image>")
+    with open(sys.argv[1], "rb") as f:
+        image = f.read()
+
+    ring = find_ring(image)
+    if ring is None:
+        sys.exit("No kernel log ring found")
+    data, capacity, head = ring
+    start = head % capacity
+    contents = data[start:] + data[:start] if head > capacity else data[:head]
+    sys.stdout.write(contents.decode("utf-8", errors="replace"))
+
+
+if __name__ == "__main__":
+    main()
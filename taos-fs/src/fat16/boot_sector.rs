@@ -1,7 +1,13 @@
 //! FAT16 Boot Sector Structure
 
+use crate::{
+    layout::{Le16, Le32},
+    on_disk,
+};
+
 /// Represents the boot sector of a FAT16 filesystem
-#[repr(C, packed)]
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct BootSector {
     /// Jump instruction to boot code
     pub jump_boot: [u8; 3],
@@ -10,45 +16,45 @@ pub struct BootSector {
     pub oem_name: [u8; 8],
 
     /// Number of bytes per sector
-    pub bytes_per_sector: u16,
+    pub bytes_per_sector: Le16,
 
     /// Number of sectors per cluster
     pub sectors_per_cluster: u8,
 
     /// Number of reserved sectors at start of volume
     /// Including the boot sector. Typically 1 for FAT16
-    pub reserved_sectors: u16,
+    pub reserved_sectors: Le16,
 
     /// Number of FAT copies
     pub fat_count: u8,
 
     /// Maximum number of root directory entries
-    pub root_dir_entries: u16,
+    pub root_dir_entries: Le16,
 
     /// Total number of sectors (16-bit)
     /// Used if volume is smaller than 32MB, otherwise use total_sectors_32
-    pub total_sectors_16: u16,
+    pub total_sectors_16: Le16,
 
     /// Media type descriptor
     pub media_type: u8,
 
     /// Sectors per FAT
     /// Size of each FAT copy in sectors
-    pub sectors_per_fat: u16,
+    pub sectors_per_fat: Le16,
 
     /// Sectors per track for interrupt 0x13
-    pub sectors_per_track: u16,
+    pub sectors_per_track: Le16,
 
     /// Number of heads for interrupt 0x13
-    pub head_count: u16,
+    pub head_count: Le16,
 
     /// Number of hidden sectors preceding the partition
     /// Used for partition boot sector
-    pub hidden_sectors: u32,
+    pub hidden_sectors: Le32,
 
     /// Total number of sectors (32-bit)
     /// Used if volume is larger than 32MB
-    pub total_sectors_32: u32,
+    pub total_sectors_32: Le32,
 
     /// INT 13h drive number
     pub drive_number: u8,
@@ -60,7 +66,7 @@ pub struct BootSector {
     pub boot_signature: u8,
 
     /// Volume serial number
-    pub volume_id: u32,
+    pub volume_id: Le32,
 
     /// Volume label
     pub volume_label: [u8; 11],
@@ -68,3 +74,26 @@ pub struct BootSector {
     /// Filesystem type string
     pub fs_type: [u8; 8],
 }
+
+on_disk!(BootSector, 62 bytes {
+    0 => jump_boot: [u8; 3],
+    3 => oem_name: [u8; 8],
+    11 => bytes_per_sector: Le16,
+    13 => sectors_per_cluster: u8,
+    14 => reserved_sectors: Le16,
+    16 => fat_count: u8,
+    17 => root_dir_entries: Le16,
+    19 => total_sectors_16: Le16,
+    21 => media_type: u8,
+    22 => sectors_per_fat: Le16,
+    24 => sectors_per_track: Le16,
+    26 => head_count: Le16,
+    28 => hidden_sectors: Le32,
+    32 => total_sectors_32: Le32,
+    36 => drive_number: u8,
+    37 => reserved1: u8,
+    38 => boot_signature: u8,
+    39 => volume_id: Le32,
+    43 => volume_label: [u8; 11],
+    54 => fs_type: [u8; 8],
+});
@@ -0,0 +1,289 @@
+//! Consistency check of a FAT16 volume, as done by fsck.
+//!
+//! Every directory is walked from the root and each entry's cluster chain
+//! is followed through the first FAT, marking the clusters it uses. A chain
+//! that leaves the data area or runs into a free cluster is broken, one that
+//! reaches a cluster already marked is cross-linked or loops, and clusters
+//! the FAT allocates but nothing marked are lost. The check only reads the
+//! volume.
+
+use super::{constants::*, *};
+use alloc::{format, string::String};
+
+/// Bit 3 of the attributes marks volume labels, and together with the
+/// low bits, long name entries. Neither has clusters of its own.
+const ATTR_VOLUME_ID: u8 = 0x08;
+
+/// Something wrong with a FAT16 volume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The two copies of the FAT differ in this cluster's entry
+    FatMismatch(u16),
+    /// A chain continues to a cluster that is free or outside the data area
+    BrokenChain { path: String, cluster: u16 },
+    /// A chain reaches a cluster another chain, or itself, already uses
+    CrossLinked { path: String, cluster: u16 },
+    /// A file's size does not fit its chain. The driver allocates the next
+    /// cluster as soon as one fills up, so one cluster beyond the size is
+    /// not counted.
+    WrongSize {
+        path: String,
+        size: u64,
+        clusters: u32,
+    },
+    /// This many clusters are allocated but belong to no file or directory
+    LostClusters(u32),
+}
+
+/// What `Fat16::check` found
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub files: u32,
+    /// Not counting the root directory
+    pub directories: u32,
+    /// Clusters used by files and directories
+    pub used_clusters: u32,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Fat16<'_> {
+    /// Checks the volume for broken, cross-linked and lost cluster chains,
+    /// file sizes that do not match their chains, and FAT copies that
+    /// disagree
+    ///
+    /// # Returns
+    /// Every problem found, or an error if the device could not be read
+    pub fn check(&self) -> Result<CheckReport, FsError> {
+        let limit = self.cluster_limit();
+        let fat = self.read_fat(0)?;
+        let mut report = CheckReport::default();
+
+        if self.boot_sector.fat_count > 1 {
+            let copy = self.read_fat(1)?;
+            report.problems.extend(
+                (2..limit)
+                    .filter(|&cluster| fat[cluster as usize] != copy[cluster as usize])
+                    .map(Problem::FatMismatch),
+            );
+        }
+
+        let mut used = vec![false; limit as usize];
+        // Directories still to walk, by first cluster and path. The root
+        // has a fixed area instead of a chain.
+        let mut directories = vec![(0u16, String::new())];
+        while let Some((dir_cluster, dir_path)) = directories.pop() {
+            for entry in self.directory_entries(dir_cluster, &fat)? {
+                if entry.name[0] == b'.' || entry.attributes & ATTR_VOLUME_ID != 0 {
+                    continue;
+                }
+                let path = format!("{}/{}", dir_path, entry.get_name());
+                let start = entry.start_cluster.get();
+                let clusters = self.mark_chain(&path, start, &fat, &mut used, &mut report);
+                report.used_clusters += clusters;
+
+                if entry.is_directory() {
+                    report.directories += 1;
+                    // Walking a cross-linked directory could go round forever
+                    if clusters > 0 && !report.problems.iter().any(|p| p.is_on(&path)) {
+                        directories.push((start, path));
+                    }
+                    continue;
+                }
+
+                report.files += 1;
+                let size = entry.file_size.get() as u64;
+                let needed = size.div_ceil(self.cluster_size as u64) as u32;
+                if needed > clusters || clusters > needed.max(1) + 1 {
+                    report.problems.push(Problem::WrongSize {
+                        path,
+                        size,
+                        clusters,
+                    });
+                }
+            }
+        }
+
+        let lost = (2..limit)
+            .filter(|&cluster| fat[cluster as usize] != 0 && !used[cluster as usize])
+            .count() as u32;
+        if lost > 0 {
+            report.problems.push(Problem::LostClusters(lost));
+        }
+        Ok(report)
+    }
+
+    /// Reads the entries of copy `copy` of the FAT up to the last cluster
+    fn read_fat(&self, copy: u8) -> Result<Vec<u16>, FsError> {
+        let start = self.fat_start + copy as u64 * self.boot_sector.sectors_per_fat.get() as u64;
+        let entries = self.cluster_limit() as usize;
+        let mut fat = Vec::with_capacity(entries);
+        let mut sector_data = vec![0u8; SECTOR_SIZE];
+        for sector in 0..(entries * FAT_ENTRY_SIZE).div_ceil(SECTOR_SIZE) {
+            self.read_sector(start + sector as u64, &mut sector_data)?;
+            fat.extend(
+                sector_data
+                    .as_chunks::<FAT_ENTRY_SIZE>()
+                    .0
+                    .iter()
+                    .map(|&entry| u16::from_le_bytes(entry)),
+            );
+        }
+        fat.truncate(entries);
+        Ok(fat)
+    }
+
+    /// Follows a chain from `start`, marking its clusters as used
+    ///
+    /// # Returns
+    /// The number of clusters marked, up to where the chain breaks
+    fn mark_chain(
+        &self,
+        path: &str,
+        start: u16,
+        fat: &[u16],
+        used: &mut [bool],
+        report: &mut CheckReport,
+    ) -> u32 {
+        let mut cluster = start;
+        let mut count = 0;
+        // An empty file may have no chain at all
+        if cluster == 0 {
+            return 0;
+        }
+        loop {
+            if !(2..used.len()).contains(&(cluster as usize)) || fat[cluster as usize] == 0 {
+                report.problems.push(Problem::BrokenChain {
+                    path: path.into(),
+                    cluster,
+                });
+                return count;
+            }
+            if used[cluster as usize] {
+                report.problems.push(Problem::CrossLinked {
+                    path: path.into(),
+                    cluster,
+                });
+                return count;
+            }
+            used[cluster as usize] = true;
+            count += 1;
+
+            let next = FatEntry {
+                cluster: fat[cluster as usize],
+            };
+            if next.is_end_of_chain() {
+                return count;
+            }
+            cluster = next.cluster;
+        }
+    }
+
+    /// Reads the entries in use in a directory, 0 being the root directory
+    fn directory_entries(&self, cluster: u16, fat: &[u16]) -> Result<Vec<DirEntry83>, FsError> {
+        let entries_per_sector = SECTOR_SIZE / DirEntry83::SIZE;
+        let mut sectors = Vec::new();
+        if cluster == 0 {
+            let root_sectors = (ROOT_DIR_ENTRIES / entries_per_sector) as u64;
+            sectors.extend((0..root_sectors).map(|i| self.root_dir_start + i));
+        } else {
+            // Only reached for chains that were marked without problems
+            let mut cluster = cluster;
+            loop {
+                let first = self.cluster_to_sector(cluster);
+                sectors.extend((0..self.boot_sector.sectors_per_cluster as u64).map(|i| first + i));
+                let next = FatEntry {
+                    cluster: fat[cluster as usize],
+                };
+                if next.is_end_of_chain() {
+                    break;
+                }
+                cluster = next.cluster;
+            }
+        }
+
+        let mut entries = Vec::new();
+        let mut sector_data = vec![0u8; SECTOR_SIZE];
+        for sector in sectors {
+            self.read_sector(sector, &mut sector_data)?;
+            for i in 0..entries_per_sector {
+                let entry = DirEntry83::read_slot(&sector_data, i);
+                // The first free entry ends the directory
+                if entry.is_free() {
+                    return Ok(entries);
+                }
+                if !entry.is_deleted() {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Problem {
+    /// Returns true if the problem is with the chain of `path`
+    fn is_on(&self, path: &str) -> bool {
+        match self {
+            Problem::BrokenChain { path: p, .. } | Problem::CrossLinked { path: p, .. } => {
+                p == path
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::MemoryBlockDevice;
+
+    #[test]
+    fn test_check() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).unwrap();
+        fs.create_dir("/dir").unwrap();
+        fs.create_file("/dir/data").unwrap();
+        let fd = fs.open_file("/dir/data").unwrap();
+        fs.write_file(fd, &[7; 5000]).unwrap();
+        fs.close_file(fd);
+        fs.create_file("/empty").unwrap();
+
+        let report = fs.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!((report.files, report.directories), (2, 1));
+        assert_eq!(report.used_clusters, 1 + 3 + 1);
+
+        // Allocate a cluster in the first FAT only, so nothing uses it and
+        // the copies disagree
+        let stray = fs.cluster_limit() - 1;
+        fs.set_fat_entry(&mut fs.fat.write(), stray, FatEntry { cluster: 0xFFFF })
+            .unwrap();
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        let offset = stray as u64 * FAT_ENTRY_SIZE as u64;
+        let second = fs.fat_start + fs.boot_sector.sectors_per_fat.get() as u64;
+        fs.read_sector(second + offset / SECTOR_SIZE as u64, &mut sector)
+            .unwrap();
+        put_u16_le_at(&mut sector, (offset % SECTOR_SIZE as u64) as usize, 0);
+        fs.write_sectors(second + offset / SECTOR_SIZE as u64, &sector)
+            .unwrap();
+
+        // And point the empty file's chain into the data file's
+        let (_, position) = fs.find_entry("/empty").unwrap();
+        let data = fs.find_entry("/dir/data").unwrap().0.start_cluster;
+        fs.update_entry(position, |entry| entry.start_cluster = data)
+            .unwrap();
+
+        let problems = fs.check().unwrap().problems;
+        assert!(problems.contains(&Problem::FatMismatch(stray)));
+        assert!(problems.contains(&Problem::LostClusters(2)));
+        assert!(problems
+            .iter()
+            .any(|p| matches!(p, Problem::CrossLinked { .. })));
+    }
+}
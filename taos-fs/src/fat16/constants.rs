@@ -6,6 +6,9 @@ pub const SECTOR_SIZE: usize = 512;
 /// Size of FAT entry in bytes (16-bit)
 pub const FAT_ENTRY_SIZE: usize = 2;
 
+/// First cluster number reserved for bad and end of chain markers
+pub const MAX_CLUSTER: u16 = 0xFFF0;
+
 /// Maximum number of root directory entries
 pub const ROOT_DIR_ENTRIES: usize = 512;
 
@@ -26,3 +29,9 @@ pub const ATTR_ARCHIVE: u8 = 0x20;
 
 /// Marker for deleted directory entries
 pub const DELETED_ENTRY_MARKER: u8 = 0xE5;
+
+/// First second representable in a FAT timestamp, 1980-01-01 00:00:00
+pub const FAT_EPOCH: i64 = 315_532_800;
+
+/// Last second representable in a FAT timestamp, 2107-12-31 23:59:58
+pub const FAT_LAST_SECOND: i64 = 4_354_819_198;
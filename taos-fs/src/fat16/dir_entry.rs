@@ -0,0 +1,175 @@
+//! FAT16 directory entry structure and operations
+
+use super::{constants::*, short_name::ShortName, *};
+use crate::{
+    on_disk,
+    time::{self, DateTime},
+};
+
+/// 8.3 format directory entry (32 bytes)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DirEntry83 {
+    /// 8 character filename
+    pub name: [u8; 8],
+
+    /// 3 character extension
+    pub ext: [u8; 3],
+
+    /// File attributes (read-only, directory, etc)
+    pub attributes: u8,
+
+    /// Reserved, and creation time which we do not maintain
+    pub reserved: [u8; 6],
+
+    /// Last access date. FAT records no access time of day.
+    pub access_date: Le16,
+
+    /// High word of the first cluster, always 0 on FAT16
+    pub cluster_high: Le16,
+
+    /// Modification time
+    pub time: Le16,
+
+    /// Modification date
+    pub date: Le16,
+
+    /// First cluster number
+    pub start_cluster: Le16,
+
+    /// File size in bytes
+    pub file_size: Le32,
+}
+
+on_disk!(DirEntry83, 32 bytes {
+    0 => name: [u8; 8],
+    8 => ext: [u8; 3],
+    11 => attributes: u8,
+    12 => reserved: [u8; 6],
+    18 => access_date: Le16,
+    20 => cluster_high: Le16,
+    22 => time: Le16,
+    24 => date: Le16,
+    26 => start_cluster: Le16,
+    28 => file_size: Le32,
+});
+
+impl DirEntry83 {
+    /// Creates a new file entry with given name and starting cluster
+    pub fn new_file(name: ShortName, start_cluster: u16) -> Self {
+        let mut entry = Self {
+            name: name.name,
+            ext: name.ext,
+            attributes: ATTR_ARCHIVE,
+            reserved: [0; 6],
+            access_date: Le16::new(0),
+            cluster_high: Le16::new(0),
+            time: Le16::new(0),
+            date: Le16::new(0),
+            start_cluster: Le16::new(start_cluster),
+            file_size: Le32::new(0),
+        };
+        entry.touch();
+        entry.set_accessed(time::now());
+        entry
+    }
+
+    /// Creates a new directory entry with given name and starting cluster
+    pub fn new_directory(name: ShortName, start_cluster: u16) -> Self {
+        let mut entry = Self::new_file(name, start_cluster);
+        entry.attributes = ATTR_DIRECTORY;
+        entry
+    }
+
+    /// Sets the modification time to the current wall clock time
+    pub fn touch(&mut self) {
+        self.set_modified(time::now());
+    }
+
+    /// Sets the modification time, see `encode_timestamp` for the range
+    ///
+    /// # Arguments
+    /// * `unix_secs` - Seconds since the Unix epoch
+    pub fn set_modified(&mut self, unix_secs: i64) {
+        let (date, time) = encode_timestamp(unix_secs);
+        self.date.set(date);
+        self.time.set(time);
+    }
+
+    /// Returns the modification time in seconds since the Unix epoch, or 0
+    /// if it was never set
+    pub fn modified(&self) -> i64 {
+        decode_timestamp(self.date.get(), self.time.get())
+    }
+
+    /// Sets the access date. Only the day is stored.
+    ///
+    /// # Arguments
+    /// * `unix_secs` - Seconds since the Unix epoch
+    pub fn set_accessed(&mut self, unix_secs: i64) {
+        self.access_date.set(encode_timestamp(unix_secs).0);
+    }
+
+    /// Returns midnight of the access date in seconds since the Unix epoch,
+    /// or 0 if it was never set
+    pub fn accessed(&self) -> i64 {
+        decode_timestamp(self.access_date.get(), 0)
+    }
+
+    /// Returns true if entry is marked as deleted
+    pub fn is_deleted(&self) -> bool {
+        self.name[0] == DELETED_ENTRY_MARKER
+    }
+
+    /// Returns true if entry is empty/unused
+    pub fn is_free(&self) -> bool {
+        self.name[0] == 0x00
+    }
+
+    /// Returns true if entry is a directory
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// Returns the filename as a string, including extension if present
+    pub fn get_name(&self) -> String {
+        let name_end = self.name.iter().position(|&x| x == 0x20).unwrap_or(8);
+        let ext_end = self.ext.iter().position(|&x| x == 0x20).unwrap_or(3);
+
+        let name = core::str::from_utf8(&self.name[..name_end]).unwrap_or("");
+        let ext = core::str::from_utf8(&self.ext[..ext_end]).unwrap_or("");
+
+        if ext_end > 0 {
+            alloc::format!("{}.{}", name, ext)
+        } else {
+            name.into()
+        }
+    }
+}
+
+/// Converts Unix seconds to a FAT date and time. FAT stores local time with
+/// two second resolution from 1980 to 2107, we store UTC and clamp to that
+/// range.
+fn encode_timestamp(unix_secs: i64) -> (u16, u16) {
+    let date = DateTime::from_unix(unix_secs.clamp(FAT_EPOCH, FAT_LAST_SECOND));
+    (
+        (((date.year - 1980) as u16) << 9) | ((date.month as u16) << 5) | date.day as u16,
+        ((date.hour as u16) << 11) | ((date.minute as u16) << 5) | (date.second as u16 / 2),
+    )
+}
+
+/// Converts a FAT date and time to Unix seconds, 0 for an unset date
+fn decode_timestamp(date: u16, time: u16) -> i64 {
+    if date == 0 {
+        return 0;
+    }
+    DateTime {
+        year: 1980 + (date >> 9) as u32,
+        month: ((date >> 5) & 0xF) as u8,
+        day: (date & 0x1F) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+    }
+    .to_unix()
+}
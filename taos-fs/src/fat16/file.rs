@@ -0,0 +1,88 @@
+//! Per-descriptor state of open FAT16 files
+
+use super::{table::FatTable, *};
+
+/// Represents an open file on a FAT16 filesystem. Each descriptor has its
+/// own, so positions are never shared between descriptors.
+pub struct Fat16File {
+    /// First cluster of the file
+    pub start_cluster: u16,
+
+    /// Current cluster being accessed
+    pub current_cluster: u16,
+
+    /// Index of `current_cluster` within the file's cluster chain
+    pub cluster_index: u64,
+
+    /// Current position in file
+    pub position: u64,
+
+    /// Total file size in bytes
+    pub size: u64,
+
+    /// Location of directory entry
+    pub entry_position: u64,
+}
+
+impl Fat16File {
+    /// Points `current_cluster` at the cluster holding `position`, or at the
+    /// last cluster of the chain if the position is beyond it
+    pub fn locate(&mut self, fat: &FatTable, cluster_size: usize) {
+        let target = self.position / cluster_size as u64;
+        if target == self.cluster_index {
+            return;
+        }
+
+        // Chains only link forward, so seeking backwards starts over
+        let (mut cluster, mut index) = if target > self.cluster_index {
+            (self.current_cluster, self.cluster_index)
+        } else {
+            (self.start_cluster, 0)
+        };
+        while index < target {
+            let next = fat.get(cluster);
+            if next.is_end_of_chain() {
+                break;
+            }
+            cluster = next.cluster;
+            index += 1;
+        }
+
+        self.current_cluster = cluster;
+        self.cluster_index = index;
+    }
+
+    /// Moves the position as `seek_file` describes
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => {
+                if offset < 0 {
+                    self.size
+                        .checked_sub(offset.unsigned_abs())
+                        .ok_or(FsError::InvalidOffset)?
+                } else {
+                    self.size
+                        .checked_add(offset as u64)
+                        .ok_or(FsError::InvalidOffset)?
+                }
+            }
+            SeekFrom::Current(offset) => {
+                if offset < 0 {
+                    self.position
+                        .checked_sub(offset.unsigned_abs())
+                        .ok_or(FsError::InvalidOffset)?
+                } else {
+                    self.position
+                        .checked_add(offset as u64)
+                        .ok_or(FsError::InvalidOffset)?
+                }
+            }
+        };
+
+        // The cluster is found on the next read or write, and seeking past
+        // the end is allowed. Writing there fills the gap with zeros.
+        self.position = new_pos;
+        Ok(new_pos)
+    }
+}
@@ -0,0 +1,1299 @@
+//! FAT16 filesystem implementation
+//!
+//! A mounted volume can be shared between cores: besides the `FileSystem`
+//! methods, every operation is available through `&self`, and the driver
+//! locks only what an operation touches.
+//!
+//! - The device sits behind a read-write lock held for one transfer at a
+//!   time. Directory entries and FAT sectors are changed with
+//!   `modify_sector`, which holds it from the read to the write, so
+//!   changes to two entries sharing a sector cannot undo each other.
+//! - The FAT is kept in memory behind a read-write lock. Following chains
+//!   shares it, allocating and freeing clusters takes it alone.
+//! - Every open file has its own lock and position, so descriptors only
+//!   wait on each other while they touch the FAT or the device.
+//! - Creating, removing and renaming lock the directories whose entries
+//!   change, so namespace changes in different directories run side by
+//!   side. Moves between directories also take `rename_lock`, so two moves
+//!   cannot each pass the check that keeps a directory out of its own
+//!   subtree.
+//!
+//! Locks are taken in that list's reverse order: directories, a file, the
+//! FAT, then the device. Writes to one file through different descriptors
+//! are not ordered against each other.
+
+use super::{
+    layout::{put_u16_le_at, Le16, Le32, OnDisk},
+    *,
+};
+use alloc::{
+    collections::{BTreeSet, BinaryHeap},
+    sync::Arc,
+    vec,
+};
+use core::cmp::{max, min};
+use spin::{Mutex, RwLock};
+
+mod boot_sector;
+mod check;
+mod constants;
+mod dir_entry;
+mod fat_entry;
+mod file;
+mod short_name;
+mod table;
+
+pub use boot_sector::BootSector;
+pub use check::{CheckReport, Problem};
+use constants::*;
+pub use dir_entry::DirEntry83;
+pub use fat_entry::FatEntry;
+pub use file::Fat16File;
+pub use short_name::ShortName;
+use table::FatTable;
+
+/// Descriptors of the open files
+#[derive(Default)]
+struct OpenFiles {
+    /// Indexed by descriptor, None once closed
+    files: Vec<Option<Arc<Mutex<Fat16File>>>>,
+    /// Pool of reusable file descriptors
+    reuse_fds: BinaryHeap<usize>,
+}
+
+/// FAT16 filesystem driver
+pub struct Fat16<'a> {
+    /// Underlying block device
+    device: RwLock<Box<dyn BlockDevice + 'a>>,
+    /// Boot sector containing filesystem parameters
+    boot_sector: BootSector,
+    /// Starting sector of first FAT
+    fat_start: u64,
+    /// Starting sector of root directory
+    root_dir_start: u64,
+    /// Starting sector of data area
+    data_start: u64,
+    /// Size of each cluster in bytes
+    cluster_size: usize,
+    /// The first FAT
+    fat: RwLock<FatTable>,
+    /// Table of open files
+    open_files: RwLock<OpenFiles>,
+    /// Directories whose entries are being changed, by first cluster, 0
+    /// being the root directory
+    busy_dirs: Mutex<BTreeSet<u16>>,
+    /// Held while moving an entry to another directory
+    rename_lock: Mutex<()>,
+}
+
+/// Keeps directories locked by `Fat16::lock_dirs` until dropped
+struct DirGuard<'f> {
+    busy_dirs: &'f Mutex<BTreeSet<u16>>,
+    clusters: Vec<u16>,
+}
+
+impl Drop for DirGuard<'_> {
+    fn drop(&mut self) {
+        let mut busy_dirs = self.busy_dirs.lock();
+        for cluster in &self.clusters {
+            busy_dirs.remove(cluster);
+        }
+    }
+}
+
+/// Returns the number of clusters in the data area
+fn data_clusters(boot_sector: &BootSector, data_start: u64) -> u32 {
+    let total_sectors = if boot_sector.total_sectors_16.get() != 0 {
+        boot_sector.total_sectors_16.get() as u64
+    } else {
+        boot_sector.total_sectors_32.get() as u64
+    };
+    let clusters =
+        total_sectors.saturating_sub(data_start) / boot_sector.sectors_per_cluster as u64;
+    // Cluster numbers from 0xFFF0 up are reserved
+    clusters.min(MAX_CLUSTER as u64 - 1) as u32
+}
+
+impl<'a> Fat16<'a> {
+    pub fn format(mut device: Box<dyn BlockDevice + 'a>) -> Result<Self, FsError> {
+        let total_blocks = device.total_blocks();
+        let block_size = device.block_size();
+
+        let sectors_per_cluster = 4; // Typically 4 for small drives
+        let reserved_sectors = 1; // Boot sector
+        let fat_count = 2;
+        let root_dir_entries = ROOT_DIR_ENTRIES as u16;
+        let root_dir_sectors = (root_dir_entries as usize * 32).div_ceil(block_size);
+
+        // Calculate sectors per FAT
+        let total_clusters = (total_blocks as usize - reserved_sectors as usize - root_dir_sectors)
+            / sectors_per_cluster as usize;
+        let sectors_per_fat = (total_clusters * 2).div_ceil(block_size);
+
+        // Create boot sector
+        let boot_sector = BootSector {
+            jump_boot: [0xEB, 0x3C, 0x90], // Standard boot jump
+            oem_name: *b"UTTAOS.0",
+            bytes_per_sector: Le16::new(block_size as u16),
+            sectors_per_cluster: sectors_per_cluster as u8,
+            reserved_sectors: Le16::new(reserved_sectors),
+            fat_count,
+            root_dir_entries: Le16::new(root_dir_entries),
+            total_sectors_16: Le16::new(if total_blocks < 65536 {
+                total_blocks as u16
+            } else {
+                0
+            }),
+            media_type: 0xF8, // Fixed disk
+            sectors_per_fat: Le16::new(sectors_per_fat as u16),
+            sectors_per_track: Le16::new(63), // Apparently the standard?
+            head_count: Le16::new(255),       // Why not?
+            hidden_sectors: Le32::new(0),
+            total_sectors_32: Le32::new(if total_blocks >= 65536 {
+                total_blocks as u32
+            } else {
+                0
+            }),
+            drive_number: 0x80, // Hard disk
+            reserved1: 0,
+            boot_signature: 0x29,
+            volume_id: Le32::new(0x12345678), // Random volume ID
+            volume_label: *b"NO NAME    ",
+            fs_type: *b"FAT16   ",
+        };
+
+        let mut block_buf = vec![0u8; block_size];
+        boot_sector.to_bytes(&mut block_buf);
+        block_buf[510] = 0x55; // Boot signature
+        block_buf[511] = 0xAA;
+        device.write_block(0, &block_buf)?;
+
+        let mut fat_block = vec![0u8; block_size];
+        // First two FAT entries are reserved
+        fat_block[0] = boot_sector.media_type;
+        fat_block[1] = 0xFF;
+        fat_block[2] = 0xFF;
+        fat_block[3] = 0xFF;
+
+        // Write first sector of each FAT
+        for i in 0..fat_count {
+            let fat_start = reserved_sectors as u64 + (i as u64 * sectors_per_fat as u64);
+            device.write_block(fat_start, &fat_block)?;
+        }
+
+        // Clear the rest of the FAT tables
+        let zero_block = vec![0u8; block_size];
+        for i in 0..fat_count {
+            let fat_start = reserved_sectors as u64 + (i as u64 * sectors_per_fat as u64);
+            for j in 1..sectors_per_fat {
+                device.write_block(fat_start + j as u64, &zero_block)?;
+            }
+        }
+
+        // Initialize empty root directory
+        let root_dir_start = reserved_sectors as u64 + (fat_count as u64 * sectors_per_fat as u64);
+        for i in 0..root_dir_sectors {
+            device.write_block(root_dir_start + i as u64, &zero_block)?;
+        }
+        Fat16::new(device)
+    }
+
+    pub fn new(device: Box<dyn BlockDevice + 'a>) -> Result<Self, FsError> {
+        let mut boot_sector_data = vec![0u8; SECTOR_SIZE];
+        device.read_block(0, &mut boot_sector_data)?;
+
+        let boot_sector = BootSector::from_bytes(&boot_sector_data);
+
+        let fat_start = boot_sector.reserved_sectors.get() as u64;
+        let sectors_per_fat = boot_sector.sectors_per_fat.get() as u64;
+        let root_dir_start = fat_start + (sectors_per_fat * boot_sector.fat_count as u64);
+        let root_dir_sectors = (ROOT_DIR_ENTRIES * 32).div_ceil(SECTOR_SIZE);
+        let data_start = root_dir_start + root_dir_sectors as u64;
+        let cluster_size = boot_sector.sectors_per_cluster as usize * SECTOR_SIZE;
+        let cluster_limit = (data_clusters(&boot_sector, data_start) + 2) as u16;
+        let fat = FatTable::load(&*device, fat_start, cluster_limit)?;
+
+        Ok(Fat16 {
+            device: RwLock::new(device),
+            boot_sector,
+            fat_start,
+            root_dir_start,
+            data_start,
+            cluster_size,
+            fat: RwLock::new(fat),
+            open_files: RwLock::new(OpenFiles::default()),
+            busy_dirs: Mutex::new(BTreeSet::new()),
+            rename_lock: Mutex::new(()),
+        })
+    }
+
+    /// Returns the number of clusters in the data area
+    fn data_clusters(&self) -> u32 {
+        data_clusters(&self.boot_sector, self.data_start)
+    }
+
+    /// Returns one past the last valid cluster number
+    fn cluster_limit(&self) -> u16 {
+        (self.data_clusters() + 2) as u16
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.device.read().read_block(sector, buf)
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.device.read().read_blocks(sector, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.device.write().write_blocks(sector, buf)
+    }
+
+    /// Reads a sector, lets `change` edit it and writes it back, with no
+    /// other write to the device in between
+    fn modify_sector(&self, sector: u64, change: impl FnOnce(&mut [u8])) -> Result<(), FsError> {
+        let mut device = self.device.write();
+        let mut sector_data = vec![0u8; SECTOR_SIZE];
+        device.read_block(sector, &mut sector_data)?;
+        change(&mut sector_data);
+        device.write_block(sector, &sector_data)
+    }
+
+    /// Changes a FAT entry and writes the sector holding it to every copy
+    /// of the FAT
+    fn set_fat_entry(
+        &self,
+        fat: &mut FatTable,
+        cluster: u16,
+        entry: FatEntry,
+    ) -> Result<(), FsError> {
+        let index = fat.set(cluster, entry);
+        let sector_data = fat.sector(index);
+        let sectors_per_fat = self.boot_sector.sectors_per_fat.get() as u64;
+        let mut device = self.device.write();
+        for copy in 0..self.boot_sector.fat_count as u64 {
+            device.write_block(
+                self.fat_start + copy * sectors_per_fat + index,
+                &sector_data,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn allocate_cluster(&self, fat: &mut FatTable) -> Result<u16, FsError> {
+        // No free clusters
+        let cluster = fat.find_free().ok_or(FsError::NotSupported)?;
+        self.set_fat_entry(fat, cluster, FatEntry { cluster: 0xFFFF })?;
+        Ok(cluster)
+    }
+
+    /// Returns the cluster after `cluster` in its chain, first adding one
+    /// if the chain ends there
+    fn next_or_extend(&self, cluster: u16) -> Result<u16, FsError> {
+        // Checked and extended under one lock, so two writers at the end of
+        // a chain cannot both extend it
+        let mut fat = self.fat.write();
+        let next = fat.get(cluster);
+        if !next.is_end_of_chain() {
+            return Ok(next.cluster);
+        }
+        let new_cluster = self.allocate_cluster(&mut fat)?;
+        self.set_fat_entry(
+            &mut fat,
+            cluster,
+            FatEntry {
+                cluster: new_cluster,
+            },
+        )?;
+        Ok(new_cluster)
+    }
+
+    /// Frees every cluster of the chain starting at `start`
+    fn free_chain(&self, start: u16) -> Result<(), FsError> {
+        let mut fat = self.fat.write();
+        let mut cluster = start;
+        loop {
+            let next = fat.get(cluster);
+            self.set_fat_entry(&mut fat, cluster, FatEntry { cluster: 0 })?;
+            if next.is_end_of_chain() {
+                return Ok(());
+            }
+            cluster = next.cluster;
+        }
+    }
+
+    /// Locks directories, given by first cluster, until the returned guard
+    /// is dropped. All of them are taken at once, so callers locking
+    /// overlapping sets cannot deadlock.
+    fn lock_dirs(&self, clusters: &[u16]) -> DirGuard<'_> {
+        loop {
+            {
+                let mut busy_dirs = self.busy_dirs.lock();
+                if clusters.iter().all(|cluster| !busy_dirs.contains(cluster)) {
+                    busy_dirs.extend(clusters);
+                    return DirGuard {
+                        busy_dirs: &self.busy_dirs,
+                        clusters: clusters.to_vec(),
+                    };
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Locks the directories holding each of `paths`
+    ///
+    /// # Returns
+    /// The first cluster of each directory, 0 for the root, and the guard
+    /// holding them
+    fn lock_parents<const N: usize>(
+        &self,
+        paths: [&str; N],
+    ) -> Result<([u16; N], DirGuard<'_>), FsError> {
+        loop {
+            let mut parents = [0; N];
+            for (parent, path) in parents.iter_mut().zip(paths) {
+                *parent = self.parent_cluster(path)?;
+            }
+            let guard = self.lock_dirs(&parents);
+            // A directory may have been removed or moved while waiting
+            let moved = parents
+                .iter()
+                .zip(paths)
+                .any(|(&parent, path)| !matches!(self.parent_cluster(path), Ok(c) if c == parent));
+            if !moved {
+                return Ok((parents, guard));
+            }
+        }
+    }
+
+    /// Adds an entry to a directory. The caller holds the directory's lock.
+    fn write_dir_entry(&self, dir_cluster: u16, entry: &DirEntry83) -> Result<(), FsError> {
+        let entries_per_sector = SECTOR_SIZE / core::mem::size_of::<DirEntry83>();
+        let mut sector_buffer = vec![0u8; SECTOR_SIZE];
+
+        let (start_sector, num_sectors) = if dir_cluster == 0 {
+            (
+                self.root_dir_start,
+                (ROOT_DIR_ENTRIES / entries_per_sector) as u64,
+            )
+        } else {
+            let start_sector = self.cluster_to_sector(dir_cluster);
+            let sectors_per_cluster = self.boot_sector.sectors_per_cluster as u64;
+            (start_sector, sectors_per_cluster)
+        };
+
+        for sector_offset in 0..num_sectors {
+            self.read_sector(start_sector + sector_offset, &mut sector_buffer)?;
+
+            for i in 0..entries_per_sector {
+                let slot = DirEntry83::read_slot(&sector_buffer, i);
+
+                if slot.is_free() || slot.is_deleted() {
+                    return self.modify_sector(start_sector + sector_offset, |sector_data| {
+                        entry.write_slot(sector_data, i)
+                    });
+                }
+            }
+        }
+
+        // Directory is full
+        // Probably should be an error, but then again
+        // Fancier filesystems just magically grow
+        Err(FsError::NotSupported)
+    }
+
+    fn init_directory(&self, cluster: u16, parent_cluster: u16) -> Result<(), FsError> {
+        let dot_entry = DirEntry83::new_directory(ShortName::DOT, cluster);
+        let dotdot_entry = DirEntry83::new_directory(ShortName::DOTDOT, parent_cluster);
+
+        let mut cluster_data = vec![0u8; self.cluster_size];
+        dot_entry.write_slot(&mut cluster_data, 0);
+        dotdot_entry.write_slot(&mut cluster_data, 1);
+
+        self.write_sectors(self.cluster_to_sector(cluster), &cluster_data)
+    }
+
+    fn cluster_to_sector(&self, cluster: u16) -> u64 {
+        self.data_start + ((cluster as u64 - 2) * self.boot_sector.sectors_per_cluster as u64)
+    }
+
+    fn find_entry(&self, path: &str) -> Result<(DirEntry83, u64), FsError> {
+        let mut current_dir_cluster = 0;
+        let components: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        if components.is_empty() {
+            return Err(FsError::InvalidName);
+        }
+
+        let mut i = 0;
+        while i < components.len() - 1 {
+            let entry = self.find_entry_in_dir(current_dir_cluster, components[i])?;
+            if !entry.0.is_directory() {
+                return Err(FsError::NotFound);
+            }
+            current_dir_cluster = entry.0.start_cluster.get() as u64;
+            i += 1;
+        }
+
+        self.find_entry_in_dir(current_dir_cluster, components[components.len() - 1])
+    }
+
+    /// Removes an entry and frees its chain. The caller holds the lock of
+    /// the directory holding it.
+    fn remove_entry(&self, path: &str, is_dir: bool) -> Result<(), FsError> {
+        let (entry, entry_pos) = self.find_entry(path)?;
+
+        if entry.is_directory() != is_dir {
+            return Err(FsError::NotSupported);
+        }
+
+        self.update_entry(entry_pos, |entry| entry.name[0] = DELETED_ENTRY_MARKER)?;
+        self.free_chain(entry.start_cluster.get())
+    }
+
+    fn is_directory_empty(&self, dir_cluster: u16) -> Result<bool, FsError> {
+        let entries_per_sector = SECTOR_SIZE / core::mem::size_of::<DirEntry83>();
+        let mut sector_buffer = vec![0u8; SECTOR_SIZE];
+
+        let sector = self.cluster_to_sector(dir_cluster);
+        self.read_sector(sector, &mut sector_buffer)?;
+
+        for i in 0..entries_per_sector {
+            let entry = DirEntry83::read_slot(&sector_buffer, i);
+
+            if entry.is_free() {
+                break;
+            }
+
+            if !entry.is_deleted() {
+                let name = entry.get_name();
+                if name != "." && name != ".." {
+                    return Ok(false); // Found a regular entry
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn find_entry_in_dir(
+        &self,
+        dir_cluster: u64,
+        name: &str,
+    ) -> Result<(DirEntry83, u64), FsError> {
+        // A name that is not valid 8.3 cannot be on disk
+        let name = ShortName::parse(name).map_err(|_| FsError::NotFound)?;
+        let entries_per_sector = SECTOR_SIZE / core::mem::size_of::<DirEntry83>();
+        let mut sector_buffer = vec![0u8; SECTOR_SIZE];
+
+        let (start_sector, num_sectors) = if dir_cluster == 0 {
+            (
+                self.root_dir_start,
+                (ROOT_DIR_ENTRIES / entries_per_sector) as u64,
+            )
+        } else {
+            let start_sector = self.cluster_to_sector(dir_cluster as u16);
+            let sectors_per_cluster = self.boot_sector.sectors_per_cluster as u64;
+            (start_sector, sectors_per_cluster)
+        };
+
+        for sector_offset in 0..num_sectors {
+            self.read_sector(start_sector + sector_offset, &mut sector_buffer)?;
+
+            for i in 0..entries_per_sector {
+                let entry = DirEntry83::read_slot(&sector_buffer, i);
+
+                if entry.is_free() {
+                    break;
+                }
+
+                if !entry.is_deleted() && ShortName::from_entry(&entry) == name {
+                    let entry_offset = i * core::mem::size_of::<DirEntry83>();
+                    let sector_offset_bytes = (start_sector + sector_offset) * SECTOR_SIZE as u64;
+                    let absolute_position = sector_offset_bytes + entry_offset as u64;
+                    return Ok((entry, absolute_position));
+                }
+            }
+        }
+
+        Err(FsError::NotFound)
+    }
+
+    /// Returns the cluster of the directory containing `path`, 0 for the
+    /// root directory
+    fn parent_cluster(&self, path: &str) -> Result<u16, FsError> {
+        let (parent_path, _) = split_path(path);
+        if parent_path.is_empty() || parent_path == "/" {
+            return Ok(0);
+        }
+        let (parent, _) = self.find_entry(parent_path)?;
+        if !parent.is_directory() {
+            return Err(FsError::NotFound);
+        }
+        Ok(parent.start_cluster.get())
+    }
+
+    /// Changes the directory entry at an absolute byte position in place
+    fn update_entry(
+        &self,
+        entry_pos: u64,
+        change: impl FnOnce(&mut DirEntry83),
+    ) -> Result<(), FsError> {
+        let offset = (entry_pos % SECTOR_SIZE as u64) as usize;
+        self.modify_sector(entry_pos / SECTOR_SIZE as u64, |sector_data| {
+            let mut entry = DirEntry83::from_bytes(&sector_data[offset..]);
+            change(&mut entry);
+            entry.to_bytes(&mut sector_data[offset..]);
+        })
+    }
+
+    /// Returns the state of an open file
+    fn file(&self, fd: usize) -> Arc<Mutex<Fat16File>> {
+        self.open_files
+            .read()
+            .files
+            .get(fd)
+            .and_then(Option::clone)
+            .expect("Invalid file descriptor.")
+    }
+
+    /// Writes at a file's position, growing the file as needed
+    fn write_at(&self, file: &mut Fat16File, buf: &[u8]) -> Result<usize, FsError> {
+        file.locate(&self.fat.read(), self.cluster_size);
+
+        let mut bytes_written = 0;
+        let mut cluster_data = vec![0u8; self.cluster_size];
+
+        while bytes_written < buf.len() {
+            let cluster_offset = (file.position % self.cluster_size as u64) as usize;
+            let bytes_left_in_cluster = self.cluster_size - cluster_offset;
+            let chunk_size = min(bytes_left_in_cluster, buf.len() - bytes_written);
+
+            let sector = self.cluster_to_sector(file.current_cluster);
+            // A whole cluster is overwritten without reading it first
+            if chunk_size < self.cluster_size {
+                self.read_sectors(sector, &mut cluster_data)?;
+            }
+
+            cluster_data[cluster_offset..cluster_offset + chunk_size]
+                .copy_from_slice(&buf[bytes_written..bytes_written + chunk_size]);
+            self.write_sectors(sector, &cluster_data)?;
+
+            bytes_written += chunk_size;
+            file.position += chunk_size as u64;
+            file.size = max(file.size, file.position);
+
+            if cluster_offset + chunk_size == self.cluster_size {
+                file.current_cluster = self.next_or_extend(file.current_cluster)?;
+                file.cluster_index += 1;
+            }
+        }
+
+        let size = file.size;
+        self.update_entry(file.entry_position, |entry| {
+            entry.file_size.set(size as u32);
+            entry.touch();
+        })?;
+        Ok(bytes_written)
+    }
+
+    pub fn create_file(&self, path: &str) -> Result<(), FsError> {
+        let (_, name) = split_path(path);
+        let name = ShortName::parse(name)?;
+
+        let ([parent_cluster], _dir) = self.lock_parents([path])?;
+        if self.find_entry(path).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let cluster = self.allocate_cluster(&mut self.fat.write())?;
+        let entry = DirEntry83::new_file(name, cluster);
+        self.write_dir_entry(parent_cluster, &entry)
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<(), FsError> {
+        let (_, name) = split_path(path);
+        let name = ShortName::parse(name)?;
+
+        let ([parent_cluster], _dir) = self.lock_parents([path])?;
+        if self.find_entry(path).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let cluster = self.allocate_cluster(&mut self.fat.write())?;
+        let entry = DirEntry83::new_directory(name, cluster);
+        self.init_directory(cluster, parent_cluster)?;
+        self.write_dir_entry(parent_cluster, &entry)
+    }
+
+    pub fn remove_file(&self, path: &str) -> Result<(), FsError> {
+        let (_, _dir) = self.lock_parents([path])?;
+        self.remove_entry(path, false)
+    }
+
+    pub fn remove_dir(&self, path: &str) -> Result<(), FsError> {
+        let (_, _parent) = self.lock_parents([path])?;
+        let (entry, _) = self.find_entry(path)?;
+
+        if !entry.is_directory() {
+            return Err(FsError::NotSupported);
+        }
+
+        // Nothing can be created in the directory while it is removed
+        let _dir = self.lock_dirs(&[entry.start_cluster.get()]);
+        if !self.is_directory_empty(entry.start_cluster.get())? {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        self.remove_entry(path, true)
+    }
+
+    pub fn open_file(&self, path: &str) -> Result<usize, FsError> {
+        let (entry, entry_pos) = self.find_entry(path)?;
+
+        if entry.is_directory() {
+            return Err(FsError::NotSupported);
+        }
+
+        let file = Arc::new(Mutex::new(Fat16File {
+            start_cluster: entry.start_cluster.get(),
+            current_cluster: entry.start_cluster.get(),
+            cluster_index: 0,
+            position: 0,
+            size: entry.file_size.get() as u64,
+            entry_position: entry_pos,
+        }));
+
+        let mut open_files = self.open_files.write();
+        let fd = if let Some(reused_fd) = open_files.reuse_fds.pop() {
+            assert!(open_files.files[reused_fd].is_none());
+            open_files.files[reused_fd] = Some(file);
+            reused_fd
+        } else {
+            open_files.files.push(Some(file));
+            open_files.files.len() - 1
+        };
+        Ok(fd)
+    }
+
+    pub fn close_file(&self, fd: usize) {
+        let mut open_files = self.open_files.write();
+        let file = open_files
+            .files
+            .get_mut(fd)
+            .expect("Invalid file descriptor.");
+        assert!(
+            file.take().is_some(),
+            "Cannot close an invailid file descriptor."
+        );
+        open_files.reuse_fds.push(fd);
+    }
+
+    pub fn write_file(&self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let file = self.file(fd);
+        let mut file = file.lock();
+        if file.position > file.size {
+            // Fill the gap left by seeking past the end with zeros
+            let end = file.position;
+            file.position = file.size;
+            let zeros = vec![0u8; self.cluster_size];
+            while file.position < end {
+                let len = min(end - file.position, self.cluster_size as u64) as usize;
+                self.write_at(&mut file, &zeros[..len])?;
+            }
+        }
+        self.write_at(&mut file, buf)
+    }
+
+    pub fn seek_file(&self, fd: usize, pos: SeekFrom) -> Result<u64, FsError> {
+        self.file(fd).lock().seek(pos)
+    }
+
+    pub fn read_file(&self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let file = self.file(fd);
+        let mut file = file.lock();
+
+        if file.position >= file.size {
+            return Ok(0);
+        }
+        file.locate(&self.fat.read(), self.cluster_size);
+
+        let mut bytes_read = 0;
+        let bytes_to_read = min(buf.len(), (file.size - file.position) as usize);
+        let mut cluster_data = vec![0u8; self.cluster_size];
+
+        while bytes_read < bytes_to_read {
+            let cluster_offset = (file.position % self.cluster_size as u64) as usize;
+            let bytes_left_in_cluster = self.cluster_size - cluster_offset;
+            let chunk_size = min(bytes_left_in_cluster, bytes_to_read - bytes_read);
+
+            let sector = self.cluster_to_sector(file.current_cluster);
+            self.read_sectors(sector, &mut cluster_data)?;
+
+            buf[bytes_read..bytes_read + chunk_size]
+                .copy_from_slice(&cluster_data[cluster_offset..cluster_offset + chunk_size]);
+
+            bytes_read += chunk_size;
+            file.position += chunk_size as u64;
+
+            if cluster_offset + chunk_size == self.cluster_size {
+                let next_cluster = self.fat.read().get(file.current_cluster);
+                if next_cluster.is_end_of_chain() {
+                    break;
+                }
+                file.current_cluster = next_cluster.cluster;
+                file.cluster_index += 1;
+            }
+        }
+
+        Ok(bytes_read)
+    }
+
+    /// Reads whole clusters from a cluster aligned position straight into
+    /// `buf`, a device transfer per run of adjacent clusters. Anything else
+    /// is read with `read_file`.
+    pub fn read_file_direct(&self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let file = self.file(fd);
+        let mut file = file.lock();
+        let cluster_size = self.cluster_size as u64;
+        let left = file.size.saturating_sub(file.position);
+        let clusters = min(buf.len() as u64, left) / cluster_size;
+        if !file.position.is_multiple_of(cluster_size) || clusters == 0 {
+            drop(file);
+            return self.read_file(fd, buf);
+        }
+        file.locate(&self.fat.read(), self.cluster_size);
+
+        let mut done = 0;
+        while done < clusters {
+            let first = file.current_cluster;
+            let (mut last, mut run) = (first, 1);
+            let mut next = self.fat.read().get(last);
+            while done + run < clusters && next.cluster == last + 1 {
+                last = next.cluster;
+                run += 1;
+                next = self.fat.read().get(last);
+            }
+
+            let range = (done * cluster_size) as usize..((done + run) * cluster_size) as usize;
+            self.device
+                .read()
+                .read_blocks_direct(self.cluster_to_sector(first), &mut buf[range])?;
+            done += run;
+            file.position += run * cluster_size;
+
+            if next.is_end_of_chain() {
+                file.current_cluster = last;
+                file.cluster_index += run - 1;
+                break;
+            }
+            file.current_cluster = next.cluster;
+            file.cluster_index += run;
+        }
+        Ok((done * cluster_size) as usize)
+    }
+
+    /// Writes whole clusters at a cluster aligned position straight from
+    /// `buf`, like `read_file_direct`
+    pub fn write_file_direct(&self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let file = self.file(fd);
+        let mut file = file.lock();
+        let cluster_size = self.cluster_size as u64;
+        let clusters = buf.len() as u64 / cluster_size;
+        // Gaps left by seeking past the end are zeroed by `write_file`
+        if !file.position.is_multiple_of(cluster_size) || file.position > file.size || clusters == 0
+        {
+            drop(file);
+            return self.write_file(fd, buf);
+        }
+        file.locate(&self.fat.read(), self.cluster_size);
+
+        let mut done = 0;
+        while done < clusters {
+            // Clusters are allocated lowest first, so a growing file mostly
+            // gets adjacent ones
+            let first = file.current_cluster;
+            let (mut last, mut run) = (first, 1);
+            let mut next = self.next_or_extend(last)?;
+            while done + run < clusters && next == last + 1 {
+                last = next;
+                run += 1;
+                next = self.next_or_extend(last)?;
+            }
+
+            let range = (done * cluster_size) as usize..((done + run) * cluster_size) as usize;
+            self.device
+                .write()
+                .write_blocks_direct(self.cluster_to_sector(first), &buf[range])?;
+            done += run;
+            file.position += run * cluster_size;
+            file.size = max(file.size, file.position);
+            file.current_cluster = next;
+            file.cluster_index += run;
+        }
+
+        let size = file.size;
+        self.update_entry(file.entry_position, |entry| {
+            entry.file_size.set(size as u32);
+            entry.touch();
+        })?;
+        Ok((done * cluster_size) as usize)
+    }
+
+    pub fn sync(&self) -> Result<(), FsError> {
+        self.device.write().flush()
+    }
+
+    pub fn set_permissions(&self, path: &str, permissions: FilePermissions) -> Result<(), FsError> {
+        // FAT only has a read-only attribute
+        let (_, entry_pos) = self.find_entry(path)?;
+        self.update_entry(entry_pos, |entry| {
+            if permissions.writable {
+                entry.attributes &= !ATTR_READ_ONLY;
+            } else {
+                entry.attributes |= ATTR_READ_ONLY;
+            }
+        })
+    }
+
+    pub fn set_times(&self, path: &str, times: FileTimes) -> Result<(), FsError> {
+        let set = |entry: &mut DirEntry83| {
+            if let Some(accessed) = times.accessed {
+                entry.set_accessed(accessed as i64);
+            }
+            if let Some(modified) = times.modified {
+                entry.set_modified(modified as i64);
+            }
+        };
+        let (mut entry, entry_pos) = self.find_entry(path)?;
+        let before = (entry.access_date.get(), entry.date.get(), entry.time.get());
+        set(&mut entry);
+        // Access dates have day resolution, so most access time updates
+        // change nothing and need no write
+        if (entry.access_date.get(), entry.date.get(), entry.time.get()) == before {
+            return Ok(());
+        }
+
+        self.update_entry(entry_pos, set)
+    }
+
+    pub fn statfs(&self) -> Result<StatFs, FsError> {
+        Ok(StatFs {
+            block_size: self.cluster_size as u64,
+            total_blocks: self.data_clusters() as u64,
+            free_blocks: self.fat.read().free() as u64,
+            // FAT has no inodes
+            total_inodes: 0,
+            free_inodes: 0,
+        })
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), FsError> {
+        let (_, new_name) = split_path(to);
+        let new_name = ShortName::parse(new_name)?;
+
+        let moving = self.parent_cluster(from)? != self.parent_cluster(to)?;
+        let _rename = moving.then(|| self.rename_lock.lock());
+        let ([src_parent, dest_parent], _dirs) = self.lock_parents([from, to])?;
+
+        let (src_entry, src_pos) = self.find_entry(from)?;
+        match self.find_entry(to) {
+            // Names differing only in case are the same entry
+            Ok((_, pos)) if pos == src_pos => return Ok(()),
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(_) => {}
+        }
+
+        // Within one directory a single sector write renames atomically
+        if src_parent == dest_parent {
+            return self.update_entry(src_pos, |entry| {
+                entry.name = new_name.name;
+                entry.ext = new_name.ext;
+            });
+        }
+        // A concurrent move changed where the paths lead
+        if !moving {
+            return Err(FsError::Busy);
+        }
+
+        if src_entry.is_directory() {
+            // A directory cannot be moved below itself
+            let mut ancestor = dest_parent;
+            while ancestor != 0 {
+                if ancestor == src_entry.start_cluster.get() {
+                    return Err(FsError::InvalidName);
+                }
+                ancestor = self
+                    .find_entry_in_dir(ancestor as u64, "..")?
+                    .0
+                    .start_cluster
+                    .get();
+            }
+        }
+
+        let mut new_entry = src_entry;
+        new_entry.name = new_name.name;
+        new_entry.ext = new_name.ext;
+
+        // Add the new link before removing the old one. A crash in between
+        // leaves the file reachable under both names rather than neither.
+        self.write_dir_entry(dest_parent, &new_entry)?;
+
+        if src_entry.is_directory() {
+            let (_, dotdot_pos) =
+                self.find_entry_in_dir(src_entry.start_cluster.get() as u64, "..")?;
+            self.update_entry(dotdot_pos, |dotdot| dotdot.start_cluster.set(dest_parent))?;
+        }
+
+        self.update_entry(src_pos, |entry| entry.name[0] = DELETED_ENTRY_MARKER)
+    }
+}
+
+/// Splits a path into its parent directory and final component
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => ("", path),
+    }
+}
+
+impl FileSystem for Fat16<'_> {
+    fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        Fat16::create_file(self, path)
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        Fat16::create_dir(self, path)
+    }
+
+    fn remove_file(&mut self, path: &str) -> Result<(), FsError> {
+        Fat16::remove_file(self, path)
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        Fat16::remove_dir(self, path)
+    }
+
+    fn open_file(&mut self, path: &str) -> Result<usize, FsError> {
+        Fat16::open_file(self, path)
+    }
+
+    fn close_file(&mut self, fd: usize) {
+        Fat16::close_file(self, fd)
+    }
+
+    fn write_file(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        Fat16::write_file(self, fd, buf)
+    }
+
+    fn seek_file(&mut self, fd: usize, pos: SeekFrom) -> Result<u64, FsError> {
+        Fat16::seek_file(self, fd, pos)
+    }
+
+    fn read_file(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        Fat16::read_file(self, fd, buf)
+    }
+
+    fn read_file_direct(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        Fat16::read_file_direct(self, fd, buf)
+    }
+
+    fn write_file_direct(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        Fat16::write_file_direct(self, fd, buf)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let (entry, _) = if path.is_empty() || path == "/" {
+            // Root directory - return entry with dummy position
+            (
+                DirEntry83 {
+                    name: *b"        ",
+                    ext: *b"   ",
+                    attributes: ATTR_DIRECTORY,
+                    ..DirEntry83::zeroed()
+                },
+                0,
+            )
+        } else {
+            self.find_entry(path)?
+        };
+
+        if !entry.is_directory() {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut result = Vec::new();
+        let entries_per_sector = SECTOR_SIZE / core::mem::size_of::<DirEntry83>();
+        let mut sector_buffer = vec![0u8; SECTOR_SIZE];
+
+        let (start_sector, num_sectors) = if entry.start_cluster.get() == 0 {
+            (
+                self.root_dir_start,
+                (ROOT_DIR_ENTRIES / entries_per_sector) as u64,
+            )
+        } else {
+            let start_sector = self.cluster_to_sector(entry.start_cluster.get());
+            let sectors_per_cluster = self.boot_sector.sectors_per_cluster as u64;
+            (start_sector, sectors_per_cluster)
+        };
+
+        for sector_offset in 0..num_sectors {
+            self.read_sector(start_sector + sector_offset, &mut sector_buffer)?;
+
+            for i in 0..entries_per_sector {
+                let fat_entry = DirEntry83::read_slot(&sector_buffer, i);
+
+                if fat_entry.is_free() {
+                    break;
+                }
+
+                if !fat_entry.is_deleted() && fat_entry.name[0] != 0x2E {
+                    result.push(DirEntry {
+                        name: fat_entry.get_name(),
+                        metadata: FileMetadata {
+                            size: fat_entry.file_size.get() as u64,
+                            is_dir: fat_entry.is_directory(),
+                            created: 0, // FAT16 doesn't store creation time
+                            modified: entry.modified().max(0) as u64,
+                            accessed: entry.accessed().max(0) as u64,
+                            permissions: FilePermissions {
+                                readable: true,
+                                writable: fat_entry.attributes & ATTR_READ_ONLY == 0,
+                                executable: false,
+                            },
+                            mode: None,
+                            uid: 0,
+                            gid: 0,
+                        },
+                    });
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn metadata(&self, path: &str) -> Result<FileMetadata, FsError> {
+        let (entry, _) = self.find_entry(path)?;
+
+        Ok(FileMetadata {
+            size: entry.file_size.get() as u64,
+            is_dir: entry.is_directory(),
+            created: 0, // FAT16 doesn't store creation time
+            modified: entry.modified().max(0) as u64,
+            accessed: entry.accessed().max(0) as u64,
+            permissions: FilePermissions {
+                readable: true,
+                writable: entry.attributes & ATTR_READ_ONLY == 0,
+                executable: false,
+            },
+            mode: None,
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        Fat16::sync(self)
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "fat16"
+    }
+
+    fn set_permissions(&mut self, path: &str, permissions: FilePermissions) -> Result<(), FsError> {
+        Fat16::set_permissions(self, path, permissions)
+    }
+
+    fn set_times(&mut self, path: &str, times: FileTimes) -> Result<(), FsError> {
+        Fat16::set_times(self, path, times)
+    }
+
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        Fat16::statfs(self)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        Fat16::rename(self, from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::MemoryBlockDevice;
+
+    #[test]
+    fn test_seek_past_end() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        fs.create_file("/sparse").unwrap();
+        let fd = fs.open_file("/sparse").unwrap();
+        fs.write_file(fd, b"head").unwrap();
+
+        // Leave a gap spanning more than one cluster
+        let offset = fs.cluster_size as u64 * 2 + 10;
+        assert_eq!(fs.seek_file(fd, SeekFrom::Start(offset)).unwrap(), offset);
+        let mut buf = [0xFFu8; 4];
+        assert_eq!(fs.read_file(fd, &mut buf).unwrap(), 0);
+        fs.write_file(fd, b"tail").unwrap();
+        assert_eq!(fs.metadata("/sparse").unwrap().size, offset + 4);
+
+        fs.seek_file(fd, SeekFrom::Start(2)).unwrap();
+        let mut contents = vec![0xFFu8; offset as usize + 2];
+        let mut read = 0;
+        while read < contents.len() {
+            read += fs.read_file(fd, &mut contents[read..]).unwrap();
+        }
+        assert_eq!(&contents[..2], b"ad");
+        assert!(contents[2..offset as usize - 2].iter().all(|&b| b == 0));
+        assert_eq!(&contents[offset as usize - 2..], b"tail");
+        fs.close_file(fd);
+    }
+
+    #[test]
+    fn test_case_insensitive_lookup() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        fs.create_dir("/docs").unwrap();
+        fs.create_file("/docs/readme.txt").unwrap();
+
+        assert!(fs.metadata("/DOCS/README.TXT").is_ok());
+        assert!(matches!(
+            fs.create_file("/docs/ReadMe.Txt"),
+            Err(FsError::AlreadyExists)
+        ));
+        assert!(matches!(
+            fs.create_file("/docs/bad:name"),
+            Err(FsError::InvalidName)
+        ));
+        fs.rename("/docs/readme.txt", "/docs/README.TXT").unwrap();
+        assert_eq!(fs.read_dir("/docs").unwrap()[0].name, "README.TXT");
+    }
+
+    #[test]
+    fn test_rename_directory() {
+        let device = Box::new(MemoryBlockDevice::new(256, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        fs.create_dir("/a").unwrap();
+        fs.create_dir("/a/sub").unwrap();
+        fs.create_file("/a/sub/file").unwrap();
+        fs.create_dir("/b").unwrap();
+
+        assert!(matches!(
+            fs.rename("/a", "/a/sub/a"),
+            Err(FsError::InvalidName)
+        ));
+        fs.rename("/a/sub", "/b/moved").unwrap();
+        assert!(fs.read_dir("/a").unwrap().is_empty());
+        assert!(fs.metadata("/b/moved/file").is_ok());
+
+        // The moved directory's parent link follows it
+        let b = fs.find_entry("/b").unwrap().0.start_cluster.get();
+        let parent = fs.find_entry("/b/moved/..").unwrap().0.start_cluster.get();
+        assert_eq!(parent, b);
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let device = Box::new(MemoryBlockDevice::new(512, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        let cluster_size = fs.cluster_size;
+        fs.create_file("/image").unwrap();
+        let fd = fs.open_file("/image").unwrap();
+        let data: Vec<u8> = (0..cluster_size * 5 + 100).map(|i| i as u8).collect();
+
+        // The trailing partial cluster is left to the next call
+        assert_eq!(fs.write_file_direct(fd, &data).unwrap(), cluster_size * 5);
+        assert_eq!(
+            fs.write_file_direct(fd, &data[cluster_size * 5..]).unwrap(),
+            100
+        );
+        assert_eq!(fs.metadata("/image").unwrap().size, data.len() as u64);
+
+        fs.seek_file(fd, SeekFrom::Start(0)).unwrap();
+        let mut contents = vec![0u8; data.len()];
+        assert_eq!(
+            fs.read_file_direct(fd, &mut contents).unwrap(),
+            cluster_size * 5
+        );
+        assert_eq!(
+            fs.read_file_direct(fd, &mut contents[cluster_size * 5..])
+                .unwrap(),
+            100
+        );
+        assert_eq!(contents, data);
+
+        // Unaligned positions read as usual
+        fs.seek_file(fd, SeekFrom::Start(10)).unwrap();
+        let mut tail = vec![0u8; cluster_size * 2];
+        assert_eq!(fs.read_file_direct(fd, &mut tail).unwrap(), tail.len());
+        assert_eq!(tail, data[10..10 + tail.len()]);
+        fs.close_file(fd);
+
+        let report = fs.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn test_concurrent_operations() {
+        let device = Box::new(MemoryBlockDevice::new(2048, 512));
+        let fs = Fat16::format(device).expect("Failed to format filesystem");
+        fs.create_file("/shared").unwrap();
+        let fd = fs.open_file("/shared").unwrap();
+        let shared: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        fs.write_file(fd, &shared).unwrap();
+        fs.close_file(fd);
+        fs.create_dir("/a").unwrap();
+        fs.create_dir("/b").unwrap();
+
+        std::thread::scope(|scope| {
+            // Readers of one file, each through its own descriptor
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let fd = fs.open_file("/shared").unwrap();
+                    for _ in 0..20 {
+                        fs.seek_file(fd, SeekFrom::Start(0)).unwrap();
+                        let mut contents = vec![0u8; shared.len()];
+                        let mut read = 0;
+                        while read < contents.len() {
+                            read += fs.read_file(fd, &mut contents[read..]).unwrap();
+                        }
+                        assert_eq!(contents, shared);
+                    }
+                    fs.close_file(fd);
+                });
+            }
+            // Writers growing files and changing entries in their own
+            // directories, all sharing the FAT
+            for dir in ["/a", "/b"] {
+                let fs = &fs;
+                scope.spawn(move || {
+                    for i in 0..10 {
+                        let path = format!("{dir}/file{i}");
+                        fs.create_file(&path).unwrap();
+                        let fd = fs.open_file(&path).unwrap();
+                        for _ in 0..3 {
+                            fs.write_file(fd, &[i as u8; 1000]).unwrap();
+                        }
+                        fs.close_file(fd);
+                        if i % 2 == 1 {
+                            fs.remove_file(&path).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        for dir in ["/a", "/b"] {
+            let entries = fs.read_dir(dir).unwrap();
+            assert_eq!(entries.len(), 5);
+            assert!(entries.iter().all(|entry| entry.metadata.size == 3000));
+        }
+        let report = fs.check().unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        let free = fs.statfs().unwrap().free_blocks;
+        assert_eq!(free as u32, fs.data_clusters() - report.used_clusters);
+    }
+}
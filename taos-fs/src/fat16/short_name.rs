@@ -0,0 +1,153 @@
+//! Canonical 8.3 names.
+//!
+//! FAT compares names case-insensitively by storing them uppercased, so
+//! every name coming from a path is canonicalized here before it is stored
+//! or compared against a directory entry.
+
+use super::{constants::*, DirEntry83, FsError};
+use alloc::{format, string::String};
+
+/// Punctuation allowed in 8.3 names besides letters and digits
+const SPECIAL_CHARACTERS: &[u8] = b"!#$%&'()-@^_`{}~";
+
+/// An 8.3 name as stored in a directory entry: uppercase and space padded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortName {
+    pub name: [u8; MAX_FILENAME_LENGTH],
+    pub ext: [u8; MAX_EXTENSION_LENGTH],
+}
+
+impl ShortName {
+    /// The entry of a directory referring to itself
+    pub const DOT: ShortName = ShortName {
+        name: *b".       ",
+        ext: *b"   ",
+    };
+
+    /// The entry of a directory referring to its parent
+    pub const DOTDOT: ShortName = ShortName {
+        name: *b"..      ",
+        ext: *b"   ",
+    };
+
+    /// Canonicalizes a name, uppercasing it
+    ///
+    /// # Returns
+    /// `FsError::InvalidName` if the name does not fit 8.3 or contains a
+    /// character FAT does not allow
+    pub fn parse(name: &str) -> Result<Self, FsError> {
+        match name {
+            "." => return Ok(Self::DOT),
+            ".." => return Ok(Self::DOTDOT),
+            _ => {}
+        }
+
+        let (base, ext) = match name.split_once('.') {
+            Some((base, ext)) => (base, ext),
+            None => (name, ""),
+        };
+        if base.is_empty()
+            || base.len() > MAX_FILENAME_LENGTH
+            || ext.len() > MAX_EXTENSION_LENGTH
+            || ext.contains('.')
+        {
+            return Err(FsError::InvalidName);
+        }
+
+        let mut short_name = ShortName {
+            name: [b' '; MAX_FILENAME_LENGTH],
+            ext: [b' '; MAX_EXTENSION_LENGTH],
+        };
+        canonicalize_into(base, &mut short_name.name)?;
+        canonicalize_into(ext, &mut short_name.ext)?;
+        Ok(short_name)
+    }
+
+    /// Generates the short name `BASE~N.EXT` for a name that does not fit
+    /// 8.3, dropping invalid characters. Long file name entries will store
+    /// the full name next to it; callers try increasing `n` until the short
+    /// name is unused.
+    ///
+    /// # Arguments
+    /// * `long_name` - The name as given by the user
+    /// * `n` - Numeric tail, starting from 1
+    pub fn numbered(long_name: &str, n: u32) -> Result<Self, FsError> {
+        let (base, ext) = match long_name.rsplit_once('.') {
+            Some((base, ext)) if !base.is_empty() => (base, ext),
+            _ => (long_name, ""),
+        };
+        let tail = format!("~{}", n);
+        if n == 0 || tail.len() >= MAX_FILENAME_LENGTH {
+            return Err(FsError::InvalidName);
+        }
+
+        let base: String = valid_characters(base)
+            .take(MAX_FILENAME_LENGTH - tail.len())
+            .collect();
+        let ext: String = valid_characters(ext).take(MAX_EXTENSION_LENGTH).collect();
+        let base = if base.is_empty() {
+            String::from("_")
+        } else {
+            base
+        };
+        if ext.is_empty() {
+            Self::parse(&format!("{}{}", base, tail))
+        } else {
+            Self::parse(&format!("{}{}.{}", base, tail, ext))
+        }
+    }
+
+    /// Returns the name stored in a directory entry
+    pub fn from_entry(entry: &DirEntry83) -> Self {
+        ShortName {
+            name: entry.name,
+            ext: entry.ext,
+        }
+    }
+}
+
+/// Returns true if FAT allows `byte` in a short name once uppercased
+fn is_valid_character(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || SPECIAL_CHARACTERS.contains(&byte)
+}
+
+/// Uppercases the valid characters of a name, skipping the rest
+fn valid_characters(name: &str) -> impl Iterator<Item = char> + '_ {
+    name.bytes()
+        .filter(|&byte| is_valid_character(byte))
+        .map(|byte| byte.to_ascii_uppercase() as char)
+}
+
+/// Copies an uppercased component into a space padded field
+fn canonicalize_into(component: &str, field: &mut [u8]) -> Result<(), FsError> {
+    for (slot, byte) in field.iter_mut().zip(component.bytes()) {
+        if !is_valid_character(byte) {
+            return Err(FsError::InvalidName);
+        }
+        *slot = byte.to_ascii_uppercase();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_names() {
+        let upper = ShortName::parse("README.TXT").unwrap();
+        assert_eq!(ShortName::parse("readme.txt").unwrap(), upper);
+        assert_eq!(&upper.name, b"README  ");
+        assert_eq!(&upper.ext, b"TXT");
+
+        assert!(ShortName::parse("toolongname.txt").is_err());
+        assert!(ShortName::parse("a.b.c").is_err());
+        assert!(ShortName::parse("bad*name").is_err());
+        assert!(ShortName::parse(".hidden").is_err());
+        assert_eq!(ShortName::parse("..").unwrap(), ShortName::DOTDOT);
+
+        let numbered = ShortName::numbered("Long File Name.text", 1).unwrap();
+        assert_eq!(&numbered.name, b"LONGFI~1");
+        assert_eq!(&numbered.ext, b"TEX");
+    }
+}
@@ -0,0 +1,87 @@
+//! In-memory copy of the file allocation table
+
+use super::{constants::*, fat_entry::FatEntry, *};
+
+/// The first FAT, kept in memory so following a chain never waits on the
+/// device. Changes go through `Fat16::set_fat_entry`, which writes the
+/// sector holding the entry to every FAT copy.
+pub struct FatTable {
+    /// Every entry of the sectors that cover the data area, including the
+    /// two reserved ones and whatever pads the last sector
+    entries: Vec<u16>,
+    /// One past the last valid cluster number
+    limit: u16,
+    /// Number of free clusters
+    free: u32,
+}
+
+impl FatTable {
+    /// Reads the first FAT up to the cluster before `limit`
+    pub fn load(device: &dyn BlockDevice, fat_start: u64, limit: u16) -> Result<Self, FsError> {
+        let sectors = (limit as usize * FAT_ENTRY_SIZE).div_ceil(SECTOR_SIZE);
+        let mut entries = Vec::with_capacity(sectors * SECTOR_SIZE / FAT_ENTRY_SIZE);
+        let mut sector_data = vec![0u8; SECTOR_SIZE];
+        for sector in 0..sectors {
+            device.read_block(fat_start + sector as u64, &mut sector_data)?;
+            entries.extend(
+                sector_data
+                    .as_chunks::<FAT_ENTRY_SIZE>()
+                    .0
+                    .iter()
+                    .map(|&entry| u16::from_le_bytes(entry)),
+            );
+        }
+        let free = entries[2..limit as usize]
+            .iter()
+            .filter(|&&entry| entry == 0)
+            .count() as u32;
+        Ok(FatTable {
+            entries,
+            limit,
+            free,
+        })
+    }
+
+    pub fn get(&self, cluster: u16) -> FatEntry {
+        FatEntry {
+            cluster: self.entries[cluster as usize],
+        }
+    }
+
+    /// Changes an entry in memory only
+    ///
+    /// # Returns
+    /// The index of the FAT sector holding the entry, within the FAT
+    pub fn set(&mut self, cluster: u16, entry: FatEntry) -> u64 {
+        let old = &mut self.entries[cluster as usize];
+        if (*old == 0) != entry.is_free() {
+            if entry.is_free() {
+                self.free += 1;
+            } else {
+                self.free -= 1;
+            }
+        }
+        *old = entry.cluster;
+        (cluster as usize * FAT_ENTRY_SIZE / SECTOR_SIZE) as u64
+    }
+
+    /// Returns a FAT sector as it goes on disk
+    pub fn sector(&self, index: u64) -> Vec<u8> {
+        let per_sector = SECTOR_SIZE / FAT_ENTRY_SIZE;
+        let first = index as usize * per_sector;
+        let mut sector_data = vec![0u8; SECTOR_SIZE];
+        for (i, &entry) in self.entries[first..first + per_sector].iter().enumerate() {
+            put_u16_le_at(&mut sector_data, i * FAT_ENTRY_SIZE, entry);
+        }
+        sector_data
+    }
+
+    /// Returns the lowest free cluster
+    pub fn find_free(&self) -> Option<u16> {
+        (2..self.limit).find(|&cluster| self.entries[cluster as usize] == 0)
+    }
+
+    pub fn free(&self) -> u32 {
+        self.free
+    }
+}
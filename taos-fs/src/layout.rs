@@ -0,0 +1,252 @@
+//! Layout checks and byte conversions for on-disk structures.
+//!
+//! Filesystem structures are declared as Rust structs matching the on-disk
+//! format and copied in and out of sector buffers. That is only sound if
+//! the struct really has the on-disk layout, has no padding, and every bit
+//! pattern is a valid value. `on_disk!` checks all three at compile time
+//! from a list of every field with its offset and type, taken from the
+//! format's specification, and only then implements `OnDisk`, which does
+//! the copying. A field that is reordered, resized, or made something
+//! other than an integer or byte array fails the build instead of
+//! corrupting disks.
+//!
+//! Multi-byte fields of little endian formats are declared as `Le16`,
+//! `Le32` or `Le64`, which hold the bytes as they are on disk and convert
+//! on access. Such structures have an alignment of 1, so their fields can
+//! be borrowed, and they read correctly whatever the host's byte order.
+//! Formats parsed without a struct use `u16_le_at` and its siblings.
+
+use core::mem::size_of;
+
+/// A type that can be copied to and from raw bytes
+///
+/// # Safety
+/// Every bit pattern of `size_of::<Self>()` bytes must be a valid value,
+/// and the type must have no padding. Implement it for structs with
+/// `on_disk!`, which checks this.
+pub unsafe trait OnDisk: Copy {
+    /// Size of the structure on disk
+    const SIZE: usize = size_of::<Self>();
+
+    /// Reads a value from the start of `bytes`, which needs no particular
+    /// alignment
+    fn from_bytes(bytes: &[u8]) -> Self {
+        assert!(bytes.len() >= Self::SIZE, "Too few bytes for the structure");
+        unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Self) }
+    }
+
+    /// Writes the value to the start of `bytes`
+    fn to_bytes(&self, bytes: &mut [u8]) {
+        let raw =
+            unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, Self::SIZE) };
+        bytes[..Self::SIZE].copy_from_slice(raw);
+    }
+
+    /// Reads the `index`th of an array of values packed into `bytes`
+    fn read_slot(bytes: &[u8], index: usize) -> Self {
+        Self::from_bytes(&bytes[index * Self::SIZE..])
+    }
+
+    /// Writes the value as the `index`th of an array packed into `bytes`
+    fn write_slot(&self, bytes: &mut [u8], index: usize) {
+        self.to_bytes(&mut bytes[index * Self::SIZE..]);
+    }
+
+    /// Returns a value with every byte zero
+    fn zeroed() -> Self {
+        unsafe { core::mem::zeroed() }
+    }
+}
+
+macro_rules! plain {
+    ($($ty:ty),*) => {
+        $(unsafe impl OnDisk for $ty {})*
+    };
+}
+
+plain!(u8, u16, u32, u64, i8, i16, i32, i64, Le16, Le32, Le64);
+
+unsafe impl<T: OnDisk, const N: usize> OnDisk for [T; N] {}
+
+macro_rules! little_endian {
+    ($($(#[$doc:meta])* $name:ident($int:ty, $len:literal);)*) => {
+        $(
+            $(#[$doc])*
+            #[repr(transparent)]
+            #[derive(Clone, Copy, Default, PartialEq, Eq)]
+            pub struct $name([u8; $len]);
+
+            impl $name {
+                pub const fn new(value: $int) -> Self {
+                    $name(value.to_le_bytes())
+                }
+
+                pub const fn get(self) -> $int {
+                    <$int>::from_le_bytes(self.0)
+                }
+
+                pub fn set(&mut self, value: $int) {
+                    self.0 = value.to_le_bytes();
+                }
+            }
+
+            impl From<$int> for $name {
+                fn from(value: $int) -> Self {
+                    $name::new(value)
+                }
+            }
+
+            impl From<$name> for $int {
+                fn from(value: $name) -> Self {
+                    value.get()
+                }
+            }
+
+            impl core::fmt::Debug for $name {
+                fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                    self.get().fmt(f)
+                }
+            }
+        )*
+    };
+}
+
+little_endian! {
+    /// A little endian `u16` as stored on disk
+    Le16(u16, 2);
+    /// A little endian `u32` as stored on disk
+    Le32(u32, 4);
+    /// A little endian `u64` as stored on disk
+    Le64(u64, 8);
+}
+
+/// Reads the little endian `u16` at `offset` of `bytes`
+pub fn u16_le_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+/// Reads the little endian `u32` at `offset` of `bytes`
+pub fn u32_le_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads the little endian `u64` at `offset` of `bytes`
+pub fn u64_le_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Writes `value` little endian at `offset` of `bytes`
+pub fn put_u16_le_at(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Writes `value` little endian at `offset` of `bytes`
+pub fn put_u32_le_at(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Writes `value` little endian at `offset` of `bytes`
+pub fn put_u64_le_at(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Checks a struct against its on-disk layout and implements `OnDisk`
+/// for it. Every field must be listed, in order, with its byte offset and
+/// type:
+///
+/// ```ignore
+/// on_disk!(Header, 8 bytes {
+///     0 => inode: u32,
+///     4 => rec_len: u16,
+///     6 => name_len: u8,
+///     7 => file_type: u8,
+/// });
+/// ```
+#[macro_export]
+macro_rules! on_disk {
+    ($ty:ty, $size:literal bytes { $($offset:literal => $field:ident: $field_ty:ty),* $(,)? }) => {
+        const _: () = {
+            assert!(core::mem::size_of::<$ty>() == $size, "Wrong on-disk size");
+            $(assert!(
+                core::mem::offset_of!($ty, $field) == $offset,
+                concat!("Wrong on-disk offset of ", stringify!($field)),
+            );)*
+            // Together with the offsets, fields covering every byte means
+            // there is no padding and no field was left out
+            assert!(
+                0 $(+ core::mem::size_of::<$field_ty>())* == $size,
+                "Fields do not cover the whole structure",
+            );
+        };
+
+        // Fields are only integers, byte arrays and other checked structures
+        const _: fn() = || {
+            fn on_disk<T: $crate::layout::OnDisk>() {}
+            $(
+                on_disk::<$field_ty>();
+                let _: fn(&$ty) -> $field_ty = |value| value.$field;
+            )*
+        };
+
+        unsafe impl $crate::layout::OnDisk for $ty {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, packed)]
+    #[derive(Clone, Copy)]
+    struct Record {
+        tag: u8,
+        value: u32,
+        name: [u8; 3],
+    }
+
+    on_disk!(Record, 8 bytes {
+        0 => tag: u8,
+        1 => value: u32,
+        5 => name: [u8; 3],
+    });
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = [
+            0xAA, 1, 2, 3, 4, b'a', b'b', b'c', 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+        ];
+        // Misaligned on purpose
+        let record = Record::from_bytes(&bytes[1..]);
+        assert_eq!({ record.tag }, 1);
+        assert_eq!({ record.value }, u32::from_le_bytes([2, 3, 4, b'a']));
+        assert_eq!(Record::read_slot(&bytes[1..], 1).tag, 8);
+
+        let mut out = [0; 17];
+        record.write_slot(&mut out, 1);
+        assert_eq!(out[8..16], bytes[1..9]);
+        assert_eq!(out[..8], [0; 8]);
+        assert_eq!({ Record::zeroed().value }, 0);
+    }
+
+    #[test]
+    fn test_little_endian() {
+        let mut bytes = [0; 16];
+        put_u16_le_at(&mut bytes, 1, 0x0102);
+        put_u32_le_at(&mut bytes, 3, 0x0304_0506);
+        put_u64_le_at(&mut bytes, 7, 0x0708_090A_0B0C_0D0E);
+        assert_eq!(bytes[1..3], [0x02, 0x01]);
+        assert_eq!(bytes[3..7], [0x06, 0x05, 0x04, 0x03]);
+        assert_eq!(u16_le_at(&bytes, 1), 0x0102);
+        assert_eq!(u32_le_at(&bytes, 3), 0x0304_0506);
+        assert_eq!(u64_le_at(&bytes, 7), 0x0708_090A_0B0C_0D0E);
+
+        // The wrappers keep the on-disk byte order and need no alignment
+        assert_eq!(Le32::read_slot(&bytes[3..], 0).get(), 0x0304_0506);
+        let mut value = Le16::new(0xBEEF);
+        Le16::new(0xBEEF).to_bytes(&mut bytes);
+        assert_eq!(bytes[..2], [0xEF, 0xBE]);
+        value.set(7);
+        assert_eq!(u16::from(value), 7);
+        assert_eq!(core::mem::align_of::<Le64>(), 1);
+    }
+}
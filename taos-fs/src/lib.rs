@@ -0,0 +1,206 @@
+//! Filesystem types and drivers shared with the host tools.
+//!
+//! The on-disk formats, the filesystem traits and the FAT16 and partition
+//! table code are `no_std` and need nothing from the kernel beyond a heap,
+//! so the same code that mounts a disk in TAOS can build and check disk
+//! images on the host. The kernel registers its clock with `time::set_clock`
+//! for file timestamps.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::result::Result;
+
+pub mod fat16;
+pub mod layout;
+pub mod partition;
+pub mod time;
+
+#[cfg(test)]
+mod test_device;
+
+#[derive(Debug)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    InvalidName,
+    IOError,
+    NotSupported,
+    InvalidOffset,
+    NoSpace,
+    DirectoryNotEmpty,
+    /// The filesystem is in use
+    Busy,
+    /// The filesystem is mounted read-only
+    ReadOnly,
+    /// The operation is not allowed
+    PermissionDenied,
+    /// Directories are nested too deeply to traverse
+    TooDeep,
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError>;
+    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError>;
+    fn block_size(&self) -> usize;
+    fn total_blocks(&self) -> u64;
+    /// Reads consecutive blocks starting at `block_num`. `buf` holds a
+    /// whole number of blocks. Devices that can move several blocks with
+    /// one command should override this, the default reads them one by one.
+    fn read_blocks(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let block_size = self.block_size();
+        if !buf.len().is_multiple_of(block_size) {
+            return Err(FsError::IOError);
+        }
+        for (i, block) in buf.chunks_exact_mut(block_size).enumerate() {
+            self.read_block(block_num + i as u64, block)?;
+        }
+        Ok(())
+    }
+    /// Writes consecutive blocks starting at `block_num`, like `read_blocks`
+    fn write_blocks(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let block_size = self.block_size();
+        if !buf.len().is_multiple_of(block_size) {
+            return Err(FsError::IOError);
+        }
+        for (i, block) in buf.chunks_exact(block_size).enumerate() {
+            self.write_block(block_num + i as u64, block)?;
+        }
+        Ok(())
+    }
+    /// Reads consecutive blocks like `read_blocks`, but around any cache
+    /// the device keeps, so large transfers neither copy through it nor
+    /// push out what it holds. Caching devices must still return what was
+    /// last written.
+    fn read_blocks_direct(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.read_blocks(block_num, buf)
+    }
+    /// Writes consecutive blocks around any cache, like `read_blocks_direct`
+    fn write_blocks_direct(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.write_blocks(block_num, buf)
+    }
+    /// Makes every completed write durable. Devices without write caching
+    /// need not override this.
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// Represents a file in the filesystem
+pub trait File {
+    fn read_with_device(
+        &mut self,
+        device: &mut dyn BlockDevice,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError>;
+    fn write_with_device(
+        &mut self,
+        device: &mut dyn BlockDevice,
+        buf: &[u8],
+    ) -> Result<usize, FsError>;
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError>;
+    fn flush(&mut self) -> Result<(), FsError>;
+    fn size(&self) -> u64;
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: FileMetadata,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileMetadata {
+    pub size: u64,
+    pub is_dir: bool,
+    /// Creation time in seconds since the Unix epoch, 0 if unknown
+    pub created: u64,
+    /// Modification time in seconds since the Unix epoch, 0 if unknown
+    pub modified: u64,
+    /// Last access time in seconds since the Unix epoch, 0 if unknown
+    pub accessed: u64,
+    pub permissions: FilePermissions,
+    /// File type and permission bits as in `st_mode`, None if the
+    /// filesystem has no Unix modes
+    pub mode: Option<u16>,
+    /// Owner user ID, 0 if the filesystem records no owners
+    pub uid: u32,
+    /// Owner group ID, 0 if the filesystem records no owners
+    pub gid: u32,
+}
+
+/// Timestamps to change, in seconds since the Unix epoch. `None` leaves a
+/// timestamp unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileTimes {
+    pub accessed: Option<u64>,
+    pub modified: Option<u64>,
+}
+
+/// Space usage of a filesystem, laid out for user programs
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatFs {
+    /// Allocation unit in bytes
+    pub block_size: u64,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    /// Zero for filesystems without inodes
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FilePermissions {
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// The main filesystem trait that must be implemented by all filesystem types
+pub trait FileSystem {
+    fn create_file(&mut self, path: &str) -> Result<(), FsError>;
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError>;
+    fn remove_file(&mut self, path: &str) -> Result<(), FsError>;
+    fn remove_dir(&mut self, path: &str) -> Result<(), FsError>;
+    fn open_file(&mut self, path: &str) -> Result<usize, FsError>;
+    fn close_file(&mut self, fd: usize);
+    fn write_file(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError>;
+    fn seek_file(&mut self, fd: usize, pos: SeekFrom) -> Result<u64, FsError>;
+    fn read_file(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError>;
+    /// Reads like `read_file`, but when the file's position is aligned to
+    /// the filesystem's blocks, whole blocks move straight from the device
+    /// into `buf` with `BlockDevice::read_blocks_direct`, and the count
+    /// stops at the last whole block. Otherwise, and on filesystems without
+    /// a direct path, it reads as usual.
+    fn read_file_direct(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.read_file(fd, buf)
+    }
+    /// Writes like `write_file`, moving whole blocks straight from `buf` to
+    /// the device like `read_file_direct`
+    fn write_file_direct(&mut self, fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+        self.write_file(fd, buf)
+    }
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError>;
+    fn metadata(&self, path: &str) -> Result<FileMetadata, FsError>;
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError>;
+    /// Writes all cached data and metadata to the underlying device
+    fn sync(&mut self) -> Result<(), FsError>;
+    /// Name of the filesystem type, as shown in mount listings
+    fn fs_type(&self) -> &'static str;
+    /// Sets the access and modification times of a file or directory
+    fn set_times(&mut self, path: &str, times: FileTimes) -> Result<(), FsError>;
+    /// Sets the permissions of a file or directory, as far as the
+    /// filesystem can represent them
+    fn set_permissions(&mut self, path: &str, permissions: FilePermissions) -> Result<(), FsError>;
+    /// Reports the size and free space of the filesystem
+    fn statfs(&mut self) -> Result<StatFs, FsError>;
+}
@@ -0,0 +1,362 @@
+//! MBR and GPT partition tables.
+//!
+//! Parses the partition table of a disk and exposes each partition as a
+//! block device of its own, a window onto the disk that cannot reach past
+//! the partition's ends. The partitions share the disk through a lock, so
+//! one card can hold, for example, a FAT16 data partition next to a boot
+//! partition of ELF images, each with its own filesystem.
+//!
+//! A disk whose MBR has a protective 0xEE entry is read as GPT, and the
+//! GPT header and entry array must pass their CRC checks. Extended MBR
+//! partitions are listed but their logical partitions are not followed.
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    layout::{u16_le_at, u32_le_at, u64_le_at},
+    BlockDevice, FsError,
+};
+
+/// Offset of the partition entries in the MBR
+const MBR_ENTRIES_OFFSET: usize = 446;
+/// Size of an MBR partition entry
+const MBR_ENTRY_SIZE: usize = 16;
+/// Number of primary partitions in an MBR
+const MBR_ENTRY_COUNT: usize = 4;
+/// Boot signature at the end of the MBR
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// Partition type of the protective entry on GPT disks
+pub const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+/// Partition type of FAT16 partitions addressed by LBA
+pub const MBR_TYPE_FAT16_LBA: u8 = 0x0E;
+/// Partition type of Linux native partitions, such as ext2
+pub const MBR_TYPE_LINUX: u8 = 0x83;
+
+/// Block holding the primary GPT header
+const GPT_HEADER_BLOCK: u64 = 1;
+/// Signature at the start of a GPT header
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Smallest GPT header, as defined by revision 1.0
+const GPT_HEADER_MIN_SIZE: usize = 92;
+/// Smallest GPT partition entry
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// Most GPT entries read, far more than any real table uses
+const GPT_MAX_ENTRIES: u32 = 1024;
+/// Bytes of UTF-16 partition name in a GPT entry
+const GPT_NAME_LENGTH: usize = 72;
+
+/// A block device shared by its partitions
+pub type SharedBlockDevice = Arc<Mutex<Box<dyn BlockDevice>>>;
+
+/// Type of a partition, as recorded in the partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// MBR partition type byte
+    Mbr(u8),
+    /// GPT partition type GUID, in on-disk byte order
+    Gpt([u8; 16]),
+}
+
+/// Where a partition is and what it holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Position in the partition table, starting at 0
+    pub index: usize,
+    /// First block of the partition on the disk
+    pub first_block: u64,
+    /// Length of the partition in blocks
+    pub block_count: u64,
+    pub partition_type: PartitionType,
+    /// Partition name, empty for MBR partitions
+    pub name: String,
+}
+
+/// CRC-32 as used by GPT (IEEE 802.3, reflected)
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// Reads the partition table of a disk
+///
+/// # Returns
+/// The partitions in table order, leaving out unused entries. Fails with
+/// `NotFound` if the disk has no partition table and with `IOError` if the
+/// table is corrupt.
+pub fn read_partition_table(device: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, FsError> {
+    let mut mbr = vec![0u8; device.block_size()];
+    if mbr.len() < 512 {
+        return Err(FsError::NotSupported);
+    }
+    device.read_block(0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Err(FsError::NotFound);
+    }
+
+    let entries = (0..MBR_ENTRY_COUNT).map(|index| {
+        let entry = &mbr[MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        (index, entry[4], u32_le_at(entry, 8), u32_le_at(entry, 12))
+    });
+    if entries
+        .clone()
+        .any(|(_, kind, _, _)| kind == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return read_gpt(device);
+    }
+
+    let partitions: Vec<_> = entries
+        .filter(|&(_, kind, _, length)| kind != 0 && length != 0)
+        .map(|(index, kind, first, length)| PartitionInfo {
+            index,
+            first_block: first as u64,
+            block_count: length as u64,
+            partition_type: PartitionType::Mbr(kind),
+            name: String::new(),
+        })
+        .collect();
+    check_bounds(device, &partitions)?;
+    Ok(partitions)
+}
+
+fn read_gpt(device: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, FsError> {
+    let block_size = device.block_size();
+    let mut header = vec![0u8; block_size];
+    device.read_block(GPT_HEADER_BLOCK, &mut header)?;
+
+    let header_size = u32_le_at(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE || !(GPT_HEADER_MIN_SIZE..=block_size).contains(&header_size) {
+        return Err(FsError::IOError);
+    }
+    // The header CRC is computed with its own field zeroed
+    let header_crc = u32_le_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc {
+        return Err(FsError::IOError);
+    }
+
+    let entries_block = u64_le_at(&header, 72);
+    let entry_count = u32_le_at(&header, 80);
+    let entry_size = u32_le_at(&header, 84) as usize;
+    if entry_count > GPT_MAX_ENTRIES
+        || entry_size < GPT_ENTRY_MIN_SIZE
+        || !entry_size.is_power_of_two()
+    {
+        return Err(FsError::IOError);
+    }
+
+    let table_size = entry_count as usize * entry_size;
+    let mut table = vec![0u8; table_size.div_ceil(block_size) * block_size];
+    for (i, block) in table.chunks_mut(block_size).enumerate() {
+        device.read_block(entries_block + i as u64, block)?;
+    }
+    if crc32(&table[..table_size]) != u32_le_at(&header, 88) {
+        return Err(FsError::IOError);
+    }
+
+    let partitions: Vec<_> = table[..table_size]
+        .chunks(entry_size)
+        .enumerate()
+        .filter(|(_, entry)| entry[..16].iter().any(|&b| b != 0))
+        .map(|(index, entry)| {
+            let first = u64_le_at(entry, 32);
+            let last = u64_le_at(entry, 40);
+            let name: Vec<u16> = entry[56..56 + GPT_NAME_LENGTH]
+                .chunks(2)
+                .map(|pair| u16_le_at(pair, 0))
+                .take_while(|&unit| unit != 0)
+                .collect();
+            PartitionInfo {
+                index,
+                first_block: first,
+                block_count: last.saturating_add(1).saturating_sub(first),
+                partition_type: PartitionType::Gpt(entry[..16].try_into().unwrap()),
+                name: String::from_utf16_lossy(&name),
+            }
+        })
+        .collect();
+    check_bounds(device, &partitions)?;
+    Ok(partitions)
+}
+
+/// Fails if a partition reaches past the end of the disk
+fn check_bounds(device: &dyn BlockDevice, partitions: &[PartitionInfo]) -> Result<(), FsError> {
+    let fits = partitions.iter().all(|p| {
+        p.block_count > 0
+            && p.first_block
+                .checked_add(p.block_count)
+                .is_some_and(|end| end <= device.total_blocks())
+    });
+    fits.then_some(()).ok_or(FsError::IOError)
+}
+
+/// One partition of a disk
+pub struct Partition {
+    device: SharedBlockDevice,
+    info: PartitionInfo,
+}
+
+impl Partition {
+    /// Returns where the partition is and what it holds
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+
+    /// Translates a block number of the partition to one of the disk
+    fn disk_block(&self, block_num: u64) -> Result<u64, FsError> {
+        if block_num >= self.info.block_count {
+            return Err(FsError::IOError);
+        }
+        Ok(self.info.first_block + block_num)
+    }
+
+    /// Translates the first of `len` bytes of blocks, all of which must be
+    /// inside the partition
+    fn disk_blocks(&self, block_num: u64, len: usize) -> Result<u64, FsError> {
+        let count = (len / self.block_size()) as u64;
+        let last = block_num.checked_add(count.saturating_sub(1));
+        self.disk_block(last.ok_or(FsError::IOError)?)?;
+        self.disk_block(block_num)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let block = self.disk_block(block_num)?;
+        self.device.lock().read_block(block, buf)
+    }
+
+    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let block = self.disk_block(block_num)?;
+        self.device.lock().write_block(block, buf)
+    }
+
+    fn read_blocks(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let block = self.disk_blocks(block_num, buf.len())?;
+        self.device.lock().read_blocks(block, buf)
+    }
+
+    fn write_blocks(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let block = self.disk_blocks(block_num, buf.len())?;
+        self.device.lock().write_blocks(block, buf)
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.lock().block_size()
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.info.block_count
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.device.lock().flush()
+    }
+}
+
+/// Splits a disk into its partitions
+///
+/// # Returns
+/// A block device for each partition, in table order. Fails as
+/// `read_partition_table` does.
+pub fn partitions(device: Box<dyn BlockDevice>) -> Result<Vec<Partition>, FsError> {
+    let table = read_partition_table(device.as_ref())?;
+    let device: SharedBlockDevice = Arc::new(Mutex::new(device));
+    Ok(table
+        .into_iter()
+        .map(|info| Partition {
+            device: device.clone(),
+            info,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fat16::Fat16, test_device::MemoryBlockDevice, FileSystem};
+
+    fn mbr_entry(mbr: &mut [u8], index: usize, kind: u8, first: u32, length: u32) {
+        let entry = &mut mbr[MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&first.to_le_bytes());
+        entry[12..16].copy_from_slice(&length.to_le_bytes());
+    }
+
+    #[test]
+    fn test_mbr_partitions() {
+        let mut disk = MemoryBlockDevice::new(1024, 512);
+        let mut mbr = vec![0u8; 512];
+        mbr[510..].copy_from_slice(&MBR_SIGNATURE);
+        mbr_entry(&mut mbr, 0, MBR_TYPE_FAT16_LBA, 1, 511);
+        mbr_entry(&mut mbr, 2, MBR_TYPE_LINUX, 512, 512);
+        disk.write_block(0, &mbr).unwrap();
+
+        let mut parts = partitions(Box::new(disk)).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[1].info().index, 2);
+        assert_eq!(
+            parts[1].info().partition_type,
+            PartitionType::Mbr(MBR_TYPE_LINUX)
+        );
+
+        // Each partition is a window of its own
+        let mut data = parts.pop().unwrap();
+        let fs = Fat16::format(Box::new(parts.pop().unwrap())).unwrap();
+        fs.create_file("/data.txt").unwrap();
+        data.write_block(0, &[0xAB; 512]).unwrap();
+        assert!(data.write_block(512, &[0; 512]).is_err());
+        assert!(fs.metadata("/data.txt").is_ok());
+
+        // Runs of blocks may not cross the end of the partition
+        let mut run = vec![0u8; 3 * 512];
+        data.read_blocks(0, &mut run).unwrap();
+        assert_eq!(run[..512], [0xAB; 512]);
+        data.write_blocks(509, &run).unwrap();
+        assert!(data.write_blocks(510, &run).is_err());
+        assert!(data.read_blocks(0, &mut run[..100]).is_err());
+    }
+
+    #[test]
+    fn test_gpt_partitions() {
+        let mut disk = MemoryBlockDevice::new(128, 512);
+        let mut mbr = vec![0u8; 512];
+        mbr[510..].copy_from_slice(&MBR_SIGNATURE);
+        mbr_entry(&mut mbr, 0, MBR_TYPE_GPT_PROTECTIVE, 1, 127);
+        disk.write_block(0, &mbr).unwrap();
+
+        let mut entries = vec![0u8; 4 * 128];
+        entries[..16].copy_from_slice(&[0x11; 16]);
+        entries[32..40].copy_from_slice(&34u64.to_le_bytes());
+        entries[40..48].copy_from_slice(&63u64.to_le_bytes());
+        for (i, unit) in "boot".encode_utf16().enumerate() {
+            entries[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        disk.write_block(2, &entries[..512]).unwrap();
+
+        let mut header = vec![0u8; 512];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.write_block(1, &header).unwrap();
+
+        let table = read_partition_table(&disk).unwrap();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].first_block, 34);
+        assert_eq!(table[0].block_count, 30);
+        assert_eq!(table[0].name, "boot");
+
+        // A corrupt header is rejected
+        header[20] ^= 1;
+        disk.write_block(1, &header).unwrap();
+        assert!(matches!(read_partition_table(&disk), Err(FsError::IOError)));
+    }
+}
@@ -0,0 +1,45 @@
+//! In-memory block device for tests
+
+use crate::{BlockDevice, FsError};
+
+/// Block device that stores data in memory
+pub struct MemoryBlockDevice {
+    blocks: Vec<Vec<u8>>,
+    block_size: usize,
+}
+
+impl MemoryBlockDevice {
+    pub fn new(total_blocks: u64, block_size: usize) -> Self {
+        let blocks = (0..total_blocks).map(|_| vec![0; block_size]).collect();
+        Self { blocks, block_size }
+    }
+
+    fn block(&self, block_num: u64, len: usize) -> Result<usize, FsError> {
+        if block_num as usize >= self.blocks.len() || len != self.block_size {
+            return Err(FsError::IOError);
+        }
+        Ok(block_num as usize)
+    }
+}
+
+impl BlockDevice for MemoryBlockDevice {
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let block = self.block(block_num, buf.len())?;
+        buf.copy_from_slice(&self.blocks[block]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let block = self.block(block_num, buf.len())?;
+        self.blocks[block].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+}
@@ -0,0 +1,100 @@
+//! Calendar dates and the clock used for file timestamps.
+//!
+//! The crate has no clock of its own. The kernel registers its wall clock
+//! with `set_clock` at boot and host tools register the system time. Until
+//! then new timestamps read as the Unix epoch, which FAT clamps to 1980.
+
+use spin::Once;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Seconds since the Unix epoch, as set with `set_clock`
+static CLOCK: Once<fn() -> i64> = Once::new();
+
+/// Sets the clock new timestamps are taken from. Only the first call has
+/// any effect.
+pub fn set_clock(clock: fn() -> i64) {
+    CLOCK.call_once(|| clock);
+}
+
+/// Seconds since the Unix epoch, 0 if no clock is set
+pub fn now() -> i64 {
+    CLOCK.get().map_or(0, |clock| clock())
+}
+
+/// A UTC calendar date and time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts seconds since the Unix epoch to a calendar date.
+    /// Uses the days-to-civil algorithm from Howard Hinnant's date library.
+    pub fn from_unix(seconds: i64) -> Self {
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        let time_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (time_of_day / 3600) as u8,
+            minute: (time_of_day / 60 % 60) as u8,
+            second: (time_of_day % 60) as u8,
+        }
+    }
+
+    /// Converts the date to seconds since the Unix epoch
+    pub fn to_unix(&self) -> i64 {
+        let month = self.month as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days * SECONDS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date_conversion() {
+        let date = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 37,
+            second: 42,
+        };
+        assert_eq!(date.to_unix(), 1_709_213_862);
+        assert_eq!(DateTime::from_unix(1_709_213_862), date);
+        assert_eq!(DateTime::from_unix(0).year, 1970);
+    }
+}
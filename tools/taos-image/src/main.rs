@@ -0,0 +1,287 @@
+//! Builds, populates and checks TAOS disk images on the host.
+//!
+//! Images are read and written with the kernel's own FAT16 and partition
+//! table code from `taos-fs`, so anything this tool writes the kernel can
+//! read, and the other way round. Paths inside an image are absolute and
+//! use 8.3 names.
+//!
+//! ```text
+//! taos-image create IMAGE SIZE_MIB
+//! taos-image mkfs [-p N] IMAGE
+//! taos-image ls [-p N] IMAGE [PATH]
+//! taos-image mkdir [-p N] IMAGE PATH
+//! taos-image put [-p N] IMAGE HOST_FILE PATH
+//! taos-image get [-p N] IMAGE PATH HOST_FILE
+//! taos-image fsck [-p N] IMAGE
+//! ```
+//!
+//! `-p N` works on partition N of a disk with an MBR or GPT partition
+//! table instead of on the whole image.
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom as IoSeekFrom, Write},
+    process::ExitCode,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use taos_fs::{fat16::Fat16, partition::partitions, BlockDevice, FileSystem, FsError, SeekFrom};
+
+/// Sector size of the images, and of SD cards
+const BLOCK_SIZE: usize = 512;
+
+/// Bytes copied in and out of an image at a time
+const COPY_CHUNK: usize = 64 * 1024;
+
+const USAGE: &str = "usage:
+    taos-image create IMAGE SIZE_MIB
+    taos-image mkfs [-p N] IMAGE
+    taos-image ls [-p N] IMAGE [PATH]
+    taos-image mkdir [-p N] IMAGE PATH
+    taos-image put [-p N] IMAGE HOST_FILE PATH
+    taos-image get [-p N] IMAGE PATH HOST_FILE
+    taos-image fsck [-p N] IMAGE";
+
+/// A disk image file as a block device
+struct ImageFile {
+    file: Mutex<fs::File>,
+    blocks: u64,
+}
+
+impl ImageFile {
+    fn open(path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|error| format!("{path}: {error}"))?;
+        let len = file
+            .metadata()
+            .map_err(|error| format!("{path}: {error}"))?
+            .len();
+        if len % BLOCK_SIZE as u64 != 0 {
+            return Err(format!(
+                "{path}: not a whole number of {BLOCK_SIZE} byte sectors"
+            ));
+        }
+        Ok(ImageFile {
+            file: Mutex::new(file),
+            blocks: len / BLOCK_SIZE as u64,
+        })
+    }
+
+    fn seek(file: &mut fs::File, block_num: u64, len: usize, blocks: u64) -> Result<(), FsError> {
+        if block_num >= blocks || len != BLOCK_SIZE {
+            return Err(FsError::IOError);
+        }
+        file.seek(IoSeekFrom::Start(block_num * BLOCK_SIZE as u64))
+            .map_err(|_| FsError::IOError)?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for ImageFile {
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut file = self.file.lock().unwrap();
+        Self::seek(&mut file, block_num, buf.len(), self.blocks)?;
+        file.read_exact(buf).map_err(|_| FsError::IOError)
+    }
+
+    fn write_block(&mut self, block_num: u64, buf: &[u8]) -> Result<(), FsError> {
+        let file = self.file.get_mut().unwrap();
+        Self::seek(file, block_num, buf.len(), self.blocks)?;
+        file.write_all(buf).map_err(|_| FsError::IOError)
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.file
+            .get_mut()
+            .unwrap()
+            .sync_all()
+            .map_err(|_| FsError::IOError)
+    }
+}
+
+/// Opens an image, or one partition of it
+fn open_device(image: &str, partition: Option<usize>) -> Result<Box<dyn BlockDevice>, String> {
+    let image_file = Box::new(ImageFile::open(image)?);
+    let Some(index) = partition else {
+        return Ok(image_file);
+    };
+    let found = partitions(image_file)
+        .map_err(|error| format!("{image}: no partition table ({error:?})"))?
+        .into_iter()
+        .find(|partition| partition.info().index == index)
+        .ok_or_else(|| format!("{image}: no partition {index}"))?;
+    Ok(Box::new(found))
+}
+
+fn open_fs(image: &str, partition: Option<usize>) -> Result<Fat16<'static>, String> {
+    let device = open_device(image, partition)?;
+    Fat16::new(device).map_err(|error| format!("{image}: not a FAT16 volume ({error:?})"))
+}
+
+fn fs_error(path: &str, error: FsError) -> String {
+    format!("{path}: {error:?}")
+}
+
+fn create(image: &str, size_mib: &str) -> Result<(), String> {
+    let size_mib: u64 = size_mib
+        .parse()
+        .map_err(|_| format!("{size_mib}: not a size in MiB"))?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image)
+        .map_err(|error| format!("{image}: {error}"))?;
+    // Left sparse, so reads as zeros without taking up space
+    file.set_len(size_mib * 1024 * 1024)
+        .map_err(|error| format!("{image}: {error}"))
+}
+
+fn mkfs(image: &str, partition: Option<usize>) -> Result<(), String> {
+    let device = open_device(image, partition)?;
+    let fs = Fat16::format(device).map_err(|error| fs_error(image, error))?;
+    fs.sync().map_err(|error| fs_error(image, error))
+}
+
+fn ls(fs: &Fat16, path: &str) -> Result<(), String> {
+    let entries = fs.read_dir(path).map_err(|error| fs_error(path, error))?;
+    for entry in entries {
+        let kind = if entry.metadata.is_dir {
+            "dir "
+        } else {
+            "file"
+        };
+        println!("{kind} {:>10} {}", entry.metadata.size, entry.name);
+    }
+    Ok(())
+}
+
+fn put(fs: &Fat16, host_file: &str, path: &str) -> Result<(), String> {
+    let mut source = fs::File::open(host_file).map_err(|error| format!("{host_file}: {error}"))?;
+    match fs.create_file(path) {
+        Ok(()) => {}
+        // Replaced, as by cp
+        Err(FsError::AlreadyExists) => {
+            fs.remove_file(path)
+                .map_err(|error| fs_error(path, error))?;
+            fs.create_file(path)
+                .map_err(|error| fs_error(path, error))?;
+        }
+        Err(error) => return Err(fs_error(path, error)),
+    }
+
+    let fd = fs.open_file(path).map_err(|error| fs_error(path, error))?;
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let result = loop {
+        let len = match source.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(len) => len,
+            Err(error) => break Err(format!("{host_file}: {error}")),
+        };
+        if let Err(error) = fs.write_file(fd, &buf[..len]) {
+            break Err(fs_error(path, error));
+        }
+    };
+    fs.close_file(fd);
+    result?;
+    fs.sync().map_err(|error| fs_error(path, error))
+}
+
+fn get(fs: &Fat16, path: &str, host_file: &str) -> Result<(), String> {
+    let fd = fs.open_file(path).map_err(|error| fs_error(path, error))?;
+    let mut target =
+        fs::File::create(host_file).map_err(|error| format!("{host_file}: {error}"))?;
+    fs.seek_file(fd, SeekFrom::Start(0))
+        .map_err(|error| fs_error(path, error))?;
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let result = loop {
+        let len = match fs.read_file(fd, &mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(len) => len,
+            Err(error) => break Err(fs_error(path, error)),
+        };
+        if let Err(error) = target.write_all(&buf[..len]) {
+            break Err(format!("{host_file}: {error}"));
+        }
+    };
+    fs.close_file(fd);
+    result
+}
+
+/// Returns true if the volume is consistent
+fn fsck(fs: &Fat16, image: &str) -> Result<bool, String> {
+    let report = fs.check().map_err(|error| fs_error(image, error))?;
+    for problem in &report.problems {
+        println!("{problem:?}");
+    }
+    println!(
+        "{image}: {} files, {} directories, {} clusters in use, {} problems",
+        report.files,
+        report.directories,
+        report.used_clusters,
+        report.problems.len()
+    );
+    Ok(report.is_clean())
+}
+
+fn run(args: &[String]) -> Result<bool, String> {
+    let mut args = args.to_vec();
+    let mut partition = None;
+    if let Some(flag) = args.iter().position(|arg| arg == "-p") {
+        let index = args
+            .get(flag + 1)
+            .and_then(|index| index.parse().ok())
+            .ok_or("-p needs a partition number")?;
+        partition = Some(index);
+        args.drain(flag..flag + 2);
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["create", image, size_mib] => create(image, size_mib)?,
+        ["mkfs", image] => mkfs(image, partition)?,
+        ["ls", image] => ls(&open_fs(image, partition)?, "/")?,
+        ["ls", image, path] => ls(&open_fs(image, partition)?, path)?,
+        ["mkdir", image, path] => {
+            let fs = open_fs(image, partition)?;
+            fs.create_dir(path).map_err(|error| fs_error(path, error))?;
+            fs.sync().map_err(|error| fs_error(path, error))?;
+        }
+        ["put", image, host_file, path] => put(&open_fs(image, partition)?, host_file, path)?,
+        ["get", image, path, host_file] => get(&open_fs(image, partition)?, path, host_file)?,
+        ["fsck", image] => return fsck(&open_fs(image, partition)?, image),
+        _ => return Err(USAGE.into()),
+    }
+    Ok(true)
+}
+
+fn main() -> ExitCode {
+    taos_fs::time::set_clock(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64)
+    });
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::from(2)
+        }
+    }
+}